@@ -2,6 +2,15 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Mimic the behaviour of `firefox.exe` on Windows.
+//!
+//! By default, the first run of firefox.exe starts the "Launcher Process", which
+//! does a bunch of work before re-executing `firefox.exe` as the main (parent)
+//! process.
+//!
+//! There is no launcher process on other platforms, so the first run is the
+//! main process.
+
 use std::env;
 use std::process::{exit, Command};
 use std::thread::sleep;
@@ -9,12 +18,6 @@ use std::time::Duration;
 
 use structopt::StructOpt;
 
-/// Mimic the behaviour of `firefox.exe` on Windows.
-///
-/// By default, the first run of firefox.exe starts the "Launcher Process", which
-/// does a bunch of work before re-executing `firefox.exe` as the main (parent)
-/// process.
-
 /// Matches the options passed to `firefox.exe` by `fxrunner.
 #[derive(StructOpt)]
 struct LauncherOptions {
@@ -26,6 +29,15 @@ struct LauncherOptions {
 
     #[structopt(long)]
     wait_for_browser: bool,
+
+    #[structopt(long = "safe-mode")]
+    _safe_mode: bool,
+
+    /// A URL to open.
+    ///
+    /// When given without `--new-instance`, the URL is forwarded to the
+    /// running instance.
+    _url: Option<String>,
 }
 
 /// Options to distinguish main process mode from launcher mode.
@@ -39,12 +51,23 @@ fn main() {
     let args: Vec<String> = env::args().collect();
 
     if let Ok(opts) = LauncherOptions::from_iter_safe(&args) {
+        if !opts.new_instance {
+            // Remote process.
+            // Forwarding the URL to the running instance is a no-op.
+            eprintln!("[remote] args: {:?}", args);
+            exit(0);
+        }
+
         eprintln!("[launcher] args: {:?}", args);
         // Launcher process.
         // Launch this executable with different command
         assert!(opts.wait_for_browser);
         assert!(opts.new_instance);
 
+        if cfg!(not(windows)) {
+            run_main_process();
+        }
+
         let mut child = Command::new(&args[0])
             .arg("--main")
             .spawn()
@@ -63,14 +86,18 @@ fn main() {
             eprintln!("[main] args: {:?}", args);
             assert!(opts.main);
 
-            // Main process.
-            // Just spin the even loop waiting to be terminated.
-            loop {
-                sleep(Duration::from_secs(30));
-            }
+            run_main_process();
         }
     }
 
     eprintln!("[fakefox] args: {:?}", args);
     panic!("[fakefox] not executed as child or parent");
 }
+
+/// Run the main process.
+fn run_main_process() -> ! {
+    // Just spin the even loop waiting to be terminated.
+    loop {
+        sleep(Duration::from_secs(30));
+    }
+}
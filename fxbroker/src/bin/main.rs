@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+
+use libfxbroker::broker::Broker;
+use libfxbroker::config::Config;
+use libfxrecord::config::{ConfigCommand, ConfigError, ConfigLoader};
+use libfxrecord::logging::{build_logger, build_terminal_logger};
+use slog::{error, info, Logger};
+use structopt::StructOpt;
+use tokio::net::TcpListener;
+
+/// Lease runners to recorders across a fleet.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "fxbroker")]
+struct Options {
+    /// The configuration file to use.
+    #[structopt(long = "config", default_value = "fxrecord.toml")]
+    config_path: PathBuf,
+
+    /// The profile in the configuration file to use.
+    ///
+    /// A profile is a table nested in the `fxbroker` section, e.g.,
+    /// `[fxbroker.staging]`, whose options override those of the section.
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// The address and port to listen on.
+    ///
+    /// Overrides the `host` configuration option.
+    #[structopt(long)]
+    host: Option<SocketAddr>,
+
+    #[structopt(subcommand)]
+    command: Option<BrokerCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+enum BrokerCommand {
+    /// Inspect the configuration instead of starting FxBroker.
+    Config(ConfigCommand),
+}
+
+#[tokio::main]
+async fn main() {
+    let options = Options::from_args();
+
+    if let Some(BrokerCommand::Config(ConfigCommand::Check)) = options.command {
+        exit(check_config(&options));
+    }
+
+    // Without a configuration, there is nowhere to log to yet.
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+    };
+
+    let log = if config.log.is_empty() {
+        build_terminal_logger()
+    } else {
+        build_logger(&config.log).expect("Could not open log")
+    };
+
+    if let Err(e) = fxbroker(log.clone(), config).await {
+        error!(log, "unexpected error"; "error" => %e);
+        drop(log);
+        exit(1);
+    }
+}
+
+async fn fxbroker(log: Logger, config: Config) -> Result<(), Box<dyn Error>> {
+    info!(log, "Loaded configuration"; "config" => ?config);
+
+    let listener = TcpListener::bind(&config.host).await?;
+    info!(log, "Waiting for runners and recorders..."; "host" => %config.host);
+
+    Broker::new(log, &config).serve(listener).await?;
+
+    Ok(())
+}
+
+/// Load the configuration from the built-in defaults, the configuration file,
+/// `FXBROKER_*` environment variables, and command-line flags, in increasing
+/// order of precedence.
+fn load_config(options: &Options) -> Result<Config, ConfigError> {
+    config_loader(options)?.load()
+}
+
+/// Check the configuration, printing every problem found, and return the exit
+/// status.
+fn check_config(options: &Options) -> i32 {
+    let issues = match config_loader(options) {
+        Ok(loader) => loader.check::<Config>(),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+
+    if issues.is_empty() {
+        println!("Configuration is valid.");
+        return 0;
+    }
+
+    for issue in &issues {
+        eprintln!("error: {}", issue);
+    }
+
+    1
+}
+
+fn config_loader(options: &Options) -> Result<ConfigLoader, ConfigError> {
+    Ok(ConfigLoader::new("fxbroker")
+        .set_default("host", "0.0.0.0:8891")
+        .set_default("lease_secs", 3600)
+        .file_with_profile(&options.config_path, options.profile.as_deref())?
+        .env("FXBROKER_")
+        .set_some("host", options.host.map(|host| host.to_string())))
+}
@@ -0,0 +1,315 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serving requests from runners and recorders.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use libfxrecord::error::ErrorExt;
+use libfxrecord::net::broker::{
+    BrokerClientMessageKind, BrokerRequest, BrokerServerProto, Lease, LeaseOutcome, Leased, Queued,
+    Registered, Released, Renewed,
+};
+use libfxrecord::net::ProtoError;
+use slog::{error, info, warn, Logger};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::interval;
+
+use crate::config::Config;
+use crate::history::{append_history, HistoryEvent};
+use crate::pool::{EndedLease, Grant, Pool, PoolError};
+
+/// How often expired leases are reclaimed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The broker, which leases the runners registered with it to recorders.
+pub struct Broker {
+    log: Logger,
+    pool: Mutex<Pool<oneshot::Sender<Lease>>>,
+    history_path: Option<PathBuf>,
+}
+
+impl Broker {
+    pub fn new(log: Logger, config: &Config) -> Arc<Self> {
+        let lease_prefix = Utc::now().format("%Y%m%dT%H%M%S").to_string();
+
+        Arc::new(Broker {
+            log,
+            pool: Mutex::new(Pool::new(config.lease_duration(), lease_prefix)),
+            history_path: config.history_path.clone(),
+        })
+    }
+
+    /// Serve requests from the listener until accepting a connection fails.
+    ///
+    /// Expired leases are reclaimed in the background.
+    pub async fn serve(self: Arc<Self>, mut listener: TcpListener) -> io::Result<()> {
+        tokio::spawn(self.clone().reclaim_expired_leases());
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let broker = self.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = broker.handle_connection(stream, peer).await {
+                    warn!(broker.log, "Could not serve request"; "peer" => %peer, "error" => %e);
+                }
+            });
+        }
+    }
+
+    /// Serve the single request carried by the connection.
+    async fn handle_connection(
+        &self,
+        stream: TcpStream,
+        peer: SocketAddr,
+    ) -> Result<(), ProtoError<BrokerClientMessageKind>> {
+        let mut proto = BrokerServerProto::new(stream);
+
+        match proto.recv::<BrokerRequest>().await? {
+            BrokerRequest::Register(registration) => {
+                let host = SocketAddr::new(peer.ip(), registration.port).to_string();
+
+                info!(
+                    self.log, "Runner registered";
+                    "runner" => &registration.name,
+                    "host" => &host,
+                    "tags" => ?registration.tags,
+                );
+
+                self.pool.lock().unwrap().register(
+                    registration.name.clone(),
+                    host.clone(),
+                    registration.tags,
+                );
+
+                self.record(HistoryEvent::Registered {
+                    runner: &registration.name,
+                    host: &host,
+                })
+                .await;
+
+                proto.send(Registered { result: Ok(()) }).await?;
+                self.dispatch().await;
+            }
+
+            BrokerRequest::Lease(request) => {
+                let (tx, rx) = oneshot::channel();
+
+                let queued = self.pool.lock().unwrap().enqueue(
+                    request.tags,
+                    request.requester.clone(),
+                    tx,
+                    Instant::now(),
+                );
+
+                let position = match queued {
+                    Ok(position) => position,
+                    Err(e) => {
+                        warn!(self.log, "Refused lease request"; "requester" => &request.requester, "error" => %e);
+                        proto.send(Queued { position: 0 }).await?;
+                        proto
+                            .send(Leased {
+                                result: Err(e.to_foreign_error()),
+                            })
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                info!(self.log, "Queued lease request"; "requester" => &request.requester, "position" => position);
+                proto.send(Queued { position }).await?;
+                self.dispatch().await;
+
+                let lease = rx
+                    .await
+                    .expect("waiting lease request was dropped from the queue");
+
+                if let Err(e) = proto
+                    .send(Leased {
+                        result: Ok(lease.clone()),
+                    })
+                    .await
+                {
+                    // The recorder went away while it was waiting, so the
+                    // runner can go to the next request.
+                    self.release(
+                        &lease.lease_id,
+                        LeaseOutcome::Failed("recorder disconnected while queued".into()),
+                    )
+                    .await
+                    .ok();
+                    return Err(e);
+                }
+            }
+
+            BrokerRequest::Renew(renewal) => {
+                let result = self
+                    .pool
+                    .lock()
+                    .unwrap()
+                    .renew(&renewal.lease_id, Instant::now());
+
+                if let Err(ref e) = result {
+                    warn!(self.log, "Could not renew lease"; "lease_id" => &renewal.lease_id, "error" => %e);
+                }
+
+                proto
+                    .send(Renewed {
+                        result: result.map_err(|e| e.to_foreign_error()),
+                    })
+                    .await?;
+            }
+
+            BrokerRequest::Release(release) => {
+                let result = self
+                    .release(&release.lease_id, release.outcome)
+                    .await
+                    .map_err(|e| e.to_foreign_error());
+
+                proto.send(Released { result }).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Grant leases on free runners to waiting requests.
+    async fn dispatch(&self) {
+        loop {
+            let grants = self.pool.lock().unwrap().dispatch(Instant::now());
+            if grants.is_empty() {
+                break;
+            }
+
+            for Grant {
+                waiter,
+                lease,
+                requester,
+                waited,
+            } in grants
+            {
+                info!(
+                    self.log, "Leased runner";
+                    "runner" => &lease.runner,
+                    "lease_id" => &lease.lease_id,
+                    "requester" => &requester,
+                );
+
+                self.record(HistoryEvent::Leased {
+                    lease_id: &lease.lease_id,
+                    runner: &lease.runner,
+                    requester: &requester,
+                    waited_secs: waited.as_secs(),
+                })
+                .await;
+
+                // If the request stopped waiting, the runner is free for the
+                // next one.
+                if let Err(lease) = waiter.send(lease) {
+                    let released = self
+                        .pool
+                        .lock()
+                        .unwrap()
+                        .release(&lease.lease_id, Instant::now());
+
+                    if let Ok(released) = released {
+                        self.record_released(
+                            &released,
+                            LeaseOutcome::Failed("recorder stopped waiting".into()),
+                        )
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Release the lease with the given ID and grant the runner to the next
+    /// waiting request.
+    async fn release(&self, lease_id: &str, outcome: LeaseOutcome) -> Result<(), PoolError> {
+        let released = self
+            .pool
+            .lock()
+            .unwrap()
+            .release(lease_id, Instant::now())?;
+
+        self.record_released(&released, outcome).await;
+        self.dispatch().await;
+        Ok(())
+    }
+
+    /// Log the released lease and record it in the fleet history.
+    async fn record_released(&self, released: &EndedLease, outcome: LeaseOutcome) {
+        info!(
+            self.log, "Released runner";
+            "runner" => &released.runner,
+            "lease_id" => &released.lease_id,
+            "outcome" => %outcome,
+        );
+
+        self.record(HistoryEvent::Released {
+            lease_id: &released.lease_id,
+            runner: &released.runner,
+            requester: &released.requester,
+            held_secs: released.held.as_secs(),
+            outcome: outcome.to_string(),
+        })
+        .await;
+    }
+
+    /// Periodically reclaim runners whose leases have expired.
+    async fn reclaim_expired_leases(self: Arc<Self>) {
+        let mut interval = interval(EXPIRY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let expired = self.pool.lock().unwrap().expire(Instant::now());
+            if expired.is_empty() {
+                continue;
+            }
+
+            for EndedLease {
+                lease_id,
+                runner,
+                requester,
+                held,
+            } in &expired
+            {
+                warn!(
+                    self.log, "Lease expired";
+                    "runner" => runner,
+                    "lease_id" => lease_id,
+                    "requester" => requester,
+                );
+
+                self.record(HistoryEvent::Expired {
+                    lease_id,
+                    runner,
+                    requester,
+                    held_secs: held.as_secs(),
+                })
+                .await;
+            }
+
+            self.dispatch().await;
+        }
+    }
+
+    /// Record the event in the fleet history, if one is kept.
+    async fn record(&self, event: HistoryEvent<'_>) {
+        if let Some(ref history_path) = self.history_path {
+            if let Err(e) = append_history(history_path, event).await {
+                error!(self.log, "Could not record fleet history"; "error" => %e);
+            }
+        }
+    }
+}
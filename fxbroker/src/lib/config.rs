@@ -0,0 +1,46 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libfxrecord::config::{ConfigIssues, Validate};
+use libfxrecord::logging::DrainConfig;
+use serde::Deserialize;
+
+/// The configuration for FxBroker.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// The address and port to listen on.
+    pub host: SocketAddr,
+
+    /// How long a lease lasts without being renewed before the runner is
+    /// returned to the pool, in seconds.
+    pub lease_secs: u64,
+
+    /// The path to write the fleet history to.
+    ///
+    /// If not provided, no history is kept.
+    pub history_path: Option<PathBuf>,
+
+    /// Where to log to.
+    ///
+    /// If empty, human-readable logs are written to stderr.
+    #[serde(default)]
+    pub log: Vec<DrainConfig>,
+}
+
+impl Config {
+    /// Return how long a runner may be leased.
+    pub fn lease_duration(&self) -> Duration {
+        Duration::from_secs(self.lease_secs)
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_range("lease_secs", self.lease_secs, 1, u64::MAX);
+    }
+}
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A persistent history of the fleet, so that operators can see which
+//! runners served which recorders, how long requests waited, and how
+//! sessions ended.
+//!
+//! The history is kept as one JSON record per line.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::fs::OpenOptions;
+use tokio::prelude::*;
+
+/// A record in the fleet history.
+#[derive(Debug, Serialize)]
+pub struct HistoryRecord<'a> {
+    /// When the event happened.
+    pub time: DateTime<Utc>,
+
+    #[serde(flatten)]
+    pub event: HistoryEvent<'a>,
+}
+
+/// Something that happened to the fleet.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HistoryEvent<'a> {
+    /// A runner registered with the broker.
+    Registered { runner: &'a str, host: &'a str },
+
+    /// A runner was leased to a recorder.
+    Leased {
+        lease_id: &'a str,
+        runner: &'a str,
+        requester: &'a str,
+        waited_secs: u64,
+    },
+
+    /// A recorder released its lease.
+    Released {
+        lease_id: &'a str,
+        runner: &'a str,
+        requester: &'a str,
+        held_secs: u64,
+        outcome: String,
+    },
+
+    /// A lease was neither renewed nor released in time and the runner was
+    /// reclaimed.
+    Expired {
+        lease_id: &'a str,
+        runner: &'a str,
+        requester: &'a str,
+        held_secs: u64,
+    },
+}
+
+/// Append a record of the event to the history at the given path.
+pub async fn append_history(path: &Path, event: HistoryEvent<'_>) -> Result<(), HistoryError> {
+    let record = HistoryRecord {
+        time: Utc::now(),
+        event,
+    };
+
+    let mut line = serde_json::to_vec(&record).expect("could not serialize history record");
+    line.push(b'\n');
+
+    let io_err = |source| HistoryError {
+        path: path.into(),
+        source,
+    };
+
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(io_err)?;
+
+    f.write_all(&line).await.map_err(io_err)
+}
+
+#[derive(Debug, Error)]
+#[error("Could not write fleet history `{}': {}", .path.display(), .source)]
+pub struct HistoryError {
+    pub path: PathBuf,
+    pub source: io::Error,
+}
@@ -0,0 +1,398 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The pool of registered runners and the queue of requests waiting for them.
+//!
+//! Requests are served in the order they arrive, except that a request
+//! waiting for a runner with particular tags does not hold up requests that
+//! can be served by another runner.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use libfxrecord::net::broker::Lease;
+use thiserror::Error;
+
+/// The runners known to the broker and the requests waiting for them.
+///
+/// Each waiting request carries a waiter of type `W`, which is handed back
+/// along with the lease once a runner is free.
+#[derive(Debug)]
+pub struct Pool<W> {
+    /// How long a lease lasts without being renewed before the runner is
+    /// reclaimed.
+    lease_duration: Duration,
+
+    /// A prefix for lease IDs, so that IDs are not reused across restarts of
+    /// the broker.
+    lease_prefix: String,
+
+    /// The number of leases granted so far.
+    leases_granted: u64,
+
+    /// The registered runners, by name.
+    runners: BTreeMap<String, PooledRunner>,
+
+    /// The requests waiting for a runner, oldest first.
+    queue: VecDeque<Waiting<W>>,
+}
+
+/// A runner in the pool.
+#[derive(Debug)]
+struct PooledRunner {
+    host: String,
+    tags: BTreeMap<String, String>,
+    lease: Option<ActiveLease>,
+}
+
+/// A lease held on a runner.
+#[derive(Debug)]
+struct ActiveLease {
+    lease_id: String,
+    requester: String,
+    granted: Instant,
+
+    /// When the lease was last granted or renewed.
+    renewed: Instant,
+}
+
+/// A request waiting for a runner.
+#[derive(Debug)]
+struct Waiting<W> {
+    tags: BTreeMap<String, String>,
+    requester: String,
+    queued: Instant,
+    waiter: W,
+}
+
+/// A lease granted to a waiting request.
+#[derive(Debug)]
+pub struct Grant<W> {
+    /// The waiter of the request.
+    pub waiter: W,
+
+    /// The lease granted.
+    pub lease: Lease,
+
+    /// The recorder that requested the lease.
+    pub requester: String,
+
+    /// How long the request waited.
+    pub waited: Duration,
+}
+
+/// A lease that has ended, either because it was released or because it
+/// expired.
+#[derive(Debug, Eq, PartialEq)]
+pub struct EndedLease {
+    pub lease_id: String,
+    pub runner: String,
+    pub requester: String,
+
+    /// How long the lease was held.
+    pub held: Duration,
+}
+
+impl<W> Pool<W> {
+    /// Create an empty pool whose leases last for `lease_duration`.
+    pub fn new(lease_duration: Duration, lease_prefix: String) -> Self {
+        Pool {
+            lease_duration,
+            lease_prefix,
+            leases_granted: 0,
+            runners: BTreeMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Add a runner to the pool, or update the host and tags of a runner
+    /// already in the pool.
+    ///
+    /// Runners register again after restarting, which they do in the middle
+    /// of a session, so any lease on the runner is kept.
+    pub fn register(&mut self, name: String, host: String, tags: BTreeMap<String, String>) {
+        let lease = self.runners.remove(&name).and_then(|runner| runner.lease);
+
+        self.runners
+            .insert(name, PooledRunner { host, tags, lease });
+    }
+
+    /// Queue a request for a runner with the given tags, returning the number
+    /// of requests ahead of it.
+    ///
+    /// Requests that no registered runner could ever serve are refused.
+    pub fn enqueue(
+        &mut self,
+        tags: BTreeMap<String, String>,
+        requester: String,
+        waiter: W,
+        now: Instant,
+    ) -> Result<usize, PoolError> {
+        if !self.runners.values().any(|runner| runner.has_tags(&tags)) {
+            return Err(PoolError::NoMatchingRunner(format_tags(&tags)));
+        }
+
+        self.queue.push_back(Waiting {
+            tags,
+            requester,
+            queued: now,
+            waiter,
+        });
+
+        Ok(self.queue.len() - 1)
+    }
+
+    /// Grant leases on free runners to the waiting requests that they can
+    /// serve.
+    pub fn dispatch(&mut self, now: Instant) -> Vec<Grant<W>> {
+        let mut grants = Vec::new();
+        let mut waiting = VecDeque::new();
+
+        while let Some(request) = self.queue.pop_front() {
+            let free = self
+                .runners
+                .iter_mut()
+                .find(|(_, runner)| runner.lease.is_none() && runner.has_tags(&request.tags));
+
+            let (name, runner) = match free {
+                Some(free) => free,
+                None => {
+                    waiting.push_back(request);
+                    continue;
+                }
+            };
+
+            self.leases_granted += 1;
+            let lease_id = format!("{}-{}", self.lease_prefix, self.leases_granted);
+
+            runner.lease = Some(ActiveLease {
+                lease_id: lease_id.clone(),
+                requester: request.requester.clone(),
+                granted: now,
+                renewed: now,
+            });
+
+            grants.push(Grant {
+                waiter: request.waiter,
+                lease: Lease {
+                    lease_id,
+                    runner: name.clone(),
+                    host: runner.host.clone(),
+                    duration_secs: self.lease_duration.as_secs(),
+                },
+                requester: request.requester,
+                waited: now.duration_since(request.queued),
+            });
+        }
+
+        self.queue = waiting;
+        grants
+    }
+
+    /// Restart the duration of the lease with the given ID.
+    pub fn renew(&mut self, lease_id: &str, now: Instant) -> Result<(), PoolError> {
+        let lease = self
+            .runners
+            .values_mut()
+            .filter_map(|runner| runner.lease.as_mut())
+            .find(|lease| lease.lease_id == lease_id)
+            .ok_or_else(|| PoolError::UnknownLease(lease_id.into()))?;
+
+        lease.renewed = now;
+        Ok(())
+    }
+
+    /// End the lease with the given ID, returning the runner to the pool.
+    pub fn release(&mut self, lease_id: &str, now: Instant) -> Result<EndedLease, PoolError> {
+        for (name, runner) in &mut self.runners {
+            if runner.lease.as_ref().map(|lease| lease.lease_id.as_str()) == Some(lease_id) {
+                let lease = runner.lease.take().unwrap();
+                return Ok(lease.end(name, now));
+            }
+        }
+
+        Err(PoolError::UnknownLease(lease_id.into()))
+    }
+
+    /// End every lease that has not been renewed within the lease duration.
+    pub fn expire(&mut self, now: Instant) -> Vec<EndedLease> {
+        let lease_duration = self.lease_duration;
+        let mut expired = Vec::new();
+
+        for (name, runner) in &mut self.runners {
+            let is_expired = runner
+                .lease
+                .as_ref()
+                .map(|lease| now.duration_since(lease.renewed) >= lease_duration)
+                .unwrap_or(false);
+
+            if is_expired {
+                expired.push(runner.lease.take().unwrap().end(name, now));
+            }
+        }
+
+        expired
+    }
+}
+
+impl PooledRunner {
+    fn has_tags(&self, tags: &BTreeMap<String, String>) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+}
+
+impl ActiveLease {
+    fn end(self, runner: &str, now: Instant) -> EndedLease {
+        EndedLease {
+            lease_id: self.lease_id,
+            runner: runner.into(),
+            requester: self.requester,
+            held: now.duration_since(self.granted),
+        }
+    }
+}
+
+/// Format tags as a comma-separated list of `key=value` pairs.
+fn format_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("No registered runner has the tags {}", .0)]
+    NoMatchingRunner(String),
+
+    #[error("Unknown lease `{}'", .0)]
+    UnknownLease(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags(tags: &[(&str, &str)]) -> BTreeMap<String, String> {
+        tags.iter()
+            .map(|(key, value)| ((*key).into(), (*value).into()))
+            .collect()
+    }
+
+    fn pool() -> Pool<&'static str> {
+        let mut pool = Pool::new(Duration::from_secs(60), "test".into());
+        pool.register(
+            "intel".into(),
+            "10.0.0.3:8888".into(),
+            tags(&[("gpu", "intel")]),
+        );
+        pool.register(
+            "nvidia".into(),
+            "10.0.0.2:8888".into(),
+            tags(&[("gpu", "nvidia")]),
+        );
+        pool
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let start = Instant::now();
+        let mut pool = pool();
+
+        let nvidia = tags(&[("gpu", "nvidia")]);
+        assert_eq!(
+            pool.enqueue(nvidia.clone(), "a".into(), "a", start)
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            pool.enqueue(nvidia.clone(), "b".into(), "b", start)
+                .unwrap(),
+            1
+        );
+        assert_eq!(pool.enqueue(tags(&[]), "c".into(), "c", start).unwrap(), 2);
+
+        // The second request for the nvidia runner must wait, but it does not
+        // hold up the request that any runner can serve.
+        let later = start + Duration::from_secs(5);
+        let grants = pool.dispatch(later);
+        assert_eq!(grants.len(), 2);
+        assert_eq!(grants[0].waiter, "a");
+        assert_eq!(grants[0].lease.runner, "nvidia");
+        assert_eq!(grants[0].lease.host, "10.0.0.2:8888");
+        assert_eq!(grants[0].lease.lease_id, "test-1");
+        assert_eq!(grants[0].waited, Duration::from_secs(5));
+        assert_eq!(grants[1].waiter, "c");
+        assert_eq!(grants[1].lease.runner, "intel");
+
+        assert!(pool.dispatch(later).is_empty());
+
+        // A runner that registers again keeps its lease.
+        pool.register("nvidia".into(), "10.0.0.2:8888".into(), nvidia);
+        assert!(pool.dispatch(later).is_empty());
+
+        let released = pool
+            .release("test-1", later + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            released,
+            EndedLease {
+                lease_id: "test-1".into(),
+                runner: "nvidia".into(),
+                requester: "a".into(),
+                held: Duration::from_secs(10),
+            }
+        );
+
+        let grants = pool.dispatch(later);
+        assert_eq!(grants.len(), 1);
+        assert_eq!(grants[0].waiter, "b");
+        assert_eq!(grants[0].lease.lease_id, "test-3");
+
+        match pool.release("test-1", later) {
+            Err(PoolError::UnknownLease(lease_id)) => assert_eq!(lease_id, "test-1"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match pool.enqueue(tags(&[("gpu", "amd")]), "d".into(), "d", start) {
+            Err(PoolError::NoMatchingRunner(tags)) => assert_eq!(tags, "gpu=amd"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_expire() {
+        let start = Instant::now();
+        let mut pool = pool();
+
+        pool.enqueue(tags(&[]), "a".into(), "a", start).unwrap();
+        assert_eq!(pool.dispatch(start).len(), 1);
+
+        assert!(pool.expire(start + Duration::from_secs(59)).is_empty());
+
+        let expired = pool.expire(start + Duration::from_secs(60));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].runner, "intel");
+        assert_eq!(expired[0].requester, "a");
+
+        pool.enqueue(tags(&[]), "b".into(), "b", start).unwrap();
+        assert_eq!(pool.dispatch(start)[0].lease.runner, "intel");
+
+        // A renewed lease lasts for the lease duration from its renewal, but
+        // is still reported as held since it was granted.
+        pool.renew("test-2", start + Duration::from_secs(50)).unwrap();
+        assert!(pool.expire(start + Duration::from_secs(100)).is_empty());
+
+        let expired = pool.expire(start + Duration::from_secs(110));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].lease_id, "test-2");
+        assert_eq!(expired[0].held, Duration::from_secs(110));
+
+        match pool.renew("test-2", start) {
+            Err(PoolError::UnknownLease(lease_id)) => assert_eq!(lease_id, "test-2"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -2,26 +2,70 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::env::current_dir;
+use std::collections::BTreeMap;
+use std::env::{self, current_dir};
 use std::error::Error;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use libfxrecord::config::read_config;
+use chrono::Utc;
+use libfxrecord::config::{
+    write_new_config, ConfigCommand, ConfigError, ConfigLoader, InitOptions,
+};
+use libfxrecord::discovery::{broadcast_addr, discover, DISCOVERY_PORT};
 use libfxrecord::error::ErrorMessage;
-use libfxrecord::logging::build_terminal_logger;
-use libfxrecord::net::Idle;
+use libfxrecord::logging::{build_logger, build_terminal_logger};
+use libfxrecord::net::broker::{
+    lease, release, renew, BrokerClientProto, Lease, LeaseOutcome, LeaseRelease, LeaseRenewal,
+    LeaseRequest,
+};
+use libfxrecord::net::{
+    BuildTask, Channel, Idle, NetworkConditions, OfficialBuild, ProxyMode, RunOptions,
+    RunnerStatus, SessionType, UploadedArtifact,
+};
 use libfxrecord::prefs::{parse_pref, PrefValue};
-use libfxrecorder::analysis::{compute_visual_metrics, crop_video, VisualMetrics};
-use libfxrecorder::config::Config;
-use libfxrecorder::perfherder::generate_perfherder_metrics;
-use libfxrecorder::proto::RecorderProto;
-use libfxrecorder::recorder::FfmpegRecorder;
-use libfxrecorder::retry::delayed_exponential_retry;
-use slog::{error, info, Logger};
+use libfxrecord::retry::{
+    log_retries, retry, ExponentialBackoff, RetryAttempt, RetryError, RetryPolicy,
+};
+use libfxrecord::timeout::{with_timeout, TimeoutPhase};
+use libfxrecorder::analysis::{
+    compute_visual_metrics, crop_video, Metrics, MetricsOrigin, VisualMetrics,
+};
+use libfxrecorder::archive::{Archive, ArchivedFile};
+use libfxrecorder::bisect::{
+    fetch_pushes, BisectMetric, BisectReport, BisectStep, Bisection, Verdict,
+};
+use libfxrecorder::config::{starter_config, ArchiveConfig, Config, TreeherderConfig};
+use libfxrecorder::extensions::{package_extensions, Extension};
+use libfxrecorder::ffmpeg::list_capture_devices;
+use libfxrecorder::gfx::{GfxToggles, WebRender};
+use libfxrecorder::iterations::{iteration_name, IteratedMetrics, IterationMetrics};
+use libfxrecorder::manifest::Manifest;
+use libfxrecorder::matrix::{
+    combine_variants, EnvMatrix, MatrixError, PrefMatrix, Variant, VariantMetrics,
+};
+use libfxrecorder::perfherder::{
+    generate_perfherder_iterated_metrics, generate_perfherder_matrix_metrics,
+    generate_perfherder_metrics,
+};
+use libfxrecorder::profile::{check_profile, pack_profile, ProfileFilter};
+use libfxrecorder::proto::{
+    launch_name, new_request_id, BuildRequest, RecorderProto, RecorderProtoError, SessionOutput,
+};
+use libfxrecorder::recorder::{FfmpegRecorder, FfmpegRecordingError};
+use libfxrecorder::registry::Registry;
+use libfxrecorder::signing::Signature;
+use libfxrecorder::taskcluster::TaskclusterIndex;
+use libfxrecorder::timeline::{as_millis, Phase, PhaseTimings, Timeline};
+use libfxrecorder::treeherder::{Job, Treeherder};
+use libfxrecorder::tunnel::{connect_runner, RunnerStream};
+use slog::{error, info, o, warn, Logger};
 use structopt::StructOpt;
 use tempfile::TempDir;
 use tokio::net::TcpStream;
@@ -34,111 +78,1983 @@ struct Options {
     #[structopt(long = "config", default_value = "fxrecord.toml")]
     config_path: PathBuf,
 
+    /// The profile in the configuration file to use.
+    ///
+    /// A profile is a table nested in the `fxrecorder` section, e.g.,
+    /// `[fxrecorder.staging]`, whose options override those of the section.
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// The address of the `fxrunner` to connect to.
+    ///
+    /// Overrides the `host` configuration option.
+    #[structopt(long)]
+    host: Option<String>,
+
+    /// Select the first runner in the registry with the given tag instead of
+    /// connecting to the configured host.
+    ///
+    /// If a broker is configured, a runner with the given tag is leased from
+    /// the broker instead.
+    ///
+    /// Tags should be of the form `key=value`, e.g., `gpu=nvidia`. This may
+    /// be given more than once, in which case the runner must have every tag.
+    #[structopt(
+        long = "runner-tag",
+        value_name = "tag",
+        number_of_values(1),
+        conflicts_with = "host",
+        parse(try_from_str = parse_runner_tag)
+    )]
+    runner_tags: Vec<(String, String)>,
+
     #[structopt(subcommand)]
     command: Command,
 
-    /// The path to write the computed visual metrics to.
-    ///
-    /// Defaults to stdout if not provided.
-    #[structopt(long = "output", env = "FXRECORD_OUTPUT_PATH")]
-    output_path: Option<PathBuf>,
+    /// The path to write the computed visual metrics to.
+    ///
+    /// Defaults to stdout if not provided.
+    ///
+    /// When recording, the runner's log of the session and the session's
+    /// timeline are written alongside the metrics with the extensions
+    /// `.fxrunner.log` and `.session.json`, or to `fxrunner.log` and
+    /// `session.json` in the current directory if this is not provided.
+    #[structopt(long = "output", env = "FXRECORD_OUTPUT_PATH")]
+    output_path: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Connect to an FxRunner instance and record a video.
+    ///
+    /// Analysis will be run on the captured video.
+    Record(RecordOptions),
+
+    /// Analyze a recorded video and compute visual metrics.
+    Analyze(AnalyzeOptions),
+
+    /// Print the status of the FxRunner instance as JSON without starting a
+    /// session.
+    Status,
+
+    /// Inspect the configuration.
+    Config(ConfigCommand),
+
+    /// Write a starter configuration file.
+    Init(InitOptions),
+
+    /// List the runners on the local network as JSON.
+    ///
+    /// Runners must be configured with a `discovery_host` to be discovered.
+    Discover(DiscoverOptions),
+
+    /// Verify the signature of a results file.
+    ///
+    /// Requires the `signing` configuration option.
+    Verify(VerifyOptions),
+
+    /// Check that a zipped profile can be used by the runner.
+    ///
+    /// The profile must contain `prefs.js` or `places.sqlite` at its root or
+    /// within a single top-level directory, and no entries that would be
+    /// extracted outside of it.
+    VerifyProfile(VerifyProfileOptions),
+
+    /// Zip a Firefox profile directory for use with `record --profile`.
+    ///
+    /// Lock files and caches are left out unless included with `--include`.
+    PackProfile(PackProfileOptions),
+
+    /// Find the push that regressed a metric between two revisions.
+    ///
+    /// The pushes between the revisions are read from the pushlog and the
+    /// push in the middle of the remaining range is recorded until the
+    /// regressing push is isolated.
+    Bisect(BisectOptions),
+}
+
+/// Record a video from FxRunner and perform analysis.
+#[derive(Clone, Debug, StructOpt)]
+struct RecordOptions {
+    /// The ID of a build task that will be used by the runner.
+    #[structopt(
+        env = "FXRECORD_TASK_ID",
+        required_unless_one = &["index-route", "revision", "release", "nightly", "build-url", "runner-build-path", "upload-build", "good"]
+    )]
+    task_id: Option<String>,
+
+    /// Use the build task indexed at the given Taskcluster index route instead
+    /// of a task ID.
+    #[structopt(
+        long = "index-route",
+        value_name = "route",
+        conflicts_with = "revision"
+    )]
+    index_route: Option<String>,
+
+    /// Use the build task for the given revision instead of a task ID.
+    ///
+    /// The task is looked up in the Taskcluster index by the runner.
+    #[structopt(long = "revision")]
+    revision: Option<String>,
+
+    /// The project that the revision belongs to.
+    #[structopt(long = "project", default_value = "mozilla-central")]
+    project: String,
+
+    /// The build platform to use with the revision.
+    #[structopt(long = "platform", default_value = "win64-shippable-opt")]
+    platform: String,
+
+    /// Wait up to the given number of seconds for the build of the revision
+    /// or index route to be indexed before starting the session.
+    ///
+    /// This allows recording a revision that was just pushed, e.g., with
+    /// `--project try`, once its build task completes.
+    #[structopt(long = "wait-for-build", value_name = "secs")]
+    wait_for_build_secs: Option<u64>,
+
+    /// The build task found by `--wait-for-build`.
+    #[structopt(skip)]
+    indexed_task_id: Option<String>,
+
+    /// The name of the build artifact for the runner to download.
+    ///
+    /// This may be a glob pattern (e.g., `public/build/*.zip`), which must
+    /// match exactly one artifact. If not provided, the runner's configured
+    /// artifact is used.
+    #[structopt(long = "artifact", value_name = "name")]
+    artifact: Option<String>,
+
+    /// Have the runner download the official release of the given version
+    /// from archive.mozilla.org instead of from Taskcluster.
+    #[structopt(
+        long = "release",
+        value_name = "version",
+        conflicts_with_all = &["index-route", "revision", "nightly", "build-url", "runner-build-path", "upload-build"]
+    )]
+    release: Option<String>,
+
+    /// The channel of the release.
+    ///
+    /// One of `release`, `beta`, `esr`, or `devedition`.
+    #[structopt(long = "channel", default_value = "release", parse(try_from_str = parse_channel))]
+    channel: Channel,
+
+    /// Have the runner download the nightly build from the given date
+    /// (YYYY-MM-DD) from archive.mozilla.org instead of from Taskcluster.
+    #[structopt(
+        long = "nightly",
+        value_name = "date",
+        conflicts_with_all = &["index-route", "revision", "build-url", "runner-build-path", "upload-build"]
+    )]
+    nightly: Option<String>,
+
+    /// Have the runner download the build archive from the given URL instead
+    /// of from Taskcluster.
+    #[structopt(
+        long = "build-url",
+        value_name = "url",
+        conflicts_with_all = &["index-route", "revision", "runner-build-path", "upload-build"]
+    )]
+    build_url: Option<String>,
+
+    /// Have the runner use the build archive at the given path on the runner
+    /// instead of downloading one.
+    #[structopt(
+        long = "runner-build-path",
+        value_name = "path",
+        conflicts_with_all = &["index-route", "revision", "upload-build"]
+    )]
+    runner_build_path: Option<PathBuf>,
+
+    /// Send the build archive at the given path to the runner instead of
+    /// having it download one.
+    #[structopt(
+        long = "upload-build",
+        value_name = "path",
+        conflicts_with_all = &["index-route", "revision"]
+    )]
+    upload_build_path: Option<PathBuf>,
+
+    /// The path to a zipped Firefox profile for the runner to use.
+    ///
+    /// If not provided, the runner will create a new profile.
+    #[structopt(long = "profile")]
+    profile_path: Option<PathBuf>,
+
+    /// Only send the files in the profile that differ from the last profile
+    /// the runner received.
+    ///
+    /// The runner caches the last profile it received, so repeated runs with
+    /// the same conditioned profile only send the files that changed.
+    #[structopt(long = "profile-delta", requires = "profile-path")]
+    profile_delta: bool,
+
+    /// The name of a profile template on the runner to use.
+    ///
+    /// Templates are profiles provisioned ahead of time in the runner's
+    /// `profiles_dir`. The runner uses a fresh copy of the template.
+    #[structopt(
+        long = "profile-template",
+        value_name = "name",
+        conflicts_with = "profile-path"
+    )]
+    profile_template: Option<String>,
+
+    /// The locale to run Firefox in, e.g., `de` or `ja`.
+    ///
+    /// Builds from archive.mozilla.org are downloaded in this locale. Other
+    /// builds have the language pack for their version installed.
+    #[structopt(long = "locale", value_name = "locale")]
+    locale: Option<String>,
+
+    /// Extensions for the runner to install into the profile.
+    ///
+    /// Extensions are either paths to XPI files or the IDs or slugs of
+    /// extensions on addons.mozilla.org, whose latest versions are
+    /// downloaded.
+    #[structopt(long = "extension", value_name = "xpi-or-id", number_of_values(1))]
+    extensions: Vec<Extension>,
+
+    /// The path to a zipped distribution for the runner to install into the
+    /// build, simulating a partner repack.
+    ///
+    /// The archive must contain a `distribution.ini`, either at its root or
+    /// within a single top-level directory.
+    #[structopt(long = "distribution", value_name = "path")]
+    distribution_path: Option<PathBuf>,
+
+    /// Preferences that the runner should use.
+    ///
+    /// Preferences should be of the form `pref.name:value` where value is a
+    /// string, boolean, or number.
+    #[structopt(long = "pref", number_of_values(1), parse(try_from_str = parse_pref))]
+    prefs: Vec<(String, PrefValue)>,
+
+    /// Force how Firefox uses WebRender: `on`, `software`, or `off`.
+    ///
+    /// The relevant prefs and environment variables are set before any given
+    /// with `--pref` or `--env`, and the results are labelled with the mode.
+    #[structopt(long = "webrender", value_name = "mode", parse(try_from_str = parse_webrender))]
+    webrender: Option<WebRender>,
+
+    /// Force Firefox's GPU process `on` or `off`.
+    ///
+    /// The relevant prefs are set before any given with `--pref`, and the
+    /// results are labelled with the setting.
+    #[structopt(long = "gpu-process", value_name = "on|off", parse(try_from_str = parse_toggle))]
+    gpu_process: Option<bool>,
+
+    /// Record the build once for each variant in the given pref matrix.
+    ///
+    /// A pref matrix is a TOML file of `[[variant]]` tables, each with a
+    /// `name` and a table of `prefs` that are set in addition to those given
+    /// with `--pref`. The metrics of every variant are reported together.
+    #[structopt(
+        long = "pref-matrix",
+        value_name = "path",
+        conflicts_with_all = &["return-profile", "record-archive"]
+    )]
+    pref_matrix_path: Option<PathBuf>,
+
+    /// Environment variables to set for Firefox on the runner.
+    ///
+    /// Variables should be of the form `NAME=value`.
+    #[structopt(long = "env", number_of_values(1), parse(try_from_str = parse_env))]
+    env: Vec<(String, String)>,
+
+    /// Record the build once for each combination of environment variables
+    /// in the given environment matrix.
+    ///
+    /// An environment matrix is a TOML file with an `env` table that lists
+    /// the values of each variable, e.g., `MOZ_FEATURE = ["0", "1"]`. Each
+    /// combination is labelled by its values. If a pref matrix is also given,
+    /// every combination of both matrices is recorded.
+    #[structopt(
+        long = "env-matrix",
+        value_name = "path",
+        conflicts_with_all = &["return-profile", "record-archive"]
+    )]
+    env_matrix_path: Option<PathBuf>,
+
+    /// Record the session the given number of times and report summary
+    /// statistics of every metric.
+    #[structopt(
+        long = "iterations",
+        value_name = "N",
+        parse(try_from_str = parse_iterations),
+        conflicts_with_all = &["pref-matrix", "env-matrix", "return-profile", "record-archive"]
+    )]
+    iterations: Option<u32>,
+
+    /// Record the given number of warm-up iterations before the measured
+    /// iterations.
+    ///
+    /// The first run after the runner downloads a build is systematically
+    /// different from those after it. Warm-up iterations are saved and marked
+    /// as such, but are excluded from the summary statistics.
+    #[structopt(
+        long = "warmup",
+        value_name = "N",
+        conflicts_with_all = &["pref-matrix", "env-matrix", "return-profile", "record-archive"]
+    )]
+    warmup: Option<u32>,
+
+    /// Re-run an iteration that fails up to the given number of times instead
+    /// of failing the whole run.
+    ///
+    /// The number of retries each iteration needed is reported with its
+    /// metrics. The files of each retry are written with a `.retry-N` suffix
+    /// on the iteration's name.
+    #[structopt(long = "iteration-retries", value_name = "N", default_value = "0")]
+    iteration_retries: u32,
+
+    /// Launch Firefox the given number of times in a single session and
+    /// report summary statistics of every metric.
+    ///
+    /// Every launch uses the build the runner downloaded for the session, and
+    /// each launch after the first is a warm start. The video of each launch
+    /// is written to a `launch-N` directory.
+    #[structopt(
+        long = "launches",
+        value_name = "N",
+        parse(try_from_str = parse_launches),
+        conflicts_with_all = &["iterations", "warmup", "pref-matrix", "env-matrix"]
+    )]
+    launches: Option<u32>,
+
+    /// Reset the profile to its state before the first launch before each
+    /// subsequent launch, instead of reusing it.
+    #[structopt(long = "reset-profile", requires = "launches")]
+    reset_profile: bool,
+
+    /// Have the runner wait the given number of seconds between launches, so
+    /// that one launch does not interfere with the next.
+    #[structopt(long = "cool-down", value_name = "secs", requires = "launches")]
+    cool_down_secs: Option<u64>,
+
+    /// Have the runner also wait for its disk to become idle between launches,
+    /// e.g., for Firefox's delayed writes and the search indexer to finish.
+    #[structopt(long = "cool-down-disk-idle", requires = "launches")]
+    cool_down_disk_idle: bool,
+
+    /// Restart the runner before every launch, so that every launch is a cold
+    /// start.
+    ///
+    /// The session is resumed once per launch, reconnecting to the runner
+    /// after each restart. The profile, pings, and memory report that the
+    /// runner returns are those of the last launch.
+    #[structopt(
+        long = "restart-between-launches",
+        requires = "launches",
+        conflicts_with_all = &["cool-down", "cool-down-disk-idle"]
+    )]
+    restart_between_launches: bool,
+
+    /// If the runner is serving another recorder, poll it until it is free
+    /// for up to the given number of seconds instead of failing.
+    #[structopt(long = "wait-timeout", value_name = "secs")]
+    wait_timeout_secs: Option<u64>,
+
+    /// Do not require the runner to become idle before running Firefox.
+    #[structopt(long)]
+    skip_idle: bool,
+
+    /// Do not delete the video after analysis.
+    #[structopt(long = "keep-video")]
+    keep_video: bool,
+
+    /// Measure the session without capturing video.
+    ///
+    /// The runner lets Firefox finish starting up, and startup is measured by
+    /// Firefox's `firstPaint` marker, i.e., from process launch to the first
+    /// window being painted. No visual metrics are computed, so this does not
+    /// need capture hardware.
+    #[structopt(long = "headless-measurement", conflicts_with = "keep-video")]
+    headless_measurement: bool,
+
+    /// Measure a page load of the given URL instead of a cold start.
+    ///
+    /// Firefox will be started and allowed to settle before recording begins.
+    /// Recording begins when the runner navigates to the URL.
+    #[structopt(long = "pageload", value_name = "url")]
+    pageload_url: Option<String>,
+
+    /// Record all network traffic during the session into the named archive.
+    ///
+    /// The archive is stored in the runner's proxy archive directory.
+    #[structopt(long = "record-archive", value_name = "name")]
+    record_archive: Option<String>,
+
+    /// Replay network traffic from the named archive during the session.
+    ///
+    /// The archive must exist in the runner's proxy archive directory.
+    #[structopt(
+        long = "replay-archive",
+        value_name = "name",
+        conflicts_with = "record-archive"
+    )]
+    replay_archive: Option<String>,
+
+    /// Override the address of a host name on the runner for the session.
+    ///
+    /// Overrides should be of the form `host=address`.
+    #[structopt(long = "host-override", number_of_values(1), parse(try_from_str = parse_host_override))]
+    host_overrides: Vec<(String, IpAddr)>,
+
+    /// Set the runner's timezone for the session, e.g., `UTC` or `Pacific
+    /// Standard Time`.
+    ///
+    /// The runner's previous timezone is restored once the session finishes.
+    #[structopt(long = "timezone", value_name = "tz")]
+    timezone: Option<String>,
+
+    /// Synchronize the runner's clock with its time server before the
+    /// session.
+    #[structopt(long = "sync-clock")]
+    sync_clock: bool,
+
+    /// Start Firefox in safe mode, which disables extensions, themes, and
+    /// hardware acceleration.
+    ///
+    /// Results recorded in safe mode are labelled `safe-mode`.
+    #[structopt(long = "safe-mode")]
+    safe_mode: bool,
+
+    /// Emulate the given network conditions on the runner.
+    ///
+    /// Conditions are either one of the profiles `3g`, `3gfast`, `4g`, or
+    /// `cable`, or of the form `download:upload:latency`, where bandwidth is
+    /// in kilobits per second (zero is unlimited) and latency is the round
+    /// trip time in milliseconds.
+    #[structopt(long = "network", parse(try_from_str = parse_network_conditions))]
+    network: Option<NetworkConditions>,
+
+    /// Have the runner send back the profile after the run and write it to the
+    /// given path as a zip file.
+    #[structopt(long = "return-profile", value_name = "path")]
+    return_profile_path: Option<PathBuf>,
+
+    /// Have the runner upload large artifacts, such as the returned profile,
+    /// to its artifact store instead of sending them back.
+    ///
+    /// The locations of the uploaded artifacts are written next to the
+    /// output in `artifacts.json`.
+    #[structopt(long)]
+    upload_artifacts: bool,
+
+    /// Have the runner capture the telemetry pings Firefox submits during the
+    /// session.
+    ///
+    /// Firefox submits its pings to a server on the runner instead of to
+    /// Mozilla. The pings are written next to the output in `pings.zip`.
+    #[structopt(long = "capture-pings")]
+    capture_pings: bool,
+
+    /// Have the runner collect a memory report from Firefox once startup has
+    /// settled, as with "Save..." in `about:memory`.
+    ///
+    /// The report is collected over Marionette after the recording finishes,
+    /// so Firefox runs with Marionette enabled. The report is written next to
+    /// the output in `memory-report.json.gz`.
+    #[structopt(long = "memory-report")]
+    memory_report: bool,
+
+    /// Submit the results to Treeherder as a job against the revision of the
+    /// recorded build, so that Perfherder shows them next to those from CI.
+    ///
+    /// Requires the `treeherder` configuration option.
+    #[structopt(long = "submit-treeherder")]
+    submit_treeherder: bool,
+}
+
+impl RecordOptions {
+    /// Return the build that the runner should use.
+    fn build(&self) -> BuildRequest<'_> {
+        if let Some(ref version) = self.release {
+            BuildRequest::MozillaArchive(OfficialBuild::Release {
+                channel: self.channel,
+                version: version.clone(),
+            })
+        } else if let Some(ref date) = self.nightly {
+            BuildRequest::MozillaArchive(OfficialBuild::Nightly { date: date.clone() })
+        } else if let Some(ref url) = self.build_url {
+            BuildRequest::Url(url)
+        } else if let Some(ref path) = self.runner_build_path {
+            BuildRequest::RunnerPath(path)
+        } else if let Some(ref path) = self.upload_build_path {
+            BuildRequest::Upload(path)
+        } else {
+            BuildRequest::Taskcluster {
+                task: self.build_task(),
+                artifact: self.artifact.as_deref(),
+            }
+        }
+    }
+
+    /// Return the Taskcluster build task that the runner should use.
+    fn build_task(&self) -> BuildTask {
+        if let Some(ref task_id) = self.indexed_task_id {
+            BuildTask::TaskId(task_id.clone())
+        } else if let Some(ref route) = self.index_route {
+            BuildTask::IndexRoute(route.clone())
+        } else if let Some(ref revision) = self.revision {
+            BuildTask::Revision {
+                project: self.project.clone(),
+                revision: revision.clone(),
+                platform: self.platform.clone(),
+            }
+        } else {
+            BuildTask::TaskId(self.task_id.clone().expect("no task ID"))
+        }
+    }
+
+    /// Return the name of the build being recorded, which the archived files
+    /// of its sessions are grouped under.
+    fn archive_task(&self) -> String {
+        match self.build() {
+            BuildRequest::Taskcluster { task, .. } => match task {
+                BuildTask::TaskId(task_id) => task_id,
+                BuildTask::IndexRoute(route) => route,
+                BuildTask::Revision {
+                    project, revision, ..
+                } => format!("{}-{}", project, revision),
+            },
+            BuildRequest::MozillaArchive(OfficialBuild::Release { channel, version }) => {
+                format!("{}-{}", channel, version)
+            }
+            BuildRequest::MozillaArchive(OfficialBuild::Nightly { date }) => {
+                format!("nightly-{}", date)
+            }
+            BuildRequest::Url(..) => "url".into(),
+            BuildRequest::RunnerPath(..) => "runner-path".into(),
+            BuildRequest::Upload(..) => "upload".into(),
+        }
+    }
+
+    /// The graphics configuration to force.
+    fn gfx_toggles(&self) -> GfxToggles {
+        GfxToggles {
+            webrender: self.webrender,
+            gpu_process: self.gpu_process,
+        }
+    }
+
+    /// The labels that distinguish the results of the session.
+    fn labels(&self) -> Vec<String> {
+        let mut labels = self.gfx_toggles().labels();
+        if self.safe_mode {
+            labels.push("safe-mode".into());
+        }
+        labels
+    }
+
+    /// The prefs to set, including those that force the graphics
+    /// configuration.
+    fn prefs(&self) -> Vec<(String, PrefValue)> {
+        let mut prefs = self.gfx_toggles().prefs();
+        prefs.extend(self.prefs.iter().cloned());
+        prefs
+    }
+
+    /// The options for the session run to request from the runner.
+    fn run_options(&self) -> RunOptions {
+        let session_type = match self.pageload_url {
+            Some(ref url) => SessionType::PageLoad { url: url.clone() },
+            None => SessionType::ColdStart,
+        };
+
+        let proxy = match (&self.record_archive, &self.replay_archive) {
+            (Some(archive), _) => Some(ProxyMode::Record {
+                archive: archive.clone(),
+            }),
+            (None, Some(archive)) => Some(ProxyMode::Replay {
+                archive: archive.clone(),
+            }),
+            (None, None) => None,
+        };
+
+        RunOptions {
+            session_type,
+            proxy,
+            host_overrides: self.host_overrides.clone(),
+            network: self.network,
+            return_profile: self.return_profile_path.is_some(),
+            env: self
+                .gfx_toggles()
+                .env()
+                .into_iter()
+                .chain(self.env.iter().cloned())
+                .collect(),
+            upload_artifacts: self.upload_artifacts,
+            capture_pings: self.capture_pings,
+            memory_report: self.memory_report,
+            timezone: self.timezone.clone(),
+            sync_clock: self.sync_clock,
+            safe_mode: self.safe_mode,
+            headless_measurement: self.headless_measurement,
+            relaunches: if self.restart_between_launches {
+                0
+            } else {
+                self.launches.unwrap_or(1) - 1
+            },
+            reset_profile: self.reset_profile,
+            cool_down: Duration::from_secs(self.cool_down_secs.unwrap_or(0)),
+            cool_down_disk_idle: self.cool_down_disk_idle,
+            restart_after: false,
+        }
+    }
+
+    /// Return the variants to record, if recording a matrix.
+    fn matrix(&self) -> Result<Option<Vec<Variant>>, MatrixError> {
+        let prefs = match self.pref_matrix_path {
+            Some(ref path) => Some(PrefMatrix::load(path)?.variants()),
+            None => None,
+        };
+
+        let env = match self.env_matrix_path {
+            Some(ref path) => Some(EnvMatrix::load(path)?.variants()),
+            None => None,
+        };
+
+        Ok(match (prefs, env) {
+            (Some(prefs), Some(env)) => Some(combine_variants(&prefs, &env)),
+            (prefs, env) => prefs.or(env),
+        })
+    }
+
+    /// Return the number of warm-up and measured iterations to record, if
+    /// recording more than a single session.
+    fn iterations(&self) -> Option<(u32, u32)> {
+        match (self.warmup, self.iterations) {
+            (None, None) => None,
+            (warmup, iterations) => Some((warmup.unwrap_or(0), iterations.unwrap_or(1))),
+        }
+    }
+}
+
+/// Discover runners on the local network.
+#[derive(Debug, StructOpt)]
+struct DiscoverOptions {
+    /// The port that runners answer discovery probes on.
+    ///
+    /// Defaults to 8890.
+    #[structopt(long)]
+    port: Option<u16>,
+
+    /// How long to wait for runners to answer, in seconds.
+    #[structopt(long = "wait-secs", default_value = "2")]
+    wait_secs: u64,
+}
+
+/// Bisect a regression between two revisions.
+#[derive(Debug, StructOpt)]
+struct BisectOptions {
+    /// The last revision known not to have regressed.
+    #[structopt(long, value_name = "revision")]
+    good: String,
+
+    /// The first revision known to have regressed.
+    ///
+    /// Both revisions must belong to the project given with `--project`.
+    #[structopt(long, value_name = "revision")]
+    bad: String,
+
+    /// The metric that regressed, e.g., `speedindex` or `startupduration`.
+    #[structopt(long, default_value = "speedindex")]
+    metric: BisectMetric,
+
+    /// How much worse than the good revision a push must be to have
+    /// regressed, as a percentage.
+    #[structopt(long, default_value = "5%", parse(try_from_str = parse_threshold))]
+    threshold: f64,
+
+    /// How each revision is recorded.
+    ///
+    /// With `--iterations`, the median of the measured iterations is compared.
+    #[structopt(flatten)]
+    record: RecordOptions,
+}
+
+/// Verify the signature of a results file.
+#[derive(Debug, StructOpt)]
+struct VerifyOptions {
+    /// The results file to verify.
+    results_path: PathBuf,
+
+    /// The signature of the results.
+    ///
+    /// Defaults to the `sig.json` file written alongside the results.
+    #[structopt(long = "signature", value_name = "path")]
+    signature_path: Option<PathBuf>,
+}
+
+/// Check a zipped profile.
+#[derive(Debug, StructOpt)]
+struct VerifyProfileOptions {
+    /// The zipped profile to check.
+    profile_path: PathBuf,
+}
+
+/// Zip a Firefox profile directory.
+#[derive(Debug, StructOpt)]
+struct PackProfileOptions {
+    /// The profile directory to pack.
+    profile_dir: PathBuf,
+
+    /// The zip archive to write.
+    archive_path: PathBuf,
+
+    /// Pack entries matching the given glob pattern even if they are
+    /// excluded.
+    ///
+    /// Patterns without a `/` match entries with that name at any depth.
+    /// Other patterns match paths from the root of the profile.
+    #[structopt(long = "include", value_name = "pattern", number_of_values(1))]
+    include: Vec<String>,
+
+    /// Leave out entries matching the given glob pattern, in addition to lock
+    /// files and caches.
+    #[structopt(long = "exclude", value_name = "pattern", number_of_values(1))]
+    exclude: Vec<String>,
+}
+
+/// Analyze a pre-recorded video.
+#[derive(Debug, StructOpt)]
+struct AnalyzeOptions {
+    /// The video to analyze.
+    video_path: PathBuf,
+
+    /// The video is of a page load instead of a cold start.
+    #[structopt(long)]
+    pageload: bool,
+}
+
+impl AnalyzeOptions {
+    /// The event that the computed metrics will be relative to.
+    fn metrics_origin(&self) -> MetricsOrigin {
+        if self.pageload {
+            MetricsOrigin::Navigation
+        } else {
+            MetricsOrigin::Launch
+        }
+    }
+}
+
+/// Verify the signature of a results file and return the exit status.
+fn verify_results(log: &Logger, config: &Config, options: &VerifyOptions) -> i32 {
+    let result = || -> Result<(), Box<dyn Error>> {
+        let signing = config
+            .signing
+            .as_ref()
+            .ok_or("verify requires the `signing' configuration option")?;
+
+        let signature_path = match options.signature_path {
+            Some(ref signature_path) => signature_path.clone(),
+            None => output_sibling(Some(&options.results_path), None, "sig.json"),
+        };
+
+        let results = std::fs::read(&options.results_path)?;
+        let signature: Signature = serde_json::from_slice(&std::fs::read(&signature_path)?)?;
+
+        signature.verify(&signing.key_id, signing.key.resolve()?.as_bytes(), &results)?;
+        Ok(())
+    }();
+
+    match result {
+        Ok(()) => {
+            info!(log, "Results are signed"; "path" => options.results_path.display());
+            0
+        }
+        Err(e) => {
+            error!(log, "Could not verify results"; "path" => options.results_path.display(), "error" => %e);
+            1
+        }
+    }
+}
+
+/// Check a zipped profile, printing every problem found, and return the exit
+/// status.
+fn verify_profile(options: &VerifyProfileOptions) -> i32 {
+    let problems = match check_profile(&options.profile_path) {
+        Ok(problems) => problems,
+        Err(e) => {
+            eprintln!(
+                "error: could not read `{}': {}",
+                options.profile_path.display(),
+                e
+            );
+            return 1;
+        }
+    };
+
+    if problems.is_empty() {
+        println!("Profile is valid.");
+        return 0;
+    }
+
+    for problem in &problems {
+        eprintln!("error: {}", problem);
+    }
+
+    1
+}
+
+/// Zip a profile directory, check the archive, and return the exit status.
+fn pack_profile_dir(options: &PackProfileOptions) -> i32 {
+    let filter = ProfileFilter {
+        include: options.include.clone(),
+        exclude: options.exclude.clone(),
+    };
+
+    let packed = match pack_profile(&options.profile_dir, &options.archive_path, &filter)
+        .and_then(|packed| Ok((packed, check_profile(&options.archive_path)?)))
+    {
+        Ok((packed, problems)) if problems.is_empty() => packed,
+        Ok((_, problems)) => {
+            for problem in &problems {
+                eprintln!("error: {}", problem);
+            }
+            return 1;
+        }
+        Err(e) => {
+            eprintln!(
+                "error: could not pack `{}': {}",
+                options.profile_dir.display(),
+                e
+            );
+            return 1;
+        }
+    };
+
+    println!(
+        "Packed {} files into `{}'.",
+        packed,
+        options.archive_path.display()
+    );
+    0
+}
+
+/// Load the configuration from the built-in defaults, the configuration file,
+/// `FXRECORDER_*` environment variables, and command-line flags, in increasing
+/// order of precedence.
+fn load_config(options: &Options) -> Result<Config, ConfigError> {
+    config_loader(options)?.load()
+}
+
+/// Check the configuration, printing every problem found, and return the exit
+/// status.
+fn check_config(options: &Options) -> i32 {
+    let issues = match config_loader(options) {
+        Ok(loader) => loader.check::<Config>(),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+
+    if issues.is_empty() {
+        println!("Configuration is valid.");
+        return 0;
+    }
+
+    for issue in &issues {
+        eprintln!("error: {}", issue);
+    }
+
+    1
+}
+
+/// Write a starter configuration file and return the exit status.
+fn init_config(options: &Options, init_options: &InitOptions) -> i32 {
+    let visual_metrics_path = match current_dir() {
+        Ok(dir) => dir.join("vendor").join("visualmetrics.py"),
+        Err(e) => {
+            eprintln!("error: could not determine current directory: {}", e);
+            return 1;
+        }
+    };
+
+    // ffmpeg may not be installed yet, in which case the device is left for
+    // the user to fill in.
+    let device = list_capture_devices()
+        .ok()
+        .and_then(|devices| devices.into_iter().next());
+
+    let contents = starter_config(&visual_metrics_path, device.as_deref());
+
+    match write_new_config(&options.config_path, &contents, init_options.force) {
+        Ok(()) => {
+            println!("Wrote `{}'.", options.config_path.display());
+            0
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!(
+                "error: `{}' already exists; pass --force to overwrite it",
+                options.config_path.display()
+            );
+            1
+        }
+        Err(e) => {
+            eprintln!(
+                "error: could not write `{}': {}",
+                options.config_path.display(),
+                e
+            );
+            1
+        }
+    }
+}
+
+/// Print the runners that answer a discovery probe as JSON and return the exit
+/// status.
+#[tokio::main]
+async fn discover_runners(options: &DiscoverOptions) -> i32 {
+    let target = broadcast_addr(options.port.unwrap_or(DISCOVERY_PORT));
+
+    match discover(target, Duration::from_secs(options.wait_secs)).await {
+        Ok(runners) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&runners).expect("could not serialize runners")
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("error: could not discover runners: {}", e);
+            1
+        }
+    }
+}
+
+fn config_loader(options: &Options) -> Result<ConfigLoader, ConfigError> {
+    Ok(ConfigLoader::new("fxrecorder")
+        .set_default("host", "127.0.0.1:8888")
+        .file_with_profile(&options.config_path, options.profile.as_deref())?
+        .env("FXRECORDER_")
+        .set_some("host", options.host.clone()))
+}
+
+fn main() {
+    let mut options = Options::from_args();
+
+    match options.command {
+        Command::Config(ConfigCommand::Check) => exit(check_config(&options)),
+        Command::Init(ref init_options) => exit(init_config(&options, init_options)),
+        Command::Discover(ref discover_options) => exit(discover_runners(discover_options)),
+        Command::VerifyProfile(ref verify_profile_options) => {
+            exit(verify_profile(verify_profile_options))
+        }
+        Command::PackProfile(ref pack_profile_options) => {
+            exit(pack_profile_dir(pack_profile_options))
+        }
+        _ => {}
+    }
+
+    // Without a configuration, there is nowhere to log to yet.
+    let mut config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+    };
+
+    let log = if config.log.is_empty() {
+        build_terminal_logger()
+    } else {
+        build_logger(&config.log).expect("Could not open log")
+    };
+
+    info!(log, "read command-line options"; "options" => ?options);
+    info!(log, "Loaded configuration"; "config" => ?config);
+
+    if let Command::Verify(ref verify_options) = options.command {
+        let status = verify_results(&log, &config, verify_options);
+        drop(log);
+        exit(status);
+    }
+
+    if let Err(e) = select_runner(&log, &options, &mut config) {
+        error!(log, "Could not select a runner"; "error" => %e);
+        drop(log);
+        exit(1);
+    }
+
+    if let Command::Status = options.command {
+        match runner_status(log.clone(), &config) {
+            Ok(status) => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&status).expect("could not serialize status")
+                );
+                exit(0);
+            }
+            Err(e) => {
+                error!(log, "Could not get runner status"; "error" => %e);
+                drop(log);
+                exit(1);
+            }
+        }
+    }
+
+    // Wait for the build before leasing a runner, so that a runner is not
+    // held while the build is still in progress.
+    if let Command::Record(ref mut record_options) = options.command {
+        if let Err(e) = wait_for_build(&log, &config, record_options) {
+            error!(log, "Could not find the build"; "error" => %e);
+            drop(log);
+            exit(1);
+        }
+    }
+
+    // Recordings made through a broker must first lease a runner, which is
+    // renewed while it is in use and released once the recording is finished.
+    let lease = match (&options.command, config.broker.clone()) {
+        (Command::Record(..), Some(broker)) | (Command::Bisect(..), Some(broker)) => {
+            match lease_runner(log.clone(), &options, &broker) {
+                Ok(lease) => {
+                    config.host = lease.host.clone();
+                    let renewer = LeaseRenewer::start(log.clone(), broker.clone(), lease.clone());
+                    Some((broker, lease, renewer))
+                }
+                Err(e) => {
+                    error!(log, "Could not lease a runner"; "broker" => &broker, "error" => %e);
+                    drop(log);
+                    exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let result = || -> Result<(), Box<dyn Error>> {
+        if let Command::Bisect(ref bisect_options) = options.command {
+            let report = bisect(
+                log.clone(),
+                &config,
+                bisect_options,
+                options.output_path.as_deref(),
+            )?;
+
+            info!(
+                log,
+                "Bisection finished";
+                "last_good" => &report.last_good,
+                "first_bad" => &report.first_bad,
+                "suspects" => report.suspects.len(),
+            );
+
+            let report_json =
+                serde_json::to_string(&report).expect("could not serialize bisect report");
+            if let Some(output_path) = options.output_path.as_deref() {
+                std::fs::write(output_path, report_json)?;
+            } else {
+                println!("{}", report_json);
+            }
+
+            return Ok(());
+        }
+
+        let suite = match options.command {
+            Command::Record(RecordOptions {
+                pageload_url: Some(..),
+                ..
+            })
+            | Command::Analyze(AnalyzeOptions { pageload: true, .. }) => "pageload",
+            _ => "firstrun",
+        };
+
+        let started = Utc::now();
+        let (metrics_json, perfherder_metrics, build) = match options.command {
+            Command::Record(ref record_options) => {
+                match (record_options.matrix()?, record_options.iterations()) {
+                    (None, Some((warmup, iterations))) => {
+                        let metrics = record_iterations(
+                            log.clone(),
+                            &config,
+                            record_options,
+                            warmup,
+                            iterations,
+                            options.output_path.as_deref(),
+                        )?;
+
+                        // Every iteration runs the same build.
+                        let build = metrics
+                            .iterations
+                            .first()
+                            .and_then(|iteration| iteration.metrics.build.clone());
+
+                        (
+                            serde_json::to_string(&metrics)
+                                .expect("could not serialize visual metrics"),
+                            generate_perfherder_iterated_metrics(&metrics, suite),
+                            build,
+                        )
+                    }
+
+                    (Some(variants), _) => {
+                        let results = record_matrix(
+                            log.clone(),
+                            &config,
+                            record_options,
+                            &variants,
+                            options.output_path.as_deref(),
+                        )?;
+
+                        // Every variant runs the same build.
+                        let build = results
+                            .first()
+                            .and_then(|result| result.metrics.build.clone());
+
+                        (
+                            serde_json::to_string(&results)
+                                .expect("could not serialize visual metrics"),
+                            generate_perfherder_matrix_metrics(&results, suite),
+                            build,
+                        )
+                    }
+
+                    (None, None) => {
+                        let mut launches = record(
+                            log.clone(),
+                            &config,
+                            record_options,
+                            options.output_path.as_deref(),
+                        )?;
+
+                        if launches.len() == 1 {
+                            let metrics = launches.remove(0);
+
+                            (
+                                serde_json::to_string(&metrics)
+                                    .expect("could not serialize visual metrics"),
+                                generate_perfherder_metrics(&metrics, suite),
+                                metrics.build.clone(),
+                            )
+                        } else {
+                            // Each launch is reported as a measured iteration.
+                            let metrics = IteratedMetrics::new(
+                                launches
+                                    .into_iter()
+                                    .zip(1..)
+                                    .map(|(metrics, iteration)| IterationMetrics {
+                                        iteration,
+                                        warmup: false,
+                                        retries: 0,
+                                        metrics,
+                                    })
+                                    .collect(),
+                            );
+
+                            // Every launch runs the same build.
+                            let build = metrics
+                                .iterations
+                                .first()
+                                .and_then(|iteration| iteration.metrics.build.clone());
+
+                            (
+                                serde_json::to_string(&metrics)
+                                    .expect("could not serialize visual metrics"),
+                                generate_perfherder_iterated_metrics(&metrics, suite),
+                                build,
+                            )
+                        }
+                    }
+                }
+            }
+
+            Command::Analyze(ref analyze_options) => {
+                let analysis_start = Instant::now();
+
+                let mut metrics =
+                    Metrics::from(analyze_video(log.clone(), &config, analyze_options)?);
+                metrics.timings.analysis = Some(as_millis(analysis_start.elapsed()));
+
+                (
+                    serde_json::to_string(&metrics).expect("could not serialize visual metrics"),
+                    generate_perfherder_metrics(&metrics, suite),
+                    None,
+                )
+            }
+
+            Command::Status
+            | Command::Config(..)
+            | Command::Init(..)
+            | Command::Discover(..)
+            | Command::Verify(..)
+            | Command::VerifyProfile(..)
+            | Command::PackProfile(..)
+            | Command::Bisect(..) => {
+                unreachable!()
+            }
+        };
+
+        let perfherder_json = serde_json::to_string(&perfherder_metrics)
+            .expect("could not serialize perfherder metrics");
+
+        if let Some(output_path) = options.output_path.as_deref() {
+            let mut f = File::create(output_path)?;
+            write!(f, "{}", metrics_json)?;
+        } else {
+            println!("{}", metrics_json);
+        }
+
+        if let Some(ref signing) = config.signing {
+            let signature = Signature::sign(
+                &signing.key_id,
+                signing.key.resolve()?.as_bytes(),
+                metrics_json.as_bytes(),
+            );
+
+            let signature_path = output_sibling(options.output_path.as_deref(), None, "sig.json");
+            std::fs::write(
+                &signature_path,
+                serde_json::to_string_pretty(&signature).expect("could not serialize signature"),
+            )?;
+            info!(log, "Signed results"; "path" => signature_path.display(), "key_id" => &signing.key_id);
+        }
+
+        println!("PERFHERDER_DATA: {}", perfherder_json);
+
+        if let Command::Record(ref record_options) = options.command {
+            if record_options.submit_treeherder {
+                let treeherder_config = config
+                    .treeherder
+                    .as_ref()
+                    .ok_or("--submit-treeherder requires the `treeherder' configuration option")?;
+
+                // Prefer the revision the runner reported over the one that
+                // was requested, which may be abbreviated.
+                let revision = build
+                    .as_ref()
+                    .and_then(|build| build.source_stamp.clone())
+                    .or_else(|| record_options.revision.clone())
+                    .ok_or("cannot submit to Treeherder: the revision of the build is unknown")?;
+
+                submit_to_treeherder(
+                    log.clone(),
+                    treeherder_config,
+                    &Job {
+                        revision: &revision,
+                        name: suite,
+                        machine: runner_machine(&config.host),
+                        start: started,
+                        end: Utc::now(),
+                        performance_data: &perfherder_metrics,
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }();
+
+    if let Some((broker, lease, renewer)) = lease {
+        renewer.stop();
+
+        let outcome = match result {
+            Ok(()) => LeaseOutcome::Succeeded,
+            Err(ref e) => LeaseOutcome::Failed(e.to_string()),
+        };
+
+        if let Err(e) = release_runner(&broker, &lease, outcome) {
+            warn!(log, "Could not release runner"; "lease_id" => &lease.lease_id, "error" => %e);
+        }
+    }
+
+    if let Err(e) = result {
+        error!(log, "unexpected error"; "error" => %e);
+        drop(log);
+        exit(1);
+    }
+}
+
+/// Wait for the build task requested by `--wait-for-build` to be indexed and
+/// use it for the recording.
+#[tokio::main]
+async fn wait_for_build(
+    log: &Logger,
+    config: &Config,
+    options: &mut RecordOptions,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = match options.wait_for_build_secs {
+        Some(secs) => Duration::from_secs(secs),
+        None => return Ok(()),
+    };
+
+    if options.index_route.is_none() && options.revision.is_none() {
+        return Err("--wait-for-build requires --revision or --index-route".into());
+    }
+
+    let route = options
+        .build_task()
+        .index_route()
+        .expect("revisions and index routes have an index route");
+
+    info!(log, "Waiting for build"; "route" => &route, "timeout_secs" => timeout.as_secs());
+
+    let index = TaskclusterIndex::new(&config.taskcluster_root_url);
+    let task_id = index.wait_for_task(log, &route, timeout).await?;
+
+    info!(log, "Found build"; "route" => &route, "task_id" => &task_id);
+    options.indexed_task_id = Some(task_id);
+
+    Ok(())
+}
+
+/// Submit the job to Treeherder.
+#[tokio::main]
+async fn submit_to_treeherder(
+    log: Logger,
+    config: &TreeherderConfig,
+    job: &Job<'_>,
+) -> Result<(), Box<dyn Error>> {
+    let treeherder = Treeherder::new(config)?;
+    let job_guid = treeherder.submit(job).await?;
+
+    info!(
+        log,
+        "submitted job to Treeherder";
+        "repository" => &config.repository,
+        "revision" => job.revision,
+        "job_guid" => job_guid,
+    );
+
+    Ok(())
+}
+
+/// Return the name of the machine at the given `HOST:PORT` address.
+fn runner_machine(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(idx) => &host[..idx],
+        None => host,
+    }
+}
+
+/// Lease a runner with the tags given by `--runner-tag` from the broker,
+/// waiting until one is free.
+#[tokio::main]
+async fn lease_runner(
+    log: Logger,
+    options: &Options,
+    broker: &str,
+) -> Result<Lease, Box<dyn Error>> {
+    let stream = TcpStream::connect(broker).await?;
+    let mut proto = BrokerClientProto::new(stream);
+
+    let requester = env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "fxrecorder".into());
+
+    let request = LeaseRequest {
+        tags: options.runner_tags.iter().cloned().collect(),
+        requester,
+    };
+
+    let lease = lease(&mut proto, request, |position| {
+        info!(log, "Waiting for a runner"; "broker" => broker, "position" => position);
+    })
+    .await?;
+
+    info!(
+        log, "Leased runner";
+        "runner" => &lease.runner,
+        "host" => &lease.host,
+        "lease_id" => &lease.lease_id,
+    );
+
+    Ok(lease)
+}
+
+/// Renews a lease in the background until it is stopped.
+struct LeaseRenewer {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl LeaseRenewer {
+    /// Renew the lease every third of its duration, so that the runner is not
+    /// reclaimed while a long recording or bisection is still using it.
+    fn start(log: Logger, broker: String, lease: Lease) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let interval = Duration::from_secs((lease.duration_secs / 3).max(1));
+
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = renew_lease(&broker, &lease) {
+                    warn!(log, "Could not renew lease"; "lease_id" => &lease.lease_id, "error" => %e);
+                }
+            }
+        });
+
+        LeaseRenewer { stop, thread }
+    }
+
+    /// Stop renewing the lease, waiting for any renewal in progress.
+    fn stop(self) {
+        drop(self.stop);
+        self.thread.join().expect("lease renewal thread panicked");
+    }
+}
+
+/// Renew the lease on a runner leased from the broker.
+#[tokio::main]
+async fn renew_lease(broker: &str, lease: &Lease) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect(broker).await?;
+    let mut proto = BrokerClientProto::new(stream);
+
+    renew(
+        &mut proto,
+        LeaseRenewal {
+            lease_id: lease.lease_id.clone(),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Return a leased runner to the broker.
+#[tokio::main]
+async fn release_runner(
+    broker: &str,
+    lease: &Lease,
+    outcome: LeaseOutcome,
+) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect(broker).await?;
+    let mut proto = BrokerClientProto::new(stream);
+
+    release(
+        &mut proto,
+        LeaseRelease {
+            lease_id: lease.lease_id.clone(),
+            outcome,
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Replace the configured host with the first runner in the registry that has
+/// every tag given with `--runner-tag`.
+fn select_runner(
+    log: &Logger,
+    options: &Options,
+    config: &mut Config,
+) -> Result<(), Box<dyn Error>> {
+    // Runners leased from a broker are selected by the broker.
+    if options.runner_tags.is_empty() || config.broker.is_some() {
+        return Ok(());
+    }
+
+    let registry_path = config
+        .registry
+        .as_ref()
+        .ok_or("`--runner-tag' requires the `registry' configuration option")?;
+    let registry = Registry::load(registry_path)?;
+    let runner = registry.select(&options.runner_tags)?;
+
+    info!(log, "Selected runner"; "name" => &runner.name, "host" => &runner.host);
+    config.host = runner.host.clone();
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn runner_status(log: Logger, config: &Config) -> Result<RunnerStatus, Box<dyn Error>> {
+    let stream = connect_runner(&config.host, config.ssh.as_ref()).await?;
+    info!(log, "Connected"; "peer" => &config.host);
+
+    let mut proto = RecorderProto::new(
+        log.clone(),
+        stream,
+        FfmpegRecorder::new(log.clone(), &config.recording),
+    );
+
+    Ok(proto.ping().await?)
+}
+
+/// Return the path of a file to write alongside the metrics.
+///
+/// The files of each variant of a matrix run (or each iteration of a repeated
+/// run) are distinguished by its name.
+fn output_sibling(output_path: Option<&Path>, name: Option<&str>, extension: &str) -> PathBuf {
+    let extension = match name {
+        Some(name) => format!("{}.{}", name, extension),
+        None => extension.into(),
+    };
+
+    match output_path {
+        Some(output_path) => output_path.with_extension(extension),
+        None => PathBuf::from(extension),
+    }
+}
+
+#[tokio::main]
+async fn record(
+    log: Logger,
+    config: &Config,
+    options: &RecordOptions,
+    output_path: Option<&Path>,
+) -> Result<Vec<Metrics>, Box<dyn Error>> {
+    record_with_timeline(log, config, options, output_path, None, None).await
+}
+
+/// Record the session repeatedly, starting with the warm-up iterations.
+#[tokio::main]
+async fn record_iterations(
+    log: Logger,
+    config: &Config,
+    options: &RecordOptions,
+    warmup: u32,
+    iterations: u32,
+    output_path: Option<&Path>,
+) -> Result<IteratedMetrics, Box<dyn Error>> {
+    let mut results = Vec::with_capacity((warmup + iterations) as usize);
+
+    for iteration in 1..=warmup + iterations {
+        let warmup = iteration <= warmup;
+        let name = iteration_name(iteration, warmup);
+        let log = log.new(o!("iteration" => iteration, "warmup" => warmup));
+        info!(log, "Recording iteration");
+
+        let mut retries = 0;
+        let metrics = loop {
+            let attempt_name = match retries {
+                0 => name.clone(),
+                n => format!("{}.retry-{}", name, n),
+            };
+
+            match record_with_timeline(
+                log.new(o!("retry" => retries)),
+                config,
+                options,
+                output_path,
+                None,
+                Some(&attempt_name),
+            )
+            .await
+            {
+                // Iterations only launch Firefox once per session.
+                Ok(mut launches) => break launches.remove(0),
+                Err(e) if retries < options.iteration_retries => {
+                    retries += 1;
+                    warn!(log, "Iteration failed; retrying"; "error" => %e, "retry" => retries);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        results.push(IterationMetrics {
+            iteration,
+            warmup,
+            retries,
+            metrics,
+        });
+    }
+
+    Ok(IteratedMetrics::new(results))
 }
 
-#[derive(Debug, StructOpt)]
-enum Command {
-    /// Connect to an FxRunner instance and record a video.
-    ///
-    /// Analysis will be run on the captured video.
-    Record(RecordOptions),
+/// Record a session for each variant of a matrix in order.
+#[tokio::main]
+async fn record_matrix(
+    log: Logger,
+    config: &Config,
+    options: &RecordOptions,
+    variants: &[Variant],
+    output_path: Option<&Path>,
+) -> Result<Vec<VariantMetrics>, Box<dyn Error>> {
+    let mut results = Vec::with_capacity(variants.len());
 
-    /// Analyze a recorded video and compute visual metrics.
-    Analyze(AnalyzeOptions),
+    for variant in variants {
+        let log = log.new(o!("variant" => variant.name.clone()));
+        info!(log, "Recording variant"; "labels" => ?variant.labels);
+
+        let metrics = record_with_timeline(
+            log,
+            config,
+            options,
+            output_path,
+            Some(variant),
+            Some(&variant.name),
+        )
+        .await?
+        .remove(0);
+
+        results.push(VariantMetrics {
+            name: variant.name.clone(),
+            labels: variant.labels.clone(),
+            prefs: variant.prefs.clone(),
+            env: variant.env.clone(),
+            metrics,
+        });
+    }
+
+    Ok(results)
 }
 
-/// Record a video from FxRunner and perform analysis.
-#[derive(Debug, StructOpt)]
-struct RecordOptions {
-    /// The ID of a build task that will be used by the runner.
-    #[structopt(env = "FXRECORD_TASK_ID")]
-    task_id: String,
+/// Bisect the pushes between the good and bad revisions.
+#[tokio::main]
+async fn bisect(
+    log: Logger,
+    config: &Config,
+    options: &BisectOptions,
+    output_path: Option<&Path>,
+) -> Result<BisectReport, Box<dyn Error>> {
+    let pushes = fetch_pushes(&options.record.project, &options.good, &options.bad).await?;
+    if pushes.is_empty() {
+        return Err("there are no pushes after the good revision up to the bad revision".into());
+    }
+    info!(log, "Fetched pushlog"; "pushes" => pushes.len());
 
-    /// The path to a zipped Firefox profile for the runner to use.
-    ///
-    /// If not provided, the runner will create a new profile.
-    #[structopt(long = "profile")]
-    profile_path: Option<PathBuf>,
+    // The good revision is first, followed by the tip of each push.
+    let revisions = Some(options.good.clone())
+        .into_iter()
+        .chain(pushes.iter().map(|push| push.revision.clone()))
+        .collect::<Vec<_>>();
+    let last = revisions.len() - 1;
 
-    /// Preferences that the runner should use.
-    ///
-    /// Preferences should be of the form `pref.name:value` where value is a
-    /// string, boolean, or number.
-    #[structopt(long = "pref", number_of_values(1), parse(try_from_str = parse_pref))]
-    prefs: Vec<(String, PrefValue)>,
+    let mut steps = Vec::new();
 
-    /// Do not require the runner to become idle before running Firefox.
-    #[structopt(long)]
-    skip_idle: bool,
+    let baseline = measure_revision(&log, config, options, &revisions[0], output_path)
+        .await?
+        .ok_or("the good revision did not report the metric")?;
+    steps.push(BisectStep {
+        revision: revisions[0].clone(),
+        value: Some(baseline),
+        verdict: Verdict::Good,
+    });
 
-    /// Do not delete the video after analysis.
-    #[structopt(long = "keep-video")]
-    keep_video: bool,
+    let mut bisection = Bisection::new(revisions.len(), baseline, options.threshold);
+
+    let regressed = measure_revision(&log, config, options, &revisions[last], output_path)
+        .await?
+        .ok_or("the bad revision did not report the metric")?;
+    steps.push(BisectStep {
+        revision: revisions[last].clone(),
+        value: Some(regressed),
+        verdict: Verdict::Bad,
+    });
+
+    if !bisection.is_regression(regressed) {
+        return Err(format!(
+            "the bad revision did not regress {} beyond the threshold ({} vs. {})",
+            options.metric, regressed, baseline
+        )
+        .into());
+    }
+
+    while let Some(index) = bisection.next() {
+        let revision = &revisions[index];
+
+        // Not every push is built, in which case the runner cannot find its
+        // build in the index.
+        let (value, verdict) = match measure_revision(&log, config, options, revision, output_path)
+            .await
+        {
+            Ok(Some(value)) => (Some(value), bisection.record(index, value)),
+            Ok(None) => {
+                warn!(
+                    log,
+                    "Revision did not report the metric; skipping it";
+                    "revision" => revision,
+                );
+                (None, bisection.skip(index))
+            }
+            Err(e) => {
+                warn!(
+                    log,
+                    "Could not record revision; skipping it";
+                    "revision" => revision,
+                    "error" => %e,
+                );
+                (None, bisection.skip(index))
+            }
+        };
+
+        info!(
+            log,
+            "Bisected revision";
+            "revision" => revision,
+            "value" => ?value,
+            "verdict" => ?verdict,
+            "remaining" => bisection.remaining(),
+        );
+
+        steps.push(BisectStep {
+            revision: revision.clone(),
+            value,
+            verdict,
+        });
+    }
+
+    // Every revision after the first is the tip of the push before it.
+    let (good, bad) = bisection.range();
+    Ok(BisectReport {
+        metric: options.metric,
+        threshold: options.threshold,
+        steps,
+        last_good: revisions[good].clone(),
+        first_bad: revisions[bad].clone(),
+        suspects: pushes[good..bad].to_vec(),
+    })
 }
 
-/// Analyze a pre-recorded video.
-#[derive(Debug, StructOpt)]
-struct AnalyzeOptions {
-    /// The video to analyze.
-    video_path: PathBuf,
+/// Record the build of the given revision and return the median value of the
+/// bisected metric.
+async fn measure_revision(
+    log: &Logger,
+    config: &Config,
+    options: &BisectOptions,
+    revision: &str,
+    output_path: Option<&Path>,
+) -> Result<Option<f64>, Box<dyn Error>> {
+    let record_options = RecordOptions {
+        task_id: None,
+        index_route: None,
+        revision: Some(revision.into()),
+        ..options.record.clone()
+    };
+
+    let log = log.new(o!("revision" => revision.to_owned()));
+    info!(log, "Recording revision");
+
+    let (warmup, iterations) = record_options.iterations().unwrap_or((0, 1));
+    let mut measured = Vec::new();
+
+    for iteration in 1..=warmup + iterations {
+        let warmup = iteration <= warmup;
+        let name = format!("{}.{}", revision, iteration_name(iteration, warmup));
+
+        let launches = record_with_timeline(
+            log.new(o!("iteration" => iteration, "warmup" => warmup)),
+            config,
+            &record_options,
+            output_path,
+            None,
+            Some(&name),
+        )
+        .await?;
+
+        if !warmup {
+            measured.extend(launches);
+        }
+    }
+
+    Ok(options.metric.median(&measured))
 }
 
-fn main() {
-    let log = build_terminal_logger();
+async fn record_with_timeline(
+    log: Logger,
+    config: &Config,
+    options: &RecordOptions,
+    output_path: Option<&Path>,
+    variant: Option<&Variant>,
+    name: Option<&str>,
+) -> Result<Vec<Metrics>, Box<dyn Error>> {
+    let timeline = Timeline::default();
+    let mut files = SessionFiles::new()?;
 
-    let options = Options::from_args();
-    info!(log, "read command-line options"; "options" => ?options);
+    let result = record_session(
+        log.clone(),
+        config,
+        options,
+        output_path,
+        variant,
+        name,
+        &timeline,
+        &mut files,
+    )
+    .await;
 
-    let result = || -> Result<(), Box<dyn Error>> {
-        let config: Config = read_config(&options.config_path, "fxrecorder")?;
+    // The timeline is written even if the session failed, as that is when it
+    // is most useful.
+    let timeline_path = output_sibling(output_path, name, "session.json");
+    match timeline.write(&timeline_path) {
+        Ok(()) => {
+            info!(log, "session timeline written to disk"; "path" => timeline_path.display());
+            files.paths.push(timeline_path);
+        }
+        Err(e) => warn!(log, "could not write session timeline"; "error" => %e),
+    }
 
-        let metrics = match options.command {
-            Command::Record(ref record_options) => record(log.clone(), config, record_options),
-            Command::Analyze(ref analyze_options) => {
-                analyze_video(log.clone(), config, &analyze_options)
+    // The manifest is written last so that it lists every other file.
+    let manifest_path = output_sibling(output_path, name, "manifest.json");
+    let manifest = Manifest::new(&files.paths, &files.reported_sizes, &files.uploaded)
+        .and_then(|manifest| manifest.write(&manifest_path).map(|_| manifest));
+    match manifest {
+        Ok(manifest) => {
+            for entry in manifest.mismatches() {
+                error!(
+                    log,
+                    "file does not match what the runner reported sending";
+                    "path" => entry.path.display(),
+                    "size" => entry.size,
+                    "reported_size" => entry.reported_size,
+                );
             }
-        }?;
 
-        let metrics_json =
-            serde_json::to_string(&metrics).expect("could not serialize visual metrics");
-
-        let perfherder_metrics = serde_json::to_string(&generate_perfherder_metrics(&metrics))
-            .expect("could not serialize perfherder metrics");
+            info!(log, "manifest written to disk"; "path" => manifest_path.display());
+            files.paths.push(manifest_path);
+        }
+        Err(e) => warn!(log, "could not write manifest"; "error" => %e),
+    }
 
-        if let Some(output_path) = options.output_path.as_deref() {
-            let mut f = File::create(output_path)?;
-            write!(f, "{}", metrics_json)?;
-        } else {
-            println!("{}", metrics_json);
+    let archived = match (&config.archive, &files.session_id) {
+        (Some(archive_config), Some(session_id)) => {
+            archive_session(&log, archive_config, options, session_id, &files.paths).await
         }
+        _ => Vec::new(),
+    };
 
-        println!("PERFHERDER_DATA: {}", perfherder_metrics);
+    // Every launch shares the files of the session.
+    result.map(|launches| {
+        launches
+            .into_iter()
+            .map(|metrics| Metrics {
+                archived: archived.clone(),
+                ..metrics
+            })
+            .collect()
+    })
+}
 
-        Ok(())
-    }();
+/// The files written by a session, which are archived once it finishes.
+struct SessionFiles {
+    /// The directory that files that are not kept are written to.
+    tempdir: TempDir,
 
-    if let Err(e) = result {
-        error!(log, "unexpected error"; "error" => %e);
-        drop(log);
-        exit(1);
+    /// The ID of the session, once the runner has created it.
+    session_id: Option<String>,
+
+    /// The files written by the session.
+    paths: Vec<PathBuf>,
+
+    /// The sizes the runner reported sending files with, by the path they
+    /// were written to.
+    reported_sizes: BTreeMap<PathBuf, u64>,
+
+    /// The artifacts the runner uploaded instead of sending.
+    uploaded: Vec<UploadedArtifact>,
+}
+
+impl SessionFiles {
+    fn new() -> Result<Self, io::Error> {
+        Ok(SessionFiles {
+            tempdir: TempDir::new()?,
+            session_id: None,
+            paths: Vec::new(),
+            reported_sizes: BTreeMap::new(),
+            uploaded: Vec::new(),
+        })
+    }
+
+    /// Add a copy of a file that the runner sent, which keeps the size the
+    /// runner reported sending it with.
+    fn push_copy(&mut self, path: PathBuf, source: &Path, reported_sizes: &BTreeMap<PathBuf, u64>) {
+        if let Some(&size) = reported_sizes.get(source) {
+            self.reported_sizes.insert(path.clone(), size);
+        }
+        self.paths.push(path);
     }
 }
 
-#[tokio::main]
-async fn record(
+/// Upload the files of the session to the archive.
+///
+/// Failing to archive a file does not fail the session.
+async fn archive_session(
+    log: &Logger,
+    config: &ArchiveConfig,
+    options: &RecordOptions,
+    session_id: &str,
+    paths: &[PathBuf],
+) -> Vec<ArchivedFile> {
+    let archive = match Archive::new(config, &options.archive_task()) {
+        Ok(archive) => archive,
+        Err(e) => {
+            warn!(log, "could not open archive"; "error" => %e);
+            return Vec::new();
+        }
+    };
+
+    let mut archived = Vec::with_capacity(paths.len());
+    for path in paths {
+        match archive.archive_file(session_id, path).await {
+            Ok(file) => {
+                info!(log, "archived file"; "path" => path.display(), "url" => &file.object.url);
+                archived.push(file);
+            }
+            Err(e) => warn!(log, "could not archive file"; "path" => path.display(), "error" => %e),
+        }
+    }
+
+    archived
+}
+
+/// Reconnect to the runner once it has restarted.
+async fn reconnect_runner(
+    log: &Logger,
+    config: &Config,
+    timeline: &Timeline,
+) -> Result<RunnerStream, Box<dyn Error>> {
+    let reconnect = || {
+        info!(log, "Attempting re-connection to runner...");
+        connect_runner(&config.host, config.ssh.as_ref())
+    };
+
+    // Without jitter, this will attempt to reconnect for 0:30 + 1:00 + 2:00 +
+    // 4:00 = 7:30.
+    let policy = ExponentialBackoff::new(Duration::from_secs(30), 4)
+        .delay_first()
+        .jitter(config.reconnect_jitter)
+        .on_retry(log_retries::<io::Error>(log.clone()));
+    let stream = with_timeout(
+        TimeoutPhase::RestartReconnect,
+        config.timeouts.get(TimeoutPhase::RestartReconnect),
+        retry(policy, reconnect),
+    )
+    .await
+    .map_err(|e| {
+        error!(log, "Runner did not restart in time"; "error" => %e);
+        e
+    })?
+    .inspect_err(|e| {
+        error!(
+            log,
+            "Could not connect to runner";
+            "last_error" => %e.source().unwrap()
+        );
+    })?;
+
+    timeline.record(Phase::Reconnected);
+    info!(log, "Re-connected"; "peer" => &config.host);
+
+    Ok(stream)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn record_session(
     log: Logger,
-    config: Config,
+    config: &Config,
     options: &RecordOptions,
-) -> Result<VisualMetrics, Box<dyn Error>> {
-    let tempdir = TempDir::new().expect("could not create temp directory");
+    output_path: Option<&Path>,
+    variant: Option<&Variant>,
+    name: Option<&str>,
+    timeline: &Timeline,
+    files: &mut SessionFiles,
+) -> Result<Vec<Metrics>, Box<dyn Error>> {
+    let mut run_options = options.run_options();
+    let prefs = match variant {
+        Some(variant) => {
+            run_options.env = variant.env_over(&run_options.env);
+            variant.prefs_over(&options.prefs())
+        }
+        None => options.prefs(),
+    };
 
     if let Some(ref profile_path) = &options.profile_path {
         let meta = tokio::fs::metadata(profile_path).await?;
@@ -148,54 +2064,120 @@ async fn record(
         }
     }
 
+    let extensions_path = if options.extensions.is_empty() {
+        None
+    } else {
+        let extensions_path = files.tempdir.path().join("extensions.zip");
+        package_extensions(&options.extensions, &extensions_path).await?;
+        info!(log, "Packaged extensions"; "count" => options.extensions.len());
+        Some(extensions_path)
+    };
+
     let session_id = {
-        let stream = TcpStream::connect(&config.host).await?;
-        info!(log, "Connected"; "peer" => &config.host);
+        // Every attempt at the request has the same ID, so that the runner
+        // does not acquire the build again when the request is retried after a
+        // network error.
+        let request_id = new_request_id();
 
-        // TODO: Ideally we would split new_session and resume_session into
-        //       static methods so that we do not need to specify the recorder here.
-        let mut proto = RecorderProto::new(
-            log.clone(),
-            stream,
-            FfmpegRecorder::new(log.clone(), &config.recording),
-        );
+        let new_session = || {
+            let log = log.clone();
+            let request_id = request_id.clone();
+            let extensions_path = extensions_path.clone();
+            let prefs = &prefs;
 
-        proto
-            .new_session(
-                &options.task_id,
-                options.profile_path.as_deref(),
-                &options.prefs,
-            )
-            .await?
-    };
+            async move {
+                let stream = connect_runner(&config.host, config.ssh.as_ref()).await?;
+                timeline.record(Phase::Connected);
+                info!(log, "Connected"; "peer" => &config.host);
 
-    info!(log, "Disconnected from runner. Waiting to reconnect...");
+                // TODO: Ideally we would split new_session and resume_session into
+                //       static methods so that we do not need to specify the recorder here.
+                let mut proto = RecorderProto::new(
+                    log.clone(),
+                    stream,
+                    FfmpegRecorder::new(log.clone(), &config.recording),
+                )
+                .with_timeline(timeline.clone())
+                .with_timeouts(config.timeouts.clone())
+                .with_profile_delta(options.profile_delta)
+                .with_profile_template(options.profile_template.clone())
+                .with_locale(options.locale.clone())
+                .with_extensions(extensions_path)
+                .with_distribution(options.distribution_path.clone())
+                .with_request_id(Some(request_id));
 
-    let recording_path = {
-        let reconnect = || {
-            info!(log, "Attempting re-connection to runner...");
-            TcpStream::connect(&config.host)
+                proto
+                    .new_session(options.build(), options.profile_path.as_deref(), prefs)
+                    .await
+            }
         };
 
-        // This will attempt to reconnect for 0:30 + 1:00 + 2:00 + 4:00 = 7:30.
-        let stream = delayed_exponential_retry(reconnect, Duration::from_secs(30), 4)
-            .await
-            .map_err(|e| {
-                error!(
-                    log,
-                    "Could not connect to runner";
-                    "last_error" => %e.source().unwrap()
-                );
-                e
-            })?;
+        let new_session_with_retries = {
+            let new_session = &new_session;
+            let log = &log;
+
+            move || async move {
+                let policy = ExponentialBackoff::new(Duration::from_secs(5), 3)
+                    .retry_if(|e: &RecorderProtoError<FfmpegRecordingError>| e.is_network_error())
+                    .on_retry(log_retries(log.clone()));
+
+                retry(policy, new_session)
+                    .await
+                    .map_err(RetryError::into_source)
+            }
+        };
+
+        match options.wait_timeout_secs {
+            Some(wait_timeout_secs) => {
+                // Poll often enough that the runner is not left idle for long
+                // once it is free.
+                let log_busy =
+                    |attempt: &RetryAttempt<'_, RecorderProtoError<FfmpegRecordingError>>| {
+                        if let Some(delay) = attempt.delay {
+                            info!(
+                                log,
+                                "Runner is busy; waiting for it to be free";
+                                "delay" => ?delay,
+                            );
+                        }
+                    };
+
+                let policy = ExponentialBackoff::new(Duration::from_secs(10), u32::MAX)
+                    .max_delay(Duration::from_secs(60))
+                    .max_elapsed(Duration::from_secs(wait_timeout_secs))
+                    .retry_if(|e: &RecorderProtoError<FfmpegRecordingError>| e.is_busy())
+                    .on_retry(log_busy);
+
+                retry(policy, new_session_with_retries)
+                    .await
+                    .map_err(RetryError::into_source)?
+            }
+            None => new_session_with_retries().await?,
+        }
+    };
+    files.session_id = Some(session_id.clone());
 
-        info!(log, "Re-connected"; "peer" => &config.host);
+    info!(log, "Disconnected from runner. Waiting to reconnect...");
+
+    // When restarting between launches, the session is resumed once for each
+    // launch and the runner restarts after every launch but the last.
+    let resumes = if options.restart_between_launches {
+        options.launches.unwrap_or(1)
+    } else {
+        1
+    };
+    let mut session_output: Option<SessionOutput> = None;
+
+    for resume in 1..=resumes {
+        let stream = reconnect_runner(&log, config, timeline).await?;
 
         let mut proto = RecorderProto::new(
             log.clone(),
             stream,
             FfmpegRecorder::new(log.clone(), &config.recording),
-        );
+        )
+        .with_timeline(timeline.clone())
+        .with_timeouts(config.timeouts.clone());
 
         let idle = if options.skip_idle {
             Idle::Skip
@@ -203,35 +2185,157 @@ async fn record(
             Idle::Wait
         };
 
-        let recording_dir = if options.keep_video {
+        let mut recording_dir = if options.keep_video {
             current_dir()?
         } else {
-            tempdir.path().into()
+            files.tempdir.path().into()
         };
 
-        proto
-            .resume_session(&session_id, idle, &recording_dir)
-            .await?
-    };
+        if options.restart_between_launches {
+            run_options.restart_after = resume < resumes;
+            recording_dir = recording_dir.join(launch_name(resume));
+            tokio::fs::create_dir_all(&recording_dir).await?;
+        }
+
+        let output = proto
+            .resume_session(&session_id, idle, &run_options, &recording_dir)
+            .await?;
+
+        session_output = Some(match session_output {
+            Some(previous) => previous.followed_by(output),
+            None => output,
+        });
+
+        if resume < resumes {
+            info!(log, "Disconnected from runner. Waiting to reconnect..."; "launch" => resume);
+        }
+    }
+    let session_output = session_output.expect("session was not resumed");
 
     info!(log, "disconnected from FxRunner");
 
-    if options.keep_video {
-        info!(log, "video written to disk"; "path" => recording_path.display());
+    for recording_path in session_output
+        .launches
+        .iter()
+        .filter_map(|launch| launch.recording_path.as_ref())
+    {
+        if options.keep_video {
+            info!(log, "video written to disk"; "path" => recording_path.display());
+        }
+        files.paths.push(recording_path.clone());
     }
 
-    analyze_video(
-        log,
-        config,
-        &AnalyzeOptions {
-            video_path: recording_path,
-        },
-    )
+    if let (Some(profile_path), Some(target_path)) = (
+        session_output.profile_path.as_deref(),
+        options.return_profile_path.as_deref(),
+    ) {
+        tokio::fs::copy(profile_path, target_path).await?;
+        info!(log, "profile written to disk"; "path" => target_path.display());
+        files.push_copy(
+            target_path.into(),
+            profile_path,
+            &session_output.reported_sizes,
+        );
+    }
+
+    if let Some(ref pings_path) = session_output.pings_path {
+        let target_path = output_sibling(output_path, name, "pings.zip");
+        tokio::fs::copy(pings_path, &target_path).await?;
+        info!(log, "pings written to disk"; "path" => target_path.display());
+        files.push_copy(target_path, pings_path, &session_output.reported_sizes);
+    }
+
+    if let Some(ref report_path) = session_output.memory_report_path {
+        let target_path = output_sibling(output_path, name, "memory-report.json.gz");
+        tokio::fs::copy(report_path, &target_path).await?;
+        info!(log, "memory report written to disk"; "path" => target_path.display());
+        files.push_copy(target_path, report_path, &session_output.reported_sizes);
+    }
+
+    if !session_output.artifacts.is_empty() {
+        let manifest_path = output_sibling(output_path, name, "artifacts.json");
+        let manifest = serde_json::to_string_pretty(&session_output.artifacts)?;
+        tokio::fs::write(&manifest_path, manifest).await?;
+        info!(log, "artifact manifest written to disk"; "path" => manifest_path.display());
+        files.paths.push(manifest_path);
+    }
+    files.uploaded = session_output.artifacts;
+
+    if let Some(runner_log) = session_output.runner_log {
+        let runner_log_path = output_sibling(output_path, name, "fxrunner.log");
+        tokio::fs::write(&runner_log_path, runner_log).await?;
+        info!(log, "runner log written to disk"; "path" => runner_log_path.display());
+        files.paths.push(runner_log_path);
+    }
+
+    let mut results = Vec::with_capacity(session_output.launches.len());
+    for launch in session_output.launches {
+        let launch_to_first_paint = launch.startup_metrics.get("firstPaint").cloned();
+
+        let (visual_metrics, startup_duration, analysis) = match launch.recording_path {
+            Some(recording_path) => {
+                let analysis_start = Instant::now();
+                let visual_metrics = analyze_video(
+                    log.clone(),
+                    config,
+                    &AnalyzeOptions {
+                        video_path: recording_path,
+                        pageload: options.pageload_url.is_some(),
+                    },
+                )?;
+
+                (
+                    Some(visual_metrics),
+                    None,
+                    Some(as_millis(analysis_start.elapsed())),
+                )
+            }
+
+            None => {
+                if launch_to_first_paint.is_none() {
+                    warn!(
+                        log,
+                        "Firefox did not report when it first painted; startup duration is unknown"
+                    );
+                }
+
+                (None, launch_to_first_paint, None)
+            }
+        };
+
+        let timings = PhaseTimings {
+            restart: launch
+                .boot_timings
+                .and_then(|boot_timings| boot_timings.restart)
+                .map(as_millis),
+            boot_to_desktop: launch
+                .boot_timings
+                .map(|boot_timings| as_millis(boot_timings.boot_to_desktop)),
+            launch_to_first_paint,
+            analysis,
+            ..timeline.timings()
+        };
+
+        results.push(Metrics {
+            visual_metrics,
+            startup_duration,
+            startup_telemetry: launch.startup_metrics,
+            startup_cache: launch.startup_cache,
+            graphics: launch.graphics,
+            build: session_output.build.clone(),
+            labels: options.labels(),
+            bandwidth: session_output.bandwidth,
+            timings,
+            archived: Vec::new(),
+        });
+    }
+
+    Ok(results)
 }
 
 fn analyze_video(
     log: Logger,
-    config: Config,
+    config: &Config,
     options: &AnalyzeOptions,
 ) -> Result<VisualMetrics, Box<dyn Error>> {
     info!(log, "analyzing video"; "video" => &options.video_path.display());
@@ -246,9 +2350,151 @@ fn analyze_video(
         &config.visual_metrics_path,
         &cropped_video_path,
         working_dir.path(),
+        options.metrics_origin(),
     )?;
 
     info!(log, "computed visual metrics"; "metrics" => ?metrics);
 
     Ok(metrics)
 }
+
+/// Parse a host override of the form `host=address`.
+fn parse_host_override(s: &str) -> Result<(String, IpAddr), String> {
+    let idx = s
+        .find('=')
+        .ok_or_else(|| "expected host override of the form `host=address'".to_string())?;
+    let (host, rest) = s.split_at(idx);
+    let addr = rest[1..]
+        .parse::<IpAddr>()
+        .map_err(|e| format!("invalid address `{}': {}", &rest[1..], e))?;
+
+    Ok((host.into(), addr))
+}
+
+/// Parse an environment variable of the form `NAME=value`.
+fn parse_env(s: &str) -> Result<(String, String), String> {
+    let idx = s
+        .find('=')
+        .ok_or_else(|| "expected environment variable of the form `NAME=value'".to_string())?;
+    let (name, rest) = s.split_at(idx);
+
+    if name.is_empty() {
+        return Err("environment variable name cannot be empty".into());
+    }
+
+    Ok((name.into(), rest[1..].into()))
+}
+
+/// Parse a runner tag of the form `key=value`.
+fn parse_runner_tag(s: &str) -> Result<(String, String), String> {
+    let idx = s
+        .find('=')
+        .ok_or_else(|| "expected runner tag of the form `key=value'".to_string())?;
+    let (key, rest) = s.split_at(idx);
+
+    if key.is_empty() {
+        return Err("runner tag name cannot be empty".into());
+    }
+
+    Ok((key.into(), rest[1..].into()))
+}
+
+/// Parse a percentage threshold (e.g., `5%`) as a fraction.
+fn parse_threshold(s: &str) -> Result<f64, String> {
+    let percent = s
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .map_err(|e| format!("invalid threshold `{}': {}", s, e))?;
+
+    if !percent.is_finite() || percent < 0.0 {
+        return Err(format!("invalid threshold `{}': must not be negative", s));
+    }
+
+    Ok(percent / 100.0)
+}
+
+/// Parse a number of launches, which must be at least one.
+fn parse_launches(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("there must be at least one launch".into()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("invalid number of launches `{}': {}", s, e)),
+    }
+}
+
+/// Parse a number of iterations, which must be at least one.
+fn parse_iterations(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("there must be at least one iteration".into()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("invalid number of iterations `{}': {}", s, e)),
+    }
+}
+
+/// Parse a release channel.
+fn parse_webrender(s: &str) -> Result<WebRender, String> {
+    match s {
+        "on" => Ok(WebRender::On),
+        "software" => Ok(WebRender::Software),
+        "off" => Ok(WebRender::Off),
+        _ => Err(format!(
+            "invalid WebRender mode `{}': expected one of `on', `software', or `off'",
+            s
+        )),
+    }
+}
+
+fn parse_toggle(s: &str) -> Result<bool, String> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("invalid value `{}': expected `on' or `off'", s)),
+    }
+}
+
+fn parse_channel(s: &str) -> Result<Channel, String> {
+    match s {
+        "release" => Ok(Channel::Release),
+        "beta" => Ok(Channel::Beta),
+        "esr" => Ok(Channel::Esr),
+        "devedition" => Ok(Channel::DevEdition),
+        _ => Err(format!(
+            "invalid channel `{}': expected one of `release', `beta', `esr', or `devedition'",
+            s
+        )),
+    }
+}
+
+/// Parse network conditions from either a profile name or a string of the form
+/// `download:upload:latency`.
+fn parse_network_conditions(s: &str) -> Result<NetworkConditions, String> {
+    let (download_kbps, upload_kbps, latency_ms) = match s {
+        "3g" => (1600, 768, 300),
+        "3gfast" => (1600, 768, 150),
+        "4g" => (9000, 9000, 170),
+        "cable" => (5000, 1000, 28),
+        _ => {
+            let parts = s
+                .split(':')
+                .map(|part| part.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("invalid network conditions `{}': {}", s, e))?;
+
+            match parts[..] {
+                [download, upload, latency] => (download, upload, latency),
+                _ => {
+                    return Err(format!(
+                        "invalid network conditions `{}': expected a profile name or `download:upload:latency'",
+                        s
+                    ))
+                }
+            }
+        }
+    };
+
+    Ok(NetworkConditions {
+        download_kbps,
+        upload_kbps,
+        latency_ms,
+    })
+}
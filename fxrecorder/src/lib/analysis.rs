@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{create_dir_all, read_dir, File};
 use std::io::{self, BufReader};
@@ -11,12 +12,15 @@ use std::process::{Command, Stdio};
 
 use image::{GenericImageView, ImageError, Rgb};
 use itertools::Itertools;
+use libfxrecord::net::{BandwidthUsage, BuildMetadata, GraphicsInfo, StartupCacheStats};
 use libfxrecord::ORANGE;
 use serde::{Deserialize, Serialize};
 use slog::{error, info, warn};
 use thiserror::Error;
 
+use crate::archive::ArchivedFile;
 use crate::ffmpeg::{run_ffmpeg, FfmpegError};
+use crate::timeline::PhaseTimings;
 
 #[derive(Debug, Error)]
 #[error("Could not crop video: {}", .0)]
@@ -246,6 +250,77 @@ pub struct VisualMetrics {
     pub visual_progress: String,
 }
 
+/// All metrics gathered for a session.
+#[derive(Debug, Serialize)]
+pub struct Metrics {
+    /// The metrics computed from the recorded video, unless the session was
+    /// measured without video capture.
+    #[serde(flatten)]
+    pub visual_metrics: Option<VisualMetrics>,
+
+    /// How long Firefox took from process launch to painting its first
+    /// window, in milliseconds, if the session was measured without video
+    /// capture.
+    ///
+    /// This is Firefox's own `firstPaint` startup marker.
+    #[serde(rename = "StartupDuration", skip_serializing_if = "Option::is_none")]
+    pub startup_duration: Option<u64>,
+
+    /// Startup metrics extracted from Firefox's telemetry.
+    #[serde(
+        rename = "StartupTelemetry",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub startup_telemetry: BTreeMap<String, u64>,
+
+    /// Statistics about Firefox's startup cache, if they were collected.
+    #[serde(rename = "StartupCache", skip_serializing_if = "Option::is_none")]
+    pub startup_cache: Option<StartupCacheStats>,
+
+    /// The graphics hardware and configuration Firefox used, if known.
+    #[serde(rename = "Graphics", skip_serializing_if = "Option::is_none")]
+    pub graphics: Option<GraphicsInfo>,
+
+    /// The build of Firefox that was measured, if known.
+    #[serde(rename = "Build", skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildMetadata>,
+
+    /// The labels of the configuration the session was recorded with, e.g.,
+    /// `webrender=off`.
+    #[serde(rename = "Labels", skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+
+    /// The bytes the runner transferred for the session, if it reported
+    /// them.
+    #[serde(rename = "Bandwidth", skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<BandwidthUsage>,
+
+    /// How long each phase of the session took.
+    #[serde(rename = "Timings", skip_serializing_if = "PhaseTimings::is_empty")]
+    pub timings: PhaseTimings,
+
+    /// The files of the session that were archived in object storage.
+    #[serde(rename = "Archived", skip_serializing_if = "Vec::is_empty")]
+    pub archived: Vec<ArchivedFile>,
+}
+
+impl From<VisualMetrics> for Metrics {
+    fn from(visual_metrics: VisualMetrics) -> Self {
+        Metrics {
+            visual_metrics: Some(visual_metrics),
+            startup_duration: None,
+            startup_telemetry: BTreeMap::new(),
+            startup_cache: None,
+            graphics: None,
+            build: None,
+            labels: Vec::new(),
+            bandwidth: None,
+            timings: PhaseTimings::default(),
+            archived: Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum VisualMetricsError {
     #[error("Error executing visualmetrics.py: {}", .0)]
@@ -270,12 +345,25 @@ pub enum VisualMetricsError {
     ExtractFrames(#[from] ExtractFramesError),
 }
 
+/// The event that visual metrics are measured relative to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricsOrigin {
+    /// Metrics are relative to the launch of Firefox, which is marked by the
+    /// first orange frame painted by the runner's splash screen.
+    Launch,
+
+    /// Metrics are relative to navigation start, which is marked by the last
+    /// frame of the orange page Firefox displays before navigating.
+    Navigation,
+}
+
 /// Compute visual metrics with visualmetrics.py
 pub fn compute_visual_metrics(
     log: slog::Logger,
     vismet_path: &Path,
     video: &Path,
     target_directory: &Path,
+    origin: MetricsOrigin,
 ) -> Result<VisualMetrics, VisualMetricsError> {
     // The time base is the reciprocal of the frame rate (units of `s`);
     const TIME_BASE: f64 = 1.0 / 60.0;
@@ -324,6 +412,13 @@ pub fn compute_visual_metrics(
     );
 
     let metrics: VisualMetrics = serde_json::from_str(&stdout)?;
+
+    // visualmetrics.py already reports metrics relative to the end of the
+    // orange frames, which is exactly navigation start.
+    if origin == MetricsOrigin::Navigation {
+        return Ok(metrics);
+    }
+
     let frames_dir = extract_frames(log.clone(), video, target_directory)?;
     let orange_frame_num = find_first_orange_frame(log.clone(), &frames_dir)?;
 
@@ -0,0 +1,99 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Archiving the files of sessions in object storage.
+//!
+//! Files are stored under `<prefix><date>/<task>/<session id>/<file name>`,
+//! where the date is the UTC date the session was archived and the task
+//! names the build that was recorded.
+
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use libfxrecord::storage::{Bucket, StorageError, StoredObject};
+use serde::Serialize;
+
+use crate::config::ArchiveConfig;
+
+/// Where the files of sessions are archived.
+#[derive(Debug)]
+pub struct Archive {
+    bucket: Bucket,
+    share_link_expiry: Option<Duration>,
+    date: String,
+    task: String,
+}
+
+/// A file that was archived.
+#[derive(Clone, Debug, Serialize)]
+pub struct ArchivedFile {
+    /// The name of the file.
+    pub name: String,
+
+    #[serde(flatten)]
+    pub object: StoredObject,
+
+    /// A link that anyone may download the file from until it expires.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub share_link: Option<String>,
+}
+
+impl Archive {
+    /// Open the archive for recordings of the given task made today.
+    pub fn new(config: &ArchiveConfig, task: &str) -> Result<Self, StorageError> {
+        Ok(Archive {
+            bucket: Bucket::new(&config.storage)?,
+            share_link_expiry: config.share_link_secs.map(Duration::from_secs),
+            date: Utc::now().format("%Y-%m-%d").to_string(),
+            task: key_segment(task),
+        })
+    }
+
+    /// Upload the file at the given path as a file of the session.
+    pub async fn archive_file(
+        &self,
+        session_id: &str,
+        path: &Path,
+    ) -> Result<ArchivedFile, StorageError> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let key = self
+            .bucket
+            .key(&[&self.date, &self.task, &key_segment(session_id), &name]);
+        let object = self.bucket.put_file(&key, path).await?;
+
+        let share_link = self
+            .share_link_expiry
+            .map(|expiry| self.bucket.presigned_url(&key, expiry));
+
+        Ok(ArchivedFile {
+            name,
+            object,
+            share_link,
+        })
+    }
+}
+
+/// Make the string safe to use as a single segment of a key.
+fn key_segment(s: &str) -> String {
+    s.replace(['/', '\\'], "-")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_segment() {
+        assert_eq!(key_segment("abc123"), "abc123");
+        assert_eq!(
+            key_segment("gecko.v2.mozilla-central/latest"),
+            "gecko.v2.mozilla-central-latest"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bisecting a performance regression between two revisions.
+//!
+//! The pushes after a known-good revision up to a known-bad revision are read
+//! from the repository's pushlog. Each push is recorded with the build of its
+//! tip revision, which the runner finds in the Taskcluster index. The push at
+//! the middle of the remaining range is recorded until the regressing push is
+//! isolated. Pushes that cannot be recorded (e.g., because they were not
+//! built) are skipped, in which case several pushes may remain suspect.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::analysis::Metrics;
+use crate::iterations::MetricSummary;
+
+/// The root of the Mercurial server hosting the repositories.
+const HG_ROOT_URL: &str = "https://hg.mozilla.org/";
+
+/// A push to a repository.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Push {
+    /// The ID of the push in the pushlog.
+    pub id: u64,
+
+    /// The tip revision of the push, whose build is recorded.
+    pub revision: String,
+
+    /// Every revision in the push, oldest first.
+    pub changesets: Vec<String>,
+
+    /// Who pushed.
+    pub user: String,
+}
+
+/// A push, as it appears in the pushlog.
+#[derive(Deserialize)]
+struct PushlogEntry {
+    changesets: Vec<String>,
+    user: String,
+}
+
+/// The response to a pushlog query.
+#[derive(Deserialize)]
+struct Pushlog {
+    pushes: BTreeMap<u64, PushlogEntry>,
+}
+
+/// Return the path of the repository for the given project (e.g.,
+/// `integration/autoland` for `autoland`).
+pub fn repository_path(project: &str) -> String {
+    match project {
+        "autoland" | "mozilla-inbound" => format!("integration/{}", project),
+        "mozilla-beta" | "mozilla-release" => format!("releases/{}", project),
+        project if project.starts_with("mozilla-esr") => format!("releases/{}", project),
+        project => project.into(),
+    }
+}
+
+/// Return the pushes to the project after the push of `good`, up to and
+/// including the push of `bad`, oldest first.
+pub async fn fetch_pushes(project: &str, good: &str, bad: &str) -> Result<Vec<Push>, BisectError> {
+    let mut url = Url::parse(HG_ROOT_URL)
+        .expect("invalid Mercurial root URL")
+        .join(&format!("{}/json-pushes", repository_path(project)))
+        .map_err(|_| BisectError::Project(project.into()))?;
+
+    url.query_pairs_mut()
+        .append_pair("version", "2")
+        .append_pair("fromchange", good)
+        .append_pair("tochange", bad);
+
+    let rsp = Client::new()
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|source| BisectError::Pushlog {
+            url: url.clone(),
+            source,
+        })?;
+
+    let status = rsp.status();
+    if !status.is_success() {
+        let body = rsp.text().await.unwrap_or_default();
+        return Err(BisectError::PushlogRejected { url, status, body });
+    }
+
+    let body = rsp.bytes().await.map_err(|source| BisectError::Pushlog {
+        url: url.clone(),
+        source,
+    })?;
+
+    parse_pushlog(&body).map_err(|source| BisectError::ParsePushlog { url, source })
+}
+
+/// Parse the pushes from a pushlog response, oldest first.
+fn parse_pushlog(body: &[u8]) -> Result<Vec<Push>, serde_json::Error> {
+    let pushlog: Pushlog = serde_json::from_slice(body)?;
+
+    Ok(pushlog
+        .pushes
+        .into_iter()
+        .filter_map(|(id, entry)| {
+            let revision = entry.changesets.last()?.clone();
+
+            Some(Push {
+                id,
+                revision,
+                changesets: entry.changesets,
+                user: entry.user,
+            })
+        })
+        .collect())
+}
+
+/// A metric that can be bisected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum BisectMetric {
+    SpeedIndex,
+    FirstVisualChange,
+    LastVisualChange,
+    StartupDuration,
+}
+
+impl BisectMetric {
+    /// Return the value of the metric in the given results, if they have it.
+    pub fn value(self, metrics: &Metrics) -> Option<u64> {
+        let visual_metrics = metrics.visual_metrics.as_ref();
+
+        match self {
+            BisectMetric::SpeedIndex => visual_metrics.map(|m| u64::from(m.speed_index)),
+            BisectMetric::FirstVisualChange => {
+                visual_metrics.map(|m| u64::from(m.first_visual_change))
+            }
+            BisectMetric::LastVisualChange => {
+                visual_metrics.map(|m| u64::from(m.last_visual_change))
+            }
+            BisectMetric::StartupDuration => metrics.startup_duration,
+        }
+    }
+
+    /// Return the median value of the metric over the given results.
+    ///
+    /// Results without the metric are ignored.
+    pub fn median(self, metrics: &[Metrics]) -> Option<f64> {
+        let values = metrics
+            .iter()
+            .filter_map(|m| self.value(m))
+            .collect::<Vec<_>>();
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(MetricSummary::new(values).median)
+        }
+    }
+}
+
+impl Display for BisectMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BisectMetric::SpeedIndex => "SpeedIndex",
+            BisectMetric::FirstVisualChange => "FirstVisualChange",
+            BisectMetric::LastVisualChange => "LastVisualChange",
+            BisectMetric::StartupDuration => "StartupDuration",
+        })
+    }
+}
+
+impl FromStr for BisectMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+            "speedindex" => Ok(BisectMetric::SpeedIndex),
+            "firstvisualchange" => Ok(BisectMetric::FirstVisualChange),
+            "lastvisualchange" => Ok(BisectMetric::LastVisualChange),
+            "startupduration" => Ok(BisectMetric::StartupDuration),
+            _ => Err(format!(
+                "unknown metric `{}': expected one of speedindex, firstvisualchange, \
+                 lastvisualchange, or startupduration",
+                s
+            )),
+        }
+    }
+}
+
+/// What recording a revision showed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    /// The revision did not regress.
+    Good,
+
+    /// The revision regressed.
+    Bad,
+
+    /// The revision could not be recorded.
+    Skipped,
+}
+
+/// The state of a bisection over a range of revisions.
+///
+/// Revisions are identified by their index in the range, where the first
+/// revision is known to be good and the last is known to be bad.
+#[derive(Debug)]
+pub struct Bisection {
+    good: usize,
+    bad: usize,
+    skipped: BTreeSet<usize>,
+
+    /// The value of the metric for the first good revision.
+    baseline: f64,
+
+    /// How much worse than the baseline a value must be to have regressed,
+    /// as a fraction of the baseline.
+    threshold: f64,
+}
+
+impl Bisection {
+    /// Start bisecting a range of `len` revisions.
+    pub fn new(len: usize, baseline: f64, threshold: f64) -> Self {
+        assert!(len >= 2);
+
+        Bisection {
+            good: 0,
+            bad: len - 1,
+            skipped: BTreeSet::new(),
+            baseline,
+            threshold,
+        }
+    }
+
+    /// Whether or not the value has regressed from the baseline.
+    pub fn is_regression(&self, value: f64) -> bool {
+        value > self.baseline * (1.0 + self.threshold)
+    }
+
+    /// Return the revision to record next, or `None` if the regression has
+    /// been isolated as far as it can be.
+    pub fn next(&self) -> Option<usize> {
+        let untested = self.untested();
+
+        if untested.is_empty() {
+            None
+        } else {
+            Some(untested[untested.len() / 2])
+        }
+    }
+
+    /// The number of revisions that could still be recorded.
+    pub fn remaining(&self) -> usize {
+        self.untested().len()
+    }
+
+    /// Record the value of the metric for the revision.
+    pub fn record(&mut self, index: usize, value: f64) -> Verdict {
+        assert!(self.good < index && index < self.bad);
+
+        if self.is_regression(value) {
+            self.bad = index;
+            Verdict::Bad
+        } else {
+            self.good = index;
+            Verdict::Good
+        }
+    }
+
+    /// Skip the revision, which could not be recorded.
+    pub fn skip(&mut self, index: usize) -> Verdict {
+        self.skipped.insert(index);
+        Verdict::Skipped
+    }
+
+    /// Return the last good revision and the first bad revision found so far.
+    ///
+    /// Every revision after the good revision, up to and including the bad
+    /// revision, is suspect.
+    pub fn range(&self) -> (usize, usize) {
+        (self.good, self.bad)
+    }
+
+    fn untested(&self) -> Vec<usize> {
+        (self.good + 1..self.bad)
+            .filter(|index| !self.skipped.contains(index))
+            .collect()
+    }
+}
+
+/// A revision recorded during a bisection.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BisectStep {
+    pub revision: String,
+
+    /// The median value of the metric, if the revision could be recorded.
+    pub value: Option<f64>,
+
+    pub verdict: Verdict,
+}
+
+/// The outcome of a bisection.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BisectReport {
+    /// The metric that was bisected.
+    pub metric: BisectMetric,
+
+    /// How much worse than the good revision a push had to be to have
+    /// regressed, as a fraction.
+    pub threshold: f64,
+
+    /// The revisions that were recorded, in order.
+    pub steps: Vec<BisectStep>,
+
+    /// The last revision found not to have regressed.
+    pub last_good: String,
+
+    /// The first revision found to have regressed.
+    pub first_bad: String,
+
+    /// The pushes that may have caused the regression.
+    ///
+    /// This is a single push unless pushes had to be skipped.
+    pub suspects: Vec<Push>,
+}
+
+#[derive(Debug, Error)]
+pub enum BisectError {
+    #[error("`{}' is not a valid project", .0)]
+    Project(String),
+
+    #[error("Could not fetch pushlog from `{}': {}", .url, .source)]
+    Pushlog { url: Url, source: reqwest::Error },
+
+    #[error("Pushlog request to `{}' failed with status {}: {}", .url, .status, .body)]
+    PushlogRejected {
+        url: Url,
+        status: StatusCode,
+        body: String,
+    },
+
+    #[error("Could not parse pushlog from `{}': {}", .url, .source)]
+    ParsePushlog { url: Url, source: serde_json::Error },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_repository_path() {
+        assert_eq!(repository_path("mozilla-central"), "mozilla-central");
+        assert_eq!(repository_path("autoland"), "integration/autoland");
+        assert_eq!(repository_path("mozilla-beta"), "releases/mozilla-beta");
+        assert_eq!(repository_path("mozilla-esr78"), "releases/mozilla-esr78");
+    }
+
+    #[test]
+    fn test_parse_pushlog() {
+        let body = br#"{
+            "lastpushid": 12,
+            "pushes": {
+                "11": {"changesets": ["aaa"], "date": 1600000000, "user": "a@example.com"},
+                "9": {"changesets": ["bbb", "ccc"], "date": 1590000000, "user": "b@example.com"},
+                "10": {"changesets": [], "date": 1595000000, "user": "c@example.com"}
+            }
+        }"#;
+
+        assert_eq!(
+            parse_pushlog(body).unwrap(),
+            vec![
+                Push {
+                    id: 9,
+                    revision: "ccc".into(),
+                    changesets: vec!["bbb".into(), "ccc".into()],
+                    user: "b@example.com".into(),
+                },
+                Push {
+                    id: 11,
+                    revision: "aaa".into(),
+                    changesets: vec!["aaa".into()],
+                    user: "a@example.com".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bisect_metric() {
+        assert_eq!(
+            "speedindex".parse::<BisectMetric>().unwrap(),
+            BisectMetric::SpeedIndex
+        );
+        assert_eq!(
+            "first-visual-change".parse::<BisectMetric>().unwrap(),
+            BisectMetric::FirstVisualChange
+        );
+        assert!("firstPaint".parse::<BisectMetric>().is_err());
+    }
+
+    #[test]
+    fn test_bisection() {
+        // The regression is in the sixth revision.
+        let values = [100.0, 101.0, 99.0, 102.0, 100.0, 120.0, 119.0, 121.0, 122.0];
+        let mut bisection = Bisection::new(values.len(), values[0], 0.05);
+        assert!(bisection.is_regression(values[values.len() - 1]));
+
+        let mut recorded = Vec::new();
+        while let Some(index) = bisection.next() {
+            recorded.push(index);
+            bisection.record(index, values[index]);
+        }
+
+        assert_eq!(bisection.range(), (4, 5));
+        assert!(recorded.len() <= 3);
+
+        // Skipped revisions leave several suspects.
+        let mut bisection = Bisection::new(values.len(), values[0], 0.05);
+        while let Some(index) = bisection.next() {
+            if index == 4 || index == 5 {
+                assert_eq!(bisection.skip(index), Verdict::Skipped);
+            } else {
+                bisection.record(index, values[index]);
+            }
+        }
+
+        assert_eq!(bisection.range(), (3, 6));
+        assert_eq!(bisection.remaining(), 0);
+    }
+}
@@ -2,9 +2,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use libfxrecord::config::{toml_string, ConfigIssues, Validate};
+use libfxrecord::logging::DrainConfig;
+use libfxrecord::retry::Jitter;
+use libfxrecord::secret::Secret;
+use libfxrecord::storage::{StorageConfig, MAX_PRESIGNED_EXPIRY};
+use libfxrecord::timeout::TimeoutConfig;
 use serde::Deserialize;
+use url::Url;
+
+use crate::ffmpeg::list_capture_devices;
+use crate::taskcluster::FIREFOX_CI_ROOT_URL;
 
 /// The configuration for FxRecorder.
 #[derive(Debug, Deserialize)]
@@ -12,11 +22,197 @@ pub struct Config {
     /// The address of the `fxrunner` to connect to.
     pub host: String,
 
+    /// The address of an `fxbroker` to lease a runner from.
+    ///
+    /// If provided, recordings lease a runner with the tags given by
+    /// `--runner-tag` from the broker instead of connecting to `host`.
+    #[serde(default)]
+    pub broker: Option<String>,
+
+    /// The path to a registry of runners to select from with `--runner-tag`.
+    ///
+    /// See [`registry`](../registry/index.html) for the format.
+    #[serde(default)]
+    pub registry: Option<PathBuf>,
+
+    /// An SSH jump host to connect to the runner through.
+    ///
+    /// If provided, every connection to the runner is forwarded through the
+    /// jump host by the system `ssh` client.
+    #[serde(default)]
+    pub ssh: Option<SshConfig>,
+
     /// The path to the `visualmetrics.py` script.
     pub visual_metrics_path: PathBuf,
 
     /// The recording configuraton.
     pub recording: RecordingConfig,
+
+    /// Where to log to.
+    ///
+    /// If empty, human-readable logs are written to stderr.
+    #[serde(default)]
+    pub log: Vec<DrainConfig>,
+
+    /// How long each phase of a session may take.
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+
+    /// Object storage to archive the files of each session in.
+    ///
+    /// If not provided, files are only written to disk.
+    #[serde(default)]
+    pub archive: Option<ArchiveConfig>,
+
+    /// The Treeherder deployment that results are submitted to with
+    /// `--submit-treeherder`.
+    #[serde(default)]
+    pub treeherder: Option<TreeherderConfig>,
+
+    /// The key that results are signed with.
+    ///
+    /// If provided, a signature is written alongside the results, which
+    /// `fxrecorder verify` checks.
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+
+    /// The root URL of the Taskcluster deployment whose index is polled for
+    /// builds with `--wait-for-build`.
+    #[serde(default = "default_taskcluster_root_url")]
+    pub taskcluster_root_url: String,
+
+    /// The jitter applied to the delays between attempts to reconnect to the
+    /// runner after it restarts.
+    ///
+    /// Jitter keeps recorders whose runners restarted together from
+    /// reconnecting in lockstep, but it can shorten the total time spent
+    /// reconnecting. Without it, reconnecting is attempted for 7m30s.
+    #[serde(default)]
+    pub reconnect_jitter: Jitter,
+}
+
+fn default_taskcluster_root_url() -> String {
+    FIREFOX_CI_ROOT_URL.into()
+}
+
+/// Object storage that the files of sessions are archived in.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ArchiveConfig {
+    /// The bucket to upload to.
+    #[serde(flatten)]
+    pub storage: StorageConfig,
+
+    /// How long the share links of archived files are valid for, in seconds.
+    ///
+    /// If provided, the results include a presigned link to each archived
+    /// file. Links are valid for at most seven days.
+    #[serde(default)]
+    pub share_link_secs: Option<u64>,
+}
+
+/// The key that results are signed with.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SigningConfig {
+    /// The ID of the key, which is recorded in each signature.
+    pub key_id: String,
+
+    /// The HMAC-SHA256 key.
+    pub key: Secret,
+}
+
+/// A Treeherder deployment that Perfherder results are submitted to.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TreeherderConfig {
+    /// The root URL of the Treeherder deployment.
+    #[serde(default = "default_treeherder_root_url")]
+    pub root_url: String,
+
+    /// The Treeherder repository that jobs are reported against, e.g.,
+    /// `mozilla-central`.
+    ///
+    /// This must be the repository the recorded revisions belong to.
+    pub repository: String,
+
+    /// The ID of the Hawk credentials used to submit jobs.
+    pub client_id: String,
+
+    /// The secret of the Hawk credentials used to submit jobs.
+    pub secret: Secret,
+
+    /// The platform that jobs are reported on.
+    #[serde(default = "default_treeherder_platform")]
+    pub platform: String,
+
+    /// The operating system of the platform.
+    #[serde(default = "default_treeherder_os_name")]
+    pub os_name: String,
+
+    /// The architecture of the platform.
+    #[serde(default = "default_treeherder_architecture")]
+    pub architecture: String,
+
+    /// The symbol of the group that jobs are shown in.
+    #[serde(default = "default_treeherder_group_symbol")]
+    pub group_symbol: String,
+
+    /// The symbol of each job.
+    #[serde(default = "default_treeherder_job_symbol")]
+    pub job_symbol: String,
+}
+
+fn default_treeherder_root_url() -> String {
+    "https://treeherder.mozilla.org/".into()
+}
+
+fn default_treeherder_platform() -> String {
+    "windows10-64-ref-hw-2017".into()
+}
+
+fn default_treeherder_os_name() -> String {
+    "win".into()
+}
+
+fn default_treeherder_architecture() -> String {
+    "x86_64".into()
+}
+
+fn default_treeherder_group_symbol() -> String {
+    "fxrec".into()
+}
+
+fn default_treeherder_job_symbol() -> String {
+    "startup".into()
+}
+
+/// The SSH jump host that runners are reachable through.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SshConfig {
+    /// The jump host.
+    pub host: String,
+
+    /// The SSH port of the jump host.
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+
+    /// The user to log in to the jump host as.
+    pub user: String,
+
+    /// The path to the private key to authenticate with.
+    ///
+    /// The key must not require a passphrase.
+    pub key_path: PathBuf,
+
+    /// The path to the `ssh` executable.
+    #[serde(default = "default_ssh_path")]
+    pub ssh_path: PathBuf,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_ssh_path() -> PathBuf {
+    PathBuf::from("ssh")
 }
 
 /// Recording-specific configuration.
@@ -68,3 +264,213 @@ pub struct Size {
     /// The size in the x dimension.
     pub x: u16,
 }
+
+/// Return a commented starter configuration for FxRecorder.
+///
+/// `device` is the name of the detected capture device, if any.
+pub fn starter_config(visual_metrics_path: &Path, device: Option<&str>) -> String {
+    format!(
+        r#"# Generated by `fxrecorder init`. See docs/source/configuration.rst for every
+# available option.
+
+[fxrecorder]
+# The host and port that fxrunner is listening on. `fxrunner init` prints the
+# value to use here.
+host = "127.0.0.1:8888"
+
+# The path to vendor/visualmetrics.py.
+visual_metrics_path = {visual_metrics_path}
+
+[fxrecorder.recording]
+# The name of the capture card as detected by ffmpeg. To list the available
+# devices, run:
+#
+#   ffmpeg -hide_banner -list_devices true -f dshow -i dummy
+device = {device}
+
+# The resolution captured by the capture card.
+video_size = {{ x = 1920, y = 1080 }}
+
+# The output size of the video. This should match `fxrunner.display_size`.
+output_size = {{ x = 1366, y = 768 }}
+
+# The frame rate of the capture card.
+frame_rate = 60
+
+# The size of the buffer for capturing video while encoding. At least 1GB is
+# recommended.
+buffer_size = "1000M"
+
+# The minimum time a recording can take.
+minimum_recording_time_secs = 60
+"#,
+        visual_metrics_path = toml_string(&visual_metrics_path.display().to_string()),
+        device = toml_string(device.unwrap_or("<capture device>")),
+    )
+}
+
+impl Validate for Config {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        let port = self
+            .host
+            .rfind(':')
+            .map(|idx| &self.host[idx + 1..])
+            .and_then(|port| port.parse::<u16>().ok());
+
+        if port.is_none() {
+            issues.push(
+                "host",
+                format!("`{}' is not of the form HOST:PORT", self.host),
+            );
+        }
+
+        if let Some(ref registry) = self.registry {
+            issues.check_file("registry", registry);
+        }
+
+        if let Some(ref ssh) = self.ssh {
+            issues.nested("ssh", ssh);
+        }
+
+        issues.check_file("visual_metrics_path", &self.visual_metrics_path);
+
+        if let Err(e) = Url::parse(&self.taskcluster_root_url) {
+            issues.push(
+                "taskcluster_root_url",
+                format!("`{}' is not a valid URL: {}", self.taskcluster_root_url, e),
+            );
+        }
+
+        issues.nested("recording", &self.recording);
+        issues.nested("timeouts", &self.timeouts);
+
+        if let Some(ref archive) = self.archive {
+            issues.nested("archive", archive);
+        }
+
+        if let Some(ref treeherder) = self.treeherder {
+            issues.nested("treeherder", treeherder);
+        }
+
+        if let Some(ref signing) = self.signing {
+            issues.nested("signing", signing);
+        }
+    }
+}
+
+impl Validate for SigningConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        if self.key_id.is_empty() {
+            issues.push("key_id", "must not be empty");
+        }
+
+        if let Err(e) = self.key.resolve() {
+            issues.push("key", e);
+        }
+    }
+}
+
+impl Validate for TreeherderConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        if let Err(e) = Url::parse(&self.root_url) {
+            issues.push(
+                "root_url",
+                format!("`{}' is not a valid URL: {}", self.root_url, e),
+            );
+        }
+
+        for (field, value) in &[
+            ("repository", &self.repository),
+            ("client_id", &self.client_id),
+            ("platform", &self.platform),
+            ("group_symbol", &self.group_symbol),
+            ("job_symbol", &self.job_symbol),
+        ] {
+            if value.is_empty() {
+                issues.push(field, "must not be empty");
+            }
+        }
+
+        if let Err(e) = self.secret.resolve() {
+            issues.push("secret", e);
+        }
+    }
+}
+
+impl Validate for ArchiveConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        self.storage.validate(issues);
+
+        if let Some(share_link_secs) = self.share_link_secs {
+            issues.check_range(
+                "share_link_secs",
+                share_link_secs,
+                1,
+                MAX_PRESIGNED_EXPIRY.as_secs(),
+            );
+        }
+    }
+}
+
+impl Validate for SshConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        if self.host.is_empty() {
+            issues.push("host", "must not be empty");
+        }
+
+        if self.user.is_empty() {
+            issues.push("user", "must not be empty");
+        }
+
+        issues.check_file("key_path", &self.key_path);
+    }
+}
+
+impl Validate for RecordingConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        match list_capture_devices() {
+            Ok(devices) => {
+                if !devices.contains(&self.device) {
+                    issues.push(
+                        "device",
+                        format!(
+                            "no capture device named `{}' (found: {})",
+                            self.device,
+                            devices.join(", ")
+                        ),
+                    );
+                }
+            }
+            Err(e) => issues.push("device", format!("could not list capture devices: {}", e)),
+        }
+
+        issues.nested("video_size", &self.video_size);
+        if let Some(ref output_size) = self.output_size {
+            issues.nested("output_size", output_size);
+        }
+
+        issues.check_range("frame_rate", self.frame_rate, 1, 240);
+
+        let digits = self.buffer_size.trim_end_matches(|c| "kKmMgG".contains(c));
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            issues.push(
+                "buffer_size",
+                format!("`{}' is not a size such as `1000M'", self.buffer_size),
+            );
+        }
+
+        issues.check_range(
+            "minimum_recording_time_secs",
+            self.minimum_recording_time_secs,
+            1,
+            u8::MAX,
+        );
+    }
+}
+
+impl Validate for Size {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_range("x", self.x, 1, u16::MAX);
+        issues.check_range("y", self.y, 1, u16::MAX);
+    }
+}
@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Computing the changes between a zipped profile and the profile cached on
+//! the runner.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use libfxrecord::hex;
+use libfxrecord::net::ProfileHashes;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// Write the files in the zipped profile at `profile` that are missing from
+/// or differ from the runner's `manifest` to a new archive at `delta`.
+///
+/// If every file in the profile is within a single top-level directory, the
+/// paths are taken relative to that directory, as the runner does when it
+/// receives an entire profile.
+///
+/// Returns the paths in the manifest that are no longer in the profile.
+pub fn write_profile_delta(
+    profile: &Path,
+    manifest: &ProfileHashes,
+    delta: &Path,
+) -> Result<Vec<String>, DeltaError> {
+    let mut zip = ZipArchive::new(File::open(profile)?)?;
+
+    let mut files = Vec::new();
+    for i in 0..zip.len() {
+        let zipped = zip.by_index(i)?;
+        if zipped.is_file() {
+            files.push((i, zipped.sanitized_name()));
+        }
+    }
+
+    let top_level_dir = top_level_dir(files.iter().map(|(_, path)| path.as_path()));
+
+    let mut writer = ZipWriter::new(File::create(delta)?);
+    let mut seen = BTreeSet::new();
+
+    for (i, path) in files {
+        let name = match top_level_dir {
+            Some(ref dir) => path.strip_prefix(dir).unwrap().to_owned(),
+            None => path,
+        };
+
+        // Manifest paths always use forward slashes, like zip files.
+        let name = name
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut zip.by_index(i)?, &mut hasher)?;
+        let hash = hex(&hasher.finalize());
+
+        if manifest.get(&name) != Some(&hash) {
+            writer.start_file(name.as_str(), FileOptions::default())?;
+            io::copy(&mut zip.by_index(i)?, &mut writer)?;
+        }
+
+        seen.insert(name);
+    }
+
+    writer.finish()?;
+
+    Ok(manifest
+        .keys()
+        .filter(|name| !seen.contains(*name))
+        .cloned()
+        .collect())
+}
+
+/// Return the single directory that contains every path, if any.
+pub(crate) fn top_level_dir<'a, I>(paths: I) -> Option<PathBuf>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let mut top_level_dir = None;
+
+    for path in paths {
+        let mut components = path.components();
+        let first = match (components.next(), components.next()) {
+            (Some(Component::Normal(first)), Some(_)) => first,
+            _ => return None,
+        };
+
+        match top_level_dir {
+            None => top_level_dir = Some(PathBuf::from(first)),
+            Some(ref dir) if dir.as_os_str() == first => {}
+            Some(_) => return None,
+        }
+    }
+
+    top_level_dir
+}
+
+#[derive(Debug, Error)]
+pub enum DeltaError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::current_dir;
+    use std::io::Read;
+
+    use libfxrecord::sha256_hex;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn test_dir() -> PathBuf {
+        current_dir().unwrap().parent().unwrap().join("test")
+    }
+
+    fn entry_names(archive: &Path) -> Vec<String> {
+        let mut zip = ZipArchive::new(File::open(archive).unwrap()).unwrap();
+        (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_write_profile_delta() {
+        let tempdir = TempDir::new().unwrap();
+
+        for profile in &["profile.zip", "profile_nested.zip"] {
+            let profile = test_dir().join(profile);
+            let delta = tempdir.path().join("delta.zip");
+
+            // Nothing is cached, so every file is sent.
+            let removed = write_profile_delta(&profile, &ProfileHashes::new(), &delta).unwrap();
+            assert!(removed.is_empty());
+
+            let mut names = entry_names(&delta);
+            names.sort();
+            assert_eq!(names, vec!["places.sqlite", "prefs.js", "user.js"]);
+
+            // Cache everything but `prefs.js`, which is stale, and a file that
+            // is no longer in the profile.
+            let mut manifest = ProfileHashes::new();
+            {
+                let mut zip = ZipArchive::new(File::open(&delta).unwrap()).unwrap();
+                for i in 0..zip.len() {
+                    let mut zipped = zip.by_index(i).unwrap();
+                    let mut contents = Vec::new();
+                    zipped.read_to_end(&mut contents).unwrap();
+                    manifest.insert(zipped.name().to_owned(), sha256_hex(&contents));
+                }
+            }
+            manifest.insert("prefs.js".into(), sha256_hex(b"stale"));
+            manifest.insert("removed.js".into(), sha256_hex(b"removed"));
+
+            let removed = write_profile_delta(&profile, &manifest, &delta).unwrap();
+            assert_eq!(removed, vec!["removed.js"]);
+            assert_eq!(entry_names(&delta), vec!["prefs.js"]);
+        }
+    }
+
+    #[test]
+    fn test_top_level_dir() {
+        let paths = |paths: &[&'static str]| top_level_dir(paths.iter().map(Path::new));
+
+        assert_eq!(
+            paths(&["profile/prefs.js", "profile/nested/data.sqlite"]),
+            Some(PathBuf::from("profile"))
+        );
+        assert_eq!(paths(&["profile/prefs.js", "prefs.js"]), None);
+        assert_eq!(paths(&["profile/prefs.js", "other/prefs.js"]), None);
+        assert_eq!(paths(&[]), None);
+    }
+}
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Packaging extensions for the runner to install into the profile.
+
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use reqwest::{Client, Url};
+use thiserror::Error;
+use tokio::task::spawn_blocking;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+/// Where the latest versions of extensions are downloaded from on
+/// addons.mozilla.org.
+const AMO_DOWNLOAD_URL: &str = "https://addons.mozilla.org/firefox/downloads/latest/";
+
+/// An extension to install into the profile.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Extension {
+    /// An XPI file on disk.
+    Xpi(PathBuf),
+
+    /// The latest version of an extension on addons.mozilla.org, identified by
+    /// its ID or slug, e.g., `uBlock0@raymondhill.net` or `ublock-origin`.
+    Amo(String),
+}
+
+impl FromStr for Extension {
+    type Err = Infallible;
+
+    /// Parse an extension from the command line.
+    ///
+    /// Anything ending in `.xpi` is a path to an XPI file. Anything else is an
+    /// extension on addons.mozilla.org.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.ends_with(".xpi") {
+            Ok(Extension::Xpi(s.into()))
+        } else {
+            Ok(Extension::Amo(s.into()))
+        }
+    }
+}
+
+/// Write a zip archive of the given extensions to `archive`, downloading any
+/// from addons.mozilla.org.
+pub async fn package_extensions(
+    extensions: &[Extension],
+    archive: &Path,
+) -> Result<(), ExtensionError> {
+    let client = Client::new();
+    let mut xpis = Vec::with_capacity(extensions.len());
+
+    for extension in extensions {
+        let xpi = match extension {
+            Extension::Xpi(path) => {
+                tokio::fs::read(path)
+                    .await
+                    .map_err(|source| ExtensionError::Read {
+                        path: path.clone(),
+                        source,
+                    })?
+            }
+
+            Extension::Amo(id) => {
+                let url = amo_url(id);
+
+                client
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|source| ExtensionError::Download {
+                        url: url.clone(),
+                        source,
+                    })?
+                    .bytes()
+                    .await
+                    .map_err(|source| ExtensionError::Download { url, source })?
+                    .to_vec()
+            }
+        };
+
+        xpis.push(xpi);
+    }
+
+    spawn_blocking({
+        let archive = archive.to_owned();
+        move || write_archive(&archive, &xpis)
+    })
+    .await
+    .expect("package extensions task was cancelled or panicked")
+}
+
+/// Return the URL of the latest version of the extension on
+/// addons.mozilla.org.
+fn amo_url(id: &str) -> Url {
+    let mut url = Url::parse(AMO_DOWNLOAD_URL).expect("invalid AMO download URL");
+
+    url.path_segments_mut()
+        .expect("AMO download URL cannot be a base")
+        .pop_if_empty()
+        .push(id)
+        .push("latest.xpi");
+
+    url
+}
+
+/// Write each XPI to the zip archive at `archive`.
+///
+/// The runner names the extensions by the IDs in their manifests, so the
+/// names in the archive only need to be unique.
+fn write_archive(archive: &Path, xpis: &[Vec<u8>]) -> Result<(), ExtensionError> {
+    let mut writer = ZipWriter::new(File::create(archive)?);
+
+    for (i, xpi) in xpis.iter().enumerate() {
+        writer.start_file(format!("{}.xpi", i), FileOptions::default())?;
+        writer.write_all(xpi)?;
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("Could not read extension `{}': {}", .path.display(), .source)]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("Could not download extension from `{}': {}", .url, .source)]
+    Download { url: Url, source: reqwest::Error },
+
+    #[error("Could not package extensions: {}", .0)]
+    Io(#[from] io::Error),
+
+    #[error("Could not package extensions: {}", .0)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use tempfile::TempDir;
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_extension() {
+        assert_eq!(
+            "extensions/ublock.xpi".parse::<Extension>().unwrap(),
+            Extension::Xpi("extensions/ublock.xpi".into())
+        );
+        assert_eq!(
+            "uBlock0@raymondhill.net".parse::<Extension>().unwrap(),
+            Extension::Amo("uBlock0@raymondhill.net".into())
+        );
+    }
+
+    #[test]
+    fn test_amo_url() {
+        assert_eq!(
+            amo_url("ublock-origin").as_str(),
+            "https://addons.mozilla.org/firefox/downloads/latest/ublock-origin/latest.xpi"
+        );
+        assert_eq!(
+            amo_url("{446900e4-71c2-419f-a6a7-df9c091e268b}").as_str(),
+            "https://addons.mozilla.org/firefox/downloads/latest/%7B446900e4-71c2-419f-a6a7-df9c091e268b%7D/latest.xpi"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_package_extensions() {
+        let tempdir = TempDir::new().unwrap();
+        let first = tempdir.path().join("first.xpi");
+        let second = tempdir.path().join("second.xpi");
+        let archive = tempdir.path().join("extensions.zip");
+
+        std::fs::write(&first, b"first").unwrap();
+        std::fs::write(&second, b"second").unwrap();
+
+        package_extensions(&[Extension::Xpi(first), Extension::Xpi(second)], &archive)
+            .await
+            .unwrap();
+
+        let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        assert_eq!(zip.len(), 2);
+
+        let mut contents = String::new();
+        zip.by_name("1.xpi")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "second");
+    }
+}
@@ -21,6 +21,55 @@ pub enum FfmpegError {
     ExitCode(i32),
 }
 
+/// List the names of the DirectShow video capture devices known to `ffmpeg`.
+pub fn list_capture_devices() -> Result<Vec<String>, FfmpegError> {
+    // ffmpeg always fails because there is no input named `dummy`, so its
+    // exit status is ignored.
+    let output = Command::new("ffmpeg")
+        .args([
+            "-hide_banner",
+            "-list_devices",
+            "true",
+            "-f",
+            "dshow",
+            "-i",
+            "dummy",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(FfmpegError::Spawn)?
+        .wait_with_output()
+        .map_err(FfmpegError::Wait)?;
+
+    Ok(parse_video_devices(&String::from_utf8_lossy(
+        &output.stderr,
+    )))
+}
+
+/// Parse the video device names from the output of `ffmpeg -list_devices`.
+fn parse_video_devices(output: &str) -> Vec<String> {
+    let mut devices = Vec::new();
+    let mut in_video_devices = false;
+
+    for line in output.lines() {
+        if line.contains("DirectShow video devices") {
+            in_video_devices = true;
+        } else if line.contains("DirectShow audio devices") {
+            in_video_devices = false;
+        } else if in_video_devices && !line.contains("Alternative name") {
+            let mut parts = line.splitn(3, '"');
+
+            if let (Some(_), Some(name), Some(_)) = (parts.next(), parts.next(), parts.next()) {
+                devices.push(name.to_owned());
+            }
+        }
+    }
+
+    devices
+}
+
 pub fn run_ffmpeg(log: slog::Logger, args: &[&OsStr]) -> Result<(), FfmpegError> {
     info!(log, "executing ffmpeg"; "args" => ?args);
 
@@ -52,3 +101,28 @@ pub fn run_ffmpeg(log: slog::Logger, args: &[&OsStr]) -> Result<(), FfmpegError>
         Err(FfmpegError::ExitCode(status))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_video_devices() {
+        let output = [
+            r#"[dshow @ 000001a2656ad240] DirectShow video devices (some may be both video and audio devices)"#,
+            r#"[dshow @ 000001a2656ad240]  "AVerMedia GC551 Video Capture""#,
+            r#"[dshow @ 000001a2656ad240]     Alternative name "@device_pnp_\\?\usb#vid_07ca""#,
+            r#"[dshow @ 000001a2656ad240]  "Game Capture HD60 S""#,
+            r#"[dshow @ 000001a2656ad240]     Alternative name "@device_pnp_\\?\usb#vid_0fd9""#,
+            r#"[dshow @ 000001a2656ad240] DirectShow audio devices"#,
+            r#"[dshow @ 000001a2656ad240]  "Microphone (Realtek Audio)""#,
+            r#"dummy: Immediate exit requested"#,
+        ]
+        .join("\n");
+
+        assert_eq!(
+            parse_video_devices(&output),
+            vec!["AVerMedia GC551 Video Capture", "Game Capture HD60 S"]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Toggles for Firefox's graphics configuration.
+//!
+//! Each toggle expands to the prefs and environment variables that force the
+//! configuration, so that graphics configurations can be compared without
+//! writing out the prefs by hand. The toggles that were set label the results.
+
+use std::fmt::{self, Display};
+
+use libfxrecord::prefs::PrefValue;
+
+/// How Firefox should use WebRender.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WebRender {
+    /// Force hardware WebRender.
+    On,
+
+    /// Force WebRender with its software backend.
+    Software,
+
+    /// Disable WebRender in favour of the legacy compositor.
+    Off,
+}
+
+impl Display for WebRender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            WebRender::On => "on",
+            WebRender::Software => "software",
+            WebRender::Off => "off",
+        })
+    }
+}
+
+/// The graphics configuration to force for a session.
+///
+/// Anything that is not set is left to Firefox.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GfxToggles {
+    /// How Firefox should use WebRender.
+    pub webrender: Option<WebRender>,
+
+    /// Whether or not Firefox should composite in a GPU process.
+    pub gpu_process: Option<bool>,
+}
+
+impl GfxToggles {
+    /// Return the prefs that force the configuration.
+    pub fn prefs(&self) -> Vec<(String, PrefValue)> {
+        let mut prefs: Vec<(&str, PrefValue)> = Vec::new();
+
+        match self.webrender {
+            Some(WebRender::On) => {
+                prefs.push(("gfx.webrender.all", true.into()));
+                prefs.push(("gfx.webrender.software", false.into()));
+            }
+            Some(WebRender::Software) => {
+                prefs.push(("gfx.webrender.all", true.into()));
+                prefs.push(("gfx.webrender.software", true.into()));
+            }
+            Some(WebRender::Off) => {
+                prefs.push(("gfx.webrender.force-disabled", true.into()));
+            }
+            None => {}
+        }
+
+        match self.gpu_process {
+            Some(true) => {
+                prefs.push(("layers.gpu-process.enabled", true.into()));
+                prefs.push(("layers.gpu-process.force-enabled", true.into()));
+            }
+            Some(false) => {
+                prefs.push(("layers.gpu-process.enabled", false.into()));
+            }
+            None => {}
+        }
+
+        prefs
+            .into_iter()
+            .map(|(name, value)| (name.into(), value))
+            .collect()
+    }
+
+    /// Return the environment variables that force the configuration.
+    ///
+    /// Firefox checks `MOZ_WEBRENDER` before any prefs, so it is also set in
+    /// case the build's defaults would override the prefs.
+    pub fn env(&self) -> Vec<(String, String)> {
+        match self.webrender {
+            Some(WebRender::On) | Some(WebRender::Software) => {
+                vec![("MOZ_WEBRENDER".into(), "1".into())]
+            }
+            Some(WebRender::Off) => vec![("MOZ_WEBRENDER".into(), "0".into())],
+            None => Vec::new(),
+        }
+    }
+
+    /// Return the labels that distinguish results recorded with this
+    /// configuration.
+    pub fn labels(&self) -> Vec<String> {
+        let mut labels = Vec::new();
+
+        if let Some(webrender) = self.webrender {
+            labels.push(format!("webrender={}", webrender));
+        }
+
+        if let Some(gpu_process) = self.gpu_process {
+            labels.push(format!(
+                "gpu-process={}",
+                if gpu_process { "on" } else { "off" }
+            ));
+        }
+
+        labels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gfx_toggles() {
+        let toggles = GfxToggles::default();
+        assert!(toggles.prefs().is_empty());
+        assert!(toggles.env().is_empty());
+        assert!(toggles.labels().is_empty());
+
+        let toggles = GfxToggles {
+            webrender: Some(WebRender::Software),
+            gpu_process: Some(false),
+        };
+        assert_eq!(
+            toggles.prefs(),
+            vec![
+                ("gfx.webrender.all".to_owned(), PrefValue::from(true)),
+                ("gfx.webrender.software".to_owned(), PrefValue::from(true)),
+                (
+                    "layers.gpu-process.enabled".to_owned(),
+                    PrefValue::from(false)
+                ),
+            ]
+        );
+        assert_eq!(
+            toggles.env(),
+            vec![("MOZ_WEBRENDER".to_owned(), "1".to_owned())]
+        );
+        assert_eq!(
+            toggles.labels(),
+            vec!["webrender=software", "gpu-process=off"]
+        );
+
+        let toggles = GfxToggles {
+            webrender: Some(WebRender::Off),
+            gpu_process: None,
+        };
+        assert_eq!(
+            toggles.prefs(),
+            vec![(
+                "gfx.webrender.force-disabled".to_owned(),
+                PrefValue::from(true)
+            )]
+        );
+        assert_eq!(
+            toggles.env(),
+            vec![("MOZ_WEBRENDER".to_owned(), "0".to_owned())]
+        );
+        assert_eq!(toggles.labels(), vec!["webrender=off"]);
+    }
+}
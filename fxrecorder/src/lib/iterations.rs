@@ -0,0 +1,239 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recording the same session several times.
+//!
+//! The first run after the runner downloads a build is systematically
+//! different from the runs after it, so a number of warm-up iterations can be
+//! recorded first. Warm-up iterations are saved and reported like any other,
+//! but they are marked as such and excluded from the summary statistics.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::analysis::Metrics;
+
+/// The metrics of a single iteration.
+#[derive(Debug, Serialize)]
+pub struct IterationMetrics {
+    /// The number of the iteration, starting at 1.
+    #[serde(rename = "Iteration")]
+    pub iteration: u32,
+
+    /// Whether or not this was a warm-up iteration.
+    ///
+    /// Warm-up iterations are excluded from the summary.
+    #[serde(rename = "Warmup")]
+    pub warmup: bool,
+
+    /// How many times the iteration was retried after failing before it
+    /// succeeded.
+    #[serde(rename = "Retries")]
+    pub retries: u32,
+
+    #[serde(flatten)]
+    pub metrics: Metrics,
+}
+
+/// The metrics of every iteration and a summary of the measured iterations.
+#[derive(Debug, Serialize)]
+pub struct IteratedMetrics {
+    #[serde(rename = "Iterations")]
+    pub iterations: Vec<IterationMetrics>,
+
+    /// Summary statistics of each metric, keyed by metric name.
+    #[serde(rename = "Summary")]
+    pub summary: BTreeMap<String, MetricSummary>,
+}
+
+/// Summary statistics of a single metric over the measured iterations.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MetricSummary {
+    /// The values of each measured iteration, in order.
+    pub replicates: Vec<u64>,
+    pub median: f64,
+    pub mean: f64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl IteratedMetrics {
+    /// Summarize the given iterations, excluding warm-up iterations.
+    pub fn new(iterations: Vec<IterationMetrics>) -> Self {
+        let mut values: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+
+        for iteration in iterations.iter().filter(|i| !i.warmup) {
+            let metrics = &iteration.metrics;
+
+            if let Some(ref visual_metrics) = metrics.visual_metrics {
+                for (name, value) in [
+                    ("SpeedIndex", visual_metrics.speed_index),
+                    ("FirstVisualChange", visual_metrics.first_visual_change),
+                    ("LastVisualChange", visual_metrics.last_visual_change),
+                ]
+                .iter()
+                {
+                    values
+                        .entry((*name).into())
+                        .or_default()
+                        .push(u64::from(*value));
+                }
+            }
+
+            if let Some(startup_duration) = metrics.startup_duration {
+                values
+                    .entry("StartupDuration".into())
+                    .or_default()
+                    .push(startup_duration);
+            }
+
+            for (name, value) in &metrics.startup_telemetry {
+                values.entry(name.clone()).or_default().push(*value);
+            }
+        }
+
+        let summary = values
+            .into_iter()
+            .map(|(name, replicates)| (name, MetricSummary::new(replicates)))
+            .collect();
+
+        IteratedMetrics {
+            iterations,
+            summary,
+        }
+    }
+}
+
+impl MetricSummary {
+    pub(crate) fn new(replicates: Vec<u64>) -> Self {
+        assert!(!replicates.is_empty());
+
+        let mut sorted = replicates.clone();
+        sorted.sort_unstable();
+
+        let mid = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[mid - 1] + sorted[mid]) as f64 / 2.0
+        } else {
+            sorted[mid] as f64
+        };
+
+        let mean = sorted.iter().sum::<u64>() as f64 / sorted.len() as f64;
+
+        MetricSummary {
+            median,
+            mean,
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            replicates,
+        }
+    }
+}
+
+/// Return the name of an iteration, which is used in the names of the files
+/// written for it.
+pub fn iteration_name(iteration: u32, warmup: bool) -> String {
+    if warmup {
+        format!("warmup-{}", iteration)
+    } else {
+        format!("iteration-{}", iteration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::analysis::VisualMetrics;
+
+    fn iteration(iteration: u32, warmup: bool, speed_index: u32) -> IterationMetrics {
+        let mut metrics = Metrics::from(VisualMetrics {
+            video_recording_start: 0,
+            first_visual_change: speed_index / 2,
+            last_visual_change: speed_index * 2,
+            speed_index,
+            visual_progress: String::new(),
+        });
+        metrics
+            .startup_telemetry
+            .insert("firstPaint".into(), u64::from(speed_index) + 1);
+
+        IterationMetrics {
+            iteration,
+            warmup,
+            retries: 0,
+            metrics,
+        }
+    }
+
+    #[test]
+    fn test_iterated_metrics() {
+        let metrics = IteratedMetrics::new(vec![
+            iteration(1, true, 5000),
+            iteration(2, false, 400),
+            iteration(3, false, 100),
+            iteration(4, false, 200),
+            iteration(5, false, 300),
+        ]);
+
+        assert_eq!(metrics.iterations.len(), 5);
+        assert_eq!(
+            metrics.summary.keys().collect::<Vec<_>>(),
+            vec![
+                "FirstVisualChange",
+                "LastVisualChange",
+                "SpeedIndex",
+                "firstPaint"
+            ]
+        );
+
+        assert_eq!(
+            metrics.summary["SpeedIndex"],
+            MetricSummary {
+                replicates: vec![400, 100, 200, 300],
+                median: 250.0,
+                mean: 250.0,
+                min: 100,
+                max: 400,
+            }
+        );
+        assert_eq!(
+            metrics.summary["firstPaint"].replicates,
+            vec![401, 101, 201, 301]
+        );
+
+        let metrics = IteratedMetrics::new(vec![
+            iteration(1, false, 300),
+            iteration(2, false, 100),
+            iteration(3, false, 600),
+        ]);
+        assert_eq!(metrics.summary["SpeedIndex"].median, 300.0);
+        assert_eq!(metrics.summary["SpeedIndex"].mean, 1000.0 / 3.0);
+    }
+
+    #[test]
+    fn test_iterated_metrics_headless() {
+        let headless = |startup_duration| {
+            let mut iteration = iteration(1, false, 0);
+            iteration.metrics.visual_metrics = None;
+            iteration.metrics.startup_duration = Some(startup_duration);
+            iteration
+        };
+
+        let metrics = IteratedMetrics::new(vec![headless(300), headless(100), headless(200)]);
+
+        assert_eq!(
+            metrics.summary.keys().collect::<Vec<_>>(),
+            vec!["StartupDuration", "firstPaint"]
+        );
+        assert_eq!(metrics.summary["StartupDuration"].median, 200.0);
+    }
+
+    #[test]
+    fn test_iteration_name() {
+        assert_eq!(iteration_name(1, true), "warmup-1");
+        assert_eq!(iteration_name(3, false), "iteration-3");
+    }
+}
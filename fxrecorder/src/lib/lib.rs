@@ -3,9 +3,25 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod analysis;
+pub mod archive;
+pub mod bisect;
 pub mod config;
+pub mod delta;
+pub mod extensions;
 pub mod ffmpeg;
+pub mod gfx;
+pub mod iterations;
+pub mod manifest;
+pub mod matrix;
 pub mod perfherder;
+pub mod profile;
 pub mod proto;
 pub mod recorder;
-pub mod retry;
+pub mod registry;
+pub mod signing;
+pub mod taskcluster;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod timeline;
+pub mod treeherder;
+pub mod tunnel;
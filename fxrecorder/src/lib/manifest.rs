@@ -0,0 +1,168 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The manifest of the files of a session.
+//!
+//! Every file the recorder wrote is listed with its size and SHA-256 digest,
+//! along with the artifacts the runner uploaded instead of sending. The files
+//! that the runner sent are checked against the sizes it reported sending.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libfxrecord::hex;
+use libfxrecord::net::UploadedArtifact;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// A file written by the recorder.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    /// The path the file was written to.
+    pub path: PathBuf,
+
+    /// The size of the file, in bytes.
+    pub size: u64,
+
+    /// The hex-encoded SHA-256 digest of the file.
+    pub sha256: String,
+
+    /// The size the runner reported sending the file with, if the runner sent
+    /// it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_size: Option<u64>,
+}
+
+impl ManifestEntry {
+    /// Whether or not the file matches what the runner reported sending.
+    ///
+    /// Files that the runner did not send always match.
+    pub fn matches_report(&self) -> bool {
+        self.reported_size.is_none_or(|size| size == self.size)
+    }
+}
+
+/// The manifest of the files of a session.
+#[derive(Debug, Default, Serialize)]
+pub struct Manifest {
+    /// The files written by the recorder.
+    pub files: Vec<ManifestEntry>,
+
+    /// The artifacts the runner uploaded to its artifact store.
+    pub uploaded: Vec<UploadedArtifact>,
+}
+
+impl Manifest {
+    /// Build the manifest of the given files.
+    ///
+    /// `reported_sizes` are the sizes the runner reported sending files with,
+    /// by the path the recorder wrote them to.
+    pub fn new(
+        paths: &[PathBuf],
+        reported_sizes: &BTreeMap<PathBuf, u64>,
+        uploaded: &[UploadedArtifact],
+    ) -> Result<Self, ManifestError> {
+        let files = paths
+            .iter()
+            .map(|path| {
+                let (size, sha256) = digest_file(path).map_err(|source| ManifestError::Read {
+                    path: path.clone(),
+                    source,
+                })?;
+
+                Ok(ManifestEntry {
+                    path: path.clone(),
+                    size,
+                    sha256,
+                    reported_size: reported_sizes.get(path).cloned(),
+                })
+            })
+            .collect::<Result<_, ManifestError>>()?;
+
+        Ok(Manifest {
+            files,
+            uploaded: uploaded.to_vec(),
+        })
+    }
+
+    /// Return the files that do not match what the runner reported sending.
+    pub fn mismatches(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.files.iter().filter(|entry| !entry.matches_report())
+    }
+
+    /// Write the manifest to the given path as JSON.
+    pub fn write(&self, path: &Path) -> Result<(), ManifestError> {
+        let f = File::create(path).map_err(ManifestError::Write)?;
+        serde_json::to_writer_pretty(f, self).map_err(|e| ManifestError::Write(e.into()))
+    }
+}
+
+/// Return the size and hex-encoded SHA-256 digest of the file at the given
+/// path.
+pub(crate) fn digest_file(path: &Path) -> Result<(u64, String), io::Error> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok((size, hex(&hasher.finalize())))
+}
+
+#[derive(Debug, Error)]
+pub enum ManifestError {
+    #[error("Could not read `{}': {}", .path.display(), .source)]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("Could not write manifest: {}", .0)]
+    Write(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let tempdir = TempDir::new().unwrap();
+        let video = tempdir.path().join("recording.mp4");
+        let pings = tempdir.path().join("pings.zip");
+
+        std::fs::write(&video, b"video").unwrap();
+        std::fs::write(&pings, b"pings").unwrap();
+
+        let mut reported_sizes = BTreeMap::new();
+        reported_sizes.insert(pings.clone(), 5);
+
+        let manifest =
+            Manifest::new(&[video.clone(), pings.clone()], &reported_sizes, &[]).unwrap();
+
+        assert_eq!(
+            manifest.files,
+            vec![
+                ManifestEntry {
+                    path: video,
+                    size: 5,
+                    sha256: "0cab1c9617404faf2b24e221e189ca5945813e14d3f766345b09ca13bbe28ffc"
+                        .into(),
+                    reported_size: None,
+                },
+                ManifestEntry {
+                    path: pings.clone(),
+                    size: 5,
+                    sha256: "3fcd9715fed1ecad26ddda2648eb6170429e28ea2a04d304d0d3a27d50a10199"
+                        .into(),
+                    reported_size: Some(5),
+                },
+            ]
+        );
+        assert_eq!(manifest.mismatches().count(), 0);
+
+        reported_sizes.insert(pings.clone(), 6);
+        let manifest = Manifest::new(&[pings], &reported_sizes, &[]).unwrap();
+        assert_eq!(manifest.mismatches().count(), 1);
+    }
+}
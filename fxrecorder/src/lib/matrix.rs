@@ -0,0 +1,533 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running the same build once per configuration in a matrix.
+//!
+//! A pref matrix is a TOML manifest listing named variants, e.g.,
+//!
+//! ```toml
+//! [[variant]]
+//! name = "flag-off"
+//! prefs = { "browser.feature.enabled" = false }
+//!
+//! [[variant]]
+//! name = "flag-on"
+//! prefs = { "browser.feature.enabled" = true }
+//! ```
+//!
+//! An environment matrix is a TOML manifest listing the values of each
+//! environment variable, e.g.,
+//!
+//! ```toml
+//! [env]
+//! MOZ_FEATURE = ["0", "1"]
+//! MOZ_OTHER_FEATURE = ["0", "1"]
+//! ```
+//!
+//! Every combination of values is a variant, which is labelled by its values
+//! (e.g., `MOZ_FEATURE=1` and `MOZ_OTHER_FEATURE=0`). If both matrices are
+//! given, every combination of their variants is recorded.
+//!
+//! Each variant is recorded in its own session and the results are reported
+//! together, grouped by variant.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter::once;
+use std::path::{Path, PathBuf};
+
+use libfxrecord::prefs::{PrefError, PrefValue};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::analysis::Metrics;
+
+/// A list of pref sets to record a build with.
+#[derive(Debug, Deserialize)]
+pub struct PrefMatrix {
+    /// The variants to record, in order.
+    #[serde(rename = "variant")]
+    pub variants: Vec<PrefVariant>,
+}
+
+/// A named set of prefs in a [`PrefMatrix`](struct.PrefMatrix.html).
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrefVariant {
+    /// The name of the variant, which labels its results.
+    pub name: String,
+
+    /// The prefs to set for this variant.
+    #[serde(default)]
+    pub prefs: BTreeMap<String, PrefValue>,
+}
+
+/// The values of environment variables to record a build with.
+#[derive(Debug, Deserialize)]
+pub struct EnvMatrix {
+    /// The values of each environment variable.
+    pub env: BTreeMap<String, Vec<String>>,
+}
+
+/// A single configuration of a matrix run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Variant {
+    /// The name of the variant.
+    ///
+    /// This is used in the names of the files written for the variant.
+    pub name: String,
+
+    /// The labels that distinguish the results of this variant.
+    pub labels: Vec<String>,
+
+    /// The prefs to set for this variant.
+    pub prefs: BTreeMap<String, PrefValue>,
+
+    /// The environment variables to set for this variant.
+    pub env: BTreeMap<String, String>,
+}
+
+/// The metrics gathered for a single variant.
+#[derive(Debug, Serialize)]
+pub struct VariantMetrics {
+    /// The name of the variant.
+    #[serde(rename = "Variant")]
+    pub name: String,
+
+    /// The labels that distinguish the variant.
+    #[serde(rename = "Labels")]
+    pub labels: Vec<String>,
+
+    /// The prefs the variant set.
+    #[serde(rename = "Prefs", skip_serializing_if = "BTreeMap::is_empty")]
+    pub prefs: BTreeMap<String, PrefValue>,
+
+    /// The environment variables the variant set.
+    #[serde(rename = "Env", skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+
+    #[serde(flatten)]
+    pub metrics: Metrics,
+}
+
+impl PrefMatrix {
+    /// Load a pref matrix from the manifest at the given path.
+    pub fn load(path: &Path) -> Result<Self, MatrixError> {
+        Self::parse(&read_manifest(path)?)
+    }
+
+    /// Parse and validate a pref matrix.
+    pub fn parse(contents: &str) -> Result<Self, MatrixError> {
+        let matrix: PrefMatrix = toml::from_str(contents)?;
+
+        if matrix.variants.is_empty() {
+            return Err(MatrixError::Empty);
+        }
+
+        let mut names = BTreeSet::new();
+        for variant in &matrix.variants {
+            if !is_valid_name(&variant.name) {
+                return Err(MatrixError::InvalidVariantName(variant.name.clone()));
+            }
+
+            if !names.insert(variant.name.as_str()) {
+                return Err(MatrixError::DuplicateVariant(variant.name.clone()));
+            }
+
+            // Pref values are deserialized as arbitrary values, so they are
+            // checked the same way as prefs given on the command line.
+            for (pref, value) in &variant.prefs {
+                PrefValue::try_from(Value::from(value.clone())).map_err(|source| {
+                    MatrixError::InvalidPref {
+                        variant: variant.name.clone(),
+                        pref: pref.clone(),
+                        source,
+                    }
+                })?;
+            }
+        }
+
+        Ok(matrix)
+    }
+
+    /// Return the variants of the matrix, in order.
+    pub fn variants(&self) -> Vec<Variant> {
+        self.variants
+            .iter()
+            .map(|variant| Variant {
+                name: variant.name.clone(),
+                labels: vec![variant.name.clone()],
+                prefs: variant.prefs.clone(),
+                env: BTreeMap::new(),
+            })
+            .collect()
+    }
+}
+
+impl EnvMatrix {
+    /// Load an environment matrix from the manifest at the given path.
+    pub fn load(path: &Path) -> Result<Self, MatrixError> {
+        Self::parse(&read_manifest(path)?)
+    }
+
+    /// Parse and validate an environment matrix.
+    pub fn parse(contents: &str) -> Result<Self, MatrixError> {
+        let matrix: EnvMatrix = toml::from_str(contents)?;
+
+        if matrix.env.is_empty() {
+            return Err(MatrixError::Empty);
+        }
+
+        for (var, values) in &matrix.env {
+            let valid =
+                !var.is_empty() && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+            if !valid {
+                return Err(MatrixError::InvalidEnvName(var.clone()));
+            }
+
+            if values.is_empty() {
+                return Err(MatrixError::EmptyDimension(var.clone()));
+            }
+        }
+
+        // Values are sanitized for the variant names, so distinct values may
+        // still produce the same name.
+        let mut names = BTreeSet::new();
+        for variant in matrix.variants() {
+            if !names.insert(variant.name.clone()) {
+                return Err(MatrixError::DuplicateVariant(variant.name));
+            }
+        }
+
+        Ok(matrix)
+    }
+
+    /// Return every combination of the values in the matrix.
+    pub fn variants(&self) -> Vec<Variant> {
+        let mut variants = vec![Variant::default()];
+
+        for (var, values) in &self.env {
+            let dimension = values
+                .iter()
+                .map(|value| Variant {
+                    name: format!("{}-{}", var, sanitize_name(value)),
+                    labels: vec![format!("{}={}", var, value)],
+                    prefs: BTreeMap::new(),
+                    env: once((var.clone(), value.clone())).collect(),
+                })
+                .collect::<Vec<_>>();
+
+            variants = combine_variants(&variants, &dimension);
+        }
+
+        variants
+    }
+}
+
+impl Variant {
+    /// Return the prefs for this variant layered over the given prefs.
+    ///
+    /// Prefs are written to the profile in order, so the variant's prefs take
+    /// precedence.
+    pub fn prefs_over(&self, base: &[(String, PrefValue)]) -> Vec<(String, PrefValue)> {
+        base.iter()
+            .cloned()
+            .chain(
+                self.prefs
+                    .iter()
+                    .map(|(pref, value)| (pref.clone(), value.clone())),
+            )
+            .collect()
+    }
+
+    /// Return the environment variables for this variant layered over the
+    /// given environment variables.
+    pub fn env_over(&self, base: &[(String, String)]) -> Vec<(String, String)> {
+        base.iter()
+            .filter(|(var, _)| !self.env.contains_key(var))
+            .cloned()
+            .chain(
+                self.env
+                    .iter()
+                    .map(|(var, value)| (var.clone(), value.clone())),
+            )
+            .collect()
+    }
+
+    /// Return the variant that configures both this variant and `other`.
+    fn and(&self, other: &Variant) -> Variant {
+        let name = match (self.name.is_empty(), other.name.is_empty()) {
+            (true, _) => other.name.clone(),
+            (_, true) => self.name.clone(),
+            _ => format!("{}_{}", self.name, other.name),
+        };
+
+        Variant {
+            name,
+            labels: self.labels.iter().chain(&other.labels).cloned().collect(),
+            prefs: self
+                .prefs
+                .iter()
+                .chain(&other.prefs)
+                .map(|(pref, value)| (pref.clone(), value.clone()))
+                .collect(),
+            env: self
+                .env
+                .iter()
+                .chain(&other.env)
+                .map(|(var, value)| (var.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// Return every combination of a variant from `a` with a variant from `b`.
+pub fn combine_variants(a: &[Variant], b: &[Variant]) -> Vec<Variant> {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| x.and(y)))
+        .collect()
+}
+
+/// Read the contents of the matrix manifest at the given path.
+fn read_manifest(path: &Path) -> Result<String, MatrixError> {
+    fs::read_to_string(path).map_err(|source| MatrixError::Io {
+        path: path.into(),
+        source,
+    })
+}
+
+/// Return whether or not the given character may appear in a variant name.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+/// Return whether or not the given variant name is valid.
+///
+/// Variant names are used in the names of the files written for the variant,
+/// so they must be plain file names.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && name.chars().all(is_name_char)
+}
+
+/// Replace the characters of a value that may not appear in a variant name.
+fn sanitize_name(value: &str) -> String {
+    if value.is_empty() {
+        return "empty".into();
+    }
+
+    value
+        .chars()
+        .map(|c| if is_name_char(c) { c } else { '-' })
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum MatrixError {
+    #[error("Could not read matrix `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Could not parse matrix: {}", .0)]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Matrix does not contain any variants")]
+    Empty,
+
+    #[error("Invalid variant name `{}' in matrix", .0)]
+    InvalidVariantName(String),
+
+    #[error("Duplicate variant `{}' in matrix", .0)]
+    DuplicateVariant(String),
+
+    #[error("Invalid value for pref `{}' in variant `{}': {}", .pref, .variant, .source)]
+    InvalidPref {
+        variant: String,
+        pref: String,
+        source: PrefError,
+    },
+
+    #[error("Invalid environment variable name `{}' in matrix", .0)]
+    InvalidEnvName(String),
+
+    #[error("Environment variable `{}' in matrix has no values", .0)]
+    EmptyDimension(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let matrix = PrefMatrix::parse(
+            r#"
+            [[variant]]
+            name = "flag-off"
+            prefs = { "browser.feature.enabled" = false, "browser.count" = 1 }
+
+            [[variant]]
+            name = "flag-on"
+            prefs = { "browser.feature.enabled" = true }
+
+            [[variant]]
+            name = "baseline"
+            "#,
+        )
+        .unwrap();
+
+        let variants = matrix.variants();
+        let names = variants.iter().map(|v| v.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["flag-off", "flag-on", "baseline"]);
+        assert_eq!(variants[1].labels, vec!["flag-on"]);
+        assert!(variants[2].prefs.is_empty());
+
+        let expected: Vec<(String, PrefValue)> = vec![
+            ("browser.feature.enabled".into(), true.into()),
+            ("browser.other".into(), "value".into()),
+            ("browser.count".into(), 1i64.into()),
+            ("browser.feature.enabled".into(), false.into()),
+        ];
+        assert_eq!(
+            variants[0].prefs_over(&[
+                ("browser.feature.enabled".into(), true.into()),
+                ("browser.other".into(), "value".into()),
+            ]),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(matches!(PrefMatrix::parse(""), Err(MatrixError::Parse(..))));
+        assert!(matches!(
+            PrefMatrix::parse("variant = []"),
+            Err(MatrixError::Empty)
+        ));
+
+        match PrefMatrix::parse(
+            r#"
+            [[variant]]
+            name = "on"
+
+            [[variant]]
+            name = "on"
+            "#,
+        ) {
+            Err(MatrixError::DuplicateVariant(name)) => assert_eq!(name, "on"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match PrefMatrix::parse(
+            r#"
+            [[variant]]
+            name = "../on"
+            "#,
+        ) {
+            Err(MatrixError::InvalidVariantName(name)) => assert_eq!(name, "../on"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match PrefMatrix::parse(
+            r#"
+            [[variant]]
+            name = "on"
+            prefs = { "browser.list" = [1, 2] }
+            "#,
+        ) {
+            Err(MatrixError::InvalidPref {
+                variant,
+                pref,
+                source: PrefError::Array,
+            }) => {
+                assert_eq!(variant, "on");
+                assert_eq!(pref, "browser.list");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_env_matrix() {
+        let matrix = EnvMatrix::parse(
+            r#"
+            [env]
+            MOZ_FEATURE = ["0", "1"]
+            MOZ_LOG = ["", "sync:5"]
+            "#,
+        )
+        .unwrap();
+
+        let variants = matrix.variants();
+        let names = variants.iter().map(|v| v.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "MOZ_FEATURE-0_MOZ_LOG-empty",
+                "MOZ_FEATURE-0_MOZ_LOG-sync-5",
+                "MOZ_FEATURE-1_MOZ_LOG-empty",
+                "MOZ_FEATURE-1_MOZ_LOG-sync-5",
+            ]
+        );
+        assert_eq!(variants[1].labels, vec!["MOZ_FEATURE=0", "MOZ_LOG=sync:5"]);
+
+        let expected: Vec<(String, String)> = vec![
+            ("PATH".into(), r"C:\Windows".into()),
+            ("MOZ_FEATURE".into(), "0".into()),
+            ("MOZ_LOG".into(), "sync:5".into()),
+        ];
+        assert_eq!(
+            variants[1].env_over(&[
+                ("MOZ_LOG".into(), "".into()),
+                ("PATH".into(), r"C:\Windows".into()),
+            ]),
+            expected
+        );
+
+        let prefs = PrefMatrix::parse(
+            r#"
+            [[variant]]
+            name = "flag-off"
+            prefs = { "browser.feature.enabled" = false }
+
+            [[variant]]
+            name = "flag-on"
+            prefs = { "browser.feature.enabled" = true }
+            "#,
+        )
+        .unwrap()
+        .variants();
+
+        let combined = combine_variants(&prefs, &variants);
+        assert_eq!(combined.len(), 8);
+        assert_eq!(combined[7].name, "flag-on_MOZ_FEATURE-1_MOZ_LOG-sync-5");
+        assert_eq!(
+            combined[7].labels,
+            vec!["flag-on", "MOZ_FEATURE=1", "MOZ_LOG=sync:5"]
+        );
+        assert_eq!(combined[7].prefs, prefs[1].prefs);
+        assert_eq!(combined[7].env, variants[3].env);
+    }
+
+    #[test]
+    fn test_env_matrix_invalid() {
+        assert!(matches!(EnvMatrix::parse("[env]"), Err(MatrixError::Empty)));
+
+        match EnvMatrix::parse(r#"env = { "MOZ=FOO" = ["1"] }"#) {
+            Err(MatrixError::InvalidEnvName(var)) => assert_eq!(var, "MOZ=FOO"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match EnvMatrix::parse(r#"env = { MOZ_FEATURE = [] }"#) {
+            Err(MatrixError::EmptyDimension(var)) => assert_eq!(var, "MOZ_FEATURE"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match EnvMatrix::parse(r#"env = { MOZ_LOG = ["sync:5", "sync/5"] }"#) {
+            Err(MatrixError::DuplicateVariant(name)) => assert_eq!(name, "MOZ_LOG-sync-5"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
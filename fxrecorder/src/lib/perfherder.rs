@@ -2,45 +2,227 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::analysis::VisualMetrics;
+use crate::analysis::Metrics;
+use crate::iterations::IteratedMetrics;
+use crate::matrix::VariantMetrics;
+use libfxrecord::net::StartupCacheStats;
 use serde_json::{json, Value};
 
 /// Generate a JSON blob containing the performance metrics for Perfherder.
-pub fn generate_perfherder_metrics(metrics: &VisualMetrics) -> Value {
+///
+/// The metrics are reported under the suite with the given name.
+pub fn generate_perfherder_metrics(metrics: &Metrics, suite: &str) -> Value {
     json!({
-      "application": {
-        "name": "firefox",
+      "application": application(metrics),
+      "framework": {
+        "name": "fxrecord",
       },
+      "suites": [perfherder_suite(metrics, suite)],
+    })
+}
+
+/// Generate a JSON blob containing the performance metrics of every variant
+/// of a matrix run for Perfherder.
+///
+/// Each variant is reported as its own suite with the given name, which is
+/// distinguished by the variant's labels in its `extraOptions`.
+pub fn generate_perfherder_matrix_metrics(results: &[VariantMetrics], suite: &str) -> Value {
+    let suites = results
+        .iter()
+        .map(|result| {
+            let mut variant_suite = perfherder_suite(&result.metrics, suite);
+            variant_suite["extraOptions"] = json!(result
+                .labels
+                .iter()
+                .chain(&result.metrics.labels)
+                .collect::<Vec<_>>());
+            variant_suite
+        })
+        .collect::<Vec<_>>();
+
+    // Every variant runs the same build.
+    let application = match results.first() {
+        Some(result) => application(&result.metrics),
+        None => json!({ "name": "firefox" }),
+    };
+
+    json!({
+      "application": application,
       "framework": {
         "name": "fxrecord",
       },
-      "suites": [
-        {
-          "name": "firstrun",
-          "subtests": [
-            {
-              "name": "SpeedIndex",
-              "value": metrics.speed_index,
-              "unit": "ms * %",
-              "lowerIsBetter": true,
-              "shouldAlert": true,
-            },
-            {
-              "name": "FirstVisualChange",
-              "value": metrics.first_visual_change,
-              "unit": "ms",
-              "lowerIsBetter": true,
-              "shouldAlert": true,
-            },
-            {
-              "name": "LastVisualChange",
-              "value": metrics.last_visual_change,
-              "unit": "ms",
-              "lowerIsBetter": true,
-              "shouldAlert": true,
+      "suites": suites,
+    })
+}
+
+/// Generate a JSON blob containing the performance metrics of a session
+/// recorded several times for Perfherder.
+///
+/// Each subtest reports the median of the measured iterations, along with the
+/// value of each as its replicates. Warm-up iterations are not reported.
+pub fn generate_perfherder_iterated_metrics(metrics: &IteratedMetrics, suite: &str) -> Value {
+    let subtests = metrics
+        .summary
+        .iter()
+        .map(|(name, summary)| {
+            let (unit, should_alert) = match name.as_str() {
+                "SpeedIndex" => ("ms * %", true),
+                "FirstVisualChange" | "LastVisualChange" | "StartupDuration" => ("ms", true),
+                _ => ("ms", false),
+            };
+
+            json!({
+                "name": name,
+                "value": summary.median,
+                "replicates": summary.replicates,
+                "unit": unit,
+                "lowerIsBetter": true,
+                "shouldAlert": should_alert,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut iterated_suite = json!({
+        "name": suite,
+        "subtests": subtests,
+    });
+
+    // Every iteration runs the same build in the same configuration.
+    let application = match metrics.iterations.first() {
+        Some(iteration) => {
+            if !iteration.metrics.labels.is_empty() {
+                iterated_suite["extraOptions"] = json!(iteration.metrics.labels);
             }
-          ]
+            application(&iteration.metrics)
         }
-      ],
+        None => json!({ "name": "firefox" }),
+    };
+
+    json!({
+      "application": application,
+      "framework": {
+        "name": "fxrecord",
+      },
+      "suites": [iterated_suite],
     })
 }
+
+/// Generate the Perfherder suite with the given name for the metrics.
+fn perfherder_suite(metrics: &Metrics, suite: &str) -> Value {
+    let mut subtests = Vec::new();
+
+    if let Some(ref visual_metrics) = metrics.visual_metrics {
+        subtests.extend(vec![
+            json!({
+                "name": "SpeedIndex",
+                "value": visual_metrics.speed_index,
+                "unit": "ms * %",
+                "lowerIsBetter": true,
+                "shouldAlert": true,
+            }),
+            json!({
+                "name": "FirstVisualChange",
+                "value": visual_metrics.first_visual_change,
+                "unit": "ms",
+                "lowerIsBetter": true,
+                "shouldAlert": true,
+            }),
+            json!({
+                "name": "LastVisualChange",
+                "value": visual_metrics.last_visual_change,
+                "unit": "ms",
+                "lowerIsBetter": true,
+                "shouldAlert": true,
+            }),
+        ]);
+    }
+
+    if let Some(startup_duration) = metrics.startup_duration {
+        subtests.push(json!({
+            "name": "StartupDuration",
+            "value": startup_duration,
+            "unit": "ms",
+            "lowerIsBetter": true,
+            "shouldAlert": true,
+        }));
+    }
+
+    subtests.extend(metrics.startup_telemetry.iter().map(|(name, value)| {
+        json!({
+            "name": name,
+            "value": value,
+            "unit": "ms",
+            "lowerIsBetter": true,
+            "shouldAlert": false,
+        })
+    }));
+
+    if let Some(ref startup_cache) = metrics.startup_cache {
+        subtests.extend(startup_cache_subtests(startup_cache));
+    }
+
+    let mut suite = json!({
+      "name": suite,
+      "subtests": subtests,
+    });
+
+    if !metrics.labels.is_empty() {
+        suite["extraOptions"] = json!(metrics.labels);
+    }
+
+    suite
+}
+
+/// Generate the Perfherder subtests for the startup cache statistics.
+///
+/// These explain differences in the other metrics rather than measure
+/// performance themselves, so they never alert.
+fn startup_cache_subtests(startup_cache: &StartupCacheStats) -> Vec<Value> {
+    let mut subtests = vec![
+        json!({
+            "name": "startupCacheSizeBefore",
+            "value": startup_cache.size_before,
+            "unit": "bytes",
+            "lowerIsBetter": false,
+            "shouldAlert": false,
+        }),
+        json!({
+            "name": "startupCacheSizeAfter",
+            "value": startup_cache.size_after,
+            "unit": "bytes",
+            "lowerIsBetter": false,
+            "shouldAlert": false,
+        }),
+    ];
+
+    if let Some(ref requests) = startup_cache.requests {
+        for (name, value, lower_is_better) in &[
+            ("startupCacheHitMemory", requests.hit_memory, false),
+            ("startupCacheHitDisk", requests.hit_disk, false),
+            ("startupCacheMiss", requests.miss, true),
+        ] {
+            subtests.push(json!({
+                "name": name,
+                "value": value,
+                "unit": "count",
+                "lowerIsBetter": lower_is_better,
+                "shouldAlert": false,
+            }));
+        }
+    }
+
+    subtests
+}
+
+/// Generate the Perfherder description of the measured application.
+fn application(metrics: &Metrics) -> Value {
+    let mut application = json!({
+        "name": "firefox",
+    });
+
+    if let Some(ref build) = metrics.build {
+        application["version"] = json!(build.version);
+    }
+
+    application
+}
@@ -0,0 +1,330 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Packing profiles for the runner and checking that a zipped profile is
+//! usable before it is sent.
+//!
+//! The runner extracts a profile either from the root of the archive or from
+//! within a single top-level directory, so the profile's files must be at one
+//! of those depths. Entries with absolute paths or `..` components would be
+//! extracted outside of the profile and are rejected.
+
+use std::fs::{read_dir, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libfxrecord::glob::glob_matches;
+use thiserror::Error;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::delta::top_level_dir;
+
+/// The files that mark the root of a profile, at least one of which must be
+/// present.
+pub const PROFILE_FILES: &[&str] = &["prefs.js", "places.sqlite"];
+
+/// The entries of a profile directory that are not packed by default.
+///
+/// Lock files would make Firefox consider the profile in use, and caches are
+/// rebuilt by Firefox and would only skew the first startup.
+pub const DEFAULT_EXCLUDES: &[&str] = &[
+    "parent.lock",
+    ".parentlock",
+    "lock",
+    "cache2",
+    "startupCache",
+    "OfflineCache",
+    "jumpListCache",
+    "shader-cache",
+    "thumbnails",
+    "safebrowsing",
+    "crashes",
+    "minidumps",
+];
+
+/// Which entries of a profile directory to pack.
+///
+/// Patterns are globs. A pattern without a `/` matches entries with that name
+/// at any depth, and any other pattern matches paths from the root of the
+/// profile, e.g., `storage/default/*`.
+#[derive(Clone, Debug, Default)]
+pub struct ProfileFilter {
+    /// Patterns of entries to pack even if they are excluded.
+    pub include: Vec<String>,
+
+    /// Patterns of entries to exclude in addition to the
+    /// [defaults](constant.DEFAULT_EXCLUDES.html).
+    pub exclude: Vec<String>,
+}
+
+impl ProfileFilter {
+    /// Return whether or not the entry at the given path, relative to the
+    /// root of the profile and separated by `/`, is packed.
+    ///
+    /// The contents of directories that are not packed are skipped entirely.
+    pub fn packs(&self, path: &str) -> bool {
+        let matches = |pattern: &str| {
+            if pattern.contains('/') {
+                glob_matches(pattern, path)
+            } else {
+                glob_matches(pattern, path.rsplit('/').next().unwrap())
+            }
+        };
+
+        self.include.iter().any(|pattern| matches(pattern))
+            || !DEFAULT_EXCLUDES
+                .iter()
+                .copied()
+                .chain(self.exclude.iter().map(String::as_str))
+                .any(matches)
+    }
+}
+
+/// Write the profile in the directory `profile` to a new zip archive at
+/// `archive`, with the profile's files at the root of the archive.
+///
+/// Returns the number of files packed.
+pub fn pack_profile(
+    profile: &Path,
+    archive: &Path,
+    filter: &ProfileFilter,
+) -> Result<usize, ProfileError> {
+    let mut writer = ZipWriter::new(File::create(archive)?);
+    let mut packed = 0;
+    let mut dirs = vec![profile.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = read_dir(&dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        for path in entries {
+            // Zip files always use forward slashes as path separators.
+            let name = path
+                .strip_prefix(profile)
+                .expect("entry is not within the profile directory")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            if !filter.packs(&name) {
+                continue;
+            }
+
+            if path.is_dir() {
+                writer.add_directory(name, FileOptions::default())?;
+                dirs.push(path);
+            } else {
+                writer.start_file(name, FileOptions::default())?;
+                io::copy(&mut File::open(&path)?, &mut writer)?;
+                packed += 1;
+            }
+        }
+    }
+
+    writer.finish()?;
+    Ok(packed)
+}
+
+/// A problem with a zipped profile that would cause a session to fail.
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ProfileProblem {
+    #[error("`{}' is an absolute path", .0)]
+    AbsolutePath(String),
+
+    #[error("`{}' is outside of the profile", .0)]
+    Traversal(String),
+
+    #[error("The archive is empty")]
+    Empty,
+
+    #[error(
+        "Neither {} is at the root of the archive or within a single top-level directory",
+        PROFILE_FILES.join(" nor ")
+    )]
+    NotAProfile,
+}
+
+/// Check the zipped profile at the given path, returning every problem found.
+pub fn check_profile(archive: &Path) -> Result<Vec<ProfileProblem>, ProfileError> {
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+
+    let mut problems = Vec::new();
+    let mut files = Vec::new();
+
+    for i in 0..zip.len() {
+        let zipped = zip.by_index(i)?;
+        let name = zipped.name();
+
+        if is_absolute(name) {
+            problems.push(ProfileProblem::AbsolutePath(name.into()));
+        } else if name.split(['/', '\\']).any(|c| c == "..") {
+            problems.push(ProfileProblem::Traversal(name.into()));
+        } else if zipped.is_file() {
+            files.push(PathBuf::from(name));
+        }
+    }
+
+    if files.is_empty() {
+        if problems.is_empty() {
+            problems.push(ProfileProblem::Empty);
+        }
+        return Ok(problems);
+    }
+
+    let root = top_level_dir(files.iter().map(PathBuf::as_path)).unwrap_or_default();
+    if !PROFILE_FILES
+        .iter()
+        .any(|file| files.contains(&root.join(file)))
+    {
+        problems.push(ProfileProblem::NotAProfile);
+    }
+
+    Ok(problems)
+}
+
+/// Return whether or not the name of a zip entry is an absolute path, on
+/// either Windows or Unix.
+fn is_absolute(name: &str) -> bool {
+    name.starts_with('/') || name.starts_with('\\') || name.get(1..2) == Some(":")
+}
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::current_dir;
+    use std::fs::{create_dir_all, write};
+    use std::io::Write;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn test_dir() -> PathBuf {
+        current_dir().unwrap().parent().unwrap().join("test")
+    }
+
+    /// Write an archive containing the given files to the given path.
+    fn write_zip(path: &Path, names: &[&str]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for name in names {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(b"contents").unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_profile_filter() {
+        let filter = ProfileFilter {
+            include: vec!["startupCache".into()],
+            exclude: vec!["*.bak".into(), "storage/temporary".into()],
+        };
+
+        assert!(filter.packs("prefs.js"));
+        assert!(filter.packs("storage/default"));
+        assert!(filter.packs("startupCache"));
+
+        assert!(!filter.packs("parent.lock"));
+        assert!(!filter.packs("cache2"));
+        assert!(!filter.packs("bookmarkbackups/bookmarks.bak"));
+        assert!(!filter.packs("storage/temporary"));
+    }
+
+    #[test]
+    fn test_pack_profile() {
+        let tempdir = TempDir::new().unwrap();
+        let profile = tempdir.path().join("profile");
+        let archive = tempdir.path().join("profile.zip");
+
+        create_dir_all(profile.join("cache2").join("entries")).unwrap();
+        create_dir_all(profile.join("storage").join("default")).unwrap();
+        write(profile.join("prefs.js"), "prefs").unwrap();
+        write(profile.join("parent.lock"), "").unwrap();
+        write(profile.join("cache2").join("entries").join("0"), "cache").unwrap();
+        write(
+            profile.join("storage").join("default").join("data.sqlite"),
+            "data",
+        )
+        .unwrap();
+
+        let packed = pack_profile(&profile, &archive, &ProfileFilter::default()).unwrap();
+        assert_eq!(packed, 2);
+        assert_eq!(check_profile(&archive).unwrap(), vec![]);
+
+        let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        let names = (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "prefs.js",
+                "storage/",
+                "storage/default/",
+                "storage/default/data.sqlite",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_profile() {
+        assert_eq!(
+            check_profile(&test_dir().join("profile.zip")).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            check_profile(&test_dir().join("profile_nested.zip")).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            check_profile(&test_dir().join("empty.zip")).unwrap(),
+            vec![ProfileProblem::Empty]
+        );
+
+        let tempdir = TempDir::new().unwrap();
+        let archive = tempdir.path().join("profile.zip");
+
+        write_zip(&archive, &["profile/places.sqlite", "profile/user.js"]);
+        assert_eq!(check_profile(&archive).unwrap(), vec![]);
+
+        write_zip(
+            &archive,
+            &["outer/profile/prefs.js", "outer/profile/user.js"],
+        );
+        assert_eq!(
+            check_profile(&archive).unwrap(),
+            vec![ProfileProblem::NotAProfile]
+        );
+
+        write_zip(
+            &archive,
+            &[
+                "prefs.js",
+                "../escape.js",
+                "/etc/passwd",
+                "C:\\Windows\\win.ini",
+            ],
+        );
+        assert_eq!(
+            check_profile(&archive).unwrap(),
+            vec![
+                ProfileProblem::Traversal("../escape.js".into()),
+                ProfileProblem::AbsolutePath("/etc/passwd".into()),
+                ProfileProblem::AbsolutePath("C:\\Windows\\win.ini".into()),
+            ]
+        );
+    }
+}
@@ -2,45 +2,272 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::Debug;
 use std::io;
 use std::path::{Path, PathBuf};
 
-use libfxrecord::error::ErrorMessage;
+use chrono::Utc;
+use libfxrecord::error::{ForeignError, ForeignErrorKind};
+use libfxrecord::net::compression::{
+    recv_payload, send_payload, Codec, TransferStats, SUPPORTED_CODECS,
+};
 use libfxrecord::net::*;
 use libfxrecord::prefs::PrefValue;
+use libfxrecord::timeout::{with_timeout, TimeoutConfig, TimeoutError, TimeoutPhase};
 use slog::{error, info, warn, Logger};
 use thiserror::Error;
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::task::spawn_blocking;
 
+use crate::delta::{write_profile_delta, DeltaError};
+use crate::manifest::digest_file;
 use crate::recorder::Recorder;
+use crate::timeline::{Payload, Phase, Timeline};
+
+/// The output of a single launch of Firefox.
+#[derive(Debug)]
+pub struct LaunchOutput {
+    /// The path to the recorded video, unless the session was measured
+    /// without video capture.
+    pub recording_path: Option<PathBuf>,
+
+    /// Startup metrics extracted from Firefox's telemetry.
+    ///
+    /// This will be empty if the runner could not extract telemetry.
+    pub startup_metrics: BTreeMap<String, u64>,
+
+    /// Statistics about Firefox's startup cache, if the runner could collect
+    /// them.
+    pub startup_cache: Option<StartupCacheStats>,
+
+    /// The graphics hardware and configuration Firefox used, if the runner
+    /// could find them.
+    pub graphics: Option<GraphicsInfo>,
+
+    /// How long the runner took to restart before this launch, if it was the
+    /// first launch after the restart and the runner could measure it.
+    pub boot_timings: Option<BootTimings>,
+}
+
+/// The output of a resumed session.
+#[derive(Debug)]
+pub struct SessionOutput {
+    /// The output of each launch of Firefox, in order.
+    pub launches: Vec<LaunchOutput>,
+
+    /// The build of Firefox that was used, if the runner could identify it.
+    pub build: Option<BuildMetadata>,
+
+    /// The path to the zipped profile returned by the runner, if it was
+    /// requested and not uploaded.
+    pub profile_path: Option<PathBuf>,
+
+    /// The path to the zip archive of telemetry pings captured by the
+    /// runner, if they were requested and not uploaded.
+    pub pings_path: Option<PathBuf>,
+
+    /// The path to the gzipped memory report collected by the runner, if it
+    /// was requested and not uploaded.
+    pub memory_report_path: Option<PathBuf>,
+
+    /// The artifacts the runner uploaded to its artifact store instead of
+    /// sending them.
+    pub artifacts: Vec<UploadedArtifact>,
+
+    /// The sizes the runner reported sending files with, by the path they
+    /// were written to.
+    pub reported_sizes: BTreeMap<PathBuf, u64>,
+
+    /// The runner's log of the session, if the runner could read it.
+    pub runner_log: Option<String>,
+
+    /// The bytes the runner transferred for the session, if it reported
+    /// them.
+    pub bandwidth: Option<BandwidthUsage>,
+}
+
+impl SessionOutput {
+    /// Combine this output with the output of the next time the session was
+    /// resumed, after the runner restarted.
+    ///
+    /// The launches and artifacts of both are kept. The files the runner
+    /// returned are those of the later resumption, which has the last launch.
+    pub fn followed_by(mut self, next: SessionOutput) -> SessionOutput {
+        self.launches.extend(next.launches);
+        self.artifacts.extend(next.artifacts);
+        self.reported_sizes.extend(next.reported_sizes);
+
+        SessionOutput {
+            launches: self.launches,
+            build: next.build.or(self.build),
+            profile_path: next.profile_path.or(self.profile_path),
+            pings_path: next.pings_path.or(self.pings_path),
+            memory_report_path: next.memory_report_path.or(self.memory_report_path),
+            artifacts: self.artifacts,
+            reported_sizes: self.reported_sizes,
+            runner_log: match (self.runner_log, next.runner_log) {
+                (Some(log), Some(next_log)) => Some(log + &next_log),
+                (log, next_log) => next_log.or(log),
+            },
+            // The runner reports the total for the session so far.
+            bandwidth: next.bandwidth.or(self.bandwidth),
+        }
+    }
+}
+
+/// What became of the profile the runner returned.
+enum ReturnedProfile {
+    /// The profile was received and written to the given path.
+    Received(PathBuf),
+
+    /// The runner uploaded the profile to its artifact store.
+    Uploaded(UploadedArtifact),
+}
+
+/// The build that the runner should use for a new session.
+#[derive(Debug)]
+pub enum BuildRequest<'a> {
+    /// A build artifact from a Taskcluster task.
+    Taskcluster {
+        task: BuildTask,
+
+        /// The name of the build artifact, if not the runner's default.
+        artifact: Option<&'a str>,
+    },
+
+    /// An official build that the runner downloads from archive.mozilla.org.
+    MozillaArchive(OfficialBuild),
+
+    /// A build archive that the runner downloads from the given URL.
+    Url(&'a str),
+
+    /// A build archive at the given path on the runner.
+    RunnerPath(&'a Path),
+
+    /// A build archive at the given local path, which is sent to the runner.
+    Upload(&'a Path),
+}
+
+impl From<BuildTask> for BuildRequest<'_> {
+    fn from(task: BuildTask) -> Self {
+        BuildRequest::Taskcluster {
+            task,
+            artifact: None,
+        }
+    }
+}
 
 /// The recorder side of the protocol.
-pub struct RecorderProto<R> {
-    inner: Option<Proto<RunnerMessage, RecorderMessage, RunnerMessageKind, RecorderMessageKind>>,
+///
+/// The protocol runs over a `TcpStream` unless otherwise specified.
+pub struct RecorderProto<R, St = TcpStream>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    inner:
+        Option<Proto<RunnerMessage, RecorderMessage, RunnerMessageKind, RecorderMessageKind, St>>,
     log: Logger,
     recorder: R,
+    timeline: Timeline,
+    profile_delta: bool,
+    profile_template: Option<String>,
+    locale: Option<String>,
+    extensions_path: Option<PathBuf>,
+    distribution_path: Option<PathBuf>,
+    timeouts: TimeoutConfig,
+    request_id: Option<String>,
+    codec: Codec,
+    max_profile_size: Option<u64>,
 }
 
-impl<R> RecorderProto<R>
+impl<R, St> RecorderProto<R, St>
 where
     R: Recorder,
+    St: AsyncRead + AsyncWrite + Unpin,
 {
     /// Create a new RecorderProto.
-    pub fn new(log: Logger, stream: TcpStream, recorder: R) -> Self {
+    pub fn new(log: Logger, stream: St, recorder: R) -> Self {
         Self {
             inner: Some(Proto::new(stream)),
             log,
             recorder,
+            timeline: Timeline::default(),
+            profile_delta: false,
+            profile_template: None,
+            locale: None,
+            extensions_path: None,
+            distribution_path: None,
+            timeouts: TimeoutConfig::default(),
+            request_id: None,
+            codec: Codec::default(),
+            max_profile_size: None,
         }
     }
 
+    /// Record the phases of the session into the given timeline.
+    pub fn with_timeline(mut self, timeline: Timeline) -> Self {
+        self.timeline = timeline;
+        self
+    }
+
+    /// Only send the changes to the profile cached on the runner instead of
+    /// the entire profile.
+    pub fn with_profile_delta(mut self, profile_delta: bool) -> Self {
+        self.profile_delta = profile_delta;
+        self
+    }
+
+    /// Have the runner copy the named profile template instead of creating a
+    /// new profile when no profile is sent.
+    pub fn with_profile_template(mut self, profile_template: Option<String>) -> Self {
+        self.profile_template = profile_template;
+        self
+    }
+
+    /// Have the runner run Firefox in the given locale instead of the build's
+    /// own.
+    pub fn with_locale(mut self, locale: Option<String>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    /// Have the runner install the extensions in the zip archive at the given
+    /// path into the profile.
+    pub fn with_extensions(mut self, extensions_path: Option<PathBuf>) -> Self {
+        self.extensions_path = extensions_path;
+        self
+    }
+
+    /// Have the runner install the distribution in the zip archive at the
+    /// given path into the build, simulating a partner repack.
+    pub fn with_distribution(mut self, distribution_path: Option<PathBuf>) -> Self {
+        self.distribution_path = distribution_path;
+        self
+    }
+
+    /// Limit how long the runner may take to finish each phase of a session.
+    pub fn with_timeouts(mut self, timeouts: TimeoutConfig) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Identify the new session request with the given ID.
+    ///
+    /// Every attempt at the same request should use the same ID, so that the
+    /// runner can reuse the work it did for earlier attempts.
+    pub fn with_request_id(mut self, request_id: Option<String>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
     /// Send a request for a new session to the runner.
     pub async fn new_session(
         &mut self,
-        task_id: &str,
+        build: BuildRequest<'_>,
         profile_path: Option<&Path>,
         prefs: &[(String, PrefValue)],
     ) -> Result<String, RecorderProtoError<R::Error>> {
@@ -51,77 +278,290 @@ where
             Some(profile_path) => Some(tokio::fs::metadata(profile_path).await?.len()),
         };
 
+        let distribution_size = match self.distribution_path {
+            None => None,
+            Some(ref distribution_path) => {
+                Some(tokio::fs::metadata(distribution_path).await?.len())
+            }
+        };
+
+        let extensions_size = match self.extensions_path {
+            None => None,
+            Some(ref extensions_path) => Some(tokio::fs::metadata(extensions_path).await?.len()),
+        };
+
+        let (build_source, upload_path) = match build {
+            BuildRequest::Taskcluster { task, artifact } => (
+                BuildSource::Taskcluster {
+                    task,
+                    artifact: artifact.map(Into::into),
+                },
+                None,
+            ),
+            BuildRequest::MozillaArchive(build) => (BuildSource::MozillaArchive(build), None),
+            BuildRequest::Url(url) => (BuildSource::Url(url.into()), None),
+            BuildRequest::RunnerPath(path) => (BuildSource::Path(path.into()), None),
+            BuildRequest::Upload(path) => {
+                let (size, sha256) = spawn_blocking({
+                    let path = path.to_owned();
+                    move || digest_file(&path)
+                })
+                .await
+                .expect("digest build task was cancelled or panicked")?;
+
+                (BuildSource::Upload { size, sha256 }, Some(path))
+            }
+        };
+        let is_taskcluster = matches!(build_source, BuildSource::Taskcluster { .. });
+
         self.send::<Session>(
             NewSessionRequest {
-                build_task_id: task_id.into(),
+                build: build_source,
+                distribution_size,
                 profile_size,
+                profile_delta: self.profile_delta && profile_size.is_some(),
+                profile_template: self
+                    .profile_template
+                    .clone()
+                    .filter(|_| profile_size.is_none()),
                 prefs: Vec::from(prefs),
+                locale: self.locale.clone(),
+                extensions_size,
+                request_id: self.request_id.clone(),
+                codecs: SUPPORTED_CODECS.to_vec(),
             }
             .into(),
         )
         .await?;
 
-        let session_id = match self.recv::<NewSessionResponse>().await?.session_id {
-            Ok(session_id) => session_id,
+        let response = with_timeout(
+            TimeoutPhase::Handshake,
+            self.timeouts.get(TimeoutPhase::Handshake),
+            self.recv::<NewSessionResponse>(),
+        )
+        .await??;
+
+        self.codec = response.codec;
+        self.max_profile_size = response.max_profile_size;
+
+        let session_id = match response.session_id {
+            Ok(session_id) => {
+                self.timeline.set_session_id(&session_id);
+                self.timeline.record(Phase::Handshake);
+                session_id
+            }
             Err(e) => {
-                error!(self.log, "runner could not create new session"; "error" => %e);
+                error!(self.log, "runner could not create new session"; "error" => %e.chain());
                 return Err(e.into());
             }
         };
 
+        // A profile delta is checked once it has been computed, as it may be
+        // much smaller than the profile.
+        if !self.profile_delta {
+            if let Some(profile_size) = profile_size {
+                self.check_profile_size(profile_size)?;
+            }
+        }
+
+        with_timeout(
+            TimeoutPhase::Download,
+            self.timeouts.get(TimeoutPhase::Download),
+            self.recv_build(is_taskcluster, upload_path),
+        )
+        .await??;
+
+        if let Some(distribution_size) = distribution_size {
+            self.send_distribution(distribution_size).await?;
+        }
+
+        if let DisableUpdates { result: Err(e) } = self.recv().await? {
+            error!(self.log, "Runner could not disable updates"; "error" => %e.chain());
+            return Err(e.into());
+        }
+
+        with_timeout(
+            TimeoutPhase::ProfileTransfer,
+            self.timeouts.get(TimeoutPhase::ProfileTransfer),
+            self.transfer_profile(profile_path, profile_size),
+        )
+        .await??;
+
+        if let WritePrefs { result: Err(e) } = self.recv().await? {
+            error!(self.log, "Runner could not write prefs"; "error" => %e.chain());
+            return Err(e.into());
+        }
+
+        if let Some(locale) = self.locale.clone() {
+            if let InstalledLocale { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not install locale"; "locale" => locale, "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner installed locale"; "locale" => locale);
+        }
+
+        if let Some(extensions_size) = extensions_size {
+            self.send_extensions(extensions_size).await?;
+        }
+
+        self.recv_restarting().await?;
+
+        Ok(session_id)
+    }
+
+    /// Wait for the runner to report that it is restarting.
+    async fn recv_restarting(&mut self) -> Result<(), RecorderProtoError<R::Error>> {
+        match self.recv::<Restarting>().await?.result {
+            Ok(RestartInfo {
+                delay,
+                fast_startup,
+            }) => {
+                self.timeline.record(Phase::RestartRequested);
+                info!(
+                    self.log,
+                    "Runner is restarting...";
+                    "delay" => ?delay,
+                    "fast_startup" => %fast_startup,
+                );
+
+                Ok(())
+            }
+            Err(e) => {
+                error!(self.log, "Runner could not restart"; "error" => %e.chain());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Wait for the runner to acquire and extract the build, sending it the
+    /// build at `upload_path` if there is one.
+    async fn recv_build(
+        &mut self,
+        is_taskcluster: bool,
+        upload_path: Option<&Path>,
+    ) -> Result<(), RecorderProtoError<R::Error>> {
+        if is_taskcluster {
+            match self.recv::<ResolveTask>().await?.result {
+                Ok(task_id) => {
+                    info!(self.log, "Resolved build task"; "task_id" => &task_id);
+                }
+                Err(e) => {
+                    error!(self.log, "Runner could not resolve build task"; "error" => %e.chain());
+                    return Err(e.into());
+                }
+            }
+        }
+
         loop {
             let DownloadBuild { result } = self.recv().await?;
 
+            if let Ok(DownloadStatus::Downloading) = result {
+                self.timeline.record(Phase::DownloadStarted);
+            }
+
             match result {
-                Ok(DownloadStatus::Downloading) => {
-                    info!(self.log, "Downloading build ...");
+                Ok(DownloadStatus::Downloading) => match upload_path {
+                    Some(upload_path) => {
+                        info!(self.log, "Sending build"; "path" => upload_path.display());
+
+                        let mut stream = self.inner.take().unwrap().into_inner();
+                        let result = Self::send_file(&mut stream, upload_path, self.codec).await;
+                        self.inner = Some(Proto::new(stream));
+
+                        self.record_transfer(Payload::Build, result?);
+                    }
+                    None => info!(self.log, "Downloading build ..."),
+                },
+
+                Ok(DownloadStatus::Progress(progress)) => {
+                    info!(self.log, "Downloading build ..."; "progress" => %progress);
                 }
 
                 Ok(DownloadStatus::Downloaded) => {
+                    self.timeline.record(Phase::DownloadFinished);
                     info!(self.log, "Build download complete; extracting build ...");
                 }
 
+                Ok(DownloadStatus::Extracting(progress)) => {
+                    info!(self.log, "Extracting build ..."; "progress" => %progress);
+                }
+
                 Ok(DownloadStatus::Extracted) => {
+                    self.timeline.record(Phase::BuildExtracted);
                     info!(self.log, "Build extracted");
                     break;
                 }
 
                 Err(e) => {
-                    error!(self.log, "Build download failed"; "task_id" => task_id, "error" => %e);
+                    error!(self.log, "Build download failed"; "error" => %e.chain());
                     return Err(e.into());
                 }
             }
         }
 
-        if let DisableUpdates { result: Err(e) } = self.recv().await? {
-            error!(self.log, "Runner could not disable updates"; "error" => %e);
-            return Err(e.into());
-        }
+        Ok(())
+    }
 
+    /// Send the profile at `profile_path` to the runner, or wait for the
+    /// runner to create one if there is no profile to send.
+    async fn transfer_profile(
+        &mut self,
+        profile_path: Option<&Path>,
+        profile_size: Option<u64>,
+    ) -> Result<(), RecorderProtoError<R::Error>> {
         if let Some(profile_path) = profile_path {
-            self.send_profile(profile_path, profile_size.unwrap())
-                .await?
+            if self.profile_delta {
+                self.send_profile_delta(profile_path).await?;
+            } else {
+                self.send_profile(profile_path, profile_size.unwrap())
+                    .await?;
+            }
+            self.timeline.record(Phase::ProfileExtracted);
         } else {
-            info!(self.log, "No profile to send");
+            match self.profile_template {
+                Some(ref template) => {
+                    info!(self.log, "Using profile template"; "template" => template)
+                }
+                None => info!(self.log, "No profile to send"),
+            }
             if let Err(e) = self.recv::<CreateProfile>().await?.result {
-                error!(self.log, "Runner could not create profile"; "error" => %e);
+                error!(self.log, "Runner could not create profile"; "error" => %e.chain());
                 return Err(e.into());
             }
+            self.timeline.record(Phase::ProfileCreated);
         }
 
-        if let WritePrefs { result: Err(e) } = self.recv().await? {
-            error!(self.log, "Runner could not write prefs"; "error" => %e);
-            return Err(e.into());
-        }
+        Ok(())
+    }
+
+    /// Request the runner's status without starting a session.
+    pub async fn ping(&mut self) -> Result<RunnerStatus, RecorderProtoError<R::Error>> {
+        info!(self.log, "Requesting runner status");
+        self.send(Session::Ping).await?;
+
+        let Status { status } = self.recv().await?;
+        info!(self.log, "Received runner status"; "status" => ?status);
+
+        Ok(status)
+    }
+
+    /// Cancel the session created by
+    /// [`new_session()`](struct.RecorderProto.html#method.new_session).
+    ///
+    /// The runner will abort its restart and clean up the session. This must
+    /// be called before the restart delay reported by the runner has elapsed.
+    pub async fn cancel_session(&mut self) -> Result<(), RecorderProtoError<R::Error>> {
+        info!(self.log, "Cancelling session");
+        self.send(CancelSession).await?;
 
-        if let Restarting { result: Err(e) } = self.recv().await? {
-            error!(self.log, "Runner could not restart"; "error" => %e);
+        if let RestartCancelled { result: Err(e) } = self.recv().await? {
+            error!(self.log, "Runner could not cancel restart"; "error" => %e);
             return Err(e.into());
         }
 
-        info!(self.log, "Runner is restarting...");
-
-        Ok(session_id)
+        info!(self.log, "Session cancelled");
+        Ok(())
     }
 
     /// Send a request to resume a session to the runner.
@@ -129,59 +569,327 @@ where
         &mut self,
         session_id: &str,
         idle: Idle,
+        run_options: &RunOptions,
         directory: &Path,
-    ) -> Result<PathBuf, RecorderProtoError<R::Error>> {
+    ) -> Result<SessionOutput, RecorderProtoError<R::Error>> {
         info!(self.log, "Resuming session");
         self.send::<Session>(
             ResumeSessionRequest {
                 session_id: session_id.into(),
                 idle,
+                run_options: run_options.clone(),
+                codecs: SUPPORTED_CODECS.to_vec(),
             }
             .into(),
         )
         .await?;
 
-        if let ResumeResponse { result: Err(e) } = self.recv().await? {
+        let response = with_timeout(
+            TimeoutPhase::Handshake,
+            self.timeouts.get(TimeoutPhase::Handshake),
+            self.recv::<ResumeResponse>(),
+        )
+        .await??;
+
+        self.codec = response.codec;
+
+        if let ResumeResponse { result: Err(e), .. } = response {
             error!(
                 self.log,
                 "Could not resume session with runner";
                 "id" => session_id,
-                "error" => %e,
+                "error" => %e.chain(),
             );
             return Err(e.into());
         }
 
+        self.timeline.set_session_id(session_id);
+        self.timeline.record(Phase::Resumed);
+
+        let build = match self.recv::<BuildInfo>().await?.result {
+            Ok(build) => {
+                info!(self.log, "runner is using build"; "build" => ?build);
+                Some(build)
+            }
+            Err(e) => {
+                warn!(self.log, "runner could not identify build"; "error" => %e.chain());
+                None
+            }
+        };
+
+        let mut boot_timings = match self.recv::<BootInfo>().await?.result {
+            Ok(boot_timings) => {
+                info!(
+                    self.log,
+                    "runner restarted";
+                    "restart" => ?boot_timings.restart,
+                    "boot_to_desktop" => ?boot_timings.boot_to_desktop,
+                );
+                Some(boot_timings)
+            }
+            Err(e) => {
+                warn!(self.log, "runner could not measure its restart"; "error" => %e.chain());
+                None
+            }
+        };
+
+        if run_options.timezone.is_some() || run_options.sync_clock {
+            info!(self.log, "Waiting for runner to normalize its clock...");
+
+            if let NormalizedClock { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not normalize its clock"; "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner normalized its clock");
+        }
+
+        if !run_options.host_overrides.is_empty() {
+            info!(self.log, "Waiting for runner to override hosts...");
+
+            if let OverrodeHosts { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not override hosts"; "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner overrode hosts");
+        }
+
+        if run_options.proxy.is_some() {
+            info!(self.log, "Waiting for runner to start proxy...");
+
+            if let StartedProxy { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not start proxy"; "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner started proxy");
+        }
+
+        if run_options.network.is_some() {
+            info!(self.log, "Waiting for runner to condition network...");
+
+            if let ConditionedNetwork { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not condition network"; "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner conditioned network");
+        }
+
+        if run_options.capture_pings {
+            info!(self.log, "Waiting for runner to start capturing pings...");
+
+            if let StartedPingCapture { result: Err(e) } = self.recv().await? {
+                error!(self.log, "Runner could not start capturing pings"; "error" => %e.chain());
+                return Err(e.into());
+            }
+
+            info!(self.log, "Runner started capturing pings");
+        }
+
         if idle == Idle::Wait {
             info!(self.log, "Waiting for runner to become idle...");
 
             if let WaitForIdle { result: Err(e) } = self.recv().await? {
-                error!(self.log, "Runner could not become idle"; "error" => %e);
+                error!(self.log, "Runner could not become idle"; "error" => %e.chain());
                 return Err(e.into());
             }
 
+            self.timeline.record(Phase::Idle);
             info!(self.log, "Runner became idle");
         }
 
-        info!(self.log, "Beginning recording...");
-        let handle = self
-            .recorder
-            .start_recording(directory)
-            .await
-            .map_err(RecorderProtoError::Recording)?;
+        let mut launches = Vec::with_capacity(run_options.relaunches as usize + 1);
+        for launch in 0..=run_options.relaunches {
+            // The recordings of each launch are kept apart.
+            let launch_dir = if run_options.relaunches == 0 {
+                directory.to_owned()
+            } else {
+                let launch_dir = directory.join(launch_name(launch + 1));
+                tokio::fs::create_dir_all(&launch_dir)
+                    .await
+                    .map_err(RecorderProtoError::LaunchDir)?;
+                launch_dir
+            };
 
-        info!(self.log, "requesting Firefox start...");
-        self.send(StartFirefox).await?;
-        if let Err(e) = self.recv::<StartedFirefox>().await?.result {
-            error!(self.log, "recorder could not launch firefox"; "error" => %e);
-            return Err(e.into());
+            if launch > 0 {
+                if run_options.cools_down() {
+                    info!(self.log, "Waiting for runner to cool down...");
+                    self.recv::<CooledDown>().await?;
+                }
+
+                info!(self.log, "Relaunching Firefox"; "launch" => launch + 1);
+            }
+
+            let mut output = self.launch_firefox(run_options, &launch_dir).await?;
+            output.boot_timings = boot_timings.take();
+            launches.push(output);
         }
-        info!(self.log, "runner started Firefox.");
 
-        let recording_path = self
-            .recorder
-            .wait_for_recording_finished(handle)
-            .await
-            .map_err(RecorderProtoError::Recording)?;
+        let mut artifacts = Vec::new();
+        let mut reported_sizes = BTreeMap::new();
+        let pings_path = if run_options.capture_pings {
+            self.recv_pings(directory, &mut artifacts, &mut reported_sizes)
+                .await?
+        } else {
+            None
+        };
+
+        let memory_report_path = if run_options.memory_report {
+            self.recv_memory_report(directory, &mut artifacts, &mut reported_sizes)
+                .await?
+        } else {
+            None
+        };
+
+        let profile_path = if run_options.return_profile {
+            match self.recv_profile(directory, &mut reported_sizes).await? {
+                ReturnedProfile::Received(profile_path) => Some(profile_path),
+                ReturnedProfile::Uploaded(artifact) => {
+                    artifacts.push(artifact);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let runner_log = match self.recv::<SessionLog>().await?.result {
+            Ok(runner_log) => Some(runner_log),
+            Err(e) => {
+                warn!(self.log, "runner could not send its log"; "error" => %e.chain());
+                None
+            }
+        };
+
+        let finished = self.recv::<SessionFinished>().await?;
+        if let Err(e) = finished.result {
+            warn!(self.log, "runner did not clean up successfully"; "error" => ?e);
+        }
+        if let Some(bandwidth) = finished.bandwidth {
+            info!(
+                self.log,
+                "runner transferred bytes for session";
+                "downloaded" => bandwidth.downloaded,
+                "sent" => bandwidth.sent,
+                "received" => bandwidth.received,
+            );
+        }
+        self.timeline.record(Phase::Finished);
+
+        info!(self.log, "recording complete");
+
+        // The session will be resumed again once the runner has restarted.
+        if run_options.restart_after {
+            self.recv_restarting().await?;
+        }
+
+        Ok(SessionOutput {
+            launches,
+            build,
+            profile_path,
+            pings_path,
+            memory_report_path,
+            artifacts,
+            reported_sizes,
+            runner_log,
+            bandwidth: finished.bandwidth,
+        })
+    }
+
+    /// Have the runner launch Firefox, measure the launch, and then have the
+    /// runner stop Firefox.
+    ///
+    /// The recording of the launch is written to `directory`.
+    async fn launch_firefox(
+        &mut self,
+        run_options: &RunOptions,
+        directory: &Path,
+    ) -> Result<LaunchOutput, RecorderProtoError<R::Error>> {
+        let (recording_path, navigate_result) = match &run_options.session_type {
+            SessionType::ColdStart if run_options.headless_measurement => {
+                // The runner only reports that Firefox has started once it has
+                // finished starting up.
+                self.start_firefox().await?;
+
+                (None, Ok(()))
+            }
+
+            SessionType::ColdStart => {
+                info!(self.log, "Beginning recording...");
+                let handle = self
+                    .recorder
+                    .start_recording(directory)
+                    .await
+                    .map_err(RecorderProtoError::Recording)?;
+                self.timeline.record(Phase::CaptureStarted);
+
+                self.start_firefox().await?;
+
+                let recording_path = with_timeout(
+                    TimeoutPhase::Capture,
+                    self.timeouts.get(TimeoutPhase::Capture),
+                    self.recorder.wait_for_recording_finished(handle),
+                )
+                .await?
+                .map_err(RecorderProtoError::Recording)?;
+                self.timeline.record(Phase::CaptureStopped);
+
+                (Some(recording_path), Ok(()))
+            }
+
+            SessionType::PageLoad { url } => {
+                self.start_firefox().await?;
+
+                let handle = if run_options.headless_measurement {
+                    None
+                } else {
+                    info!(self.log, "Beginning recording...");
+                    let handle = self
+                        .recorder
+                        .start_recording(directory)
+                        .await
+                        .map_err(RecorderProtoError::Recording)?;
+                    self.timeline.record(Phase::CaptureStarted);
+
+                    Some(handle)
+                };
+
+                info!(self.log, "requesting runner navigate Firefox..."; "url" => url);
+                self.send(Navigate).await?;
+                let navigate_result = with_timeout(
+                    TimeoutPhase::Capture,
+                    self.timeouts.get(TimeoutPhase::Capture),
+                    self.recv::<Navigated>(),
+                )
+                .await??
+                .result;
+
+                match navigate_result {
+                    Ok(()) => info!(self.log, "page loaded"),
+                    Err(ref e) => {
+                        error!(self.log, "runner could not navigate Firefox"; "error" => %e.chain())
+                    }
+                }
+
+                let recording_path = match handle {
+                    Some(handle) => {
+                        let recording_path = self
+                            .recorder
+                            .stop_recording(handle)
+                            .await
+                            .map_err(RecorderProtoError::Recording)?;
+                        self.timeline.record(Phase::CaptureStopped);
+
+                        Some(recording_path)
+                    }
+                    None => None,
+                };
+
+                (recording_path, navigate_result)
+            }
+        };
 
         info!(self.log, "requesting runner stop Firefox...");
         self.send(StopFirefox).await?;
@@ -192,7 +900,7 @@ where
                     warn!(
                         self.log,
                         "recorder could not stop firefox (multiple errors)";
-                        "error" => %error
+                        "error" => %error.chain()
                     );
                 }
             } else {
@@ -200,20 +908,60 @@ where
                 warn!(
                     self.log,
                     "recorder could not stop Firefox";
-                    "error" => %errors[0]
+                    "error" => %errors[0].chain()
                 );
             }
         }
 
+        self.timeline.record(Phase::FirefoxStopped);
         info!(self.log, "runner stopped Firefox");
 
-        if let Err(e) = self.recv::<SessionFinished>().await?.result {
-            warn!(self.log, "runner did not clean up successfully"; "error" => ?e);
-        }
+        // The runner does not finish the session if it could not navigate.
+        navigate_result?;
 
-        info!(self.log, "recording complete");
+        let StartupTelemetry {
+            result,
+            startup_cache,
+            graphics,
+        } = self.recv::<StartupTelemetry>().await?;
+
+        let startup_metrics = match result {
+            Ok(startup_metrics) => startup_metrics,
+            Err(e) => {
+                warn!(self.log, "runner could not extract startup telemetry"; "error" => %e.chain());
+                BTreeMap::new()
+            }
+        };
+
+        Ok(LaunchOutput {
+            recording_path,
+            startup_metrics,
+            startup_cache,
+            graphics,
+            boot_timings: None,
+        })
+    }
+
+    /// Request the runner start Firefox.
+    async fn start_firefox(&mut self) -> Result<(), RecorderProtoError<R::Error>> {
+        info!(self.log, "requesting Firefox start...");
+        self.send(StartFirefox).await?;
+
+        let started = with_timeout(
+            TimeoutPhase::Launch,
+            self.timeouts.get(TimeoutPhase::Launch),
+            self.recv::<StartedFirefox>(),
+        )
+        .await??;
+
+        if let Err(e) = started.result {
+            error!(self.log, "recorder could not launch firefox"; "error" => %e.chain());
+            return Err(e.into());
+        }
+        self.timeline.record(Phase::FirefoxLaunched);
+        info!(self.log, "runner started Firefox.");
 
-        Ok(recording_path)
+        Ok(())
     }
 
     /// Send the profile at the given path to the runner.
@@ -226,6 +974,7 @@ where
 
         match result? {
             DownloadStatus::Downloading => {
+                self.timeline.record(Phase::ProfileTransferStarted);
                 info!(self.log, "Sending profile"; "profile_size" => profile_size);
             }
 
@@ -238,10 +987,10 @@ where
         }
 
         let mut stream = self.inner.take().unwrap().into_inner();
-        let result = Self::send_profile_impl(&mut stream, profile_path).await;
+        let result = Self::send_file(&mut stream, profile_path, self.codec).await;
         self.inner = Some(Proto::new(stream));
 
-        result?;
+        self.record_transfer(Payload::Profile, result?);
 
         let mut state = DownloadStatus::Downloading;
         loop {
@@ -260,8 +1009,10 @@ where
             state = next_state;
 
             match state {
-                // This would be caught above because this is never an expected state.
-                DownloadStatus::Downloading => unreachable!(),
+                // These would be caught above because they are never expected states.
+                DownloadStatus::Downloading
+                | DownloadStatus::Progress(..)
+                | DownloadStatus::Extracting(..) => unreachable!(),
 
                 DownloadStatus::Downloaded => {
                     info!(self.log, "Profile sent; extracting...");
@@ -279,17 +1030,266 @@ where
         Ok(())
     }
 
-    /// Write the raw bytes from the profile to the runner.
-    async fn send_profile_impl(
-        stream: &mut TcpStream,
+    /// Send the zip archive of the distribution to the runner and wait for it
+    /// to install it.
+    async fn send_distribution(
+        &mut self,
+        distribution_size: u64,
+    ) -> Result<(), RecorderProtoError<R::Error>> {
+        let RecvDistribution = self.recv().await?;
+        info!(self.log, "Sending distribution"; "size" => distribution_size);
+
+        let distribution_path = self.distribution_path.clone().unwrap();
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = Self::send_file(&mut stream, &distribution_path, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.record_transfer(Payload::Distribution, result?);
+
+        if let InstalledDistribution { result: Err(e) } = self.recv().await? {
+            error!(self.log, "Runner could not install distribution"; "error" => %e.chain());
+            return Err(e.into());
+        }
+
+        info!(self.log, "Runner installed distribution");
+        Ok(())
+    }
+
+    /// Send the zip archive of extensions to the runner and wait for it to
+    /// install them.
+    async fn send_extensions(
+        &mut self,
+        extensions_size: u64,
+    ) -> Result<(), RecorderProtoError<R::Error>> {
+        let RecvExtensions = self.recv().await?;
+        info!(self.log, "Sending extensions"; "size" => extensions_size);
+
+        let extensions_path = self.extensions_path.clone().unwrap();
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = Self::send_file(&mut stream, &extensions_path, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.record_transfer(Payload::Extensions, result?);
+
+        match self.recv::<InstalledExtensions>().await?.result {
+            Ok(ids) => {
+                info!(self.log, "Runner installed extensions"; "ids" => ids.join(", "));
+                Ok(())
+            }
+            Err(e) => {
+                error!(self.log, "Runner could not install extensions"; "error" => %e.chain());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Send the files in the profile at the given path that differ from the
+    /// profile cached on the runner.
+    async fn send_profile_delta(
+        &mut self,
         profile_path: &Path,
     ) -> Result<(), RecorderProtoError<R::Error>> {
-        let mut f = File::open(profile_path).await?;
+        let ProfileManifest { manifest } = self.recv().await?;
+        info!(self.log, "Received cached profile manifest"; "files" => manifest.len());
+
+        let tempdir = tempfile::tempdir()?;
+        let delta_path = tempdir.path().join("profile_delta.zip");
+
+        let removed = spawn_blocking({
+            let profile_path = profile_path.to_owned();
+            let delta_path = delta_path.clone();
+            move || write_profile_delta(&profile_path, &manifest, &delta_path)
+        })
+        .await
+        .expect("profile delta task was cancelled or panicked")?;
+
+        let size = tokio::fs::metadata(&delta_path).await?.len();
+        self.check_profile_size(size)?;
+
+        info!(
+            self.log,
+            "Sending profile delta";
+            "size" => size,
+            "removed" => removed.len(),
+        );
+        self.send(ProfileDelta { size, removed }).await?;
+
+        self.send_profile(&delta_path, size).await
+    }
+
+    /// Receive the zipped profile from the runner after the session.
+    ///
+    /// The profile is written to `profile.zip` in the given directory, unless
+    /// the runner uploaded it.
+    async fn recv_profile(
+        &mut self,
+        directory: &Path,
+        reported_sizes: &mut BTreeMap<PathBuf, u64>,
+    ) -> Result<ReturnedProfile, RecorderProtoError<R::Error>> {
+        let ReturnProfile { result, uploaded } = self.recv::<ReturnProfile>().await?;
+
+        let profile_size = match result {
+            Ok(profile_size) => profile_size,
+            Err(e) => {
+                error!(self.log, "runner could not return profile"; "error" => %e.chain());
+                return Err(e.into());
+            }
+        };
 
-        tokio::io::copy(&mut f, stream)
+        if let Some(artifact) = uploaded {
+            info!(self.log, "Runner uploaded profile"; "url" => &artifact.url, "profile_size" => profile_size);
+            return Ok(ReturnedProfile::Uploaded(artifact));
+        }
+
+        info!(self.log, "Receiving profile"; "profile_size" => profile_size);
+
+        let profile_path = directory.join("profile.zip");
+        let mut f = File::create(&profile_path).await?;
+        self.send(RecvReturned).await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = recv_payload(&mut stream, &mut f, profile_size, self.codec, |_| {}).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.record_transfer(Payload::ReturnedProfile, result?);
+
+        info!(self.log, "Received profile"; "path" => profile_path.display());
+        reported_sizes.insert(profile_path.clone(), profile_size);
+        Ok(ReturnedProfile::Received(profile_path))
+    }
+
+    /// Receive the zipped telemetry pings from the runner after the session.
+    ///
+    /// The pings are written to `pings.zip` in the given directory, unless
+    /// the runner uploaded them, in which case they are added to the
+    /// artifacts. Failing to capture pings does not fail the session.
+    async fn recv_pings(
+        &mut self,
+        directory: &Path,
+        artifacts: &mut Vec<UploadedArtifact>,
+        reported_sizes: &mut BTreeMap<PathBuf, u64>,
+    ) -> Result<Option<PathBuf>, RecorderProtoError<R::Error>> {
+        let TelemetryPings { result, uploaded } = self.recv::<TelemetryPings>().await?;
+
+        let captured = match result {
+            Ok(captured) => captured,
+            Err(e) => {
+                warn!(self.log, "runner could not return pings"; "error" => %e.chain());
+                return Ok(None);
+            }
+        };
+
+        if let Some(artifact) = uploaded {
+            info!(self.log, "Runner uploaded pings"; "url" => &artifact.url, "count" => captured.count);
+            artifacts.push(artifact);
+            return Ok(None);
+        }
+
+        info!(self.log, "Receiving pings"; "count" => captured.count, "size" => captured.size);
+
+        let pings_path = directory.join("pings.zip");
+        let mut f = File::create(&pings_path).await?;
+        self.send(RecvReturned).await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = recv_payload(&mut stream, &mut f, captured.size, self.codec, |_| {}).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.record_transfer(Payload::TelemetryPings, result?);
+
+        info!(self.log, "Received pings"; "path" => pings_path.display());
+        reported_sizes.insert(pings_path.clone(), captured.size);
+        Ok(Some(pings_path))
+    }
+
+    /// Receive the gzipped memory report from the runner after the session.
+    ///
+    /// The report is written to `memory-report.json.gz` in the given
+    /// directory, unless the runner uploaded it, in which case it is added to
+    /// the artifacts. Failing to collect the report does not fail the session.
+    async fn recv_memory_report(
+        &mut self,
+        directory: &Path,
+        artifacts: &mut Vec<UploadedArtifact>,
+        reported_sizes: &mut BTreeMap<PathBuf, u64>,
+    ) -> Result<Option<PathBuf>, RecorderProtoError<R::Error>> {
+        let MemoryReport { result, uploaded } = self.recv::<MemoryReport>().await?;
+
+        let size = match result {
+            Ok(size) => size,
+            Err(e) => {
+                warn!(self.log, "runner could not collect memory report"; "error" => %e.chain());
+                return Ok(None);
+            }
+        };
+
+        if let Some(artifact) = uploaded {
+            info!(self.log, "Runner uploaded memory report"; "url" => &artifact.url, "size" => size);
+            artifacts.push(artifact);
+            return Ok(None);
+        }
+
+        info!(self.log, "Receiving memory report"; "size" => size);
+
+        let report_path = directory.join("memory-report.json.gz");
+        let mut f = File::create(&report_path).await?;
+        self.send(RecvReturned).await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = recv_payload(&mut stream, &mut f, size, self.codec, |_| {}).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.record_transfer(Payload::MemoryReport, result?);
+
+        info!(self.log, "Received memory report"; "path" => report_path.display());
+        reported_sizes.insert(report_path.clone(), size);
+        Ok(Some(report_path))
+    }
+
+    /// Write the bytes of the file at the given path to the runner,
+    /// compressed with the given codec if the file is large enough.
+    async fn send_file(
+        stream: &mut St,
+        path: &Path,
+        codec: Codec,
+    ) -> Result<TransferStats, RecorderProtoError<R::Error>> {
+        let mut f = File::open(path).await?;
+        let size = f.metadata().await?.len();
+
+        send_payload(&mut f, stream, size, codec)
             .await
             .map_err(Into::into)
-            .map(drop)
+    }
+
+    /// Fail if the runner does not accept a profile of the given size, so that
+    /// it is not sent only to be refused.
+    fn check_profile_size(&self, size: u64) -> Result<(), RecorderProtoError<R::Error>> {
+        match self.max_profile_size {
+            Some(max) if size > max => {
+                error!(
+                    self.log,
+                    "Profile is larger than the runner accepts";
+                    "size" => size,
+                    "max_profile_size" => max,
+                );
+                Err(RecorderProtoError::ProfileTooLarge { size, max })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Record the transfer of a payload in the timeline.
+    fn record_transfer(&self, payload: Payload, stats: TransferStats) {
+        info!(
+            self.log, "Transferred payload";
+            "payload" => ?payload,
+            "codec" => %stats.codec,
+            "size" => stats.size,
+            "wire_size" => stats.wire_size,
+        );
+        self.timeline.record_transfer(payload, stats);
     }
     /// Send the given message to the recorder.
     ///
@@ -333,15 +1333,84 @@ where
         received: DownloadStatus,
     },
 
+    #[error("Could not compute profile delta: {}", .0)]
+    ProfileDelta(#[from] DeltaError),
+
+    #[error(
+        "The profile is {} bytes, but the runner accepts at most {} bytes",
+        .size,
+        .max
+    )]
+    ProfileTooLarge { size: u64, max: u64 },
+
     #[error(transparent)]
     Recording(RecordingError),
+
+    #[error("Could not create recording directory: {}", .0)]
+    LaunchDir(#[source] io::Error),
+
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+}
+
+impl<RecordingError> RecorderProtoError<RecordingError>
+where
+    RecordingError: Error + 'static,
+{
+    /// Whether or not the error was caused by the connection to the runner
+    /// failing, in which case the request may be retried.
+    pub fn is_network_error(&self) -> bool {
+        matches!(
+            self,
+            RecorderProtoError::Proto(ProtoError::Io(..))
+                | RecorderProtoError::Proto(ProtoError::EndOfStream)
+        )
+    }
+
+    /// Whether or not the runner refused the request because it was serving
+    /// another recorder, in which case the request may be retried once the
+    /// runner is free.
+    pub fn is_busy(&self) -> bool {
+        self.foreign_kind() == Some(ForeignErrorKind::Busy)
+    }
+
+    /// The error the runner reported, if the request failed on the runner.
+    pub fn foreign_error(&self) -> Option<&ForeignError> {
+        match self {
+            RecorderProtoError::Proto(ProtoError::Foreign(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// The kind of error the runner reported, if the request failed on the
+    /// runner.
+    pub fn foreign_kind(&self) -> Option<ForeignErrorKind> {
+        self.foreign_error().map(|e| e.kind)
+    }
+}
+
+/// Return the name of a launch of Firefox in a session, which the files
+/// written for it are grouped under.
+pub fn launch_name(launch: u32) -> String {
+    format!("launch-{}", launch)
+}
+
+/// Return a new ID for a request to the runner.
+///
+/// IDs are unique to the recorder process and the time they were created.
+pub fn new_request_id() -> String {
+    format!(
+        "{}-{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+        std::process::id()
+    )
 }
 
-impl<RecordingError> From<ErrorMessage<String>> for RecorderProtoError<RecordingError>
+impl<RecordingError> From<ForeignError> for RecorderProtoError<RecordingError>
 where
     RecordingError: Error + 'static,
 {
-    fn from(e: ErrorMessage<String>) -> Self {
+    fn from(e: ForeignError) -> Self {
         RecorderProtoError::Proto(ProtoError::from(e))
     }
 }
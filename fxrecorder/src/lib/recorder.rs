@@ -41,6 +41,11 @@ pub trait Recorder {
         &self,
         handle: Self::Handle,
     ) -> Result<PathBuf, Self::Error>;
+
+    /// Stop the recording indicated by `handle` immediately.
+    ///
+    /// The path to the recording is returned.
+    async fn stop_recording(&self, handle: Self::Handle) -> Result<PathBuf, Self::Error>;
 }
 
 /// A Recorder that uses `ffmpeg`.
@@ -154,17 +159,21 @@ impl<'a> Recorder for FfmpegRecorder<'a> {
         &self,
         handle: Self::Handle,
     ) -> Result<PathBuf, Self::Error> {
+        delay_for(Duration::from_secs(
+            self.config.minimum_recording_time_secs as u64,
+        ))
+        .await;
+
+        self.stop_recording(handle).await
+    }
+
+    async fn stop_recording(&self, handle: Self::Handle) -> Result<PathBuf, Self::Error> {
         let FfmpegRecordingHandle {
             output_path,
             task_join_handle,
             mut ffmpeg_stdin,
         } = handle;
 
-        delay_for(Duration::from_secs(
-            self.config.minimum_recording_time_secs as u64,
-        ))
-        .await;
-
         info!(self.log, "requesting ffmpeg to finish recording...");
 
         ffmpeg_stdin
@@ -0,0 +1,191 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Selecting a runner by its tags instead of by its address.
+//!
+//! A registry is a TOML file listing the runners in a lab and the tags that
+//! describe each of them, e.g.,
+//!
+//! ```toml
+//! [[runner]]
+//! name = "lab1-win10-nvidia"
+//! host = "10.0.0.2:8888"
+//! tags = { os = "win10", gpu = "nvidia", display = "1366x768", location = "yto" }
+//!
+//! [[runner]]
+//! name = "lab1-win10-intel"
+//! host = "10.0.0.3:8888"
+//! tags = { os = "win10", gpu = "intel", display = "1366x768", location = "yto" }
+//! ```
+//!
+//! The first runner that has every requested tag is selected.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// A list of runners and their tags.
+#[derive(Debug, Deserialize)]
+pub struct Registry {
+    /// The runners, in order of preference.
+    #[serde(rename = "runner", default)]
+    pub runners: Vec<RunnerEntry>,
+}
+
+/// A runner listed in a [`Registry`](struct.Registry.html).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RunnerEntry {
+    /// The name of the runner, which is only used for logging.
+    pub name: String,
+
+    /// The address of the runner, in the same form as the `host`
+    /// configuration option.
+    pub host: String,
+
+    /// The tags describing the runner, e.g., `os = "win10"`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Registry {
+    /// Load the registry at the given path.
+    pub fn load(path: &Path) -> Result<Self, RegistryError> {
+        let contents = fs::read_to_string(path).map_err(|source| RegistryError::Io {
+            path: path.into(),
+            source,
+        })?;
+
+        Self::parse(&contents)
+    }
+
+    /// Parse a registry from its contents.
+    pub fn parse(contents: &str) -> Result<Self, RegistryError> {
+        let registry: Registry = toml::from_str(contents)?;
+
+        let mut names = BTreeSet::new();
+        for runner in &registry.runners {
+            if !names.insert(runner.name.as_str()) {
+                return Err(RegistryError::DuplicateRunner(runner.name.clone()));
+            }
+        }
+
+        Ok(registry)
+    }
+
+    /// Return the first runner that has all of the given tags.
+    pub fn select(&self, tags: &[(String, String)]) -> Result<&RunnerEntry, RegistryError> {
+        self.runners
+            .iter()
+            .find(|runner| runner.has_tags(tags))
+            .ok_or_else(|| {
+                RegistryError::NoMatchingRunner(
+                    tags.iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            })
+    }
+}
+
+impl RunnerEntry {
+    /// Return whether or not the runner has all of the given tags.
+    pub fn has_tags(&self, tags: &[(String, String)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("Could not read runner registry `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Could not parse runner registry: {}", .0)]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Duplicate runner `{}' in registry", .0)]
+    DuplicateRunner(String),
+
+    #[error("No runner in the registry has the tags {}", .0)]
+    NoMatchingRunner(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(key, value)| ((*key).into(), (*value).into()))
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        let registry = Registry::parse(
+            r#"
+            [[runner]]
+            name = "win10-intel"
+            host = "10.0.0.3:8888"
+            tags = { os = "win10", gpu = "intel" }
+
+            [[runner]]
+            name = "win10-nvidia"
+            host = "10.0.0.2:8888"
+            tags = { os = "win10", gpu = "nvidia" }
+
+            [[runner]]
+            name = "untagged"
+            host = "10.0.0.4:8888"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(registry.select(&[]).unwrap().name, "win10-intel");
+        assert_eq!(
+            registry.select(&tags(&[("os", "win10")])).unwrap().name,
+            "win10-intel"
+        );
+        assert_eq!(
+            registry
+                .select(&tags(&[("gpu", "nvidia"), ("os", "win10")]))
+                .unwrap()
+                .host,
+            "10.0.0.2:8888"
+        );
+
+        match registry.select(&tags(&[("gpu", "nvidia"), ("os", "win7")])) {
+            Err(RegistryError::NoMatchingRunner(tags)) => assert_eq!(tags, "gpu=nvidia, os=win7"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(matches!(
+            Registry::parse("[[runner]]\nname = \"a\""),
+            Err(RegistryError::Parse(..))
+        ));
+
+        match Registry::parse(
+            r#"
+            [[runner]]
+            name = "a"
+            host = "10.0.0.2:8888"
+
+            [[runner]]
+            name = "a"
+            host = "10.0.0.3:8888"
+            "#,
+        ) {
+            Err(RegistryError::DuplicateRunner(name)) => assert_eq!(name, "a"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing results so that they cannot be edited without detection.
+//!
+//! Results are signed with HMAC-SHA256 using a key shared by the recorders
+//! and whoever audits their results. The signature is written alongside the
+//! results, and names the key it was made with.
+
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+/// The only supported signature algorithm.
+pub const HMAC_SHA256: &str = "hmac-sha256";
+
+/// The signature of a results file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Signature {
+    /// The ID of the key the results were signed with.
+    pub key_id: String,
+
+    /// The algorithm the results were signed with.
+    pub algorithm: String,
+
+    /// The hex-encoded signature.
+    pub signature: String,
+}
+
+impl Signature {
+    /// Sign the given contents with the key.
+    pub fn sign(key_id: &str, key: &[u8], contents: &[u8]) -> Self {
+        let mut mac = hmac(key);
+        mac.update(contents);
+
+        Signature {
+            key_id: key_id.into(),
+            algorithm: HMAC_SHA256.into(),
+            signature: hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+
+    /// Verify that the contents were signed with the key.
+    pub fn verify(&self, key_id: &str, key: &[u8], contents: &[u8]) -> Result<(), SigningError> {
+        if self.algorithm != HMAC_SHA256 {
+            return Err(SigningError::Algorithm(self.algorithm.clone()));
+        }
+
+        if self.key_id != key_id {
+            return Err(SigningError::KeyId(self.key_id.clone()));
+        }
+
+        let signature = hex::decode(&self.signature).map_err(|_| SigningError::Mismatch)?;
+
+        let mut mac = hmac(key);
+        mac.update(contents);
+        mac.verify(&signature).map_err(|_| SigningError::Mismatch)
+    }
+}
+
+/// Return a new HMAC-SHA256 with the given key.
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length.
+    Hmac::<Sha256>::new_varkey(key).expect("HMAC key was rejected")
+}
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error("Unsupported signature algorithm `{}'", .0)]
+    Algorithm(String),
+
+    #[error("The results were signed with a different key (`{}')", .0)]
+    KeyId(String),
+
+    #[error("The signature does not match the results")]
+    Mismatch,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let signature = Signature::sign("audit", b"secret", b"{\"SpeedIndex\": 1000}");
+        assert_eq!(signature.key_id, "audit");
+        assert_eq!(signature.algorithm, HMAC_SHA256);
+
+        signature
+            .verify("audit", b"secret", b"{\"SpeedIndex\": 1000}")
+            .unwrap();
+
+        assert!(matches!(
+            signature.verify("audit", b"secret", b"{\"SpeedIndex\": 900}"),
+            Err(SigningError::Mismatch)
+        ));
+        assert!(matches!(
+            signature.verify("audit", b"other secret", b"{\"SpeedIndex\": 1000}"),
+            Err(SigningError::Mismatch)
+        ));
+        assert!(matches!(
+            signature.verify("other", b"secret", b"{\"SpeedIndex\": 1000}"),
+            Err(SigningError::KeyId(ref key_id)) if key_id == "audit"
+        ));
+    }
+}
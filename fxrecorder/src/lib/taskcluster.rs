@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Waiting for builds to appear in the Taskcluster index.
+//!
+//! Builds are only indexed once their build task completes, so a recording of
+//! a revision that was just pushed (e.g., to try) polls the index until its
+//! build task is indexed and then records the session with that task.
+
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use slog::{info, warn, Logger};
+use thiserror::Error;
+use tokio::time::delay_for;
+
+/// The root URL of Firefox CI.
+pub const FIREFOX_CI_ROOT_URL: &str = "https://firefox-ci-tc.services.mozilla.com/";
+
+/// How often the index is polled for a build that is not yet indexed.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A task in the index.
+#[derive(Debug, Deserialize)]
+struct IndexedTask {
+    #[serde(rename = "taskId")]
+    task_id: String,
+}
+
+/// A client for the Taskcluster Index API.
+#[derive(Debug)]
+pub struct TaskclusterIndex {
+    client: Client,
+    root_url: String,
+}
+
+impl TaskclusterIndex {
+    /// Create a client for the index of the Taskcluster deployment at the
+    /// given root URL.
+    pub fn new(root_url: &str) -> Self {
+        TaskclusterIndex {
+            client: Client::new(),
+            root_url: root_url.into(),
+        }
+    }
+
+    /// Return the ID of the task indexed at the given route, or `None` if no
+    /// task is indexed there yet.
+    pub async fn find_task(&self, route: &str) -> Result<Option<String>, TaskclusterError> {
+        let url = index_task_url(&self.root_url, route)?;
+
+        let rsp = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|source| TaskclusterError::Request {
+                url: url.clone(),
+                source,
+            })?;
+
+        let status = rsp.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(None);
+        } else if !status.is_success() {
+            let body = rsp.text().await.unwrap_or_default();
+            return Err(TaskclusterError::Rejected { url, status, body });
+        }
+
+        let body = rsp
+            .bytes()
+            .await
+            .map_err(|source| TaskclusterError::Request {
+                url: url.clone(),
+                source,
+            })?;
+
+        let task: IndexedTask = serde_json::from_slice(&body)
+            .map_err(|source| TaskclusterError::Parse { url, source })?;
+
+        Ok(Some(task.task_id))
+    }
+
+    /// Poll the index until a task is indexed at the given route, returning
+    /// its ID.
+    ///
+    /// Requests that fail are retried at the next poll, so only a timeout or
+    /// an invalid route ends the wait early.
+    pub async fn wait_for_task(
+        &self,
+        log: &Logger,
+        route: &str,
+        timeout: Duration,
+    ) -> Result<String, TaskclusterError> {
+        let start = Instant::now();
+
+        loop {
+            match self.find_task(route).await {
+                Ok(Some(task_id)) => return Ok(task_id),
+                Ok(None) => info!(
+                    log,
+                    "Build is not indexed yet";
+                    "route" => route,
+                    "waited_secs" => start.elapsed().as_secs(),
+                ),
+                Err(e @ TaskclusterError::Route { .. }) => return Err(e),
+                Err(e) => warn!(log, "Could not query the index"; "route" => route, "error" => %e),
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(TaskclusterError::Timeout {
+                    route: route.into(),
+                    timeout,
+                });
+            }
+
+            delay_for(POLL_INTERVAL.min(timeout - elapsed)).await;
+        }
+    }
+}
+
+/// Return the URL of the Index API endpoint for the task indexed at the given
+/// route.
+fn index_task_url(root_url: &str, route: &str) -> Result<Url, TaskclusterError> {
+    let mut root_url = root_url.to_owned();
+    if !root_url.ends_with('/') {
+        root_url.push('/');
+    }
+
+    Url::parse(&root_url)
+        .and_then(|url| url.join(&format!("api/index/v1/task/{}", route)))
+        .map_err(|source| TaskclusterError::Route {
+            route: route.into(),
+            source,
+        })
+}
+
+#[derive(Debug, Error)]
+pub enum TaskclusterError {
+    #[error("Could not build the index URL for `{}': {}", .route, .source)]
+    Route {
+        route: String,
+        source: url::ParseError,
+    },
+
+    #[error("Could not query the index at `{}': {}", .url, .source)]
+    Request { url: Url, source: reqwest::Error },
+
+    #[error("Index request to `{}' failed with status {}: {}", .url, .status, .body)]
+    Rejected {
+        url: Url,
+        status: StatusCode,
+        body: String,
+    },
+
+    #[error("Could not parse the index response from `{}': {}", .url, .source)]
+    Parse { url: Url, source: serde_json::Error },
+
+    #[error("No build was indexed at `{}' within {} seconds", .route, .timeout.as_secs())]
+    Timeout { route: String, timeout: Duration },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_index_task_url() {
+        let route = "gecko.v2.try.revision.abcdef.firefox.win64-opt";
+        let expected = "https://firefox-ci-tc.services.mozilla.com/api/index/v1/task/gecko.v2.try.revision.abcdef.firefox.win64-opt";
+
+        assert_eq!(
+            index_task_url(FIREFOX_CI_ROOT_URL, route).unwrap().as_str(),
+            expected
+        );
+        assert_eq!(
+            index_task_url("https://firefox-ci-tc.services.mozilla.com", route)
+                .unwrap()
+                .as_str(),
+            expected
+        );
+        assert!(matches!(
+            index_task_url("not a url", route),
+            Err(TaskclusterError::Route { .. })
+        ));
+    }
+}
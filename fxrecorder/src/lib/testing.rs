@@ -0,0 +1,58 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for testing the recorder side of the protocol.
+//!
+//! This module is only available with the `testing` feature.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use slog::Logger;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::proto::RecorderProto;
+use crate::recorder::Recorder;
+
+/// A [`Recorder`](../recorder/trait.Recorder.html) that does not record
+/// anything.
+///
+/// The recording is reported to be `recording.mp4` in the requested directory,
+/// but the file is never created.
+pub struct TestRecorder;
+
+/// A handle to a recording started by a
+/// [`TestRecorder`](struct.TestRecorder.html).
+pub struct TestRecorderHandle(PathBuf);
+
+#[async_trait]
+impl Recorder for TestRecorder {
+    type Error = io::Error;
+    type Handle = TestRecorderHandle;
+
+    async fn start_recording(&self, directory: &Path) -> Result<Self::Handle, Self::Error> {
+        Ok(TestRecorderHandle(directory.join("recording.mp4")))
+    }
+
+    async fn wait_for_recording_finished(
+        &self,
+        handle: Self::Handle,
+    ) -> Result<PathBuf, Self::Error> {
+        Ok(handle.0)
+    }
+
+    async fn stop_recording(&self, handle: Self::Handle) -> Result<PathBuf, Self::Error> {
+        Ok(handle.0)
+    }
+}
+
+/// Create a [`RecorderProto`](../proto/struct.RecorderProto.html) that uses a
+/// [`TestRecorder`](struct.TestRecorder.html) over the given stream.
+pub fn test_recorder_proto<St>(log: Logger, stream: St) -> RecorderProto<TestRecorder, St>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    RecorderProto::new(log, stream, TestRecorder)
+}
@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A machine-readable timeline of the phases of a session.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use libfxrecord::net::compression::TransferStats;
+use serde::Serialize;
+
+/// A phase of a session that the recorder has reached.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// The recorder connected to the runner to request a new session.
+    Connected,
+
+    /// The runner created the new session.
+    Handshake,
+
+    /// The runner started downloading (or receiving) the build.
+    DownloadStarted,
+
+    /// The runner finished downloading the build.
+    DownloadFinished,
+
+    /// The runner finished extracting the build.
+    BuildExtracted,
+
+    /// The recorder started sending the profile to the runner.
+    ProfileTransferStarted,
+
+    /// The runner finished extracting the profile sent by the recorder.
+    ProfileExtracted,
+
+    /// The runner created a new empty profile.
+    ProfileCreated,
+
+    /// The runner scheduled a restart.
+    RestartRequested,
+
+    /// The recorder re-connected to the runner after its restart.
+    Reconnected,
+
+    /// The runner resumed the session.
+    Resumed,
+
+    /// The runner became idle.
+    Idle,
+
+    /// The recorder started capturing video.
+    CaptureStarted,
+
+    /// The runner launched Firefox.
+    FirefoxLaunched,
+
+    /// The recorder stopped capturing video.
+    CaptureStopped,
+
+    /// The runner stopped Firefox.
+    FirefoxStopped,
+
+    /// The runner finished the session.
+    Finished,
+}
+
+/// A phase transition.
+#[derive(Clone, Debug, Serialize)]
+pub struct TimelineEvent {
+    pub phase: Phase,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A payload sent between the recorder and the runner.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    /// The build uploaded to the runner.
+    Build,
+
+    /// The profile (or profile delta) sent to the runner.
+    Profile,
+
+    /// The extensions sent to the runner.
+    Extensions,
+
+    /// The distribution sent to the runner.
+    Distribution,
+
+    /// The profile returned by the runner after the session.
+    ReturnedProfile,
+
+    /// The telemetry pings captured by the runner during the session.
+    TelemetryPings,
+
+    /// The memory report collected by the runner after startup.
+    MemoryReport,
+}
+
+/// A payload transfer.
+#[derive(Clone, Debug, Serialize)]
+pub struct Transfer {
+    pub payload: Payload,
+
+    #[serde(flatten)]
+    pub stats: TransferStats,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct TimelineInner {
+    session_id: Option<String>,
+    events: Vec<TimelineEvent>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    transfers: Vec<Transfer>,
+}
+
+/// The timeline of a session.
+///
+/// A session spans multiple connections to the runner, so timelines are
+/// shared: clones of a timeline record into the same list of events.
+#[derive(Clone, Debug, Default)]
+pub struct Timeline(Arc<Mutex<TimelineInner>>);
+
+impl Timeline {
+    /// Record that the given phase was reached now.
+    pub fn record(&self, phase: Phase) {
+        self.0.lock().unwrap().events.push(TimelineEvent {
+            phase,
+            timestamp: Utc::now(),
+        });
+    }
+
+    /// Record that the given payload was transferred.
+    pub fn record_transfer(&self, payload: Payload, stats: TransferStats) {
+        self.0
+            .lock()
+            .unwrap()
+            .transfers
+            .push(Transfer { payload, stats });
+    }
+
+    /// Set the ID of the session that the timeline belongs to.
+    pub fn set_session_id(&self, session_id: &str) {
+        self.0.lock().unwrap().session_id = Some(session_id.into());
+    }
+
+    /// Return the recorded events, oldest first.
+    pub fn events(&self) -> Vec<TimelineEvent> {
+        self.0.lock().unwrap().events.clone()
+    }
+
+    /// Return how long each phase of the session took, as far as the timeline
+    /// can tell.
+    pub fn timings(&self) -> PhaseTimings {
+        let inner = self.0.lock().unwrap();
+        let between = |start, end| duration_between(&inner.events, start, end).map(as_millis);
+
+        PhaseTimings {
+            restart_wait: between(Phase::RestartRequested, Phase::Reconnected),
+            download: between(Phase::DownloadStarted, Phase::DownloadFinished),
+            extraction: between(Phase::DownloadFinished, Phase::BuildExtracted),
+            profile_transfer: between(Phase::ProfileTransferStarted, Phase::ProfileExtracted),
+            capture: between(Phase::CaptureStarted, Phase::CaptureStopped),
+            ..Default::default()
+        }
+    }
+
+    /// Write the timeline to the given path as JSON.
+    pub fn write(&self, path: &Path) -> Result<(), io::Error> {
+        let f = File::create(path)?;
+        serde_json::to_writer_pretty(f, &*self.0.lock().unwrap())?;
+
+        Ok(())
+    }
+}
+
+/// How long each phase of a session took, in milliseconds.
+///
+/// Phases that did not happen (or could not be measured) are omitted.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PhaseTimings {
+    /// From the runner scheduling its restart to the recorder re-connecting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_wait: Option<u64>,
+
+    /// From the runner initiating its restart to the runner starting again,
+    /// according to the runner.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<u64>,
+
+    /// From the runner's machine booting to its desktop becoming interactive,
+    /// according to the runner.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_to_desktop: Option<u64>,
+
+    /// Downloading (or uploading) the build.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download: Option<u64>,
+
+    /// Extracting the build after it was downloaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extraction: Option<u64>,
+
+    /// Sending the profile to the runner and extracting it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile_transfer: Option<u64>,
+
+    /// From Firefox's process starting to its first paint, according to its
+    /// telemetry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub launch_to_first_paint: Option<u64>,
+
+    /// Capturing video.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture: Option<u64>,
+
+    /// Analyzing the captured video.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub analysis: Option<u64>,
+}
+
+impl PhaseTimings {
+    /// Whether or not no phases were measured.
+    pub fn is_empty(&self) -> bool {
+        *self == PhaseTimings::default()
+    }
+}
+
+/// Return the time from the first occurrence of `start` to the first
+/// occurrence of `end` after it.
+fn duration_between(events: &[TimelineEvent], start: Phase, end: Phase) -> Option<Duration> {
+    let start_idx = events.iter().position(|event| event.phase == start)?;
+    let end_event = events[start_idx..]
+        .iter()
+        .find(|event| event.phase == end)?;
+
+    (end_event.timestamp - events[start_idx].timestamp)
+        .to_std()
+        .ok()
+}
+
+/// Convert a duration to whole milliseconds.
+pub fn as_millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use libfxrecord::net::compression::Codec;
+
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let timeline = Timeline::default();
+        timeline.record(Phase::Connected);
+
+        let clone = timeline.clone();
+        clone.set_session_id("foo");
+        clone.record(Phase::RestartRequested);
+
+        let phases = timeline
+            .events()
+            .into_iter()
+            .map(|event| event.phase)
+            .collect::<Vec<_>>();
+        assert_eq!(phases, vec![Phase::Connected, Phase::RestartRequested]);
+
+        let json = serde_json::to_value(&*timeline.0.lock().unwrap()).unwrap();
+        assert_eq!(json["session_id"], "foo");
+        assert_eq!(json["events"][1]["phase"], "restart_requested");
+        assert!(json.get("transfers").is_none());
+
+        timeline.record_transfer(
+            Payload::Build,
+            TransferStats {
+                codec: Codec::Deflate,
+                size: 100,
+                wire_size: 40,
+            },
+        );
+
+        let json = serde_json::to_value(&*timeline.0.lock().unwrap()).unwrap();
+        assert_eq!(json["transfers"][0]["payload"], "build");
+        assert_eq!(json["transfers"][0]["codec"], "deflate");
+        assert_eq!(json["transfers"][0]["wire_size"], 40);
+    }
+
+    #[test]
+    fn test_timings() {
+        let timeline = Timeline::default();
+        let start = Utc::now();
+
+        {
+            let mut inner = timeline.0.lock().unwrap();
+            for (phase, offset) in &[
+                (Phase::Connected, 0),
+                (Phase::DownloadStarted, 100),
+                (Phase::DownloadFinished, 1100),
+                (Phase::BuildExtracted, 1600),
+                (Phase::RestartRequested, 2000),
+                (Phase::Reconnected, 62000),
+                (Phase::CaptureStarted, 63000),
+                (Phase::CaptureStopped, 93000),
+            ] {
+                inner.events.push(TimelineEvent {
+                    phase: *phase,
+                    timestamp: start + chrono::Duration::milliseconds(*offset),
+                });
+            }
+        }
+
+        assert_eq!(
+            timeline.timings(),
+            PhaseTimings {
+                restart_wait: Some(60000),
+                download: Some(1000),
+                extraction: Some(500),
+                capture: Some(30000),
+                ..Default::default()
+            }
+        );
+        assert!(PhaseTimings::default().is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Submitting results to Treeherder.
+//!
+//! Each recording is reported as a completed job against the revision of the
+//! recorded build. The Perfherder metrics are attached to the job as its
+//! `performance_data` artifact, which Perfherder ingests the same way as the
+//! `PERFHERDER_DATA` of jobs run in CI.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use libfxrecord::secret::SecretError;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Client, StatusCode, Url};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::config::TreeherderConfig;
+use crate::proto::new_request_id;
+
+/// A job to report to Treeherder.
+#[derive(Debug)]
+pub struct Job<'a> {
+    /// The revision of the recorded build.
+    pub revision: &'a str,
+
+    /// The name of the job, e.g., the Perfherder suite.
+    pub name: &'a str,
+
+    /// The machine that the job ran on.
+    pub machine: &'a str,
+
+    /// When the job started.
+    pub start: DateTime<Utc>,
+
+    /// When the job ended.
+    pub end: DateTime<Utc>,
+
+    /// The Perfherder metrics of the job.
+    pub performance_data: &'a Value,
+}
+
+/// A client for submitting jobs to Treeherder.
+pub struct Treeherder {
+    client: Client,
+    jobs_url: Url,
+    credentials: hawk::Credentials,
+    config: TreeherderConfig,
+}
+
+impl fmt::Debug for Treeherder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The signing key is derived from the secret, so it is left out.
+        f.debug_struct("Treeherder")
+            .field("jobs_url", &self.jobs_url)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Treeherder {
+    /// Create a client from the given configuration.
+    pub fn new(config: &TreeherderConfig) -> Result<Self, TreeherderError> {
+        let mut root_url = Url::parse(&config.root_url)
+            .map_err(|e| TreeherderError::RootUrl(config.root_url.clone(), e))?;
+
+        // Ensure that the API path is joined onto the root URL instead of
+        // replacing its last path segment.
+        if !root_url.path().ends_with('/') {
+            let path = format!("{}/", root_url.path());
+            root_url.set_path(&path);
+        }
+
+        let jobs_url = root_url
+            .join(&format!("api/project/{}/jobs/", config.repository))
+            .map_err(|e| TreeherderError::RootUrl(config.root_url.clone(), e))?;
+
+        let secret = config.secret.resolve()?;
+        let key = hawk::Key::new(secret.as_bytes(), hawk::SHA256).map_err(TreeherderError::Sign)?;
+
+        Ok(Treeherder {
+            client: Client::new(),
+            jobs_url,
+            credentials: hawk::Credentials {
+                id: config.client_id.clone(),
+                key,
+            },
+            config: config.clone(),
+        })
+    }
+
+    /// Submit the job to Treeherder.
+    ///
+    /// Returns the GUID of the submitted job.
+    pub async fn submit(&self, job: &Job<'_>) -> Result<String, TreeherderError> {
+        let job_guid = format!("fxrecord-{}", new_request_id());
+        let body = serde_json::to_vec(&self.job_submission(job, &job_guid))
+            .expect("could not serialize job submission");
+
+        let payload_hash = hawk::PayloadHasher::hash("application/json", hawk::SHA256, &body)
+            .map_err(TreeherderError::Sign)?;
+        let header = hawk::RequestBuilder::from_url("POST", &self.jobs_url)
+            .map_err(TreeherderError::Sign)?
+            .hash(&payload_hash[..])
+            .request()
+            .make_header(&self.credentials)
+            .map_err(TreeherderError::Sign)?;
+
+        let rsp = self
+            .client
+            .post(self.jobs_url.clone())
+            .header(AUTHORIZATION, format!("Hawk {}", header))
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, "fxrecorder")
+            .body(body)
+            .send()
+            .await?;
+
+        let status = rsp.status();
+        if !status.is_success() {
+            let body = rsp.text().await.unwrap_or_default();
+            return Err(TreeherderError::Rejected { status, body });
+        }
+
+        Ok(job_guid)
+    }
+
+    /// Build the body of a request submitting the job.
+    fn job_submission(&self, job: &Job, job_guid: &str) -> Value {
+        let config = &self.config;
+        let platform = json!({
+            "platform": config.platform,
+            "os_name": config.os_name,
+            "architecture": config.architecture,
+        });
+
+        json!([{
+            "project": config.repository,
+            "revision": job.revision,
+            "job": {
+                "job_guid": job_guid,
+                "name": job.name,
+                "job_symbol": config.job_symbol,
+                "group_name": "fxrecord",
+                "group_symbol": config.group_symbol,
+                "product_name": "firefox",
+                "state": "completed",
+                "result": "success",
+                "reason": "scheduled",
+                "who": "fxrecord",
+                "tier": 2,
+                "submit_timestamp": job.start.timestamp(),
+                "start_timestamp": job.start.timestamp(),
+                "end_timestamp": job.end.timestamp(),
+                "machine": job.machine,
+                "build_platform": platform,
+                "machine_platform": platform,
+                "option_collection": { "opt": true },
+                "log_references": [],
+                "artifacts": [{
+                    "type": "json",
+                    "name": "performance_data",
+                    "job_guid": job_guid,
+                    "blob": {
+                        "performance_data": job.performance_data,
+                    },
+                }],
+            },
+        }])
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TreeherderError {
+    #[error("`{}' is not a valid root URL: {}", .0, .1)]
+    RootUrl(String, #[source] url::ParseError),
+
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+
+    #[error("could not sign request: {}", .0)]
+    Sign(#[source] hawk::Error),
+
+    #[error("could not submit job: {}", .0)]
+    Submit(#[from] reqwest::Error),
+
+    #[error("job submission was rejected with status {}: {}", .status, .body)]
+    Rejected { status: StatusCode, body: String },
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn test_config() -> TreeherderConfig {
+        toml::from_str(
+            r#"
+            repository = "mozilla-central"
+            client_id = "fxrecord-lab"
+            secret = "hunter2"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_jobs_url() {
+        let treeherder = Treeherder::new(&test_config()).unwrap();
+        assert_eq!(
+            treeherder.jobs_url.as_str(),
+            "https://treeherder.mozilla.org/api/project/mozilla-central/jobs/"
+        );
+
+        let treeherder = Treeherder::new(&TreeherderConfig {
+            root_url: "https://treeherder.allizom.org/staging".into(),
+            ..test_config()
+        })
+        .unwrap();
+        assert_eq!(
+            treeherder.jobs_url.as_str(),
+            "https://treeherder.allizom.org/staging/api/project/mozilla-central/jobs/"
+        );
+    }
+
+    #[test]
+    fn test_job_submission() {
+        let treeherder = Treeherder::new(&test_config()).unwrap();
+        let performance_data = json!({
+            "framework": { "name": "fxrecord" },
+            "suites": [],
+        });
+
+        let submission = treeherder.job_submission(
+            &Job {
+                revision: "abcdef012345",
+                name: "firstrun",
+                machine: "lab1-win10-nvidia",
+                start: Utc.ymd(2020, 11, 1).and_hms(12, 0, 0),
+                end: Utc.ymd(2020, 11, 1).and_hms(12, 5, 0),
+                performance_data: &performance_data,
+            },
+            "fxrecord-guid",
+        );
+
+        let submission = &submission[0];
+        assert_eq!(submission["project"], "mozilla-central");
+        assert_eq!(submission["revision"], "abcdef012345");
+
+        let job = &submission["job"];
+        assert_eq!(job["job_guid"], "fxrecord-guid");
+        assert_eq!(job["name"], "firstrun");
+        assert_eq!(job["job_symbol"], "startup");
+        assert_eq!(job["group_symbol"], "fxrec");
+        assert_eq!(job["start_timestamp"], 1604232000);
+        assert_eq!(job["end_timestamp"], 1604232300);
+        assert_eq!(job["machine"], "lab1-win10-nvidia");
+        assert_eq!(
+            job["machine_platform"]["platform"],
+            "windows10-64-ref-hw-2017"
+        );
+
+        let artifact = &job["artifacts"][0];
+        assert_eq!(artifact["name"], "performance_data");
+        assert_eq!(artifact["job_guid"], "fxrecord-guid");
+        assert_eq!(artifact["blob"]["performance_data"], performance_data);
+    }
+}
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Connecting to runners that are only reachable through an SSH jump host.
+//!
+//! Each connection runs the system `ssh` client with `-W`, which forwards its
+//! standard input and output to the runner through the jump host. The
+//! protocol runs over the client's standard streams exactly as it would over
+//! a `TcpStream`.
+
+use std::io;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+
+use crate::config::SshConfig;
+
+/// A connection to a runner, made either directly or through an SSH tunnel.
+#[derive(Debug)]
+pub enum RunnerStream {
+    Direct(TcpStream),
+    Tunneled(Box<SshStream>),
+}
+
+/// Connect to the runner at `host`, through the jump host if `ssh` is
+/// provided.
+pub async fn connect_runner(host: &str, ssh: Option<&SshConfig>) -> io::Result<RunnerStream> {
+    match ssh {
+        Some(ssh) => {
+            SshStream::connect(ssh, host).map(|stream| RunnerStream::Tunneled(Box::new(stream)))
+        }
+        None => TcpStream::connect(host).await.map(RunnerStream::Direct),
+    }
+}
+
+/// A stream forwarded to a runner by an `ssh -W` process.
+///
+/// The process is killed when the stream is dropped.
+#[derive(Debug)]
+pub struct SshStream {
+    // Held so that the process lives as long as the stream.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl SshStream {
+    /// Start forwarding a stream to `target` through the jump host.
+    ///
+    /// Failing to reach the jump host or the target is reported by `ssh` on
+    /// stderr and surfaces as the stream closing.
+    pub fn connect(config: &SshConfig, target: &str) -> io::Result<Self> {
+        let mut child = Command::new(&config.ssh_path)
+            .arg("-W")
+            .arg(target)
+            .arg("-l")
+            .arg(&config.user)
+            .arg("-i")
+            .arg(&config.key_path)
+            .arg("-p")
+            .arg(config.port.to_string())
+            // Never prompt for a password or passphrase.
+            .args(["-o", "BatchMode=yes"])
+            .arg(&config.host)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("ssh stdin is piped");
+        let stdout = child.stdout.take().expect("ssh stdout is piped");
+
+        Ok(SshStream {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for RunnerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RunnerStream::Direct(stream) => Pin::new(stream).poll_read(cx, buf),
+            RunnerStream::Tunneled(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RunnerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RunnerStream::Direct(stream) => Pin::new(stream).poll_write(cx, buf),
+            RunnerStream::Tunneled(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RunnerStream::Direct(stream) => Pin::new(stream).poll_flush(cx),
+            RunnerStream::Tunneled(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RunnerStream::Direct(stream) => Pin::new(stream).poll_shutdown(cx),
+            RunnerStream::Tunneled(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
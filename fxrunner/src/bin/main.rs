@@ -2,24 +2,48 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::env::{self, current_dir};
 use std::error::Error;
+use std::ffi::OsStr;
+use std::future::Future;
 use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::Duration;
 
-use libfxrecord::config::read_config;
-use libfxrecord::logging::build_file_logger;
-use libfxrunner::config::Config;
-use libfxrunner::osapi::{WindowsPerfProvider, WindowsShutdownProvider};
-use libfxrunner::proto::RunnerProto;
-use libfxrunner::session::DefaultSessionManager;
-use libfxrunner::splash::WindowsSplash;
-use libfxrunner::taskcluster::FirefoxCi;
+use futures::future::{select, Either};
+use libfxrecord::config::{
+    write_new_config, ConfigCommand, ConfigError, ConfigLoader, InitOptions,
+};
+use libfxrecord::discovery::{serve_discovery, Advertisement};
+use libfxrecord::logging::{build_file_logger, build_logger};
+use libfxrecord::net::broker::{register, BrokerClientProto, RunnerRegistration};
+use libfxrecord::timeout::{with_timeout, TimeoutPhase};
+use libfxrunner::config::{starter_config, Config};
+use libfxrunner::metrics::{serve_metrics, Phase, METRICS};
+use libfxrunner::osapi::{
+    ConfiguredShutdownProvider, DryRunShutdownProvider, PlatformPerfProvider,
+    PlatformShutdownProvider, ShutdownProvider,
+};
+use libfxrunner::profile_cache::PROFILE_CACHE_NAME;
+use libfxrunner::proto::{handle_busy_request, RequestOutcome, RunnerProto};
+use libfxrunner::restarts::RESTART_LOG_NAME;
+use libfxrunner::session::{DefaultSessionManager, SessionManager};
+#[cfg(not(windows))]
+use libfxrunner::splash::DesktopSplash as PlatformSplash;
+use libfxrunner::splash::Splash;
+#[cfg(windows)]
+use libfxrunner::splash::WindowsSplash as PlatformSplash;
+use libfxrunner::store::STORE_NAME;
+use libfxrunner::taskcluster::{FirefoxCi, Taskcluster};
+#[cfg(feature = "testing")]
+use libfxrunner::testing::{FakeBuildProvider, TestSplash};
 use slog::{error, info, warn, Logger};
 use structopt::StructOpt;
 use tokio::fs::create_dir_all;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::spawn_blocking;
 use tokio::time::delay_for;
 
 #[derive(Debug, StructOpt)]
@@ -29,6 +53,25 @@ struct Options {
     #[structopt(long = "config", default_value = "fxrecord.toml")]
     config_path: PathBuf,
 
+    /// The profile in the configuration file to use.
+    ///
+    /// A profile is a table nested in the `fxrunner` section, e.g.,
+    /// `[fxrunner.staging]`, whose options override those of the section.
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// The address and port to listen on.
+    ///
+    /// Overrides the `host` configuration option.
+    #[structopt(long)]
+    host: Option<SocketAddr>,
+
+    /// The directory to store session state in.
+    ///
+    /// Overrides the `session_dir` configuration option.
+    #[structopt(long = "session-dir")]
+    session_dir: Option<PathBuf>,
+
     /// Skip the restart when the recorder requests it.
     ///
     /// Only available in debug builds.
@@ -36,8 +79,32 @@ struct Options {
     #[structopt(long)]
     skip_restart: bool,
 
+    /// Run a fake runner that serves every Taskcluster build from the given
+    /// archive, never restarts, and does not display a splash screen.
+    ///
+    /// The archive should contain a stand-in for Firefox, such as fakefox, so
+    /// that the recorder can be tested end-to-end without reference hardware.
+    ///
+    /// Only available when built with the `testing` feature.
+    #[cfg(feature = "testing")]
+    #[structopt(long = "fake", value_name = "BUILD")]
+    fake_build: Option<PathBuf>,
+
+    /// The file to log to, unless logging is configured.
     #[structopt(long = "log", default_value = "fxrunner.log")]
     log_path: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Option<RunnerCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+enum RunnerCommand {
+    /// Inspect the configuration instead of starting FxRunner.
+    Config(ConfigCommand),
+
+    /// Write a starter configuration file instead of starting FxRunner.
+    Init(InitOptions),
 }
 
 impl Options {
@@ -56,25 +123,157 @@ impl Options {
     fn skip_restart(&self) -> bool {
         false
     }
+
+    /// The build archive to serve if we are running a fake runner.
+    #[cfg(feature = "testing")]
+    fn fake_build(&self) -> Option<&Path> {
+        self.fake_build.as_deref()
+    }
+
+    /// The build archive to serve if we are running a fake runner.
+    ///
+    /// Will always be `None`.
+    #[cfg(not(feature = "testing"))]
+    fn fake_build(&self) -> Option<&Path> {
+        None
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let options = Options::from_args();
 
+    match options.command {
+        Some(RunnerCommand::Config(ConfigCommand::Check)) => exit(check_config(&options)),
+        Some(RunnerCommand::Init(ref init_options)) => exit(init_config(&options, init_options)),
+        None => {}
+    }
+
+    // Without a configuration, there is nowhere to log to yet.
+    let config = match load_config(&options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(1);
+        }
+    };
+
     // If we cannot open a log, we may as well crash since we have no where to
     // log the error.
-    let log = build_file_logger(&options.log_path).expect("Could not open log");
+    let log = if config.log.is_empty() {
+        build_file_logger(&options.log_path)
+    } else {
+        build_logger(&config.log)
+    }
+    .expect("Could not open log");
 
-    if let Err(e) = fxrunner(log.clone(), options).await {
+    if let Err(e) = fxrunner(log.clone(), options, config).await {
         error!(log, "unexpected error"; "error" => %e);
         drop(log);
         exit(1);
     }
 }
 
-async fn fxrunner(log: Logger, options: Options) -> Result<(), Box<dyn Error>> {
-    let config: Config = read_config(&options.config_path, "fxrunner")?;
+/// Load the configuration from the built-in defaults, the configuration file,
+/// `FXRUNNER_*` environment variables, and command-line flags, in increasing
+/// order of precedence.
+fn load_config(options: &Options) -> Result<Config, ConfigError> {
+    config_loader(options)?.load()
+}
+
+/// Check the configuration, printing every problem found, and return the exit
+/// status.
+fn check_config(options: &Options) -> i32 {
+    let issues = match config_loader(options) {
+        Ok(loader) => loader.check::<Config>(),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+
+    if issues.is_empty() {
+        println!("Configuration is valid.");
+        return 0;
+    }
+
+    for issue in &issues {
+        eprintln!("error: {}", issue);
+    }
+
+    1
+}
+
+/// Write a starter configuration file and return the exit status.
+fn init_config(options: &Options, init_options: &InitOptions) -> i32 {
+    let session_dir = match current_dir() {
+        Ok(dir) => dir.join("sessions"),
+        Err(e) => {
+            eprintln!("error: could not determine current directory: {}", e);
+            return 1;
+        }
+    };
+
+    let host = SocketAddr::from(([0, 0, 0, 0], free_port()));
+    let hostname = env::var("COMPUTERNAME")
+        .or_else(|_| env::var("HOSTNAME"))
+        .ok();
+
+    let contents = starter_config(host, hostname.as_deref(), &session_dir);
+
+    match write_new_config(&options.config_path, &contents, init_options.force) {
+        Ok(()) => {
+            println!("Wrote `{}'.", options.config_path.display());
+            0
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!(
+                "error: `{}' already exists; pass --force to overwrite it",
+                options.config_path.display()
+            );
+            1
+        }
+        Err(e) => {
+            eprintln!(
+                "error: could not write `{}': {}",
+                options.config_path.display(),
+                e
+            );
+            1
+        }
+    }
+}
+
+/// Return a port to listen on, preferring the default port of 8888.
+fn free_port() -> u16 {
+    StdTcpListener::bind(("0.0.0.0", 8888))
+        .or_else(|_| StdTcpListener::bind(("0.0.0.0", 0)))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .unwrap_or(8888)
+}
+
+fn config_loader(options: &Options) -> Result<ConfigLoader, ConfigError> {
+    Ok(ConfigLoader::new("fxrunner")
+        .set_default("host", "0.0.0.0:8888")
+        .file_with_profile(&options.config_path, options.profile.as_deref())?
+        .env("FXRUNNER_")
+        .set_some("host", options.host.map(|host| host.to_string()))
+        .set_some(
+            "session_dir",
+            options
+                .session_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        ))
+}
+
+async fn fxrunner(log: Logger, options: Options, config: Config) -> Result<(), Box<dyn Error>> {
+    info!(log, "Loaded configuration"; "config" => ?config);
+
+    if let Some(build) = options.fake_build() {
+        warn!(log, "Running a fake runner"; "build" => build.display());
+    }
 
     if let Err(e) = create_dir_all(&config.session_dir).await {
         error!(
@@ -87,42 +286,130 @@ async fn fxrunner(log: Logger, options: Options) -> Result<(), Box<dyn Error>> {
         return Err(e.into());
     }
 
+    if let Some(metrics_host) = config.metrics_host {
+        let log = log.clone();
+        let session_dir = config.session_dir.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(log.clone(), metrics_host, session_dir).await {
+                error!(log, "Could not serve metrics"; "error" => %e);
+            }
+        });
+    }
+
+    if let Some(discovery_host) = config.discovery_host {
+        let log = log.clone();
+        let socket = UdpSocket::bind(discovery_host).await?;
+        let advertisement = Advertisement {
+            name: config.advertised_name(),
+            version: env!("CARGO_PKG_VERSION").into(),
+            port: config.host.port(),
+            capabilities: config.capabilities(),
+            busy: false,
+        };
+
+        info!(log, "Answering discovery probes"; "discovery_host" => %discovery_host);
+
+        tokio::spawn(async move {
+            let advertise = move || Advertisement {
+                busy: METRICS.phase() != Phase::Idle,
+                ..advertisement.clone()
+            };
+
+            if let Err(e) = serve_discovery(log.clone(), socket, advertise).await {
+                error!(log, "Could not answer discovery probes"; "error" => %e);
+            }
+        });
+    }
+
+    if let Some(ref broker) = config.broker {
+        match register_with_broker(broker, &config).await {
+            Ok(()) => info!(log, "Registered with broker"; "broker" => broker),
+            Err(e) => {
+                warn!(log, "Could not register with broker"; "broker" => broker, "error" => %e)
+            }
+        }
+    }
+
     loop {
         let mut listener = TcpListener::bind(&config.host).await?;
 
+        // A session left over from before a restart must be resumed in time,
+        // or it is abandoned.
+        let mut resume_limit = if has_pending_session(&config.session_dir).await {
+            config.timeouts.get(TimeoutPhase::RestartReconnect)
+        } else {
+            None
+        };
+
         loop {
             info!(log, "Waiting for connection...");
+            METRICS.set_phase(Phase::Idle);
 
-            let (stream, addr) = listener.accept().await?;
-            info!(log, "Received connection"; "peer" => addr);
-
-            let result = RunnerProto::<_, _, _, _, WindowsSplash>::handle_request(
-                log.clone(),
-                config.display_size,
-                stream,
-                shutdown_provider(&options),
-                FirefoxCi::default(),
-                WindowsPerfProvider::default(),
-                DefaultSessionManager::new(log.clone(), &config.session_dir),
+            let accepted = with_timeout(
+                TimeoutPhase::RestartReconnect,
+                resume_limit.take(),
+                listener.accept(),
             )
             .await;
 
-            match result {
-                Ok(restart) => {
-                    if restart {
-                        break;
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted?,
+                Err(e) => {
+                    error!(log, "Recorder did not resume session"; "error" => %e);
+                    METRICS.request_failed("timeout");
+
+                    if let Err(e) = cleanup_session_dir(log.clone(), &config.session_dir).await {
+                        error!(log, "Could not cleanup session directory"; "error" => %e);
                     }
+
+                    continue;
                 }
-                Err(e) => {
-                    error!(log, "Encountered an unexpected error while serving a request"; "error" => %e);
+            };
+            info!(log, "Received connection"; "peer" => addr);
+
+            let outcome = match options.fake_build() {
+                #[cfg(feature = "testing")]
+                Some(build) => {
+                    serve_request::<_, _, TestSplash>(
+                        &log,
+                        &mut listener,
+                        &config,
+                        stream,
+                        DryRunShutdownProvider::new(log.clone()),
+                        FakeBuildProvider::new(build),
+                    )
+                    .await
+                }
+
+                _ => {
+                    serve_request::<_, _, PlatformSplash>(
+                        &log,
+                        &mut listener,
+                        &config,
+                        stream,
+                        shutdown_provider(&options, &config, &log),
+                        FirefoxCi::new(&config.taskcluster)?,
+                    )
+                    .await
+                }
+            };
+
+            match outcome {
+                Some(RequestOutcome::Restart) => break,
+                Some(RequestOutcome::Finished) | None => {}
+                Some(RequestOutcome::Status) => {
+                    info!(log, "Client disconnected");
+                    continue;
                 }
             }
 
             info!(log, "Client disconnected");
 
-            // We aren't restarting, which means we handled a resume request. We
-            // only expect a single pending request at a time, so the request
-            // directory *should* be empty. If it isn't, then isn't empty it.
+            // We aren't restarting, which means we handled a resume request or
+            // a cancelled session. We only expect a single pending request at
+            // a time, so the request directory *should* be empty. If it isn't,
+            // then isn't empty it.
             if let Err(e) = cleanup_session_dir(log.clone(), &config.session_dir).await {
                 error!(log, "Could not cleanup session directory"; "error" => %e);
             }
@@ -131,7 +418,7 @@ async fn fxrunner(log: Logger, options: Options) -> Result<(), Box<dyn Error>> {
         info!(log, "Client disconnected for restart");
         drop(listener);
 
-        if options.skip_restart() {
+        if options.skip_restart() || config.dry_run_shutdown || options.fake_build().is_some() {
             // We are skipping doing an actual restart here. We disconnect
             // our socket and the listener and wait 30 seconds. This is
             // enough time for the socket to get recycled by the operating
@@ -149,14 +436,158 @@ async fn fxrunner(log: Logger, options: Options) -> Result<(), Box<dyn Error>> {
     }
 }
 
-#[cfg(debug_assertions)]
-fn shutdown_provider(options: &Options) -> WindowsShutdownProvider {
-    WindowsShutdownProvider::skipping_restart(options.skip_restart)
+/// Register the runner with the broker, so that it can be leased to recorders.
+async fn register_with_broker(broker: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let stream = TcpStream::connect(broker).await?;
+    let mut proto = BrokerClientProto::new(stream);
+
+    register(
+        &mut proto,
+        RunnerRegistration {
+            name: config.advertised_name(),
+            port: config.host.port(),
+            tags: config.tags.clone(),
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Serve a request from the recorder with the given providers.
+///
+/// Failures are logged and counted in the metrics, in which case `None` is
+/// returned.
+async fn serve_request<S, T, Sp>(
+    log: &Logger,
+    listener: &mut TcpListener,
+    config: &Config,
+    stream: TcpStream,
+    shutdown_provider: S,
+    tc: T,
+) -> Option<RequestOutcome>
+where
+    S: ShutdownProvider,
+    T: Taskcluster + Send,
+    Sp: Splash,
+{
+    let request = RunnerProto::<_, _, _, _, Sp>::handle_request(
+        log.clone(),
+        config.clone(),
+        stream,
+        shutdown_provider,
+        tc,
+        PlatformPerfProvider::default(),
+        DefaultSessionManager::new(log.clone(), &config.session_dir),
+    );
+    let result = serve_while_busy(log, listener, config, request).await;
+
+    METRICS.request_served();
+
+    match result {
+        Ok(outcome) => Some(outcome),
+        Err(e) => {
+            METRICS.request_failed(e.kind());
+            error!(log, "Encountered an unexpected error while serving a request"; "error" => %e);
+            None
+        }
+    }
+}
+
+/// Wait for the request to be handled, answering any other connections that
+/// arrive in the meantime with the runner's status.
+async fn serve_while_busy<F: Future>(
+    log: &Logger,
+    listener: &mut TcpListener,
+    config: &Config,
+    request: F,
+) -> F::Output {
+    let mut request = Box::pin(request);
+
+    loop {
+        match select(request, Box::pin(listener.accept())).await {
+            Either::Left((result, _)) => return result,
+            Either::Right((accepted, pending)) => {
+                request = pending;
+
+                match accepted {
+                    Ok((stream, addr)) => {
+                        info!(log, "Received connection while busy"; "peer" => addr);
+
+                        let log = log.clone();
+                        let session_manager =
+                            DefaultSessionManager::new(log.clone(), &config.session_dir);
+
+                        tokio::spawn(async move {
+                            if let Err(e) =
+                                handle_busy_request(log.clone(), stream, session_manager).await
+                            {
+                                warn!(log, "Could not respond while busy"; "error" => %e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!(log, "Could not accept connection"; "error" => %e),
+                }
+            }
+        }
+    }
+}
+
+fn shutdown_provider(
+    options: &Options,
+    config: &Config,
+    log: &Logger,
+) -> ConfiguredShutdownProvider<PlatformShutdownProvider> {
+    if config.dry_run_shutdown {
+        ConfiguredShutdownProvider::DryRun(DryRunShutdownProvider::new(log.clone()))
+    } else {
+        ConfiguredShutdownProvider::Real(platform_shutdown_provider(options, log))
+    }
+}
+
+#[cfg(all(windows, debug_assertions))]
+fn platform_shutdown_provider(options: &Options, _: &Logger) -> PlatformShutdownProvider {
+    PlatformShutdownProvider::skipping_restart(options.skip_restart)
+}
+
+#[cfg(all(windows, not(debug_assertions)))]
+fn platform_shutdown_provider(_: &Options, _: &Logger) -> PlatformShutdownProvider {
+    PlatformShutdownProvider::default()
+}
+
+#[cfg(all(target_os = "linux", debug_assertions))]
+fn platform_shutdown_provider(options: &Options, log: &Logger) -> PlatformShutdownProvider {
+    PlatformShutdownProvider::skipping_restart(log.clone(), options.skip_restart)
+}
+
+#[cfg(all(target_os = "linux", not(debug_assertions)))]
+fn platform_shutdown_provider(_: &Options, log: &Logger) -> PlatformShutdownProvider {
+    PlatformShutdownProvider::new(log.clone())
+}
+
+/// Whether or not the given entry of the session directory is kept across
+/// sessions.
+fn is_kept_across_sessions(name: &OsStr) -> bool {
+    // The restart log, profile cache, and store are kept across sessions.
+    [RESTART_LOG_NAME, PROFILE_CACHE_NAME, STORE_NAME]
+        .iter()
+        .any(|kept| name == *kept)
 }
 
-#[cfg(not(debug_assertions))]
-fn shutdown_provider(_: &Options) -> WindowsShutdownProvider {
-    WindowsShutdownProvider::default()
+/// Whether or not the session directory contains a session.
+async fn has_pending_session(path: &Path) -> bool {
+    let mut entries = match tokio::fs::read_dir(path).await {
+        Ok(entries) => entries,
+        Err(..) => return false,
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if !is_kept_across_sessions(&entry.file_name()) {
+            return true;
+        }
+    }
+
+    false
 }
 
 async fn cleanup_session_dir(log: slog::Logger, path: &Path) -> Result<(), io::Error> {
@@ -164,6 +595,10 @@ async fn cleanup_session_dir(log: slog::Logger, path: &Path) -> Result<(), io::E
 
     let mut entries = tokio::fs::read_dir(path).await?;
     while let Some(entry) = entries.next_entry().await? {
+        if is_kept_across_sessions(&entry.file_name()) {
+            continue;
+        }
+
         let path = entry.path();
         if let Err(e) = tokio::fs::remove_dir_all(&path).await {
             error!(
@@ -177,5 +612,27 @@ async fn cleanup_session_dir(log: slog::Logger, path: &Path) -> Result<(), io::E
         }
     }
 
+    prune_store(&log, &DefaultSessionManager::new(log.clone(), path)).await;
+
     Ok(())
 }
+
+/// Remove the files in the store that are no longer used by the profile cache
+/// or a stored build.
+async fn prune_store(log: &Logger, session_manager: &DefaultSessionManager) {
+    let profile_cache = session_manager.profile_cache();
+    let store = session_manager.store();
+
+    let result = spawn_blocking(move || -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let keep = profile_cache.manifest()?.into_values().collect();
+
+        Ok(store.prune(&keep)?)
+    })
+    .await
+    .expect("prune store task was cancelled or panicked");
+
+    match result {
+        Ok(removed) => info!(log, "Pruned store"; "removed" => removed),
+        Err(e) => warn!(log, "Could not prune store"; "error" => %e),
+    }
+}
@@ -0,0 +1,733 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Extraction of build archives.
+
+use std::fs::{metadata, read_dir, remove_dir_all, rename, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::Duration;
+
+use bzip2::read::BzDecoder;
+use libfxrecord::net::ExtractProgress;
+use thiserror::Error;
+use tokio::sync::watch;
+use xz2::read::XzDecoder;
+
+use crate::zip::{extract_staged, unzip_with_progress, ExtractLimits, LimitError, ZipError};
+
+/// The magic number at the start of an xz stream.
+const XZ_MAGIC: &[u8] = b"\xFD7zXZ\0";
+
+/// The magic number at the start of a bzip2 stream.
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+/// The magic number at the start of a Windows executable.
+const EXE_MAGIC: &[u8] = b"MZ";
+
+/// How often a [`PartialArchive`](struct.PartialArchive.html) checks whether
+/// more of the archive has been downloaded.
+const PARTIAL_ARCHIVE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The directory in the installer payload that contains the build.
+const INSTALLER_PAYLOAD_DIR: &str = "core";
+
+/// Return the default path to the `7z` executable, which is resolved via
+/// `PATH`.
+pub fn default_sevenzip_path() -> PathBuf {
+    PathBuf::from("7z")
+}
+
+/// The format of a build archive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarXz,
+    TarBz2,
+
+    /// A self-extracting Windows installer, such as `target.installer.exe`.
+    Installer,
+}
+
+impl ArchiveFormat {
+    /// Detect the format of the archive at the given path from its contents.
+    ///
+    /// Anything that is not recognized as a compressed tarball or an installer
+    /// is assumed to be a zip archive.
+    pub fn detect(archive: &Path) -> Result<ArchiveFormat, io::Error> {
+        Ok(ArchiveFormat::from_header(&read_header(File::open(
+            archive,
+        )?)?))
+    }
+
+    /// Detect the format of an archive from its first few bytes.
+    fn from_header(header: &[u8]) -> ArchiveFormat {
+        if header.starts_with(XZ_MAGIC) {
+            ArchiveFormat::TarXz
+        } else if header.starts_with(BZIP2_MAGIC) {
+            ArchiveFormat::TarBz2
+        } else if header.starts_with(EXE_MAGIC) {
+            ArchiveFormat::Installer
+        } else {
+            ArchiveFormat::Zip
+        }
+    }
+}
+
+/// Read enough of an archive to detect its format.
+fn read_header<R: Read>(reader: R) -> Result<Vec<u8>, io::Error> {
+    let mut header = Vec::with_capacity(XZ_MAGIC.len());
+    reader
+        .take(XZ_MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+
+    Ok(header)
+}
+
+/// Extract the archive at the given location to the target location.
+///
+/// Zip archives and tarballs compressed with xz or bzip2 are supported, as are
+/// Windows installers. Installers are unpacked with the `7z` executable at
+/// `sevenzip_path` and their payload is placed in a `firefox` directory, as it
+/// would be in the other archives.
+///
+/// Progress is only reported for zip archives, which are extracted across
+/// several threads.
+///
+/// Archives that exceed the given limits are rejected and nothing is left in
+/// `target`. Installers are checked once `7z` has unpacked them, as the size
+/// of their payload is not known until then.
+pub fn extract(
+    archive: &Path,
+    target: &Path,
+    sevenzip_path: &Path,
+    limits: &ExtractLimits,
+    progress: watch::Sender<ExtractProgress>,
+) -> Result<ArchiveFormat, ArchiveError> {
+    let format = ArchiveFormat::detect(archive).map_err(|source| ArchiveError::OpenArchive {
+        archive: archive.into(),
+        source,
+    })?;
+
+    match format {
+        ArchiveFormat::Zip => {
+            unzip_with_progress(archive, target, limits, |p| {
+                // The receiver only goes away once extraction is no longer
+                // being waited on.
+                let _ = progress.broadcast(p);
+            })?;
+        }
+
+        ArchiveFormat::TarXz => {
+            let reader = XzDecoder::new(open(archive)?);
+            extract_staged(target, |staging| untar(reader, archive, staging, limits))?;
+        }
+
+        ArchiveFormat::TarBz2 => {
+            let reader = BzDecoder::new(open(archive)?);
+            extract_staged(target, |staging| untar(reader, archive, staging, limits))?;
+        }
+
+        ArchiveFormat::Installer => {
+            extract_staged(target, |staging| {
+                unpack_installer(archive, staging, sevenzip_path, limits)
+            })?;
+        }
+    }
+
+    Ok(format)
+}
+
+/// Extract a tarball to the target location as it is read from `reader`.
+///
+/// This allows a tarball to be extracted while it is still being downloaded.
+/// Other formats cannot be extracted from a stream, in which case `None` is
+/// returned after reading the start of the archive and nothing is extracted.
+/// Nothing is left in `target` if the tarball cannot be extracted.
+pub fn extract_stream<R: Read>(
+    mut reader: R,
+    archive: &Path,
+    target: &Path,
+    limits: &ExtractLimits,
+) -> Result<Option<ArchiveFormat>, ArchiveError> {
+    let header = read_header(&mut reader).map_err(|source| ArchiveError::OpenArchive {
+        archive: archive.into(),
+        source,
+    })?;
+
+    let format = ArchiveFormat::from_header(&header);
+    let reader = BufReader::new(io::Cursor::new(header).chain(reader));
+
+    match format {
+        ArchiveFormat::TarXz => extract_staged(target, |staging| {
+            untar(XzDecoder::new(reader), archive, staging, limits)
+        })?,
+        ArchiveFormat::TarBz2 => extract_staged(target, |staging| {
+            untar(BzDecoder::new(reader), archive, staging, limits)
+        })?,
+        ArchiveFormat::Zip | ArchiveFormat::Installer => return Ok(None),
+    }
+
+    Ok(Some(format))
+}
+
+/// A reader for an archive that is still being downloaded.
+///
+/// Reads block until more of the archive has been written or the download
+/// has finished, at which point the end of the archive has been reached.
+#[derive(Debug)]
+pub struct PartialArchive {
+    path: PathBuf,
+
+    /// The archive, while there are bytes left to read.
+    ///
+    /// The file is closed while waiting for more bytes so that the download
+    /// is free to replace it.
+    file: Option<File>,
+
+    /// The number of bytes read so far.
+    pos: u64,
+
+    /// Whether or not the download has finished.
+    finished: Arc<AtomicBool>,
+}
+
+impl PartialArchive {
+    /// Create a reader for the archive being downloaded to the given path.
+    ///
+    /// The download must set `finished` once it has finished writing the
+    /// archive, whether or not it succeeded.
+    pub fn new(path: PathBuf, finished: Arc<AtomicBool>) -> Self {
+        PartialArchive {
+            path,
+            file: None,
+            pos: 0,
+            finished,
+        }
+    }
+}
+
+impl Read for PartialArchive {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(ref mut file) = self.file {
+                let n = file.read(buf)?;
+                if n > 0 {
+                    self.pos += n as u64;
+                    return Ok(n);
+                }
+
+                self.file = None;
+            }
+
+            // This must be checked before the length of the archive so that
+            // we cannot miss bytes written just before the download finished.
+            let finished = self.finished.load(Ordering::SeqCst);
+
+            let len = match metadata(&self.path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e),
+            };
+
+            if len > self.pos {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(self.pos))?;
+                self.file = Some(file);
+            } else if len < self.pos {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "archive was truncated while it was being extracted",
+                ));
+            } else if finished {
+                return Ok(0);
+            } else {
+                sleep(PARTIAL_ARCHIVE_POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Open an archive for buffered reading.
+fn open(archive: &Path) -> Result<BufReader<File>, ArchiveError> {
+    File::open(archive)
+        .map(BufReader::new)
+        .map_err(|source| ArchiveError::OpenArchive {
+            archive: archive.into(),
+            source,
+        })
+}
+
+/// Unpack the decompressed tarball read from `reader` to the target location.
+///
+/// The limits are checked before each entry is unpacked, so a tarball that
+/// exceeds them may be partially extracted. Callers extract into a staging
+/// directory so that this is never seen.
+fn untar<R: Read>(
+    reader: R,
+    archive: &Path,
+    target: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), ArchiveError> {
+    let extract_error = |source| ArchiveError::ExtractTarball {
+        archive: archive.into(),
+        source,
+    };
+
+    let mut tarball = tar::Archive::new(reader);
+    let (mut entries, mut size) = (0u64, 0u64);
+
+    for entry in tarball.entries().map_err(extract_error)? {
+        let mut entry = entry.map_err(extract_error)?;
+
+        entries += 1;
+        size = size.saturating_add(entry.size());
+        limits
+            .check(entries, size)
+            .map_err(|source| ArchiveError::Limit {
+                archive: archive.into(),
+                source,
+            })?;
+
+        entry.unpack_in(target).map_err(extract_error)?;
+    }
+
+    Ok(())
+}
+
+/// Unpack the payload of a Windows installer into `target/firefox`.
+///
+/// The installer is a 7z self-extracting archive that contains the build in
+/// its `core` directory. Everything that `7z` unpacks counts towards the
+/// limits.
+fn unpack_installer(
+    archive: &Path,
+    target: &Path,
+    sevenzip_path: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), ArchiveError> {
+    let unpack_dir = target.join("installer");
+
+    let status = Command::new(sevenzip_path)
+        .arg("x")
+        .arg("-y")
+        .arg(format!("-o{}", unpack_dir.display()))
+        .arg(archive)
+        .status()
+        .map_err(ArchiveError::SevenZip)?;
+
+    if !status.success() {
+        return Err(ArchiveError::SevenZipFailed {
+            archive: archive.into(),
+            status,
+        });
+    }
+
+    let (entries, size) = dir_usage(&unpack_dir).map_err(|source| ArchiveError::ReadPayload {
+        archive: archive.into(),
+        source,
+    })?;
+    limits
+        .check(entries, size)
+        .map_err(|source| ArchiveError::Limit {
+            archive: archive.into(),
+            source,
+        })?;
+
+    let payload_dir = unpack_dir.join(INSTALLER_PAYLOAD_DIR);
+    if !payload_dir.is_dir() {
+        return Err(ArchiveError::MissingInstallerPayload(archive.into()));
+    }
+
+    rename(&payload_dir, target.join("firefox")).map_err(|source| ArchiveError::MovePayload {
+        archive: archive.into(),
+        source,
+    })?;
+
+    remove_dir_all(&unpack_dir).map_err(|source| ArchiveError::MovePayload {
+        archive: archive.into(),
+        source,
+    })
+}
+
+/// Return the number of entries within the directory and the total size of
+/// its files.
+fn dir_usage(dir: &Path) -> Result<(u64, u64), io::Error> {
+    let (mut entries, mut size) = (0u64, 0u64);
+    let mut dirs = vec![dir.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+
+            entries += 1;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size = size.saturating_add(entry.metadata()?.len());
+            }
+        }
+    }
+
+    Ok((entries, size))
+}
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    Zip(#[from] ZipError),
+
+    #[error(
+        "Could not open archive `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    OpenArchive { archive: PathBuf, source: io::Error },
+
+    #[error(
+        "Could not extract tarball `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    ExtractTarball { archive: PathBuf, source: io::Error },
+
+    #[error(
+        "Refusing to extract `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    Limit {
+        archive: PathBuf,
+        source: LimitError,
+    },
+
+    #[error("Could not run 7z: {}", .0)]
+    SevenZip(#[source] io::Error),
+
+    #[error(
+        "7z could not unpack installer `{}': {}",
+        .archive.display(),
+        .status
+    )]
+    SevenZipFailed {
+        archive: PathBuf,
+        status: ExitStatus,
+    },
+
+    #[error(
+        "Installer `{}' does not contain a `core' directory",
+        .0.display()
+    )]
+    MissingInstallerPayload(PathBuf),
+
+    #[error(
+        "Could not read the payload of installer `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    ReadPayload { archive: PathBuf, source: io::Error },
+
+    #[error(
+        "Could not move the payload of installer `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    MovePayload { archive: PathBuf, source: io::Error },
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::current_dir;
+    use std::fs::read_to_string;
+    use std::io::Write;
+
+    use assert_matches::assert_matches;
+    use bzip2::write::BzEncoder;
+    use tempfile::TempDir;
+    use xz2::write::XzEncoder;
+
+    use super::*;
+
+    /// Write a tarball containing `firefox/firefox.exe` to the given writer.
+    fn write_tarball<W: Write>(writer: W) -> W {
+        let contents = b"fake firefox";
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(writer);
+        builder
+            .append_data(&mut header, "firefox/firefox.exe", &contents[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_extract() {
+        let tempdir = TempDir::new().unwrap();
+
+        let xz_path = tempdir.path().join("target.tar.xz");
+        write_tarball(XzEncoder::new(File::create(&xz_path).unwrap(), 6))
+            .finish()
+            .unwrap();
+
+        let bz2_path = tempdir.path().join("target.tar.bz2");
+        write_tarball(BzEncoder::new(
+            File::create(&bz2_path).unwrap(),
+            bzip2::Compression::default(),
+        ))
+        .finish()
+        .unwrap();
+
+        for (path, format) in &[
+            (xz_path, ArchiveFormat::TarXz),
+            (bz2_path, ArchiveFormat::TarBz2),
+        ] {
+            let target = TempDir::new().unwrap();
+
+            let (progress_tx, _) = watch::channel(ExtractProgress::default());
+            assert_eq!(
+                extract(
+                    path,
+                    target.path(),
+                    &default_sevenzip_path(),
+                    &ExtractLimits::default(),
+                    progress_tx
+                )
+                .unwrap(),
+                *format
+            );
+            assert_eq!(
+                read_to_string(target.path().join("firefox").join("firefox.exe")).unwrap(),
+                "fake firefox"
+            );
+        }
+
+        let zip_path = current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("test")
+            .join("test.zip");
+        let target = TempDir::new().unwrap();
+        let (progress_tx, progress_rx) = watch::channel(ExtractProgress::default());
+        assert_eq!(
+            extract(
+                &zip_path,
+                target.path(),
+                &default_sevenzip_path(),
+                &ExtractLimits::default(),
+                progress_tx
+            )
+            .unwrap(),
+            ArchiveFormat::Zip
+        );
+        assert!(target.path().join("dir").join("test.txt").is_file());
+        assert_eq!(
+            *progress_rx.borrow(),
+            ExtractProgress {
+                extracted: 1,
+                total: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let tempdir = TempDir::new().unwrap();
+        let xz_path = tempdir.path().join("target.tar.xz");
+        write_tarball(XzEncoder::new(File::create(&xz_path).unwrap(), 6))
+            .finish()
+            .unwrap();
+
+        let limits = ExtractLimits {
+            max_size: 4,
+            ..ExtractLimits::default()
+        };
+
+        assert_matches!(
+            extract(
+                &xz_path,
+                tempdir.path(),
+                &default_sevenzip_path(),
+                &limits,
+                watch::channel(ExtractProgress::default()).0,
+            ),
+            Err(ArchiveError::Limit {
+                source: LimitError::Size(4),
+                ..
+            })
+        );
+        assert!(!tempdir.path().join("firefox").join("firefox.exe").exists());
+
+        // A tarball that is rejected part way through leaves nothing behind.
+        let mut header = tar::Header::new_gnu();
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(XzEncoder::new(File::create(&xz_path).unwrap(), 6));
+        for name in &["firefox/application.ini", "firefox/firefox.exe"] {
+            builder.append_data(&mut header, name, io::empty()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let target = tempdir.path().join("target");
+        assert_matches!(
+            extract(
+                &xz_path,
+                &target,
+                &default_sevenzip_path(),
+                &ExtractLimits {
+                    max_entries: 1,
+                    ..ExtractLimits::default()
+                },
+                watch::channel(ExtractProgress::default()).0,
+            ),
+            Err(ArchiveError::Limit {
+                source: LimitError::Entries(1),
+                ..
+            })
+        );
+        assert!(!target.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_installer_limits() {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = TempDir::new().unwrap();
+        let installer_path = tempdir.path().join("target.installer.exe");
+        std::fs::write(&installer_path, b"MZ\x90\x00").unwrap();
+
+        // Unpacks a payload of 12 bytes to the directory given by `-o`.
+        let sevenzip_path = tempdir.path().join("7z");
+        std::fs::write(
+            &sevenzip_path,
+            "#!/bin/sh\n\
+             dir=\"${3#-o}\"\n\
+             mkdir -p \"$dir/core\" && printf 'fake firefox' > \"$dir/core/firefox.exe\"\n",
+        )
+        .unwrap();
+        set_permissions(&sevenzip_path, Permissions::from_mode(0o755)).unwrap();
+
+        let target = tempdir.path().join("target");
+        assert_matches!(
+            extract(
+                &installer_path,
+                &target,
+                &sevenzip_path,
+                &ExtractLimits {
+                    max_size: 4,
+                    ..ExtractLimits::default()
+                },
+                watch::channel(ExtractProgress::default()).0,
+            ),
+            Err(ArchiveError::Limit {
+                source: LimitError::Size(4),
+                ..
+            })
+        );
+        assert!(!target.exists());
+
+        assert_eq!(
+            extract(
+                &installer_path,
+                &target,
+                &sevenzip_path,
+                &ExtractLimits::default(),
+                watch::channel(ExtractProgress::default()).0,
+            )
+            .unwrap(),
+            ArchiveFormat::Installer
+        );
+        assert_eq!(
+            read_to_string(target.join("firefox").join("firefox.exe")).unwrap(),
+            "fake firefox"
+        );
+        assert_eq!(read_dir(&target).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_extract_stream() {
+        let tempdir = TempDir::new().unwrap();
+        let archive_path = tempdir.path().join("target.tar.xz");
+        let finished = Arc::new(AtomicBool::new(false));
+
+        let extractor = std::thread::spawn({
+            let reader = PartialArchive::new(archive_path.clone(), finished.clone());
+            let archive_path = archive_path.clone();
+            let target = tempdir.path().to_owned();
+            move || extract_stream(reader, &archive_path, &target, &ExtractLimits::default())
+        });
+
+        // Write the archive in pieces, as a download would.
+        let tarball = write_tarball(XzEncoder::new(Vec::new(), 6))
+            .finish()
+            .unwrap();
+        let mut f = File::create(&archive_path).unwrap();
+        for chunk in tarball.chunks(64) {
+            f.write_all(chunk).unwrap();
+            f.flush().unwrap();
+            sleep(Duration::from_millis(1));
+        }
+        drop(f);
+        finished.store(true, Ordering::SeqCst);
+
+        assert_eq!(
+            extractor.join().unwrap().unwrap(),
+            Some(ArchiveFormat::TarXz)
+        );
+        assert_eq!(
+            read_to_string(tempdir.path().join("firefox").join("firefox.exe")).unwrap(),
+            "fake firefox"
+        );
+
+        // Zip archives cannot be extracted from a stream.
+        let zip_path = tempdir.path().join("target.zip");
+        std::fs::write(&zip_path, b"PK\x03\x04").unwrap();
+        assert_eq!(
+            extract_stream(
+                PartialArchive::new(zip_path.clone(), Arc::new(AtomicBool::new(true))),
+                &zip_path,
+                tempdir.path(),
+                &ExtractLimits::default()
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_installer() {
+        let tempdir = TempDir::new().unwrap();
+        let installer_path = tempdir.path().join("target.installer.exe");
+        std::fs::write(&installer_path, b"MZ\x90\x00").unwrap();
+
+        assert_eq!(
+            ArchiveFormat::detect(&installer_path).unwrap(),
+            ArchiveFormat::Installer
+        );
+
+        assert_matches!(
+            extract(
+                &installer_path,
+                tempdir.path(),
+                &tempdir.path().join("missing-7z.exe"),
+                &ExtractLimits::default(),
+                watch::channel(ExtractProgress::default()).0,
+            ),
+            Err(ArchiveError::SevenZip(..))
+        );
+    }
+}
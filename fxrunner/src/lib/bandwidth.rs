@@ -0,0 +1,169 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Accounting for the bytes the runner transfers for a session, so that the
+//! bandwidth a lab needs can be budgeted.
+//!
+//! A session spans a connection before the runner restarts and at least one
+//! after it, so the usage of each connection is added to a total kept in the
+//! session's directory before the runner restarts.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use libfxrecord::net::BandwidthUsage;
+use thiserror::Error;
+use tokio::fs::{read, write};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::metrics::METRICS;
+
+/// The name of the bandwidth total within a session's directory.
+pub const BANDWIDTH_NAME: &str = "bandwidth.json";
+
+/// A stream that counts the bytes sent and received over it.
+///
+/// The bytes are also counted in the runner's [metrics](../metrics/index.html).
+pub struct CountingStream<St> {
+    inner: St,
+    sent: u64,
+    received: u64,
+}
+
+impl<St> CountingStream<St> {
+    pub fn new(inner: St) -> Self {
+        CountingStream {
+            inner,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// The number of bytes sent over the stream.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// The number of bytes received over the stream.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl<St: AsyncRead + Unpin> AsyncRead for CountingStream<St> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.received += n as u64;
+            METRICS.received_from_recorder(n as u64);
+        }
+        result
+    }
+}
+
+impl<St: AsyncWrite + Unpin> AsyncWrite for CountingStream<St> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.sent += n as u64;
+            METRICS.sent_to_recorder(n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Read the bandwidth used by the session in the given directory before its
+/// current connection.
+///
+/// Nothing has been used if no total has been written.
+pub async fn read_bandwidth(session_dir: &Path) -> Result<BandwidthUsage, BandwidthError> {
+    let path = session_dir.join(BANDWIDTH_NAME);
+
+    let contents = match read(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BandwidthUsage::default()),
+        Err(source) => return Err(BandwidthError::Io { path, source }),
+    };
+
+    serde_json::from_slice(&contents).map_err(|source| BandwidthError::Parse { path, source })
+}
+
+/// Write the bandwidth used by the session in the given directory.
+pub async fn write_bandwidth(
+    session_dir: &Path,
+    usage: &BandwidthUsage,
+) -> Result<(), BandwidthError> {
+    let path = session_dir.join(BANDWIDTH_NAME);
+    let contents = serde_json::to_vec(usage).expect("could not serialize bandwidth usage");
+
+    write(&path, contents)
+        .await
+        .map_err(|source| BandwidthError::Io { path, source })
+}
+
+#[derive(Debug, Error)]
+pub enum BandwidthError {
+    #[error("Could not access bandwidth total `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Could not parse bandwidth total `{}': {}", .path.display(), .source)]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+    use tokio::prelude::*;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bandwidth() {
+        let tempdir = TempDir::new().unwrap();
+
+        assert_eq!(
+            read_bandwidth(tempdir.path()).await.unwrap(),
+            BandwidthUsage::default()
+        );
+
+        let mut stream = CountingStream::new(Cursor::new(b"request".to_vec()));
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        stream.write_all(b"response").await.unwrap();
+
+        assert_eq!(stream.received(), 7);
+        assert_eq!(stream.sent(), 8);
+
+        let usage = BandwidthUsage {
+            downloaded: 1024,
+            sent: stream.sent(),
+            received: stream.received(),
+        };
+        write_bandwidth(tempdir.path(), &usage).await.unwrap();
+        assert_eq!(read_bandwidth(tempdir.path()).await.unwrap(), usage);
+    }
+}
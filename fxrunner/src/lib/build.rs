@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Identification of the Firefox build under test.
+
+use std::io;
+use std::path::Path;
+
+use libfxrecord::net::BuildMetadata;
+use thiserror::Error;
+use tokio::fs::read_to_string;
+
+/// Read the metadata of the build installed in the given directory from its
+/// `application.ini`.
+pub async fn read_build_metadata(firefox_dir: &Path) -> Result<BuildMetadata, BuildMetadataError> {
+    let contents = read_to_string(firefox_dir.join("application.ini"))
+        .await
+        .map_err(BuildMetadataError::Read)?;
+
+    parse_application_ini(&contents)
+}
+
+/// Parse the `[App]` section of an `application.ini` file.
+fn parse_application_ini(contents: &str) -> Result<BuildMetadata, BuildMetadataError> {
+    let mut version = None;
+    let mut build_id = None;
+    let mut source_repository = None;
+    let mut source_stamp = None;
+
+    let mut in_app_section = false;
+
+    for line in contents.lines() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            in_app_section = line == "[App]";
+            continue;
+        }
+
+        if !in_app_section {
+            continue;
+        }
+
+        let idx = match line.find('=') {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let (key, value) = (&line[..idx], line[idx + 1..].to_owned());
+
+        match key {
+            "Version" => version = Some(value),
+            "BuildID" => build_id = Some(value),
+            "SourceRepository" => source_repository = Some(value),
+            "SourceStamp" => source_stamp = Some(value),
+            _ => {}
+        }
+    }
+
+    Ok(BuildMetadata {
+        version: version.ok_or(BuildMetadataError::MissingKey("Version"))?,
+        build_id: build_id.ok_or(BuildMetadataError::MissingKey("BuildID"))?,
+        source_repository,
+        source_stamp,
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum BuildMetadataError {
+    #[error("Could not read application.ini: {}", .0)]
+    Read(#[source] io::Error),
+
+    #[error("application.ini is missing `{}'", .0)]
+    MissingKey(&'static str),
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use indoc::indoc;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_application_ini() {
+        let metadata = parse_application_ini(indoc!(
+            "
+            #filter substitution
+            [App]
+            Vendor=Mozilla
+            Name=Firefox
+            RemotingName=firefox
+            Version=80.0a1
+            BuildID=20200701093957
+            SourceRepository=https://hg.mozilla.org/mozilla-central
+            SourceStamp=a5d7e7f4c1e2a6c8f7d2b4e3f1a0c9d8e7b6a5f4
+            ID={ec8030f7-c20a-464f-9b0e-13a3a9e97384}
+
+            [Gecko]
+            MinVersion=80.0a1
+            MaxVersion=80.0a1
+            "
+        ))
+        .unwrap();
+
+        assert_eq!(
+            metadata,
+            BuildMetadata {
+                version: "80.0a1".into(),
+                build_id: "20200701093957".into(),
+                source_repository: Some("https://hg.mozilla.org/mozilla-central".into()),
+                source_stamp: Some("a5d7e7f4c1e2a6c8f7d2b4e3f1a0c9d8e7b6a5f4".into()),
+            }
+        );
+
+        let metadata = parse_application_ini(indoc!(
+            "
+            [App]
+            Version=80.0a1
+            BuildID=20200701093957
+            "
+        ))
+        .unwrap();
+
+        assert_eq!(metadata.source_repository, None);
+        assert_eq!(metadata.source_stamp, None);
+
+        assert_matches!(
+            parse_application_ini(indoc!(
+                "
+                [App]
+                Version=80.0a1
+
+                [Gecko]
+                BuildID=20200701093957
+                "
+            )),
+            Err(BuildMetadataError::MissingKey("BuildID"))
+        );
+    }
+}
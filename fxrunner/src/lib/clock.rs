@@ -0,0 +1,129 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Temporary normalization of the system timezone and clock.
+//!
+//! Firefox's startup depends on the date in places, and timestamps are only
+//! comparable across runners if their clocks agree, so sessions may set a
+//! common timezone and synchronize the clock with the time server before they
+//! start.
+
+use std::process::Output;
+
+use slog::{error, info, Logger};
+use thiserror::Error;
+use tokio::process::Command;
+
+/// The tool that gets and sets the timezone.
+const TZUTIL: &str = "tzutil.exe";
+
+/// The tool that synchronizes the clock with the time server.
+const W32TM: &str = "w32tm.exe";
+
+/// A timezone that has been set for the duration of a session.
+///
+/// The previous timezone is restored when this is dropped. Synchronizing the
+/// clock cannot be undone, nor does it need to be.
+pub struct ClockOverride {
+    log: Logger,
+    previous_timezone: Option<String>,
+}
+
+impl ClockOverride {
+    /// Set the timezone, if given, and then synchronize the clock if `sync` is
+    /// set.
+    ///
+    /// Timezones are Windows time zone IDs, e.g., `UTC` or `Pacific Standard
+    /// Time`.
+    pub async fn apply(
+        log: Logger,
+        timezone: Option<&str>,
+        sync: bool,
+    ) -> Result<ClockOverride, ClockError> {
+        let mut clock_override = ClockOverride {
+            log,
+            previous_timezone: None,
+        };
+
+        if let Some(timezone) = timezone {
+            let previous_timezone = run(TZUTIL, &["/g"]).await?.trim().to_owned();
+
+            if previous_timezone != timezone {
+                run(TZUTIL, &["/s", timezone]).await?;
+                clock_override.previous_timezone = Some(previous_timezone);
+            }
+        }
+
+        // If this fails, the timezone is restored when the override is dropped.
+        if sync {
+            run(W32TM, &["/resync", "/force"]).await?;
+            info!(clock_override.log, "Synchronized clock");
+        }
+
+        Ok(clock_override)
+    }
+}
+
+impl Drop for ClockOverride {
+    fn drop(&mut self) {
+        // This must be performed synchronously because there is no async
+        // version of the drop trait.
+        if let Some(ref previous_timezone) = self.previous_timezone {
+            let result = std::process::Command::new(TZUTIL)
+                .args(["/s", previous_timezone])
+                .output()
+                .map_err(|source| ClockError::Exec {
+                    program: TZUTIL,
+                    source,
+                })
+                .and_then(|output| check_output(TZUTIL, output));
+
+            if let Err(e) = result {
+                error!(self.log, "Could not restore timezone"; "timezone" => previous_timezone, "error" => %e);
+            }
+        }
+    }
+}
+
+/// Run the given program and return its standard output.
+async fn run(program: &'static str, args: &[&str]) -> Result<String, ClockError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|source| ClockError::Exec { program, source })?;
+
+    check_output(program, output)
+}
+
+/// Check that the program succeeded and return its standard output.
+fn check_output(program: &'static str, output: Output) -> Result<String, ClockError> {
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    if !output.status.success() {
+        // Both tools report errors on standard output.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(ClockError::Failed {
+            program,
+            output: format!("{}{}", stdout, stderr).trim().into(),
+        });
+    }
+
+    Ok(stdout)
+}
+
+#[derive(Debug, Error)]
+pub enum ClockError {
+    #[error("Could not run `{}': {}", .program, .source)]
+    Exec {
+        program: &'static str,
+        source: std::io::Error,
+    },
+
+    #[error("`{}' failed: {}", .program, .output)]
+    Failed {
+        program: &'static str,
+        output: String,
+    },
+}
@@ -2,13 +2,30 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use libfxrecord::config::{toml_string, ConfigIssues, Validate};
+use libfxrecord::logging::DrainConfig;
+use libfxrecord::retry::ExponentialBackoff;
+use libfxrecord::secret::Secret;
+use libfxrecord::storage::StorageConfig;
+use libfxrecord::timeout::TimeoutConfig;
 use serde::Deserialize;
+use url::Url;
+
+use crate::archive::default_sevenzip_path;
+use crate::hosts::default_hosts_path;
+use crate::provider::MOZILLA_ARCHIVE_URL;
+use crate::taskcluster::BUILD_ARTIFACT_NAME;
+use crate::zip::ExtractLimits;
 
 /// The configuration for FxRunner.
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     /// The address and port to listen on.
     pub host: SocketAddr,
@@ -18,6 +35,100 @@ pub struct Config {
 
     /// The size of the display.
     pub display_size: Size,
+
+    /// The path to the system hosts file.
+    ///
+    /// Sessions that override host names will temporarily modify this file.
+    #[serde(default = "default_hosts_path")]
+    pub hosts_path: PathBuf,
+
+    /// The path to the `7z` executable.
+    ///
+    /// This is used to extract builds that are only available as installers.
+    #[serde(default = "default_sevenzip_path")]
+    pub sevenzip_path: PathBuf,
+
+    /// Where to log to.
+    ///
+    /// If empty, human-readable logs are written to stderr and to the file
+    /// given on the command line.
+    #[serde(default)]
+    pub log: Vec<DrainConfig>,
+
+    /// Whether or not to log restarts instead of performing them.
+    ///
+    /// This allows the full protocol to be exercised on a development machine.
+    #[serde(default)]
+    pub dry_run_shutdown: bool,
+
+    /// The configuration for Taskcluster.
+    #[serde(default)]
+    pub taskcluster: TaskclusterConfig,
+
+    /// The configuration for downloading official builds from
+    /// archive.mozilla.org.
+    #[serde(default)]
+    pub mozilla_archive: MozillaArchiveConfig,
+
+    /// The configuration for the record/replay proxy.
+    ///
+    /// If not provided, sessions requesting a proxy will fail.
+    pub proxy: Option<ProxyConfig>,
+
+    /// The directory containing named profile templates.
+    ///
+    /// Each subdirectory is a profile that sessions may request by name. If
+    /// not provided, sessions requesting a profile template will fail.
+    pub profiles_dir: Option<PathBuf>,
+
+    /// The address and port to serve Prometheus metrics on.
+    ///
+    /// If not provided, metrics are not served.
+    pub metrics_host: Option<SocketAddr>,
+
+    /// The address and port to answer discovery probes from recorders on.
+    ///
+    /// If not provided, the runner cannot be discovered.
+    pub discovery_host: Option<SocketAddr>,
+
+    /// The name the runner advertises to recorders that discover it, and
+    /// that it registers with the broker under.
+    ///
+    /// Defaults to the name of the machine.
+    pub name: Option<String>,
+
+    /// The address of an `fxbroker` to register with when starting.
+    ///
+    /// If not provided, the runner does not register with a broker.
+    pub broker: Option<String>,
+
+    /// The tags describing the runner to recorders leasing it from the
+    /// broker, e.g., `os = "win10"`.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    /// How long each phase of a session may take.
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+
+    /// The bucket to upload large session artifacts to, when the recorder
+    /// requests it.
+    ///
+    /// If not provided, artifacts are always sent to the recorder.
+    pub artifact_store: Option<StorageConfig>,
+
+    /// Limits on what may be extracted from the builds, profiles, and
+    /// distributions that sessions provide.
+    #[serde(default)]
+    pub extract_limits: ExtractLimits,
+
+    /// The size of the largest zipped profile or profile delta, in bytes, to
+    /// accept from recorders.
+    ///
+    /// The limit is advertised to recorders when they request a session, so
+    /// that they fail before sending a larger profile. If not provided,
+    /// profiles of any size are accepted.
+    pub max_profile_size: Option<u64>,
 }
 
 /// The size of a video.
@@ -28,3 +139,439 @@ pub struct Size {
     /// The size in the x dimension.
     pub x: u16,
 }
+
+/// Configuration for Taskcluster.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TaskclusterConfig {
+    /// The root URL of the Taskcluster deployment.
+    ///
+    /// This is overridden by the `TASKCLUSTER_ROOT_URL` environment variable.
+    /// If neither is provided, Firefox CI is used.
+    pub root_url: Option<String>,
+
+    /// The URL of an HTTP proxy to send all Taskcluster requests through.
+    ///
+    /// If not provided, the standard `HTTP_PROXY` and `HTTPS_PROXY`
+    /// environment variables are honoured.
+    pub proxy: Option<String>,
+
+    /// The name of the build artifact to download when the recorder does not
+    /// request one.
+    ///
+    /// This may be a glob pattern (e.g., `public/build/*.zip`), which must
+    /// match exactly one artifact of the build task.
+    pub artifact: Option<String>,
+
+    /// Credentials used to download private artifacts.
+    ///
+    /// These are overridden by the `TASKCLUSTER_CLIENT_ID` and
+    /// `TASKCLUSTER_ACCESS_TOKEN` environment variables.
+    pub credentials: Option<TaskclusterCredentials>,
+
+    /// How to retry failed requests.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The number of connections used to download an artifact.
+    ///
+    /// With more than one connection, artifacts are downloaded in segments
+    /// in parallel. Defaults to a single connection.
+    pub connections: Option<u32>,
+
+    /// How long to wait for a build task to upload its artifact, in seconds.
+    ///
+    /// A task may be known before its artifacts are uploaded. If provided,
+    /// the task's artifacts are listed periodically until the artifact
+    /// appears or this time has passed. Otherwise, a missing artifact fails
+    /// the download immediately.
+    pub artifact_wait_secs: Option<u64>,
+}
+
+/// Configuration for retrying transient failures.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RetryConfig {
+    /// The maximum number of attempts.
+    pub attempts: u32,
+
+    /// The time to wait after the first failed attempt, in milliseconds.
+    ///
+    /// The wait doubles after each subsequent failed attempt.
+    pub initial_wait_ms: u64,
+
+    /// The maximum time to spend retrying a request, in seconds.
+    #[serde(default)]
+    pub max_elapsed_secs: Option<u64>,
+}
+
+impl RetryConfig {
+    /// The time to wait after the first failed attempt.
+    pub fn initial_wait(&self) -> Duration {
+        Duration::from_millis(self.initial_wait_ms)
+    }
+
+    /// The retry policy described by this configuration.
+    pub fn policy(&self) -> ExponentialBackoff {
+        let policy = ExponentialBackoff::new(self.initial_wait(), self.attempts.max(1));
+
+        match self.max_elapsed_secs {
+            Some(secs) => policy.max_elapsed(Duration::from_secs(secs)),
+            None => policy,
+        }
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 5,
+            initial_wait_ms: 1000,
+            max_elapsed_secs: None,
+        }
+    }
+}
+
+impl TaskclusterConfig {
+    /// Return the root URL of the Taskcluster deployment, if not Firefox CI.
+    ///
+    /// The root URL from the environment takes precedence over the one in the
+    /// configuration file.
+    pub fn root_url(&self) -> Option<String> {
+        env::var("TASKCLUSTER_ROOT_URL")
+            .ok()
+            .or_else(|| self.root_url.clone())
+    }
+
+    /// Return the name of the build artifact to download by default.
+    pub fn artifact(&self) -> &str {
+        self.artifact.as_deref().unwrap_or(BUILD_ARTIFACT_NAME)
+    }
+
+    /// Return the number of connections used to download an artifact.
+    pub fn connections(&self) -> u32 {
+        self.connections.unwrap_or(1).max(1)
+    }
+
+    /// Return how long to wait for a build task to upload its artifact, if at
+    /// all.
+    pub fn artifact_wait(&self) -> Option<Duration> {
+        self.artifact_wait_secs.map(Duration::from_secs)
+    }
+
+    /// Return the credentials to use for Taskcluster, if any.
+    ///
+    /// Credentials from the environment take precedence over those in the
+    /// configuration file.
+    pub fn credentials(&self) -> Option<TaskclusterCredentials> {
+        match (
+            env::var("TASKCLUSTER_CLIENT_ID"),
+            env::var("TASKCLUSTER_ACCESS_TOKEN"),
+        ) {
+            (Ok(client_id), Ok(access_token)) => Some(TaskclusterCredentials {
+                client_id,
+                access_token: access_token.into(),
+            }),
+            _ => self.credentials.clone(),
+        }
+    }
+}
+
+/// Permanent Taskcluster credentials.
+#[derive(Clone, Deserialize)]
+pub struct TaskclusterCredentials {
+    /// The client ID.
+    pub client_id: String,
+
+    /// The access token for the client.
+    ///
+    /// This may be read from an environment variable or the credential store
+    /// instead of being written in the configuration file.
+    pub access_token: Secret,
+}
+
+impl fmt::Debug for TaskclusterCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only where the access token comes from is logged, never the token
+        // itself.
+        f.debug_struct("TaskclusterCredentials")
+            .field("client_id", &self.client_id)
+            .field("access_token", &self.access_token)
+            .finish()
+    }
+}
+
+/// Configuration for downloading official builds.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MozillaArchiveConfig {
+    /// The URL of the archive's `pub` directory.
+    pub url: String,
+
+    /// The platform to download builds for, e.g., `win64`.
+    pub platform: String,
+
+    /// The locale to download builds for.
+    pub locale: String,
+}
+
+impl Default for MozillaArchiveConfig {
+    fn default() -> Self {
+        MozillaArchiveConfig {
+            url: MOZILLA_ARCHIVE_URL.into(),
+            platform: "win64".into(),
+            locale: "en-US".into(),
+        }
+    }
+}
+
+/// Configuration for the record/replay proxy.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProxyConfig {
+    /// The path to the `mitmdump` executable.
+    pub mitmdump_path: PathBuf,
+
+    /// The directory that recorded archives are stored in.
+    pub archive_dir: PathBuf,
+
+    /// The port the proxy will listen on.
+    pub port: u16,
+
+    /// The directory that the proxy stores its configuration in.
+    ///
+    /// The proxy's CA certificate is generated here.
+    pub confdir: PathBuf,
+}
+
+/// Return a commented starter configuration for FxRunner.
+///
+/// `hostname` is the name of this machine, if known, which is used to tell
+/// the recorder where to connect.
+pub fn starter_config(host: SocketAddr, hostname: Option<&str>, session_dir: &Path) -> String {
+    let recorder_host = format!("{}:{}", hostname.unwrap_or("<this machine>"), host.port());
+
+    format!(
+        r#"# Generated by `fxrunner init`. See docs/source/configuration.rst for every
+# available option.
+
+[fxrunner]
+# The host and port fxrunner will listen on. Configure fxrecorder with:
+#
+#   host = {recorder_host}
+host = {host}
+
+# The directory to store sessions in. Builds and profiles are kept here so that
+# they persist through restarts.
+session_dir = {session_dir}
+
+# The size of the display.
+display_size = {{ x = 1366, y = 768 }}
+
+# Optional. The path to the hosts file, which is temporarily modified for
+# sessions that override host names.
+hosts_path = {hosts_path}
+
+# Optional. The path to 7z, which is used to unpack builds that are only
+# published as installers.
+sevenzip_path = {sevenzip_path}
+"#,
+        recorder_host = toml_string(&recorder_host),
+        host = toml_string(&host.to_string()),
+        session_dir = toml_string(&session_dir.display().to_string()),
+        hosts_path = toml_string(&default_hosts_path().display().to_string()),
+        sevenzip_path = toml_string(&default_sevenzip_path().display().to_string()),
+    )
+}
+
+impl Config {
+    /// Return the name the runner advertises to recorders that discover it.
+    pub fn advertised_name(&self) -> String {
+        self.name
+            .clone()
+            .or_else(|| env::var("COMPUTERNAME").ok())
+            .unwrap_or_else(|| "fxrunner".into())
+    }
+
+    /// Return the optional features the runner is configured for.
+    pub fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = Vec::new();
+
+        if self.proxy.is_some() {
+            capabilities.push("proxy".into());
+        }
+
+        if self.profiles_dir.is_some() {
+            capabilities.push("profile-templates".into());
+        }
+
+        if self.metrics_host.is_some() {
+            capabilities.push("metrics".into());
+        }
+
+        if self.artifact_store.is_some() {
+            capabilities.push("artifact-store".into());
+        }
+
+        capabilities
+    }
+}
+
+impl Validate for Config {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_dir_if_exists("session_dir", &self.session_dir);
+        issues.check_file("hosts_path", &self.hosts_path);
+
+        // A bare executable name is looked up on the PATH when it is run.
+        if self.sevenzip_path.components().count() > 1 {
+            issues.check_file("sevenzip_path", &self.sevenzip_path);
+        }
+
+        issues.nested("display_size", &self.display_size);
+        issues.nested("taskcluster", &self.taskcluster);
+        issues.nested("mozilla_archive", &self.mozilla_archive);
+
+        if let Some(ref proxy) = self.proxy {
+            issues.nested("proxy", proxy);
+        }
+
+        if let Some(ref profiles_dir) = self.profiles_dir {
+            issues.check_dir_if_exists("profiles_dir", profiles_dir);
+        }
+
+        if let Some(metrics_host) = self.metrics_host {
+            if metrics_host.port() == self.host.port() {
+                issues.push("metrics_host", "must not use the same port as `host'");
+            }
+        }
+
+        issues.nested("timeouts", &self.timeouts);
+
+        if let Some(ref artifact_store) = self.artifact_store {
+            issues.nested("artifact_store", artifact_store);
+        }
+
+        issues.nested("extract_limits", &self.extract_limits);
+
+        if self.max_profile_size == Some(0) {
+            issues.push("max_profile_size", "must be at least 1");
+        }
+    }
+}
+
+impl Validate for Size {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_range("x", self.x, 1, u16::MAX);
+        issues.check_range("y", self.y, 1, u16::MAX);
+    }
+}
+
+impl Validate for TaskclusterConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        if let Some(ref root_url) = self.root_url {
+            check_url(issues, "root_url", root_url);
+        }
+
+        if let Some(ref proxy) = self.proxy {
+            check_url(issues, "proxy", proxy);
+        }
+
+        if let Some(ref credentials) = self.credentials {
+            if let Err(e) = credentials.access_token.resolve() {
+                issues.push("credentials.access_token", e);
+            }
+        }
+
+        if let Some(connections) = self.connections {
+            issues.check_range("connections", connections, 1, 32);
+        }
+
+        if let Some(artifact_wait_secs) = self.artifact_wait_secs {
+            issues.check_range("artifact_wait_secs", artifact_wait_secs, 1, 24 * 60 * 60);
+        }
+
+        issues.nested("retry", &self.retry);
+    }
+}
+
+impl Validate for RetryConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_range("attempts", self.attempts, 1, 100);
+        issues.check_range("initial_wait_ms", self.initial_wait_ms, 1, 60_000);
+
+        if let Some(max_elapsed_secs) = self.max_elapsed_secs {
+            issues.check_range("max_elapsed_secs", max_elapsed_secs, 1, 24 * 60 * 60);
+        }
+    }
+}
+
+impl Validate for ExtractLimits {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        // A limit of zero would reject every archive.
+        for (field, value) in &[
+            ("max_size", self.max_size),
+            ("max_entries", self.max_entries),
+            ("max_ratio", self.max_ratio),
+        ] {
+            if *value == 0 {
+                issues.push(field, "must be at least 1");
+            }
+        }
+    }
+}
+
+impl Validate for MozillaArchiveConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        check_url(issues, "url", &self.url);
+    }
+}
+
+impl Validate for ProxyConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        issues.check_file("mitmdump_path", &self.mitmdump_path);
+        issues.check_dir_if_exists("archive_dir", &self.archive_dir);
+        issues.check_dir_if_exists("confdir", &self.confdir);
+        issues.check_range("port", self.port, 1, u16::MAX);
+    }
+}
+
+/// Report a problem if the given value is not a valid URL.
+fn check_url(issues: &mut ConfigIssues, field: &str, url: &str) {
+    if let Err(e) = Url::parse(url) {
+        issues.push(field, format!("`{}' is not a valid URL: {}", url, e));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use libfxrecord::config::ConfigLoader;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_starter_config() {
+        let dir = TempDir::new().unwrap();
+        let session_dir = PathBuf::from(r"C:\fxrunner\sessions");
+        let path = dir.path().join("fxrecord.toml");
+
+        fs::write(
+            &path,
+            starter_config(
+                "0.0.0.0:8888".parse().unwrap(),
+                Some("runner"),
+                &session_dir,
+            ),
+        )
+        .unwrap();
+
+        let config: Config = ConfigLoader::new("fxrunner")
+            .file(&path)
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_eq!(config.host, "0.0.0.0:8888".parse().unwrap());
+        assert_eq!(config.session_dir, session_dir);
+        assert_eq!(config.hosts_path, default_hosts_path());
+    }
+}
@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Simulating partner repacks of Firefox.
+//!
+//! A partner repack is an ordinary build with a `distribution` directory
+//! containing a `distribution.ini`, which customizes prefs, bookmarks, and
+//! bundled extensions at startup. Installing such a directory into an
+//! extracted CI build reproduces the startup characteristics of the repack.
+
+use std::fs::remove_dir_all;
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::staging::{StagingDir, StagingError};
+use crate::zip::{unzip, ExtractLimits, ZipError};
+
+/// The file that identifies a distribution.
+const DISTRIBUTION_INI: &str = "distribution.ini";
+
+/// Install the distribution in the zip archive at `archive` into the Firefox
+/// in `firefox_dir`, replacing any distribution it already has.
+///
+/// The archive is extracted to a staging directory within `session_dir` first,
+/// so the build is untouched unless the archive extracts successfully. The
+/// archive may contain the distribution files directly or within a single
+/// top-level directory.
+///
+/// The enterprise policies in `distribution/policies.json` are written
+/// afterward, so any policies in the archive are replaced.
+pub fn install_distribution(
+    archive: &Path,
+    session_dir: &Path,
+    firefox_dir: &Path,
+    limits: &ExtractLimits,
+) -> Result<(), DistributionError> {
+    let distribution_dir = firefox_dir.join("distribution");
+    let staging = StagingDir::new(session_dir, &distribution_dir)?;
+    let stats = unzip(archive, staging.path(), limits)?;
+
+    let distribution_root = if staging.path().join(DISTRIBUTION_INI).is_file() {
+        None
+    } else {
+        match stats.top_level_dir {
+            Some(dir) if staging.path().join(&dir).join(DISTRIBUTION_INI).is_file() => Some(dir),
+            _ => return Err(DistributionError::MissingIni),
+        }
+    };
+
+    if distribution_dir.exists() {
+        remove_dir_all(&distribution_dir).map_err(DistributionError::Install)?;
+    }
+
+    match distribution_root {
+        Some(dir) => staging.commit_subdir(&dir)?,
+        None => staging.commit()?,
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum DistributionError {
+    #[error(transparent)]
+    Zip(#[from] ZipError),
+
+    #[error("The distribution does not contain a `distribution.ini'")]
+    MissingIni,
+
+    #[error(transparent)]
+    Staging(#[from] StagingError),
+
+    #[error("Could not install distribution: {}", .0)]
+    Install(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir_all, read_to_string, File};
+    use std::io::Write;
+
+    use assert_matches::assert_matches;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+    use crate::staging::STAGING_NAME;
+
+    /// Write a zip archive containing the given files.
+    fn write_archive(path: &Path, files: &[(&str, &str)]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        for (name, contents) in files {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_install_distribution() {
+        for (i, prefix) in ["", "partner/"].iter().enumerate() {
+            let tempdir = TempDir::new().unwrap();
+            let archive = tempdir.path().join("distribution.zip");
+            let firefox_dir = tempdir.path().join("firefox");
+
+            // The build's own distribution is replaced.
+            create_dir_all(firefox_dir.join("distribution")).unwrap();
+            File::create(firefox_dir.join("distribution").join("original.txt")).unwrap();
+
+            write_archive(
+                &archive,
+                &[
+                    (
+                        &format!("{}distribution.ini", prefix),
+                        "[Global]\nid=partner\n",
+                    ),
+                    (
+                        &format!("{}extensions/addon@example.com.xpi", prefix),
+                        "xpi",
+                    ),
+                ],
+            );
+
+            install_distribution(
+                &archive,
+                tempdir.path(),
+                &firefox_dir,
+                &ExtractLimits::default(),
+            )
+            .unwrap_or_else(|e| panic!("case {}: {}", i, e));
+
+            let distribution_dir = firefox_dir.join("distribution");
+            assert_eq!(
+                read_to_string(distribution_dir.join("distribution.ini")).unwrap(),
+                "[Global]\nid=partner\n"
+            );
+            assert!(distribution_dir
+                .join("extensions")
+                .join("addon@example.com.xpi")
+                .is_file());
+            assert!(!distribution_dir.join("original.txt").exists());
+            assert!(!tempdir
+                .path()
+                .join(STAGING_NAME)
+                .join("distribution")
+                .exists());
+        }
+    }
+
+    #[test]
+    fn test_install_distribution_missing_ini() {
+        let tempdir = TempDir::new().unwrap();
+        let archive = tempdir.path().join("distribution.zip");
+        let firefox_dir = tempdir.path().join("firefox");
+
+        write_archive(&archive, &[("prefs.ini", "")]);
+
+        assert_matches!(
+            install_distribution(
+                &archive,
+                tempdir.path(),
+                &firefox_dir,
+                &ExtractLimits::default()
+            ),
+            Err(DistributionError::MissingIni)
+        );
+        assert!(!firefox_dir.join("distribution").exists());
+        assert!(!tempdir
+            .path()
+            .join(STAGING_NAME)
+            .join("distribution")
+            .exists());
+    }
+}
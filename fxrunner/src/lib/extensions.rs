@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Installing extensions into a profile before Firefox starts.
+//!
+//! Extensions are sideloaded: each XPI is copied into the `extensions`
+//! directory of the profile under its extension ID, and Firefox installs it
+//! at startup.
+
+use std::fs::{create_dir_all, File};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use libfxrecord::prefs::PrefValue;
+use serde_json::Value;
+use thiserror::Error;
+use zip::ZipArchive;
+
+/// Return the prefs that allow extensions sideloaded into the profile to be
+/// enabled without prompting.
+pub fn extension_prefs() -> Vec<(String, PrefValue)> {
+    vec![("extensions.autoDisableScopes".into(), 0i64.into())]
+}
+
+/// Install every XPI in the zip archive at `archive` into the profile.
+///
+/// Returns the IDs of the installed extensions.
+pub fn install_extensions(
+    archive: &Path,
+    profile_path: &Path,
+) -> Result<Vec<String>, ExtensionError> {
+    let mut zip = ZipArchive::new(File::open(archive)?)?;
+
+    let extensions_dir = profile_path.join("extensions");
+    create_dir_all(&extensions_dir)?;
+
+    let mut ids = Vec::with_capacity(zip.len());
+    for i in 0..zip.len() {
+        let mut zipped = zip.by_index(i)?;
+        if !zipped.is_file() {
+            continue;
+        }
+
+        let name = zipped.name().to_owned();
+        let mut xpi = Vec::with_capacity(zipped.size() as usize);
+        zipped.read_to_end(&mut xpi)?;
+
+        let id = extension_id(&name, &xpi)?;
+        std::fs::write(extensions_dir.join(format!("{}.xpi", id)), &xpi)?;
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+/// Read the extension ID from the manifest of the given XPI.
+///
+/// Sideloaded extensions are only installed if their ID matches their file
+/// name, so extensions without an explicit ID cannot be installed.
+fn extension_id(name: &str, xpi: &[u8]) -> Result<String, ExtensionError> {
+    let mut zip = ZipArchive::new(Cursor::new(xpi))?;
+
+    let manifest: Value = {
+        let mut contents = String::new();
+        zip.by_name("manifest.json")?
+            .read_to_string(&mut contents)?;
+
+        serde_json::from_str(&contents).map_err(|source| ExtensionError::Manifest {
+            name: name.into(),
+            source,
+        })?
+    };
+
+    let id = manifest
+        .get("browser_specific_settings")
+        .or_else(|| manifest.get("applications"))
+        .and_then(|settings| settings["gecko"]["id"].as_str())
+        .ok_or_else(|| ExtensionError::MissingId(name.into()))?;
+
+    // The ID becomes a file name in the profile.
+    if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+        return Err(ExtensionError::InvalidId(id.into()));
+    }
+
+    Ok(id.into())
+}
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("Could not install extensions: {}", .0)]
+    Io(#[from] io::Error),
+
+    #[error("Could not read extensions: {}", .0)]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Could not parse manifest of extension `{}': {}", .name, .source)]
+    Manifest {
+        name: String,
+        source: serde_json::Error,
+    },
+
+    #[error("Extension `{}' does not specify an ID in its manifest", .0)]
+    MissingId(String),
+
+    #[error("`{}' is not a valid extension ID", .0)]
+    InvalidId(String),
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Seek, Write};
+
+    use assert_matches::assert_matches;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::*;
+
+    /// Return an XPI with the given manifest.
+    fn xpi(manifest: &str) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer
+            .start_file("manifest.json", FileOptions::default())
+            .unwrap();
+        writer.write_all(manifest.as_bytes()).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Write a zip archive of the given XPIs.
+    fn write_archive<W: Write + Seek>(w: W, xpis: &[(&str, Vec<u8>)]) {
+        let mut writer = ZipWriter::new(w);
+        for (name, contents) in xpis {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_install_extensions() {
+        let tempdir = TempDir::new().unwrap();
+        let archive = tempdir.path().join("extensions.zip");
+        let profile = tempdir.path().join("profile");
+
+        let first = xpi(r#"{"browser_specific_settings": {"gecko": {"id": "first@example.com"}}}"#);
+        let second =
+            xpi(r#"{"applications": {"gecko": {"id": "{5b6b4f8a-d4e2-4f6b-9b8e-3f3c0e6c5a11}"}}}"#);
+
+        write_archive(
+            File::create(&archive).unwrap(),
+            &[("0.xpi", first.clone()), ("1.xpi", second.clone())],
+        );
+
+        assert_eq!(
+            install_extensions(&archive, &profile).unwrap(),
+            vec![
+                "first@example.com".to_owned(),
+                "{5b6b4f8a-d4e2-4f6b-9b8e-3f3c0e6c5a11}".to_owned(),
+            ]
+        );
+
+        let extensions_dir = profile.join("extensions");
+        assert_eq!(
+            std::fs::read(extensions_dir.join("first@example.com.xpi")).unwrap(),
+            first
+        );
+        assert_eq!(
+            std::fs::read(extensions_dir.join("{5b6b4f8a-d4e2-4f6b-9b8e-3f3c0e6c5a11}.xpi"))
+                .unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_extension_id() {
+        assert_matches!(
+            extension_id("0.xpi", &xpi(r#"{"name": "No ID"}"#)),
+            Err(ExtensionError::MissingId(name)) => {
+                assert_eq!(name, "0.xpi");
+            }
+        );
+
+        assert_matches!(
+            extension_id(
+                "0.xpi",
+                &xpi(r#"{"browser_specific_settings": {"gecko": {"id": "../evil"}}}"#)
+            ),
+            Err(ExtensionError::InvalidId(id)) => {
+                assert_eq!(id, "../evil");
+            }
+        );
+
+        assert_matches!(
+            extension_id("0.xpi", &xpi("{")),
+            Err(ExtensionError::Manifest { .. })
+        );
+
+        assert_matches!(
+            extension_id("0.xpi", b"not a zip"),
+            Err(ExtensionError::Zip(..))
+        );
+    }
+}
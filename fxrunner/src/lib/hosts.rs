@@ -0,0 +1,192 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Temporary host name overrides via the system hosts file.
+
+use std::env;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use slog::{error, Logger};
+use thiserror::Error;
+use tokio::fs::{read_to_string, write};
+
+/// The line that begins the block of overrides written by the runner.
+const BEGIN_MARKER: &str = "# BEGIN fxrunner overrides";
+
+/// The line that ends the block of overrides written by the runner.
+const END_MARKER: &str = "# END fxrunner overrides";
+
+/// Return the default path to the system hosts file.
+pub fn default_hosts_path() -> PathBuf {
+    let system_root = env::var_os("SystemRoot").unwrap_or_else(|| "C:\\Windows".into());
+
+    PathBuf::from(system_root)
+        .join("System32")
+        .join("drivers")
+        .join("etc")
+        .join("hosts")
+}
+
+/// Host name overrides that have been written to the hosts file.
+///
+/// The overrides are removed from the hosts file when this is dropped.
+pub struct HostOverrides {
+    log: Logger,
+    hosts_path: PathBuf,
+}
+
+impl HostOverrides {
+    /// Add the given overrides to the hosts file at `hosts_path`.
+    ///
+    /// Any overrides left over from a previous session are replaced.
+    pub async fn apply(
+        log: Logger,
+        hosts_path: &Path,
+        overrides: &[(String, IpAddr)],
+    ) -> Result<HostOverrides, HostsError> {
+        for (host, _) in overrides {
+            if !is_valid_hostname(host) {
+                return Err(HostsError::InvalidHostname(host.clone()));
+            }
+        }
+
+        let contents = read_to_string(hosts_path).await.map_err(HostsError::Read)?;
+
+        write(hosts_path, add_overrides(&contents, overrides))
+            .await
+            .map_err(HostsError::Write)?;
+
+        Ok(HostOverrides {
+            log,
+            hosts_path: hosts_path.into(),
+        })
+    }
+}
+
+impl Drop for HostOverrides {
+    fn drop(&mut self) {
+        // This must be performed synchronously because there is no async
+        // version of the drop trait.
+        let result = std::fs::read_to_string(&self.hosts_path)
+            .and_then(|contents| std::fs::write(&self.hosts_path, remove_overrides(&contents)));
+
+        if let Err(e) = result {
+            error!(self.log, "Could not remove host overrides"; "error" => %e);
+        }
+    }
+}
+
+/// Return whether or not the host name is safe to write to the hosts file.
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('-')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// Return the contents of a hosts file with the overrides added.
+fn add_overrides(contents: &str, overrides: &[(String, IpAddr)]) -> String {
+    let mut result = remove_overrides(contents);
+
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+
+    result.push_str(BEGIN_MARKER);
+    result.push('\n');
+
+    for (host, addr) in overrides {
+        result.push_str(&format!("{} {}\n", addr, host));
+    }
+
+    result.push_str(END_MARKER);
+    result.push('\n');
+
+    result
+}
+
+/// Return the contents of a hosts file with any overrides removed.
+fn remove_overrides(contents: &str) -> String {
+    let mut result = String::with_capacity(contents.len());
+    let mut in_block = false;
+    let mut rest = contents;
+
+    while !rest.is_empty() {
+        // Split off the next line, including its line ending.
+        let end = rest.find('\n').map(|i| i + 1).unwrap_or_else(|| rest.len());
+        let (line, next) = rest.split_at(end);
+        rest = next;
+
+        let trimmed = line.trim_end();
+
+        if trimmed == BEGIN_MARKER {
+            in_block = true;
+        } else if trimmed == END_MARKER {
+            in_block = false;
+        } else if !in_block {
+            result.push_str(line);
+        }
+    }
+
+    result
+}
+
+#[derive(Debug, Error)]
+pub enum HostsError {
+    #[error("Invalid host name `{}'", .0)]
+    InvalidHostname(String),
+
+    #[error("Could not read hosts file: {}", .0)]
+    Read(#[source] io::Error),
+
+    #[error("Could not write hosts file: {}", .0)]
+    Write(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOSTS: &str = "127.0.0.1 localhost\r\n::1 localhost\r\n";
+
+    #[test]
+    fn test_add_remove_overrides() {
+        let overrides: Vec<(String, IpAddr)> = vec![
+            ("example.com".into(), "10.0.0.1".parse().unwrap()),
+            ("www.example.com".into(), "::2".parse().unwrap()),
+        ];
+
+        let added = add_overrides(HOSTS, &overrides);
+        assert_eq!(
+            added,
+            "127.0.0.1 localhost\r\n\
+             ::1 localhost\r\n\
+             # BEGIN fxrunner overrides\n\
+             10.0.0.1 example.com\n\
+             ::2 www.example.com\n\
+             # END fxrunner overrides\n"
+        );
+        assert_eq!(remove_overrides(&added), HOSTS);
+
+        // Stale overrides are replaced.
+        assert_eq!(add_overrides(&added, &overrides), added);
+
+        assert_eq!(remove_overrides(HOSTS), HOSTS);
+        assert_eq!(remove_overrides(""), "");
+    }
+
+    #[test]
+    fn test_is_valid_hostname() {
+        for host in &["example.com", "www.example-1.com", "localhost"] {
+            assert!(is_valid_hostname(host), "{}", host);
+        }
+
+        for host in &["", "-example.com", "example.com\n1.2.3.4 foo", "a b"] {
+            assert!(!is_valid_hostname(host), "{:?}", host);
+        }
+    }
+}
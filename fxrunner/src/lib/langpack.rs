@@ -0,0 +1,171 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Running Firefox in a locale other than the one it was built for.
+//!
+//! Builds from archive.mozilla.org are downloaded in the requested locale.
+//! Other builds are assumed to be `en-US`, so the language pack for their
+//! version is downloaded from archive.mozilla.org and installed into the
+//! profile instead.
+
+use std::io;
+use std::path::Path;
+
+use libfxrecord::prefs::PrefValue;
+use reqwest::{Client, Url};
+use thiserror::Error;
+use tokio::fs::{create_dir_all, write};
+
+use crate::build::BuildMetadataError;
+use crate::config::MozillaArchiveConfig;
+use crate::extensions::extension_prefs;
+
+/// The directory that Nightly language packs are published to.
+///
+/// Language packs do not depend on the platform, so they are only published
+/// once.
+const NIGHTLY_LANGPACK_DIR: &str = "firefox/nightly/latest-mozilla-central-l10n/linux-x86_64/xpi/";
+
+/// Return whether or not the string is a valid locale code, e.g., `en-US`,
+/// `ast`, or `ja-JP-mac`.
+pub fn is_valid_locale(locale: &str) -> bool {
+    !locale.is_empty()
+        && locale.len() <= 16
+        && locale
+            .split('-')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Return the prefs that make Firefox use the given locale.
+///
+/// Language packs installed into the profile are sideloaded extensions, so
+/// they must be allowed to be enabled without prompting.
+pub fn locale_prefs(locale: &str) -> Vec<(String, PrefValue)> {
+    let mut prefs = vec![("intl.locale.requested".into(), locale.into())];
+    prefs.extend(extension_prefs());
+    prefs
+}
+
+/// Download the language pack for the given version of Firefox and install it
+/// into the profile.
+pub async fn install_langpack(
+    config: &MozillaArchiveConfig,
+    profile_path: &Path,
+    version: &str,
+    locale: &str,
+) -> Result<(), LangpackError> {
+    if !is_valid_locale(locale) {
+        return Err(LangpackError::InvalidLocale(locale.into()));
+    }
+
+    let url = langpack_url(&config.url, &config.platform, version, locale)?;
+
+    let contents = Client::new()
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|source| LangpackError::Download {
+            url: url.clone(),
+            source,
+        })?
+        .bytes()
+        .await
+        .map_err(|source| LangpackError::Download { url, source })?;
+
+    let extensions_dir = profile_path.join("extensions");
+    create_dir_all(&extensions_dir)
+        .await
+        .map_err(LangpackError::Install)?;
+
+    write(
+        extensions_dir.join(format!("langpack-{}@firefox.mozilla.org.xpi", locale)),
+        contents,
+    )
+    .await
+    .map_err(LangpackError::Install)
+}
+
+/// Return the URL of the language pack for the given version of Firefox.
+fn langpack_url(
+    archive_url: &str,
+    platform: &str,
+    version: &str,
+    locale: &str,
+) -> Result<Url, LangpackError> {
+    let mut base_url = Url::parse(archive_url)?;
+
+    // Ensure that paths are joined onto the URL instead of replacing its last
+    // path segment.
+    if !base_url.path().ends_with('/') {
+        let path = format!("{}/", base_url.path());
+        base_url.set_path(&path);
+    }
+
+    let path = if version.ends_with("a1") {
+        format!(
+            "{}firefox-{}.{}.langpack.xpi",
+            NIGHTLY_LANGPACK_DIR, version, locale
+        )
+    } else {
+        format!(
+            "firefox/releases/{}/{}/xpi/{}.xpi",
+            version, platform, locale
+        )
+    };
+
+    Ok(base_url.join(&path)?)
+}
+
+#[derive(Debug, Error)]
+pub enum LangpackError {
+    #[error("`{}' is not a valid locale", .0)]
+    InvalidLocale(String),
+
+    #[error(transparent)]
+    BuildMetadata(#[from] BuildMetadataError),
+
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+
+    #[error("Could not download language pack from `{}': {}", .url, .source)]
+    Download { url: Url, source: reqwest::Error },
+
+    #[error("Could not install language pack: {}", .0)]
+    Install(#[source] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_locale() {
+        assert!(is_valid_locale("en-US"));
+        assert!(is_valid_locale("ast"));
+        assert!(is_valid_locale("ja-JP-mac"));
+
+        assert!(!is_valid_locale(""));
+        assert!(!is_valid_locale("en-"));
+        assert!(!is_valid_locale("../../en-US"));
+        assert!(!is_valid_locale("en US"));
+    }
+
+    #[test]
+    fn test_langpack_url() {
+        assert_eq!(
+            langpack_url("https://archive.mozilla.org/pub", "win64", "80.0", "fr")
+                .unwrap()
+                .as_str(),
+            "https://archive.mozilla.org/pub/firefox/releases/80.0/win64/xpi/fr.xpi"
+        );
+
+        assert_eq!(
+            langpack_url("https://archive.mozilla.org/pub/", "win64", "82.0a1", "ja-JP-mac")
+                .unwrap()
+                .as_str(),
+            "https://archive.mozilla.org/pub/firefox/nightly/latest-mozilla-central-l10n/linux-x86_64/xpi/firefox-82.0a1.ja-JP-mac.langpack.xpi"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Launching Firefox several times in one session.
+//!
+//! Every launch after the first reuses the extracted build without the runner
+//! restarting. The profile is either reused, so that each launch sees the
+//! changes made by those before it, or reset from a snapshot taken before the
+//! first launch.
+
+use std::fs::{create_dir_all, remove_dir_all};
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::zip::{unzip, zip_dir, ExtractLimits, ZipError};
+
+/// Take a snapshot of the profile at `profile_path`, which is written to the
+/// zip archive at `snapshot_path`.
+pub fn snapshot_profile(profile_path: &Path, snapshot_path: &Path) -> Result<(), LaunchError> {
+    zip_dir(profile_path, snapshot_path)?;
+    Ok(())
+}
+
+/// Replace the profile at `profile_path` with the snapshot at
+/// `snapshot_path`.
+pub fn reset_profile(snapshot_path: &Path, profile_path: &Path) -> Result<(), LaunchError> {
+    if profile_path.exists() {
+        remove_dir_all(profile_path).map_err(LaunchError::ResetProfile)?;
+    }
+
+    // The archive has no entries if the profile was empty.
+    create_dir_all(profile_path).map_err(LaunchError::ResetProfile)?;
+
+    // The snapshot was taken by the runner, from a profile that was already
+    // extracted within the limits.
+    unzip(snapshot_path, profile_path, &ExtractLimits::unlimited())?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum LaunchError {
+    #[error("Could not reset profile: {}", .0)]
+    ResetProfile(#[source] io::Error),
+
+    #[error(transparent)]
+    Zip(#[from] ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_reset_profile() {
+        let tempdir = TempDir::new().unwrap();
+        let profile = tempdir.path().join("profile");
+        let snapshot = tempdir.path().join("snapshot.zip");
+
+        create_dir_all(profile.join("startupCache")).unwrap();
+        write(profile.join("prefs.js"), "prefs").unwrap();
+
+        snapshot_profile(&profile, &snapshot).unwrap();
+
+        // Simulate the changes Firefox makes to the profile when it runs.
+        write(profile.join("prefs.js"), "modified").unwrap();
+        write(
+            profile.join("startupCache").join("startupCache.8.little"),
+            "cache",
+        )
+        .unwrap();
+
+        reset_profile(&snapshot, &profile).unwrap();
+
+        assert_eq!(read_to_string(profile.join("prefs.js")).unwrap(), "prefs");
+        assert!(profile.join("startupCache").is_dir());
+        assert!(!profile
+            .join("startupCache")
+            .join("startupCache.8.little")
+            .exists());
+    }
+}
@@ -2,11 +2,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod archive;
+pub mod bandwidth;
+pub mod build;
+pub mod clock;
 pub mod config;
+pub mod distribution;
+pub mod extensions;
 pub mod fs;
+pub mod hosts;
+pub mod langpack;
+pub mod launches;
+pub mod marionette;
+pub mod metrics;
 pub mod osapi;
+pub mod pings;
+pub mod profile_cache;
 pub mod proto;
+pub mod provider;
+pub mod proxy;
+pub mod restarts;
 pub mod session;
 pub mod splash;
+pub mod staging;
+pub mod store;
 pub mod taskcluster;
+pub mod telemetry;
+pub mod templates;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod throttle;
 pub mod zip;
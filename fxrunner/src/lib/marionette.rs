@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal client for Firefox's Marionette remote protocol.
+//!
+//! Only what is needed to run privileged scripts in a running Firefox is
+//! supported, such as collecting a memory report once startup has settled.
+//!
+//! Marionette packets are of the form `<length>:<JSON>`. Commands are sent as
+//! `[0, id, name, params]` and answered with `[1, id, error, result]`.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+
+use libfxrecord::prefs::PrefValue;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::time::delay_for;
+
+/// The number of times to try connecting to Marionette before giving up.
+const CONNECT_ATTEMPTS: usize = 60;
+
+/// How long to wait between attempts to connect to Marionette.
+const CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The largest packet that is accepted from Firefox.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// A privileged script that writes a gzipped memory report to the path given
+/// as its first argument.
+///
+/// This is equivalent to "Save..." in `about:memory`.
+const DUMP_MEMORY_REPORT_SCRIPT: &str = r#"
+    const [path, resolve] = arguments;
+    const dumper = Cc["@mozilla.org/memory-info-dumper;1"]
+        .getService(Ci.nsIMemoryInfoDumper);
+
+    dumper.dumpMemoryReportsToNamedFile(
+        path,
+        () => resolve(null),
+        null,
+        /* anonymize = */ false,
+        /* minimizeMemoryUsage = */ false,
+    );
+"#;
+
+/// A connection to Marionette.
+pub struct Marionette {
+    stream: TcpStream,
+    next_id: u64,
+}
+
+impl Marionette {
+    /// Connect to Marionette on the given port and start a session.
+    ///
+    /// Firefox only starts listening once it has started up, so connecting is
+    /// retried for a while.
+    pub async fn connect(port: u16) -> Result<Marionette, MarionetteError> {
+        let mut attempt = 0;
+        let stream = loop {
+            match TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await {
+                Ok(stream) => break stream,
+                Err(e) => {
+                    attempt += 1;
+                    if attempt == CONNECT_ATTEMPTS {
+                        return Err(MarionetteError::Connect(e));
+                    }
+
+                    delay_for(CONNECT_INTERVAL).await;
+                }
+            }
+        };
+
+        let mut marionette = Marionette { stream, next_id: 0 };
+
+        // Marionette greets every new connection.
+        let greeting = marionette.read_packet().await?;
+        if greeting["applicationType"] != "gecko" {
+            return Err(MarionetteError::Greeting(greeting));
+        }
+
+        marionette
+            .command("WebDriver:NewSession", json!({}))
+            .await?;
+        marionette
+            .command("Marionette:SetContext", json!({ "value": "chrome" }))
+            .await?;
+
+        Ok(marionette)
+    }
+
+    /// Write a gzipped memory report to the given path.
+    pub async fn dump_memory_report(&mut self, path: &Path) -> Result<(), MarionetteError> {
+        self.command(
+            "WebDriver:ExecuteAsyncScript",
+            json!({
+                "script": DUMP_MEMORY_REPORT_SCRIPT,
+                "args": [path.to_string_lossy()],
+                "scriptTimeout": 60_000,
+            }),
+        )
+        .await
+        .map(drop)
+    }
+
+    /// Send a command and return its result.
+    async fn command(&mut self, name: &str, params: Value) -> Result<Value, MarionetteError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.write_packet(&json!([0, id, name, params])).await?;
+
+        loop {
+            let response = self.read_packet().await?;
+
+            // Only the response to this command is of interest.
+            if response[0] != 1 || response[1] != id {
+                continue;
+            }
+
+            if !response[2].is_null() {
+                return Err(MarionetteError::Command {
+                    command: name.into(),
+                    error: response[2]["error"].as_str().unwrap_or("unknown").into(),
+                    message: response[2]["message"].as_str().unwrap_or_default().into(),
+                });
+            }
+
+            return Ok(response[3].clone());
+        }
+    }
+
+    async fn write_packet(&mut self, value: &Value) -> Result<(), MarionetteError> {
+        let body = serde_json::to_vec(value).expect("could not serialize packet");
+
+        self.stream
+            .write_all(format!("{}:", body.len()).as_bytes())
+            .await?;
+        self.stream.write_all(&body).await?;
+
+        Ok(())
+    }
+
+    async fn read_packet(&mut self) -> Result<Value, MarionetteError> {
+        let mut len: usize = 0;
+
+        loop {
+            let b = self.stream.read_u8().await?;
+            match b {
+                b':' => break,
+                b'0'..=b'9' if len <= MAX_PACKET_SIZE => {
+                    len = len * 10 + usize::from(b - b'0');
+                }
+                _ => return Err(MarionetteError::Packet),
+            }
+        }
+
+        if len > MAX_PACKET_SIZE {
+            return Err(MarionetteError::Packet);
+        }
+
+        let mut body = vec![0u8; len];
+        self.stream.read_exact(&mut body).await?;
+
+        serde_json::from_slice(&body).map_err(|_| MarionetteError::Packet)
+    }
+}
+
+/// Return an unused port on localhost for Marionette to listen on.
+pub async fn unused_port() -> Result<u16, io::Error> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Return the prefs required for Marionette to listen on the given port.
+///
+/// Firefox must also be started with `--marionette`.
+pub fn marionette_prefs(port: u16) -> Vec<(String, PrefValue)> {
+    vec![("marionette.port".into(), i64::from(port).into())]
+}
+
+#[derive(Debug, Error)]
+pub enum MarionetteError {
+    #[error("Could not connect to Marionette: {}", .0)]
+    Connect(#[source] io::Error),
+
+    #[error("Unexpected greeting from Marionette: {}", .0)]
+    Greeting(Value),
+
+    #[error("Invalid packet from Marionette")]
+    Packet,
+
+    #[error("Marionette command `{}' failed: {}: {}", .command, .error, .message)]
+    Command {
+        command: String,
+        error: String,
+        message: String,
+    },
+
+    #[error("Timed out waiting for Marionette")]
+    Timeout,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    /// Serve a single Marionette connection, answering each command with the
+    /// given responses in order.
+    async fn serve(responses: Vec<Value>) -> u16 {
+        let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut server = Marionette { stream, next_id: 0 };
+
+            server
+                .write_packet(&json!({
+                    "applicationType": "gecko",
+                    "marionetteProtocol": 3,
+                }))
+                .await
+                .unwrap();
+
+            for response in responses {
+                let command = server.read_packet().await.unwrap();
+                assert_eq!(command[0], 0);
+
+                server
+                    .write_packet(&json!([
+                        1,
+                        command[1],
+                        response["error"],
+                        response["result"]
+                    ]))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn test_dump_memory_report() {
+        let port = serve(vec![
+            json!({ "error": null, "result": { "capabilities": {} } }),
+            json!({ "error": null, "result": { "value": null } }),
+            json!({ "error": null, "result": { "value": null } }),
+        ])
+        .await;
+
+        let mut marionette = Marionette::connect(port).await.unwrap();
+        marionette
+            .dump_memory_report(Path::new("memory-report.json.gz"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_error() {
+        let port = serve(vec![
+            json!({ "error": null, "result": { "capabilities": {} } }),
+            json!({ "error": null, "result": { "value": null } }),
+            json!({
+                "error": {
+                    "error": "javascript error",
+                    "message": "TypeError: dumper is undefined",
+                },
+                "result": null,
+            }),
+        ])
+        .await;
+
+        let mut marionette = Marionette::connect(port).await.unwrap();
+        assert_matches!(
+            marionette.dump_memory_report(Path::new("memory-report.json.gz")).await.unwrap_err(),
+            MarionetteError::Command { command, error, message } => {
+                assert_eq!(command, "WebDriver:ExecuteAsyncScript");
+                assert_eq!(error, "javascript error");
+                assert_eq!(message, "TypeError: dumper is undefined");
+            }
+        );
+    }
+}
@@ -0,0 +1,384 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Metrics about the runner, exposed over HTTP in the Prometheus text format
+//! so that a fleet of runners may be monitored.
+//!
+//! See: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+lazy_static! {
+    /// The metrics of this runner.
+    pub static ref METRICS: RunnerMetrics = RunnerMetrics::default();
+}
+
+/// What the runner is currently doing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Waiting for a request from the recorder.
+    Idle,
+
+    /// Downloading (or receiving) a build.
+    Downloading,
+
+    /// Extracting a build.
+    Extracting,
+
+    /// Preparing the profile for a new session.
+    PreparingProfile,
+
+    /// Waiting to restart for a new session.
+    Restarting,
+
+    /// Waiting for the CPU and disk to become idle.
+    WaitingForIdle,
+
+    /// Running Firefox.
+    RunningFirefox,
+}
+
+impl Phase {
+    const ALL: &'static [Phase] = &[
+        Phase::Idle,
+        Phase::Downloading,
+        Phase::Extracting,
+        Phase::PreparingProfile,
+        Phase::Restarting,
+        Phase::WaitingForIdle,
+        Phase::RunningFirefox,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Idle => "idle",
+            Phase::Downloading => "downloading",
+            Phase::Extracting => "extracting",
+            Phase::PreparingProfile => "preparing_profile",
+            Phase::Restarting => "restarting",
+            Phase::WaitingForIdle => "waiting_for_idle",
+            Phase::RunningFirefox => "running_firefox",
+        }
+    }
+}
+
+/// Metrics about the runner.
+#[derive(Debug)]
+pub struct RunnerMetrics {
+    requests: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    received_bytes: AtomicU64,
+    failures: Mutex<BTreeMap<&'static str, u64>>,
+    phase: Mutex<Phase>,
+}
+
+impl Default for RunnerMetrics {
+    fn default() -> Self {
+        RunnerMetrics {
+            requests: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            failures: Mutex::new(BTreeMap::new()),
+            phase: Mutex::new(Phase::Idle),
+        }
+    }
+}
+
+impl RunnerMetrics {
+    /// Record that a request from the recorder was served.
+    pub fn request_served(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a request failed with the given type of failure.
+    pub fn request_failed(&self, kind: &'static str) {
+        *self.failures.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    /// Record that the given number of bytes of a build were downloaded.
+    pub fn downloaded(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that the given number of bytes were sent to a recorder.
+    pub fn sent_to_recorder(&self, bytes: u64) {
+        self.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Record that the given number of bytes were received from a recorder.
+    pub fn received_from_recorder(&self, bytes: u64) {
+        self.received_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Set the current phase.
+    pub fn set_phase(&self, phase: Phase) {
+        *self.phase.lock().unwrap() = phase;
+    }
+
+    /// Return the current phase.
+    pub fn phase(&self) -> Phase {
+        *self.phase.lock().unwrap()
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    ///
+    /// The free space of the disk containing `session_dir` is included if it
+    /// can be determined.
+    pub fn render(&self, session_dir: &Path) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "fxrunner_requests_total",
+            "counter",
+            "The number of requests served.",
+        );
+        writeln!(
+            out,
+            "fxrunner_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "fxrunner_failures_total",
+            "counter",
+            "The number of requests that failed, by type of failure.",
+        );
+        for (kind, count) in self.failures.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "fxrunner_failures_total{{type=\"{}\"}} {}",
+                kind, count
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "fxrunner_downloaded_bytes_total",
+            "counter",
+            "The number of bytes of builds downloaded.",
+        );
+        writeln!(
+            out,
+            "fxrunner_downloaded_bytes_total {}",
+            self.downloaded_bytes.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "fxrunner_recorder_bytes_total",
+            "counter",
+            "The number of bytes exchanged with recorders, by direction.",
+        );
+        writeln!(
+            out,
+            "fxrunner_recorder_bytes_total{{direction=\"sent\"}} {}",
+            self.sent_bytes.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "fxrunner_recorder_bytes_total{{direction=\"received\"}} {}",
+            self.received_bytes.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        write_header(
+            &mut out,
+            "fxrunner_phase",
+            "gauge",
+            "Whether or not the runner is in the given phase.",
+        );
+        let current = *self.phase.lock().unwrap();
+        for phase in Phase::ALL {
+            writeln!(
+                out,
+                "fxrunner_phase{{phase=\"{}\"}} {}",
+                phase.as_str(),
+                (*phase == current) as u8
+            )
+            .unwrap();
+        }
+
+        if let Ok(free) = disk_free_space(session_dir) {
+            write_header(
+                &mut out,
+                "fxrunner_disk_free_bytes",
+                "gauge",
+                "The free space of the disk containing the session directory.",
+            );
+            writeln!(out, "fxrunner_disk_free_bytes {}", free).unwrap();
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Return the free space available to the runner on the disk containing the
+/// given path.
+#[cfg(windows)]
+fn disk_free_space(path: &Path) -> Result<u64, io::Error> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+
+    use winapi::shared::ntdef::ULARGE_INTEGER;
+    use winapi::um::fileapi;
+
+    use crate::osapi::error::check_nonzero;
+
+    let path = OsStr::new(path)
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    let mut free: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+
+    check_nonzero(unsafe {
+        fileapi::GetDiskFreeSpaceExW(path.as_ptr(), &mut free, null_mut(), null_mut())
+    })?;
+
+    Ok(unsafe { *free.QuadPart() })
+}
+
+/// Return the free space available to the runner on the disk containing the
+/// given path.
+#[cfg(unix)]
+fn disk_free_space(path: &Path) -> Result<u64, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The field types vary between platforms.
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stats.f_bavail) * u64::from(stats.f_frsize))
+}
+
+/// Serve the metrics over HTTP at `/metrics` on the given address.
+///
+/// This only returns if the address cannot be listened on.
+pub async fn serve_metrics(
+    log: Logger,
+    addr: SocketAddr,
+    session_dir: PathBuf,
+) -> Result<(), io::Error> {
+    let mut listener = TcpListener::bind(addr).await?;
+    info!(log, "Serving metrics"; "addr" => addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(log, "Could not accept metrics connection"; "error" => %e);
+                continue;
+            }
+        };
+
+        let log = log.clone();
+        let session_dir = session_dir.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_request(stream, &session_dir).await {
+                warn!(log, "Could not serve metrics"; "peer" => peer, "error" => %e);
+            }
+        });
+    }
+}
+
+/// Respond to a single HTTP request for the metrics.
+async fn handle_metrics_request(stream: TcpStream, session_dir: &Path) -> Result<(), io::Error> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+
+    // The headers are not needed, but must be read before responding.
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render(session_dir)),
+        (Some("GET"), Some(..)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    );
+
+    let mut stream = stream.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown(Shutdown::Write)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = RunnerMetrics::default();
+        metrics.request_served();
+        metrics.request_served();
+        metrics.request_failed("download");
+        metrics.downloaded(1024);
+        metrics.sent_to_recorder(256);
+        metrics.received_from_recorder(512);
+        metrics.set_phase(Phase::RunningFirefox);
+
+        let rendered = metrics.render(&std::env::current_dir().unwrap());
+
+        assert!(rendered.contains("fxrunner_requests_total 2\n"));
+        assert!(rendered.contains("fxrunner_failures_total{type=\"download\"} 1\n"));
+        assert!(rendered.contains("fxrunner_downloaded_bytes_total 1024\n"));
+        assert!(rendered.contains("fxrunner_recorder_bytes_total{direction=\"sent\"} 256\n"));
+        assert!(rendered.contains("fxrunner_recorder_bytes_total{direction=\"received\"} 512\n"));
+        assert!(rendered.contains("fxrunner_phase{phase=\"running_firefox\"} 1\n"));
+        assert!(rendered.contains("fxrunner_phase{phase=\"idle\"} 0\n"));
+        assert!(rendered.contains("# TYPE fxrunner_disk_free_bytes gauge\n"));
+    }
+}
@@ -4,21 +4,119 @@
 
 //! Traits for interacting safely with OS-level APIs.
 
+use std::convert::Infallible;
 use std::error::Error;
 use std::fmt::Debug;
 use std::io;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use slog::{info, Logger};
 use thiserror::Error;
 use tokio::time::delay_for;
 
+#[cfg(windows)]
+mod boot;
+#[cfg(windows)]
+mod desktop;
+#[cfg(windows)]
 pub mod error;
+#[cfg(windows)]
 pub mod handle;
+#[cfg(target_os = "linux")]
+mod logind;
+#[cfg(windows)]
 mod perf;
+pub mod power;
+#[cfg(windows)]
 pub mod process;
+#[cfg(target_os = "linux")]
+mod procfs;
+#[cfg(windows)]
 mod shutdown;
 
-pub use perf::{CpuTimes, IoCounters};
+/// The [`ShutdownProvider`](trait.ShutdownProvider.html) for the platform
+/// fxrunner was built for.
+#[cfg(windows)]
+pub type PlatformShutdownProvider = WindowsShutdownProvider;
+
+/// The [`ShutdownProvider`](trait.ShutdownProvider.html) for the platform
+/// fxrunner was built for.
+#[cfg(target_os = "linux")]
+pub type PlatformShutdownProvider = LinuxShutdownProvider;
+
+/// The [`PerfProvider`](trait.PerfProvider.html) for the platform fxrunner
+/// was built for.
+#[cfg(windows)]
+pub type PlatformPerfProvider = WindowsPerfProvider;
+
+/// The [`PerfProvider`](trait.PerfProvider.html) for the platform fxrunner
+/// was built for.
+#[cfg(target_os = "linux")]
+pub type PlatformPerfProvider = LinuxPerfProvider;
+
+/// Raw read and write IO counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoCounters {
+    pub reads: u32,
+    pub writes: u32,
+}
+
+/// Information about the idle time of a CPU in an interval.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CpuTimes {
+    /// The amount of time the CPU was idle in the interval (in arbitrary units).
+    pub idle: u64,
+    /// The total amount of time in the interval (in arbitrary units).
+    pub total: u64,
+}
+
+/// When the machine booted and became ready.
+#[derive(Clone, Copy, Debug)]
+pub struct BootTimes {
+    /// When the machine booted.
+    pub boot: SystemTime,
+
+    /// When the desktop became interactive.
+    pub desktop: SystemTime,
+
+    /// When the runner started.
+    pub runner: SystemTime,
+}
+
+/// The reasons that the desktop is not ready for Firefox to be recorded.
+///
+/// If the workstation is locked, a UAC prompt is showing, a full-screen window
+/// is in the foreground, or the screensaver is running, the recording captures
+/// that instead of Firefox.
+#[derive(Debug, Error)]
+pub enum DesktopError {
+    #[error("The screensaver is running; dismiss it and disable the screensaver on the runner")]
+    ScreenSaverRunning,
+
+    #[error("The desktop is locked; sign in on the runner and disable the lock screen")]
+    Locked,
+
+    #[error("A UAC prompt is showing; dismiss it on the runner")]
+    UacPrompt,
+
+    #[error("A full-screen window (`{}') is in the foreground; close it on the runner", .0)]
+    FullScreenWindow(String),
+
+    #[error(
+        "The screensaver is enabled and may start during the recording; disable it on the runner"
+    )]
+    ScreenSaverEnabled,
+
+    #[error("Could not check the state of the desktop: {}", .0)]
+    Os(#[from] io::Error),
+}
+
+/// The delay before a restart started with
+/// [`initiate_restart()`](trait.ShutdownProvider.html#method.initiate_restart).
+///
+/// Three seconds gives us plenty of time to shutdown TCP connections and exit
+/// cleanly.
+pub const DEFAULT_RESTART_DELAY: Duration = Duration::from_secs(3);
 
 /// A trait providing the ability to restart the current machine.
 pub trait ShutdownProvider: Debug {
@@ -26,7 +124,32 @@ pub trait ShutdownProvider: Debug {
     type Error: Error + 'static;
 
     /// Initiate a restart with the given reason.
-    fn initiate_restart(&self, reason: &str) -> Result<(), Self::Error>;
+    fn initiate_restart(&self, reason: &str) -> Result<(), Self::Error> {
+        self.schedule_restart(reason, DEFAULT_RESTART_DELAY)
+    }
+
+    /// Schedule a restart with the given reason after `delay`.
+    ///
+    /// Until the delay has elapsed, the restart can be aborted with
+    /// [`cancel_restart()`](trait.ShutdownProvider.html#method.cancel_restart).
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error>;
+
+    /// Cancel a pending restart.
+    fn cancel_restart(&self) -> Result<(), Self::Error>;
+
+    /// Return whether or not Windows Fast Startup is enabled.
+    ///
+    /// With Fast Startup enabled, the kernel session is restored from
+    /// hibernation when booting, so a restart is not a true cold start. It is
+    /// never enabled on other platforms.
+    fn fast_startup_enabled(&self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    /// Enable or disable Windows Fast Startup.
+    fn set_fast_startup(&self, _enabled: bool) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 /// A trait providing the ability to retrieve disk and CPU performance
@@ -46,9 +169,26 @@ pub trait PerfProvider: Debug {
 
     /// Return the interval that the cpu was idle since startup (in arbitrary units).
     fn get_cpu_usage_time(&self) -> Result<CpuTimes, Self::CpuTimeError>;
+
+    /// Check that the desktop is ready for Firefox to be recorded.
+    ///
+    /// The desktop must be unlocked and uncovered, and the screensaver must
+    /// be disabled. Only the Windows provider checks anything.
+    fn check_desktop(&self) -> Result<(), DesktopError> {
+        Ok(())
+    }
+
+    /// Return when the machine booted, when its desktop became interactive,
+    /// and when the runner started.
+    ///
+    /// Only the Windows provider can determine these.
+    fn boot_times(&self) -> Result<BootTimes, io::Error> {
+        Err(io::Error::other("boot times are not available"))
+    }
 }
 
 /// A [`ShutdownProvider`](trait.ShutdownProvider.html) that uses the Windows API.
+#[cfg(windows)]
 #[derive(Debug, Default)]
 pub struct WindowsShutdownProvider {
     /// Whether or not to skip the actual restart.
@@ -56,7 +196,7 @@ pub struct WindowsShutdownProvider {
     skip_restart: bool,
 }
 
-#[cfg(debug_assertions)]
+#[cfg(all(windows, debug_assertions))]
 impl WindowsShutdownProvider {
     pub fn skipping_restart(skip_restart: bool) -> Self {
         let mut provider = WindowsShutdownProvider::default();
@@ -65,27 +205,189 @@ impl WindowsShutdownProvider {
     }
 }
 
+#[cfg(windows)]
 impl ShutdownProvider for WindowsShutdownProvider {
     type Error = shutdown::ShutdownError;
 
     #[cfg(debug_assertions)]
-    fn initiate_restart(&self, reason: &str) -> Result<(), Self::Error> {
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
         if self.skip_restart {
             Ok(())
         } else {
-            shutdown::initiate_restart(reason)
+            shutdown::initiate_restart(reason, delay)
         }
     }
 
     #[cfg(not(debug_assertions))]
-    fn initiate_restart(&self, reason: &str) -> Result<(), Self::Error> {
-        shutdown::initiate_restart(reason)
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
+        shutdown::initiate_restart(reason, delay)
+    }
+
+    #[cfg(debug_assertions)]
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        if self.skip_restart {
+            Ok(())
+        } else {
+            shutdown::abort_restart()
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        shutdown::abort_restart()
+    }
+
+    fn fast_startup_enabled(&self) -> Result<bool, Self::Error> {
+        shutdown::fast_startup_enabled()
+    }
+
+    fn set_fast_startup(&self, enabled: bool) -> Result<(), Self::Error> {
+        shutdown::set_fast_startup(enabled)
+    }
+}
+
+/// A [`ShutdownProvider`](trait.ShutdownProvider.html) that uses
+/// systemd-logind.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct LinuxShutdownProvider {
+    log: Logger,
+
+    /// Whether or not to skip the actual restart.
+    #[cfg(debug_assertions)]
+    skip_restart: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl LinuxShutdownProvider {
+    pub fn new(log: Logger) -> Self {
+        LinuxShutdownProvider {
+            log,
+            #[cfg(debug_assertions)]
+            skip_restart: false,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn skipping_restart(log: Logger, skip_restart: bool) -> Self {
+        let mut provider = LinuxShutdownProvider::new(log);
+        provider.skip_restart = skip_restart;
+        provider
     }
 }
 
+#[cfg(target_os = "linux")]
+impl ShutdownProvider for LinuxShutdownProvider {
+    type Error = logind::LogindError;
+
+    #[cfg(debug_assertions)]
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
+        if self.skip_restart {
+            Ok(())
+        } else {
+            logind::schedule_reboot(&self.log, reason, delay)
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
+        logind::schedule_reboot(&self.log, reason, delay)
+    }
+
+    #[cfg(debug_assertions)]
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        if self.skip_restart {
+            Ok(())
+        } else {
+            logind::cancel_reboot()
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        logind::cancel_reboot()
+    }
+}
+
+/// A [`ShutdownProvider`](trait.ShutdownProvider.html) that logs restarts
+/// instead of performing them.
+#[derive(Debug)]
+pub struct DryRunShutdownProvider {
+    log: Logger,
+}
+
+impl DryRunShutdownProvider {
+    pub fn new(log: Logger) -> Self {
+        DryRunShutdownProvider { log }
+    }
+}
+
+impl ShutdownProvider for DryRunShutdownProvider {
+    type Error = Infallible;
+
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
+        info!(self.log, "Dry run: not restarting"; "reason" => reason, "delay" => ?delay);
+        Ok(())
+    }
+
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        info!(self.log, "Dry run: cancelled restart");
+        Ok(())
+    }
+}
+
+/// A [`ShutdownProvider`](trait.ShutdownProvider.html) that either restarts
+/// the machine or only logs restarts, depending on the configuration.
+#[derive(Debug)]
+pub enum ConfiguredShutdownProvider<S> {
+    Real(S),
+    DryRun(DryRunShutdownProvider),
+}
+
+impl<S> ShutdownProvider for ConfiguredShutdownProvider<S>
+where
+    S: ShutdownProvider,
+{
+    type Error = S::Error;
+
+    fn schedule_restart(&self, reason: &str, delay: Duration) -> Result<(), Self::Error> {
+        match self {
+            ConfiguredShutdownProvider::Real(provider) => provider.schedule_restart(reason, delay),
+            ConfiguredShutdownProvider::DryRun(provider) => provider
+                .schedule_restart(reason, delay)
+                .map_err(|never| match never {}),
+        }
+    }
+
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        match self {
+            ConfiguredShutdownProvider::Real(provider) => provider.cancel_restart(),
+            ConfiguredShutdownProvider::DryRun(provider) => {
+                provider.cancel_restart().map_err(|never| match never {})
+            }
+        }
+    }
+
+    fn fast_startup_enabled(&self) -> Result<bool, Self::Error> {
+        match self {
+            ConfiguredShutdownProvider::Real(provider) => provider.fast_startup_enabled(),
+            ConfiguredShutdownProvider::DryRun(..) => Ok(false),
+        }
+    }
+
+    fn set_fast_startup(&self, enabled: bool) -> Result<(), Self::Error> {
+        match self {
+            ConfiguredShutdownProvider::Real(provider) => provider.set_fast_startup(enabled),
+            ConfiguredShutdownProvider::DryRun(..) => Ok(()),
+        }
+    }
+}
+
+#[cfg(windows)]
 #[derive(Debug, Default)]
 pub struct WindowsPerfProvider;
 
+#[cfg(windows)]
 impl PerfProvider for WindowsPerfProvider {
     type DiskIoError = perf::DiskIoError;
     type CpuTimeError = io::Error;
@@ -97,6 +399,33 @@ impl PerfProvider for WindowsPerfProvider {
     fn get_cpu_usage_time(&self) -> Result<CpuTimes, Self::CpuTimeError> {
         perf::get_cpu_usage_time()
     }
+
+    fn check_desktop(&self) -> Result<(), DesktopError> {
+        desktop::check_desktop()
+    }
+
+    fn boot_times(&self) -> Result<BootTimes, io::Error> {
+        boot::boot_times()
+    }
+}
+
+/// A [`PerfProvider`](trait.PerfProvider.html) that reads `/proc`.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct LinuxPerfProvider;
+
+#[cfg(target_os = "linux")]
+impl PerfProvider for LinuxPerfProvider {
+    type DiskIoError = io::Error;
+    type CpuTimeError = io::Error;
+
+    fn get_disk_io_counters(&self) -> Result<IoCounters, Self::DiskIoError> {
+        procfs::get_disk_io_counters()
+    }
+
+    fn get_cpu_usage_time(&self) -> Result<CpuTimes, Self::CpuTimeError> {
+        procfs::get_cpu_usage_time()
+    }
 }
 
 #[derive(Debug, Error)]
@@ -107,6 +436,9 @@ where
     #[error("timed out waiting for CPU and disk to become idle")]
     TimeoutError,
 
+    #[error("timed out waiting for disk to become idle")]
+    DiskTimeoutError,
+
     #[error(transparent)]
     DiskIoError(P::DiskIoError),
 
@@ -154,3 +486,29 @@ where
 
     Err(WaitForIdleError::TimeoutError)
 }
+
+/// Wait for the disk to become idle.
+pub async fn disk_idle<P>(p: &P) -> Result<(), WaitForIdleError<P>>
+where
+    P: PerfProvider,
+{
+    let mut counters = p
+        .get_disk_io_counters()
+        .map_err(WaitForIdleError::DiskIoError)?;
+
+    for _ in 0..P::ATTEMPT_COUNT {
+        delay_for(Duration::from_millis(500)).await;
+
+        let new_counters = p
+            .get_disk_io_counters()
+            .map_err(WaitForIdleError::DiskIoError)?;
+
+        if new_counters.reads == counters.reads && new_counters.writes == counters.writes {
+            return Ok(());
+        }
+
+        counters = new_counters;
+    }
+
+    Err(WaitForIdleError::DiskTimeoutError)
+}
@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! When the machine booted, when its desktop became interactive, and when the
+//! runner started after it.
+
+use std::io;
+use std::mem::zeroed;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use winapi::shared::minwindef::{DWORD, FILETIME};
+use winapi::um::winnt::{HANDLE, PROCESS_QUERY_LIMITED_INFORMATION};
+use winapi::um::{processthreadsapi, sysinfoapi, winuser};
+
+use crate::osapi::error::check_nonzero;
+use crate::osapi::process::open_process;
+use crate::osapi::BootTimes;
+
+/// The number of 100ns intervals from the `FILETIME` epoch (1601-01-01) to
+/// the UNIX epoch.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+pub(super) fn boot_times() -> Result<BootTimes, io::Error> {
+    let uptime = Duration::from_millis(unsafe { sysinfoapi::GetTickCount64() });
+    let boot = SystemTime::now() - uptime;
+
+    // The shell is started once the user signs in, which is when the desktop
+    // becomes interactive.
+    let shell_window = unsafe { winuser::GetShellWindow() };
+    if shell_window.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "there is no shell window",
+        ));
+    }
+
+    let mut shell_pid: DWORD = 0;
+    check_nonzero(unsafe { winuser::GetWindowThreadProcessId(shell_window, &mut shell_pid) })?;
+    let shell = open_process(shell_pid, PROCESS_QUERY_LIMITED_INFORMATION)?;
+
+    Ok(BootTimes {
+        boot,
+        desktop: process_creation_time(shell.as_ptr())?,
+        runner: process_creation_time(unsafe { processthreadsapi::GetCurrentProcess() })?,
+    })
+}
+
+/// Return when the given process was created.
+fn process_creation_time(process: HANDLE) -> Result<SystemTime, io::Error> {
+    let mut creation_time: FILETIME = unsafe { zeroed() };
+    let mut exit_time: FILETIME = unsafe { zeroed() };
+    let mut kernel_time: FILETIME = unsafe { zeroed() };
+    let mut user_time: FILETIME = unsafe { zeroed() };
+
+    check_nonzero(unsafe {
+        processthreadsapi::GetProcessTimes(
+            process,
+            &mut creation_time,
+            &mut exit_time,
+            &mut kernel_time,
+            &mut user_time,
+        )
+    })?;
+
+    let intervals =
+        (u64::from(creation_time.dwHighDateTime) << 32) | u64::from(creation_time.dwLowDateTime);
+
+    Ok(UNIX_EPOCH + Duration::from_nanos(intervals.saturating_sub(FILETIME_UNIX_EPOCH) * 100))
+}
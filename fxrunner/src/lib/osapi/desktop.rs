@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that the interactive desktop is ready for Firefox to be recorded.
+//!
+//! If the workstation is locked, a UAC prompt is showing, a full-screen window
+//! is in the foreground, or the screensaver is running, the recording captures
+//! that instead of Firefox.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::io;
+use std::mem::{size_of, zeroed};
+use std::os::windows::ffi::OsStringExt;
+
+use winapi::shared::minwindef::{BOOL, FALSE};
+use winapi::shared::windef::{HWND, RECT};
+use winapi::um::tlhelp32::{self, PROCESSENTRY32W};
+use winapi::um::winuser::{self, MONITORINFO};
+
+use crate::osapi::error::check_nonzero;
+use crate::osapi::handle::Handle;
+use crate::osapi::DesktopError;
+
+/// The executable that shows UAC prompts.
+const UAC_PROMPT_EXE: &str = "consent.exe";
+
+/// Check that the desktop is unlocked, that nothing is covering it, and that
+/// the screensaver will not interrupt the recording.
+pub(super) fn check_desktop() -> Result<(), DesktopError> {
+    if system_parameter(winuser::SPI_GETSCREENSAVERRUNNING)? {
+        return Err(DesktopError::ScreenSaverRunning);
+    }
+
+    // The input desktop cannot be opened while the secure desktop, which
+    // shows both the lock screen and UAC prompts, is active.
+    let desktop = unsafe { winuser::OpenInputDesktop(0, FALSE, winuser::DESKTOP_SWITCHDESKTOP) };
+    if desktop.is_null() {
+        return if process_running(UAC_PROMPT_EXE)? {
+            Err(DesktopError::UacPrompt)
+        } else {
+            Err(DesktopError::Locked)
+        };
+    }
+    unsafe { winuser::CloseDesktop(desktop) };
+
+    if let Some(title) = full_screen_window()? {
+        return Err(DesktopError::FullScreenWindow(title));
+    }
+
+    if system_parameter(winuser::SPI_GETSCREENSAVEACTIVE)? {
+        return Err(DesktopError::ScreenSaverEnabled);
+    }
+
+    Ok(())
+}
+
+/// Query a boolean system parameter.
+fn system_parameter(action: u32) -> Result<bool, io::Error> {
+    let mut value: BOOL = FALSE;
+
+    check_nonzero(unsafe {
+        winuser::SystemParametersInfoW(action, 0, &mut value as *mut BOOL as *mut _, 0)
+    })?;
+
+    Ok(value != FALSE)
+}
+
+/// Return the title of the foreground window if it covers its entire monitor.
+///
+/// The desktop itself is never considered a full-screen window.
+fn full_screen_window() -> Result<Option<String>, io::Error> {
+    let window = unsafe { winuser::GetForegroundWindow() };
+    if window.is_null()
+        || window == unsafe { winuser::GetDesktopWindow() }
+        || window == unsafe { winuser::GetShellWindow() }
+    {
+        return Ok(None);
+    }
+
+    let mut window_rect: RECT = unsafe { zeroed() };
+    check_nonzero(unsafe { winuser::GetWindowRect(window, &mut window_rect) })?;
+
+    let monitor = unsafe { winuser::MonitorFromWindow(window, winuser::MONITOR_DEFAULTTONEAREST) };
+    let mut monitor_info: MONITORINFO = unsafe { zeroed() };
+    monitor_info.cbSize = size_of::<MONITORINFO>() as u32;
+    check_nonzero(unsafe { winuser::GetMonitorInfoW(monitor, &mut monitor_info) })?;
+
+    let monitor_rect = monitor_info.rcMonitor;
+    if window_rect.left <= monitor_rect.left
+        && window_rect.top <= monitor_rect.top
+        && window_rect.right >= monitor_rect.right
+        && window_rect.bottom >= monitor_rect.bottom
+    {
+        Ok(Some(window_title(window)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Return the title of the given window.
+fn window_title(window: HWND) -> String {
+    let mut buf = [0u16; 256];
+    let len = unsafe { winuser::GetWindowTextW(window, buf.as_mut_ptr(), buf.len() as i32) };
+
+    OsString::from_wide(&buf[..len.max(0) as usize])
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Return whether or not a process with the given executable name is running.
+fn process_running(exe_name: &str) -> Result<bool, io::Error> {
+    let snapshot = Handle::try_from(unsafe {
+        tlhelp32::CreateToolhelp32Snapshot(tlhelp32::TH32CS_SNAPPROCESS, 0)
+    })?;
+
+    let mut entry: PROCESSENTRY32W = unsafe { zeroed() };
+    entry.dwSize = size_of::<PROCESSENTRY32W>() as u32;
+
+    if unsafe { tlhelp32::Process32FirstW(snapshot.as_ptr(), &mut entry) } == FALSE {
+        return Ok(false);
+    }
+
+    loop {
+        let len = entry
+            .szExeFile
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or_else(|| entry.szExeFile.len());
+        let name = OsString::from_wide(&entry.szExeFile[..len]);
+
+        if name.to_string_lossy().eq_ignore_ascii_case(exe_name) {
+            return Ok(true);
+        }
+
+        if unsafe { tlhelp32::Process32NextW(snapshot.as_ptr(), &mut entry) } == FALSE {
+            return Ok(false);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Restarting Linux machines through systemd-logind's D-Bus API.
+//!
+//! See: https://www.freedesktop.org/wiki/Software/systemd/logind/
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dbus::arg::OwnedFd;
+use dbus::blocking::{Connection, Proxy};
+use slog::{warn, Logger};
+use thiserror::Error;
+
+/// The bus name of systemd-logind.
+const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+
+/// The object path of the logind manager.
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+
+/// The interface of the logind manager.
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// How long to wait for logind to respond to a method call.
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum LogindError {
+    #[error("Could not connect to the system bus: {}", .0)]
+    Connect(#[source] dbus::Error),
+
+    #[error("{} failed: {}", .method, .source)]
+    MethodCall {
+        method: &'static str,
+        source: dbus::Error,
+    },
+
+    #[error("There is no scheduled restart to cancel")]
+    NotScheduled,
+}
+
+pub(super) fn schedule_reboot(
+    log: &Logger,
+    reason: &str,
+    delay: Duration,
+) -> Result<(), LogindError> {
+    let conn = Connection::new_system().map_err(LogindError::Connect)?;
+    let manager = manager(&conn);
+
+    // Let anyone logged in know why the machine is restarting. This is only a
+    // courtesy, so it does not prevent the restart.
+    if let Err(e) = call(&manager, "SetWallMessage", (reason, true)) {
+        warn!(log, "Could not set wall message"; "error" => %e);
+    }
+
+    if delay == Duration::from_secs(0) {
+        // Do not prompt for authorization.
+        return call(&manager, "Reboot", (false,));
+    }
+
+    // Scheduled shutdowns are given as a CLOCK_REALTIME timestamp in
+    // microseconds.
+    let when = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time is before the UNIX epoch")
+        + delay;
+
+    call(
+        &manager,
+        "ScheduleShutdown",
+        ("reboot", when.as_micros() as u64),
+    )
+}
+
+pub(super) fn cancel_reboot() -> Result<(), LogindError> {
+    let conn = Connection::new_system().map_err(LogindError::Connect)?;
+
+    let (cancelled,): (bool,) = manager(&conn)
+        .method_call(LOGIND_MANAGER, "CancelScheduledShutdown", ())
+        .map_err(|source| LogindError::MethodCall {
+            method: "CancelScheduledShutdown",
+            source,
+        })?;
+
+    if cancelled {
+        Ok(())
+    } else {
+        Err(LogindError::NotScheduled)
+    }
+}
+
+/// Prevent the machine from idling or sleeping until the returned file
+/// descriptor is closed.
+pub(super) fn inhibit_idle() -> Result<OwnedFd, LogindError> {
+    let conn = Connection::new_system().map_err(LogindError::Connect)?;
+
+    let (fd,): (OwnedFd,) = manager(&conn)
+        .method_call(
+            LOGIND_MANAGER,
+            "Inhibit",
+            ("idle:sleep", "fxrunner", "Running a session", "block"),
+        )
+        .map_err(|source| LogindError::MethodCall {
+            method: "Inhibit",
+            source,
+        })?;
+
+    Ok(fd)
+}
+
+fn manager(conn: &Connection) -> Proxy<'_, &Connection> {
+    conn.with_proxy(LOGIND_DESTINATION, LOGIND_PATH, DBUS_TIMEOUT)
+}
+
+/// Call a method on the logind manager that does not return anything.
+fn call<A: dbus::arg::AppendAll>(
+    manager: &Proxy<'_, &Connection>,
+    method: &'static str,
+    args: A,
+) -> Result<(), LogindError> {
+    manager
+        .method_call(LOGIND_MANAGER, method, args)
+        .map_err(|source| LogindError::MethodCall { method, source })
+}
@@ -15,12 +15,7 @@ use winapi::um::{fileapi, ioapiset, processthreadsapi, winioctl, winnt};
 
 use crate::osapi::error::check_nonzero;
 use crate::osapi::handle::Handle;
-
-#[derive(Clone, Copy, Debug, Default)]
-pub struct IoCounters {
-    pub reads: u32,
-    pub writes: u32,
-}
+use crate::osapi::{CpuTimes, IoCounters};
 
 #[derive(Debug, Error)]
 enum DiskIoErrorKind {
@@ -84,15 +79,6 @@ pub(super) fn get_disk_io_counters() -> Result<IoCounters, DiskIoError> {
     })
 }
 
-/// Information about the idle time of a CPU in an interval.
-#[derive(Debug, Default, Clone, Copy)]
-pub struct CpuTimes {
-    /// The amount of time the CPU was idle in the interval (in arbitrary units).
-    pub idle: u64,
-    /// The total amount of time in the interval (in arbitrary units).
-    pub total: u64,
-}
-
 pub(super) fn get_cpu_usage_time() -> Result<CpuTimes, io::Error> {
     let mut idle_time = FILETIME {
         dwLowDateTime: 0,
@@ -0,0 +1,108 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Keeping the display on and the system awake while sessions run.
+
+#[cfg(windows)]
+use std::io;
+#[cfg(windows)]
+use std::sync::mpsc;
+#[cfg(windows)]
+use std::thread;
+
+#[cfg(target_os = "linux")]
+use dbus::arg::OwnedFd;
+#[cfg(windows)]
+use winapi::um::{winbase, winnt};
+
+#[cfg(target_os = "linux")]
+use crate::osapi::logind::{inhibit_idle, LogindError};
+
+/// Keeps the display on and prevents the system from sleeping until it is
+/// dropped.
+///
+/// The execution state set by `SetThreadExecutionState` belongs to the thread
+/// that set it, so a dedicated thread holds it for as long as this is alive
+/// instead of one of the runtime's worker threads.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct KeepAwake {
+    /// Dropping the sender releases the execution state.
+    release_tx: Option<mpsc::Sender<()>>,
+
+    /// The thread holding the execution state.
+    thread_join_handle: Option<thread::JoinHandle<()>>,
+}
+
+#[cfg(windows)]
+impl KeepAwake {
+    /// Keep the display on and the system awake.
+    pub fn new() -> Result<Self, io::Error> {
+        let (result_tx, result_rx) = mpsc::channel::<Result<(), io::Error>>();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+
+        let join_handle = thread::spawn(move || {
+            let previous = unsafe {
+                winbase::SetThreadExecutionState(
+                    winnt::ES_CONTINUOUS | winnt::ES_DISPLAY_REQUIRED | winnt::ES_SYSTEM_REQUIRED,
+                )
+            };
+
+            if previous == 0 {
+                result_tx.send(Err(io::Error::last_os_error())).unwrap();
+                return;
+            }
+            result_tx.send(Ok(())).unwrap();
+
+            // Block until released. This also returns once the sender is
+            // dropped.
+            let _ = release_rx.recv();
+
+            unsafe {
+                winbase::SetThreadExecutionState(winnt::ES_CONTINUOUS);
+            }
+        });
+
+        if let Err(e) = result_rx.recv().unwrap() {
+            join_handle.join().expect("execution state thread panicked");
+            return Err(e);
+        }
+
+        Ok(KeepAwake {
+            release_tx: Some(release_tx),
+            thread_join_handle: Some(join_handle),
+        })
+    }
+}
+
+#[cfg(windows)]
+impl Drop for KeepAwake {
+    fn drop(&mut self) {
+        drop(self.release_tx.take());
+
+        if let Some(join_handle) = self.thread_join_handle.take() {
+            join_handle.join().expect("execution state thread panicked");
+        }
+    }
+}
+
+/// Prevents the system from idling or sleeping until it is dropped.
+///
+/// This holds a systemd-logind inhibitor lock, which is released when its file
+/// descriptor is closed.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct KeepAwake {
+    _inhibitor: OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl KeepAwake {
+    /// Keep the system awake.
+    pub fn new() -> Result<Self, LogindError> {
+        Ok(KeepAwake {
+            _inhibitor: inhibit_idle()?,
+        })
+    }
+}
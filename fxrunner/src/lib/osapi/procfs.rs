@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Disk and CPU performance information on Linux, read from `/proc`.
+//!
+//! See: https://www.kernel.org/doc/html/latest/filesystems/proc.html
+
+use std::fs::read_to_string;
+use std::io;
+
+use crate::osapi::{CpuTimes, IoCounters};
+
+/// The path of the per-device IO statistics.
+const DISKSTATS_PATH: &str = "/proc/diskstats";
+
+/// The path of the kernel and system statistics.
+const STAT_PATH: &str = "/proc/stat";
+
+pub(super) fn get_disk_io_counters() -> Result<IoCounters, io::Error> {
+    parse_diskstats(&read_to_string(DISKSTATS_PATH)?)
+}
+
+pub(super) fn get_cpu_usage_time() -> Result<CpuTimes, io::Error> {
+    parse_stat(&read_to_string(STAT_PATH)?)
+}
+
+/// Sum the completed reads and writes of every device in `/proc/diskstats`.
+///
+/// Only the change in the counters is used, so counting partitions as well as
+/// the disks that contain them does not matter.
+fn parse_diskstats(diskstats: &str) -> Result<IoCounters, io::Error> {
+    let mut counters = IoCounters::default();
+
+    for line in diskstats.lines() {
+        // The fields are the major and minor device numbers, the device name,
+        // and then the statistics, starting with completed reads. Completed
+        // writes are the fifth statistic.
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 8 {
+            return Err(invalid_data(DISKSTATS_PATH, line));
+        }
+
+        let reads = fields[3]
+            .parse::<u64>()
+            .map_err(|_| invalid_data(DISKSTATS_PATH, line))?;
+        let writes = fields[7]
+            .parse::<u64>()
+            .map_err(|_| invalid_data(DISKSTATS_PATH, line))?;
+
+        // The counters are truncated to match those reported on Windows.
+        counters.reads = counters.reads.wrapping_add(reads as u32);
+        counters.writes = counters.writes.wrapping_add(writes as u32);
+    }
+
+    Ok(counters)
+}
+
+/// Read the idle and total time of all CPUs from `/proc/stat`.
+fn parse_stat(stat: &str) -> Result<CpuTimes, io::Error> {
+    let line = stat
+        .lines()
+        .find(|line| line.starts_with("cpu "))
+        .ok_or_else(|| invalid_data(STAT_PATH, "no cpu line"))?;
+
+    // The times are user, nice, system, idle, iowait, irq, softirq, and steal,
+    // followed by guest times that are already counted in user and nice.
+    let times = line
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|time| time.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid_data(STAT_PATH, line))?;
+
+    if times.len() < 5 {
+        return Err(invalid_data(STAT_PATH, line));
+    }
+
+    Ok(CpuTimes {
+        idle: times[3] + times[4],
+        total: times.iter().sum(),
+    })
+}
+
+fn invalid_data(path: &str, line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("could not parse `{}' from {}", line, path),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_diskstats() {
+        let counters = parse_diskstats(
+            "   8       0 sda 1000 20 30000 400 2000 50 60000 700 0 800 900 0 0 0 0\n\
+             \x20  8       1 sda1 100 2 3000 40 200 5 6000 70 0 80 90 0 0 0 0\n",
+        )
+        .unwrap();
+
+        assert_eq!(counters.reads, 1100);
+        assert_eq!(counters.writes, 2200);
+
+        assert_eq!(
+            parse_diskstats("8 0 sda 1000\n").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let times = parse_stat(
+            "cpu  100 10 50 800 40 5 5 0 20 0\n\
+             cpu0 50 5 25 400 20 3 2 0 10 0\n\
+             intr 12345\n",
+        )
+        .unwrap();
+
+        assert_eq!(times.idle, 840);
+        assert_eq!(times.total, 1010);
+
+        assert_eq!(
+            parse_stat("intr 12345\n").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
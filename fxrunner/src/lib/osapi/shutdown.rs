@@ -2,13 +2,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::convert::TryFrom;
 use std::ffi::CString;
 use std::io;
+use std::mem::size_of;
 use std::ptr::null_mut;
+use std::time::Duration;
 
 use thiserror::Error;
-use winapi::shared::minwindef::{BOOL, DWORD};
+use winapi::shared::minwindef::{BOOL, DWORD, LPCVOID, LPVOID};
 use winapi::shared::ntdef::{LPSTR, LUID};
+use winapi::shared::winerror;
 use winapi::um::winnt::TOKEN_PRIVILEGES;
 use winapi::um::{processthreadsapi, reason, securitybaseapi, winbase, winnt, winreg};
 
@@ -25,8 +29,20 @@ enum ShutdownErrorKind {
     AdjustTokenPrivileges,
     #[error("InitiateSystemShutdownExA failed")]
     InitiateSystemShutdown,
+    #[error("AbortSystemShutdownA failed")]
+    AbortSystemShutdown,
+    #[error("Could not read Fast Startup setting")]
+    ReadFastStartup,
+    #[error("Could not write Fast Startup setting")]
+    WriteFastStartup,
 }
 
+/// The registry key that contains the Fast Startup setting.
+const POWER_KEY: &str = r"SYSTEM\CurrentControlSet\Control\Session Manager\Power";
+
+/// The registry value that controls whether or not Fast Startup is enabled.
+const HIBERBOOT_ENABLED: &str = "HiberbootEnabled";
+
 #[derive(Debug, Error)]
 #[error("{}: {}", .kind, .source)]
 pub struct ShutdownError {
@@ -35,7 +51,53 @@ pub struct ShutdownError {
 }
 
 // See: https://docs.microsoft.com/en-us/windows/win32/shutdown/how-to-shut-down-the-system
-pub(super) fn initiate_restart(reason: &str) -> Result<(), ShutdownError> {
+pub(super) fn initiate_restart(reason: &str, delay: Duration) -> Result<(), ShutdownError> {
+    acquire_shutdown_privilege()?;
+
+    let reason = CString::new(reason).unwrap();
+    check_nonzero(unsafe {
+        winreg::InitiateSystemShutdownExA(
+            // Shutdown this machine.
+            null_mut(),
+            // This casts a `*const c_char` to a `*mut c_char` but the API does
+            // not modify the string.
+            reason.as_ptr() as LPSTR,
+            // The restart can be aborted until the timeout has elapsed.
+            DWORD::try_from(delay.as_secs()).unwrap_or(std::u32::MAX),
+            // Force apps to close.
+            true as BOOL,
+            // Reboot after shutdown.
+            true as BOOL,
+            reason::SHTDN_REASON_MINOR_OTHER | reason::SHTDN_REASON_FLAG_PLANNED,
+        )
+    })
+    .map_err(|source| ShutdownError {
+        kind: ShutdownErrorKind::InitiateSystemShutdown,
+        source,
+    })?;
+
+    Ok(())
+}
+
+// See: https://docs.microsoft.com/en-us/windows/win32/shutdown/how-to-stop-a-system-shutdown
+pub(super) fn abort_restart() -> Result<(), ShutdownError> {
+    acquire_shutdown_privilege()?;
+
+    check_nonzero(unsafe {
+        // Abort the shutdown of this machine.
+        winreg::AbortSystemShutdownA(null_mut())
+    })
+    .map_err(|source| ShutdownError {
+        kind: ShutdownErrorKind::AbortSystemShutdown,
+        source,
+    })?;
+
+    Ok(())
+}
+
+/// Enable the shutdown privilege for the current process, which is required
+/// to start or abort a shutdown.
+fn acquire_shutdown_privilege() -> Result<(), ShutdownError> {
     let mut token = Handle::null();
     let mut privs = unsafe { std::mem::zeroed::<TOKEN_PRIVILEGES>() };
 
@@ -83,28 +145,63 @@ pub(super) fn initiate_restart(reason: &str) -> Result<(), ShutdownError> {
         source,
     })?;
 
-    let reason = CString::new(reason).unwrap();
-    check_nonzero(unsafe {
-        winreg::InitiateSystemShutdownExA(
-            // Shutdown this machine.
+    Ok(())
+}
+
+pub(super) fn fast_startup_enabled() -> Result<bool, ShutdownError> {
+    let key = CString::new(POWER_KEY).unwrap();
+    let value = CString::new(HIBERBOOT_ENABLED).unwrap();
+
+    let mut enabled: DWORD = 0;
+    let mut size = size_of::<DWORD>() as DWORD;
+
+    // Registry functions return their error instead of setting the last error.
+    let status = unsafe {
+        winreg::RegGetValueA(
+            winreg::HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value.as_ptr(),
+            winreg::RRF_RT_REG_DWORD,
             null_mut(),
-            // This casts a `*const c_char` to a `*mut c_char` but the API does
-            // not modify the string.
-            reason.as_ptr() as LPSTR,
-            // A three second timeout gives us plenty of time to shutdown TCP
-            // connections and exit cleanly.
-            3,
-            // Force apps to close.
-            true as BOOL,
-            // Reboot after shutdown.
-            true as BOOL,
-            reason::SHTDN_REASON_MINOR_OTHER | reason::SHTDN_REASON_FLAG_PLANNED,
+            &mut enabled as *mut DWORD as LPVOID,
+            &mut size,
         )
-    })
-    .map_err(|source| ShutdownError {
-        kind: ShutdownErrorKind::InitiateSystemShutdown,
-        source,
-    })?;
+    } as DWORD;
+
+    match status {
+        winerror::ERROR_SUCCESS => Ok(enabled != 0),
+        // Fast Startup is disabled when the value is missing.
+        winerror::ERROR_FILE_NOT_FOUND => Ok(false),
+        _ => Err(ShutdownError {
+            kind: ShutdownErrorKind::ReadFastStartup,
+            source: io::Error::from_raw_os_error(status as i32),
+        }),
+    }
+}
+
+pub(super) fn set_fast_startup(enabled: bool) -> Result<(), ShutdownError> {
+    let key = CString::new(POWER_KEY).unwrap();
+    let value = CString::new(HIBERBOOT_ENABLED).unwrap();
+
+    let enabled = DWORD::from(enabled);
+
+    let status = unsafe {
+        winreg::RegSetKeyValueA(
+            winreg::HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value.as_ptr(),
+            winnt::REG_DWORD,
+            &enabled as *const DWORD as LPCVOID,
+            size_of::<DWORD>() as DWORD,
+        )
+    } as DWORD;
+
+    if status != winerror::ERROR_SUCCESS {
+        return Err(ShutdownError {
+            kind: ShutdownErrorKind::WriteFastStartup,
+            source: io::Error::from_raw_os_error(status as i32),
+        });
+    }
 
     Ok(())
 }
@@ -0,0 +1,407 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An embedded server that captures the telemetry pings Firefox submits.
+//!
+//! Firefox is pointed at the server instead of the telemetry pipeline, so
+//! pings are never sent to Mozilla. Each ping is written to its own file as
+//! it arrives, named `<sequence>-<document type>-<document ID>.json`.
+
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use flate2::read::GzDecoder;
+use futures::future::{abortable, AbortHandle};
+use libfxrecord::prefs::PrefValue;
+use slog::{info, warn, Logger};
+use tokio::fs::{create_dir_all, write};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+
+/// The largest request head that is accepted.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// The largest ping that is accepted.
+///
+/// This is well above the limit Firefox imposes on pings it submits.
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// The response sent for every ping.
+const OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+
+/// A running ping capture server.
+///
+/// The server stops accepting connections when dropped.
+pub struct PingCapture {
+    port: u16,
+    count: Arc<AtomicU64>,
+    abort_handle: AbortHandle,
+}
+
+impl PingCapture {
+    /// Start capturing pings into the given directory.
+    pub async fn start(log: Logger, directory: &Path) -> Result<PingCapture, io::Error> {
+        create_dir_all(directory).await?;
+
+        let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let count = Arc::new(AtomicU64::new(0));
+
+        let (accept_loop, abort_handle) = abortable({
+            let count = count.clone();
+            let directory = directory.to_owned();
+
+            async move {
+                loop {
+                    let (stream, _) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!(log, "Could not accept connection"; "error" => %e);
+                            continue;
+                        }
+                    };
+
+                    let log = log.clone();
+                    let count = count.clone();
+                    let directory = directory.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(&log, stream, &directory, &count).await {
+                            warn!(log, "Ping submission failed"; "error" => %e);
+                        }
+                    });
+                }
+            }
+        });
+
+        tokio::spawn(accept_loop);
+
+        Ok(PingCapture {
+            port,
+            count,
+            abort_handle,
+        })
+    }
+
+    /// Return the prefs required for Firefox to submit its pings to the
+    /// server.
+    pub fn prefs(&self) -> Vec<(String, PrefValue)> {
+        vec![
+            (
+                "toolkit.telemetry.server".into(),
+                format!("http://127.0.0.1:{}", self.port).into(),
+            ),
+            (
+                "datareporting.healthreport.uploadEnabled".into(),
+                true.into(),
+            ),
+            (
+                "datareporting.policy.dataSubmissionEnabled".into(),
+                true.into(),
+            ),
+            (
+                "datareporting.policy.dataSubmissionPolicyBypassNotification".into(),
+                true.into(),
+            ),
+            // Builds that are not official (e.g., try builds) do not submit
+            // pings otherwise.
+            (
+                "toolkit.telemetry.send.overrideOfficialCheck".into(),
+                true.into(),
+            ),
+            // Never send pings through the record/replay or network
+            // conditioning proxies.
+            (
+                "network.proxy.no_proxies_on".into(),
+                format!("127.0.0.1:{}", self.port).into(),
+            ),
+        ]
+    }
+
+    /// Stop capturing pings.
+    ///
+    /// Returns the number of pings received.
+    pub fn stop(self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for PingCapture {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+/// The parts of a request head that are needed to capture a ping.
+#[derive(Debug, Eq, PartialEq)]
+struct RequestHead {
+    method: String,
+    path: String,
+    content_length: Option<usize>,
+    gzip: bool,
+}
+
+/// Handle a connection from Firefox, which may submit several pings.
+async fn handle_connection(
+    log: &Logger,
+    mut stream: TcpStream,
+    directory: &Path,
+    count: &AtomicU64,
+) -> Result<(), io::Error> {
+    let mut buf = Vec::new();
+
+    loop {
+        let head_len = loop {
+            if let Some(idx) = find_head_end(&buf) {
+                break idx;
+            }
+
+            if buf.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("request head is too large"));
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                // The connection was closed between requests.
+                return Ok(());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+
+        let head = parse_head(&buf[..head_len])
+            .ok_or_else(|| invalid_data("could not parse request head"))?;
+        let body_len = match head.content_length {
+            Some(len) if len <= MAX_BODY_SIZE => len,
+            Some(..) => return Err(invalid_data("ping is too large")),
+            None => {
+                stream
+                    .write_all(b"HTTP/1.1 411 Length Required\r\nContent-Length: 0\r\n\r\n")
+                    .await?;
+                return Err(invalid_data("request has no Content-Length"));
+            }
+        };
+
+        buf.drain(..head_len);
+        while buf.len() < body_len {
+            let mut chunk = [0u8; 16 * 1024];
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body: Vec<u8> = buf.drain(..body_len).collect();
+
+        if head.method == "POST" {
+            let body = if head.gzip { gunzip(&body)? } else { body };
+
+            let sequence = count.fetch_add(1, Ordering::SeqCst) + 1;
+            let path = directory.join(ping_file_name(sequence, &head.path));
+            write(&path, &body).await?;
+
+            info!(log, "Captured ping"; "path" => &head.path, "size" => body.len());
+        }
+
+        stream.write_all(OK_RESPONSE).await?;
+    }
+}
+
+/// Return the length of the request head at the start of the buffer,
+/// including the blank line that ends it, if the buffer contains it all.
+fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|idx| idx + 4)
+}
+
+/// Parse the request head.
+fn parse_head(head: &[u8]) -> Option<RequestHead> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_owned();
+    let path = request_line.next()?.to_owned();
+
+    let mut content_length = None;
+    let mut gzip = false;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => continue,
+        };
+
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse().ok()?);
+        } else if name.eq_ignore_ascii_case("content-encoding") {
+            gzip = value.eq_ignore_ascii_case("gzip");
+        }
+    }
+
+    Some(RequestHead {
+        method,
+        path,
+        content_length,
+        gzip,
+    })
+}
+
+/// Return the name of the file to store the ping submitted to the given path.
+///
+/// Pings are submitted to
+/// `/submit/telemetry/<document ID>/<document type>/...`.
+fn ping_file_name(sequence: u64, path: &str) -> String {
+    let segments: Vec<&str> = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let (doc_id, doc_type) = match segments.as_slice() {
+        ["submit", _, doc_id, doc_type, ..] => (*doc_id, *doc_type),
+        _ => ("unknown", "unknown"),
+    };
+
+    let sanitize = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect()
+    };
+
+    format!(
+        "{:04}-{}-{}.json",
+        sequence,
+        sanitize(doc_type),
+        sanitize(doc_id)
+    )
+}
+
+/// Decompress a gzipped ping.
+fn gunzip(body: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(body).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write as _;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use libfxrecord::logging::build_terminal_logger;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        assert_eq!(
+            parse_head(
+                b"POST /submit/telemetry/abc/main/Firefox/84.0/nightly/20201101?v=4 HTTP/1.1\r\n\
+                  Host: 127.0.0.1\r\n\
+                  content-length: 12\r\n\
+                  Content-Encoding: gzip\r\n\r\n"
+            ),
+            Some(RequestHead {
+                method: "POST".into(),
+                path: "/submit/telemetry/abc/main/Firefox/84.0/nightly/20201101?v=4".into(),
+                content_length: Some(12),
+                gzip: true,
+            })
+        );
+
+        assert_eq!(
+            parse_head(b"GET / HTTP/1.1\r\n\r\n"),
+            Some(RequestHead {
+                method: "GET".into(),
+                path: "/".into(),
+                content_length: None,
+                gzip: false,
+            })
+        );
+
+        assert_eq!(
+            parse_head(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ping_file_name() {
+        assert_eq!(
+            ping_file_name(
+                1,
+                "/submit/telemetry/0d1e-2f/event/Firefox/84.0/nightly/20201101?v=4"
+            ),
+            "0001-event-0d1e-2f.json"
+        );
+        assert_eq!(
+            ping_file_name(12, "/submit/telemetry/../..\\main/"),
+            "0012-main-.json"
+        );
+        assert_eq!(ping_file_name(3, "/"), "0003-unknown-unknown.json");
+    }
+
+    #[tokio::test]
+    async fn test_capture() {
+        let tempdir = TempDir::new().unwrap();
+        let directory = tempdir.path().join("pings");
+
+        let capture = PingCapture::start(build_terminal_logger(), &directory)
+            .await
+            .unwrap();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(br#"{"type":"main"}"#).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, capture.port))
+            .await
+            .unwrap();
+
+        // Both pings are submitted over the same connection.
+        let mut request = format!(
+            "POST /submit/telemetry/a1/main/Firefox/84.0/nightly/1?v=4 HTTP/1.1\r\n\
+             Content-Encoding: gzip\r\n\
+             Content-Length: {}\r\n\r\n",
+            gzipped.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&gzipped);
+        request.extend_from_slice(
+            b"POST /submit/telemetry/b2/event/Firefox/84.0/nightly/1?v=4 HTTP/1.1\r\n\
+              Content-Length: 16\r\n\r\n\
+              {\"type\":\"event\"}",
+        );
+        stream.write_all(&request).await.unwrap();
+
+        let mut response = vec![0u8; OK_RESPONSE.len() * 2];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..OK_RESPONSE.len()], OK_RESPONSE);
+        assert_eq!(&response[OK_RESPONSE.len()..], OK_RESPONSE);
+
+        assert_eq!(capture.stop(), 2);
+        assert_eq!(
+            std::fs::read_to_string(directory.join("0001-main-a1.json")).unwrap(),
+            r#"{"type":"main"}"#
+        );
+        assert_eq!(
+            std::fs::read_to_string(directory.join("0002-event-b2.json")).unwrap(),
+            r#"{"type":"event"}"#
+        );
+    }
+}
@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A cache of the last profile received from the recorder.
+//!
+//! Recorders that request a profile delta only send the files that differ
+//! from the cached profile, which saves re-sending large conditioned profiles
+//! for every session.
+
+use std::fs::{create_dir_all, read_dir, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libfxrecord::net::ProfileHashes;
+use tempfile::NamedTempFile;
+use thiserror::Error;
+
+use crate::staging::{StagingDir, StagingError};
+use crate::store::{Store, StoreError};
+use crate::zip::{unzip, ExtractLimits, ZipError};
+
+/// The name of the profile cache in the session directory.
+pub const PROFILE_CACHE_NAME: &str = "profile-cache";
+
+/// The name of the manifest in the profile cache.
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// A cache of the last profile received from the recorder.
+///
+/// The cache is a manifest of the files in the profile, whose contents are
+/// kept in the runner's [`Store`](../store/struct.Store.html). The manifest
+/// is replaced atomically, so an interrupted update leaves the previous
+/// cache intact.
+#[derive(Clone, Debug)]
+pub struct ProfileCache {
+    path: PathBuf,
+    store: Store,
+}
+
+impl ProfileCache {
+    /// Create a handle to the profile cache at the given path, whose files are
+    /// kept in `store`.
+    ///
+    /// The directory is created when the cache is first updated.
+    pub fn new<P: Into<PathBuf>>(path: P, store: Store) -> Self {
+        ProfileCache {
+            path: path.into(),
+            store,
+        }
+    }
+
+    /// Return the hashes of the files in the cached profile.
+    ///
+    /// If there is no cached profile, the manifest is empty.
+    pub fn manifest(&self) -> Result<ProfileHashes, ProfileCacheError> {
+        let path = self.manifest_path();
+
+        let f = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ProfileHashes::new()),
+            Err(source) => return Err(ProfileCacheError::Io { path, source }),
+        };
+
+        serde_json::from_reader(f).map_err(|source| ProfileCacheError::Manifest { path, source })
+    }
+
+    /// Build a profile at `target` from the cached profile and a delta.
+    ///
+    /// Every file in `manifest` that is not `removed` is copied from the store
+    /// and then the changed files in the `delta` archive are extracted over
+    /// them, subject to the given limits.
+    ///
+    /// The profile is built in a staging directory within `session_dir` and
+    /// only moved to `target` once the delta has been applied.
+    pub fn apply_delta(
+        &self,
+        manifest: &ProfileHashes,
+        removed: &[String],
+        delta: &Path,
+        session_dir: &Path,
+        target: &Path,
+        limits: &ExtractLimits,
+    ) -> Result<(), ProfileCacheError> {
+        let staging = StagingDir::new(session_dir, target)?;
+
+        for (name, hash) in manifest {
+            if removed.contains(name) {
+                continue;
+            }
+
+            let dest = staging.path().join(name.split('/').collect::<PathBuf>());
+
+            if let Some(parent) = dest.parent() {
+                create_dir_all(parent).map_err(|source| ProfileCacheError::Io {
+                    path: parent.into(),
+                    source,
+                })?;
+            }
+
+            self.store.copy_to(hash, &dest)?;
+        }
+
+        unzip(delta, staging.path(), limits)?;
+        staging.commit()?;
+
+        Ok(())
+    }
+
+    /// Replace the cached profile with the profile at the given path.
+    pub fn update(&self, profile: &Path) -> Result<(), ProfileCacheError> {
+        let mut manifest = ProfileHashes::new();
+        let mut dirs = vec![profile.to_owned()];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = read_dir(&dir).map_err(|source| ProfileCacheError::Io {
+                path: dir.clone(),
+                source,
+            })?;
+
+            for entry in entries {
+                let path = entry
+                    .map_err(|source| ProfileCacheError::Io {
+                        path: dir.clone(),
+                        source,
+                    })?
+                    .path();
+
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                // Manifest paths always use forward slashes, like zip files.
+                let name = path
+                    .strip_prefix(profile)
+                    .expect("entry is not within the profile directory")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+
+                manifest.insert(name, self.store.insert(&path)?);
+            }
+        }
+
+        create_dir_all(&self.path).map_err(|source| ProfileCacheError::Io {
+            path: self.path.clone(),
+            source,
+        })?;
+
+        let manifest_path = self.manifest_path();
+        let mut temp =
+            NamedTempFile::new_in(&self.path).map_err(|source| ProfileCacheError::Io {
+                path: self.path.clone(),
+                source,
+            })?;
+
+        serde_json::to_writer(&mut temp, &manifest).map_err(|source| {
+            ProfileCacheError::Manifest {
+                path: manifest_path.clone(),
+                source,
+            }
+        })?;
+
+        temp.persist(&manifest_path)
+            .map(drop)
+            .map_err(|e| ProfileCacheError::Io {
+                path: manifest_path,
+                source: e.error,
+            })
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.path.join(MANIFEST_NAME)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProfileCacheError {
+    #[error("IO error in profile cache at `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error(
+        "Could not read or write profile cache manifest `{}': {}",
+        .path.display(),
+        .source
+    )]
+    Manifest {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    Staging(#[from] StagingError),
+
+    #[error(transparent)]
+    Zip(#[from] ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, read_to_string, write};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::store::STORE_NAME;
+    use crate::zip::zip_dir;
+
+    #[test]
+    fn test_profile_cache() {
+        let tempdir = TempDir::new().unwrap();
+        let store = Store::new(tempdir.path().join(STORE_NAME));
+        let cache = ProfileCache::new(tempdir.path().join(PROFILE_CACHE_NAME), store.clone());
+
+        assert!(cache.manifest().unwrap().is_empty());
+
+        let profile = tempdir.path().join("profile");
+        create_dir(&profile).unwrap();
+        create_dir(profile.join("nested")).unwrap();
+        write(profile.join("prefs.js"), "prefs").unwrap();
+        write(profile.join("stale.js"), "stale").unwrap();
+        write(profile.join("nested").join("data.sqlite"), "data").unwrap();
+
+        cache.update(&profile).unwrap();
+
+        let manifest = cache.manifest().unwrap();
+        assert_eq!(
+            manifest.keys().collect::<Vec<_>>(),
+            vec!["nested/data.sqlite", "prefs.js", "stale.js"]
+        );
+        assert_eq!(
+            manifest["prefs.js"],
+            "6bea0bdc5c3d60ced0dd7f71d1314cd3d51d740468802955515fc71cecd1cd15"
+        );
+        assert!(store.get(&manifest["prefs.js"]).is_some());
+
+        let delta_source = tempdir.path().join("delta");
+        let delta = tempdir.path().join("delta.zip");
+        create_dir(&delta_source).unwrap();
+        write(delta_source.join("prefs.js"), "new prefs").unwrap();
+        write(delta_source.join("user.js"), "user").unwrap();
+        zip_dir(&delta_source, &delta).unwrap();
+
+        let target = tempdir.path().join("target");
+        cache
+            .apply_delta(
+                &manifest,
+                &["stale.js".into()],
+                &delta,
+                tempdir.path(),
+                &target,
+                &ExtractLimits::default(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            read_to_string(target.join("prefs.js")).unwrap(),
+            "new prefs"
+        );
+        assert_eq!(read_to_string(target.join("user.js")).unwrap(), "user");
+        assert_eq!(
+            read_to_string(target.join("nested").join("data.sqlite")).unwrap(),
+            "data"
+        );
+        assert!(!target.join("stale.js").exists());
+
+        cache.update(&target).unwrap();
+        let updated = cache.manifest().unwrap();
+        assert_eq!(
+            updated.keys().collect::<Vec<_>>(),
+            vec!["nested/data.sqlite", "prefs.js", "user.js"]
+        );
+        assert_eq!(
+            updated["nested/data.sqlite"],
+            manifest["nested/data.sqlite"]
+        );
+        assert_ne!(updated["prefs.js"], manifest["prefs.js"]);
+    }
+}
@@ -2,100 +2,223 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
-
-use indoc::indoc;
-use libfxrecord::error::ErrorExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use futures::future::{select, Either};
+use libfxrecord::error::{ErrorExt, ForeignError, ForeignErrorKind};
+use libfxrecord::logging::build_tee_logger;
+use libfxrecord::net::compression::{negotiate, recv_payload, send_payload, Codec, TransferStats};
 use libfxrecord::net::*;
-use libfxrecord::prefs::write_prefs;
+use libfxrecord::prefs::{write_prefs, PrefValue};
+use libfxrecord::storage::Bucket;
+use libfxrecord::timeout::{with_timeout, TimeoutError, TimeoutPhase};
+use libfxrecord::ORANGE;
 use scopeguard::{guard, ScopeGuard};
-use slog::{error, info, Logger};
+use serde_json::{json, Value};
+use slog::{error, info, o, warn, Logger};
 use thiserror::Error;
-use tokio::fs::{create_dir, rename, File, OpenOptions};
+use tokio::fs::{create_dir, remove_file, File, OpenOptions};
 use tokio::net::TcpStream;
 use tokio::prelude::*;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
 use tokio::task::spawn_blocking;
-
-use crate::config::Size;
+use tokio::time::{delay_for, timeout};
+
+use crate::archive::{extract, extract_stream, ArchiveError, ArchiveFormat, PartialArchive};
+use crate::bandwidth::{read_bandwidth, write_bandwidth, CountingStream};
+use crate::build::read_build_metadata;
+use crate::clock::{ClockError, ClockOverride};
+use crate::config::Config;
+use crate::distribution::{install_distribution, DistributionError};
+use crate::extensions::{extension_prefs, install_extensions, ExtensionError};
 use crate::fs::PathExt;
+use crate::hosts::{HostOverrides, HostsError};
+use crate::langpack::{install_langpack, locale_prefs, LangpackError};
+use crate::launches::{reset_profile, snapshot_profile, LaunchError};
+use crate::marionette::{marionette_prefs, unused_port, Marionette, MarionetteError};
+use crate::metrics::{Phase, METRICS};
+use crate::osapi::power::KeepAwake;
+#[cfg(windows)]
 use crate::osapi::process::{child_processes, open_process, terminate_process};
-use crate::osapi::{cpu_and_disk_idle, PerfProvider, ShutdownProvider, WaitForIdleError};
+use crate::osapi::{
+    cpu_and_disk_idle, disk_idle, DesktopError, PerfProvider, ShutdownProvider, WaitForIdleError,
+};
+use crate::pings::PingCapture;
+use crate::profile_cache::ProfileCacheError;
+use crate::provider::{
+    BuildProvider, MozillaArchiveBuild, MozillaArchiveError, PathBuild, PathBuildError,
+    TaskclusterBuild, UploadBuild, UrlBuild, UrlBuildError,
+};
+use crate::proxy::{Proxy, ProxyError};
 use crate::session::{
-    cleanup_session, NewSessionError, ResumeSessionError, SessionInfo, SessionManager,
+    cleanup_session, NewSessionError, ResumeSessionError, SessionInfo, SessionManager, FIREFOX_BIN,
 };
 use crate::splash::Splash;
+use crate::staging::{StagingDir, StagingError};
 use crate::taskcluster::Taskcluster;
-use crate::zip::{unzip, ZipError};
+use crate::telemetry::{
+    graphics_info, startup_cache_size, startup_cache_stats, startup_metrics, TelemetryError,
+};
+use crate::templates::{copy_template, TemplateError};
+use crate::throttle::Throttle;
+use crate::zip::{unzip, zip_dir, ExtractLimits, ZipError};
+
+/// How often download progress is reported to the recorder.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The file left in a session directory when Windows Fast Startup was disabled
+/// for its restart.
+const FAST_STARTUP_MARKER: &str = "fast_startup_disabled";
+
+/// The snapshot of the profile that launches are reset from, which is kept in
+/// the session directory.
+const PROFILE_SNAPSHOT: &str = "profile_snapshot.zip";
+
+/// The reason given for restarting for a new session.
+const RESTART_REASON: &str = "fxrunner: restarting for cold Firefox start";
+
+/// The namespace of refs to builds in the store.
+pub const BUILD_REFS: &str = "builds";
+
+/// How many downloaded builds are kept in the store.
+const STORED_BUILDS: usize = 4;
+
+/// How long pings are still captured for after Firefox stops.
+///
+/// Firefox hands the pings it submits at shutdown to the ping sender, which
+/// submits them after Firefox has exited.
+const PING_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How long collecting a memory report may take.
+const MEMORY_REPORT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long the runner waits before restarting for a new session.
+///
+/// The recorder may cancel the session until then.
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+
+/// What became of a request from the recorder.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestOutcome {
+    /// The runner is restarting for a new session.
+    Restart,
+
+    /// The session finished or was cancelled.
+    Finished,
+
+    /// The recorder only asked for the runner's status, so any pending session
+    /// is left untouched.
+    Status,
+}
+
+/// The protocol as seen by the runner, over a stream of type `St`.
+type RunnerSideProto<St> =
+    Proto<RecorderMessage, RunnerMessage, RecorderMessageKind, RunnerMessageKind, St>;
 
 /// The runner side of the protocol.
-pub struct RunnerProto<S, T, P, R, Sp> {
-    inner: Option<Proto<RecorderMessage, RunnerMessage, RecorderMessageKind, RunnerMessageKind>>,
+///
+/// The protocol runs over a `TcpStream` unless otherwise specified.
+pub struct RunnerProto<S, T, P, R, Sp, St = TcpStream>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    inner: Option<RunnerSideProto<CountingStream<St>>>,
     log: Logger,
-    display_size: Size,
+    config: Config,
     shutdown_handler: S,
     tc: T,
     perf_provider: P,
     session_manager: R,
 
+    /// The codec for payloads, as negotiated with the recorder.
+    codec: Codec,
+
+    /// The number of bytes of builds downloaded during this connection.
+    downloaded: u64,
+
     _marker: PhantomData<Sp>,
 }
 
-impl<S, T, P, R, Sp> RunnerProto<S, T, P, R, Sp>
+impl<S, T, P, R, Sp, St> RunnerProto<S, T, P, R, Sp, St>
 where
     S: ShutdownProvider,
-    T: Taskcluster,
+    T: Taskcluster + Send,
     P: PerfProvider + 'static,
     R: SessionManager,
     Sp: Splash,
+    St: AsyncRead + AsyncWrite + Unpin + Send,
 {
     /// Handle a request from the recorder.
     pub async fn handle_request(
         log: Logger,
-        display_size: Size,
-        stream: TcpStream,
+        config: Config,
+        stream: St,
         shutdown_handler: S,
         tc: T,
         perf_provider: P,
         session_manager: R,
-    ) -> Result<bool, RunnerProtoError<S, T, P>> {
+    ) -> Result<RequestOutcome, RunnerProtoError<S, T, P>> {
         let mut proto = Self {
-            inner: Some(Proto::new(stream)),
-            display_size,
+            inner: Some(Proto::new(CountingStream::new(stream))),
+            config,
             log,
             shutdown_handler,
             tc,
             perf_provider,
             session_manager,
+            codec: Codec::default(),
+            downloaded: 0,
             _marker: PhantomData,
         };
 
-        match proto.recv::<Session>().await? {
-            Session::NewSession(req) => {
-                proto.handle_new_session(req).await?;
-                Ok(true)
-            }
+        let request = with_timeout(
+            TimeoutPhase::Handshake,
+            proto.config.timeouts.get(TimeoutPhase::Handshake),
+            proto.recv::<Session>(),
+        )
+        .await??;
 
-            Session::ResumeSession(req) => {
-                proto.handle_resume_session(req).await?;
-                Ok(false)
+        match request {
+            Session::NewSession(req) => proto.handle_new_session(req).await,
+
+            Session::ResumeSession(req) => proto.handle_resume_session(req).await,
+
+            Session::Ping => {
+                info!(proto.log, "Received ping");
+                let status = runner_status(&proto.log, &proto.session_manager, false).await;
+                proto.send(Status { status }).await?;
+                Ok(RequestOutcome::Status)
             }
         }
     }
 
     /// Handle a request for a new session from the recorder.
+    ///
+    /// The runner will be restarting unless the recorder cancelled the
+    /// session.
     async fn handle_new_session(
         &mut self,
         request: NewSessionRequest,
-    ) -> Result<(), RunnerProtoError<S, T, P>> {
+    ) -> Result<RequestOutcome, RunnerProtoError<S, T, P>> {
+        self.codec = negotiate(&request.codecs);
+
         let session_info = match self.session_manager.new_session().await {
             Ok(session_info) => session_info,
             Err(e) => {
                 self.send(NewSessionResponse {
-                    session_id: Err(e.into_error_message()),
+                    session_id: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Session)),
+                    codec: self.codec,
+                    max_profile_size: self.config.max_profile_size,
                 })
                 .await?;
                 return Err(e.into());
@@ -103,21 +226,57 @@ where
         };
 
         let cleanup = guard(self.log.clone(), |log| cleanup_session(log, &session_info));
+        self.log_session(&session_info);
 
         self.send(NewSessionResponse {
             session_id: Ok(session_info.id.clone().into_owned()),
+            codec: self.codec,
+            max_profile_size: self.config.max_profile_size,
         })
         .await?;
 
-        let firefox_bin = self
-            .download_build(&session_info, &request.build_task_id)
-            .await?;
+        // Builds from archive.mozilla.org are downloaded in the requested
+        // locale, so they do not need a language pack.
+        let localized_build = matches!(request.build, BuildSource::MozillaArchive(..));
+
+        let firefox_bin = match with_timeout(
+            TimeoutPhase::Download,
+            self.config.timeouts.get(TimeoutPhase::Download),
+            self.download_build(
+                &session_info,
+                request.build,
+                request.request_id.as_deref(),
+                request.locale.as_deref(),
+            ),
+        )
+        .await
+        {
+            Ok(result) => result?,
+            Err(e) => {
+                error!(self.log, "Could not download build in time"; "error" => %e);
+
+                // The connection is lost if the build was being uploaded.
+                if self.inner.is_some() {
+                    self.send(DownloadBuild {
+                        result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Timeout)),
+                    })
+                    .await?;
+                }
+
+                return Err(e.into());
+            }
+        };
         assert!(firefox_bin.is_file_async().await);
 
+        if let Some(distribution_size) = request.distribution_size {
+            self.install_distribution(&session_info, distribution_size)
+                .await?;
+        }
+
         if let Err(e) = self.disable_updates(&session_info).await {
             error!(self.log, "Could not disable updates for downloaded Firefox"; "error" => %e);
             self.send(DisableUpdates {
-                result: Err(e.into_error_message()),
+                result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
             })
             .await?;
 
@@ -125,54 +284,51 @@ where
         }
         self.send(DisableUpdates { result: Ok(()) }).await?;
 
-        let profile_path = match request.profile_size {
-            Some(profile_size) => self.recv_profile(&session_info, profile_size).await?,
-            None => {
-                info!(self.log, "Creating new empty profile");
+        METRICS.set_phase(Phase::PreparingProfile);
+        let profile_path = match with_timeout(
+            TimeoutPhase::ProfileTransfer,
+            self.config.timeouts.get(TimeoutPhase::ProfileTransfer),
+            self.prepare_profile(
+                &session_info,
+                request.profile_size,
+                request.profile_delta,
+                request.profile_template.as_deref(),
+            ),
+        )
+        .await
+        {
+            Ok(result) => result?,
+            Err(e) => {
+                error!(self.log, "Could not prepare profile in time"; "error" => %e);
 
-                let profile_path = match self
-                    .session_manager
-                    .ensure_valid_profile_dir(&session_info)
-                    .await
-                {
-                    Ok(profile_path) => profile_path,
-                    Err(e) => {
-                        self.send(CreateProfile {
-                            result: Err(e.into_error_message()),
-                        })
-                        .await?;
-                        return Err(RunnerProtoError::EnsureProfile(e));
+                // The connection is lost if the profile was being received.
+                if self.inner.is_some() {
+                    let err = e.to_foreign_error().with_kind(ForeignErrorKind::Timeout);
+
+                    if request.profile_size.is_some() {
+                        self.send(RecvProfile { result: Err(err) }).await?;
+                    } else {
+                        self.send(CreateProfile { result: Err(err) }).await?;
                     }
-                };
-                self.send(CreateProfile { result: Ok(()) }).await?;
+                }
 
-                profile_path
+                return Err(e.into());
             }
         };
         assert!(profile_path.is_dir_async().await);
 
-        if !request.prefs.is_empty() {
-            let prefs_path = profile_path.join("user.js");
-            let mut f = match OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&prefs_path)
-                .await
-            {
-                Ok(f) => f,
-                Err(e) => {
-                    self.send(WritePrefs {
-                        result: Err(e.into_error_message()),
-                    })
-                    .await?;
-
-                    return Err(e.into());
-                }
-            };
+        let mut prefs = request.prefs;
+        if let Some(ref locale) = request.locale {
+            prefs.extend(locale_prefs(locale));
+        }
+        if request.extensions_size.is_some() {
+            prefs.extend(extension_prefs());
+        }
 
-            if let Err(e) = write_prefs(&mut f, request.prefs.into_iter()).await {
+        if !prefs.is_empty() {
+            if let Err(e) = append_prefs(&profile_path, prefs.into_iter()).await {
                 self.send(WritePrefs {
-                    result: Err(e.into_error_message()),
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
                 })
                 .await?;
                 return Err(e.into());
@@ -181,318 +337,2160 @@ where
 
         self.send(WritePrefs { result: Ok(()) }).await?;
 
-        if let Err(e) = self
-            .shutdown_handler
-            .initiate_restart("fxrunner: restarting for cold Firefox start")
-        {
-            error!(self.log, "Could not restart"; "error" => %e);
-            self.send(Restarting {
-                result: Err(e.into_error_message()),
-            })
-            .await?;
+        if let Some(ref locale) = request.locale {
+            self.install_locale(&session_info, &profile_path, locale, localized_build)
+                .await?;
+        }
 
-            return Err(RunnerProtoError::Shutdown(e));
+        if let Some(extensions_size) = request.extensions_size {
+            self.install_extensions(&session_info, &profile_path, extensions_size)
+                .await?;
         }
 
-        self.send(Restarting { result: Ok(()) }).await?;
+        self.restart(&session_info).await?;
 
-        drop(ScopeGuard::into_inner(cleanup));
+        // The recorder has until the restart to cancel the session. If it
+        // disconnects instead, the restart goes ahead.
+        match timeout(RESTART_DELAY, self.recv::<CancelSession>()).await {
+            Ok(Ok(CancelSession)) => {
+                info!(self.log, "Session cancelled; cancelling restart");
 
-        Ok(())
-    }
+                if let Err(e) = self.shutdown_handler.cancel_restart() {
+                    error!(self.log, "Could not cancel restart"; "error" => %e);
+                    self.send(RestartCancelled {
+                        result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Shutdown)),
+                    })
+                    .await?;
 
-    /// Resume a session from the recorder.
-    async fn handle_resume_session(
-        &mut self,
-        request: ResumeSessionRequest,
-    ) -> Result<(), RunnerProtoError<S, T, P>> {
-        info!(self.log, "Received resumption request");
+                    return Err(RunnerProtoError::Shutdown(e));
+                }
 
-        let session_info = match self
-            .session_manager
-            .resume_session(&request.session_id)
-            .await
-        {
-            Ok(session_info) => session_info,
-            Err(e) => {
-                self.send(ResumeResponse {
-                    result: Err(e.into_error_message()),
-                })
-                .await?;
-                return Err(e.into());
+                self.record_restart(&session_info, RestartResult::Cancelled)
+                    .await;
+                self.restore_fast_startup(&session_info).await;
+                self.send(RestartCancelled { result: Ok(()) }).await?;
+
+                return Ok(RequestOutcome::Finished);
             }
-        };
 
-        let _cleanup = guard(self.log.clone(), |log| cleanup_session(log, &session_info));
+            Ok(Err(ProtoError::EndOfStream)) | Err(..) => {}
 
-        self.send(ResumeResponse { result: Ok(()) }).await?;
+            Ok(Err(e)) => {
+                warn!(self.log, "Error while waiting to restart"; "error" => %e);
+            }
+        }
 
-        if request.idle == Idle::Wait {
-            info!(self.log, "Waiting to become idle");
+        drop(ScopeGuard::into_inner(cleanup));
 
-            if let Err(e) = cpu_and_disk_idle(&self.perf_provider).await {
-                error!(self.log, "CPU and disk did not become idle"; "error" => %e);
-                self.send(WaitForIdle {
-                    result: Err(e.into_error_message()),
+        Ok(RequestOutcome::Restart)
+    }
+
+    /// Schedule a restart that is a cold start, which the session will be
+    /// resumed after.
+    async fn restart(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        METRICS.set_phase(Phase::Restarting);
+        let fast_startup = match self.disable_fast_startup(session_info).await {
+            Ok(fast_startup) => fast_startup,
+            Err(e) => {
+                error!(self.log, "Could not disable Fast Startup"; "error" => %e);
+                self.send(Restarting {
+                    result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
                 })
                 .await?;
 
-                return Err(RunnerProtoError::WaitForIdle(e));
+                return Err(e);
             }
-            info!(self.log, "Became idle");
+        };
+        info!(self.log, "Ensured cold start"; "fast_startup" => %fast_startup);
 
-            self.send(WaitForIdle { result: Ok(()) }).await?;
-        }
+        if let Err(e) = self
+            .shutdown_handler
+            .schedule_restart(RESTART_REASON, RESTART_DELAY)
+        {
+            error!(self.log, "Could not restart"; "error" => %e);
+            self.record_restart(session_info, RestartResult::Failed(e.to_string()))
+                .await;
+            self.restore_fast_startup(session_info).await;
+            self.send(Restarting {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Shutdown)),
+            })
+            .await?;
 
-        self.recv::<StartFirefox>().await?;
+            return Err(RunnerProtoError::Shutdown(e));
+        }
 
-        let mut splash = Sp::new(self.display_size.x as u32, self.display_size.y as u32).await?;
-        let run_firefox_result = self
-            .run_firefox(&session_info.firefox_path(), &session_info.profile_path())
+        self.record_restart(session_info, RestartResult::Scheduled)
             .await;
 
-        if let Err(e) = splash.destroy() {
-            error!(self.log, "Could not destroy splash"; "error" => %e);
-
-            self.send(SessionFinished {
-                result: Err(e.into_error_message()),
-            })
-            .await?;
+        // The connection is lost when the runner restarts, so its usage is
+        // kept with the session.
+        let bandwidth = self.session_bandwidth(session_info).await;
+        if let Err(e) = write_bandwidth(&session_info.path, &bandwidth).await {
+            warn!(self.log, "Could not record bandwidth usage"; "error" => %e);
         }
 
-        if let Err(e) = run_firefox_result {
-            return Err(e);
-        }
+        self.send(Restarting {
+            result: Ok(RestartInfo {
+                delay: RESTART_DELAY,
+                fast_startup,
+            }),
+        })
+        .await?;
 
-        self.send(SessionFinished { result: Ok(()) }).await?;
         Ok(())
     }
 
-    /// Download a build from taskcluster.
-    async fn download_build<'a>(
-        &mut self,
-        session_info: &'a SessionInfo<'a>,
-        task_id: &str,
-    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
-        info!(self.log, "Download build from Taskcluster"; "task_id" => &task_id);
-        self.send(DownloadBuild {
-            result: Ok(DownloadStatus::Downloading),
-        })
-        .await?;
-
-        let download_path = match self
-            .tc
-            .download_build_artifact(task_id, &session_info.path)
-            .await
-        {
-            Ok(download_path) => download_path,
+    /// Return the bytes transferred for the session so far, including those
+    /// transferred before any restarts.
+    async fn session_bandwidth(&self, session_info: &SessionInfo<'_>) -> BandwidthUsage {
+        let before = match read_bandwidth(&session_info.path).await {
+            Ok(before) => before,
             Err(e) => {
-                error!(self.log, "Could not download build"; "error" => %e);
-                self.send(DownloadBuild {
-                    result: Err(e.into_error_message()),
-                })
-                .await?;
-                return Err(RunnerProtoError::Taskcluster(e));
+                warn!(self.log, "Could not read bandwidth usage"; "error" => %e);
+                BandwidthUsage::default()
             }
         };
 
-        self.send(DownloadBuild {
-            result: Ok(DownloadStatus::Downloaded),
-        })
-        .await?;
-        info!(self.log, "Extracting downloaded artifact...");
-
-        let unzip_result = spawn_blocking({
-            let download_dir = PathBuf::from(&session_info.path);
-            move || unzip(&download_path, &download_dir)
-        })
-        .await
-        .expect("unzip task was cancelled or panicked");
+        let (sent, received) = self
+            .inner
+            .as_ref()
+            .map(|inner| (inner.get_ref().sent(), inner.get_ref().received()))
+            .unwrap_or_default();
+
+        before
+            + BandwidthUsage {
+                downloaded: self.downloaded,
+                sent,
+                received,
+            }
+    }
 
-        if let Err(e) = unzip_result {
-            self.send(DownloadBuild {
-                result: Err(e.into_error_message()),
-            })
-            .await?;
-            return Err(e.into());
-        }
+    /// Determine how long the runner took to restart for the session.
+    async fn boot_timings(&self, session_info: &SessionInfo<'_>) -> Result<BootTimings, io::Error> {
+        let boot_times = self.perf_provider.boot_times()?;
 
-        let firefox_path = session_info.path.join("firefox").join("firefox.exe");
-        if !firefox_path.is_file_async().await {
-            let err = RunnerProtoError::MissingFirefox;
+        let restarts = match self.session_manager.restart_log().await {
+            Ok(restarts) => restarts,
+            Err(e) => {
+                warn!(self.log, "Could not read restart log"; "error" => %e);
+                Vec::new()
+            }
+        };
 
-            self.send(DownloadBuild {
-                result: Err(err.into_error_message()),
+        // Only a restart scheduled for this session before the machine booted
+        // can be the restart that the session was resumed after.
+        let boot = DateTime::<Utc>::from(boot_times.boot);
+        let restart = restarts
+            .iter()
+            .rev()
+            .find(|record| {
+                record.session_id == session_info.id
+                    && record.result == RestartResult::Scheduled
+                    && record.timestamp <= boot
             })
-            .await?;
-
-            return Err(err);
-        }
-
-        info!(self.log, "Extracted build");
-        self.send(DownloadBuild {
-            result: Ok(DownloadStatus::Extracted),
+            .and_then(|record| {
+                (DateTime::<Utc>::from(boot_times.runner) - record.timestamp)
+                    .to_std()
+                    .ok()
+            });
+
+        Ok(BootTimings {
+            restart,
+            boot_to_desktop: boot_times
+                .desktop
+                .duration_since(boot_times.boot)
+                .unwrap_or_default(),
         })
-        .await?;
-        Ok(firefox_path)
     }
 
-    async fn disable_updates(
+    /// Install the language pack for the given locale into the profile.
+    ///
+    /// Nothing needs to be installed if the build is already localized.
+    async fn install_locale(
         &mut self,
         session_info: &SessionInfo<'_>,
+        profile_path: &Path,
+        locale: &str,
+        localized_build: bool,
     ) -> Result<(), RunnerProtoError<S, T, P>> {
-        const DISABLE_UPDATE_POLICY: &[u8] = indoc!(
-            br#"
-            {
-                "policies": {
-                    "DisableAppUpdate": true
+        let result = if localized_build {
+            info!(self.log, "Build is already localized"; "locale" => locale);
+            Ok(())
+        } else {
+            info!(self.log, "Installing language pack"; "locale" => locale);
+
+            match read_build_metadata(&session_info.path.join("firefox")).await {
+                Ok(metadata) => {
+                    install_langpack(
+                        &self.config.mozilla_archive,
+                        profile_path,
+                        &metadata.version,
+                        locale,
+                    )
+                    .await
                 }
+                Err(e) => Err(e.into()),
             }
-            "#
-        );
-        let distribution_dir = session_info.path.join("firefox").join("distribution");
+        };
 
-        create_dir(&distribution_dir)
-            .await
-            .map_err(RunnerProtoError::DisableUpdates)?;
+        if let Err(e) = result {
+            error!(self.log, "Could not install language pack"; "error" => %e);
+            self.send(InstalledLocale {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+            })
+            .await?;
 
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(distribution_dir.join("policies.json"))
-            .await
-            .map_err(RunnerProtoError::DisableUpdates)?
-            .write_all(DISABLE_UPDATE_POLICY)
-            .await
-            .map_err(RunnerProtoError::DisableUpdates)?;
+            return Err(e.into());
+        }
 
+        self.send(InstalledLocale { result: Ok(()) }).await?;
         Ok(())
     }
 
-    /// Receive a profile from the recorder.
-    async fn recv_profile(
+    /// Receive the zip archive of extensions from the recorder and install
+    /// them into the profile.
+    async fn install_extensions(
         &mut self,
         session_info: &SessionInfo<'_>,
-        profile_size: u64,
-    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
-        info!(self.log, "Receiving profile...");
-        self.send(RecvProfile {
-            result: Ok(DownloadStatus::Downloading),
-        })
-        .await?;
+        profile_path: &Path,
+        extensions_size: u64,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        let archive = session_info.path.join("extensions.zip");
+        let mut f = File::create(&archive).await?;
 
-        let mut stream = self.inner.take().unwrap().into_inner();
-        let result = Self::recv_profile_raw(&mut stream, &session_info.path, profile_size).await;
-        self.inner = Some(Proto::new(stream));
+        info!(self.log, "Receiving extensions..."; "size" => extensions_size);
+        self.send(RecvExtensions).await?;
 
-        let zip_path = match result {
-            Ok(zip_path) => zip_path,
-            Err(e) => {
-                self.send(DownloadBuild {
-                    result: Err(e.into_error_message()),
+        let result = match self.recv_archive(&mut f, extensions_size).await {
+            Ok(stats) => {
+                self.log_transfer("Received extensions", &stats);
+
+                spawn_blocking({
+                    let profile_path = profile_path.to_owned();
+                    move || install_extensions(&archive, &profile_path)
                 })
-                .await?;
-                return Err(e);
+                .await
+                .expect("install extensions task was cancelled or panicked")
+                .map_err(RunnerProtoError::from)
             }
+            Err(e) => Err(e.into()),
         };
 
-        info!(self.log, "Profile received; extracting...");
-        self.send(RecvProfile {
-            result: Ok(DownloadStatus::Downloaded),
-        })
-        .await?;
+        match result {
+            Ok(ids) => {
+                info!(self.log, "Installed extensions"; "ids" => ids.join(", "));
+                self.send(InstalledExtensions { result: Ok(ids) }).await?;
+                Ok(())
+            }
+            Err(e) => {
+                error!(self.log, "Could not install extensions"; "error" => %e);
+                self.send(InstalledExtensions {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                })
+                .await?;
 
-        // It is possible that the profile contains a top-level directory, in
-        // which case we don't want to directly extract to
-        // `request_info.path.join("profile")`. Instead, we unzip it to a
-        // temporary directory and then move the top level directory (which may
-        // be the path we extracted it to) to the target profile directory.
-        let unzip_path = session_info.path.join("unzipped_profile");
+                Err(e)
+            }
+        }
+    }
 
-        let unzip_result = spawn_blocking({
-            let zip_path = zip_path.clone();
-            let unzip_path = unzip_path.clone();
-            move || unzip(&zip_path, &unzip_path)
-        })
-        .await
-        .expect("unzip profile task was cancelled or panicked");
+    /// Receive the zip archive of a distribution from the recorder and install
+    /// it into the build.
+    async fn install_distribution(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        distribution_size: u64,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        let archive = session_info.path.join("distribution.zip");
+        let mut f = File::create(&archive).await?;
 
-        let stats = match unzip_result {
-            Ok(stats) => stats,
-            Err(e) => {
-                error!(self.log, "Could not extract profile"; "error" => %e);
+        info!(self.log, "Receiving distribution..."; "size" => distribution_size);
+        self.send(RecvDistribution).await?;
 
-                self.send(RecvProfile {
-                    result: Err(e.into_error_message()),
-                })
-                .await?;
+        let result = match self.recv_archive(&mut f, distribution_size).await {
+            Ok(stats) => {
+                self.log_transfer("Received distribution", &stats);
 
-                return Err(e.into());
+                spawn_blocking({
+                    let session_dir = session_info.path.clone();
+                    let firefox_dir = session_info.path.join("firefox");
+                    let limits = self.config.extract_limits;
+                    move || install_distribution(&archive, &session_dir, &firefox_dir, &limits)
+                })
+                .await
+                .expect("install distribution task was cancelled or panicked")
+                .map_err(RunnerProtoError::from)
             }
+            Err(e) => Err(e.into()),
         };
 
-        if stats.extracted == 0 {
-            error!(self.log, "Profile was empty");
-            let e = RunnerProtoError::EmptyProfile;
+        if let Err(e) = result {
+            error!(self.log, "Could not install distribution"; "error" => %e);
+            self.send(InstalledDistribution {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Extraction)),
+            })
+            .await?;
+
+            return Err(e);
+        }
+
+        info!(self.log, "Installed distribution");
+        self.send(InstalledDistribution { result: Ok(()) }).await?;
+        Ok(())
+    }
+
+    /// Receive an archive of the given size from the recorder and write it to
+    /// the given file.
+    async fn recv_archive(&mut self, f: &mut File, size: u64) -> Result<TransferStats, io::Error> {
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = recv_payload(&mut stream, f, size, self.codec, |_| {}).await;
+        self.inner = Some(Proto::new(stream));
+
+        result
+    }
+
+    /// Receive the profile sent by the recorder, or create one if it did not
+    /// send one.
+    async fn prepare_profile(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        profile_size: Option<u64>,
+        profile_delta: bool,
+        profile_template: Option<&str>,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        match profile_size {
+            Some(..) if profile_delta => self.recv_profile_delta(session_info).await,
+            Some(profile_size) => self.recv_profile(session_info, profile_size).await,
+            None => {
+                let result = match profile_template {
+                    Some(template) => {
+                        info!(self.log, "Copying profile template"; "template" => template);
+                        self.copy_profile_template(session_info, template).await
+                    }
+                    None => {
+                        info!(self.log, "Creating new empty profile");
+                        self.session_manager
+                            .ensure_valid_profile_dir(session_info)
+                            .await
+                            .map_err(RunnerProtoError::EnsureProfile)
+                    }
+                };
+
+                let profile_path = match result {
+                    Ok(profile_path) => profile_path,
+                    Err(e) => {
+                        self.send(CreateProfile {
+                            result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
+                        })
+                        .await?;
+                        return Err(e);
+                    }
+                };
+                self.send(CreateProfile { result: Ok(()) }).await?;
+
+                Ok(profile_path)
+            }
+        }
+    }
+
+    /// Resume a session from the recorder.
+    ///
+    /// The runner will be restarting if the recorder asked to resume the
+    /// session again after a restart.
+    async fn handle_resume_session(
+        &mut self,
+        request: ResumeSessionRequest,
+    ) -> Result<RequestOutcome, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Received resumption request");
+        self.codec = negotiate(&request.codecs);
+
+        let session_info = match self
+            .session_manager
+            .resume_session(&request.session_id)
+            .await
+        {
+            Ok(session_info) => session_info,
+            Err(e) => {
+                self.send(ResumeResponse {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Session)),
+                    codec: self.codec,
+                })
+                .await?;
+                return Err(e.into());
+            }
+        };
+
+        let cleanup = guard(self.log.clone(), |log| cleanup_session(log, &session_info));
+        self.log_session(&session_info);
+
+        // Keep the display from blanking for the rest of the session. This is
+        // released when the session ends, however it ends.
+        let _keep_awake = match KeepAwake::new() {
+            Ok(keep_awake) => Some(keep_awake),
+            Err(e) => {
+                warn!(self.log, "Could not keep the display on"; "error" => %e);
+                None
+            }
+        };
+
+        // The restart has happened, so Fast Startup no longer needs to be
+        // disabled.
+        self.restore_fast_startup(&session_info).await;
+
+        self.send(ResumeResponse {
+            result: Ok(()),
+            codec: self.codec,
+        })
+        .await?;
+
+        let build_metadata = read_build_metadata(&session_info.path.join("firefox")).await;
+        match build_metadata {
+            Ok(ref metadata) => info!(self.log, "Read build metadata"; "metadata" => ?metadata),
+            Err(ref e) => warn!(self.log, "Could not read build metadata"; "error" => %e),
+        }
+        self.send(BuildInfo {
+            result: build_metadata
+                .map_err(|e| e.to_foreign_error().with_kind(ForeignErrorKind::Io)),
+        })
+        .await?;
+
+        let boot_timings = self.boot_timings(&session_info).await;
+        match boot_timings {
+            Ok(ref timings) => info!(
+                self.log,
+                "Measured restart";
+                "restart" => ?timings.restart,
+                "boot_to_desktop" => ?timings.boot_to_desktop,
+            ),
+            Err(ref e) => warn!(self.log, "Could not measure restart"; "error" => %e),
+        }
+        self.send(BootInfo {
+            result: boot_timings.map_err(|e| e.to_foreign_error().with_kind(ForeignErrorKind::Io)),
+        })
+        .await?;
+
+        let _clock_override =
+            if request.run_options.timezone.is_some() || request.run_options.sync_clock {
+                Some(
+                    self.normalize_clock(
+                        request.run_options.timezone.as_deref(),
+                        request.run_options.sync_clock,
+                    )
+                    .await?,
+                )
+            } else {
+                None
+            };
+
+        let _host_overrides = if request.run_options.host_overrides.is_empty() {
+            None
+        } else {
+            Some(
+                self.override_hosts(&request.run_options.host_overrides)
+                    .await?,
+            )
+        };
+
+        let proxy = match request.run_options.proxy {
+            Some(ref mode) => Some(self.start_proxy(&session_info, mode).await?),
+            None => None,
+        };
+
+        let _throttle = match request.run_options.network {
+            Some(ref conditions) => Some(
+                self.condition_network(&session_info, conditions, proxy.as_ref())
+                    .await?,
+            ),
+            None => None,
+        };
+
+        let ping_capture = if request.run_options.capture_pings {
+            Some(self.start_ping_capture(&session_info).await?)
+        } else {
+            None
+        };
+
+        if request.idle == Idle::Wait {
+            info!(self.log, "Waiting to become idle");
+            METRICS.set_phase(Phase::WaitingForIdle);
+
+            if let Err(e) = cpu_and_disk_idle(&self.perf_provider).await {
+                error!(self.log, "CPU and disk did not become idle"; "error" => %e);
+                self.send(WaitForIdle {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Idle)),
+                })
+                .await?;
+
+                return Err(RunnerProtoError::WaitForIdle(e));
+            }
+            info!(self.log, "Became idle");
+
+            self.send(WaitForIdle { result: Ok(()) }).await?;
+        }
+
+        self.recv::<StartFirefox>().await?;
+
+        // Check the desktop before the splash covers it.
+        if let Err(e) = self.perf_provider.check_desktop() {
+            error!(self.log, "Desktop is not ready for recording"; "error" => %e);
+            self.send(StartedFirefox {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Desktop)),
+            })
+            .await?;
+
+            return Err(RunnerProtoError::Desktop(e));
+        }
+
+        // The snapshot is taken before Firefox first runs so that every
+        // relaunch starts from the same profile. It is kept in the session
+        // directory so that launches after a restart start from it too.
+        let profile_snapshot = if request.run_options.reset_profile {
+            let snapshot_path = session_info.path.join(PROFILE_SNAPSHOT);
+
+            let result = if snapshot_path.is_file_async().await {
+                self.reset_profile(&session_info, &snapshot_path)
+                    .await
+                    .map(|_| true)
+            } else if request.run_options.relaunches > 0 || request.run_options.restart_after {
+                self.snapshot_profile(&session_info, &snapshot_path)
+                    .await
+                    .map(|_| true)
+            } else {
+                Ok(false)
+            };
+
+            match result {
+                Ok(true) => Some(snapshot_path),
+                Ok(false) => None,
+                Err(e) => {
+                    self.send(StartedFirefox {
+                        result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                    })
+                    .await?;
+
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut splash = Sp::new(
+            self.config.display_size.x as u32,
+            self.config.display_size.y as u32,
+        )
+        .await?;
+        let memory_report_path = session_info.path.join("memory-report.json.gz");
+        let relaunches = request.run_options.relaunches;
+        let mut run_firefox_result = Ok(None);
+
+        for launch in 0..=relaunches {
+            if launch > 0 {
+                self.recv::<StartFirefox>().await?;
+                info!(self.log, "Relaunching Firefox"; "launch" => launch + 1);
+
+                if let Some(ref snapshot_path) = profile_snapshot {
+                    if let Err(e) = self.reset_profile(&session_info, snapshot_path).await {
+                        self.send(StartedFirefox {
+                            result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                        })
+                        .await?;
+
+                        run_firefox_result = Err(e);
+                        break;
+                    }
+                }
+            }
+
+            // The startup cache is measured before Firefox starts so that cold
+            // and warm starts can be told apart.
+            let startup_cache_size_before = spawn_blocking({
+                let profile_path = session_info.profile_path();
+                move || startup_cache_size(&profile_path)
+            })
+            .await
+            .expect("startup cache task was cancelled or panicked");
+
+            // Telemetry archived before this launch belongs to an earlier run.
+            let launched = SystemTime::now();
+
+            let options = LaunchOptions {
+                session_type: &request.run_options.session_type,
+                env: &request.run_options.env,
+                safe_mode: request.run_options.safe_mode,
+                headless_measurement: request.run_options.headless_measurement,
+
+                // The memory report is only collected from the last launch.
+                memory_report_path: if request.run_options.memory_report && launch == relaunches {
+                    Some(&memory_report_path)
+                } else {
+                    None
+                },
+            };
+
+            run_firefox_result = self
+                .run_firefox(
+                    &session_info.firefox_path(),
+                    &session_info.profile_path(),
+                    &options,
+                )
+                .await;
+
+            if run_firefox_result.is_err() {
+                break;
+            }
+
+            self.send_startup_telemetry(&session_info, launched, startup_cache_size_before)
+                .await?;
+
+            if launch < relaunches && request.run_options.cools_down() {
+                self.cool_down(
+                    request.run_options.cool_down,
+                    request.run_options.cool_down_disk_idle,
+                )
+                .await?;
+            }
+        }
+
+        if let Ok(ref mut memory_report) = run_firefox_result {
+            if let Some(ping_capture) = ping_capture {
+                self.return_pings(
+                    &session_info,
+                    ping_capture,
+                    request.run_options.upload_artifacts,
+                )
+                .await?;
+            }
+
+            if let Some(result) = memory_report.take() {
+                self.return_memory_report(
+                    &session_info,
+                    &memory_report_path,
+                    result,
+                    request.run_options.upload_artifacts,
+                )
+                .await?;
+            }
+
+            if request.run_options.return_profile {
+                self.return_profile(&session_info, request.run_options.upload_artifacts)
+                    .await?;
+            }
+        }
+
+        if let Some(proxy) = proxy {
+            if let Err(e) = proxy.stop().await {
+                warn!(self.log, "Could not stop proxy"; "error" => %e);
+            }
+
+            if let Err(e) = write_policies(&session_info, default_policies()).await {
+                warn!(self.log, "Could not remove proxy certificate"; "error" => %e);
+            }
+        }
+
+        let destroy_result = splash.destroy();
+        if let Err(ref e) = destroy_result {
+            error!(self.log, "Could not destroy splash"; "error" => %e);
+        }
+
+        if run_firefox_result.is_ok() {
+            self.send_session_log(&session_info).await?;
+        }
+
+        let bandwidth = self.session_bandwidth(&session_info).await;
+        info!(
+            self.log,
+            "Transferred bytes for session";
+            "downloaded" => bandwidth.downloaded,
+            "sent" => bandwidth.sent,
+            "received" => bandwidth.received,
+        );
+
+        if let Err(e) = destroy_result {
+            self.send(SessionFinished {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Session)),
+                bandwidth: Some(bandwidth),
+            })
+            .await?;
+        }
+
+        run_firefox_result?;
+
+        self.send(SessionFinished {
+            result: Ok(()),
+            bandwidth: Some(bandwidth),
+        })
+        .await?;
+
+        if request.run_options.restart_after {
+            self.restart(&session_info).await?;
+
+            // The session is kept so that it can be resumed after the restart.
+            drop(ScopeGuard::into_inner(cleanup));
+            return Ok(RequestOutcome::Restart);
+        }
+
+        Ok(RequestOutcome::Finished)
+    }
+
+    /// Tag every subsequent log record with the session ID and also write it to
+    /// the session's log, which is sent to the recorder when the session ends.
+    fn log_session(&mut self, session_info: &SessionInfo<'_>) {
+        self.log = build_tee_logger(&self.log, &session_info.log_path())
+            .new(o! { "session_id" => session_info.id.to_string() });
+    }
+
+    /// Send the session's log to the recorder.
+    ///
+    /// Failing to read the log does not fail the session.
+    async fn send_session_log(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        let result = tokio::fs::read_to_string(session_info.log_path()).await;
+
+        if let Err(ref e) = result {
+            warn!(self.log, "Could not read session log"; "error" => %e);
+        }
+
+        self.send(SessionLog {
+            result: result.map_err(|e| e.to_foreign_error().with_kind(ForeignErrorKind::Io)),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Extract startup metrics from the telemetry the profile archived since
+    /// Firefox was launched, along with statistics about the startup cache,
+    /// and send them to the recorder.
+    ///
+    /// Failing to extract telemetry or startup cache statistics does not fail
+    /// the session.
+    async fn send_startup_telemetry(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        launched: SystemTime,
+        startup_cache_size_before: Result<u64, TelemetryError>,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        info!(self.log, "Extracting startup telemetry");
+
+        let (result, startup_cache, graphics) = spawn_blocking({
+            let profile_path = session_info.profile_path();
+            move || {
+                (
+                    startup_metrics(&profile_path, launched),
+                    startup_cache_size_before.and_then(|size_before| {
+                        startup_cache_stats(&profile_path, launched, size_before)
+                    }),
+                    graphics_info(&profile_path, launched),
+                )
+            }
+        })
+        .await
+        .expect("telemetry task was cancelled or panicked");
+
+        if let Err(ref e) = result {
+            warn!(self.log, "Could not extract startup telemetry"; "error" => %e);
+        }
+
+        let startup_cache = match startup_cache {
+            Ok(stats) => {
+                info!(self.log, "Collected startup cache statistics"; "stats" => ?stats);
+                Some(stats)
+            }
+            Err(e) => {
+                warn!(self.log, "Could not collect startup cache statistics"; "error" => %e);
+                None
+            }
+        };
+
+        let graphics = match graphics {
+            Ok(graphics) => {
+                info!(self.log, "Collected graphics information"; "graphics" => ?graphics);
+                Some(graphics)
+            }
+            Err(e) => {
+                warn!(self.log, "Could not collect graphics information"; "error" => %e);
+                None
+            }
+        };
+
+        self.send(StartupTelemetry {
+            result: result.map_err(|e| e.to_foreign_error().with_kind(ForeignErrorKind::Io)),
+            startup_cache,
+            graphics,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stop capturing pings, then zip the captured pings and send them to the
+    /// recorder.
+    ///
+    /// If `upload` is set, the pings are uploaded to the artifact store
+    /// instead when possible. Failing to zip the pings does not fail the
+    /// session.
+    async fn return_pings(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        ping_capture: PingCapture,
+        upload: bool,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        delay_for(PING_GRACE_PERIOD).await;
+        let count = ping_capture.stop();
+        info!(self.log, "Stopped capturing pings"; "count" => count);
+
+        let zip_path = session_info.path.join("pings.zip");
+        let result = spawn_blocking({
+            let pings_path = session_info.path.join("pings");
+            let zip_path = zip_path.clone();
+            move || zip_dir(&pings_path, &zip_path)
+        })
+        .await
+        .expect("zip pings task was cancelled or panicked");
+
+        let result = match result {
+            Ok(..) => open_with_size(&zip_path)
+                .await
+                .map_err(|source| ZipError::OpenArchive {
+                    archive: zip_path.clone(),
+                    source,
+                }),
+            Err(e) => Err(e),
+        };
+
+        let (mut f, size) = match result {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(self.log, "Could not zip pings"; "error" => %e);
+                self.send(TelemetryPings {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Telemetry)),
+                    uploaded: None,
+                })
+                .await?;
+                return Ok(());
+            }
+        };
+
+        let captured = CapturedPings { count, size };
+
+        if upload {
+            if let Some(uploaded) = self
+                .upload_artifact(session_info, "pings.zip", &zip_path)
+                .await
+            {
+                self.send(TelemetryPings {
+                    result: Ok(captured),
+                    uploaded: Some(uploaded),
+                })
+                .await?;
+                return Ok(());
+            }
+        }
+
+        info!(self.log, "Sending pings"; "size" => size);
+        self.send(TelemetryPings {
+            result: Ok(captured),
+            uploaded: None,
+        })
+        .await?;
+        self.recv::<RecvReturned>().await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = send_payload(&mut f, &mut stream, size, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.log_transfer("Sent pings", &result?);
+        Ok(())
+    }
+
+    /// Send the memory report Firefox wrote to the recorder.
+    ///
+    /// If `upload` is set, the report is uploaded to the artifact store
+    /// instead when possible. Failing to collect the report does not fail the
+    /// session.
+    async fn return_memory_report(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        report_path: &Path,
+        collect_result: Result<(), MarionetteError>,
+        upload: bool,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        let result = match collect_result {
+            Ok(()) => open_with_size(report_path)
+                .await
+                .map_err(MarionetteError::from),
+            Err(e) => Err(e),
+        };
+
+        let (mut f, size) = match result {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(self.log, "Could not collect memory report"; "error" => %e);
+                self.send(MemoryReport {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox)),
+                    uploaded: None,
+                })
+                .await?;
+                return Ok(());
+            }
+        };
+
+        if upload {
+            if let Some(uploaded) = self
+                .upload_artifact(session_info, "memory-report.json.gz", report_path)
+                .await
+            {
+                self.send(MemoryReport {
+                    result: Ok(size),
+                    uploaded: Some(uploaded),
+                })
+                .await?;
+                return Ok(());
+            }
+        }
+
+        info!(self.log, "Sending memory report"; "size" => size);
+        self.send(MemoryReport {
+            result: Ok(size),
+            uploaded: None,
+        })
+        .await?;
+        self.recv::<RecvReturned>().await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = send_payload(&mut f, &mut stream, size, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.log_transfer("Sent memory report", &result?);
+        Ok(())
+    }
+
+    /// Zip the profile and send it to the recorder.
+    ///
+    /// If `upload` is set, the profile is uploaded to the artifact store
+    /// instead when possible.
+    async fn return_profile(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        upload: bool,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        info!(self.log, "Zipping profile...");
+
+        let zip_path = session_info.path.join("returned_profile.zip");
+        let zip_result = spawn_blocking({
+            let profile_path = session_info.profile_path();
+            let zip_path = zip_path.clone();
+            move || zip_dir(&profile_path, &zip_path)
+        })
+        .await
+        .expect("zip profile task was cancelled or panicked");
+
+        if let Err(e) = zip_result {
+            error!(self.log, "Could not zip profile"; "error" => %e);
+            self.send(ReturnProfile {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                uploaded: None,
+            })
+            .await?;
+            return Err(e.into());
+        }
+
+        let mut f = match File::open(&zip_path).await {
+            Ok(f) => f,
+            Err(e) => {
+                self.send(ReturnProfile {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                    uploaded: None,
+                })
+                .await?;
+                return Err(e.into());
+            }
+        };
+
+        let profile_size = match f.metadata().await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                self.send(ReturnProfile {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                    uploaded: None,
+                })
+                .await?;
+                return Err(e.into());
+            }
+        };
+
+        if upload {
+            if let Some(uploaded) = self
+                .upload_artifact(session_info, "profile.zip", &zip_path)
+                .await
+            {
+                self.send(ReturnProfile {
+                    result: Ok(profile_size),
+                    uploaded: Some(uploaded),
+                })
+                .await?;
+                return Ok(());
+            }
+        }
+
+        info!(self.log, "Sending profile"; "profile_size" => profile_size);
+        self.send(ReturnProfile {
+            result: Ok(profile_size),
+            uploaded: None,
+        })
+        .await?;
+        self.recv::<RecvReturned>().await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result = send_payload(&mut f, &mut stream, profile_size, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        self.log_transfer("Sent profile", &result?);
+        Ok(())
+    }
+
+    /// Upload the named artifact of the session to the artifact store.
+    ///
+    /// Returns `None` if the runner has no artifact store or the upload
+    /// failed, in which case the artifact should be sent to the recorder.
+    async fn upload_artifact(
+        &self,
+        session_info: &SessionInfo<'_>,
+        name: &str,
+        path: &Path,
+    ) -> Option<UploadedArtifact> {
+        let config = match self.config.artifact_store {
+            Some(ref config) => config,
+            None => {
+                warn!(self.log, "Cannot upload artifact without an artifact store"; "artifact" => name);
+                return None;
+            }
+        };
+
+        info!(self.log, "Uploading artifact"; "artifact" => name, "bucket" => &config.bucket);
+
+        // Artifacts are stored under `<prefix><session_id>/<name>`.
+        let result = match Bucket::new(config) {
+            Ok(bucket) => {
+                let key = bucket.key(&[&session_info.id, name]);
+                bucket.put_file(&key, path).await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(object) => {
+                info!(self.log, "Uploaded artifact"; "artifact" => name, "url" => &object.url, "size" => object.size);
+                Some(UploadedArtifact {
+                    name: name.into(),
+                    key: object.key,
+                    url: object.url,
+                    size: object.size,
+                })
+            }
+            Err(e) => {
+                warn!(self.log, "Could not upload artifact; sending it to the recorder instead"; "artifact" => name, "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Temporarily set the timezone and synchronize the clock.
+    ///
+    /// The timezone is restored when the returned value is dropped.
+    async fn normalize_clock(
+        &mut self,
+        timezone: Option<&str>,
+        sync: bool,
+    ) -> Result<ClockOverride, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Normalizing clock"; "timezone" => ?timezone, "sync" => sync);
+
+        match ClockOverride::apply(self.log.clone(), timezone, sync).await {
+            Ok(clock_override) => {
+                self.send(NormalizedClock { result: Ok(()) }).await?;
+                Ok(clock_override)
+            }
+
+            Err(e) => {
+                error!(self.log, "Could not normalize clock"; "error" => %e);
+                self.send(NormalizedClock {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Clock)),
+                })
+                .await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Temporarily override the addresses of the given host names.
+    ///
+    /// The overrides are reverted when the returned value is dropped.
+    async fn override_hosts(
+        &mut self,
+        overrides: &[(String, IpAddr)],
+    ) -> Result<HostOverrides, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Overriding hosts"; "overrides" => ?overrides);
+
+        match HostOverrides::apply(self.log.clone(), &self.config.hosts_path, overrides).await {
+            Ok(host_overrides) => {
+                self.send(OverrodeHosts { result: Ok(()) }).await?;
+                Ok(host_overrides)
+            }
+
+            Err(e) => {
+                error!(self.log, "Could not override hosts"; "error" => %e);
+                self.send(OverrodeHosts {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Hosts)),
+                })
+                .await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Start the record/replay proxy and configure the profile to use it.
+    async fn start_proxy(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        mode: &ProxyMode,
+    ) -> Result<Proxy, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Starting proxy"; "mode" => ?mode);
+
+        let proxy = match self.config.proxy {
+            Some(ref config) => Proxy::start(config, mode).await,
+            None => Err(ProxyError::NotConfigured),
+        };
+
+        let proxy = match proxy {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                error!(self.log, "Could not start proxy"; "error" => %e);
+                self.send(StartedProxy {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Proxy)),
+                })
+                .await?;
+                return Err(e.into());
+            }
+        };
+
+        if let Err(e) = append_prefs(&session_info.profile_path(), proxy.prefs().into_iter()).await
+        {
+            error!(self.log, "Could not write proxy prefs"; "error" => %e);
+            self.send(StartedProxy {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Proxy)),
+            })
+            .await?;
+            return Err(e.into());
+        }
+
+        // The proxy intercepts TLS connections, so Firefox must trust its CA
+        // certificate. The policy is removed after Firefox exits.
+        let mut policies = default_policies();
+        policies["Certificates"] = json!({
+            "Install": [proxy.ca_cert_path().to_string_lossy()],
+        });
+
+        if let Err(e) = write_policies(session_info, policies).await {
+            error!(self.log, "Could not install proxy certificate"; "error" => %e);
+            self.send(StartedProxy {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Proxy)),
+            })
+            .await?;
+            return Err(RunnerProtoError::InstallCertificate(e));
+        }
+
+        info!(self.log, "Started proxy");
+        self.send(StartedProxy { result: Ok(()) }).await?;
+
+        Ok(proxy)
+    }
+
+    /// Start capturing the telemetry pings that Firefox submits and configure
+    /// the profile to submit them to the capture server.
+    async fn start_ping_capture(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<PingCapture, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Starting ping capture");
+
+        let result =
+            match PingCapture::start(self.log.clone(), &session_info.path.join("pings")).await {
+                Ok(ping_capture) => append_prefs(
+                    &session_info.profile_path(),
+                    ping_capture.prefs().into_iter(),
+                )
+                .await
+                .map(|()| ping_capture),
+                Err(e) => Err(e),
+            };
+
+        match result {
+            Ok(ping_capture) => {
+                self.send(StartedPingCapture { result: Ok(()) }).await?;
+                Ok(ping_capture)
+            }
+
+            Err(e) => {
+                error!(self.log, "Could not start ping capture"; "error" => %e);
+                self.send(StartedPingCapture {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Telemetry)),
+                })
+                .await?;
+                Err(RunnerProtoError::PingCapture(e))
+            }
+        }
+    }
+
+    /// Start shaping Firefox's network traffic to match the given conditions.
+    ///
+    /// If the record/replay proxy is running, traffic will be shaped before
+    /// being forwarded to it.
+    async fn condition_network(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        conditions: &NetworkConditions,
+        proxy: Option<&Proxy>,
+    ) -> Result<Throttle, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Conditioning network"; "conditions" => ?conditions);
+
+        let result = match Throttle::start(
+            self.log.clone(),
+            conditions,
+            proxy.map(|proxy| proxy.addr()),
+        )
+        .await
+        {
+            Ok(throttle) => {
+                append_prefs(&session_info.profile_path(), throttle.prefs().into_iter())
+                    .await
+                    .map(|()| throttle)
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(throttle) => {
+                self.send(ConditionedNetwork { result: Ok(()) }).await?;
+                Ok(throttle)
+            }
+
+            Err(e) => {
+                error!(self.log, "Could not condition network"; "error" => %e);
+                self.send(ConditionedNetwork {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Network)),
+                })
+                .await?;
+                Err(RunnerProtoError::ConditionNetwork(e))
+            }
+        }
+    }
+
+    /// Resolve the build task to a task ID, looking it up in the Taskcluster
+    /// index if necessary.
+    async fn resolve_task(
+        &mut self,
+        build_task: &BuildTask,
+    ) -> Result<String, RunnerProtoError<S, T, P>> {
+        let result = if let BuildTask::TaskId(task_id) = build_task {
+            Ok(task_id.clone())
+        } else {
+            let route = build_task
+                .index_route()
+                .expect("build task has no index route");
+
+            info!(self.log, "Resolving build task"; "route" => &route);
+            self.tc.find_task(&route).await
+        };
+
+        match result {
+            Ok(task_id) => {
+                info!(self.log, "Resolved build task"; "task_id" => &task_id);
+                self.send(ResolveTask {
+                    result: Ok(task_id.clone()),
+                })
+                .await?;
+                Ok(task_id)
+            }
+
+            Err(e) => {
+                error!(self.log, "Could not resolve build task"; "error" => %e);
+                self.send(ResolveTask {
+                    result: Err(e
+                        .to_foreign_error()
+                        .with_kind(ForeignErrorKind::Taskcluster)),
+                })
+                .await?;
+                Err(RunnerProtoError::Taskcluster(e))
+            }
+        }
+    }
+
+    /// Record a restart for the session in the restart log.
+    ///
+    /// Failures are logged but otherwise ignored, as they do not affect the
+    /// session.
+    async fn record_restart(&self, session_info: &SessionInfo<'_>, result: RestartResult) {
+        let record = RestartRecord::new(RESTART_REASON, &session_info.id, result);
+
+        if let Err(e) = self.session_manager.record_restart(&record).await {
+            warn!(self.log, "Could not record restart"; "error" => %e);
+        }
+    }
+
+    /// Disable Windows Fast Startup if it is enabled, so that the restart is a
+    /// cold start.
+    ///
+    /// If it was disabled, a marker is left in the session directory so that
+    /// it can be re-enabled when the session is resumed.
+    async fn disable_fast_startup(
+        &self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<FastStartup, RunnerProtoError<S, T, P>> {
+        let enabled = self
+            .shutdown_handler
+            .fast_startup_enabled()
+            .map_err(RunnerProtoError::Shutdown)?;
+
+        if !enabled {
+            return Ok(FastStartup::NotEnabled);
+        }
+
+        // The marker is written first so that Fast Startup cannot be left
+        // disabled without a record of it.
+        File::create(session_info.path.join(FAST_STARTUP_MARKER))
+            .await
+            .map_err(RunnerProtoError::FastStartup)?;
+
+        self.shutdown_handler
+            .set_fast_startup(false)
+            .map_err(RunnerProtoError::Shutdown)?;
+
+        Ok(FastStartup::Disabled)
+    }
+
+    /// Re-enable Windows Fast Startup if it was disabled for the session.
+    ///
+    /// Failures are logged but otherwise ignored, as they do not affect the
+    /// session.
+    async fn restore_fast_startup(&self, session_info: &SessionInfo<'_>) {
+        let marker_path = session_info.path.join(FAST_STARTUP_MARKER);
+        if !marker_path.is_file_async().await {
+            return;
+        }
+
+        if let Err(e) = self.shutdown_handler.set_fast_startup(true) {
+            error!(self.log, "Could not re-enable Fast Startup"; "error" => %e);
+            return;
+        }
+        info!(self.log, "Re-enabled Fast Startup");
+
+        if let Err(e) = remove_file(&marker_path).await {
+            warn!(self.log, "Could not remove Fast Startup marker"; "error" => %e);
+        }
+    }
+
+    /// Acquire a build from the given source.
+    ///
+    /// Builds from Taskcluster are first resolved to a task ID. If the request
+    /// has an ID, a build already acquired for an earlier attempt at the same
+    /// request is reused. Builds from archive.mozilla.org are acquired in the
+    /// given locale, if any.
+    async fn download_build<'a>(
+        &mut self,
+        session_info: &'a SessionInfo<'a>,
+        build: BuildSource,
+        request_id: Option<&str>,
+        locale: Option<&str>,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        let task_id = match build {
+            BuildSource::Taskcluster { ref task, .. } => Some(self.resolve_task(task).await?),
+            _ => None,
+        };
+
+        // Taskcluster artifacts are already kept in the store under their own
+        // name and builds on the runner do not need to be acquired.
+        let request_ref = match (&build, request_id) {
+            (BuildSource::Taskcluster { .. }, _) | (BuildSource::Path(..), _) | (_, None) => None,
+            (build, Some(request_id)) => Some(request_build_ref(request_id, build)),
+        };
+
+        let reused = match request_ref {
+            Some(ref name) => self.stored_build(name).await,
+            None => None,
+        };
+
+        if let Some(ref archive) = reused {
+            // The recorder is not told that the build is downloading, so that
+            // it does not upload the build again.
+            info!(
+                self.log,
+                "Reusing build acquired for an earlier attempt at this request";
+                "source" => ?build,
+                "path" => archive.display(),
+            );
+        } else {
+            info!(self.log, "Downloading build"; "source" => ?build);
+            METRICS.set_phase(Phase::Downloading);
+            self.send(DownloadBuild {
+                result: Ok(DownloadStatus::Downloading),
+            })
+            .await?;
+        }
+
+        // The name under which the downloaded archive is kept in the store, if
+        // it is kept. Taskcluster artifacts are kept, as they never change once
+        // published, as are builds acquired for a request with an ID.
+        let mut store_as = None;
+
+        // The digest that the recorder declared for an uploaded build, which
+        // the stored archive must match.
+        let mut expected_sha256 = None;
+
+        // Whether or not the build is downloaded over the network, rather than
+        // found on the runner or uploaded by the recorder.
+        let mut remote = false;
+
+        let limits = self.config.extract_limits;
+
+        // Builds are extracted into a staging directory, so that nothing is
+        // left in the session directory if the download fails, cannot be
+        // verified, or cannot be extracted.
+        let mut staging = self.stage_build(session_info).await?;
+        let staging_dir = staging.path().to_owned();
+
+        let download_result = match build {
+            _ if reused.is_some() => fetch_build(
+                &self.log,
+                self.inner.as_mut(),
+                PathBuild::new(reused.unwrap()),
+                &session_info.path,
+                &staging_dir,
+                &limits,
+            )
+            .await?
+            .map_err(Into::into),
+
+            BuildSource::Taskcluster { artifact, .. } => {
+                let artifact =
+                    artifact.unwrap_or_else(|| self.config.taskcluster.artifact().into());
+                let task_id = task_id.unwrap();
+                let name = format!("taskcluster/{}/{}", task_id, artifact);
+
+                match self.stored_build(&name).await {
+                    Some(archive) => {
+                        info!(self.log, "Using stored build"; "path" => archive.display());
+
+                        fetch_build(
+                            &self.log,
+                            self.inner.as_mut(),
+                            PathBuild::new(archive),
+                            &session_info.path,
+                            &staging_dir,
+                            &limits,
+                        )
+                        .await?
+                        .map_err(Into::into)
+                    }
+
+                    None => {
+                        store_as = Some(name);
+                        remote = true;
+                        let provider = TaskclusterBuild::new(&mut self.tc, task_id, artifact);
+
+                        fetch_build(
+                            &self.log,
+                            self.inner.as_mut(),
+                            provider,
+                            &session_info.path,
+                            &staging_dir,
+                            &limits,
+                        )
+                        .await?
+                        .map_err(RunnerProtoError::Taskcluster)
+                    }
+                }
+            }
+
+            BuildSource::MozillaArchive(build) => {
+                store_as = request_ref;
+                remote = true;
+
+                match MozillaArchiveBuild::new(&self.config.mozilla_archive, build)
+                    .and_then(|provider| provider.with_locale(locale))
+                {
+                    Ok(provider) => fetch_build(
+                        &self.log,
+                        self.inner.as_mut(),
+                        provider,
+                        &session_info.path,
+                        &staging_dir,
+                        &limits,
+                    )
+                    .await?
+                    .map_err(Into::into),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            BuildSource::Url(ref url) => {
+                store_as = request_ref;
+                remote = true;
+
+                match UrlBuild::new(url) {
+                    Ok(provider) => fetch_build(
+                        &self.log,
+                        self.inner.as_mut(),
+                        provider,
+                        &session_info.path,
+                        &staging_dir,
+                        &limits,
+                    )
+                    .await?
+                    .map_err(Into::into),
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            BuildSource::Path(path) => fetch_build(
+                &self.log,
+                self.inner.as_mut(),
+                PathBuild::new(path),
+                &session_info.path,
+                &staging_dir,
+                &limits,
+            )
+            .await?
+            .map_err(Into::into),
+
+            BuildSource::Upload { size, sha256 } => {
+                store_as = request_ref;
+                expected_sha256 = Some(sha256);
+
+                // The build arrives over the connection, so there is no way to
+                // report progress until it has been received. The recorder is
+                // tracking its own progress anyway.
+                let mut stream = self.inner.take().unwrap().into_inner();
+                let result = fetch_build(
+                    &self.log,
+                    None::<&mut RunnerSideProto<CountingStream<St>>>,
+                    UploadBuild::new(&mut stream, size).with_codec(self.codec),
+                    &session_info.path,
+                    &staging_dir,
+                    &limits,
+                )
+                .await;
+                self.inner = Some(Proto::new(stream));
+
+                result?.map_err(RunnerProtoError::UploadBuild)
+            }
+        };
+
+        let fetched = match download_result {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                error!(self.log, "Could not download build"; "error" => %e);
+                self.send(DownloadBuild {
+                    result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
+                })
+                .await?;
+                return Err(e);
+            }
+        };
+
+        if remote {
+            self.downloaded += fetched.downloaded;
+        }
+
+        self.send(DownloadBuild {
+            result: Ok(DownloadStatus::Downloaded),
+        })
+        .await?;
+
+        let extract_result = match fetched.extracted {
+            Some(format) => {
+                info!(self.log, "Extracted build while downloading"; "format" => ?format);
+                Ok(format)
+            }
+
+            None => {
+                // Anything extracted while downloading is discarded.
+                drop(staging);
+                staging = self.stage_build(session_info).await?;
+
+                info!(self.log, "Extracting downloaded artifact...");
+                METRICS.set_phase(Phase::Extracting);
+
+                let (progress_tx, progress_rx) = watch::channel(ExtractProgress::default());
+                let extract_task = spawn_blocking({
+                    let archive = fetched.archive.clone();
+                    let extract_dir = staging.path().to_owned();
+                    let sevenzip_path = self.config.sevenzip_path.clone();
+                    let limits = self.config.extract_limits;
+                    move || extract(&archive, &extract_dir, &sevenzip_path, &limits, progress_tx)
+                });
+
+                report_progress(
+                    self.inner.as_mut().unwrap(),
+                    extract_task,
+                    progress_rx,
+                    DownloadStatus::Extracting,
+                )
+                .await?
+                .expect("extract task was cancelled or panicked")
+            }
+        };
+
+        if let Err(e) = extract_result {
+            self.send(DownloadBuild {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Extraction)),
+            })
+            .await?;
+            return Err(e.into());
+        }
+
+        // The archive is no longer needed once it has been extracted, unless
+        // it was provided by the runner itself or is kept in the store.
+        if fetched.archive.starts_with(&session_info.path) {
+            match store_as {
+                Some(name) => {
+                    self.store_build(&fetched.archive, name, expected_sha256)
+                        .await
+                }
+                None => {
+                    if let Err(e) = remove_file(&fetched.archive).await {
+                        warn!(self.log, "Could not remove downloaded archive"; "error" => %e);
+                    }
+                }
+            }
+        }
+
+        // Only a build that contains Firefox is moved into place.
+        let staged_firefox = staging.path().join("firefox").join(FIREFOX_BIN);
+        if !staged_firefox.is_file_async().await {
+            let err = RunnerProtoError::MissingFirefox;
+
+            self.send(DownloadBuild {
+                result: Err(err.to_foreign_error().with_kind(err.foreign_kind())),
+            })
+            .await?;
+
+            return Err(err);
+        }
+
+        if let Err(e) = staging.commit_subdir(Path::new("firefox")) {
+            error!(self.log, "Could not move extracted build into place"; "error" => %e);
+            self.send(DownloadBuild {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Extraction)),
+            })
+            .await?;
+            return Err(e.into());
+        }
+
+        let firefox_path = session_info.firefox_path();
+
+        info!(self.log, "Extracted build");
+        self.send(DownloadBuild {
+            result: Ok(DownloadStatus::Extracted),
+        })
+        .await?;
+        Ok(firefox_path)
+    }
+
+    /// Create the staging directory that the session's build is extracted
+    /// into.
+    ///
+    /// Failures are reported to the recorder.
+    async fn stage_build(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<StagingDir, RunnerProtoError<S, T, P>> {
+        match StagingDir::new(&session_info.path, &session_info.path.join("firefox")) {
+            Ok(staging) => Ok(staging),
+            Err(e) => {
+                error!(self.log, "Could not create build staging directory"; "error" => %e);
+                self.send(DownloadBuild {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Extraction)),
+                })
+                .await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Return the path of the build archive kept in the store under the given
+    /// name, if any.
+    async fn stored_build(&self, name: &str) -> Option<PathBuf> {
+        let store = self.session_manager.store();
+
+        let result = spawn_blocking({
+            let store = store.clone();
+            let name = name.to_owned();
+            move || store.get_ref(BUILD_REFS, &name)
+        })
+        .await
+        .expect("read store task was cancelled or panicked");
+
+        match result {
+            Ok(hash) => hash.and_then(|hash| store.get(&hash)),
+            Err(e) => {
+                warn!(self.log, "Could not look up stored build"; "error" => %e);
+                None
+            }
+        }
+    }
+
+    /// Move the downloaded build archive into the store under the given name.
+    ///
+    /// Only the most recently stored builds are kept. An uploaded build whose
+    /// contents do not match the digest the recorder declared is not kept
+    /// under the name, since the name is derived from that digest.
+    async fn store_build(&self, archive: &Path, name: String, expected_sha256: Option<String>) {
+        let store = self.session_manager.store();
+
+        let result = spawn_blocking({
+            let archive = archive.to_owned();
+            let log = self.log.clone();
+            move || {
+                let hash = store.insert_move(&archive)?;
+
+                if let Some(expected) = expected_sha256 {
+                    if expected != hash {
+                        warn!(
+                            log,
+                            "Not keeping uploaded build that does not match its digest";
+                            "expected" => expected,
+                            "actual" => hash,
+                        );
+                        return Ok(());
+                    }
+                }
+
+                store.set_ref(BUILD_REFS, &name, &hash)?;
+                store.trim_refs(BUILD_REFS, STORED_BUILDS)
+            }
+        })
+        .await
+        .expect("store build task was cancelled or panicked");
+
+        if let Err(e) = result {
+            warn!(self.log, "Could not store downloaded archive"; "error" => %e);
+        }
+    }
+
+    async fn disable_updates(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        write_policies(session_info, default_policies())
+            .await
+            .map_err(RunnerProtoError::DisableUpdates)
+    }
+
+    /// Copy the named profile template to the session's profile directory.
+    async fn copy_profile_template(
+        &self,
+        session_info: &SessionInfo<'_>,
+        template: &str,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        let profile_path = session_info.profile_path();
+
+        let result = spawn_blocking({
+            let profiles_dir = self.config.profiles_dir.clone();
+            let template = template.to_owned();
+            let profile_path = profile_path.clone();
+            move || copy_template(profiles_dir.as_deref(), &template, &profile_path)
+        })
+        .await
+        .expect("copy profile template task was cancelled or panicked");
+
+        if let Err(e) = result {
+            error!(self.log, "Could not copy profile template"; "error" => %e);
+            return Err(e.into());
+        }
+
+        Ok(profile_path)
+    }
+
+    /// Wait for the given duration, and then for the disk to become idle if
+    /// requested, before the next launch of Firefox.
+    ///
+    /// The disk not becoming idle does not fail the session.
+    async fn cool_down(
+        &mut self,
+        duration: Duration,
+        wait_for_disk: bool,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        info!(
+            self.log,
+            "Cooling down";
+            "duration" => ?duration,
+            "disk_idle" => wait_for_disk,
+        );
+        delay_for(duration).await;
+
+        if wait_for_disk {
+            if let Err(e) = disk_idle(&self.perf_provider).await {
+                warn!(self.log, "Disk did not become idle"; "error" => %e);
+            }
+        }
+
+        self.send(CooledDown).await?;
+        Ok(())
+    }
+
+    /// Take a snapshot of the session's profile to reset it from before each
+    /// relaunch.
+    async fn snapshot_profile(
+        &self,
+        session_info: &SessionInfo<'_>,
+        snapshot_path: &Path,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        info!(self.log, "Taking snapshot of profile");
+
+        let result = spawn_blocking({
+            let profile_path = session_info.profile_path();
+            let snapshot_path = snapshot_path.to_owned();
+            move || snapshot_profile(&profile_path, &snapshot_path)
+        })
+        .await
+        .expect("snapshot profile task was cancelled or panicked");
+
+        if let Err(e) = result {
+            error!(self.log, "Could not take snapshot of profile"; "error" => %e);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Reset the session's profile from the snapshot taken before the first
+    /// launch.
+    async fn reset_profile(
+        &self,
+        session_info: &SessionInfo<'_>,
+        snapshot_path: &Path,
+    ) -> Result<(), RunnerProtoError<S, T, P>> {
+        info!(self.log, "Resetting profile");
+
+        let result = spawn_blocking({
+            let profile_path = session_info.profile_path();
+            let snapshot_path = snapshot_path.to_owned();
+            move || reset_profile(&snapshot_path, &profile_path)
+        })
+        .await
+        .expect("reset profile task was cancelled or panicked");
+
+        if let Err(e) = result {
+            error!(self.log, "Could not reset profile"; "error" => %e);
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Receive a profile from the recorder.
+    async fn recv_profile(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        profile_size: u64,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        info!(self.log, "Receiving profile...");
+        let zip_path = self.recv_profile_zip(session_info, profile_size).await?;
+
+        // It is possible that the profile contains a top-level directory, in
+        // which case we don't want to directly extract to
+        // `request_info.path.join("profile")`. Instead, we unzip it to a
+        // staging directory and then move the top level directory (which may
+        // be the staging directory itself) to the target profile directory.
+        // If extraction fails, the staging directory is removed.
+        let profile_dir = session_info.path.join("profile");
+
+        let unzip_result = spawn_blocking({
+            let session_dir = session_info.path.clone();
+            let zip_path = zip_path.clone();
+            let profile_dir = profile_dir.clone();
+            let limits = self.config.extract_limits;
+            move || -> Result<usize, StagingError> {
+                let staging = StagingDir::new(&session_dir, &profile_dir)?;
+                let stats = unzip(&zip_path, staging.path(), &limits)?;
+
+                if stats.extracted > 0 {
+                    match stats.top_level_dir {
+                        Some(ref top_level_dir) => staging.commit_subdir(top_level_dir)?,
+                        None => staging.commit()?,
+                    }
+                }
+
+                Ok(stats.extracted)
+            }
+        })
+        .await
+        .expect("unzip profile task was cancelled or panicked");
+
+        let extracted = match unzip_result {
+            Ok(extracted) => extracted,
+            Err(e) => {
+                error!(self.log, "Could not extract profile"; "error" => %e);
+
+                self.send(RecvProfile {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                })
+                .await?;
+
+                return Err(e.into());
+            }
+        };
+
+        if extracted == 0 {
+            error!(self.log, "Profile was empty");
+            let e = RunnerProtoError::EmptyProfile;
             self.send(RecvProfile {
-                result: Err(e.into_error_message()),
+                result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
             })
             .await?;
 
             return Err(e);
         }
 
-        let unzipped_profile_dir = stats.top_level_dir.unwrap_or(unzip_path);
-        let profile_dir = session_info.path.join("profile");
-        if let Err(e) = rename(unzipped_profile_dir, &profile_dir).await {
-            error!(self.log, "Could not rename profile directory after extraction"; "error" => %e);
+        info!(self.log, "Profile extracted");
+
+        self.send(RecvProfile {
+            result: { Ok(DownloadStatus::Extracted) },
+        })
+        .await?;
+
+        Ok(profile_dir)
+    }
+
+    /// Receive the changes to the cached profile from the recorder and apply
+    /// them to a copy of the cached profile.
+    ///
+    /// The cache is updated with the new profile before prefs are written to
+    /// it.
+    async fn recv_profile_delta(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        let cache = self.session_manager.profile_cache();
+
+        let manifest = match spawn_blocking({
+            let cache = cache.clone();
+            move || cache.manifest()
+        })
+        .await
+        .expect("read profile cache task was cancelled or panicked")
+        {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!(self.log, "Could not read profile cache; requesting entire profile"; "error" => %e);
+                ProfileHashes::new()
+            }
+        };
+
+        info!(self.log, "Sending profile manifest"; "files" => manifest.len());
+        self.send(ProfileManifest {
+            manifest: manifest.clone(),
+        })
+        .await?;
+
+        let ProfileDelta { size, removed } = self.recv().await?;
+        info!(
+            self.log,
+            "Receiving profile delta...";
+            "size" => size,
+            "removed" => removed.len(),
+        );
+        let zip_path = self.recv_profile_zip(session_info, size).await?;
+
+        let profile_dir = session_info.profile_path();
+        let apply_result = spawn_blocking({
+            let cache = cache.clone();
+            let zip_path = zip_path.clone();
+            let session_dir = session_info.path.clone();
+            let profile_dir = profile_dir.clone();
+            let limits = self.config.extract_limits;
+            move || {
+                cache.apply_delta(
+                    &manifest,
+                    &removed,
+                    &zip_path,
+                    &session_dir,
+                    &profile_dir,
+                    &limits,
+                )
+            }
+        })
+        .await
+        .expect("apply profile delta task was cancelled or panicked");
+
+        if let Err(e) = apply_result {
+            error!(self.log, "Could not apply profile delta"; "error" => %e);
+
+            self.send(RecvProfile {
+                result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+            })
+            .await?;
+
+            return Err(e.into());
+        }
+
+        if let Err(e) = spawn_blocking({
+            let profile_dir = profile_dir.clone();
+            move || cache.update(&profile_dir)
+        })
+        .await
+        .expect("update profile cache task was cancelled or panicked")
+        {
+            warn!(self.log, "Could not update profile cache"; "error" => %e);
+        }
+
+        info!(self.log, "Profile extracted");
+
+        self.send(RecvProfile {
+            result: Ok(DownloadStatus::Extracted),
+        })
+        .await?;
+
+        Ok(profile_dir)
+    }
+
+    /// Receive a zipped profile (or profile delta) of the given size from the
+    /// recorder.
+    ///
+    /// The recorder is told when the runner is ready to receive the archive
+    /// and when it has been received. Archives larger than the configured
+    /// maximum are refused before they are sent.
+    async fn recv_profile_zip(
+        &mut self,
+        session_info: &SessionInfo<'_>,
+        size: u64,
+    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        if let Some(max) = self.config.max_profile_size {
+            if size > max {
+                let e = RunnerProtoError::ProfileTooLarge { size, max };
+                error!(self.log, "Refusing profile"; "error" => %e);
 
-            self.send(RecvProfile {
-                result: Err(e.into_error_message()),
-            })
-            .await?;
+                self.send(RecvProfile {
+                    result: Err(e.to_foreign_error().with_kind(e.foreign_kind())),
+                })
+                .await?;
 
-            return Err(e.into());
+                return Err(e);
+            }
         }
 
-        info!(self.log, "Profile extracted");
+        self.send(RecvProfile {
+            result: Ok(DownloadStatus::Downloading),
+        })
+        .await?;
+
+        let mut stream = self.inner.take().unwrap().into_inner();
+        let result =
+            Self::recv_profile_raw(&mut stream, &session_info.path, size, self.codec).await;
+        self.inner = Some(Proto::new(stream));
+
+        let zip_path = match result {
+            Ok((zip_path, stats)) => {
+                self.log_transfer("Received profile", &stats);
+                zip_path
+            }
+            Err(e) => {
+                self.send(RecvProfile {
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Profile)),
+                })
+                .await?;
+                return Err(e);
+            }
+        };
 
+        info!(self.log, "Extracting profile...");
         self.send(RecvProfile {
-            result: { Ok(DownloadStatus::Extracted) },
+            result: Ok(DownloadStatus::Downloaded),
         })
         .await?;
 
-        Ok(profile_dir)
+        Ok(zip_path)
     }
 
-    /// Receive the raw bytes of a profile from the recorder.
+    /// Receive the bytes of a profile from the recorder.
     async fn recv_profile_raw(
-        stream: &mut TcpStream,
+        stream: &mut CountingStream<St>,
         download_dir: &Path,
         profile_size: u64,
-    ) -> Result<PathBuf, RunnerProtoError<S, T, P>> {
+        codec: Codec,
+    ) -> Result<(PathBuf, TransferStats), RunnerProtoError<S, T, P>> {
         let zip_path = download_dir.join("profile.zip");
         let mut f = File::create(&zip_path).await?;
 
-        tokio::io::copy(&mut stream.take(profile_size), &mut f).await?;
+        let stats = recv_payload(stream, &mut f, profile_size, codec, |_| {}).await?;
 
-        Ok(zip_path)
+        Ok((zip_path, stats))
+    }
+
+    /// Log the transfer of a payload.
+    fn log_transfer(&self, msg: &str, stats: &TransferStats) {
+        info!(
+            self.log, "{}", msg;
+            "codec" => %stats.codec,
+            "size" => stats.size,
+            "wire_size" => stats.wire_size,
+        );
     }
 
     /// Run the given Firefox binary with the specified profile.
     ///
-    /// The process will be terminated after 45 seconds.
+    /// The process will be terminated when the recorder sends a
+    /// [`StopFirefox`](../../libfxrecord/net/message/struct.StopFirefox.html)
+    /// message.
     async fn run_firefox(
         &mut self,
         firefox_bin: &Path,
         profile: &Path,
-    ) -> Result<(), RunnerProtoError<S, T, P>> {
-        info!(self.log, "starting Firefox...");
-        let firefox_launcher = match Command::new(firefox_bin)
+        options: &LaunchOptions<'_>,
+    ) -> Result<Option<Result<(), MarionetteError>>, RunnerProtoError<S, T, P>> {
+        let LaunchOptions {
+            session_type,
+            env,
+            safe_mode,
+            headless_measurement,
+            memory_report_path,
+        } = *options;
+
+        // A failure to enable Marionette only fails the memory report.
+        let marionette_port = match memory_report_path {
+            Some(..) => Some(self.enable_marionette(profile).await),
+            None => None,
+        };
+
+        info!(self.log, "starting Firefox..."; "env" => ?env, "safe_mode" => safe_mode);
+        METRICS.set_phase(Phase::RunningFirefox);
+        let mut command = Command::new(firefox_bin);
+        command
             .arg("--profile")
             .arg(profile)
             .arg("--new-instance")
             .arg("--wait-for-browser")
+            .envs(env.iter().map(|(var, value)| (var, value)));
+
+        if safe_mode {
+            command.arg("--safe-mode");
+        }
+
+        if let Some(Ok(..)) = marionette_port {
+            command.arg("--marionette");
+        }
+
+        if let SessionType::PageLoad { .. } = session_type {
+            command.arg(orange_page_url());
+        }
+
+        let mut firefox_launcher = match command
             .stdin(Stdio::piped())
             .stderr(Stdio::piped())
             .stdout(Stdio::piped())
@@ -502,52 +2500,88 @@ where
             Err(e) => {
                 error!(self.log, "could not start Firefox"; "error" => %e);
                 self.send(StartedFirefox {
-                    result: Err(e.into_error_message()),
+                    result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox)),
                 })
                 .await?;
                 return Err(RunnerProtoError::StartFirefox(e));
             }
         };
 
-        self.send(StartedFirefox { result: Ok(()) }).await?;
-        self.recv::<StopFirefox>().await?;
+        let mut navigate_result = Ok(());
+        if let SessionType::PageLoad { ref url } = session_type {
+            // Give Firefox a chance to finish starting up so that we only
+            // measure the page load.
+            info!(self.log, "waiting for Firefox to settle...");
+            self.wait_for_idle_or_timeout().await;
 
-        info!(self.log, "stopping Firefox...");
-        let mut errors = Vec::new();
+            self.send(StartedFirefox { result: Ok(()) }).await?;
+            self.recv::<Navigate>().await?;
 
-        {
-            info!(self.log, "opening firefox process...");
-            let firefox_launcher_handle =
-                open_process(firefox_launcher.id(), winapi::um::winnt::PROCESS_ALL_ACCESS)?;
-
-            let mut terminated = false;
-
-            info!(self.log, "iterating child processes...");
-            for firefox_main_handle in child_processes(
-                firefox_launcher_handle,
-                winapi::um::winnt::PROCESS_TERMINATE,
-            )? {
-                let firefox_main_handle = match firefox_main_handle {
-                    Ok(handle) => handle,
-                    Err(e) => {
-                        error!(self.log, "could not retrieve handle to Firefox main process"; "error" => %e);
-                        errors.push(e.into_error_message());
-                        break;
-                    }
-                };
+            navigate_result = self.navigate(firefox_bin, profile, url).await;
 
-                if let Err(e) = terminate_process(&firefox_main_handle, 1) {
-                    error!(self.log, "could not terminate Firefox main process"; "error" => %e);
-                    errors.push(e.into_error_message());
-                    continue;
+            match navigate_result {
+                Ok(()) => {
+                    info!(self.log, "waiting for page load to finish...");
+                    self.wait_for_idle_or_timeout().await;
+                    self.send(Navigated { result: Ok(()) }).await?;
+                }
+                Err(ref e) => {
+                    error!(self.log, "could not navigate Firefox"; "error" => %e);
+                    self.send(Navigated {
+                        result: Err(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox)),
+                    })
+                    .await?;
                 }
+            }
+        } else {
+            if headless_measurement {
+                // Nothing is being captured, so the recorder stops Firefox as
+                // soon as it has started.
+                info!(self.log, "waiting for Firefox to finish starting up...");
+                self.wait_for_idle_or_timeout().await;
+            }
 
-                terminated = true;
+            self.send(StartedFirefox { result: Ok(()) }).await?;
+        }
+
+        // Firefox is stopped even if the recorder never asks, so that it does
+        // not keep running after the session has failed.
+        let capture_result = match with_timeout(
+            TimeoutPhase::Capture,
+            self.config.timeouts.get(TimeoutPhase::Capture),
+            self.recv::<StopFirefox>(),
+        )
+        .await
+        {
+            Ok(result) => {
+                result?;
+                Ok(())
             }
+            Err(e) => {
+                error!(self.log, "Recorder did not finish capturing in time"; "error" => %e);
+                Err(e)
+            }
+        };
+
+        // The report is collected before Firefox is stopped, but only once
+        // the recording has finished so that it is not measured.
+        let memory_report = match (marionette_port, memory_report_path) {
+            (Some(port), Some(path)) if capture_result.is_ok() => Some(match port {
+                Ok(port) => self.collect_memory_report(port, path).await,
+                Err(e) => Err(e),
+            }),
+            _ => None,
+        };
+
+        info!(self.log, "stopping Firefox...");
+        let mut errors = Vec::new();
+
+        {
+            let terminated = self.terminate_firefox(&mut firefox_launcher, &mut errors)?;
 
             if let Err(e) = firefox_launcher.await {
                 error!(self.log, "could not wait for Firefox launcher process to exit"; "error" => %e);
-                errors.push(e.into_error_message());
+                errors.push(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox));
             }
 
             if !errors.is_empty() {
@@ -563,7 +2597,149 @@ where
         info!(self.log, "terminated Firefox");
         self.send(StoppedFirefox { result: Ok(()) }).await?;
 
-        Ok(())
+        capture_result?;
+        navigate_result.map_err(RunnerProtoError::Navigate)?;
+
+        Ok(memory_report)
+    }
+
+    /// Terminate the main Firefox process started by the launcher process.
+    ///
+    /// Errors with individual processes are added to `errors`. Returns whether
+    /// or not a main process was terminated.
+    #[cfg(windows)]
+    fn terminate_firefox(
+        &self,
+        firefox_launcher: &mut Child,
+        errors: &mut Vec<ForeignError>,
+    ) -> Result<bool, io::Error> {
+        info!(self.log, "opening firefox process...");
+        let firefox_launcher_handle =
+            open_process(firefox_launcher.id(), winapi::um::winnt::PROCESS_ALL_ACCESS)?;
+
+        let mut terminated = false;
+
+        info!(self.log, "iterating child processes...");
+        for firefox_main_handle in child_processes(
+            firefox_launcher_handle,
+            winapi::um::winnt::PROCESS_TERMINATE,
+        )? {
+            let firefox_main_handle = match firefox_main_handle {
+                Ok(handle) => handle,
+                Err(e) => {
+                    error!(self.log, "could not retrieve handle to Firefox main process"; "error" => %e);
+                    errors.push(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox));
+                    break;
+                }
+            };
+
+            if let Err(e) = terminate_process(&firefox_main_handle, 1) {
+                error!(self.log, "could not terminate Firefox main process"; "error" => %e);
+                errors.push(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox));
+                continue;
+            }
+
+            terminated = true;
+        }
+
+        Ok(terminated)
+    }
+
+    /// Terminate the main Firefox process.
+    ///
+    /// There is no launcher process outside of Windows, so the process that
+    /// was started is the main process.
+    #[cfg(not(windows))]
+    fn terminate_firefox(
+        &self,
+        firefox_launcher: &mut Child,
+        errors: &mut Vec<ForeignError>,
+    ) -> Result<bool, io::Error> {
+        if let Err(e) = firefox_launcher.kill() {
+            error!(self.log, "could not terminate Firefox main process"; "error" => %e);
+            errors.push(e.to_foreign_error().with_kind(ForeignErrorKind::Firefox));
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Configure the profile so that Firefox listens for Marionette on an
+    /// unused port, which is returned.
+    async fn enable_marionette(&mut self, profile: &Path) -> Result<u16, MarionetteError> {
+        let port = unused_port().await?;
+        append_prefs(profile, marionette_prefs(port).into_iter()).await?;
+
+        info!(self.log, "enabled Marionette"; "port" => port);
+        Ok(port)
+    }
+
+    /// Wait for Firefox to settle and then have it write a memory report to
+    /// the given path.
+    async fn collect_memory_report(
+        &mut self,
+        port: u16,
+        path: &Path,
+    ) -> Result<(), MarionetteError> {
+        info!(
+            self.log,
+            "waiting for Firefox to settle before collecting memory report..."
+        );
+        self.wait_for_idle_or_timeout().await;
+
+        info!(self.log, "collecting memory report...");
+        let result = timeout(MEMORY_REPORT_TIMEOUT, async {
+            let mut marionette = Marionette::connect(port).await?;
+            marionette.dump_memory_report(path).await
+        })
+        .await;
+
+        match result {
+            Ok(result) => result,
+            Err(..) => Err(MarionetteError::Timeout),
+        }
+    }
+
+    /// Navigate the running instance of Firefox to the given URL.
+    ///
+    /// This re-invokes Firefox with the same profile, which forwards the URL
+    /// to the running instance and exits.
+    async fn navigate(
+        &mut self,
+        firefox_bin: &Path,
+        profile: &Path,
+        url: &str,
+    ) -> Result<(), io::Error> {
+        info!(self.log, "navigating Firefox"; "url" => url);
+
+        let status = Command::new(firefox_bin)
+            .arg("--profile")
+            .arg(profile)
+            .arg(url)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .await?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "Firefox exited with status {}",
+                status
+            )))
+        }
+    }
+
+    /// Wait for the CPU and disk to become idle.
+    ///
+    /// Failing to become idle is not fatal; Firefox may never become fully idle
+    /// on some pages.
+    async fn wait_for_idle_or_timeout(&mut self) {
+        if let Err(e) = cpu_and_disk_idle(&self.perf_provider).await {
+            warn!(self.log, "did not become idle"; "error" => %e);
+        }
     }
 
     /// Send the given message to the runner.
@@ -587,6 +2763,298 @@ where
     }
 }
 
+/// The number of restarts reported in the runner's status.
+const STATUS_RESTARTS: usize = 10;
+
+/// Return the status of the runner.
+async fn runner_status<R: SessionManager>(
+    log: &Logger,
+    session_manager: &R,
+    busy: bool,
+) -> RunnerStatus {
+    let mut restarts = match session_manager.restart_log().await {
+        Ok(restarts) => restarts,
+        Err(e) => {
+            warn!(log, "Could not read restart log"; "error" => %e);
+            Vec::new()
+        }
+    };
+
+    if restarts.len() > STATUS_RESTARTS {
+        restarts.drain(..restarts.len() - STATUS_RESTARTS);
+    }
+
+    RunnerStatus {
+        version: env!("CARGO_PKG_VERSION").into(),
+        busy,
+        restarts,
+    }
+}
+
+/// Respond to a request from a recorder while another request is being served.
+///
+/// Pings are answered with the runner's status and sessions are refused.
+pub async fn handle_busy_request<R, St>(
+    log: Logger,
+    stream: St,
+    session_manager: R,
+) -> Result<(), ProtoError<RecorderMessageKind>>
+where
+    R: SessionManager,
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut proto = RunnerSideProto::new(stream);
+
+    fn busy<T>() -> ForeignResult<T> {
+        Err(ForeignError::new("the runner is serving another session")
+            .with_kind(ForeignErrorKind::Busy))
+    }
+
+    match proto.recv::<Session>().await? {
+        Session::NewSession(..) => {
+            warn!(log, "Refused new session while busy");
+            proto
+                .send(NewSessionResponse {
+                    session_id: busy(),
+                    codec: Codec::default(),
+                    max_profile_size: None,
+                })
+                .await
+        }
+
+        Session::ResumeSession(..) => {
+            warn!(log, "Refused to resume session while busy");
+            proto
+                .send(ResumeResponse {
+                    result: busy(),
+                    codec: Codec::default(),
+                })
+                .await
+        }
+
+        Session::Ping => {
+            info!(log, "Received ping while busy");
+            let status = runner_status(&log, &session_manager, true).await;
+            proto.send(Status { status }).await
+        }
+    }
+}
+
+/// How Firefox is launched for a session.
+#[derive(Clone, Copy)]
+struct LaunchOptions<'a> {
+    /// The type of session, which determines whether Firefox is navigated
+    /// after it starts.
+    session_type: &'a SessionType,
+
+    /// Additional environment variables to set for Firefox.
+    env: &'a [(String, String)],
+
+    /// Whether or not to start Firefox in safe mode.
+    safe_mode: bool,
+
+    /// Whether or not startup is measured without a recording, in which case
+    /// Firefox finishes starting up before it is reported as started.
+    headless_measurement: bool,
+
+    /// Where to write a memory report collected before Firefox is stopped, if
+    /// one is requested.
+    memory_report_path: Option<&'a Path>,
+}
+
+/// A build fetched by a [`BuildProvider`](../provider/trait.BuildProvider.html).
+struct FetchedBuild {
+    /// The path to the build archive.
+    archive: PathBuf,
+
+    /// The format of the archive, if it was extracted while it was being
+    /// fetched.
+    extracted: Option<ArchiveFormat>,
+
+    /// The number of bytes fetched.
+    downloaded: u64,
+}
+
+/// Fetch a build from the given provider into `download_dir`.
+///
+/// If the provider knows where it will write the archive, the archive is
+/// extracted to `extract_dir` while it is being fetched, if its format allows,
+/// subject to the given limits. The extracted build has not been verified
+/// until the fetch has succeeded.
+///
+/// If `inner` is provided, the progress of the fetch is periodically reported
+/// to the recorder.
+async fn fetch_build<B, St>(
+    log: &Logger,
+    inner: Option<&mut RunnerSideProto<St>>,
+    mut provider: B,
+    download_dir: &Path,
+    extract_dir: &Path,
+    limits: &ExtractLimits,
+) -> Result<Result<FetchedBuild, B::Error>, ProtoError<RecorderMessageKind>>
+where
+    B: BuildProvider,
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    let download_path = provider.download_path(download_dir);
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let extract_task = download_path.clone().map(|archive| {
+        let reader = PartialArchive::new(archive.clone(), finished.clone());
+        let target = extract_dir.to_owned();
+        let limits = *limits;
+        spawn_blocking(move || extract_stream(reader, &archive, &target, &limits))
+    });
+
+    let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+    let fetch = provider.fetch_build(download_dir, progress_tx);
+
+    let result = match inner {
+        Some(inner) => {
+            report_progress(inner, fetch, progress_rx.clone(), DownloadStatus::Progress).await
+        }
+        None => Ok(fetch.await),
+    };
+
+    let downloaded = progress_rx.borrow().downloaded;
+    METRICS.downloaded(downloaded);
+
+    // The extraction stops once it has read everything that was fetched,
+    // whether or not the fetch succeeded.
+    finished.store(true, Ordering::SeqCst);
+    let extract_result = match extract_task {
+        Some(extract_task) => extract_task
+            .await
+            .expect("extract task was cancelled or panicked"),
+        None => Ok(None),
+    };
+
+    Ok(result?.map(|archive| {
+        let extracted = match extract_result {
+            Ok(format) if download_path.as_ref() == Some(&archive) => format,
+            Ok(..) => None,
+            Err(e) => {
+                warn!(log, "Could not extract build while downloading"; "error" => %e);
+                None
+            }
+        };
+
+        FetchedBuild {
+            archive,
+            extracted,
+            downloaded,
+        }
+    }))
+}
+
+/// Wait for the future to complete, periodically reporting its progress to
+/// the recorder.
+///
+/// The future is polled alongside a timer so that progress is only reported
+/// every `PROGRESS_INTERVAL`, and only if it has changed.
+async fn report_progress<F, P, St>(
+    inner: &mut RunnerSideProto<St>,
+    mut fut: F,
+    progress_rx: watch::Receiver<P>,
+    status: fn(P) -> DownloadStatus,
+) -> Result<F::Output, ProtoError<RecorderMessageKind>>
+where
+    F: Future + Unpin,
+    P: Copy + Default + PartialEq,
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut last_progress = P::default();
+
+    loop {
+        match select(fut, delay_for(PROGRESS_INTERVAL)).await {
+            Either::Left((result, _)) => return Ok(result),
+            Either::Right((_, pending)) => {
+                fut = pending;
+
+                let progress = *progress_rx.borrow();
+                if progress != last_progress {
+                    inner
+                        .send(DownloadBuild {
+                            result: Ok(status(progress)),
+                        })
+                        .await?;
+                    last_progress = progress;
+                }
+            }
+        }
+    }
+}
+
+/// Return the name of the ref to the build acquired for the request with the
+/// given ID.
+///
+/// The name includes the build source, so that a request that reuses an ID
+/// for a different build does not receive the wrong build.
+fn request_build_ref(request_id: &str, build: &BuildSource) -> String {
+    format!(
+        "request/{}/{}",
+        request_id,
+        serde_json::to_string(build).expect("could not serialize build source")
+    )
+}
+
+/// The enterprise policies that every session's Firefox is configured with.
+fn default_policies() -> Value {
+    json!({
+        "DisableAppUpdate": true,
+    })
+}
+
+/// Write the enterprise policies for the session's Firefox.
+///
+/// Any existing policies will be replaced.
+async fn write_policies(session_info: &SessionInfo<'_>, policies: Value) -> Result<(), io::Error> {
+    let distribution_dir = session_info.path.join("firefox").join("distribution");
+
+    if !distribution_dir.is_dir_async().await {
+        create_dir(&distribution_dir).await?;
+    }
+
+    let contents = serde_json::to_vec_pretty(&json!({ "policies": policies }))?;
+
+    File::create(distribution_dir.join("policies.json"))
+        .await?
+        .write_all(&contents)
+        .await
+}
+
+/// Append the given prefs to the `user.js` file in the given profile.
+async fn append_prefs<I>(profile_path: &Path, prefs: I) -> Result<(), io::Error>
+where
+    I: Iterator<Item = (String, PrefValue)>,
+{
+    let mut f = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(profile_path.join("user.js"))
+        .await?;
+
+    write_prefs(&mut f, prefs).await
+}
+
+/// Open the file at the given path and return it with its size.
+async fn open_with_size(path: &Path) -> Result<(File, u64), io::Error> {
+    let f = File::open(path).await?;
+    let size = f.metadata().await?.len();
+    Ok((f, size))
+}
+
+/// Return a URL for a page that is entirely orange.
+///
+/// Firefox displays this page before navigating during page load sessions, so
+/// that the start of navigation can be detected in the recording.
+fn orange_page_url() -> String {
+    format!(
+        "data:text/html,<html style=\"background-color:rgb({},{},{})\"></html>",
+        ORANGE[0], ORANGE[1], ORANGE[2]
+    )
+}
+
 #[derive(Debug, Error)]
 pub enum RunnerProtoError<S, T, P>
 where
@@ -597,7 +3065,14 @@ where
     #[error("An empty profile was received")]
     EmptyProfile,
 
-    #[error("No firefox.exe in build artifact")]
+    #[error(
+        "The profile is {} bytes, but the runner accepts at most {} bytes",
+        .size,
+        .max
+    )]
+    ProfileTooLarge { size: u64, max: u64 },
+
+    #[error("No `{}' in build artifact", FIREFOX_BIN)]
     MissingFirefox,
 
     #[error(transparent)]
@@ -609,15 +3084,57 @@ where
     #[error("Could not disable updates: {}", .0)]
     DisableUpdates(#[source] io::Error),
 
+    #[error("Could not record Fast Startup state: {}", .0)]
+    FastStartup(#[source] io::Error),
+
     #[error(transparent)]
     Taskcluster(T::Error),
 
+    #[error(transparent)]
+    MozillaArchive(#[from] MozillaArchiveError),
+
+    #[error(transparent)]
+    UrlBuild(#[from] UrlBuildError),
+
+    #[error(transparent)]
+    PathBuild(#[from] PathBuildError),
+
+    #[error("Could not receive build: {}", .0)]
+    UploadBuild(#[source] io::Error),
+
     #[error(transparent)]
     WaitForIdle(WaitForIdleError<P>),
 
+    #[error(transparent)]
+    Desktop(DesktopError),
+
     #[error(transparent)]
     Zip(#[from] ZipError),
 
+    #[error(transparent)]
+    ProfileCache(#[from] ProfileCacheError),
+
+    #[error(transparent)]
+    Staging(#[from] StagingError),
+
+    #[error(transparent)]
+    ProfileTemplate(#[from] TemplateError),
+
+    #[error(transparent)]
+    Langpack(#[from] LangpackError),
+
+    #[error(transparent)]
+    Extensions(#[from] ExtensionError),
+
+    #[error(transparent)]
+    Distribution(#[from] DistributionError),
+
+    #[error(transparent)]
+    Launch(#[from] LaunchError),
+
+    #[error(transparent)]
+    Extract(#[from] ArchiveError),
+
     #[error(transparent)]
     NewSession(#[from] NewSessionError),
 
@@ -629,6 +3146,108 @@ where
 
     #[error("Could not start Firefox: {}", .0)]
     StartFirefox(#[source] io::Error),
+
+    #[error("Could not navigate Firefox: {}", .0)]
+    Navigate(#[source] io::Error),
+
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+
+    #[error(transparent)]
+    Clock(#[from] ClockError),
+
+    #[error(transparent)]
+    Hosts(#[from] HostsError),
+
+    #[error("Could not condition network: {}", .0)]
+    ConditionNetwork(#[source] io::Error),
+
+    #[error("Could not install proxy certificate: {}", .0)]
+    InstallCertificate(#[source] io::Error),
+
+    #[error("Could not capture pings: {}", .0)]
+    PingCapture(#[source] io::Error),
+
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+}
+
+impl<S, T, P> RunnerProtoError<S, T, P>
+where
+    S: ShutdownProvider,
+    T: Taskcluster,
+    P: PerfProvider,
+{
+    /// A short name for the type of error, e.g., for labelling metrics.
+    pub fn kind(&self) -> &'static str {
+        use RunnerProtoError::*;
+
+        match self {
+            EmptyProfile
+            | ProfileTooLarge { .. }
+            | Zip(..)
+            | ProfileCache(..)
+            | Staging(..)
+            | ProfileTemplate(..)
+            | EnsureProfile(..)
+            | Launch(..) => "profile",
+            Langpack(..) => "locale",
+            Extensions(..) => "extensions",
+            Distribution(..) => "distribution",
+            MissingFirefox | Extract(..) => "extract",
+            Proto(..) => "protocol",
+            Shutdown(..) | FastStartup(..) => "restart",
+            DisableUpdates(..) => "disable_updates",
+            Taskcluster(..) | MozillaArchive(..) | UrlBuild(..) | PathBuild(..)
+            | UploadBuild(..) => "download",
+            WaitForIdle(..) => "wait_for_idle",
+            Desktop(..) => "desktop",
+            NewSession(..) => "new_session",
+            ResumeSession(..) => "resume_session",
+            StartFirefox(..) | Navigate(..) => "firefox",
+            Proxy(..) | InstallCertificate(..) => "proxy",
+            Clock(..) => "clock",
+            Hosts(..) => "hosts",
+            ConditionNetwork(..) => "network",
+            PingCapture(..) => "telemetry",
+            Timeout(..) => "timeout",
+        }
+    }
+
+    /// Classify the error for the recorder.
+    pub fn foreign_kind(&self) -> ForeignErrorKind {
+        use RunnerProtoError::*;
+
+        match self {
+            EmptyProfile
+            | ProfileTooLarge { .. }
+            | Zip(..)
+            | ProfileCache(..)
+            | Staging(..)
+            | ProfileTemplate(..)
+            | EnsureProfile(..)
+            | Langpack(..)
+            | Extensions(..)
+            | Launch(..) => ForeignErrorKind::Profile,
+            MissingFirefox | Extract(..) | Distribution(..) => ForeignErrorKind::Extraction,
+            Proto(..) => ForeignErrorKind::Protocol,
+            Shutdown(..) | FastStartup(..) => ForeignErrorKind::Shutdown,
+            Taskcluster(..) => ForeignErrorKind::Taskcluster,
+            MozillaArchive(..) | UrlBuild(..) | PathBuild(..) | UploadBuild(..) => {
+                ForeignErrorKind::Download
+            }
+            WaitForIdle(..) => ForeignErrorKind::Idle,
+            Desktop(..) => ForeignErrorKind::Desktop,
+            NewSession(..) | ResumeSession(..) => ForeignErrorKind::Session,
+            DisableUpdates(..) | StartFirefox(..) | Navigate(..) => ForeignErrorKind::Firefox,
+            Proxy(..) | InstallCertificate(..) => ForeignErrorKind::Proxy,
+            Clock(..) => ForeignErrorKind::Clock,
+            Hosts(..) => ForeignErrorKind::Hosts,
+            ConditionNetwork(..) => ForeignErrorKind::Network,
+            PingCapture(..) => ForeignErrorKind::Telemetry,
+            Timeout(..) => ForeignErrorKind::Timeout,
+        }
+    }
 }
 
 impl<S, T, P> From<io::Error> for RunnerProtoError<S, T, P>
@@ -0,0 +1,817 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Acquisition of the Firefox build used by a session.
+//!
+//! Each [`BuildSource`][BuildSource] that a recorder can request is served by
+//! a [`BuildProvider`](trait.BuildProvider.html).
+//!
+//! [BuildSource]: ../../libfxrecord/net/message/enum.BuildSource.html
+
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use libfxrecord::net::compression::{recv_payload, Codec};
+use libfxrecord::net::{DownloadProgress, OfficialBuild};
+use percent_encoding::percent_decode_str;
+use reqwest::{Client, StatusCode, Url};
+use thiserror::Error;
+use tokio::fs::File;
+use tokio::prelude::*;
+use tokio::sync::watch;
+
+use crate::config::MozillaArchiveConfig;
+use crate::langpack::is_valid_locale;
+use crate::taskcluster::Taskcluster;
+
+/// The URL of archive.mozilla.org's `pub` directory.
+pub const MOZILLA_ARCHIVE_URL: &str = "https://archive.mozilla.org/pub/";
+
+/// The extensions of build archives, in order of preference.
+const ARCHIVE_EXTENSIONS: &[&str] = &[".zip", ".tar.xz", ".tar.bz2"];
+
+/// A provider of build archives.
+#[async_trait]
+pub trait BuildProvider: Send {
+    type Error: Error + 'static;
+
+    /// Fetch the build archive into the given directory and return its path.
+    ///
+    /// The progress of the fetch is reported via `progress`.
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, Self::Error>;
+
+    /// Return the path that the build archive will be written to in the given
+    /// directory, if it is known before the fetch begins.
+    ///
+    /// If it is, the archive may be extracted while it is being written.
+    fn download_path(&self, _download_dir: &Path) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// A build artifact from a Taskcluster task.
+#[derive(Debug)]
+pub struct TaskclusterBuild<'a, T> {
+    tc: &'a mut T,
+    task_id: String,
+    artifact: String,
+}
+
+impl<'a, T> TaskclusterBuild<'a, T> {
+    /// Create a provider for the named artifact of the given task.
+    pub fn new(tc: &'a mut T, task_id: String, artifact: String) -> Self {
+        TaskclusterBuild {
+            tc,
+            task_id,
+            artifact,
+        }
+    }
+}
+
+#[async_trait]
+impl<'a, T> BuildProvider for TaskclusterBuild<'a, T>
+where
+    T: Taskcluster + Send,
+{
+    type Error = T::Error;
+
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, Self::Error> {
+        self.tc
+            .download_build_artifact(&self.task_id, &self.artifact, download_dir, progress)
+            .await
+    }
+
+    fn download_path(&self, download_dir: &Path) -> Option<PathBuf> {
+        // The name of the artifact is not known until a pattern is resolved.
+        if self.artifact.contains(['*', '?']) {
+            return None;
+        }
+
+        Some(download_dir.join(self.artifact.rsplit('/').next().unwrap()))
+    }
+}
+
+/// A build archive downloaded directly from an HTTP(S) URL.
+#[derive(Debug)]
+pub struct UrlBuild {
+    client: Client,
+    url: Url,
+}
+
+impl UrlBuild {
+    /// Create a provider for the build archive at the given URL.
+    pub fn new(url: &str) -> Result<Self, UrlBuildError> {
+        let url = Url::parse(url)?;
+
+        match url.scheme() {
+            "http" | "https" => {}
+            scheme => return Err(UrlBuildError::UnsupportedScheme(scheme.into())),
+        }
+
+        Ok(UrlBuild {
+            client: Client::new(),
+            url,
+        })
+    }
+}
+
+#[async_trait]
+impl BuildProvider for UrlBuild {
+    type Error = UrlBuildError;
+
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, UrlBuildError> {
+        let path = self.download_path(download_dir).unwrap();
+
+        download(&self.client, &self.url, &path, &progress).await?;
+
+        Ok(path)
+    }
+
+    fn download_path(&self, download_dir: &Path) -> Option<PathBuf> {
+        // Keep the archive's file name so that its extension is preserved.
+        Some(download_dir.join(file_name(&self.url).unwrap_or_else(|| "build".into())))
+    }
+}
+
+/// An official build downloaded from archive.mozilla.org.
+#[derive(Debug)]
+pub struct MozillaArchiveBuild {
+    client: Client,
+
+    /// The URL of the archive's `pub` directory.
+    base_url: Url,
+
+    platform: String,
+    locale: String,
+    build: OfficialBuild,
+}
+
+impl MozillaArchiveBuild {
+    /// Create a provider for the given official build.
+    pub fn new(
+        config: &MozillaArchiveConfig,
+        build: OfficialBuild,
+    ) -> Result<Self, MozillaArchiveError> {
+        let mut base_url = Url::parse(&config.url)?;
+
+        // Ensure that paths are joined onto the URL instead of replacing its
+        // last path segment.
+        if !base_url.path().ends_with('/') {
+            let path = format!("{}/", base_url.path());
+            base_url.set_path(&path);
+        }
+
+        if let OfficialBuild::Nightly { ref date } = build {
+            if !is_valid_date(date) {
+                return Err(MozillaArchiveError::InvalidDate(date.clone()));
+            }
+        }
+
+        Ok(MozillaArchiveBuild {
+            client: Client::new(),
+            base_url,
+            platform: config.platform.clone(),
+            locale: config.locale.clone(),
+            build,
+        })
+    }
+
+    /// Download the build for the given locale instead of the configured
+    /// one.
+    pub fn with_locale(mut self, locale: Option<&str>) -> Result<Self, MozillaArchiveError> {
+        if let Some(locale) = locale {
+            if !is_valid_locale(locale) {
+                return Err(MozillaArchiveError::InvalidLocale(locale.into()));
+            }
+
+            self.locale = locale.into();
+        }
+
+        Ok(self)
+    }
+
+    /// Find the URL of the build archive.
+    async fn find_build(&self) -> Result<Url, MozillaArchiveError> {
+        let dir = match self.build {
+            OfficialBuild::Release {
+                channel,
+                ref version,
+            } => self.base_url.join(&format!(
+                "{}/releases/{}/{}/{}/",
+                channel.product(),
+                version,
+                self.platform,
+                self.locale
+            ))?,
+
+            OfficialBuild::Nightly { ref date } => {
+                let month_url = self.base_url.join(&format!(
+                    "firefox/nightly/{}/{}/",
+                    &date[..4],
+                    &date[5..7]
+                ))?;
+
+                // Nightly directories are named after the time of the build,
+                // so the last one is the latest build of the day.
+                self.list_directory(&month_url)
+                    .await?
+                    .into_iter()
+                    .filter(|(name, _)| {
+                        name.starts_with(date.as_str()) && name.ends_with("-mozilla-central")
+                    })
+                    .max_by(|(a, _), (b, _)| a.cmp(b))
+                    .map(|(_, url)| url)
+                    .ok_or_else(|| MozillaArchiveError::NoNightly(date.clone()))?
+            }
+        };
+
+        let entries = self.list_directory(&dir).await?;
+        self.select_archive(&entries)
+            .ok_or(MozillaArchiveError::NoBuild(dir))
+    }
+
+    /// Select the build archive from the entries of a build directory.
+    ///
+    /// Archives are preferred over installers.
+    fn select_archive(&self, entries: &[(String, Url)]) -> Option<Url> {
+        let find = |matches: &dyn Fn(&str) -> bool| {
+            entries
+                .iter()
+                .find(|(name, _)| matches(name.as_str()))
+                .map(|(_, url)| url.clone())
+        };
+
+        let archive = ARCHIVE_EXTENSIONS.iter().find_map(|ext| match self.build {
+            OfficialBuild::Release { .. } => {
+                find(&|name| name.starts_with("firefox-") && name.ends_with(ext))
+            }
+            OfficialBuild::Nightly { .. } => {
+                let suffix = format!(".{}.{}{}", self.locale, self.platform, ext);
+                find(&|name| name.starts_with("firefox-") && name.ends_with(&suffix))
+            }
+        });
+
+        match (archive, &self.build) {
+            (Some(archive), _) => Some(archive),
+            (None, OfficialBuild::Release { version, .. }) => {
+                let installer = format!("Firefox Setup {}.exe", version);
+                find(&|name| name == installer)
+            }
+            (None, OfficialBuild::Nightly { .. }) => None,
+        }
+    }
+
+    /// Return the entries of the directory listing at the given URL.
+    async fn list_directory(&self, url: &Url) -> Result<Vec<(String, Url)>, MozillaArchiveError> {
+        let html = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(MozillaArchiveError::ListDirectory)?
+            .text()
+            .await
+            .map_err(MozillaArchiveError::ListDirectory)?;
+
+        Ok(parse_listing(url, &html))
+    }
+}
+
+#[async_trait]
+impl BuildProvider for MozillaArchiveBuild {
+    type Error = MozillaArchiveError;
+
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, MozillaArchiveError> {
+        let url = self.find_build().await?;
+        let path = download_dir.join(file_name(&url).unwrap());
+
+        download(&self.client, &url, &path, &progress).await?;
+
+        Ok(path)
+    }
+}
+
+/// A build archive that already exists on the runner.
+///
+/// The archive is used in place and is not removed when the session ends.
+#[derive(Debug)]
+pub struct PathBuild {
+    path: PathBuf,
+}
+
+impl PathBuild {
+    /// Create a provider for the build archive at the given path.
+    pub fn new(path: PathBuf) -> Self {
+        PathBuild { path }
+    }
+}
+
+#[async_trait]
+impl BuildProvider for PathBuild {
+    type Error = PathBuildError;
+
+    async fn fetch_build(
+        &mut self,
+        _download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, PathBuildError> {
+        let metadata =
+            tokio::fs::metadata(&self.path)
+                .await
+                .map_err(|source| PathBuildError::Metadata {
+                    path: self.path.clone(),
+                    source,
+                })?;
+
+        if !metadata.is_file() {
+            return Err(PathBuildError::NotAFile(self.path.clone()));
+        }
+
+        progress
+            .broadcast(DownloadProgress {
+                downloaded: metadata.len(),
+                total: Some(metadata.len()),
+            })
+            .ok();
+
+        Ok(self.path.clone())
+    }
+}
+
+/// A build archive that the recorder streams over the connection.
+#[derive(Debug)]
+pub struct UploadBuild<'a, R> {
+    reader: &'a mut R,
+    size: u64,
+    codec: Codec,
+}
+
+impl<'a, R> UploadBuild<'a, R> {
+    /// Create a provider that reads a build archive of the given size from
+    /// `reader`.
+    pub fn new(reader: &'a mut R, size: u64) -> Self {
+        UploadBuild {
+            reader,
+            size,
+            codec: Codec::default(),
+        }
+    }
+
+    /// Expect the build to be sent with the given codec.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+}
+
+#[async_trait]
+impl<'a, R> BuildProvider for UploadBuild<'a, R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    type Error = io::Error;
+
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, io::Error> {
+        let path = self.download_path(download_dir).unwrap();
+        let mut file = File::create(&path).await?;
+
+        let total = Some(self.size);
+
+        recv_payload(
+            self.reader,
+            &mut file,
+            self.size,
+            self.codec,
+            |downloaded| {
+                progress
+                    .broadcast(DownloadProgress { downloaded, total })
+                    .ok();
+            },
+        )
+        .await?;
+
+        Ok(path)
+    }
+
+    fn download_path(&self, download_dir: &Path) -> Option<PathBuf> {
+        Some(download_dir.join("uploaded_build"))
+    }
+}
+
+/// Download the file at the given URL to the given path.
+///
+/// The progress of the download is reported via `progress`.
+async fn download(
+    client: &Client,
+    url: &Url,
+    path: &Path,
+    progress: &watch::Sender<DownloadProgress>,
+) -> Result<(), UrlBuildError> {
+    let mut response = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(UrlBuildError::Request)?;
+
+    if !response.status().is_success() {
+        return Err(UrlBuildError::Status(response.status()));
+    }
+
+    let mut file = File::create(path).await?;
+
+    let mut current = DownloadProgress {
+        downloaded: 0,
+        total: response.content_length(),
+    };
+    // The receiver may have gone away, in which case nobody is interested in
+    // the progress.
+    progress.broadcast(current).ok();
+
+    while let Some(chunk) = response.chunk().await.map_err(UrlBuildError::Request)? {
+        file.write_all(&chunk).await?;
+
+        current.downloaded += chunk.len() as u64;
+        progress.broadcast(current).ok();
+    }
+
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Return the decoded name of the last path segment of the URL, if any.
+fn file_name(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.rfind(|s| !s.is_empty())?;
+
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|name| name.into_owned())
+}
+
+/// Parse the entries of an HTML directory listing.
+///
+/// Only links to the children of `dir` are returned, along with their names.
+fn parse_listing(dir: &Url, html: &str) -> Vec<(String, Url)> {
+    html.split("href=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .filter_map(|href| dir.join(href).ok())
+        .filter(|url| url.path().starts_with(dir.path()) && url.path() != dir.path())
+        .filter_map(|url| file_name(&url).map(|name| (name, url)))
+        .collect()
+}
+
+/// Return whether or not the date is of the form `YYYY-MM-DD`.
+fn is_valid_date(date: &str) -> bool {
+    let bytes = date.as_bytes();
+
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+#[derive(Debug, Error)]
+pub enum UrlBuildError {
+    #[error("could not parse URL: {}", .0)]
+    UrlParse(#[from] url::ParseError),
+
+    #[error("unsupported URL scheme `{}'", .0)]
+    UnsupportedScheme(String),
+
+    #[error("an error occurred while downloading the build: {}", .0)]
+    Request(#[source] reqwest::Error),
+
+    #[error("an error occurred while downloading the build: {}", .0)]
+    Status(StatusCode),
+
+    #[error("IO error: {}", .0)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum MozillaArchiveError {
+    #[error("could not parse URL: {}", .0)]
+    UrlParse(#[from] url::ParseError),
+
+    #[error("invalid date `{}': expected YYYY-MM-DD", .0)]
+    InvalidDate(String),
+
+    #[error("invalid locale `{}'", .0)]
+    InvalidLocale(String),
+
+    #[error("could not list directory: {}", .0)]
+    ListDirectory(#[source] reqwest::Error),
+
+    #[error("no nightly build was found for {}", .0)]
+    NoNightly(String),
+
+    #[error("no build was found in `{}'", .0)]
+    NoBuild(Url),
+
+    #[error(transparent)]
+    Download(#[from] UrlBuildError),
+}
+
+#[derive(Debug, Error)]
+pub enum PathBuildError {
+    #[error("Could not read build `{}': {}", .path.display(), .source)]
+    Metadata { path: PathBuf, source: io::Error },
+
+    #[error("Build `{}' is not a file", .0.display())]
+    NotAFile(PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use libfxrecord::net::Channel;
+    use mockito::mock;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn progress() -> watch::Sender<DownloadProgress> {
+        watch::channel(DownloadProgress::default()).0
+    }
+
+    #[tokio::test]
+    async fn test_url_build() {
+        let _m = mock("GET", "/builds/target.tar.bz2")
+            .with_body("build")
+            .create();
+
+        let download_dir = TempDir::new().unwrap();
+        let mut provider =
+            UrlBuild::new(&format!("{}/builds/target.tar.bz2", mockito::server_url())).unwrap();
+
+        let path = provider
+            .fetch_build(download_dir.path(), progress())
+            .await
+            .unwrap();
+        assert_eq!(path, download_dir.path().join("target.tar.bz2"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"build");
+
+        let _m = mock("GET", "/builds/missing.zip").with_status(404).create();
+        let mut provider =
+            UrlBuild::new(&format!("{}/builds/missing.zip", mockito::server_url())).unwrap();
+        assert_matches!(
+            provider.fetch_build(download_dir.path(), progress()).await,
+            Err(UrlBuildError::Status(StatusCode::NOT_FOUND))
+        );
+
+        assert_matches!(
+            UrlBuild::new("file:///C:/builds/target.zip"),
+            Err(UrlBuildError::UnsupportedScheme(ref scheme)) if scheme == "file"
+        );
+    }
+
+    fn mozilla_archive_config() -> MozillaArchiveConfig {
+        MozillaArchiveConfig {
+            url: format!("{}/pub", mockito::server_url()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_listing() {
+        let dir = Url::parse("https://archive.mozilla.org/pub/firefox/releases/80.0/win64/en-US/")
+            .unwrap();
+        let html = r#"
+            <tr><td><a href="/pub/firefox/releases/80.0/win64/">..</a></td></tr>
+            <tr><td><a href="/pub/firefox/releases/80.0/win64/en-US/Firefox%20Setup%2080.0.exe">Firefox Setup 80.0.exe</a></td></tr>
+            <tr><td><a href="firefox-80.0.zip">firefox-80.0.zip</a></td></tr>
+            <tr><td><a href="/pub/firefox/releases/80.0/win64/en-US/xpi/">xpi/</a></td></tr>
+        "#;
+
+        assert_eq!(
+            parse_listing(&dir, html)
+                .into_iter()
+                .map(|(name, url)| (name, String::from(url)))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "Firefox Setup 80.0.exe".into(),
+                    "https://archive.mozilla.org/pub/firefox/releases/80.0/win64/en-US/Firefox%20Setup%2080.0.exe".into()
+                ),
+                (
+                    "firefox-80.0.zip".into(),
+                    "https://archive.mozilla.org/pub/firefox/releases/80.0/win64/en-US/firefox-80.0.zip".into()
+                ),
+                (
+                    "xpi".into(),
+                    "https://archive.mozilla.org/pub/firefox/releases/80.0/win64/en-US/xpi/".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_valid_date() {
+        assert!(is_valid_date("2020-09-01"));
+
+        for date in &[
+            "2020-9-1",
+            "20200901",
+            "2020-09-01T00",
+            "../../etc",
+            "abcd-ef-gh",
+        ] {
+            assert!(!is_valid_date(date), "{}", date);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mozilla_archive_release() {
+        let _listing = mock("GET", "/pub/firefox/releases/80.0/win64/en-US/")
+            .with_body(
+                r#"<a href="/pub/firefox/releases/80.0/win64/en-US/Firefox%20Setup%2080.0.exe">Firefox Setup 80.0.exe</a>
+                <a href="/pub/firefox/releases/80.0/win64/en-US/Firefox%20Setup%2080.0.msi">Firefox Setup 80.0.msi</a>"#,
+            )
+            .create();
+        let _installer = mock(
+            "GET",
+            "/pub/firefox/releases/80.0/win64/en-US/Firefox%20Setup%2080.0.exe",
+        )
+        .with_body("installer")
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+        let mut provider = MozillaArchiveBuild::new(
+            &mozilla_archive_config(),
+            OfficialBuild::Release {
+                channel: Channel::Release,
+                version: "80.0".into(),
+            },
+        )
+        .unwrap();
+
+        let path = provider
+            .fetch_build(download_dir.path(), progress())
+            .await
+            .unwrap();
+        assert_eq!(path, download_dir.path().join("Firefox Setup 80.0.exe"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"installer");
+
+        let _listing = mock("GET", "/pub/devedition/releases/81.0b3/win64/en-US/")
+            .with_status(404)
+            .create();
+        let mut provider = MozillaArchiveBuild::new(
+            &mozilla_archive_config(),
+            OfficialBuild::Release {
+                channel: Channel::DevEdition,
+                version: "81.0b3".into(),
+            },
+        )
+        .unwrap();
+
+        assert_matches!(
+            provider.fetch_build(download_dir.path(), progress()).await,
+            Err(MozillaArchiveError::ListDirectory(ref e)) if e.status() == Some(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mozilla_archive_nightly() {
+        let _month = mock("GET", "/pub/firefox/nightly/2020/09/")
+            .with_body(
+                r#"<a href="/pub/firefox/nightly/2020/09/2020-09-01-09-45-49-mozilla-central/">2020-09-01-09-45-49-mozilla-central/</a>
+                <a href="/pub/firefox/nightly/2020/09/2020-09-01-21-42-11-mozilla-central/">2020-09-01-21-42-11-mozilla-central/</a>
+                <a href="/pub/firefox/nightly/2020/09/2020-09-01-21-42-11-mozilla-central-l10n/">2020-09-01-21-42-11-mozilla-central-l10n/</a>
+                <a href="/pub/firefox/nightly/2020/09/2020-09-02-09-33-45-mozilla-central/">2020-09-02-09-33-45-mozilla-central/</a>"#,
+            )
+            .create();
+        let _build_dir = mock("GET", "/pub/firefox/nightly/2020/09/2020-09-01-21-42-11-mozilla-central/")
+            .with_body(
+                r#"<a href="firefox-82.0a1.en-US.linux-x86_64.tar.bz2">firefox-82.0a1.en-US.linux-x86_64.tar.bz2</a>
+                <a href="firefox-82.0a1.en-US.win64.installer.exe">firefox-82.0a1.en-US.win64.installer.exe</a>
+                <a href="firefox-82.0a1.en-US.win64.zip">firefox-82.0a1.en-US.win64.zip</a>"#,
+            )
+            .create();
+        let _build = mock(
+            "GET",
+            "/pub/firefox/nightly/2020/09/2020-09-01-21-42-11-mozilla-central/firefox-82.0a1.en-US.win64.zip",
+        )
+        .with_body("nightly")
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+        let mut provider = MozillaArchiveBuild::new(
+            &mozilla_archive_config(),
+            OfficialBuild::Nightly {
+                date: "2020-09-01".into(),
+            },
+        )
+        .unwrap();
+
+        let path = provider
+            .fetch_build(download_dir.path(), progress())
+            .await
+            .unwrap();
+        assert_eq!(
+            path,
+            download_dir.path().join("firefox-82.0a1.en-US.win64.zip")
+        );
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"nightly");
+
+        let mut provider = MozillaArchiveBuild::new(
+            &mozilla_archive_config(),
+            OfficialBuild::Nightly {
+                date: "2020-09-03".into(),
+            },
+        )
+        .unwrap();
+        assert_matches!(
+            provider.fetch_build(download_dir.path(), progress()).await,
+            Err(MozillaArchiveError::NoNightly(ref date)) if date == "2020-09-03"
+        );
+
+        assert_matches!(
+            MozillaArchiveBuild::new(
+                &mozilla_archive_config(),
+                OfficialBuild::Nightly {
+                    date: "yesterday".into(),
+                },
+            ),
+            Err(MozillaArchiveError::InvalidDate(..))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_path_build() {
+        let tempdir = TempDir::new().unwrap();
+        let archive_path = tempdir.path().join("target.zip");
+        tokio::fs::write(&archive_path, b"build").await.unwrap();
+
+        assert_eq!(
+            PathBuild::new(archive_path.clone())
+                .fetch_build(tempdir.path(), progress())
+                .await
+                .unwrap(),
+            archive_path
+        );
+
+        assert_matches!(
+            PathBuild::new(tempdir.path().into())
+                .fetch_build(tempdir.path(), progress())
+                .await,
+            Err(PathBuildError::NotAFile(..))
+        );
+
+        assert_matches!(
+            PathBuild::new(tempdir.path().join("missing.zip"))
+                .fetch_build(tempdir.path(), progress())
+                .await,
+            Err(PathBuildError::Metadata { .. })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_build() {
+        let download_dir = TempDir::new().unwrap();
+
+        let mut reader: &[u8] = b"buildtrailing";
+        let path = UploadBuild::new(&mut reader, 5)
+            .fetch_build(download_dir.path(), progress())
+            .await
+            .unwrap();
+
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"build");
+        // Only the build is consumed from the stream.
+        assert_eq!(reader, b"trailing");
+
+        let mut reader: &[u8] = b"short";
+        assert_matches!(
+            UploadBuild::new(&mut reader, 10)
+                .fetch_build(download_dir.path(), progress())
+                .await,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof
+        );
+    }
+}
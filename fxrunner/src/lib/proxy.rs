@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Management of a local `mitmdump` instance for recording and replaying
+//! Firefox's network traffic.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use libfxrecord::net::ProxyMode;
+use libfxrecord::prefs::PrefValue;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::time::delay_for;
+
+use crate::config::ProxyConfig;
+use crate::fs::PathExt;
+
+/// The number of times to check if the proxy is listening before giving up.
+const LISTEN_ATTEMPTS: usize = 30;
+
+/// The name of the CA certificate that mitmdump generates in its confdir.
+const CA_CERT_NAME: &str = "mitmproxy-ca-cert.pem";
+
+/// A running record/replay proxy.
+///
+/// The proxy is killed if dropped without calling [`stop()`](#method.stop).
+pub struct Proxy {
+    child: Child,
+    port: u16,
+    ca_cert_path: PathBuf,
+}
+
+impl Proxy {
+    /// Start the proxy in the given mode.
+    ///
+    /// This will not return until the proxy is accepting connections.
+    pub async fn start(config: &ProxyConfig, mode: &ProxyMode) -> Result<Proxy, ProxyError> {
+        let archive_path = archive_path(&config.archive_dir, mode.archive())?;
+
+        let mut command = Command::new(&config.mitmdump_path);
+        command
+            .arg("--listen-host")
+            .arg("127.0.0.1")
+            .arg("--listen-port")
+            .arg(config.port.to_string())
+            .arg("--set")
+            .arg(format!("confdir={}", config.confdir.display()));
+
+        match mode {
+            ProxyMode::Record { .. } => {
+                command.arg("--save-stream-file").arg(&archive_path);
+            }
+
+            ProxyMode::Replay { .. } => {
+                if !archive_path.is_file_async().await {
+                    return Err(ProxyError::MissingArchive(archive_path));
+                }
+
+                command
+                    .arg("--server-replay")
+                    .arg(&archive_path)
+                    // Allow responses to be replayed more than once.
+                    .arg("--set")
+                    .arg("server_replay_nopop=true")
+                    // Never forward requests that are not in the archive.
+                    .arg("--set")
+                    .arg("server_replay_kill_extra=true")
+                    .arg("--set")
+                    .arg("upstream_cert=false");
+            }
+        }
+
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(ProxyError::Start)?;
+
+        let proxy = Proxy {
+            child,
+            port: config.port,
+            ca_cert_path: config.confdir.join(CA_CERT_NAME),
+        };
+
+        proxy.wait_for_listening().await?;
+
+        // mitmdump generates its CA certificate on first run if it does not
+        // already exist.
+        if !proxy.ca_cert_path.is_file_async().await {
+            return Err(ProxyError::MissingCertificate(proxy.ca_cert_path));
+        }
+
+        Ok(proxy)
+    }
+
+    /// Return the prefs required for Firefox to use the proxy.
+    pub fn prefs(&self) -> Vec<(String, PrefValue)> {
+        http_proxy_prefs(self.port)
+    }
+
+    /// Return the address the proxy is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, self.port))
+    }
+
+    /// Return the path to the proxy's CA certificate.
+    ///
+    /// Firefox must trust this certificate for the proxy to intercept TLS
+    /// connections.
+    pub fn ca_cert_path(&self) -> &Path {
+        &self.ca_cert_path
+    }
+
+    /// Stop the proxy.
+    pub async fn stop(mut self) -> Result<(), io::Error> {
+        self.child.kill()?;
+        self.child.await.map(drop)
+    }
+
+    /// Wait for the proxy to begin accepting connections.
+    async fn wait_for_listening(&self) -> Result<(), ProxyError> {
+        let addr = self.addr();
+
+        for _ in 0..LISTEN_ATTEMPTS {
+            if TcpStream::connect(&addr).await.is_ok() {
+                return Ok(());
+            }
+
+            delay_for(Duration::from_millis(500)).await;
+        }
+
+        Err(ProxyError::Timeout)
+    }
+}
+
+/// Return the prefs required for Firefox to use an HTTP proxy listening on
+/// localhost at the given port.
+pub fn http_proxy_prefs(port: u16) -> Vec<(String, PrefValue)> {
+    let port = i64::from(port);
+
+    vec![
+        // Manual proxy configuration.
+        ("network.proxy.type".into(), 1i64.into()),
+        ("network.proxy.http".into(), "127.0.0.1".into()),
+        ("network.proxy.http_port".into(), port.into()),
+        ("network.proxy.ssl".into(), "127.0.0.1".into()),
+        ("network.proxy.ssl_port".into(), port.into()),
+        ("network.proxy.no_proxies_on".into(), "".into()),
+        (
+            "network.proxy.allow_hijacking_localhost".into(),
+            true.into(),
+        ),
+    ]
+}
+
+/// Return the path to the archive with the given name.
+///
+/// Archive names must be plain file names so that they cannot refer to files
+/// outside of the archive directory.
+fn archive_path(archive_dir: &Path, name: &str) -> Result<PathBuf, ProxyError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Ok(archive_dir.join(name))
+    } else {
+        Err(ProxyError::InvalidArchiveName(name.into()))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("The runner is not configured with a proxy")]
+    NotConfigured,
+
+    #[error("Invalid archive name `{}'", .0)]
+    InvalidArchiveName(String),
+
+    #[error("Archive `{}' does not exist", .0.display())]
+    MissingArchive(PathBuf),
+
+    #[error("Could not start mitmdump: {}", .0)]
+    Start(#[source] io::Error),
+
+    #[error("Timed out waiting for the proxy to accept connections")]
+    Timeout,
+
+    #[error("Proxy CA certificate `{}' does not exist", .0.display())]
+    MissingCertificate(PathBuf),
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    #[test]
+    fn test_archive_path() {
+        let archive_dir = Path::new("archives");
+
+        assert_eq!(
+            archive_path(archive_dir, "example.com.mitm").unwrap(),
+            archive_dir.join("example.com.mitm")
+        );
+
+        for name in &["", ".hidden", "../escape", "nested/archive", "C:\\archive"] {
+            assert_matches!(
+                archive_path(archive_dir, name),
+                Err(ProxyError::InvalidArchiveName(ref n)) => {
+                    assert_eq!(n, name);
+                }
+            );
+        }
+    }
+}
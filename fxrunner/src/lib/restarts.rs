@@ -0,0 +1,116 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A persistent log of the restarts that the runner initiates, so that
+//! operators can explain why a shared machine rebooted.
+//!
+//! The log is kept in the session directory as one JSON record per line.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libfxrecord::net::RestartRecord;
+use thiserror::Error;
+use tokio::fs::{read_to_string, OpenOptions};
+use tokio::prelude::*;
+
+/// The name of the restart log within the session directory.
+pub const RESTART_LOG_NAME: &str = "restarts.jsonl";
+
+/// Append a record to the restart log in the given session directory.
+///
+/// The log is synced to disk before returning, as the machine is about to
+/// restart.
+pub async fn append_restart_record(
+    session_dir: &Path,
+    record: &RestartRecord,
+) -> Result<(), RestartLogError> {
+    let path = session_dir.join(RESTART_LOG_NAME);
+
+    let mut line = serde_json::to_vec(record).expect("could not serialize restart record");
+    line.push(b'\n');
+
+    let io_err = |source| RestartLogError::Io {
+        path: path.clone(),
+        source,
+    };
+
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .map_err(io_err)?;
+
+    f.write_all(&line).await.map_err(io_err)?;
+    f.sync_all().await.map_err(io_err)?;
+
+    Ok(())
+}
+
+/// Read every record from the restart log in the given session directory,
+/// oldest first.
+pub async fn read_restart_log(session_dir: &Path) -> Result<Vec<RestartRecord>, RestartLogError> {
+    let path = session_dir.join(RESTART_LOG_NAME);
+
+    let contents = match read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(source) => return Err(RestartLogError::Io { path, source }),
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line).map_err(|source| RestartLogError::Parse {
+                path: path.clone(),
+                source,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum RestartLogError {
+    #[error("Could not access restart log `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Could not parse restart log `{}': {}", .path.display(), .source)]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use libfxrecord::net::RestartResult;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_restart_log() {
+        let tempdir = TempDir::new().unwrap();
+
+        assert!(read_restart_log(tempdir.path()).await.unwrap().is_empty());
+
+        let records = vec![
+            RestartRecord::new("reason", "session1", RestartResult::Scheduled),
+            RestartRecord::new("reason", "session1", RestartResult::Cancelled),
+            RestartRecord::new(
+                "reason",
+                "session2",
+                RestartResult::Failed("access denied".into()),
+            ),
+        ];
+
+        for record in &records {
+            append_restart_record(tempdir.path(), record).await.unwrap();
+        }
+
+        assert_eq!(read_restart_log(tempdir.path()).await.unwrap(), records);
+    }
+}
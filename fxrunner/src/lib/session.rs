@@ -8,6 +8,7 @@ use std::iter;
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
+use libfxrecord::net::RestartRecord;
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 use scopeguard::{guard, ScopeGuard};
@@ -16,9 +17,22 @@ use thiserror::Error;
 use tokio::fs::create_dir;
 
 use crate::fs::PathExt;
+use crate::profile_cache::{ProfileCache, PROFILE_CACHE_NAME};
+use crate::restarts::{append_restart_record, read_restart_log, RestartLogError};
+use crate::store::{Store, STORE_NAME};
 
 const REQUEST_ID_LEN: usize = 32;
 
+/// The name of the Firefox executable within the `firefox` directory of a
+/// build.
+#[cfg(windows)]
+pub const FIREFOX_BIN: &str = "firefox.exe";
+
+/// The name of the Firefox executable within the `firefox` directory of a
+/// build.
+#[cfg(not(windows))]
+pub const FIREFOX_BIN: &str = "firefox";
+
 #[derive(Clone)]
 pub struct SessionInfo<'a> {
     pub id: Cow<'a, str>,
@@ -27,11 +41,16 @@ pub struct SessionInfo<'a> {
 
 impl<'a> SessionInfo<'a> {
     pub fn firefox_path(&self) -> PathBuf {
-        self.path.join("firefox").join("firefox.exe")
+        self.path.join("firefox").join(FIREFOX_BIN)
     }
     pub fn profile_path(&self) -> PathBuf {
         self.path.join("profile")
     }
+
+    /// The path to the runner's log of the session.
+    pub fn log_path(&self) -> PathBuf {
+        self.path.join("fxrunner.log")
+    }
 }
 
 /// A trait for creating and validating session.
@@ -55,6 +74,20 @@ pub trait SessionManager {
         &self,
         session_info: &SessionInfo<'a>,
     ) -> Result<PathBuf, io::Error>;
+
+    /// Record a restart in the restart log.
+    async fn record_restart(&self, record: &RestartRecord) -> Result<(), RestartLogError>;
+
+    /// Return every restart in the restart log, oldest first.
+    async fn restart_log(&self) -> Result<Vec<RestartRecord>, RestartLogError>;
+
+    /// Return the store of files that are kept across sessions.
+    fn store(&self) -> Store;
+
+    /// Return the cache of the last profile received from the recorder.
+    ///
+    /// The cache is kept across sessions.
+    fn profile_cache(&self) -> ProfileCache;
 }
 
 pub struct DefaultSessionManager {
@@ -168,6 +201,22 @@ impl SessionManager for DefaultSessionManager {
         create_dir(&profile_path).await?;
         Ok(profile_path)
     }
+
+    async fn record_restart(&self, record: &RestartRecord) -> Result<(), RestartLogError> {
+        append_restart_record(&self.path, record).await
+    }
+
+    async fn restart_log(&self) -> Result<Vec<RestartRecord>, RestartLogError> {
+        read_restart_log(&self.path).await
+    }
+
+    fn store(&self) -> Store {
+        Store::new(self.path.join(STORE_NAME))
+    }
+
+    fn profile_cache(&self) -> ProfileCache {
+        ProfileCache::new(self.path.join(PROFILE_CACHE_NAME), self.store())
+    }
 }
 
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Staging archives uploaded by the recorder before they are used.
+//!
+//! Uploads are extracted into a directory within the session's `staging`
+//! directory and only moved into place once extraction has succeeded. A
+//! failed extraction is removed with its staging directory, so it never leaves
+//! half-written files where the session would use them.
+
+use std::fs::{create_dir_all, remove_dir_all, rename};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::zip::ZipError;
+
+/// The name of the directory within a session's directory that uploads are
+/// staged in.
+pub const STAGING_NAME: &str = "staging";
+
+/// A directory that an upload is extracted into before it is moved to its
+/// target.
+///
+/// The directory is removed when it is dropped, unless it was committed.
+#[derive(Debug)]
+pub struct StagingDir {
+    path: PathBuf,
+    target: PathBuf,
+}
+
+impl StagingDir {
+    /// Create an empty staging directory for `target` within the session
+    /// directory `session_dir`.
+    ///
+    /// Anything left behind by an earlier attempt is removed. The target must
+    /// be within the session directory, so that committing is a rename on the
+    /// same volume.
+    pub fn new(session_dir: &Path, target: &Path) -> Result<Self, StagingError> {
+        let name = target.file_name().expect("staging target has no file name");
+        let path = session_dir.join(STAGING_NAME).join(name);
+
+        if path.exists() {
+            remove_dir_all(&path).map_err(|source| StagingError::Create {
+                path: path.clone(),
+                source,
+            })?;
+        }
+
+        create_dir_all(&path).map_err(|source| StagingError::Create {
+            path: path.clone(),
+            source,
+        })?;
+
+        Ok(StagingDir {
+            path,
+            target: target.into(),
+        })
+    }
+
+    /// The path of the staging directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the contents of the staging directory to the target.
+    pub fn commit(self) -> Result<(), StagingError> {
+        let path = self.path.clone();
+        self.commit_from(&path)
+    }
+
+    /// Move the directory `subdir`, relative to the staging directory, to the
+    /// target.
+    ///
+    /// Anything else in the staging directory is removed.
+    pub fn commit_subdir(self, subdir: &Path) -> Result<(), StagingError> {
+        let source = self.path.join(subdir);
+        self.commit_from(&source)
+    }
+
+    fn commit_from(self, source: &Path) -> Result<(), StagingError> {
+        // Replacing the target would leave it half-removed if the rename
+        // failed, so an existing target is an error for the caller to handle.
+        if self.target.exists() {
+            return Err(StagingError::Exists(self.target.clone()));
+        }
+
+        rename(source, &self.target).map_err(|error| StagingError::Commit {
+            target: self.target.clone(),
+            source: error,
+        })
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        // Nothing is left to remove if the whole directory was committed.
+        if self.path.exists() {
+            let _ = remove_dir_all(&self.path);
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StagingError {
+    #[error("Could not create staging directory `{}': {}", .path.display(), .source)]
+    Create { path: PathBuf, source: io::Error },
+
+    #[error("Refusing to replace existing `{}'", .0.display())]
+    Exists(PathBuf),
+
+    #[error("Could not move staged files to `{}': {}", .target.display(), .source)]
+    Commit { target: PathBuf, source: io::Error },
+
+    #[error(transparent)]
+    Zip(#[from] ZipError),
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, read_to_string, write};
+
+    use assert_matches::assert_matches;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_staging_dir() {
+        let session_dir = TempDir::new().unwrap();
+        let target = session_dir.path().join("profile");
+
+        // Leftovers from an earlier attempt are removed.
+        let leftover = session_dir.path().join(STAGING_NAME).join("profile");
+        create_dir_all(&leftover).unwrap();
+        write(leftover.join("partial.js"), "partial").unwrap();
+
+        let staging = StagingDir::new(session_dir.path(), &target).unwrap();
+        assert_eq!(staging.path(), leftover);
+        assert!(!leftover.join("partial.js").exists());
+
+        // Dropping an uncommitted directory removes it.
+        write(staging.path().join("prefs.js"), "prefs").unwrap();
+        drop(staging);
+        assert!(!leftover.exists());
+        assert!(!target.exists());
+
+        let staging = StagingDir::new(session_dir.path(), &target).unwrap();
+        write(staging.path().join("prefs.js"), "prefs").unwrap();
+        staging.commit().unwrap();
+        assert_eq!(read_to_string(target.join("prefs.js")).unwrap(), "prefs");
+        assert!(!leftover.exists());
+
+        let staging = StagingDir::new(session_dir.path(), &target).unwrap();
+        assert_matches!(staging.commit(), Err(StagingError::Exists(ref path)) if *path == target);
+        assert!(!leftover.exists());
+    }
+
+    #[test]
+    fn test_staging_dir_commit_subdir() {
+        let session_dir = TempDir::new().unwrap();
+        let target = session_dir.path().join("profile");
+
+        let staging = StagingDir::new(session_dir.path(), &target).unwrap();
+        create_dir(staging.path().join("nested")).unwrap();
+        write(staging.path().join("nested").join("prefs.js"), "prefs").unwrap();
+        write(staging.path().join("stray.txt"), "stray").unwrap();
+
+        staging.commit_subdir(Path::new("nested")).unwrap();
+        assert_eq!(read_to_string(target.join("prefs.js")).unwrap(), "prefs");
+        assert!(!session_dir
+            .path()
+            .join(STAGING_NAME)
+            .join("profile")
+            .exists());
+    }
+}
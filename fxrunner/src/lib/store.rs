@@ -0,0 +1,421 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A content-addressed store of files that are kept across sessions.
+//!
+//! Files are stored as blobs named by the SHA-256 of their contents, so a file
+//! that is stored many times (e.g., an unchanged file in a profile sent for
+//! every session) is only kept once. Blobs are never modified once they are
+//! stored.
+//!
+//! Blobs are kept alive by refs, which map a name to the hash of a blob, and
+//! by the hashes given to [`prune`](struct.Store.html#method.prune).
+//!
+//! Every write goes to a uniquely named temporary file that is then renamed
+//! into place, so readers never see a partially written blob or ref, even if
+//! several writers store the same file at once.
+
+use std::collections::BTreeSet;
+use std::fs::{self, create_dir_all, read_dir, File};
+use std::io::{self, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use libfxrecord::{hex, sha256_hex};
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// The name of the store in the session directory.
+pub const STORE_NAME: &str = "store";
+
+/// The length of the random suffix of temporary files.
+const TEMP_SUFFIX_LEN: usize = 16;
+
+/// A content-addressed store of files.
+#[derive(Clone, Debug)]
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    /// Create a handle to the store at the given path.
+    ///
+    /// The directory is created when the first blob is stored.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Store { path: path.into() }
+    }
+
+    /// Copy the file at the given path into the store, returning its hash.
+    pub fn insert(&self, source: &Path) -> Result<String, StoreError> {
+        let mut reader = File::open(source).map_err(|e| StoreError::io(source, e))?;
+        let (temp_path, temp) = self.create_temp()?;
+
+        let mut writer = HashingWriter {
+            inner: temp,
+            hasher: Sha256::new(),
+        };
+
+        if let Err(e) = io::copy(&mut reader, &mut writer) {
+            fs::remove_file(&temp_path).ok();
+            return Err(StoreError::io(source, e));
+        }
+
+        let hash = hex(&writer.hasher.finalize());
+        drop(writer.inner);
+
+        self.commit(&temp_path, &hash)?;
+        Ok(hash)
+    }
+
+    /// Move the file at the given path into the store, returning its hash.
+    ///
+    /// The file must not be modified while it is being stored.
+    pub fn insert_move(&self, source: &Path) -> Result<String, StoreError> {
+        let hash = {
+            let mut reader = File::open(source).map_err(|e| StoreError::io(source, e))?;
+            let mut hasher = Sha256::new();
+            io::copy(&mut reader, &mut hasher).map_err(|e| StoreError::io(source, e))?;
+            hex(&hasher.finalize())
+        };
+
+        // The source may be on another volume, in which case it has to be
+        // copied instead.
+        if self.commit(source, &hash).is_err() {
+            self.insert(source)?;
+            fs::remove_file(source).map_err(|e| StoreError::io(source, e))?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Return the path of the blob with the given hash, if it is stored.
+    ///
+    /// The blob must not be modified.
+    pub fn get(&self, hash: &str) -> Option<PathBuf> {
+        if !is_valid_hash(hash) {
+            return None;
+        }
+
+        Some(self.blob_path(hash)).filter(|path| path.is_file())
+    }
+
+    /// Copy the blob with the given hash to `dest`.
+    pub fn copy_to(&self, hash: &str, dest: &Path) -> Result<(), StoreError> {
+        let blob = self
+            .get(hash)
+            .ok_or_else(|| StoreError::MissingBlob(hash.into()))?;
+
+        fs::copy(&blob, dest)
+            .map(drop)
+            .map_err(|e| StoreError::io(dest, e))
+    }
+
+    /// Point the ref with the given name in `namespace` at a stored blob.
+    ///
+    /// Namespaces are chosen by the runner and must be valid file names. Ref
+    /// names may be anything.
+    pub fn set_ref(&self, namespace: &str, name: &str, hash: &str) -> Result<(), StoreError> {
+        if self.get(hash).is_none() {
+            return Err(StoreError::MissingBlob(hash.into()));
+        }
+
+        let ref_dir = self.path.join("refs").join(namespace);
+        create_dir_all(&ref_dir).map_err(|e| StoreError::io(&ref_dir, e))?;
+
+        let (temp_path, mut temp) = self.create_temp()?;
+        let result = temp.write_all(hash.as_bytes());
+        drop(temp);
+
+        let ref_path = ref_dir.join(ref_file_name(name));
+        if let Err(e) = result.and_then(|()| fs::rename(&temp_path, &ref_path)) {
+            fs::remove_file(&temp_path).ok();
+            return Err(StoreError::io(&ref_path, e));
+        }
+
+        Ok(())
+    }
+
+    /// Return the hash of the blob that the ref with the given name in
+    /// `namespace` points at, if the ref exists and the blob is stored.
+    pub fn get_ref(&self, namespace: &str, name: &str) -> Result<Option<String>, StoreError> {
+        let ref_path = self
+            .path
+            .join("refs")
+            .join(namespace)
+            .join(ref_file_name(name));
+
+        let hash = match fs::read_to_string(&ref_path) {
+            Ok(hash) => hash,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StoreError::io(&ref_path, e)),
+        };
+
+        Ok(Some(hash).filter(|hash| self.get(hash).is_some()))
+    }
+
+    /// Remove all but the `keep` most recently set refs in `namespace`.
+    pub fn trim_refs(&self, namespace: &str, keep: usize) -> Result<(), StoreError> {
+        let ref_dir = self.path.join("refs").join(namespace);
+        let mut refs = Vec::new();
+
+        for ref_path in list_dir(&ref_dir)? {
+            let modified = fs::metadata(&ref_path)
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            refs.push((modified, ref_path));
+        }
+
+        refs.sort();
+        let remove = refs.len().saturating_sub(keep);
+
+        for (_, ref_path) in refs.into_iter().take(remove) {
+            fs::remove_file(&ref_path).map_err(|e| StoreError::io(&ref_path, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove every blob that is not pointed at by a ref or listed in `keep`,
+    /// returning the number of blobs removed.
+    ///
+    /// This must not be called while a session is using the store.
+    pub fn prune(&self, keep: &BTreeSet<String>) -> Result<usize, StoreError> {
+        let mut live = keep.clone();
+
+        for ref_dir in list_dir(&self.path.join("refs"))? {
+            for ref_path in list_dir(&ref_dir)? {
+                let hash =
+                    fs::read_to_string(&ref_path).map_err(|e| StoreError::io(&ref_path, e))?;
+                live.insert(hash);
+            }
+        }
+
+        let mut removed = 0;
+        for blob_dir in list_dir(&self.path.join("blobs"))? {
+            for blob in list_dir(&blob_dir)? {
+                let is_live = blob
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|hash| live.contains(hash))
+                    .unwrap_or(false);
+
+                if !is_live {
+                    fs::remove_file(&blob).map_err(|e| StoreError::io(&blob, e))?;
+                    removed += 1;
+                }
+            }
+        }
+
+        // Temporary files are left behind by writers that were interrupted.
+        for temp in list_dir(&self.path.join("tmp"))? {
+            fs::remove_file(&temp).map_err(|e| StoreError::io(&temp, e))?;
+        }
+
+        Ok(removed)
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.path.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    /// Create a uniquely named temporary file in the store.
+    fn create_temp(&self) -> Result<(PathBuf, File), StoreError> {
+        let temp_dir = self.path.join("tmp");
+        create_dir_all(&temp_dir).map_err(|e| StoreError::io(&temp_dir, e))?;
+
+        let name = {
+            let mut rng = thread_rng();
+            iter::repeat(())
+                .map(|_| rng.sample(Alphanumeric))
+                .take(TEMP_SUFFIX_LEN)
+                .collect::<String>()
+        };
+
+        let temp_path = temp_dir.join(name);
+        let temp = File::create(&temp_path).map_err(|e| StoreError::io(&temp_path, e))?;
+
+        Ok((temp_path, temp))
+    }
+
+    /// Move the file at `source`, whose contents have the given hash, into
+    /// place as a blob.
+    fn commit(&self, source: &Path, hash: &str) -> Result<(), StoreError> {
+        let blob = self.blob_path(hash);
+
+        // Blobs with the same hash have the same contents, so whichever
+        // writer stores the blob first wins.
+        if blob.is_file() {
+            fs::remove_file(source).ok();
+            return Ok(());
+        }
+
+        let blob_dir = blob.parent().unwrap();
+        create_dir_all(blob_dir).map_err(|e| StoreError::io(blob_dir, e))?;
+
+        if let Err(e) = fs::rename(source, &blob) {
+            // Another writer may have stored the blob in the meantime.
+            if blob.is_file() {
+                fs::remove_file(source).ok();
+                return Ok(());
+            }
+
+            return Err(StoreError::io(&blob, e));
+        }
+
+        Ok(())
+    }
+}
+
+/// The name of the file that holds the ref with the given name.
+///
+/// Ref names are hashed so that they cannot escape the refs directory.
+fn ref_file_name(name: &str) -> String {
+    sha256_hex(name.as_bytes())
+}
+
+/// Whether or not the string is a hex-encoded SHA-256 hash, as used to name
+/// blobs.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Return the paths of the entries in the given directory, or nothing if it
+/// does not exist.
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>, StoreError> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StoreError::io(dir, e)),
+    };
+
+    entries
+        .map(|entry| {
+            entry
+                .map(|entry| entry.path())
+                .map_err(|e| StoreError::io(dir, e))
+        })
+        .collect()
+}
+
+/// A writer that hashes everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("IO error in store at `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("No blob `{}' in store", .0)]
+    MissingBlob(String),
+}
+
+impl StoreError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        StoreError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{read_to_string, write};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// The SHA-256 of `contents`.
+    const CONTENTS_HASH: &str = "d1b2a59fbea7e20077af9f91b27e95e865061b270be03ff539ab3b73587882e8";
+
+    #[test]
+    fn test_store() {
+        let tempdir = TempDir::new().unwrap();
+        let store = Store::new(tempdir.path().join(STORE_NAME));
+
+        let source = tempdir.path().join("source");
+        write(&source, "contents").unwrap();
+
+        assert_eq!(store.insert(&source).unwrap(), CONTENTS_HASH);
+        assert!(source.is_file());
+
+        // Storing the same contents again does not duplicate the blob.
+        assert_eq!(store.insert_move(&source).unwrap(), CONTENTS_HASH);
+        assert!(!source.exists());
+        assert_eq!(
+            list_dir(&tempdir.path().join(STORE_NAME).join("blobs").join("d1"))
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let dest = tempdir.path().join("dest");
+        store.copy_to(CONTENTS_HASH, &dest).unwrap();
+        assert_eq!(read_to_string(&dest).unwrap(), "contents");
+
+        assert!(store.get("../../source").is_none());
+        assert!(store.get(&"0".repeat(64)).is_none());
+        assert!(store.copy_to(&"0".repeat(64), &dest).is_err());
+    }
+
+    #[test]
+    fn test_store_refs() {
+        let tempdir = TempDir::new().unwrap();
+        let store = Store::new(tempdir.path().join(STORE_NAME));
+
+        let mut hashes = Vec::new();
+        for i in 0..3 {
+            let source = tempdir.path().join(i.to_string());
+            write(&source, i.to_string()).unwrap();
+            hashes.push(store.insert(&source).unwrap());
+        }
+
+        assert_eq!(store.get_ref("builds", "../foo").unwrap(), None);
+        assert!(store.set_ref("builds", "foo", &"0".repeat(64)).is_err());
+
+        store.set_ref("builds", "../foo", &hashes[0]).unwrap();
+        store.set_ref("builds", "bar", &hashes[1]).unwrap();
+        assert_eq!(
+            store.get_ref("builds", "../foo").unwrap().as_ref(),
+            Some(&hashes[0])
+        );
+
+        // Blobs that are neither referenced nor kept are removed.
+        let keep = iter::once(hashes[2].clone()).collect();
+        assert_eq!(store.prune(&keep).unwrap(), 0);
+        assert_eq!(store.prune(&BTreeSet::new()).unwrap(), 1);
+        assert!(store.get(&hashes[2]).is_none());
+
+        store.trim_refs("builds", 1).unwrap();
+        assert_eq!(
+            list_dir(&tempdir.path().join(STORE_NAME).join("refs").join("builds"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(store.prune(&BTreeSet::new()).unwrap(), 1);
+    }
+}
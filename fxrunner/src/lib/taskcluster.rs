@@ -3,21 +3,48 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::error::Error;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use futures::future::try_join_all;
 use futures::prelude::*;
 use futures::try_join;
+use libfxrecord::glob::glob_matches;
+use libfxrecord::hex;
+use libfxrecord::net::DownloadProgress;
+use libfxrecord::retry::{retry, RetryError};
+use libfxrecord::secret::SecretError;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_RANGE, RANGE};
 use reqwest::{Client, StatusCode, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tokio::fs::File;
+use tokio::fs::{remove_file, File, OpenOptions};
 use tokio::prelude::*;
+use tokio::sync::watch;
+use tokio::task::spawn_blocking;
+use tokio::time::delay_for;
 
-/// The name of the artifact containing the result of a build job.
+use crate::config::{RetryConfig, TaskclusterConfig, TaskclusterCredentials};
+
+/// The default name of the artifact containing the result of a build job.
 pub const BUILD_ARTIFACT_NAME: &str = "public/build/target.zip";
 
+/// The root URL of Firefox CI.
+pub const FIREFOX_CI_ROOT_URL: &str = "https://firefox-ci-tc.services.mozilla.com/";
+
+/// How often the artifacts of a task are listed while waiting for its build
+/// artifact to be uploaded.
+const ARTIFACT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The headers that storage backends use to report the SHA-256 of an artifact.
+const CONTENT_SHA256_HEADERS: &[&str] =
+    &["x-amz-meta-content-sha256", "x-goog-meta-content-sha256"];
+
 /// An error from Firefox CI.
 #[derive(Debug, Error)]
 pub enum FirefoxCiError {
@@ -28,6 +55,12 @@ pub enum FirefoxCiError {
     #[error("could not parse URL: {}", .0)]
     UrlParse(#[from] url::ParseError),
 
+    #[error("could not create HTTP client: {}", .0)]
+    BuildClient(#[source] reqwest::Error),
+
+    #[error("could not find indexed task: {}", .0)]
+    FindTask(#[source] reqwest::Error),
+
     #[error("could not list artifacts: {}", .0)]
     ListArtifacts(#[source] reqwest::Error),
 
@@ -36,47 +69,217 @@ pub enum FirefoxCiError {
 
     #[error("an error occurred while downloading the artifact: {}", .0)]
     StatusError(StatusCode),
+
+    #[error("could not sign request: {}", .0)]
+    Sign(#[source] hawk::Error),
+
+    #[error("could not read credentials: {}", .0)]
+    Secret(#[from] SecretError),
+
+    #[error(
+        "downloaded artifact is corrupt: expected SHA-256 {}, got {}",
+        .expected,
+        .actual
+    )]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("task `{}' has no artifact matching `{}'", .task_id, .pattern)]
+    NoMatchingArtifact { task_id: String, pattern: String },
+
+    #[error("multiple artifacts match `{}': {}", .pattern, .matches.join(", "))]
+    AmbiguousArtifact {
+        pattern: String,
+        matches: Vec<String>,
+    },
+}
+
+impl FirefoxCiError {
+    /// Return whether or not the error is likely to be transient, i.e., the
+    /// request may succeed if retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            FirefoxCiError::FindTask(e)
+            | FirefoxCiError::ListArtifacts(e)
+            | FirefoxCiError::DownloadArtifact(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+            }
+            FirefoxCiError::StatusError(status) => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            // The corrupt download is removed, so a retry starts from scratch.
+            FirefoxCiError::ChecksumMismatch { .. } => true,
+            FirefoxCiError::Io(..)
+            | FirefoxCiError::UrlParse(..)
+            | FirefoxCiError::Sign(..)
+            | FirefoxCiError::Secret(..)
+            | FirefoxCiError::BuildClient(..)
+            | FirefoxCiError::NoMatchingArtifact { .. }
+            | FirefoxCiError::AmbiguousArtifact { .. } => false,
+        }
+    }
+
+    /// Return whether or not the error is due to an artifact that has not
+    /// been uploaded yet.
+    fn is_missing_artifact(&self) -> bool {
+        match self {
+            FirefoxCiError::NoMatchingArtifact { .. } => true,
+            // The task has no runs yet, so it has no artifacts to list.
+            FirefoxCiError::StatusError(status) => *status == StatusCode::NOT_FOUND,
+            _ => false,
+        }
+    }
 }
 
 #[async_trait]
 pub trait Taskcluster: Debug {
     type Error: Error + 'static;
 
+    /// Download the named build artifact from the given task.
+    ///
+    /// The artifact name may be a glob pattern, in which case it must match
+    /// exactly one of the task's artifacts. The artifact is saved in
+    /// `download_dir` under the last component of its name.
+    ///
+    /// The progress of the download is reported via `progress`.
     async fn download_build_artifact(
         &mut self,
         task_id: &str,
+        artifact: &str,
         download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
     ) -> Result<PathBuf, Self::Error>;
+
+    /// Return the ID of the task indexed at the given route.
+    async fn find_task(&mut self, route: &str) -> Result<String, Self::Error>;
 }
 
 /// An API client to download Taskcluster build artifacts.
-#[derive(Debug)]
+///
+/// Despite the name, the client can target any Taskcluster deployment.
 pub struct FirefoxCi {
     /// The reqwest Client used for all requests.
     client: Client,
 
     /// The URL for the Taskcluster Queue API.
     queue_url: Url,
+
+    /// The URL for the Taskcluster Index API.
+    index_url: Url,
+
+    /// Credentials used to sign requests, if any.
+    ///
+    /// Without credentials, only public artifacts can be downloaded.
+    credentials: Option<hawk::Credentials>,
+
+    /// How to retry failed requests.
+    retry: RetryConfig,
+
+    /// The number of connections used to download an artifact.
+    connections: u32,
+
+    /// How long to wait for a task to upload its build artifact, if at all.
+    artifact_wait: Option<Duration>,
+
+    /// How often to list the artifacts of a task while waiting.
+    artifact_poll_interval: Duration,
+}
+
+impl Debug for FirefoxCi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only the client ID of the credentials is logged, never the key.
+        f.debug_struct("FirefoxCi")
+            .field("queue_url", &self.queue_url)
+            .field("index_url", &self.index_url)
+            .field(
+                "client_id",
+                &self.credentials.as_ref().map(|credentials| &credentials.id),
+            )
+            .field("retry", &self.retry)
+            .field("connections", &self.connections)
+            .field("artifact_wait", &self.artifact_wait)
+            .field("artifact_poll_interval", &self.artifact_poll_interval)
+            .finish()
+    }
 }
 
 impl Default for FirefoxCi {
     fn default() -> Self {
-        FirefoxCi {
-            queue_url: Url::parse("https://firefox-ci-tc.services.mozilla.com/api/queue/v1/")
-                .unwrap(),
-            client: Client::new(),
-        }
+        FirefoxCi::with_root_url(Url::parse(FIREFOX_CI_ROOT_URL).unwrap())
     }
 }
 
 impl FirefoxCi {
-    #[cfg(test)]
-    pub(crate) fn with_queue_url(queue_url: Url) -> Self {
+    /// Create a client from the given configuration.
+    ///
+    /// If credentials are available, requests will be authenticated with
+    /// them.
+    pub fn new(config: &TaskclusterConfig) -> Result<Self, FirefoxCiError> {
+        let credentials = match config.credentials() {
+            Some(ref credentials) => Some(hawk_credentials(credentials)?),
+            None => None,
+        };
+
+        let root_url = match config.root_url() {
+            Some(root_url) => Url::parse(&root_url)?,
+            None => Url::parse(FIREFOX_CI_ROOT_URL).unwrap(),
+        };
+
+        // Without an explicit proxy, reqwest uses the proxies specified by the
+        // environment.
+        let mut client = Client::builder();
+        if let Some(ref proxy) = config.proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy).map_err(FirefoxCiError::BuildClient)?);
+        }
+        let client = client.build().map_err(FirefoxCiError::BuildClient)?;
+
+        Ok(FirefoxCi {
+            client,
+            credentials,
+            retry: config.retry.clone(),
+            connections: config.connections(),
+            artifact_wait: config.artifact_wait(),
+            ..FirefoxCi::with_root_url(root_url)
+        })
+    }
+
+    /// Create an unauthenticated client for the Taskcluster deployment at the
+    /// given root URL.
+    pub fn with_root_url(mut root_url: Url) -> Self {
+        // Ensure that the API paths are joined onto the root URL instead of
+        // replacing its last path segment.
+        if !root_url.path().ends_with('/') {
+            let path = format!("{}/", root_url.path());
+            root_url.set_path(&path);
+        }
+
         FirefoxCi {
             client: Client::new(),
-            queue_url,
+            queue_url: root_url.join("api/queue/v1/").unwrap(),
+            index_url: root_url.join("api/index/v1/").unwrap(),
+            credentials: None,
+            retry: RetryConfig::default(),
+            connections: 1,
+            artifact_wait: None,
+            artifact_poll_interval: ARTIFACT_POLL_INTERVAL,
         }
     }
+
+    /// Return the value of the `Authorization` header for a `GET` request to
+    /// the given URL, if we have credentials.
+    fn authorization(&self, url: &Url) -> Result<Option<String>, FirefoxCiError> {
+        let credentials = match self.credentials {
+            Some(ref credentials) => credentials,
+            None => return Ok(None),
+        };
+
+        let header = hawk::RequestBuilder::from_url("GET", url)
+            .map_err(FirefoxCiError::Sign)?
+            .request()
+            .make_header(credentials)
+            .map_err(FirefoxCiError::Sign)?;
+
+        Ok(Some(format!("Hawk {}", header)))
+    }
 }
 
 #[async_trait]
@@ -84,30 +287,305 @@ impl Taskcluster for FirefoxCi {
     type Error = FirefoxCiError;
 
     /// Download the build artifact from a Taskcluster task.
+    ///
+    /// Transient failures are retried according to the retry configuration.
+    /// If a download is interrupted, the retry resumes it where it left off.
+    /// The downloaded artifact is verified against the SHA-256 reported in the
+    /// artifact listing or by the storage backend, if any.
+    ///
+    /// If configured to wait for artifacts, a task that has not uploaded the
+    /// artifact yet has its artifacts listed until it does or the wait is
+    /// over.
     async fn download_build_artifact(
         &mut self,
         task_id: &str,
+        artifact: &str,
         download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
     ) -> Result<PathBuf, FirefoxCiError> {
-        let url = self.queue_url.join(&format!(
-            "task/{}/artifacts/{}",
-            task_id, BUILD_ARTIFACT_NAME
-        ))?;
+        let this = &*self;
+        let deadline = self.artifact_wait.map(|wait| Instant::now() + wait);
 
-        let path = download_dir.join("firefox.zip");
+        // The listing is used both to resolve glob patterns and to find the
+        // artifact's content hash.
+        let artifact = loop {
+            let result = retry(
+                self.retry.policy().retry_if(FirefoxCiError::is_transient),
+                || this.list_artifacts(task_id),
+            )
+            .await
+            .map_err(RetryError::into_source)
+            .and_then(|artifacts| find_artifact(task_id, artifact, artifacts));
 
-        let mut request = self
-            .client
-            .get(url)
-            .send()
+            match result {
+                Err(ref e)
+                    if e.is_missing_artifact()
+                        && deadline.is_some_and(|deadline| Instant::now() < deadline) =>
+                {
+                    delay_for(self.artifact_poll_interval).await;
+                }
+                result => break result?,
+            }
+        };
+
+        let expected_sha256 = artifact.sha256();
+
+        let url = self
+            .queue_url
+            .join(&format!("task/{}/artifacts/{}", task_id, artifact.name))?;
+
+        // Keep the artifact's file name so that its extension is preserved.
+        let file_name = artifact.name.rsplit('/').next().unwrap();
+        let path = download_dir.join(file_name);
+
+        retry(
+            self.retry.policy().retry_if(FirefoxCiError::is_transient),
+            || this.download(&url, &path, expected_sha256.as_deref(), &progress),
+        )
+        .await
+        .map_err(RetryError::into_source)?;
+
+        Ok(path)
+    }
+
+    /// Return the ID of the task indexed at the given route.
+    async fn find_task(&mut self, route: &str) -> Result<String, FirefoxCiError> {
+        let url = self.index_url.join(&format!("task/{}", route))?;
+
+        let this = &*self;
+        let task = retry(
+            self.retry.policy().retry_if(FirefoxCiError::is_transient),
+            || this.get_indexed_task(&url),
+        )
+        .await
+        .map_err(RetryError::into_source)?;
+
+        Ok(task.task_id)
+    }
+}
+
+/// The response to the index's `findTask` endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IndexedTask {
+    task_id: String,
+}
+
+/// A page of the response to the queue's `listLatestArtifacts` endpoint.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListArtifactsResponse {
+    artifacts: Vec<ArtifactInfo>,
+    continuation_token: Option<String>,
+}
+
+/// Information about an artifact in a listing.
+#[derive(Debug, Deserialize)]
+struct ArtifactInfo {
+    name: String,
+
+    /// The hashes of the artifact's content, for artifacts that record them.
+    #[serde(default)]
+    hashes: Option<ArtifactHashes>,
+}
+
+/// The hashes of an artifact's content.
+#[derive(Debug, Deserialize)]
+struct ArtifactHashes {
+    sha256: Option<String>,
+}
+
+impl ArtifactInfo {
+    /// Return the SHA-256 of the artifact's content, if known.
+    fn sha256(&self) -> Option<String> {
+        self.hashes
+            .as_ref()
+            .and_then(|hashes| hashes.sha256.as_ref())
+            .map(|sha256| sha256.to_ascii_lowercase())
+    }
+}
+
+impl FirefoxCi {
+    /// Fetch the indexed task at the given index URL.
+    async fn get_indexed_task(&self, url: &Url) -> Result<IndexedTask, FirefoxCiError> {
+        let mut request = self.client.get(url.clone());
+        if let Some(authorization) = self.authorization(url)? {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        let response = request.send().await.map_err(FirefoxCiError::FindTask)?;
+
+        if !response.status().is_success() {
+            return Err(FirefoxCiError::StatusError(response.status()));
+        }
+
+        response.json().await.map_err(FirefoxCiError::FindTask)
+    }
+
+    /// List the artifacts of the latest run of the given task.
+    async fn list_artifacts(&self, task_id: &str) -> Result<Vec<ArtifactInfo>, FirefoxCiError> {
+        let url = self
+            .queue_url
+            .join(&format!("task/{}/artifacts", task_id))?;
+
+        let mut artifacts = vec![];
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let mut page_url = url.clone();
+            if let Some(ref token) = continuation_token {
+                page_url
+                    .query_pairs_mut()
+                    .append_pair("continuationToken", token);
+            }
+
+            let mut request = self.client.get(page_url.clone());
+            if let Some(authorization) = self.authorization(&page_url)? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(FirefoxCiError::ListArtifacts)?;
+
+            if !response.status().is_success() {
+                return Err(FirefoxCiError::StatusError(response.status()));
+            }
+
+            let page: ListArtifactsResponse = response
+                .json()
+                .await
+                .map_err(FirefoxCiError::ListArtifacts)?;
+
+            artifacts.extend(page.artifacts);
+
+            match page.continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => break,
+            }
+        }
+
+        Ok(artifacts)
+    }
+
+    /// Download the artifact at the given URL to the given path.
+    ///
+    /// If configured to use multiple connections and the server supports
+    /// range requests, the artifact is downloaded in segments.
+    ///
+    /// The artifact is verified against `expected_sha256` or, failing that,
+    /// the SHA-256 reported by the storage backend. The progress of the
+    /// download is reported via `progress`.
+    async fn download(
+        &self,
+        url: &Url,
+        path: &Path,
+        expected_sha256: Option<&str>,
+        progress: &watch::Sender<DownloadProgress>,
+    ) -> Result<(), FirefoxCiError> {
+        if self.connections > 1
+            && self
+                .download_segmented(url, path, expected_sha256, progress)
+                .await?
+        {
+            return Ok(());
+        }
+
+        self.download_single(url, path, expected_sha256, progress)
             .await
-            .map_err(FirefoxCiError::DownloadArtifact)?;
+    }
+
+    /// Download the artifact at the given URL to the given path over a single
+    /// connection.
+    async fn download_single(
+        &self,
+        url: &Url,
+        path: &Path,
+        expected_sha256: Option<&str>,
+        progress: &watch::Sender<DownloadProgress>,
+    ) -> Result<(), FirefoxCiError> {
+        // A previous attempt may have left behind a partial download, in which
+        // case we request only the remainder.
+        let mut existing = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+
+        let mut request = loop {
+            // The queue responds with a redirect to a signed URL for the
+            // artifact. The Authorization header is not forwarded when
+            // following it.
+            let mut request = self.client.get(url.clone());
+            if let Some(authorization) = self.authorization(url)? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            if existing > 0 {
+                request = request.header(RANGE, format!("bytes={}-", existing));
+            }
+
+            let response = request
+                .send()
+                .await
+                .map_err(FirefoxCiError::DownloadArtifact)?;
+
+            if existing == 0 || response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+                break response;
+            }
+
+            // There is nothing left to request if the previous attempt
+            // downloaded the entire artifact.
+            let total = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(content_range_total);
+
+            if total == Some(existing) {
+                progress
+                    .broadcast(DownloadProgress {
+                        downloaded: existing,
+                        total,
+                    })
+                    .ok();
+
+                let expected_sha256 = expected_sha256
+                    .map(str::to_owned)
+                    .or_else(|| content_sha256(response.headers()));
+
+                return verify_download(path, expected_sha256).await;
+            }
+
+            // Otherwise the partial download cannot be resumed, so start over.
+            remove_file(path).await.map_err(FirefoxCiError::Io)?;
+            existing = 0;
+        };
 
         if !request.status().is_success() {
             return Err(FirefoxCiError::StatusError(request.status()));
         }
 
-        let mut file = File::create(&path).await.map_err(FirefoxCiError::Io)?;
+        // The server may ignore the range and send the entire artifact.
+        let resumed = existing > 0 && request.status() == StatusCode::PARTIAL_CONTENT;
+        let offset = if resumed { existing } else { 0 };
+
+        let expected_sha256 = expected_sha256
+            .map(str::to_owned)
+            .or_else(|| content_sha256(request.headers()));
+
+        let mut file = if resumed {
+            OpenOptions::new().append(true).open(path).await
+        } else {
+            File::create(path).await
+        }
+        .map_err(FirefoxCiError::Io)?;
+
+        let mut current = DownloadProgress {
+            downloaded: offset,
+            total: request.content_length().map(|len| len + offset),
+        };
+        // The receiver may have gone away, in which case nobody is interested
+        // in the progress.
+        progress.broadcast(current).ok();
 
         // Stream the first chunk ...
         let mut chunk = request
@@ -122,33 +600,344 @@ impl Taskcluster for FirefoxCi {
                 file.write_all(&content).map_err(FirefoxCiError::Io),
             )?
             .0;
+
+            current.downloaded += content.len() as u64;
+            progress.broadcast(current).ok();
         }
 
-        Ok(path)
+        file.flush().await.map_err(FirefoxCiError::Io)?;
+        drop(file);
+
+        verify_download(path, expected_sha256).await
+    }
+
+    /// Download the artifact at the given URL to the given path over multiple
+    /// connections, each of which downloads a contiguous segment of the
+    /// artifact.
+    ///
+    /// Each segment is downloaded to its own file, so an interrupted segment
+    /// is resumed if the download is retried. The segments are concatenated
+    /// once they have all been downloaded.
+    ///
+    /// Returns `false` without downloading anything if the server does not
+    /// support range requests.
+    async fn download_segmented(
+        &self,
+        url: &Url,
+        path: &Path,
+        expected_sha256: Option<&str>,
+        progress: &watch::Sender<DownloadProgress>,
+    ) -> Result<bool, FirefoxCiError> {
+        // Request the first byte of the artifact to discover its size and,
+        // because the queue redirects to the storage backend, the URL that
+        // the segments can be requested from directly.
+        let mut request = self.client.get(url.clone()).header(RANGE, "bytes=0-0");
+        if let Some(authorization) = self.authorization(url)? {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(FirefoxCiError::DownloadArtifact)?;
+
+        if !response.status().is_success() {
+            return Err(FirefoxCiError::StatusError(response.status()));
+        }
+
+        let total = match response.headers().get(CONTENT_RANGE) {
+            Some(content_range) if response.status() == StatusCode::PARTIAL_CONTENT => {
+                match content_range_total(content_range) {
+                    Some(total) => total,
+                    None => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+
+        let segment_url = response.url().clone();
+        let expected_sha256 = expected_sha256
+            .map(str::to_owned)
+            .or_else(|| content_sha256(response.headers()));
+        drop(response);
+
+        let segments = segment_ranges(total, self.connections);
+        let part_paths = (0..segments.len())
+            .map(|i| part_path(path, i))
+            .collect::<Vec<_>>();
+
+        let downloaded = AtomicU64::new(0);
+        try_join_all(
+            segments
+                .iter()
+                .zip(part_paths.iter())
+                .map(|(&(start, end), part_path)| {
+                    self.download_segment(
+                        url,
+                        &segment_url,
+                        part_path,
+                        start,
+                        end,
+                        &downloaded,
+                        total,
+                        progress,
+                    )
+                }),
+        )
+        .await?;
+
+        let mut file = File::create(path).await.map_err(FirefoxCiError::Io)?;
+        for part_path in &part_paths {
+            let mut part = File::open(part_path).await.map_err(FirefoxCiError::Io)?;
+            tokio::io::copy(&mut part, &mut file)
+                .await
+                .map_err(FirefoxCiError::Io)?;
+        }
+        file.flush().await.map_err(FirefoxCiError::Io)?;
+        drop(file);
+
+        for part_path in &part_paths {
+            remove_file(part_path).await.map_err(FirefoxCiError::Io)?;
+        }
+
+        verify_download(path, expected_sha256).await?;
+        Ok(true)
+    }
+
+    /// Download the bytes `start..=end` of the artifact to the given path.
+    ///
+    /// If the path already contains part of the segment, only the remainder
+    /// is requested.
+    #[allow(clippy::too_many_arguments)]
+    async fn download_segment(
+        &self,
+        url: &Url,
+        segment_url: &Url,
+        path: &Path,
+        start: u64,
+        end: u64,
+        downloaded: &AtomicU64,
+        total: u64,
+        progress: &watch::Sender<DownloadProgress>,
+    ) -> Result<(), FirefoxCiError> {
+        let len = end - start + 1;
+        let existing = match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.len().min(len),
+            Err(_) => 0,
+        };
+        downloaded.fetch_add(existing, Ordering::SeqCst);
+
+        if existing == len {
+            return Ok(());
+        }
+
+        let mut request = self
+            .client
+            .get(segment_url.clone())
+            .header(RANGE, format!("bytes={}-{}", start + existing, end));
+
+        // Requests that were not redirected to the storage backend still need
+        // to be authorized.
+        if segment_url == url {
+            if let Some(authorization) = self.authorization(url)? {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+        }
+
+        let mut response = request
+            .send()
+            .await
+            .map_err(FirefoxCiError::DownloadArtifact)?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(FirefoxCiError::StatusError(response.status()));
+        }
+
+        let mut file = if existing > 0 {
+            OpenOptions::new().append(true).open(path).await
+        } else {
+            File::create(path).await
+        }
+        .map_err(FirefoxCiError::Io)?;
+
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(FirefoxCiError::DownloadArtifact)?
+        {
+            file.write_all(&chunk).await.map_err(FirefoxCiError::Io)?;
+
+            let current = downloaded.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+            progress
+                .broadcast(DownloadProgress {
+                    downloaded: current + chunk.len() as u64,
+                    total: Some(total),
+                })
+                .ok();
+        }
+
+        file.flush().await.map_err(FirefoxCiError::Io)
+    }
+}
+
+/// Verify the downloaded artifact at the given path against its expected
+/// SHA-256, if any.
+///
+/// A corrupt download is removed so that a retry starts from scratch.
+async fn verify_download(
+    path: &Path,
+    expected_sha256: Option<String>,
+) -> Result<(), FirefoxCiError> {
+    let expected = match expected_sha256 {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    let actual = spawn_blocking({
+        let path = path.to_owned();
+        move || sha256_file(&path)
+    })
+    .await
+    .expect("hash task was cancelled or panicked")
+    .map_err(FirefoxCiError::Io)?;
+
+    if actual != expected {
+        remove_file(path).await.map_err(FirefoxCiError::Io)?;
+
+        return Err(FirefoxCiError::ChecksumMismatch { expected, actual });
+    }
+
+    Ok(())
+}
+
+/// Split an artifact of the given size into at most `connections` contiguous,
+/// inclusive byte ranges.
+fn segment_ranges(total: u64, connections: u32) -> Vec<(u64, u64)> {
+    if total == 0 {
+        return vec![];
+    }
+
+    let segment_len = total.div_ceil(u64::from(connections));
+
+    (0..total)
+        .step_by(segment_len as usize)
+        .map(|start| (start, (start + segment_len).min(total) - 1))
+        .collect()
+}
+
+/// Return the path that the given segment of a download is written to.
+fn part_path(path: &Path, segment: usize) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_owned();
+    file_name.push(format!(".part{}", segment));
+    path.with_file_name(file_name)
+}
+
+/// Parse the total size from a `Content-Range` header of the form
+/// `bytes start-end/total`.
+fn content_range_total(value: &HeaderValue) -> Option<u64> {
+    value.to_str().ok()?.rsplit('/').next()?.parse().ok()
+}
+
+/// Return the SHA-256 of the artifact, if the storage backend reports it.
+///
+/// Taskcluster records the hash as object metadata when artifacts are
+/// uploaded.
+fn content_sha256(headers: &HeaderMap) -> Option<String> {
+    CONTENT_SHA256_HEADERS
+        .iter()
+        .filter_map(|name| headers.get(*name))
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| value.to_ascii_lowercase())
+        .next()
+}
+
+/// Compute the hex-encoded SHA-256 of the file at the given path.
+fn sha256_file(path: &Path) -> Result<String, io::Error> {
+    let mut f = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher)?;
+
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Find the single artifact whose name matches the given name or glob
+/// pattern.
+fn find_artifact(
+    task_id: &str,
+    pattern: &str,
+    artifacts: Vec<ArtifactInfo>,
+) -> Result<ArtifactInfo, FirefoxCiError> {
+    let mut matches: Vec<ArtifactInfo> = artifacts
+        .into_iter()
+        .filter(|artifact| glob_matches(pattern, &artifact.name))
+        .collect();
+
+    match matches.len() {
+        0 => Err(FirefoxCiError::NoMatchingArtifact {
+            task_id: task_id.into(),
+            pattern: pattern.into(),
+        }),
+        1 => Ok(matches.pop().unwrap()),
+        _ => Err(FirefoxCiError::AmbiguousArtifact {
+            pattern: pattern.into(),
+            matches: matches.into_iter().map(|artifact| artifact.name).collect(),
+        }),
     }
 }
 
+/// Convert Taskcluster credentials into Hawk credentials for signing requests.
+fn hawk_credentials(
+    credentials: &TaskclusterCredentials,
+) -> Result<hawk::Credentials, FirefoxCiError> {
+    let access_token = credentials.access_token.resolve()?;
+    let key =
+        hawk::Key::new(access_token.as_bytes(), hawk::SHA256).map_err(FirefoxCiError::Sign)?;
+
+    Ok(hawk::Credentials {
+        id: credentials.client_id.clone(),
+        key,
+    })
+}
+
 #[cfg(test)]
 mod test {
     use std::env::current_dir;
 
     use assert_matches::assert_matches;
+    use libfxrecord::sha256_hex;
+    use mockito::{Matcher, Mock};
     use reqwest::StatusCode;
+    use serde_json::{json, Value};
     use tempfile::TempDir;
 
     use super::*;
 
+    fn progress() -> watch::Sender<DownloadProgress> {
+        watch::channel(DownloadProgress::default()).0
+    }
+
+    /// Mock the artifact listing of the given task.
+    fn mock_artifacts(task_id: &str, artifacts: Value) -> Mock {
+        mockito::mock("GET", &*format!("/api/queue/v1/task/{}/artifacts", task_id))
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "artifacts": artifacts }).to_string())
+            .create()
+    }
+
     fn firefox_ci() -> FirefoxCi {
-        FirefoxCi::with_queue_url(
-            Url::parse(&mockito::server_url())
-                .unwrap()
-                .join("/api/queue/v1/")
-                .unwrap(),
-        )
+        let mut tc = FirefoxCi::with_root_url(Url::parse(&mockito::server_url()).unwrap());
+        tc.retry = RetryConfig {
+            attempts: 3,
+            initial_wait_ms: 1,
+            max_elapsed_secs: None,
+        };
+        tc
     }
 
     #[tokio::test]
     async fn test_firefox_ci() {
+        let list_rsp = mock_artifacts("foo", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
         let zip_path = current_dir()
             .unwrap()
             .parent()
@@ -160,21 +949,68 @@ mod test {
             "GET",
             &*format!("/api/queue/v1/task/foo/artifacts/{}", BUILD_ARTIFACT_NAME),
         )
-        .with_body_from_file(zip_path)
+        .with_body_from_file(&zip_path)
         .create();
 
         let download_dir = TempDir::new().unwrap();
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
 
         firefox_ci()
-            .download_build_artifact("foo", download_dir.path())
+            .download_build_artifact("foo", BUILD_ARTIFACT_NAME, download_dir.path(), progress_tx)
+            .await
+            .unwrap();
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+
+        let size = zip_path.metadata().unwrap().len();
+        assert_eq!(
+            *progress_rx.borrow(),
+            DownloadProgress {
+                downloaded: size,
+                total: Some(size),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_credentials() {
+        let list_rsp = mock_artifacts("bar", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!("/api/queue/v1/task/bar/artifacts/{}", BUILD_ARTIFACT_NAME),
+        )
+        .match_header(
+            "authorization",
+            Matcher::Regex(r#"^Hawk id="fxrunner", ts="\d+", nonce="[^"]+", mac="[^"]+"$"#.into()),
+        )
+        .with_body("zip")
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+
+        let mut tc = firefox_ci();
+        tc.credentials = Some(
+            hawk_credentials(&TaskclusterCredentials {
+                client_id: "fxrunner".into(),
+                access_token: "secret".into(),
+            })
+            .unwrap(),
+        );
+
+        tc.download_build_artifact("bar", BUILD_ARTIFACT_NAME, download_dir.path(), progress())
             .await
             .unwrap();
 
+        list_rsp.assert();
         artifact_rsp.assert();
     }
 
     #[tokio::test]
     async fn test_firefox_ci_404() {
+        let list_rsp = mock_artifacts("foo", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
         let artifact_rsp = mockito::mock(
             "GET",
             &*format!("/api/queue/v1/task/foo/artifacts/{}", BUILD_ARTIFACT_NAME),
@@ -187,35 +1023,557 @@ mod test {
 
         assert_matches!(
             firefox_ci()
-                .download_build_artifact("foo", download_dir.path())
+                .download_build_artifact(
+                    "foo",
+                    BUILD_ARTIFACT_NAME,
+                    download_dir.path(),
+                    progress()
+                )
                 .await
                 .unwrap_err(),
             FirefoxCiError::StatusError(StatusCode::NOT_FOUND)
         );
 
+        list_rsp.assert();
         artifact_rsp.assert();
     }
 
     #[tokio::test]
     async fn test_firefox_ci_503() {
+        let list_rsp = mock_artifacts("foo", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
         let artifact_rsp = mockito::mock(
             "GET",
             &*format!("/api/queue/v1/task/foo/artifacts/{}", BUILD_ARTIFACT_NAME),
         )
         .with_status(503)
         .with_body("not found")
+        .expect(3)
         .create();
 
         let download_dir = TempDir::new().unwrap();
 
         assert_matches!(
             firefox_ci()
-                .download_build_artifact("foo", download_dir.path())
+                .download_build_artifact(
+                    "foo",
+                    BUILD_ARTIFACT_NAME,
+                    download_dir.path(),
+                    progress()
+                )
                 .await
                 .unwrap_err(),
             FirefoxCiError::StatusError(StatusCode::SERVICE_UNAVAILABLE)
         );
 
+        list_rsp.assert();
+        artifact_rsp.assert();
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_resume() {
+        let list_rsp = mock_artifacts("resume", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
+        let contents = b"hello, world";
+        let sha256 = sha256_hex(contents);
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/resume/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .match_header("range", "bytes=5-")
+        .with_status(206)
+        .with_header("x-amz-meta-content-sha256", &sha256)
+        .with_body(&contents[5..])
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+        let path = download_dir.path().join("target.zip");
+        std::fs::write(&path, &contents[..5]).unwrap();
+
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+
+        firefox_ci()
+            .download_build_artifact(
+                "resume",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress_tx,
+            )
+            .await
+            .unwrap();
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+        assert_eq!(std::fs::read(&path).unwrap(), contents.to_vec());
+        assert_eq!(
+            *progress_rx.borrow(),
+            DownloadProgress {
+                downloaded: contents.len() as u64,
+                total: Some(contents.len() as u64),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_resume_complete() {
+        let contents = b"hello, world";
+        let sha256 = sha256_hex(contents);
+
+        // A previous attempt downloaded the whole artifact.
+        let list_rsp = mock_artifacts("resume-complete", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/resume-complete/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .match_header("range", "bytes=12-")
+        .with_status(416)
+        .with_header("content-range", "bytes */12")
+        .with_header("x-amz-meta-content-sha256", &sha256)
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+        let path = download_dir.path().join("target.zip");
+        std::fs::write(&path, contents).unwrap();
+
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+
+        firefox_ci()
+            .download_build_artifact(
+                "resume-complete",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress_tx,
+            )
+            .await
+            .unwrap();
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+        assert_eq!(std::fs::read(&path).unwrap(), contents.to_vec());
+        assert_eq!(
+            *progress_rx.borrow(),
+            DownloadProgress {
+                downloaded: contents.len() as u64,
+                total: Some(contents.len() as u64),
+            }
+        );
+
+        // A partial download longer than the artifact is downloaded again.
+        let list_rsp = mock_artifacts("resume-restart", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+        let artifact_path = format!(
+            "/api/queue/v1/task/resume-restart/artifacts/{}",
+            BUILD_ARTIFACT_NAME
+        );
+        let range_rsp = mockito::mock("GET", &*artifact_path)
+            .match_header("range", "bytes=20-")
+            .with_status(416)
+            .with_header("content-range", "bytes */12")
+            .create();
+        let artifact_rsp = mockito::mock("GET", &*artifact_path)
+            .match_header("range", Matcher::Missing)
+            .with_body(contents)
+            .create();
+
+        std::fs::write(&path, [0u8; 20]).unwrap();
+
+        firefox_ci()
+            .download_build_artifact(
+                "resume-restart",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress(),
+            )
+            .await
+            .unwrap();
+
+        list_rsp.assert();
+        range_rsp.assert();
+        artifact_rsp.assert();
+        assert_eq!(std::fs::read(&path).unwrap(), contents.to_vec());
+    }
+
+    #[test]
+    fn test_segment_ranges() {
+        assert_eq!(segment_ranges(10, 3), vec![(0, 3), (4, 7), (8, 9)]);
+        assert_eq!(segment_ranges(10, 1), vec![(0, 9)]);
+        assert_eq!(segment_ranges(2, 4), vec![(0, 0), (1, 1)]);
+        assert_eq!(segment_ranges(0, 4), vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_segmented() {
+        let list_rsp = mock_artifacts("segmented", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
+        let contents = b"0123456789";
+        let sha256 = sha256_hex(contents);
+        let artifact_path = format!(
+            "/api/queue/v1/task/segmented/artifacts/{}",
+            BUILD_ARTIFACT_NAME
+        );
+
+        let segment_rsps = [("0-0", 0..1), ("0-3", 0..4), ("4-7", 4..8), ("9-9", 9..10)]
+            .iter()
+            .map(|(range, bytes)| {
+                mockito::mock("GET", &*artifact_path)
+                    .match_header("range", &*format!("bytes={}", range))
+                    .with_status(206)
+                    .with_header("content-range", &format!("bytes {}/10", range))
+                    .with_header("x-amz-meta-content-sha256", &sha256)
+                    .with_body(&contents[bytes.clone()])
+                    .create()
+            })
+            .collect::<Vec<_>>();
+
+        let download_dir = TempDir::new().unwrap();
+        let path = download_dir.path().join("target.zip");
+
+        // The third segment was interrupted by a previous attempt.
+        std::fs::write(part_path(&path, 2), &contents[8..9]).unwrap();
+
+        let (progress_tx, progress_rx) = watch::channel(DownloadProgress::default());
+
+        let mut tc = firefox_ci();
+        tc.connections = 3;
+        tc.download_build_artifact(
+            "segmented",
+            BUILD_ARTIFACT_NAME,
+            download_dir.path(),
+            progress_tx,
+        )
+        .await
+        .unwrap();
+
+        list_rsp.assert();
+        for rsp in &segment_rsps {
+            rsp.assert();
+        }
+
+        assert_eq!(std::fs::read(&path).unwrap(), contents.to_vec());
+        assert!(!part_path(&path, 0).exists());
+        assert!(!part_path(&path, 2).exists());
+        assert_eq!(
+            *progress_rx.borrow(),
+            DownloadProgress {
+                downloaded: contents.len() as u64,
+                total: Some(contents.len() as u64),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_segmented_unsupported() {
+        let list_rsp = mock_artifacts("unranged", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
+        // The server ignores the range of the probe, so the artifact is
+        // downloaded over a single connection instead.
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/unranged/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .with_body("hello, world")
+        .expect(2)
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+
+        let mut tc = firefox_ci();
+        tc.connections = 4;
+        let path = tc
+            .download_build_artifact(
+                "unranged",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress(),
+            )
+            .await
+            .unwrap();
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello, world".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_checksum_mismatch() {
+        let list_rsp = mock_artifacts("corrupt", json!([{ "name": BUILD_ARTIFACT_NAME }]));
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/corrupt/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .with_header("x-goog-meta-content-sha256", &sha256_hex(b"expected"))
+        .with_body("corrupt")
+        .expect(3)
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+
+        assert_matches!(
+            firefox_ci()
+                .download_build_artifact("corrupt", BUILD_ARTIFACT_NAME, download_dir.path(), progress())
+                .await
+                .unwrap_err(),
+            FirefoxCiError::ChecksumMismatch { expected, actual } => {
+                assert_eq!(expected, sha256_hex(b"expected"));
+                assert_eq!(actual, sha256_hex(b"corrupt"));
+            }
+        );
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+        assert!(!download_dir.path().join("target.zip").exists());
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_glob() {
+        let list_rsp = mockito::mock("GET", "/api/queue/v1/task/glob/artifacts")
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{
+                    "artifacts": [
+                        {"name": "public/build/target.json"},
+                        {"name": "public/build/target.zip"},
+                        {"name": "public/logs/live.log"}
+                    ]
+                }"#,
+            )
+            .expect(3)
+            .create();
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            "/api/queue/v1/task/glob/artifacts/public/build/target.zip",
+        )
+        .with_body("zip")
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+
+        firefox_ci()
+            .download_build_artifact(
+                "glob",
+                "public/build/*.zip",
+                download_dir.path(),
+                progress(),
+            )
+            .await
+            .unwrap();
+
+        assert_matches!(
+            firefox_ci()
+                .download_build_artifact("glob", "public/build/target.*", download_dir.path(), progress())
+                .await
+                .unwrap_err(),
+            FirefoxCiError::AmbiguousArtifact { matches, .. } => {
+                assert_eq!(matches, vec!["public/build/target.json", "public/build/target.zip"]);
+            }
+        );
+
+        assert_matches!(
+            firefox_ci()
+                .download_build_artifact("glob", "*.zip", download_dir.path(), progress())
+                .await
+                .unwrap_err(),
+            FirefoxCiError::NoMatchingArtifact { .. }
+        );
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_artifact_wait() {
+        // The listing never includes the artifact, so it is listed until the
+        // wait is over.
+        let _list_rsp = mock_artifacts("pending", json!([{ "name": "public/logs/live.log" }]));
+
+        let download_dir = TempDir::new().unwrap();
+        let mut tc = firefox_ci();
+        tc.artifact_wait = Some(Duration::from_millis(50));
+        tc.artifact_poll_interval = Duration::from_millis(10);
+
+        let start = Instant::now();
+        assert_matches!(
+            tc.download_build_artifact(
+                "pending",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress()
+            )
+            .await
+            .unwrap_err(),
+            FirefoxCiError::NoMatchingArtifact { .. }
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_find_task() {
+        let index_rsp = mockito::mock(
+            "GET",
+            "/api/index/v1/task/gecko.v2.mozilla-central.latest.firefox.win64-opt",
+        )
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "namespace": "gecko.v2.mozilla-central.latest.firefox.win64-opt",
+                "taskId": "abc123",
+                "rank": 0,
+                "data": {},
+                "expires": "2021-07-01T00:00:00.000Z"
+            }"#,
+        )
+        .create();
+
+        assert_eq!(
+            firefox_ci()
+                .find_task("gecko.v2.mozilla-central.latest.firefox.win64-opt")
+                .await
+                .unwrap(),
+            "abc123"
+        );
+
+        index_rsp.assert();
+
+        let missing_rsp = mockito::mock("GET", "/api/index/v1/task/missing")
+            .with_status(404)
+            .create();
+
+        assert_matches!(
+            firefox_ci().find_task("missing").await.unwrap_err(),
+            FirefoxCiError::StatusError(StatusCode::NOT_FOUND)
+        );
+
+        missing_rsp.assert();
+    }
+
+    #[test]
+    fn test_with_root_url() {
+        let tc = FirefoxCi::with_root_url(Url::parse("https://tc.example.com/prefix").unwrap());
+        assert_eq!(
+            tc.queue_url.as_str(),
+            "https://tc.example.com/prefix/api/queue/v1/"
+        );
+        assert_eq!(
+            tc.index_url.as_str(),
+            "https://tc.example.com/prefix/api/index/v1/"
+        );
+
+        let tc = FirefoxCi::default();
+        assert_eq!(
+            tc.queue_url.as_str(),
+            "https://firefox-ci-tc.services.mozilla.com/api/queue/v1/"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_proxy() {
+        // The proxy receives requests with absolute URLs for the origin server.
+        let proxy_rsp = mockito::mock("GET", "http://tc.example.com/api/index/v1/task/proxied")
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"taskId": "abc123"}"#)
+            .create();
+
+        let mut tc = FirefoxCi::new(&TaskclusterConfig {
+            root_url: Some("http://tc.example.com".into()),
+            proxy: Some(mockito::server_url()),
+            ..TaskclusterConfig::default()
+        })
+        .unwrap();
+
+        assert_eq!(tc.find_task("proxied").await.unwrap(), "abc123");
+
+        proxy_rsp.assert();
+
+        assert_matches!(
+            FirefoxCi::new(&TaskclusterConfig {
+                proxy: Some("not a url".into()),
+                ..TaskclusterConfig::default()
+            })
+            .unwrap_err(),
+            FirefoxCiError::BuildClient(..)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_firefox_ci_listing_hash() {
+        let list_rsp = mock_artifacts(
+            "hashed",
+            json!([{
+                "name": BUILD_ARTIFACT_NAME,
+                "hashes": { "sha256": sha256_hex(b"expected").to_uppercase() },
+            }]),
+        );
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/hashed/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .with_body("corrupt")
+        .expect(3)
+        .create();
+
+        let download_dir = TempDir::new().unwrap();
+
+        assert_matches!(
+            firefox_ci()
+                .download_build_artifact("hashed", BUILD_ARTIFACT_NAME, download_dir.path(), progress())
+                .await
+                .unwrap_err(),
+            FirefoxCiError::ChecksumMismatch { expected, actual } => {
+                assert_eq!(expected, sha256_hex(b"expected"));
+                assert_eq!(actual, sha256_hex(b"corrupt"));
+            }
+        );
+
+        list_rsp.assert();
+        artifact_rsp.assert();
+
+        let list_rsp = mock_artifacts(
+            "hashed-ok",
+            json!([{
+                "name": BUILD_ARTIFACT_NAME,
+                "hashes": { "sha256": sha256_hex(b"expected") },
+            }]),
+        );
+
+        let artifact_rsp = mockito::mock(
+            "GET",
+            &*format!(
+                "/api/queue/v1/task/hashed-ok/artifacts/{}",
+                BUILD_ARTIFACT_NAME
+            ),
+        )
+        .with_body("expected")
+        .create();
+
+        firefox_ci()
+            .download_build_artifact(
+                "hashed-ok",
+                BUILD_ARTIFACT_NAME,
+                download_dir.path(),
+                progress(),
+            )
+            .await
+            .unwrap();
+
+        list_rsp.assert();
         artifact_rsp.assert();
     }
 }
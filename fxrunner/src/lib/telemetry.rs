@@ -0,0 +1,581 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Extraction of startup metrics from the telemetry Firefox saves in its
+//! profile.
+//!
+//! Statistics about the startup cache are also collected from the profile, as
+//! whether or not it was populated explains much of the difference between
+//! cold and warm starts, as is the graphics configuration Firefox ran with.
+//!
+//! Firefox is killed rather than shut down cleanly, so a run does not always
+//! archive a main ping of its own. Only pings created after Firefox was
+//! launched are used, so that numbers left over from an earlier run are never
+//! reported as this one's.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{metadata, read, read_dir, symlink_metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libfxrecord::net::{GraphicsInfo, StartupCacheRequests, StartupCacheStats};
+use serde_json::Value;
+use thiserror::Error;
+
+/// The magic number at the start of a mozlz4 file.
+const MOZLZ4_MAGIC: &[u8] = b"mozLz40\0";
+
+/// The name of the startup cache directory in the profile.
+///
+/// Firefox keeps the startup cache in the local profile directory, which is
+/// the profile directory itself when a profile is given with `-profile`.
+const STARTUP_CACHE_DIR: &str = "startupCache";
+
+/// The simple measurements from the main ping that are relevant to startup.
+const STARTUP_MEASUREMENTS: &[&str] = &[
+    "start",
+    "main",
+    "selectProfile",
+    "afterProfileLocked",
+    "startupCrashDetectionBegin",
+    "createTopLevelWindow",
+    "firstPaint",
+    "sessionRestoreInit",
+    "sessionRestored",
+    "delayedStartupStarted",
+    "delayedStartupFinished",
+    "startupCrashDetectionEnd",
+];
+
+/// Return the startup metrics recorded in the most recent main ping archived in
+/// the given profile since Firefox was launched.
+///
+/// If the profile contains a `Telemetry.ShutdownTime.txt` written since the
+/// launch, the duration of the last shutdown is reported as `lastShutdown`.
+pub fn startup_metrics(
+    profile_path: &Path,
+    launched: SystemTime,
+) -> Result<BTreeMap<String, u64>, TelemetryError> {
+    let ping_path = latest_main_ping(
+        &profile_path.join("datareporting").join("archived"),
+        launched,
+    )?
+    .ok_or(TelemetryError::NoMainPing)?;
+
+    let ping = read_ping(&ping_path)?;
+    let mut metrics = simple_measurements(&ping);
+
+    let shutdown_time_path = profile_path.join("Telemetry.ShutdownTime.txt");
+    let written_since_launch = metadata(&shutdown_time_path)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| modified >= launched)
+        .unwrap_or(false);
+
+    if written_since_launch {
+        let contents = read(&shutdown_time_path).map_err(|source| TelemetryError::Io {
+            path: shutdown_time_path.clone(),
+            source,
+        })?;
+        let shutdown_time: Value =
+            serde_json::from_slice(&contents).map_err(|source| TelemetryError::Parse {
+                path: shutdown_time_path,
+                source,
+            })?;
+
+        if let Some(last_shutdown) = shutdown_time["lastShutdown"].as_u64() {
+            metrics.insert("lastShutdown".into(), last_shutdown);
+        }
+    }
+
+    Ok(metrics)
+}
+
+/// Return the total size of the startup cache in the given profile, in bytes.
+///
+/// A profile without a startup cache has a size of zero.
+pub fn startup_cache_size(profile_path: &Path) -> Result<u64, TelemetryError> {
+    dir_size(&profile_path.join(STARTUP_CACHE_DIR))
+}
+
+/// Return statistics about the startup cache in the given profile after
+/// Firefox has stopped.
+///
+/// The requests made to the startup cache are read from the most recent main
+/// ping archived in the profile since Firefox was launched, if there is one.
+pub fn startup_cache_stats(
+    profile_path: &Path,
+    launched: SystemTime,
+    size_before: u64,
+) -> Result<StartupCacheStats, TelemetryError> {
+    let size_after = startup_cache_size(profile_path)?;
+
+    let requests = match latest_main_ping(
+        &profile_path.join("datareporting").join("archived"),
+        launched,
+    )? {
+        Some(ping_path) => startup_cache_requests(&read_ping(&ping_path)?),
+        None => None,
+    };
+
+    Ok(StartupCacheStats {
+        size_before,
+        size_after,
+        requests,
+    })
+}
+
+/// Return the graphics configuration recorded in the environment of the most
+/// recent main ping archived in the given profile since Firefox was launched.
+pub fn graphics_info(
+    profile_path: &Path,
+    launched: SystemTime,
+) -> Result<GraphicsInfo, TelemetryError> {
+    let ping_path = latest_main_ping(
+        &profile_path.join("datareporting").join("archived"),
+        launched,
+    )?
+    .ok_or(TelemetryError::NoMainPing)?;
+
+    Ok(graphics(&read_ping(&ping_path)?))
+}
+
+/// Return the total size of the files in the given directory and its
+/// subdirectories.
+fn dir_size(path: &Path) -> Result<u64, TelemetryError> {
+    let io_err = |path: &Path| {
+        let path = path.to_owned();
+        move |source| TelemetryError::Io { path, source }
+    };
+
+    if !path.is_dir() {
+        return Ok(0);
+    }
+
+    let mut size = 0;
+    for entry in read_dir(path).map_err(io_err(path))? {
+        let entry_path = entry.map_err(io_err(path))?.path();
+        let metadata = symlink_metadata(&entry_path).map_err(io_err(&entry_path))?;
+
+        if metadata.is_dir() {
+            size += dir_size(&entry_path)?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Find the most recently created main ping in the telemetry archive, if one
+/// was created since `launched`.
+///
+/// Archived pings are stored in per-month directories and their file names
+/// begin with their creation timestamp, in milliseconds since the epoch.
+fn latest_main_ping(
+    archive_dir: &Path,
+    launched: SystemTime,
+) -> Result<Option<PathBuf>, TelemetryError> {
+    let io_err = |path: &Path| {
+        let path = path.to_owned();
+        move |source| TelemetryError::Io { path, source }
+    };
+
+    if !archive_dir.is_dir() {
+        return Ok(None);
+    }
+
+    let launched = launched
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0);
+
+    let mut latest: Option<(u64, PathBuf)> = None;
+
+    for month_entry in read_dir(archive_dir).map_err(io_err(archive_dir))? {
+        let month_dir = month_entry.map_err(io_err(archive_dir))?.path();
+        if !month_dir.is_dir() {
+            continue;
+        }
+
+        for ping_entry in read_dir(&month_dir).map_err(io_err(&month_dir))? {
+            let ping_path = ping_entry.map_err(io_err(&month_dir))?.path();
+
+            let file_name = match ping_path.file_name().and_then(|name| name.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+
+            if !file_name.ends_with(".main.jsonlz4") && !file_name.ends_with(".main.json") {
+                continue;
+            }
+
+            let timestamp = match file_name
+                .split('.')
+                .next()
+                .and_then(|ts| ts.parse::<u64>().ok())
+            {
+                Some(timestamp) if timestamp >= launched => timestamp,
+                _ => continue,
+            };
+
+            if latest
+                .as_ref()
+                .map(|(ts, _)| timestamp > *ts)
+                .unwrap_or(true)
+            {
+                latest = Some((timestamp, ping_path));
+            }
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
+
+/// Read a ping, decompressing it if necessary.
+fn read_ping(path: &Path) -> Result<Value, TelemetryError> {
+    let contents = read(path).map_err(|source| TelemetryError::Io {
+        path: path.into(),
+        source,
+    })?;
+
+    let contents = if path
+        .extension()
+        .map(|ext| ext == "jsonlz4")
+        .unwrap_or(false)
+    {
+        decompress_mozlz4(&contents).map_err(|source| TelemetryError::Io {
+            path: path.into(),
+            source,
+        })?
+    } else {
+        contents
+    };
+
+    serde_json::from_slice(&contents).map_err(|source| TelemetryError::Parse {
+        path: path.into(),
+        source,
+    })
+}
+
+/// Decompress the contents of a mozlz4 file.
+///
+/// A mozlz4 file consists of a magic number, the little-endian 32-bit size of
+/// the decompressed data, and a single LZ4 block.
+fn decompress_mozlz4(contents: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let header_len = MOZLZ4_MAGIC.len() + 4;
+
+    if contents.len() < header_len || !contents.starts_with(MOZLZ4_MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a mozlz4 file",
+        ));
+    }
+
+    let size = u32::from_le_bytes(contents[MOZLZ4_MAGIC.len()..header_len].try_into().unwrap());
+    let size = size
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "mozlz4 file is too large"))?;
+
+    lz4::block::decompress(&contents[header_len..], Some(size))
+}
+
+/// Extract the startup-related simple measurements from a main ping.
+fn simple_measurements(ping: &Value) -> BTreeMap<String, u64> {
+    let measurements = &ping["payload"]["simpleMeasurements"];
+
+    STARTUP_MEASUREMENTS
+        .iter()
+        .filter_map(|&name| {
+            measurements[name]
+                .as_u64()
+                .map(|value| (name.to_owned(), value))
+        })
+        .collect()
+}
+
+/// Extract the requests made to the startup cache from a main ping.
+///
+/// `STARTUP_CACHE_REQUESTS` is a categorical histogram with the labels
+/// `HitMemory`, `HitDisk`, and `Miss`, in that order. Firefox does not record
+/// it in every build, in which case there is nothing to extract.
+fn startup_cache_requests(ping: &Value) -> Option<StartupCacheRequests> {
+    let histogram = &ping["payload"]["histograms"]["STARTUP_CACHE_REQUESTS"];
+    let values = histogram["values"].as_object()?;
+
+    let count = |label: &str| values.get(label).and_then(Value::as_u64).unwrap_or(0);
+
+    Some(StartupCacheRequests {
+        hit_memory: count("0"),
+        hit_disk: count("1"),
+        miss: count("2"),
+    })
+}
+
+/// Extract the graphics configuration from the environment of a ping.
+///
+/// The active adapter is the one Firefox reports as `GPUActive`, or the first
+/// adapter if none are.
+fn graphics(ping: &Value) -> GraphicsInfo {
+    let gfx = &ping["environment"]["system"]["gfx"];
+
+    let adapter = gfx["adapters"].as_array().and_then(|adapters| {
+        adapters
+            .iter()
+            .find(|adapter| adapter["GPUActive"].as_bool() == Some(true))
+            .or_else(|| adapters.first())
+    });
+
+    let adapter_field = |name: &str| {
+        adapter
+            .and_then(|adapter| adapter[name].as_str())
+            .map(Into::into)
+    };
+
+    let compositor = gfx["features"]["compositor"].as_str();
+
+    GraphicsInfo {
+        adapter: adapter_field("description"),
+        vendor_id: adapter_field("vendorID"),
+        device_id: adapter_field("deviceID"),
+        driver_version: adapter_field("driverVersion"),
+        compositor: compositor.map(Into::into),
+        hardware_acceleration: compositor
+            .map(|compositor| !matches!(compositor, "basic" | "none" | "webrender_software")),
+        webrender: compositor.map(|compositor| compositor.starts_with("webrender")),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TelemetryError {
+    #[error("No main ping was found in the profile")]
+    NoMainPing,
+
+    #[error("Could not read `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Could not parse `{}': {}", .path.display(), .source)]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, create_dir_all, write};
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_simple_measurements() {
+        let ping = json!({
+            "type": "main",
+            "payload": {
+                "simpleMeasurements": {
+                    "start": 12,
+                    "main": 20,
+                    "firstPaint": 1234,
+                    "sessionRestored": 1500,
+                    "totalTime": 60,
+                    "js": { "setup": 30 },
+                },
+            },
+        });
+
+        let metrics = simple_measurements(&ping);
+        assert_eq!(
+            metrics.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("firstPaint".to_string(), 1234),
+                ("main".to_string(), 20),
+                ("sessionRestored".to_string(), 1500),
+                ("start".to_string(), 12),
+            ]
+        );
+
+        assert!(simple_measurements(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_startup_cache_requests() {
+        let ping = json!({
+            "type": "main",
+            "payload": {
+                "histograms": {
+                    "STARTUP_CACHE_REQUESTS": {
+                        "bucket_count": 4,
+                        "histogram_type": 5,
+                        "sum": 190,
+                        "range": [1, 3],
+                        "values": { "0": 80, "1": 40, "2": 10, "3": 0 },
+                    },
+                },
+            },
+        });
+
+        assert_eq!(
+            startup_cache_requests(&ping),
+            Some(StartupCacheRequests {
+                hit_memory: 80,
+                hit_disk: 40,
+                miss: 10,
+            })
+        );
+
+        assert_eq!(startup_cache_requests(&json!({})), None);
+    }
+
+    #[test]
+    fn test_graphics() {
+        let ping = json!({
+            "type": "main",
+            "environment": {
+                "system": {
+                    "gfx": {
+                        "adapters": [
+                            {
+                                "description": "Intel(R) UHD Graphics 620",
+                                "vendorID": "0x8086",
+                                "deviceID": "0x5917",
+                                "driverVersion": "26.20.100.7262",
+                                "GPUActive": false,
+                            },
+                            {
+                                "description": "NVIDIA GeForce MX150",
+                                "vendorID": "0x10de",
+                                "deviceID": "0x1d10",
+                                "driverVersion": "26.21.14.4166",
+                                "GPUActive": true,
+                            },
+                        ],
+                        "features": {
+                            "compositor": "webrender",
+                        },
+                    },
+                },
+            },
+        });
+
+        assert_eq!(
+            graphics(&ping),
+            GraphicsInfo {
+                adapter: Some("NVIDIA GeForce MX150".into()),
+                vendor_id: Some("0x10de".into()),
+                device_id: Some("0x1d10".into()),
+                driver_version: Some("26.21.14.4166".into()),
+                compositor: Some("webrender".into()),
+                hardware_acceleration: Some(true),
+                webrender: Some(true),
+            }
+        );
+
+        let ping = json!({
+            "environment": {
+                "system": {
+                    "gfx": {
+                        "adapters": [{ "description": "Microsoft Basic Render Driver" }],
+                        "features": { "compositor": "basic" },
+                    },
+                },
+            },
+        });
+
+        let info = graphics(&ping);
+        assert_eq!(
+            info.adapter.as_deref(),
+            Some("Microsoft Basic Render Driver")
+        );
+        assert_eq!(info.hardware_acceleration, Some(false));
+        assert_eq!(info.webrender, Some(false));
+
+        assert_eq!(graphics(&json!({})), GraphicsInfo::default());
+    }
+
+    #[test]
+    fn test_startup_cache_size() {
+        let profile_dir = TempDir::new().unwrap();
+        assert_eq!(startup_cache_size(profile_dir.path()).unwrap(), 0);
+
+        let cache_dir = profile_dir.path().join(STARTUP_CACHE_DIR);
+        create_dir(&cache_dir).unwrap();
+        write(cache_dir.join("scriptCache.bin"), vec![0u8; 1000]).unwrap();
+        write(cache_dir.join("startupCache.8.little"), vec![0u8; 234]).unwrap();
+
+        assert_eq!(startup_cache_size(profile_dir.path()).unwrap(), 1234);
+    }
+
+    #[test]
+    fn test_startup_metrics_since_launch() {
+        let profile_dir = TempDir::new().unwrap();
+        let month_dir = profile_dir
+            .path()
+            .join("datareporting")
+            .join("archived")
+            .join("2020-06");
+        create_dir_all(&month_dir).unwrap();
+
+        let ping = |first_paint: u64| {
+            json!({
+                "type": "main",
+                "payload": { "simpleMeasurements": { "firstPaint": first_paint } },
+            })
+            .to_string()
+        };
+
+        // Pings are named for the millisecond they were created in.
+        let launched = UNIX_EPOCH + Duration::from_millis(1_592_000_000_000);
+        write(month_dir.join("1591000000000.a.main.json"), ping(100)).unwrap();
+        write(month_dir.join("1591999999999.b.event.json"), "{}").unwrap();
+
+        assert_matches!(
+            startup_metrics(profile_dir.path(), launched),
+            Err(TelemetryError::NoMainPing)
+        );
+
+        write(month_dir.join("1592000000500.c.main.json"), ping(200)).unwrap();
+        write(
+            profile_dir.path().join("Telemetry.ShutdownTime.txt"),
+            r#"{"lastShutdown":300}"#,
+        )
+        .unwrap();
+
+        let metrics = startup_metrics(profile_dir.path(), launched).unwrap();
+        assert_eq!(metrics["firstPaint"], 200);
+        assert_eq!(metrics["lastShutdown"], 300);
+
+        // A shutdown time written before the launch is left over from an
+        // earlier run.
+        let launched = SystemTime::now() + Duration::from_secs(60);
+        let created = launched.duration_since(UNIX_EPOCH).unwrap().as_millis() + 500;
+        write(
+            month_dir.join(format!("{}.d.main.json", created)),
+            ping(400),
+        )
+        .unwrap();
+
+        let metrics = startup_metrics(profile_dir.path(), launched).unwrap();
+        assert_eq!(metrics["firstPaint"], 400);
+        assert!(!metrics.contains_key("lastShutdown"));
+    }
+
+    #[test]
+    fn test_decompress_mozlz4() {
+        let data = br#"{"type":"main"}"#;
+
+        let mut contents = MOZLZ4_MAGIC.to_vec();
+        contents.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        contents.extend(lz4::block::compress(data, None, false).unwrap());
+
+        assert_eq!(decompress_mozlz4(&contents).unwrap(), data.to_vec());
+
+        assert!(decompress_mozlz4(b"not lz4").is_err());
+    }
+}
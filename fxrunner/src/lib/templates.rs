@@ -0,0 +1,156 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Named profiles provisioned on the runner ahead of time.
+//!
+//! Each template is a directory in the configured `profiles_dir`. A session
+//! that requests a template by name receives a fresh copy of it, so the
+//! template itself is never modified by Firefox.
+
+use std::fs::{copy, create_dir_all, read_dir};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// Copy the profile template with the given name to `target`.
+pub fn copy_template(
+    profiles_dir: Option<&Path>,
+    name: &str,
+    target: &Path,
+) -> Result<(), TemplateError> {
+    let profiles_dir = profiles_dir.ok_or(TemplateError::NotConfigured)?;
+    let template = template_path(profiles_dir, name)?;
+
+    if !template.is_dir() {
+        return Err(TemplateError::MissingTemplate(name.into()));
+    }
+
+    copy_dir(&template, target)
+}
+
+/// Return the path to the template with the given name.
+///
+/// Template names must be plain directory names so that they cannot refer to
+/// directories outside of the profiles directory.
+fn template_path(profiles_dir: &Path, name: &str) -> Result<PathBuf, TemplateError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Ok(profiles_dir.join(name))
+    } else {
+        Err(TemplateError::InvalidName(name.into()))
+    }
+}
+
+/// Recursively copy the directory at `source` to `target`.
+fn copy_dir(source: &Path, target: &Path) -> Result<(), TemplateError> {
+    create_dir_all(target).map_err(|e| TemplateError::io(target, e))?;
+
+    for entry in read_dir(source).map_err(|e| TemplateError::io(source, e))? {
+        let entry = entry.map_err(|e| TemplateError::io(source, e))?;
+        let path = entry.path();
+        let dest = target.join(entry.file_name());
+
+        if path.is_dir() {
+            copy_dir(&path, &dest)?;
+        } else {
+            copy(&path, &dest).map_err(|e| TemplateError::io(&path, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("The runner is not configured with profile templates")]
+    NotConfigured,
+
+    #[error("Invalid profile template name `{}'", .0)]
+    InvalidName(String),
+
+    #[error("Profile template `{}' does not exist", .0)]
+    MissingTemplate(String),
+
+    #[error("Could not copy profile template file `{}': {}", .path.display(), .source)]
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl TemplateError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        TemplateError::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{read_to_string, write};
+
+    use assert_matches::assert_matches;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_template_path() {
+        let profiles_dir = Path::new("profiles");
+
+        assert_eq!(
+            template_path(profiles_dir, "heavy-user").unwrap(),
+            profiles_dir.join("heavy-user")
+        );
+
+        for name in &["", ".", "..", "../clean", "clean/nested", r"..\clean"] {
+            assert_matches!(
+                template_path(profiles_dir, name),
+                Err(TemplateError::InvalidName(n)) => {
+                    assert_eq!(n, *name);
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_copy_template() {
+        let tempdir = TempDir::new().unwrap();
+        let profiles_dir = tempdir.path().join("profiles");
+        let template = profiles_dir.join("clean");
+        create_dir_all(template.join("nested")).unwrap();
+        write(template.join("prefs.js"), "prefs").unwrap();
+        write(template.join("nested").join("data.sqlite"), "data").unwrap();
+
+        let target = tempdir.path().join("profile");
+        copy_template(Some(&profiles_dir), "clean", &target).unwrap();
+
+        assert_eq!(read_to_string(target.join("prefs.js")).unwrap(), "prefs");
+        assert_eq!(
+            read_to_string(target.join("nested").join("data.sqlite")).unwrap(),
+            "data"
+        );
+
+        // The copy must not share files with the template.
+        write(target.join("prefs.js"), "modified").unwrap();
+        assert_eq!(read_to_string(template.join("prefs.js")).unwrap(), "prefs");
+
+        assert_matches!(
+            copy_template(Some(&profiles_dir), "missing", &tempdir.path().join("missing")),
+            Err(TemplateError::MissingTemplate(name)) => {
+                assert_eq!(name, "missing");
+            }
+        );
+
+        assert_matches!(
+            copy_template(None, "clean", &tempdir.path().join("unused")),
+            Err(TemplateError::NotConfigured)
+        );
+    }
+}
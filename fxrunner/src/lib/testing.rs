@@ -0,0 +1,305 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for testing the runner side of the protocol.
+//!
+//! This module is only available with the `testing` feature.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use libfxrecord::error::ErrorMessage;
+use libfxrecord::net::DownloadProgress;
+use slog::Logger;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+
+use crate::config::{Config, Size};
+use crate::osapi::{PerfProvider, ShutdownProvider};
+use crate::proto::{RequestOutcome, RunnerProto, RunnerProtoError};
+use crate::provider::BuildProvider;
+use crate::session::SessionManager;
+use crate::splash::Splash;
+use crate::taskcluster::Taskcluster;
+
+/// The display size used by [`test_config`](fn.test_config.html).
+pub const TEST_DISPLAY_SIZE: Size = Size { x: 640, y: 480 };
+
+/// A runner configuration suitable for tests.
+///
+/// Nothing is listened on and every path is empty, so tests must provide a
+/// [`SessionManager`](../session/trait.SessionManager.html) that does not
+/// rely on the session directory.
+pub fn test_config() -> Config {
+    Config {
+        host: "127.0.0.1:0".parse().unwrap(),
+        session_dir: PathBuf::new(),
+        display_size: TEST_DISPLAY_SIZE,
+        hosts_path: PathBuf::new(),
+        sevenzip_path: PathBuf::new(),
+        log: Vec::new(),
+        dry_run_shutdown: false,
+        taskcluster: Default::default(),
+        mozilla_archive: Default::default(),
+        proxy: None,
+        profiles_dir: None,
+        metrics_host: None,
+        discovery_host: None,
+        name: None,
+        broker: None,
+        tags: Default::default(),
+        timeouts: Default::default(),
+        artifact_store: None,
+        extract_limits: Default::default(),
+        max_profile_size: None,
+    }
+}
+
+/// A fake source of builds that serves a fixture archive from disk.
+///
+/// It is both a [`BuildProvider`](../provider/trait.BuildProvider.html) and a
+/// [`Taskcluster`](../taskcluster/trait.Taskcluster.html), so that protocol
+/// tests can acquire builds without a mock HTTP server. Every task route
+/// resolves to the task ID `task-for-<route>`.
+#[derive(Debug)]
+pub struct FakeBuildProvider {
+    fixture: PathBuf,
+    failure: Option<&'static str>,
+}
+
+impl FakeBuildProvider {
+    /// Serve the archive at the given path.
+    ///
+    /// The archive does not need to be valid, so that extraction failures can
+    /// be tested.
+    pub fn new<P: Into<PathBuf>>(fixture: P) -> Self {
+        FakeBuildProvider {
+            fixture: fixture.into(),
+            failure: None,
+        }
+    }
+
+    /// Fail every fetch and task lookup with the given error.
+    pub fn with_failure(mut self, failure: &'static str) -> Self {
+        self.failure = Some(failure);
+        self
+    }
+
+    /// Copy the fixture to `dest`, reporting its size as progress.
+    async fn copy_fixture(
+        &self,
+        dest: PathBuf,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, ErrorMessage<&'static str>> {
+        if let Some(failure) = self.failure {
+            return Err(ErrorMessage(failure));
+        }
+
+        let size = fs::copy(&self.fixture, &dest).await.unwrap_or_else(|e| {
+            panic!("could not copy fixture `{}': {}", self.fixture.display(), e)
+        });
+
+        progress
+            .broadcast(DownloadProgress {
+                downloaded: size,
+                total: Some(size),
+            })
+            .ok();
+
+        Ok(dest)
+    }
+}
+
+#[async_trait]
+impl BuildProvider for FakeBuildProvider {
+    type Error = ErrorMessage<&'static str>;
+
+    async fn fetch_build(
+        &mut self,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, Self::Error> {
+        let dest = self.download_path(download_dir).unwrap();
+        self.copy_fixture(dest, progress).await
+    }
+
+    fn download_path(&self, download_dir: &Path) -> Option<PathBuf> {
+        Some(download_dir.join(self.fixture.file_name().unwrap()))
+    }
+}
+
+#[async_trait]
+impl Taskcluster for FakeBuildProvider {
+    type Error = ErrorMessage<&'static str>;
+
+    async fn download_build_artifact(
+        &mut self,
+        _task_id: &str,
+        artifact: &str,
+        download_dir: &Path,
+        progress: watch::Sender<DownloadProgress>,
+    ) -> Result<PathBuf, Self::Error> {
+        let dest = download_dir.join(artifact.rsplit('/').next().unwrap());
+        self.copy_fixture(dest, progress).await
+    }
+
+    async fn find_task(&mut self, route: &str) -> Result<String, Self::Error> {
+        match self.failure {
+            Some(failure) => Err(ErrorMessage(failure)),
+            None => Ok(format!("task-for-{}", route)),
+        }
+    }
+}
+
+/// A [`ShutdownProvider`](../osapi/trait.ShutdownProvider.html) that never
+/// restarts the machine.
+#[derive(Debug, Default)]
+pub struct TestShutdownProvider {
+    error: Option<&'static str>,
+    cancel_error: Option<&'static str>,
+    fast_startup: Option<Arc<AtomicBool>>,
+}
+
+impl TestShutdownProvider {
+    /// Fail to schedule restarts with the given error.
+    pub fn with_error(s: &'static str) -> Self {
+        TestShutdownProvider {
+            error: Some(s),
+            ..Default::default()
+        }
+    }
+
+    /// Fail to cancel restarts with the given error.
+    pub fn with_cancel_error(s: &'static str) -> Self {
+        TestShutdownProvider {
+            cancel_error: Some(s),
+            ..Default::default()
+        }
+    }
+
+    /// Emulate Fast Startup with the given setting.
+    pub fn with_fast_startup(fast_startup: Arc<AtomicBool>) -> Self {
+        TestShutdownProvider {
+            fast_startup: Some(fast_startup),
+            ..Default::default()
+        }
+    }
+}
+
+impl ShutdownProvider for TestShutdownProvider {
+    type Error = ErrorMessage<&'static str>;
+
+    fn schedule_restart(&self, _reason: &str, _delay: Duration) -> Result<(), Self::Error> {
+        match self.error {
+            Some(e) => Err(ErrorMessage(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn cancel_restart(&self) -> Result<(), Self::Error> {
+        match &self.cancel_error {
+            Some(e) => Err(ErrorMessage(e)),
+            None => Ok(()),
+        }
+    }
+
+    fn fast_startup_enabled(&self) -> Result<bool, Self::Error> {
+        Ok(self
+            .fast_startup
+            .as_ref()
+            .map(|enabled| enabled.load(Ordering::SeqCst))
+            .unwrap_or(false))
+    }
+
+    fn set_fast_startup(&self, enabled: bool) -> Result<(), Self::Error> {
+        if let Some(ref fast_startup) = self.fast_startup {
+            fast_startup.store(enabled, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+/// A [`Splash`](../splash/trait.Splash.html) that does not display anything.
+pub struct TestSplash;
+
+#[async_trait]
+impl Splash for TestSplash {
+    async fn new(_display_width: u32, _display_height: u32) -> Result<Self, io::Error> {
+        Ok(TestSplash)
+    }
+
+    fn destroy(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+/// A builder for serving a single request with a
+/// [`RunnerProto`](../proto/struct.RunnerProto.html) in tests.
+///
+/// The runner uses [`test_config`](fn.test_config.html), a default
+/// [`TestShutdownProvider`](struct.TestShutdownProvider.html) and a
+/// [`TestSplash`](struct.TestSplash.html) unless otherwise specified.
+pub struct TestRunner<T, P, R> {
+    log: Logger,
+    config: Config,
+    shutdown_provider: TestShutdownProvider,
+    tc: T,
+    perf_provider: P,
+    session_manager: R,
+}
+
+impl<T, P, R> TestRunner<T, P, R>
+where
+    T: Taskcluster + Send,
+    P: PerfProvider + 'static,
+    R: SessionManager,
+{
+    pub fn new(log: Logger, tc: T, perf_provider: P, session_manager: R) -> Self {
+        TestRunner {
+            log,
+            config: test_config(),
+            shutdown_provider: TestShutdownProvider::default(),
+            tc,
+            perf_provider,
+            session_manager,
+        }
+    }
+
+    /// Use the given configuration instead of the canned test configuration.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Use the given shutdown provider.
+    pub fn with_shutdown_provider(mut self, shutdown_provider: TestShutdownProvider) -> Self {
+        self.shutdown_provider = shutdown_provider;
+        self
+    }
+
+    /// Serve a single request from the recorder over the given stream.
+    pub async fn serve<St>(
+        self,
+        stream: St,
+    ) -> Result<RequestOutcome, RunnerProtoError<TestShutdownProvider, T, P>>
+    where
+        St: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        RunnerProto::<_, _, _, _, TestSplash, St>::handle_request(
+            self.log,
+            self.config,
+            stream,
+            self.shutdown_provider,
+            self.tc,
+            self.perf_provider,
+            self.session_manager,
+        )
+        .await
+    }
+}
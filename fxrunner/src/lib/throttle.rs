@@ -0,0 +1,390 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An embedded proxy that shapes Firefox's network traffic to emulate slower
+//! network connections.
+//!
+//! The proxy either acts as a SOCKS5 proxy, or when combined with the
+//! [record/replay proxy](../proxy/index.html), forwards all connections to it
+//! verbatim.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{abortable, AbortHandle};
+use futures::join;
+use libfxrecord::net::NetworkConditions;
+use libfxrecord::prefs::PrefValue;
+use slog::{warn, Logger};
+use tokio::io::split;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::sync::mpsc;
+use tokio::time::{delay_until, Instant};
+
+use crate::proxy::http_proxy_prefs;
+
+/// The maximum amount of data read from a connection at once.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The number of chunks that may be in flight in each direction of a
+/// connection.
+const QUEUE_LEN: usize = 64;
+
+/// A running network conditioning proxy.
+///
+/// The proxy stops accepting connections when dropped.
+pub struct Throttle {
+    port: u16,
+    upstream: Option<SocketAddr>,
+    abort_handle: AbortHandle,
+}
+
+impl Throttle {
+    /// Start the proxy with the given network conditions.
+    ///
+    /// If `upstream` is provided, all connections will be forwarded to it.
+    /// Otherwise, the proxy will act as a SOCKS5 proxy.
+    pub async fn start(
+        log: Logger,
+        conditions: &NetworkConditions,
+        upstream: Option<SocketAddr>,
+    ) -> Result<Throttle, io::Error> {
+        let mut listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+
+        let download = Arc::new(Link::new(conditions.download_kbps, conditions.latency_ms));
+        let upload = Arc::new(Link::new(conditions.upload_kbps, conditions.latency_ms));
+
+        let (accept_loop, abort_handle) = abortable(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(log, "Could not accept connection"; "error" => %e);
+                        continue;
+                    }
+                };
+
+                let log = log.clone();
+                let download = download.clone();
+                let upload = upload.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, upstream, download, upload).await {
+                        warn!(log, "Throttled connection failed"; "error" => %e);
+                    }
+                });
+            }
+        });
+
+        tokio::spawn(accept_loop);
+
+        Ok(Throttle {
+            port,
+            upstream,
+            abort_handle,
+        })
+    }
+
+    /// Return the prefs required for Firefox to use the proxy.
+    pub fn prefs(&self) -> Vec<(String, PrefValue)> {
+        if self.upstream.is_some() {
+            return http_proxy_prefs(self.port);
+        }
+
+        vec![
+            // Manual proxy configuration.
+            ("network.proxy.type".into(), 1i64.into()),
+            ("network.proxy.socks".into(), "127.0.0.1".into()),
+            (
+                "network.proxy.socks_port".into(),
+                i64::from(self.port).into(),
+            ),
+            ("network.proxy.socks_version".into(), 5i64.into()),
+            // Resolve host names through the proxy so that DNS lookups are
+            // also subject to latency.
+            ("network.proxy.socks_remote_dns".into(), true.into()),
+            ("network.proxy.no_proxies_on".into(), "".into()),
+            (
+                "network.proxy.allow_hijacking_localhost".into(),
+                true.into(),
+            ),
+        ]
+    }
+}
+
+impl Drop for Throttle {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+/// One direction of the emulated network link.
+///
+/// The bandwidth of a link is shared between all connections.
+struct Link {
+    /// The bandwidth of the link in kilobits per second.
+    ///
+    /// A bandwidth of zero is unlimited.
+    kbps: u32,
+
+    /// The one-way latency of the link.
+    latency: Duration,
+
+    /// The time at which the link will have finished transferring all queued
+    /// data.
+    next_free: Mutex<Instant>,
+}
+
+impl Link {
+    fn new(kbps: u32, rtt_ms: u32) -> Self {
+        Link {
+            kbps,
+            latency: Duration::from_millis(u64::from(rtt_ms) / 2),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Reserve the link to transfer `len` bytes.
+    ///
+    /// Returns the time at which the transfer will have completed.
+    fn reserve(&self, len: usize) -> Instant {
+        let mut next_free = self.next_free.lock().unwrap();
+        let now = Instant::now();
+
+        let start = if *next_free > now { *next_free } else { now };
+        *next_free = start + transfer_time(len, self.kbps);
+
+        *next_free
+    }
+}
+
+/// Return how long it takes to transfer `len` bytes at the given bandwidth.
+fn transfer_time(len: usize, kbps: u32) -> Duration {
+    if kbps == 0 {
+        return Duration::from_secs(0);
+    }
+
+    Duration::from_micros(len as u64 * 8 * 1000 / u64::from(kbps))
+}
+
+/// Handle a connection from Firefox.
+async fn handle_connection(
+    mut client: TcpStream,
+    upstream: Option<SocketAddr>,
+    download: Arc<Link>,
+    upload: Arc<Link>,
+) -> Result<(), io::Error> {
+    let server = match upstream {
+        Some(addr) => TcpStream::connect(addr).await?,
+        None => socks5_handshake(&mut client).await?,
+    };
+
+    let (client_read, client_write) = split(client);
+    let (server_read, server_write) = split(server);
+
+    let (upload_result, download_result) = join!(
+        throttled_copy(client_read, server_write, upload),
+        throttled_copy(server_read, client_write, download),
+    );
+
+    upload_result.and(download_result)
+}
+
+/// Copy data from `reader` to `writer` at the rate of the given link.
+async fn throttled_copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    link: Arc<Link>,
+) -> Result<(), io::Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut tx, mut rx) = mpsc::channel::<(Instant, Vec<u8>)>(QUEUE_LEN);
+    let latency = link.latency;
+
+    let read = async move {
+        let mut buf = vec![0; CHUNK_SIZE];
+
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            if tx
+                .send((Instant::now() + latency, buf[..n].into()))
+                .await
+                .is_err()
+            {
+                // The writer has failed.
+                break;
+            }
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    let write = async move {
+        while let Some((arrival, chunk)) = rx.recv().await {
+            delay_until(arrival).await;
+            delay_until(link.reserve(chunk.len())).await;
+
+            writer.write_all(&chunk).await?;
+        }
+
+        writer.shutdown().await
+    };
+
+    let (read_result, write_result) = join!(read, write);
+    read_result.and(write_result)
+}
+
+/// Perform the server side of a SOCKS5 handshake and connect to the requested
+/// address.
+///
+/// Only the `CONNECT` command without authentication is supported.
+async fn socks5_handshake(client: &mut TcpStream) -> Result<TcpStream, io::Error> {
+    const VERSION: u8 = 5;
+    const NO_AUTH: u8 = 0;
+    const NO_ACCEPTABLE_AUTH: u8 = 0xFF;
+    const CMD_CONNECT: u8 = 1;
+    const ATYP_IPV4: u8 = 1;
+    const ATYP_DOMAIN: u8 = 3;
+    const ATYP_IPV6: u8 = 4;
+    const REPLY_SUCCEEDED: u8 = 0;
+    const REPLY_FAILURE: u8 = 1;
+    const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(invalid_data("unsupported SOCKS version"));
+    }
+
+    let mut methods = vec![0u8; usize::from(header[1])];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_AUTH]).await?;
+        return Err(invalid_data("no supported SOCKS authentication method"));
+    }
+    client.write_all(&[VERSION, NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    if request[1] != CMD_CONNECT {
+        client
+            .write_all(&socks5_reply(REPLY_COMMAND_NOT_SUPPORTED))
+            .await?;
+        return Err(invalid_data("unsupported SOCKS command"));
+    }
+
+    let host = match request[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv4Addr::from(octets)).to_string()
+        }
+
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await?;
+            IpAddr::from(Ipv6Addr::from(octets)).to_string()
+        }
+
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len).await?;
+
+            let mut domain = vec![0u8; usize::from(len[0])];
+            client.read_exact(&mut domain).await?;
+
+            String::from_utf8(domain).map_err(|_| invalid_data("invalid SOCKS domain name"))?
+        }
+
+        _ => return Err(invalid_data("unsupported SOCKS address type")),
+    };
+
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port).await?;
+    let port = u16::from_be_bytes(port);
+
+    match TcpStream::connect((host.as_str(), port)).await {
+        Ok(server) => {
+            client.write_all(&socks5_reply(REPLY_SUCCEEDED)).await?;
+            Ok(server)
+        }
+
+        Err(e) => {
+            client.write_all(&socks5_reply(REPLY_FAILURE)).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Return a SOCKS5 reply with the given status.
+///
+/// The bound address is not reported.
+fn socks5_reply(status: u8) -> [u8; 10] {
+    [5, status, 0, 1, 0, 0, 0, 0, 0, 0]
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant as StdInstant;
+
+    use libfxrecord::logging::build_terminal_logger;
+    use tokio::io::copy;
+
+    use super::*;
+
+    #[test]
+    fn test_transfer_time() {
+        assert_eq!(transfer_time(1000, 8), Duration::from_secs(1));
+        assert_eq!(transfer_time(125, 1000), Duration::from_millis(1));
+        assert_eq!(transfer_time(1_000_000, 0), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_forward() {
+        let mut upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let conditions = NetworkConditions {
+            download_kbps: 0,
+            upload_kbps: 0,
+            latency_ms: 200,
+        };
+
+        let throttle = Throttle::start(build_terminal_logger(), &conditions, Some(upstream_addr))
+            .await
+            .unwrap();
+
+        let start = StdInstant::now();
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, throttle.port))
+            .await
+            .unwrap();
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(&buf, b"hello");
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
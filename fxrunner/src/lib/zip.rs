@@ -2,15 +2,85 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::fs::{create_dir_all, File};
-use std::io;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_dir, rename, File};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 
+use libfxrecord::net::ExtractProgress;
+use serde::Deserialize;
 use thiserror::Error;
-use zip::ZipArchive;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// The number of threads that extract files from a zip archive.
+const UNZIP_THREADS: usize = 4;
+
+/// Limits on what extracting an archive may produce, so that a corrupt or
+/// malicious archive (e.g., a zip bomb) cannot fill the runner's disk.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct ExtractLimits {
+    /// The maximum total size of the extracted files, in bytes.
+    pub max_size: u64,
+
+    /// The maximum number of entries in the archive.
+    pub max_entries: u64,
+
+    /// The maximum ratio of the extracted size of the archive to its
+    /// compressed size.
+    ///
+    /// Only zip archives are checked, as the compressed size of the entries of
+    /// a tarball is not known.
+    pub max_ratio: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_size: 16 * 1024 * 1024 * 1024,
+            max_entries: 100_000,
+            max_ratio: 200,
+        }
+    }
+}
+
+impl ExtractLimits {
+    /// No limits, for archives that the runner created itself.
+    pub fn unlimited() -> Self {
+        ExtractLimits {
+            max_size: u64::MAX,
+            max_entries: u64::MAX,
+            max_ratio: u64::MAX,
+        }
+    }
+
+    /// Check the number of entries and their total size.
+    pub fn check(&self, entries: u64, size: u64) -> Result<(), LimitError> {
+        if entries > self.max_entries {
+            Err(LimitError::Entries(self.max_entries))
+        } else if size > self.max_size {
+            Err(LimitError::Size(self.max_size))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check the ratio of the extracted size to the compressed size.
+    pub fn check_ratio(&self, size: u64, compressed_size: u64) -> Result<(), LimitError> {
+        if size / compressed_size.max(1) > self.max_ratio {
+            Err(LimitError::Ratio(self.max_ratio))
+        } else {
+            Ok(())
+        }
+    }
+}
 
 /// Statistics about an unzip operation.
-#[derive(Default)]
+#[derive(Debug, Default)]
 pub struct ZipStats {
     /// The number of extracted files.
     pub extracted: usize,
@@ -20,26 +90,130 @@ pub struct ZipStats {
 }
 
 /// Unzip the archive at the given location to the target location.
-pub fn unzip(archive: &Path, target: &Path) -> Result<ZipStats, ZipError> {
-    let mut stats = ZipStats::default();
+pub fn unzip(archive: &Path, target: &Path, limits: &ExtractLimits) -> Result<ZipStats, ZipError> {
+    unzip_with_progress(archive, target, limits, |_| {})
+}
 
-    let zip_file = File::open(archive).map_err(|source| ZipError::OpenArchive {
-        archive: archive.into(),
+/// Unzip the archive at the given location to the target location, reporting
+/// progress as each file is extracted.
+///
+/// The archive is rejected before anything is extracted if it exceeds the
+/// given limits, according to the sizes its entries declare. No entry may
+/// extract to more than its declared size. Entries that would be extracted
+/// outside of `target` or over another entry are rejected.
+///
+/// Directories are created up front and the files are then extracted across
+/// `UNZIP_THREADS` threads, each of which reads from its own handle to the
+/// archive. The archive is extracted with
+/// [`extract_staged`](fn.extract_staged.html), so nothing is left in `target`
+/// if it is rejected part way through.
+pub fn unzip_with_progress<F>(
+    archive: &Path,
+    target: &Path,
+    limits: &ExtractLimits,
+    progress: F,
+) -> Result<ZipStats, ZipError>
+where
+    F: FnMut(ExtractProgress),
+{
+    extract_staged(target, |staging| {
+        unzip_into(archive, staging, limits, progress)
+    })
+}
+
+/// Run `extract` on an empty directory within `target`, and move what it
+/// extracted into `target` once it has succeeded.
+///
+/// If `extract` fails, everything it extracted is removed, as is `target` if
+/// it did not already exist.
+pub fn extract_staged<T, E, F>(target: &Path, extract: F) -> Result<T, E>
+where
+    E: From<ZipError>,
+    F: FnOnce(&Path) -> Result<T, E>,
+{
+    let created = !target.exists();
+    let make_dir_error = |source| ZipError::MakeDir {
+        path: target.into(),
         source,
-    })?;
+    };
+
+    create_dir_all(target).map_err(make_dir_error)?;
+    let staging = tempfile::Builder::new()
+        .prefix(".extract")
+        .tempdir_in(target)
+        .map_err(make_dir_error)?;
+
+    let result = extract(staging.path());
+    if result.is_ok() {
+        let move_error = |path: &Path, source| ZipError::MoveExtracted {
+            path: path.into(),
+            source,
+        };
+
+        let entries = read_dir(staging.path()).map_err(|e| move_error(staging.path(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| move_error(staging.path(), e))?;
+            rename(entry.path(), target.join(entry.file_name()))
+                .map_err(|e| move_error(&entry.path(), e))?;
+        }
+    } else {
+        drop(staging);
+        if created {
+            let _ = remove_dir(target);
+        }
+    }
+
+    result
+}
+
+/// Unzip the archive into `target`, which is empty.
+fn unzip_into<F>(
+    archive: &Path,
+    target: &Path,
+    limits: &ExtractLimits,
+    mut progress: F,
+) -> Result<ZipStats, ZipError>
+where
+    F: FnMut(ExtractProgress),
+{
+    let mut stats = ZipStats::default();
+    let mut zip = open_zip(archive)?;
+    let mut files = Vec::new();
+    let mut seen = HashMap::new();
 
-    let mut zip = ZipArchive::new(zip_file).map_err(|source| ZipError::ReadArchive {
+    let limit_error = |source| ZipError::Limit {
         archive: archive.into(),
         source,
-    })?;
+    };
+
+    limits.check(zip.len() as u64, 0).map_err(limit_error)?;
+
+    let (mut size, mut compressed_size) = (0u64, 0u64);
+    for i in 0..zip.len() {
+        let zipped = zip.by_index(i).map_err(|source| ZipError::ReadArchive {
+            archive: archive.into(),
+            source,
+        })?;
+
+        size = size.saturating_add(zipped.size());
+        compressed_size = compressed_size.saturating_add(zipped.compressed_size());
+    }
+
+    limits
+        .check(zip.len() as u64, size)
+        .and_then(|()| limits.check_ratio(size, compressed_size))
+        .map_err(limit_error)?;
 
     for i in 0..zip.len() {
-        let mut zipped = zip.by_index(i).map_err(|source| ZipError::ReadArchive {
+        let zipped = zip.by_index(i).map_err(|source| ZipError::ReadArchive {
             archive: archive.into(),
             source,
         })?;
 
-        let name = zipped.sanitized_name();
+        let name = entry_path(zipped.name()).ok_or_else(|| ZipError::UnsafeEntry {
+            archive: archive.into(),
+            name: zipped.name().into(),
+        })?;
         let path = target.join(&name);
 
         if i == 0 {
@@ -55,28 +229,255 @@ pub fn unzip(archive: &Path, target: &Path) -> Result<ZipStats, ZipError> {
 
         debug_assert!(zipped.is_file());
 
+        // Windows paths are case-insensitive, so entries differing only in
+        // case would overwrite one another.
+        let key = name.to_string_lossy().to_lowercase();
+        if let Some(existing) = seen.insert(key, zipped.name().to_owned()) {
+            return Err(ZipError::Collision {
+                archive: archive.into(),
+                name: zipped.name().into(),
+                existing,
+            });
+        }
+
         let parent = path.parent().expect("path has no parent directory");
         create_dir_all(&parent).map_err(|source| ZipError::MakeDir {
             path: parent.into(),
             source,
         })?;
 
-        let mut writer = File::create(&path).map_err(|source| ZipError::Io {
+        files.push((i, path, zipped.size()));
+    }
+
+    let total = files.len();
+    progress(ExtractProgress {
+        extracted: 0,
+        total: Some(total as u64),
+    });
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let failed = Arc::new(AtomicBool::new(false));
+    let files = Arc::new(files);
+
+    let workers = (0..UNZIP_THREADS.min(total))
+        .map(|worker| {
+            let archive = archive.to_owned();
+            let files = files.clone();
+            let failed = failed.clone();
+            let done_tx = done_tx.clone();
+
+            thread::spawn(move || -> Result<(), ZipError> {
+                let result = unzip_files(&archive, &files, worker, &failed, &done_tx);
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                result
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Otherwise the loop below would never end.
+    drop(done_tx);
+
+    for () in done_rx {
+        stats.extracted += 1;
+        progress(ExtractProgress {
+            extracted: stats.extracted as u64,
+            total: Some(total as u64),
+        });
+    }
+
+    for worker in workers {
+        worker.join().expect("unzip thread panicked")?;
+    }
+
+    Ok(stats)
+}
+
+/// Extract every `UNZIP_THREADS`-th file from the archive, starting at
+/// `worker`.
+///
+/// A message is sent on `done_tx` for each file that is extracted. Extraction
+/// stops early if another worker has failed.
+fn unzip_files(
+    archive: &Path,
+    files: &[(usize, PathBuf, u64)],
+    worker: usize,
+    failed: &AtomicBool,
+    done_tx: &mpsc::Sender<()>,
+) -> Result<(), ZipError> {
+    let mut zip = open_zip(archive)?;
+
+    for (i, path, size) in files.iter().skip(worker).step_by(UNZIP_THREADS) {
+        if failed.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let mut zipped = zip.by_index(*i).map_err(|source| ZipError::ReadArchive {
+            archive: archive.into(),
+            source,
+        })?;
+
+        let mut writer = File::create(path).map_err(|source| ZipError::Io {
             archive: archive.into(),
             file_name: path.clone(),
             source,
         })?;
 
-        io::copy(&mut zipped, &mut writer).map_err(|source| ZipError::Io {
+        // Reading one byte past the declared size detects entries that
+        // decompress to more than they declare.
+        let written =
+            io::copy(&mut (&mut zipped).take(size + 1), &mut writer).map_err(|source| {
+                ZipError::Io {
+                    archive: archive.into(),
+                    file_name: path.clone(),
+                    source,
+                }
+            })?;
+
+        if written > *size {
+            return Err(ZipError::Limit {
+                archive: archive.into(),
+                source: LimitError::Overrun(zipped.name().into()),
+            });
+        }
+
+        // Executables must keep their executable bit to be run.
+        #[cfg(unix)]
+        {
+            use std::fs::Permissions;
+            use std::os::unix::fs::PermissionsExt;
+
+            if let Some(mode) = zipped.unix_mode() {
+                writer
+                    .set_permissions(Permissions::from_mode(mode & 0o777))
+                    .map_err(|source| ZipError::Io {
+                        archive: archive.into(),
+                        file_name: path.clone(),
+                        source,
+                    })?;
+            }
+        }
+
+        // The receiver only goes away if the caller has panicked.
+        let _ = done_tx.send(());
+    }
+
+    Ok(())
+}
+
+/// Return the path, relative to the target directory, that the zip entry with
+/// the given name is extracted to.
+///
+/// Returns `None` if the entry would be extracted outside of the target
+/// directory, i.e., if it is an absolute path, has a drive letter, or has a
+/// `..` component.
+fn entry_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+
+    for (i, component) in name.split(['/', '\\']).enumerate() {
+        match component {
+            "" if i == 0 => return None,
+            "" | "." => {}
+            ".." => return None,
+            c if c.contains(':') => return None,
+            c => path.push(c),
+        }
+    }
+
+    Some(path)
+}
+
+/// Open the zip archive at the given path.
+fn open_zip(archive: &Path) -> Result<ZipArchive<File>, ZipError> {
+    let zip_file = File::open(archive).map_err(|source| ZipError::OpenArchive {
+        archive: archive.into(),
+        source,
+    })?;
+
+    ZipArchive::new(zip_file).map_err(|source| ZipError::ReadArchive {
+        archive: archive.into(),
+        source,
+    })
+}
+
+/// Zip the contents of the directory at `source` into a new archive at the
+/// given location.
+///
+/// Returns the number of files archived.
+pub fn zip_dir(source: &Path, archive: &Path) -> Result<usize, ZipError> {
+    let zip_file = File::create(archive).map_err(|source| ZipError::CreateArchive {
+        archive: archive.into(),
+        source,
+    })?;
+
+    let mut zip = ZipWriter::new(zip_file);
+    let mut archived = 0;
+    let mut dirs = vec![source.to_owned()];
+
+    while let Some(dir) = dirs.pop() {
+        let entries = read_dir(&dir).map_err(|source| ZipError::Io {
             archive: archive.into(),
-            file_name: path,
+            file_name: dir.clone(),
             source,
         })?;
 
-        stats.extracted += 1;
+        for entry in entries {
+            let path = entry
+                .map_err(|source| ZipError::Io {
+                    archive: archive.into(),
+                    file_name: dir.clone(),
+                    source,
+                })?
+                .path();
+
+            // Zip files always use forward slashes as path separators.
+            let name = path
+                .strip_prefix(source)
+                .expect("entry is not within the source directory")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            if path.is_dir() {
+                zip.add_directory(name, FileOptions::default())
+                    .map_err(|source| ZipError::WriteArchive {
+                        archive: archive.into(),
+                        source,
+                    })?;
+                dirs.push(path);
+                continue;
+            }
+
+            zip.start_file(name, FileOptions::default())
+                .map_err(|source| ZipError::WriteArchive {
+                    archive: archive.into(),
+                    source,
+                })?;
+
+            let mut f = File::open(&path).map_err(|source| ZipError::Io {
+                archive: archive.into(),
+                file_name: path.clone(),
+                source,
+            })?;
+
+            io::copy(&mut f, &mut zip).map_err(|source| ZipError::Io {
+                archive: archive.into(),
+                file_name: path,
+                source,
+            })?;
+
+            archived += 1;
+        }
     }
 
-    Ok(stats)
+    zip.finish().map_err(|source| ZipError::WriteArchive {
+        archive: archive.into(),
+        source,
+    })?;
+
+    Ok(archived)
 }
 
 fn common_stem(p1: &Path, p2: &Path) -> Option<PathBuf> {
@@ -131,22 +532,100 @@ pub enum ZipError {
         source: io::Error,
     },
 
+    #[error(
+        "Could not create zip archive `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    CreateArchive { archive: PathBuf, source: io::Error },
+
+    #[error(
+        "Could not write zip archive `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    WriteArchive {
+        archive: PathBuf,
+        source: zip::result::ZipError,
+    },
+
     #[error(
         "could not make required directory `{}': {}",
         .path.display(),
         .source
     )]
     MakeDir { path: PathBuf, source: io::Error },
+
+    #[error(
+        "Could not move extracted `{}' into place: {}",
+        .path.display(),
+        .source
+    )]
+    MoveExtracted { path: PathBuf, source: io::Error },
+
+    #[error(
+        "Refusing to extract zip archive `{}': {}",
+        .archive.display(),
+        .source
+    )]
+    Limit {
+        archive: PathBuf,
+        source: LimitError,
+    },
+
+    #[error(
+        "Refusing to extract `{}' from zip archive `{}': it is outside of the target directory",
+        .name,
+        .archive.display()
+    )]
+    UnsafeEntry { archive: PathBuf, name: String },
+
+    #[error(
+        "Refusing to extract `{}' from zip archive `{}': it collides with `{}'",
+        .name,
+        .archive.display(),
+        .existing
+    )]
+    Collision {
+        archive: PathBuf,
+        name: String,
+        existing: String,
+    },
+}
+
+/// Why an archive was rejected by its [`ExtractLimits`](struct.ExtractLimits.html).
+#[derive(Debug, Error)]
+pub enum LimitError {
+    #[error("it has more than {} entries", .0)]
+    Entries(u64),
+
+    #[error("it extracts to more than {} bytes", .0)]
+    Size(u64),
+
+    #[error("it is compressed more than {} to 1", .0)]
+    Ratio(u64),
+
+    #[error("`{}' extracts to more than its declared size", .0)]
+    Overrun(String),
 }
 
 #[cfg(test)]
 mod test {
     use std::env::current_dir;
+    use std::fs::{create_dir, read_dir, read_to_string, write, File};
+    use std::io::Write;
     use std::path::{Path, PathBuf};
 
+    use assert_matches::assert_matches;
+    use libfxrecord::net::ExtractProgress;
     use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
 
-    use super::{common_stem, unzip};
+    use super::{
+        common_stem, entry_path, unzip, unzip_with_progress, zip_dir, ExtractLimits, LimitError,
+        ZipError, UNZIP_THREADS,
+    };
 
     #[test]
     fn test_zip() {
@@ -155,7 +634,7 @@ mod test {
             let zip = test_dir.join("test.zip");
             let tempdir = TempDir::new().unwrap();
 
-            let stats = unzip(&zip, tempdir.path()).unwrap();
+            let stats = unzip(&zip, tempdir.path(), &ExtractLimits::default()).unwrap();
 
             let dir_path = tempdir.path().join("dir");
             assert!(dir_path.is_dir());
@@ -170,7 +649,7 @@ mod test {
             let zip = test_dir.join("profile.zip");
             let tempdir = TempDir::new().unwrap();
 
-            let stats = unzip(&zip, tempdir.path()).unwrap();
+            let stats = unzip(&zip, tempdir.path(), &ExtractLimits::default()).unwrap();
 
             assert!(tempdir.path().join("places.sqlite").is_file());
             assert!(tempdir.path().join("prefs.js").is_file());
@@ -184,7 +663,7 @@ mod test {
             let zip = test_dir.join("profile_nested.zip");
             let tempdir = TempDir::new().unwrap();
 
-            let stats = unzip(&zip, tempdir.path()).unwrap();
+            let stats = unzip(&zip, tempdir.path(), &ExtractLimits::default()).unwrap();
             let profile_dir = tempdir.path().join("profile");
 
             assert!(profile_dir.is_dir());
@@ -225,4 +704,200 @@ mod test {
             None,
         );
     }
+
+    #[test]
+    fn test_zip_dir() {
+        let tempdir = TempDir::new().unwrap();
+        let source = tempdir.path().join("source");
+        let archive = tempdir.path().join("archive.zip");
+        let target = tempdir.path().join("target");
+
+        create_dir(&source).unwrap();
+        create_dir(source.join("nested")).unwrap();
+        create_dir(source.join("empty")).unwrap();
+        write(source.join("prefs.js"), "prefs").unwrap();
+        write(source.join("nested").join("data.sqlite"), "data").unwrap();
+
+        assert_eq!(zip_dir(&source, &archive).unwrap(), 2);
+
+        let stats = unzip(&archive, &target, &ExtractLimits::default()).unwrap();
+        assert_eq!(stats.extracted, 2);
+
+        assert_eq!(read_to_string(target.join("prefs.js")).unwrap(), "prefs");
+        assert_eq!(
+            read_to_string(target.join("nested").join("data.sqlite")).unwrap(),
+            "data"
+        );
+        assert!(target.join("empty").is_dir());
+    }
+
+    #[test]
+    fn test_unzip_with_progress() {
+        let tempdir = TempDir::new().unwrap();
+        let source = tempdir.path().join("source");
+        let archive = tempdir.path().join("archive.zip");
+        let target = tempdir.path().join("target");
+
+        // Enough files that every thread has more than one to extract.
+        let count = UNZIP_THREADS * 2 + 1;
+
+        create_dir(&source).unwrap();
+        for i in 0..count {
+            write(source.join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+
+        assert_eq!(zip_dir(&source, &archive).unwrap(), count);
+
+        let mut reports = vec![];
+        let stats = unzip_with_progress(&archive, &target, &ExtractLimits::default(), |progress| {
+            reports.push(progress)
+        })
+        .unwrap();
+        assert_eq!(stats.extracted, count);
+
+        for i in 0..count {
+            assert_eq!(
+                read_to_string(target.join(format!("{}.txt", i))).unwrap(),
+                i.to_string()
+            );
+        }
+
+        assert_eq!(
+            reports,
+            (0..=count as u64)
+                .map(|extracted| ExtractProgress {
+                    extracted,
+                    total: Some(count as u64),
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_unzip_limits() {
+        let profile = current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .join("test")
+            .join("profile.zip");
+        let tempdir = TempDir::new().unwrap();
+        let target = tempdir.path().join("target");
+
+        assert_matches!(
+            unzip(
+                &profile,
+                &target,
+                &ExtractLimits {
+                    max_entries: 2,
+                    ..Default::default()
+                }
+            ),
+            Err(ZipError::Limit {
+                source: LimitError::Entries(2),
+                ..
+            })
+        );
+        assert!(!target.exists());
+
+        // A megabyte of zeroes compresses far more than any real profile or
+        // build.
+        let source = tempdir.path().join("source");
+        let archive = tempdir.path().join("bomb.zip");
+        create_dir(&source).unwrap();
+        write(source.join("zeroes"), vec![0u8; 1024 * 1024]).unwrap();
+        zip_dir(&source, &archive).unwrap();
+
+        assert_matches!(
+            unzip(
+                &archive,
+                &target,
+                &ExtractLimits {
+                    max_size: 1024,
+                    ..ExtractLimits::unlimited()
+                }
+            ),
+            Err(ZipError::Limit {
+                source: LimitError::Size(1024),
+                ..
+            })
+        );
+        assert!(!target.exists());
+
+        assert_matches!(
+            unzip(&archive, &target, &ExtractLimits::default()),
+            Err(ZipError::Limit {
+                source: LimitError::Ratio(200),
+                ..
+            })
+        );
+        unzip(&archive, &target, &ExtractLimits::unlimited()).unwrap();
+    }
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(
+            entry_path("profile/prefs.js"),
+            Some(PathBuf::from("profile").join("prefs.js"))
+        );
+        assert_eq!(
+            entry_path("profile\\.\\prefs.js"),
+            Some(PathBuf::from("profile").join("prefs.js"))
+        );
+        assert_eq!(entry_path("profile/"), Some(PathBuf::from("profile")));
+
+        assert_eq!(entry_path("../prefs.js"), None);
+        assert_eq!(entry_path("profile/../../prefs.js"), None);
+        assert_eq!(entry_path("/etc/passwd"), None);
+        assert_eq!(entry_path("\\Windows\\win.ini"), None);
+        assert_eq!(entry_path("C:\\Windows\\win.ini"), None);
+        assert_eq!(entry_path("prefs.js:stream"), None);
+    }
+
+    #[test]
+    fn test_unzip_unsafe() {
+        let tempdir = TempDir::new().unwrap();
+        let archive = tempdir.path().join("archive.zip");
+        let target = tempdir.path().join("target");
+
+        let write_zip = |names: &[&str]| {
+            let mut writer = ZipWriter::new(File::create(&archive).unwrap());
+            for name in names {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(b"contents").unwrap();
+            }
+            writer.finish().unwrap();
+        };
+
+        write_zip(&["prefs.js", "../escape.js"]);
+        assert_matches!(
+            unzip(&archive, &target, &ExtractLimits::default()),
+            Err(ZipError::UnsafeEntry { ref name, .. }) if name == "../escape.js"
+        );
+        assert!(!tempdir.path().join("escape.js").exists());
+        assert!(!target.exists());
+
+        write_zip(&["prefs.js", "Prefs.js"]);
+        assert_matches!(
+            unzip(&archive, &target, &ExtractLimits::default()),
+            Err(ZipError::Collision { ref name, ref existing, .. })
+                if name == "Prefs.js" && existing == "prefs.js"
+        );
+        assert!(!target.exists());
+
+        // An existing target is left as it was.
+        create_dir(&target).unwrap();
+        write(target.join("user.js"), "user").unwrap();
+        assert_matches!(
+            unzip(&archive, &target, &ExtractLimits::default()),
+            Err(ZipError::Collision { .. })
+        );
+        assert_eq!(
+            read_dir(&target)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            vec!["user.js"]
+        );
+    }
 }
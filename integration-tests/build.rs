@@ -31,17 +31,30 @@ fn main() {
     let out_dir = env::var("OUT_DIR").expect("no OUT_DIR during cargo build");
     let out_dir = Path::new(&out_dir);
 
-    let fakefox_path = fakefox_target_path.join("debug").join("fakefox.exe");
+    // Build scripts are compiled for the host, so the target platform has to
+    // be checked at runtime.
+    let exe_suffix = if env::var_os("CARGO_CFG_WINDOWS").is_some() {
+        ".exe"
+    } else {
+        ""
+    };
+
+    let fakefox_path = fakefox_target_path
+        .join("debug")
+        .join(format!("fakefox{}", exe_suffix));
     let zip_path = out_dir.join("firefox.zip");
 
     let mut zip_file = File::create(&zip_path).expect("could not create firefox.zip");
-    let mut fakefox_file = File::open(&fakefox_path).expect("could not open fakefox.exe");
+    let mut fakefox_file = File::open(&fakefox_path).expect("could not open fakefox");
 
     let mut zip = ZipWriter::new(&mut zip_file);
     zip.add_directory("firefox", FileOptions::default())
         .unwrap();
-    zip.start_file("firefox/firefox.exe", FileOptions::default())
-        .unwrap();
+    zip.start_file(
+        format!("firefox/firefox{}", exe_suffix),
+        FileOptions::default().unix_permissions(0o755),
+    )
+    .unwrap();
     io::copy(&mut fakefox_file, &mut zip).unwrap();
     zip.finish().unwrap();
 
@@ -5,102 +5,34 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::io;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
 use libfxrecord::error::ErrorMessage;
-use libfxrecorder::recorder::Recorder;
-use libfxrunner::osapi::{CpuTimes, IoCounters, PerfProvider, ShutdownProvider};
+use libfxrecord::net::RestartRecord;
+use libfxrunner::osapi::{CpuTimes, DesktopError, IoCounters, PerfProvider};
+use libfxrunner::profile_cache::{ProfileCache, PROFILE_CACHE_NAME};
+use libfxrunner::restarts::{append_restart_record, read_restart_log, RestartLogError};
 use libfxrunner::session::{
     NewSessionError, ResumeSessionError, ResumeSessionErrorKind, SessionInfo, SessionManager,
 };
-use libfxrunner::splash::Splash;
-use libfxrunner::taskcluster::Taskcluster;
+use libfxrunner::store::{Store, STORE_NAME};
 use tempfile::TempDir;
 use tokio::fs;
 
-use crate::util::{firefox_zip_path, test_dir, AssertInvoked};
+use crate::util::{firefox_zip_path, AssertInvoked};
 
 /// The only valid session ID for TestSessionManager.
 pub const VALID_SESSION_ID: &str = "REQUESTID";
 
-#[derive(Debug, Default)]
-pub struct TestShutdownProvider {
-    error: Option<&'static str>,
-}
-
-impl TestShutdownProvider {
-    pub fn with_error(s: &'static str) -> Self {
-        TestShutdownProvider { error: Some(s) }
-    }
-}
-
-impl ShutdownProvider for TestShutdownProvider {
-    type Error = ErrorMessage<&'static str>;
-
-    fn initiate_restart(&self, _reason: &str) -> Result<(), Self::Error> {
-        match self.error {
-            Some(ref e) => Err(ErrorMessage(e)),
-            None => Ok(()),
-        }
-    }
-}
-
-#[derive(Debug, Default)]
-pub struct TestTaskcluster {
-    failure_mode: Option<TaskclusterFailureMode>,
-}
-
-#[derive(Debug)]
-pub enum TaskclusterFailureMode {
-    BadZip,
-    NotZip,
-    Generic(&'static str),
-}
-
-impl TestTaskcluster {
-    pub fn with_failure(failure_mode: TaskclusterFailureMode) -> Self {
-        Self {
-            failure_mode: Some(failure_mode),
-        }
-    }
-}
-
-#[async_trait]
-impl Taskcluster for TestTaskcluster {
-    type Error = ErrorMessage<&'static str>;
-
-    async fn download_build_artifact(
-        &mut self,
-        _task_id: &str,
-        download_dir: &Path,
-    ) -> Result<PathBuf, Self::Error> {
-        let zip_path = match self.failure_mode {
-            Some(TaskclusterFailureMode::Generic(e)) => {
-                return Err(ErrorMessage(e));
-            }
-            Some(TaskclusterFailureMode::BadZip) => test_dir().join("test.zip"),
-            Some(TaskclusterFailureMode::NotZip) => test_dir().join("README.md"),
-            None => firefox_zip_path(),
-        };
-
-        let dest = download_dir.join("firefox.zip");
-
-        assert!(zip_path.exists());
-
-        fs::copy(&zip_path, &dest).await.unwrap();
-
-        Ok(dest)
-    }
-}
-
 #[derive(Debug)]
 pub enum PerfFailureMode {
     DiskIoError(&'static str),
     CpuTimeError(&'static str),
     DiskNeverIdle,
     CpuNeverIdle,
+    DesktopLocked,
 }
 
 #[derive(Debug)]
@@ -196,6 +128,13 @@ impl PerfProvider for TestPerfProvider {
             }
         }
     }
+
+    fn check_desktop(&self) -> Result<(), DesktopError> {
+        match self.failure_mode {
+            Some(PerfFailureMode::DesktopLocked) => Err(DesktopError::Locked),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -232,6 +171,14 @@ impl TestSessionManagerHandle {
     pub fn last_session_info(&self) -> Option<SessionInfo<'static>> {
         self.last_session_info.lock().unwrap().take()
     }
+
+    pub fn store(&self) -> Store {
+        Store::new(self.tempdir.path().join(STORE_NAME))
+    }
+
+    pub fn profile_cache(&self) -> ProfileCache {
+        ProfileCache::new(self.tempdir.path().join(PROFILE_CACHE_NAME), self.store())
+    }
 }
 
 impl Default for TestSessionManager {
@@ -304,7 +251,12 @@ impl SessionManager for TestSessionManager {
             .await
             .unwrap();
 
-        libfxrunner::zip::unzip(&firefox_zip_path(), &session_info.path).unwrap();
+        libfxrunner::zip::unzip(
+            &firefox_zip_path(),
+            &session_info.path,
+            &libfxrunner::zip::ExtractLimits::default(),
+        )
+        .unwrap();
 
         *self.handle.last_session_info.lock().unwrap() = Some(session_info.clone());
         Ok(session_info)
@@ -334,6 +286,22 @@ impl SessionManager for TestSessionManager {
         fs::create_dir(&profile_path).await.unwrap();
         Ok(profile_path)
     }
+
+    async fn record_restart(&self, record: &RestartRecord) -> Result<(), RestartLogError> {
+        append_restart_record(self.handle.tempdir.path(), record).await
+    }
+
+    async fn restart_log(&self) -> Result<Vec<RestartRecord>, RestartLogError> {
+        read_restart_log(self.handle.tempdir.path()).await
+    }
+
+    fn store(&self) -> Store {
+        self.handle.store()
+    }
+
+    fn profile_cache(&self) -> ProfileCache {
+        self.handle.profile_cache()
+    }
 }
 
 fn clone_new_session_err(err: &NewSessionError) -> NewSessionError {
@@ -347,36 +315,3 @@ fn clone_new_session_err(err: &NewSessionError) -> NewSessionError {
         }
     }
 }
-
-pub struct TestSplash;
-
-#[async_trait]
-impl Splash for TestSplash {
-    async fn new(_display_width: u32, _display_height: u32) -> Result<Self, io::Error> {
-        Ok(TestSplash)
-    }
-
-    fn destroy(&mut self) -> Result<(), io::Error> {
-        Ok(())
-    }
-}
-
-pub struct TestRecorder;
-pub struct TestRecorderHandle(PathBuf);
-
-#[async_trait]
-impl Recorder for TestRecorder {
-    type Error = io::Error;
-    type Handle = TestRecorderHandle;
-
-    async fn start_recording(&self, directory: &Path) -> Result<Self::Handle, Self::Error> {
-        Ok(TestRecorderHandle(directory.join("recording.mp4")))
-    }
-
-    async fn wait_for_recording_finished(
-        &self,
-        handle: Self::Handle,
-    ) -> Result<PathBuf, Self::Error> {
-        Ok(handle.0)
-    }
-}
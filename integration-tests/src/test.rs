@@ -10,19 +10,34 @@ use std::convert::TryInto;
 use std::fs::File;
 use std::future::Future;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use assert_matches::assert_matches;
 use futures::join;
 use indoc::indoc;
+use libfxrecord::error::ForeignErrorKind;
 use libfxrecord::net::*;
-use libfxrecorder::proto::{RecorderProto, RecorderProtoError};
-use libfxrunner::config::Size;
-use libfxrunner::osapi::WaitForIdleError;
-use libfxrunner::proto::{RunnerProto, RunnerProtoError};
+use libfxrecord::testing::duplex;
+use libfxrecord::timeout::{TimeoutConfig, TimeoutError, TimeoutPhase};
+use libfxrecorder::proto::{BuildRequest, RecorderProto, RecorderProtoError};
+use libfxrecorder::testing::{test_recorder_proto, TestRecorder};
+use libfxrunner::archive::ArchiveError;
+use libfxrunner::config::Config;
+use libfxrunner::hosts::HostsError;
+use libfxrunner::osapi::{DesktopError, WaitForIdleError};
+use libfxrunner::proto::{RequestOutcome, RunnerProtoError, BUILD_REFS};
+use libfxrunner::provider::UrlBuildError;
+use libfxrunner::proxy::ProxyError;
 use libfxrunner::session::{
     NewSessionError, ResumeSessionError, ResumeSessionErrorKind, SessionInfo,
 };
-use libfxrunner::zip::ZipError;
+use libfxrunner::staging::StagingError;
+use libfxrunner::taskcluster::BUILD_ARTIFACT_NAME;
+use libfxrunner::templates::TemplateError;
+use libfxrunner::testing::{test_config, FakeBuildProvider, TestRunner, TestShutdownProvider};
+use libfxrunner::zip::{unzip, ExtractLimits, ZipError};
 use serde_json::{json, Value};
 use tempfile::TempDir;
 use tokio::net::{TcpListener, TcpStream};
@@ -31,22 +46,13 @@ use crate::logging::build_test_loggers;
 use crate::mocks::*;
 use crate::util::*;
 
-type TestRunnerProto = RunnerProto<
-    TestShutdownProvider,
-    TestTaskcluster,
-    TestPerfProvider,
-    TestSessionManager,
-    TestSplash,
->;
 type TestRunnerProtoError =
-    RunnerProtoError<TestShutdownProvider, TestTaskcluster, TestPerfProvider>;
+    RunnerProtoError<TestShutdownProvider, FakeBuildProvider, TestPerfProvider>;
 
 type TestRecorderProto = RecorderProto<TestRecorder>;
 
-const DISPLAY_SIZE: Size = Size { x: 640, y: 480 };
-
 struct RunnerInfo {
-    result: Result<bool, TestRunnerProtoError>,
+    result: Result<RequestOutcome, TestRunnerProtoError>,
     session_info: Option<SessionInfo<'static>>,
 }
 
@@ -54,7 +60,7 @@ struct RunnerInfo {
 async fn run_proto_test<'a, Fut>(
     listener: &mut TcpListener,
     shutdown_provider: TestShutdownProvider,
-    tc: TestTaskcluster,
+    tc: FakeBuildProvider,
     perf_provider: TestPerfProvider,
     session_manager: TestSessionManager,
     recorder_fn: impl FnOnce(TestRecorderProto, PathBuf) -> Fut,
@@ -71,16 +77,10 @@ async fn run_proto_test<'a, Fut>(
 
         let handle = session_manager.handle();
 
-        let result = TestRunnerProto::handle_request(
-            runner_logger,
-            DISPLAY_SIZE,
-            stream,
-            shutdown_provider,
-            tc,
-            perf_provider,
-            session_manager,
-        )
-        .await;
+        let result = TestRunner::new(runner_logger, tc, perf_provider, session_manager)
+            .with_shutdown_provider(shutdown_provider)
+            .serve(stream)
+            .await;
 
         runner_fn(RunnerInfo {
             result,
@@ -90,7 +90,7 @@ async fn run_proto_test<'a, Fut>(
 
     let recorder = async {
         let stream = TcpStream::connect(&addr).await.unwrap();
-        let proto = TestRecorderProto::new(recorder_logger, stream, TestRecorder);
+        let proto = test_recorder_proto(recorder_logger, stream);
         let tempdir = TempDir::new().expect("could not create tempdir for run_proto_test");
 
         // Pass a PathBuf to work around lifetime issues of closures.
@@ -100,6 +100,59 @@ async fn run_proto_test<'a, Fut>(
     join!(runner, recorder);
 }
 
+#[tokio::test]
+async fn test_ping() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _| async move {
+            let status = recorder.ping().await.unwrap();
+
+            assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+            assert!(!status.busy);
+            assert!(status.restarts.is_empty());
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Status);
+            assert!(session_info.is_none());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_ping_in_memory() {
+    let (runner_stream, recorder_stream) = duplex();
+    let (runner_logger, recorder_logger) = build_test_loggers();
+
+    let runner = TestRunner::new(
+        runner_logger,
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+    )
+    .serve(runner_stream);
+
+    let recorder = async {
+        let mut proto = test_recorder_proto(recorder_logger, recorder_stream);
+        proto.ping().await.unwrap()
+    };
+
+    let (result, status) = join!(runner, recorder);
+
+    assert_eq!(result.unwrap(), RequestOutcome::Status);
+    assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+    assert!(!status.busy);
+}
+
 #[tokio::test]
 async fn test_new_session_ok() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -107,12 +160,15 @@ async fn test_new_session_ok() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::default(),
         |mut recorder, _tempdir| async move {
             assert_eq!(
-                recorder.new_session("task_id", None, &[]).await.unwrap(),
+                recorder
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
+                    .await
+                    .unwrap(),
                 VALID_SESSION_ID
             );
         },
@@ -120,7 +176,7 @@ async fn test_new_session_ok() {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), true);
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
             let session_info = session_info.unwrap();
             assert!(session_info.firefox_path().is_file());
@@ -155,13 +211,17 @@ async fn test_new_session_ok() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::default(),
         |mut recorder, _tempdir| async move {
             assert_eq!(
                 recorder
-                    .new_session("task_id", Some(&test_dir().join("profile.zip")), &[])
+                    .new_session(
+                        BuildTask::from("task_id").into(),
+                        Some(&test_dir().join("profile.zip")),
+                        &[]
+                    )
                     .await
                     .unwrap(),
                 VALID_SESSION_ID
@@ -171,7 +231,7 @@ async fn test_new_session_ok() {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), true);
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
             let session_info = session_info.unwrap();
             assert!(session_info.firefox_path().is_file());
@@ -186,13 +246,13 @@ async fn test_new_session_ok() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::default(),
         |mut recorder, _tempdir| async move {
             let session_id = recorder
                 .new_session(
-                    "task_id",
+                    BuildTask::from("task_id").into(),
                     Some(&test_dir().join("profile.zip")),
                     &[
                         (
@@ -212,7 +272,7 @@ async fn test_new_session_ok() {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), true);
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
             let session_info = session_info.unwrap();
             assert!(session_info.firefox_path().is_file());
@@ -235,13 +295,13 @@ async fn test_new_session_ok() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::default(),
         |mut recorder, _tempdir| async move {
             let session_id = recorder
                 .new_session(
-                    "task_id",
+                    BuildTask::from("task_id").into(),
                     None,
                     &[
                         (
@@ -261,7 +321,7 @@ async fn test_new_session_ok() {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), true);
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
             let session_info = session_info.unwrap();
             assert!(session_info.firefox_path().is_file());
@@ -282,210 +342,831 @@ async fn test_new_session_ok() {
 }
 
 #[tokio::test]
-async fn test_new_session_err_request_manager() {
+async fn test_new_session_profile_delta() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
+    // Nothing is cached, so the entire profile is sent and then cached.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
-        TestSessionManager::with_failure(SessionFailureMode::NewSession(
-            NewSessionError::TooManyAttempts(32),
-        )),
-        |mut recorder, _tempdir| async move {
-            assert_matches!(
-                recorder.new_session("task_id", None, &[]).await.unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    assert_eq!(
-                        e.to_string(),
-                        "Could not create a request directory after 32 attempts");
-                }
+        session_manager,
+        |recorder, _tempdir| async move {
+            let mut recorder = recorder.with_profile_delta(true);
+            assert_eq!(
+                recorder
+                    .new_session(
+                        BuildTask::from("task_id").into(),
+                        Some(&test_dir().join("profile_nested.zip")),
+                        &[(
+                            "foo".into(),
+                            Value::String("bar".into()).try_into().unwrap(),
+                        )],
+                    )
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
             );
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::NewSession(NewSessionError::TooManyAttempts(32))
-            );
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
-            assert!(session_info.is_none());
+            let profile_dir = session_info.unwrap().profile_path();
+            assert_populated_profile(&profile_dir);
+            assert_file_contents_eq(&profile_dir.join("user.js"), "pref(\"foo\", \"bar\");\n");
         },
     )
     .await;
 
+    // The cached profile does not contain the prefs written for the session.
+    let manifest = handle.profile_cache().manifest().unwrap();
+    assert_eq!(
+        manifest.keys().collect::<Vec<_>>(),
+        vec!["places.sqlite", "prefs.js", "user.js"]
+    );
+
+    // Only the changed files are sent and the removed files are not copied
+    // from the cache.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+
+    {
+        let stale = TempDir::new().unwrap();
+        std::fs::write(stale.path().join("places.sqlite"), "").unwrap();
+        std::fs::write(stale.path().join("prefs.js"), "stale").unwrap();
+        std::fs::write(stale.path().join("removed.js"), "removed").unwrap();
+        handle.profile_cache().update(stale.path()).unwrap();
+    }
+
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
-        TestSessionManager::with_failure(SessionFailureMode::EnsureProfileDir(
-            "could not ensure profile directory",
-        )),
-        |mut recorder, _tempdir| async move {
-            assert_matches!(
-                recorder.new_session("task_id", None, &[]).await.unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    assert_eq!(
-                        e.to_string(),
-                        "could not ensure profile directory");
-                }
+        session_manager,
+        |recorder, _tempdir| async move {
+            let mut recorder = recorder.with_profile_delta(true);
+            assert_eq!(
+                recorder
+                    .new_session(
+                        BuildTask::from("task_id").into(),
+                        Some(&test_dir().join("profile.zip")),
+                        &[]
+                    )
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
             );
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::EnsureProfile(e) => {
-                    assert_eq!(e.to_string(), "could not ensure profile directory");
-                }
-            );
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
-            let session_info = session_info.unwrap();
-            assert_eq!(session_info.id, VALID_SESSION_ID);
-            assert!(!session_info.path.exists());
+            let profile_dir = session_info.unwrap().profile_path();
+            assert_populated_profile(&profile_dir);
+            assert_file_contents_eq(&profile_dir.join("prefs.js"), "");
+            assert!(!profile_dir.join("removed.js").exists());
         },
     )
     .await;
+
+    let manifest = handle.profile_cache().manifest().unwrap();
+    assert_eq!(
+        manifest.keys().collect::<Vec<_>>(),
+        vec!["places.sqlite", "prefs.js", "user.js"]
+    );
 }
 
 #[tokio::test]
-async fn test_new_session_err_downloadbuild() {
+async fn test_new_session_profile_template() {
+    let profiles_dir = TempDir::new().unwrap();
+    unzip(
+        &test_dir().join("profile.zip"),
+        &profiles_dir.path().join("clean"),
+        &ExtractLimits::default(),
+    )
+    .unwrap();
+
+    let config = Config {
+        profiles_dir: Some(profiles_dir.path().into()),
+        ..test_config()
+    };
+
+    {
+        let (runner_stream, recorder_stream) = duplex();
+        let (runner_logger, recorder_logger) = build_test_loggers();
+
+        let session_manager = TestSessionManager::default();
+        let handle = session_manager.handle();
+
+        let runner = TestRunner::new(
+            runner_logger,
+            FakeBuildProvider::new(firefox_zip_path()),
+            TestPerfProvider::default(),
+            session_manager,
+        )
+        .with_config(config.clone())
+        .serve(runner_stream);
+
+        let recorder = async {
+            let mut recorder = test_recorder_proto(recorder_logger, recorder_stream)
+                .with_profile_template(Some("clean".into()));
+
+            recorder
+                .new_session(
+                    BuildTask::from("task_id").into(),
+                    None,
+                    &[("foo".into(), Value::Bool(true).try_into().unwrap())],
+                )
+                .await
+                .unwrap()
+        };
+
+        let (result, session_id) = join!(runner, recorder);
+
+        assert_eq!(result.unwrap(), RequestOutcome::Restart);
+        assert_eq!(session_id, VALID_SESSION_ID);
+
+        let profile_dir = handle.last_session_info().unwrap().profile_path();
+        assert_populated_profile(&profile_dir);
+        assert_file_contents_eq(&profile_dir.join("user.js"), "pref(\"foo\", true);\n");
+
+        // Prefs are written to the copy, not the template.
+        assert_file_contents_eq(&profiles_dir.path().join("clean").join("user.js"), "");
+    }
+
+    {
+        let (runner_stream, recorder_stream) = duplex();
+        let (runner_logger, recorder_logger) = build_test_loggers();
+
+        let runner = TestRunner::new(
+            runner_logger,
+            FakeBuildProvider::new(firefox_zip_path()),
+            TestPerfProvider::default(),
+            TestSessionManager::default(),
+        )
+        .with_config(config)
+        .serve(runner_stream);
+
+        let recorder = async {
+            let mut recorder = test_recorder_proto(recorder_logger, recorder_stream)
+                .with_profile_template(Some("heavy-user".into()));
+
+            recorder
+                .new_session(BuildTask::from("task_id").into(), None, &[])
+                .await
+                .unwrap_err()
+        };
+
+        let (result, err) = join!(runner, recorder);
+
+        assert_matches!(
+            result.unwrap_err(),
+            RunnerProtoError::ProfileTemplate(TemplateError::MissingTemplate(name)) => {
+                assert_eq!(name, "heavy-user");
+            }
+        );
+        assert_matches!(
+            err,
+            RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                assert_eq!(e.kind, ForeignErrorKind::Profile);
+                assert_eq!(e.to_string(), "Profile template `heavy-user' does not exist");
+            }
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let (runner_stream, _recorder_stream) = duplex();
+    let (runner_logger, _) = build_test_loggers();
+
+    let config = Config {
+        timeouts: TimeoutConfig {
+            handshake_secs: Some(1),
+            ..TimeoutConfig::default()
+        },
+        ..test_config()
+    };
+
+    // The recorder connects but never sends a request.
+    let result = TestRunner::new(
+        runner_logger,
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+    )
+    .with_config(config)
+    .serve(runner_stream)
+    .await;
+
+    assert_matches!(
+        result.unwrap_err(),
+        RunnerProtoError::Timeout(TimeoutError {
+            phase: TimeoutPhase::Handshake,
+            ..
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_new_session_stored_build() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let build_ref = format!("taskcluster/task_id/{}", BUILD_ARTIFACT_NAME);
+
+    // A downloaded build is kept in the store.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
 
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::with_failure(TaskclusterFailureMode::BadZip),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
-        TestSessionManager::default(),
+        session_manager,
         |mut recorder, _tempdir| async move {
-            assert_matches!(
+            assert_eq!(
                 recorder
-                    .new_session("task_id", None, &[])
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
                     .await
-                    .unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    assert_eq!(e.to_string(), TestRunnerProtoError::MissingFirefox.to_string());
-                }
+                    .unwrap(),
+                VALID_SESSION_ID
             );
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(result.unwrap_err(), RunnerProtoError::MissingFirefox);
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
             let session_info = session_info.unwrap();
-            assert_eq!(session_info.id, VALID_SESSION_ID);
-
-            assert!(!session_info.path.exists());
+            assert!(session_info.firefox_path().is_file());
+            assert!(!session_info.path.join("target.zip").exists());
         },
     )
     .await;
 
+    assert!(handle
+        .store()
+        .get_ref(BUILD_REFS, &build_ref)
+        .unwrap()
+        .is_some());
+
+    // A stored build is used instead of downloading it again.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+    let store = handle.store();
+    let hash = store.insert(&firefox_zip_path()).unwrap();
+    store.set_ref(BUILD_REFS, &build_ref, &hash).unwrap();
+
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::with_failure(TaskclusterFailureMode::Generic("404 Not Found")),
+        FakeBuildProvider::new(firefox_zip_path()).with_failure("build was downloaded"),
         TestPerfProvider::default(),
-        TestSessionManager::default(),
+        session_manager,
         |mut recorder, _tempdir| async move {
-            assert_matches!(
+            assert_eq!(
                 recorder
-                    .new_session("task_id", None, &[])
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
                     .await
-                    .unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                     assert_eq!(e.to_string(), "404 Not Found");
-                }
+                    .unwrap(),
+                VALID_SESSION_ID
             );
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::Taskcluster(e) => {
-                    assert_eq!(e.to_string(), "404 Not Found");
-                }
-            );
-
-            let session_info = session_info.unwrap();
-            assert_eq!(session_info.id, VALID_SESSION_ID);
-            assert!(!session_info.path.exists());
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+            assert!(session_info.unwrap().firefox_path().is_file());
         },
     )
     .await;
 
+    assert!(store.get(&hash).is_some());
+}
+
+#[tokio::test]
+async fn test_new_session_request_id() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let size = std::fs::metadata(firefox_zip_path()).unwrap().len();
+    let sha256 = libfxrecord::sha256_hex(&std::fs::read(firefox_zip_path()).unwrap());
+    let build_ref = |sha256: String| {
+        format!(
+            "request/request-id/{}",
+            serde_json::to_string(&BuildSource::Upload { size, sha256 }).unwrap()
+        )
+    };
+
+    // A build uploaded for a request with an ID is kept in the store.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::with_failure(TaskclusterFailureMode::NotZip),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
-
-        TestSessionManager::default(),
-        |mut recorder, _tempdir| async move {
-            assert_matches!(
+        session_manager,
+        |recorder, _tempdir| async move {
+            let mut recorder = recorder.with_request_id(Some("request-id".into()));
+            assert_eq!(
                 recorder
-                    .new_session("task_id", None, &[])
+                    .new_session(BuildRequest::Upload(&firefox_zip_path()), None, &[])
                     .await
-                    .unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    let msg = e.to_string();
-                    assert!(msg.starts_with("could not read zip archive"));
-                    assert!(msg.ends_with("Invalid Zip archive: Could not find central directory end"));
-                }
+                    .unwrap(),
+                VALID_SESSION_ID
             );
         },
-        |RunnerInfo { result, session_info }| {
-            let session_info = session_info.unwrap();
-            assert_eq!(session_info.id, VALID_SESSION_ID);
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+            assert!(session_info.unwrap().firefox_path().is_file());
+        },
+    )
+    .await;
 
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::Zip(e @ ZipError::ReadArchive{ .. }) => {
+    assert!(handle
+        .store()
+        .get_ref(BUILD_REFS, &build_ref(sha256.clone()))
+        .unwrap()
+        .is_some());
+
+    // A retried request reuses the stored build, so the recorder is never
+    // asked to upload it again.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+    let store = handle.store();
+    let hash = store.insert(&firefox_zip_path()).unwrap();
+    store
+        .set_ref(BUILD_REFS, &build_ref(sha256.clone()), &hash)
+        .unwrap();
 
-                    assert_eq!(
-                        e.to_string(),
-                        format!(
-                            "could not read zip archive `{}': Invalid Zip archive: Could not find central directory end",
-                            session_info.path.join("firefox.zip").display()
-                        )
-                    );
-                }
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        session_manager,
+        |recorder, _tempdir| async move {
+            let mut recorder = recorder.with_request_id(Some("request-id".into()));
+            assert_eq!(
+                recorder
+                    .new_session(BuildRequest::Upload(&firefox_zip_path()), None, &[])
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
             );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+            assert!(session_info.unwrap().firefox_path().is_file());
+        },
+    )
+    .await;
 
-            assert!(!session_info.path.exists());
+    assert!(store.get(&hash).is_some());
+
+    // A retried request that uploads a different build of the same size does
+    // not reuse the build stored for the first upload.
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+    let store = handle.store();
+    let other_build = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(other_build.path(), vec![0u8; size as usize]).unwrap();
+    let other_hash = store.insert(other_build.path()).unwrap();
+    store
+        .set_ref(BUILD_REFS, &build_ref("0".repeat(64)), &other_hash)
+        .unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        session_manager,
+        |recorder, _tempdir| async move {
+            let mut recorder = recorder.with_request_id(Some("request-id".into()));
+            assert_eq!(
+                recorder
+                    .new_session(BuildRequest::Upload(&firefox_zip_path()), None, &[])
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+            assert!(session_info.unwrap().firefox_path().is_file());
         },
     )
     .await;
+
+    assert!(store
+        .get_ref(BUILD_REFS, &build_ref(sha256))
+        .unwrap()
+        .is_some());
 }
 
 #[tokio::test]
-async fn test_new_session_err_recvprofile() {
+async fn test_new_session_err_request_manager() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
-
-        TestSessionManager::default(),
+        TestSessionManager::with_failure(SessionFailureMode::NewSession(
+            NewSessionError::TooManyAttempts(32),
+        )),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder.new_session(BuildTask::from("task_id").into(), None, &[]).await.unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Session);
+                    assert_eq!(
+                        e.to_string(),
+                        "Could not create a request directory after 32 attempts");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::NewSession(NewSessionError::TooManyAttempts(32))
+            );
+
+            assert!(session_info.is_none());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::with_failure(SessionFailureMode::EnsureProfileDir(
+            "could not ensure profile directory",
+        )),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder.new_session(BuildTask::from("task_id").into(), None, &[]).await.unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Profile);
+                    assert_eq!(
+                        e.to_string(),
+                        "could not ensure profile directory");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::EnsureProfile(e) => {
+                    assert_eq!(e.to_string(), "could not ensure profile directory");
+                }
+            );
+
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_index() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_eq!(
+                recorder
+                    .new_session(
+                        BuildTask::Revision {
+                            project: "mozilla-central".into(),
+                            revision: "abcdef".into(),
+                            platform: "win64-shippable-opt".into(),
+                        }
+                        .into(),
+                        None,
+                        &[]
+                    )
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+
+            let session_info = session_info.unwrap();
+            assert!(session_info.firefox_path().is_file());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()).with_failure("404 Not Found"),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(
+                        BuildTask::IndexRoute("gecko.v2.mozilla-central.latest.firefox.win64-opt".into()).into(),
+                        None,
+                        &[]
+                    )
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                     assert_eq!(e.kind, ForeignErrorKind::Taskcluster);
+                     assert_eq!(e.to_string(), "404 Not Found");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Taskcluster(e) => {
+                    assert_eq!(e.to_string(), "404 Not Found");
+                }
+            );
+
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_build_source() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_eq!(
+                recorder
+                    .new_session(
+                        BuildRequest::Upload(&firefox_zip_path()),
+                        Some(&test_dir().join("profile.zip")),
+                        &[]
+                    )
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+
+            let session_info = session_info.unwrap();
+            assert!(session_info.firefox_path().is_file());
+            assert_populated_profile(&session_info.profile_path());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_eq!(
+                recorder
+                    .new_session(BuildRequest::RunnerPath(&firefox_zip_path()), None, &[])
+                    .await
+                    .unwrap(),
+                VALID_SESSION_ID
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+            assert!(session_info.unwrap().firefox_path().is_file());
+            assert!(firefox_zip_path().is_file());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(BuildRequest::Url("ftp://example.com/firefox.zip"), None, &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Download);
+                    assert_eq!(e.to_string(), "unsupported URL scheme `ftp'");
+                }
+            );
+        },
+        |RunnerInfo { result, .. }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::UrlBuild(UrlBuildError::UnsupportedScheme(..))
+            );
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_err_downloadbuild() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(test_dir().join("test.zip")),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Extraction);
+                    assert_eq!(e.to_string(), TestRunnerProtoError::MissingFirefox.to_string());
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(result.unwrap_err(), RunnerProtoError::MissingFirefox);
+
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()).with_failure("404 Not Found"),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                     assert_eq!(e.kind, ForeignErrorKind::Taskcluster);
+                     assert_eq!(e.to_string(), "404 Not Found");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Taskcluster(e) => {
+                    assert_eq!(e.to_string(), "404 Not Found");
+                }
+            );
+
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(test_dir().join("README.md")),
+        TestPerfProvider::default(),
+
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(BuildTask::from("task_id").into(), None, &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Extraction);
+                    let msg = e.to_string();
+                    assert!(msg.starts_with("could not read zip archive"));
+                    assert!(msg.ends_with("Invalid Zip archive: Could not find central directory end"));
+                }
+            );
+        },
+        |RunnerInfo { result, session_info }| {
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Extract(ArchiveError::Zip(e @ ZipError::ReadArchive{ .. })) => {
+                    assert_eq!(
+                        e.to_string(),
+                        format!(
+                            "could not read zip archive `{}': Invalid Zip archive: Could not find central directory end",
+                            session_info.path.join("target.zip").display()
+                        )
+                    );
+                }
+            );
+
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_err_recvprofile() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+
+        TestSessionManager::default(),
         |mut recorder, _tempdir| async move {
             assert_matches!(
                 recorder
-                    .new_session("task_id", Some(&test_dir().join("README.md")), &[])
+                    .new_session(BuildTask::from("task_id").into(), Some(&test_dir().join("README.md")), &[])
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Profile);
                     let msg = e.to_string();
                     assert!(msg.starts_with("could not read zip archive"));
                     assert!(msg.ends_with(
@@ -494,139 +1175,611 @@ async fn test_new_session_err_recvprofile() {
                 }
             );
         },
-        |RunnerInfo { result, session_info }| {
-            let session_info = session_info.unwrap();
-            assert_eq!(session_info.id, VALID_SESSION_ID);
+        |RunnerInfo { result, session_info }| {
+            let session_info = session_info.unwrap();
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Staging(StagingError::Zip(e @ ZipError::ReadArchive{ .. })) => {
+                    assert_eq!(
+                        e.to_string(),
+                        format!(
+                            "could not read zip archive `{}': Invalid Zip archive: Could not find central directory end",
+                            session_info.path.join("profile.zip").display()
+                        )
+                    );
+                }
+            );
+
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder
+                    .new_session(BuildTask::from("task_id").into(), Some(&test_dir().join("empty.zip")), &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Profile);
+                    assert_eq!(e.to_string(), "An empty profile was received");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(result.unwrap_err(), RunnerProtoError::EmptyProfile);
+
+            let session_info = session_info.unwrap();
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_max_profile_size() {
+    let profile_path = test_dir().join("profile.zip");
+    let profile_size = std::fs::metadata(&profile_path).unwrap().len();
+
+    let (runner_stream, recorder_stream) = duplex();
+    let (runner_logger, recorder_logger) = build_test_loggers();
+
+    let session_manager = TestSessionManager::default();
+    let handle = session_manager.handle();
+
+    let runner = TestRunner::new(
+        runner_logger,
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        session_manager,
+    )
+    .with_config(Config {
+        max_profile_size: Some(profile_size - 1),
+        ..test_config()
+    })
+    .serve(runner_stream);
+
+    // The recorder fails as soon as the runner advertises its limit, without
+    // waiting for the build or sending the profile.
+    let recorder = async {
+        let mut recorder = test_recorder_proto(recorder_logger, recorder_stream);
+
+        recorder
+            .new_session(BuildTask::from("task_id").into(), Some(&profile_path), &[])
+            .await
+            .unwrap_err()
+    };
+
+    let (result, err) = join!(runner, recorder);
+
+    assert_matches!(
+        err,
+        RecorderProtoError::ProfileTooLarge { size, max } => {
+            assert_eq!(size, profile_size);
+            assert_eq!(max, profile_size - 1);
+        }
+    );
+    assert!(result.is_err());
+    assert!(!handle.last_session_info().unwrap().path.exists());
+}
+
+#[tokio::test]
+async fn test_new_session_err_restarting() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::with_error("could not shut down"),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            assert_matches!(
+                recorder.new_session(BuildTask::from("task_id").into(), None, &[])
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Shutdown);
+                    assert_eq!(e.to_string(), "could not shut down");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Shutdown(e) => {
+                    assert_eq!(e.to_string(), "could not shut down")
+                }
+            );
+
+            let session_info = session_info.unwrap();
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_cancel() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            recorder
+                .new_session(BuildTask::from("task_id").into(), None, &[])
+                .await
+                .unwrap();
+
+            recorder.cancel_session().await.unwrap();
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+
+            let session_info = session_info.unwrap();
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::with_cancel_error("could not abort shutdown"),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            recorder
+                .new_session(BuildTask::from("task_id").into(), None, &[])
+                .await
+                .unwrap();
+
+            assert_matches!(
+                recorder.cancel_session().await.unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Shutdown);
+                    assert_eq!(e.to_string(), "could not abort shutdown");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Shutdown(e) => {
+                    assert_eq!(e.to_string(), "could not abort shutdown")
+                }
+            );
+
+            let session_info = session_info.unwrap();
+            assert!(!session_info.path.exists());
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_new_session_fast_startup() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fast_startup = Arc::new(AtomicBool::new(true));
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::with_fast_startup(fast_startup.clone()),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            recorder
+                .new_session(BuildTask::from("task_id").into(), None, &[])
+                .await
+                .unwrap();
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
+
+            let session_info = session_info.unwrap();
+            assert!(session_info.path.join("fast_startup_disabled").is_file());
+            assert!(!fast_startup.load(Ordering::SeqCst));
+        },
+    )
+    .await;
+
+    // Cancelling the session re-enables Fast Startup. The first session never
+    // resumed, so Fast Startup is still disabled from it.
+    fast_startup.store(true, Ordering::SeqCst);
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::with_fast_startup(fast_startup.clone()),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, _tempdir| async move {
+            recorder
+                .new_session(BuildTask::from("task_id").into(), None, &[])
+                .await
+                .unwrap();
+            recorder.cancel_session().await.unwrap();
+        },
+        |RunnerInfo { result, .. }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert!(fast_startup.load(Ordering::SeqCst));
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_ok() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_invoked(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            let output = recorder
+                .resume_session(
+                    VALID_SESSION_ID,
+                    Idle::Wait,
+                    &RunOptions::default(),
+                    &tempdir,
+                )
+                .await
+                .unwrap();
+
+            let runner_log = output.runner_log.unwrap();
+            assert!(runner_log.contains("Became idle"));
+            assert!(runner_log.contains(VALID_SESSION_ID));
+
+            let bandwidth = output.bandwidth.unwrap();
+            assert_eq!(bandwidth.downloaded, 0);
+            assert!(bandwidth.sent > 0);
+            assert!(bandwidth.received > 0);
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            recorder
+                .resume_session(
+                    VALID_SESSION_ID,
+                    Idle::Skip,
+                    &RunOptions::default(),
+                    &tempdir,
+                )
+                .await
+                .unwrap();
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
 
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::Zip(e @ ZipError::ReadArchive{ .. }) => {
-                    assert_eq!(
-                        e.to_string(),
-                        format!(
-                            "could not read zip archive `{}': Invalid Zip archive: Could not find central directory end",
-                            session_info.path.join("profile.zip").display()
-                        )
-                    );
-                }
-            );
+#[tokio::test]
+async fn test_resume_session_pageload() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
-            assert!(!session_info.path.exists());
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_invoked(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            recorder
+                .resume_session(
+                    VALID_SESSION_ID,
+                    Idle::Skip,
+                    &RunOptions {
+                        session_type: SessionType::PageLoad {
+                            url: "https://example.com".into(),
+                        },
+                        ..Default::default()
+                    },
+                    &tempdir,
+                )
+                .await
+                .unwrap();
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
         },
     )
     .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_return_profile() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
-        TestPerfProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
         TestSessionManager::default(),
-        |mut recorder, _tempdir| async move {
-            assert_matches!(
-                recorder
-                    .new_session("task_id", Some(&test_dir().join("empty.zip")), &[])
-                    .await
-                    .unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    assert_eq!(e.to_string(), "An empty profile was received");
-                }
-            );
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                return_profile: true,
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                .await
+                .unwrap();
+
+            let profile_path = output.profile_path.unwrap();
+            assert_eq!(profile_path, tempdir.join("profile.zip"));
+
+            // The test session's profile is empty.
+            let stats = unzip(
+                &profile_path,
+                &tempdir.join("profile"),
+                &ExtractLimits::default(),
+            )
+            .unwrap();
+            assert_eq!(stats.extracted, 0);
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(result.unwrap_err(), RunnerProtoError::EmptyProfile);
-
-            let session_info = session_info.unwrap();
-            assert!(!session_info.path.exists());
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
         },
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_new_session_err_restarting() {
+async fn test_resume_session_relaunches() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     run_proto_test(
         &mut listener,
-        TestShutdownProvider::with_error("could not shut down"),
-        TestTaskcluster::default(),
-        TestPerfProvider::default(),
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
         TestSessionManager::default(),
-        |mut recorder, _tempdir| async move {
-            assert_matches!(
-                recorder.new_session("task_id", None, &[])
-                    .await
-                    .unwrap_err(),
-                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
-                    assert_eq!(e.to_string(), "could not shut down");
-                }
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                relaunches: 2,
+                reset_profile: true,
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                output
+                    .launches
+                    .iter()
+                    .map(|launch| launch.recording_path.clone().unwrap())
+                    .collect::<Vec<_>>(),
+                vec![
+                    tempdir.join("launch-1").join("recording.mp4"),
+                    tempdir.join("launch-2").join("recording.mp4"),
+                    tempdir.join("launch-3").join("recording.mp4"),
+                ]
             );
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_matches!(
-                result.unwrap_err(),
-                RunnerProtoError::Shutdown(e) => {
-                    assert_eq!(e.to_string(), "could not shut down")
-                }
-            );
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_restart_after() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                restart_after: true,
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                .await
+                .unwrap();
+
+            assert_eq!(output.launches.len(), 1);
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Restart);
 
+            // The session is kept so that it can be resumed after the restart.
             let session_info = session_info.unwrap();
-            assert!(!session_info.path.exists());
+            assert_eq!(session_info.id, VALID_SESSION_ID);
+            assert!(session_info.path.is_dir());
         },
     )
     .await;
 }
 
 #[tokio::test]
-async fn test_resume_session_ok() {
+async fn test_resume_session_relaunches_cool_down() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
-        TestPerfProvider::asserting_invoked(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
-            recorder
-                .resume_session(VALID_SESSION_ID, Idle::Wait, &tempdir)
+            let run_options = RunOptions {
+                relaunches: 1,
+                cool_down: Duration::from_millis(100),
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
                 .await
                 .unwrap();
+
+            assert_eq!(output.launches.len(), 2);
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), false);
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
             assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
         },
     )
     .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_upload_artifacts_without_store() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::asserting_not_invoked(),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
-            recorder
-                .resume_session(VALID_SESSION_ID, Idle::Skip, &tempdir)
+            let run_options = RunOptions {
+                return_profile: true,
+                upload_artifacts: true,
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                .await
+                .unwrap();
+
+            // The runner has no artifact store, so it sends the profile
+            // instead.
+            assert!(output.artifacts.is_empty());
+            assert_eq!(output.profile_path.unwrap(), tempdir.join("profile.zip"));
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_capture_pings() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::asserting_not_invoked(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                capture_pings: true,
+                ..Default::default()
+            };
+
+            let output = recorder
+                .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
                 .await
                 .unwrap();
+
+            let pings_path = output.pings_path.unwrap();
+            assert_eq!(pings_path, tempdir.join("pings.zip"));
+
+            // The stand-in for Firefox does not submit any pings.
+            let stats = unzip(
+                &pings_path,
+                &tempdir.join("pings"),
+                &ExtractLimits::default(),
+            )
+            .unwrap();
+            assert_eq!(stats.extracted, 0);
         },
         |RunnerInfo {
              result,
              session_info,
          }| {
-            assert_eq!(result.unwrap(), false);
+            assert_eq!(result.unwrap(), RequestOutcome::Finished);
             assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
         },
     )
@@ -640,14 +1793,15 @@ async fn test_resume_session_err_request_manager() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
             assert_matches!(
                 // Any request that is not VALID_REQUEST_ID triggers this error.
-                recorder.resume_session("foobar", Idle::Skip, &tempdir).await.unwrap_err(),
+                recorder.resume_session("foobar", Idle::Skip, &RunOptions::default(), &tempdir).await.unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Session);
                     assert_eq!(e.to_string(), "Invalid session ID `foobar': ID contains invalid characters");
                 }
             );
@@ -674,7 +1828,7 @@ async fn test_resume_session_err_request_manager() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::default(),
         TestSessionManager::with_failure(SessionFailureMode::ResumeSession(
             ResumeSessionErrorKind::MissingProfile,
@@ -682,10 +1836,11 @@ async fn test_resume_session_err_request_manager() {
         |mut recorder, tempdir| async move {
             assert_matches!(
                 recorder
-                    .resume_session(VALID_SESSION_ID, Idle::Skip, &tempdir)
+                    .resume_session(VALID_SESSION_ID, Idle::Skip, &RunOptions::default(), &tempdir)
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Session);
                     assert_eq!(
                         e.to_string(),
                         "Invalid session ID `REQUESTID': missing a profile directory"
@@ -716,6 +1871,134 @@ async fn test_resume_session_err_request_manager() {
     .await;
 }
 
+#[tokio::test]
+async fn test_resume_session_err_hosts() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                host_overrides: vec![("example.com\n".into(), "127.0.0.1".parse().unwrap())],
+                ..Default::default()
+            };
+
+            assert_matches!(
+                recorder
+                    .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Hosts);
+                    assert_eq!(e.to_string(), "Invalid host name `example.com\n'");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Hosts(HostsError::InvalidHostname(host)) => {
+                    assert_eq!(host, "example.com\n");
+                }
+            );
+
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_err_proxy() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::default(),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            let run_options = RunOptions {
+                proxy: Some(ProxyMode::Replay {
+                    archive: "example".into(),
+                }),
+                ..Default::default()
+            };
+
+            assert_matches!(
+                recorder
+                    .resume_session(VALID_SESSION_ID, Idle::Skip, &run_options, &tempdir)
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Proxy);
+                    assert_eq!(e.to_string(), "The runner is not configured with a proxy");
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Proxy(ProxyError::NotConfigured)
+            );
+
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_resume_session_err_desktop() {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    run_proto_test(
+        &mut listener,
+        TestShutdownProvider::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
+        TestPerfProvider::with_failure(PerfFailureMode::DesktopLocked),
+        TestSessionManager::default(),
+        |mut recorder, tempdir| async move {
+            assert_matches!(
+                recorder
+                    .resume_session(VALID_SESSION_ID, Idle::Wait, &RunOptions::default(), &tempdir)
+                    .await
+                    .unwrap_err(),
+                RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Desktop);
+                    assert_eq!(
+                        e.to_string(),
+                        "The desktop is locked; sign in on the runner and disable the lock screen"
+                    );
+                }
+            );
+        },
+        |RunnerInfo {
+             result,
+             session_info,
+         }| {
+            assert_matches!(
+                result.unwrap_err(),
+                RunnerProtoError::Desktop(DesktopError::Locked)
+            );
+
+            assert_eq!(session_info.unwrap().id, VALID_SESSION_ID);
+        },
+    )
+    .await;
+}
+
 #[tokio::test]
 async fn test_resume_session_err_waitforidle() {
     let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -723,16 +2006,17 @@ async fn test_resume_session_err_waitforidle() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::with_failure(PerfFailureMode::DiskIoError("disk io error")),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
             assert_matches!(
                 recorder
-                    .resume_session(VALID_SESSION_ID, Idle::Wait, &tempdir)
+                    .resume_session(VALID_SESSION_ID, Idle::Wait, &RunOptions::default(), &tempdir)
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Idle);
                     assert_eq!(
                         e.to_string(),
                         "disk io error"
@@ -759,16 +2043,17 @@ async fn test_resume_session_err_waitforidle() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::with_failure(PerfFailureMode::CpuTimeError("cpu time error")),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
             assert_matches!(
                 recorder
-                    .resume_session(VALID_SESSION_ID, Idle::Wait, &tempdir)
+                    .resume_session(VALID_SESSION_ID, Idle::Wait, &RunOptions::default(), &tempdir)
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Idle);
                     assert_eq!(
                         e.to_string(),
                         "cpu time error"
@@ -794,16 +2079,17 @@ async fn test_resume_session_err_waitforidle() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::with_failure(PerfFailureMode::DiskNeverIdle),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
             assert_matches!(
                 recorder
-                    .resume_session(VALID_SESSION_ID, Idle::Wait, &tempdir)
+                    .resume_session(VALID_SESSION_ID, Idle::Wait, &RunOptions::default(), &tempdir)
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Idle);
                     assert_eq!(
                         e.to_string(),
                         "timed out waiting for CPU and disk to become idle"
@@ -827,16 +2113,17 @@ async fn test_resume_session_err_waitforidle() {
     run_proto_test(
         &mut listener,
         TestShutdownProvider::default(),
-        TestTaskcluster::default(),
+        FakeBuildProvider::new(firefox_zip_path()),
         TestPerfProvider::with_failure(PerfFailureMode::CpuNeverIdle),
         TestSessionManager::default(),
         |mut recorder, tempdir| async move {
             assert_matches!(
                 recorder
-                    .resume_session(VALID_SESSION_ID, Idle::Wait, &tempdir)
+                    .resume_session(VALID_SESSION_ID, Idle::Wait, &RunOptions::default(), &tempdir)
                     .await
                     .unwrap_err(),
                 RecorderProtoError::Proto(ProtoError::Foreign(e)) => {
+                    assert_eq!(e.kind, ForeignErrorKind::Idle);
                     assert_eq!(
                         e.to_string(),
                         "timed out waiting for CPU and disk to become idle"
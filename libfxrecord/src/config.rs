@@ -2,25 +2,343 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::fs::File;
-use std::io::{self, Read};
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use derive_more::Display;
+use serde::de::DeserializeOwned;
+use structopt::StructOpt;
 use thiserror::Error;
+use toml::value::Table;
 use toml::{self, Value};
 
-/// Read the given section from the given configuration file and deserialize it as a `T`.
-pub fn read_config<T, P>(path: P, section: &'static str) -> Result<T, ConfigError>
-where
-    for<'de> T: Deserialize<'de>,
-    P: AsRef<Path>,
-{
-    let path = path.as_ref();
+/// A configuration section assembled from several layers.
+///
+/// Each layer overrides the values of the layers before it. Layers are
+/// expected to be added in the following order:
+///
+/// 1. built-in defaults ([`set_default`](#method.set_default));
+/// 2. the configuration file ([`file`](#method.file));
+/// 3. environment variables ([`env`](#method.env)); and
+/// 4. command-line flags ([`set`](#method.set)).
+#[derive(Debug)]
+pub struct ConfigLoader {
+    /// The name of the section being loaded.
+    section: &'static str,
+
+    /// The values from the layers added so far.
+    table: Table,
+}
+
+impl ConfigLoader {
+    /// Create a loader for the given section of the configuration.
+    pub fn new(section: &'static str) -> Self {
+        ConfigLoader {
+            section,
+            table: Table::new(),
+        }
+    }
+
+    /// Set the default for a key.
+    ///
+    /// Keys are dotted paths relative to the section, e.g.,
+    /// `taskcluster.artifact`.
+    pub fn set_default<V: Into<Value>>(self, key: &str, value: V) -> Self {
+        self.set(key, value)
+    }
+
+    /// Layer the section of the given configuration file.
+    ///
+    /// The section may be absent if every required key is provided by
+    /// another layer.
+    pub fn file<P: AsRef<Path>>(self, path: P) -> Result<Self, ConfigError> {
+        self.file_with_profile(path, None)
+    }
+
+    /// Layer the section of the given configuration file, followed by the
+    /// named profile within that section, if any.
+    ///
+    /// A profile is a table nested in the section, e.g., `[fxrecorder.lab1]`,
+    /// whose values override those of the section.
+    pub fn file_with_profile<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        profile: Option<&str>,
+    ) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let mut file = read_config_file(path, &mut Vec::new())?;
+
+        let mut section = match file.remove(self.section) {
+            Some(Value::Table(section)) => section,
+            _ => Table::new(),
+        };
+
+        let profile = match profile {
+            Some(profile) => match section.remove(profile) {
+                Some(Value::Table(profile)) => Some(profile),
+                _ => {
+                    return Err(ConfigError::MissingProfile {
+                        path: path.into(),
+                        section: self.section,
+                        profile: profile.into(),
+                    })
+                }
+            },
+            None => None,
+        };
+
+        merge(&mut self.table, section);
+        if let Some(profile) = profile {
+            merge(&mut self.table, profile);
+        }
+
+        Ok(self)
+    }
+
+    /// Layer the environment variables starting with the given prefix.
+    ///
+    /// The remainder of the variable's name is lowercased to form the key,
+    /// with double underscores separating nested tables. For example,
+    /// `FXRUNNER_TASKCLUSTER__ROOT_URL` sets `taskcluster.root_url` when the
+    /// prefix is `FXRUNNER_`.
+    ///
+    /// Values are parsed as TOML values if possible (e.g., `true`, `8`, or
+    /// `{ x = 1366, y = 768 }`) and are otherwise treated as strings.
+    pub fn env(self, prefix: &str) -> Self {
+        self.env_vars(prefix, env::vars())
+    }
+
+    fn env_vars<I>(mut self, prefix: &str, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, raw) in vars {
+            if name.len() > prefix.len() && name.starts_with(prefix) {
+                let key = name[prefix.len()..].to_lowercase().replace("__", ".");
+                self = self.set(&key, parse_env_value(&raw));
+            }
+        }
+
+        self
+    }
+
+    /// Override the value of a key, e.g., from a command-line flag.
+    ///
+    /// Keys are dotted paths relative to the section, e.g.,
+    /// `taskcluster.artifact`.
+    pub fn set<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        let mut parts = key.split('.').collect::<Vec<_>>();
+        let last = parts.pop().unwrap();
+
+        let mut table = &mut self.table;
+        for part in parts {
+            let entry = table
+                .entry(part.to_owned())
+                .or_insert_with(|| Value::Table(Table::new()));
+
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+
+            table = entry.as_table_mut().unwrap();
+        }
+
+        table.insert(last.to_owned(), value.into());
+        self
+    }
+
+    /// Override the value of a key if a value is provided.
+    pub fn set_some<V: Into<Value>>(self, key: &str, value: Option<V>) -> Self {
+        match value {
+            Some(value) => self.set(key, value),
+            None => self,
+        }
+    }
+
+    /// Deserialize the configuration from every layer.
+    pub fn load<T: DeserializeOwned>(self) -> Result<T, ConfigError> {
+        let section = self.section;
+
+        serde_path_to_error::deserialize(Value::Table(self.table)).map_err(|e| {
+            let key = match e.path().to_string().as_str() {
+                "." => section.to_owned(),
+                path => format!("{}.{}", section, path),
+            };
+
+            ConfigError::Invalid {
+                key,
+                source: e.into_inner(),
+            }
+        })
+    }
+
+    /// Deserialize and validate the configuration from every layer, returning
+    /// every problem found.
+    pub fn check<T>(self) -> Vec<ConfigIssue>
+    where
+        T: DeserializeOwned + Validate,
+    {
+        let mut issues = ConfigIssues::new(self.section);
+
+        match self.load::<T>() {
+            Ok(config) => config.validate(&mut issues),
+            Err(ConfigError::Invalid { key, source }) => issues.issues.push(ConfigIssue {
+                key,
+                message: source.to_string(),
+            }),
+            Err(e) => unreachable!("unexpected error loading configuration: {}", e),
+        }
+
+        issues.issues
+    }
+}
+
+/// Subcommands for inspecting the configuration.
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    /// Check the configuration for errors.
+    ///
+    /// Every problem found is printed along with the option it applies to.
+    Check,
+}
+
+/// Options for scaffolding a configuration file.
+#[derive(Debug, StructOpt)]
+pub struct InitOptions {
+    /// Overwrite the configuration file if it already exists.
+    #[structopt(long)]
+    pub force: bool,
+}
+
+/// Write a new configuration file.
+///
+/// An existing file is only overwritten if `force` is true.
+pub fn write_new_config(path: &Path, contents: &str, force: bool) -> Result<(), io::Error> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .open(path)?;
+
+    f.write_all(contents.as_bytes())
+}
+
+/// Format a string as a TOML string literal.
+pub fn toml_string(s: &str) -> String {
+    Value::String(s.into()).to_string()
+}
+
+/// Checks on configuration beyond what deserialization enforces.
+pub trait Validate {
+    /// Report every problem with this configuration.
+    fn validate(&self, issues: &mut ConfigIssues);
+}
+
+/// A problem with a single configuration option.
+#[derive(Debug, Display, Eq, PartialEq)]
+#[display(fmt = "{}: {}", key, message)]
+pub struct ConfigIssue {
+    /// The dotted path to the option, e.g., `fxrunner.proxy.port`.
+    pub key: String,
+
+    /// A description of the problem.
+    pub message: String,
+}
+
+/// The problems found while validating a configuration.
+#[derive(Debug)]
+pub struct ConfigIssues {
+    /// The key of the table currently being validated.
+    prefix: String,
+
+    issues: Vec<ConfigIssue>,
+}
+
+impl ConfigIssues {
+    fn new(section: &str) -> Self {
+        ConfigIssues {
+            prefix: section.into(),
+            issues: Vec::new(),
+        }
+    }
+
+    /// Report a problem with the given option of the current table.
+    pub fn push<M: ToString>(&mut self, field: &str, message: M) {
+        self.issues.push(ConfigIssue {
+            key: format!("{}.{}", self.prefix, field),
+            message: message.to_string(),
+        });
+    }
+
+    /// Validate a nested table.
+    pub fn nested<V: Validate>(&mut self, field: &str, value: &V) {
+        let len = self.prefix.len();
+
+        self.prefix.push('.');
+        self.prefix.push_str(field);
+        value.validate(self);
+        self.prefix.truncate(len);
+    }
+
+    /// Report a problem if the given path is not an existing file.
+    pub fn check_file(&mut self, field: &str, path: &Path) {
+        if !path.exists() {
+            self.push(field, format!("`{}' does not exist", path.display()));
+        } else if !path.is_file() {
+            self.push(field, format!("`{}' is not a file", path.display()));
+        }
+    }
+
+    /// Report a problem if the given path exists but is not a directory.
+    pub fn check_dir_if_exists(&mut self, field: &str, path: &Path) {
+        if path.exists() && !path.is_dir() {
+            self.push(field, format!("`{}' is not a directory", path.display()));
+        }
+    }
+
+    /// Report a problem if the given value is outside the inclusive range
+    /// `[min, max]`.
+    pub fn check_range<T>(&mut self, field: &str, value: T, min: T, max: T)
+    where
+        T: PartialOrd + fmt::Display,
+    {
+        if value < min || value > max {
+            self.push(
+                field,
+                format!("{} is not between {} and {}", value, min, max),
+            );
+        }
+    }
+}
+
+/// Read a configuration file along with the files it includes.
+///
+/// A file may include other files with a top-level `include` array, e.g.,
+/// `include = ["base.toml"]`. Relative paths are resolved against the
+/// directory of the including file. Included files are merged in order, so
+/// later files override earlier ones, and the including file overrides them
+/// all.
+///
+/// `stack` holds the files currently being read, to detect cycles.
+fn read_config_file(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Table, ConfigError> {
     let mut f = File::open(path).map_err(|e| ConfigError::OpenConfig {
         path: path.into(),
         source: e,
     })?;
+
+    let canonical = path.canonicalize().map_err(|e| ConfigError::OpenConfig {
+        path: path.into(),
+        source: e,
+    })?;
+
+    if stack.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle { path: path.into() });
+    }
+
     let mut buf = String::new();
 
     f.read_to_string(&mut buf)
@@ -29,26 +347,61 @@ where
             source: e,
         })?;
 
-    toml::from_str::<Value>(&buf)
-        .map_err(|e| ConfigError::Parse {
-            path: path.into(),
-            source: e,
-        })
-        .and_then(|mut value| {
-            value
-                .as_table_mut()
-                .and_then(|table| table.remove(section))
-                .ok_or_else(|| ConfigError::MissingSection {
-                    path: PathBuf::from(path),
-                    section,
-                })
-        })
-        .and_then(|val| {
-            val.try_into().map_err(|e| ConfigError::Parse {
-                path: path.into(),
-                source: e,
+    let mut table = toml::from_str::<Table>(&buf).map_err(|e| ConfigError::Parse {
+        path: path.into(),
+        source: e,
+    })?;
+
+    let includes = match table.remove("include") {
+        None => Vec::new(),
+        Some(Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(ConfigError::InvalidInclude { path: path.into() }),
             })
-        })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(..) => return Err(ConfigError::InvalidInclude { path: path.into() }),
+    };
+
+    if includes.is_empty() {
+        return Ok(table);
+    }
+
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = Table::new();
+
+    stack.push(canonical);
+    for include in includes {
+        merge(&mut merged, read_config_file(&dir.join(include), stack)?);
+    }
+    stack.pop();
+
+    merge(&mut merged, table);
+    Ok(merged)
+}
+
+/// Merge the values of `overlay` into `base`, recursing into tables.
+///
+/// Values other than tables, including arrays, are replaced.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Parse the value of an environment variable as a TOML value, falling back to
+/// a string.
+fn parse_env_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.into()))
 }
 
 /// An error occurred while loading or parsing a configuration file.
@@ -62,17 +415,333 @@ pub enum ConfigError {
     #[error("Could not read config file `{}': {}", .path.display(), source)]
     ReadConfig { path: PathBuf, source: io::Error },
 
-    /// The required section was missing from the config file.
-    #[error("Missing `{}' section in config file `{}'", .section, .path.display())]
-    MissingSection {
-        path: PathBuf,
-        section: &'static str,
-    },
-
     /// The file could not be parsed.
     #[error("Could not parse config file `{}': {}", .path.display(), .source)]
     Parse {
         path: PathBuf,
         source: toml::de::Error,
     },
+
+    /// The `include` key of the file was not an array of paths.
+    #[error("`include' in config file `{}' must be an array of paths", .path.display())]
+    InvalidInclude { path: PathBuf },
+
+    /// The file includes itself, directly or indirectly.
+    #[error("Config file `{}' includes itself", .path.display())]
+    IncludeCycle { path: PathBuf },
+
+    /// The requested profile was missing from the config file.
+    #[error("Missing `{}.{}' profile in config file `{}'", .section, .profile, .path.display())]
+    MissingProfile {
+        path: PathBuf,
+        section: &'static str,
+        profile: String,
+    },
+
+    /// The configuration assembled from every layer was invalid.
+    #[error("Invalid configuration at `{}': {}", .key, .source)]
+    Invalid {
+        /// The dotted path to the invalid option.
+        key: String,
+        source: toml::de::Error,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use assert_matches::assert_matches;
+    use serde::Deserialize;
+    use tempfile::{NamedTempFile, TempDir};
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, Eq, PartialEq)]
+    struct TestConfig {
+        host: String,
+        port: u16,
+        verbose: bool,
+        nested: Nested,
+    }
+
+    #[derive(Debug, Deserialize, Eq, PartialEq)]
+    struct Nested {
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn test_config_layers() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"
+            [other]
+            host = "ignored"
+
+            [test]
+            host = "file"
+            port = 1
+
+            [test.nested]
+            name = "file"
+            count = 1
+            "#
+        )
+        .unwrap();
+
+        let config: TestConfig = ConfigLoader::new("test")
+            .set_default("verbose", false)
+            .set_default("nested.count", 0)
+            .set_default("port", 0)
+            .file(f.path())
+            .unwrap()
+            .env_vars(
+                "TEST_",
+                vec![
+                    ("TEST_PORT".into(), "2".into()),
+                    ("TEST_NESTED__NAME".into(), "env".into()),
+                    ("OTHER_VERBOSE".into(), "true".into()),
+                ],
+            )
+            .set("nested.count", 3)
+            .set_some("host", None::<String>)
+            .load()
+            .unwrap();
+
+        assert_eq!(
+            config,
+            TestConfig {
+                host: "file".into(),
+                port: 2,
+                verbose: false,
+                nested: Nested {
+                    name: "env".into(),
+                    count: 3,
+                },
+            }
+        );
+
+        assert_matches!(
+            ConfigLoader::new("test")
+                .set("port", 1)
+                .load::<TestConfig>(),
+            Err(ConfigError::Invalid { ref key, .. }) if key == "test"
+        );
+    }
+
+    impl Validate for TestConfig {
+        fn validate(&self, issues: &mut ConfigIssues) {
+            issues.check_range("port", self.port, 1, 1024);
+            issues.nested("nested", &self.nested);
+        }
+    }
+
+    impl Validate for Nested {
+        fn validate(&self, issues: &mut ConfigIssues) {
+            if self.name.is_empty() {
+                issues.push("name", "must not be empty");
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_check() {
+        let loader = || {
+            ConfigLoader::new("test")
+                .set("host", "host")
+                .set("port", 1)
+                .set("verbose", true)
+                .set("nested.name", "name")
+                .set("nested.count", 1)
+        };
+
+        assert!(loader().check::<TestConfig>().is_empty());
+
+        assert_eq!(
+            loader()
+                .set("port", 0)
+                .set("nested.name", "")
+                .check::<TestConfig>()
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "test.port: 0 is not between 1 and 1024",
+                "test.nested.name: must not be empty",
+            ]
+        );
+
+        assert_eq!(
+            loader()
+                .set("nested.count", -1)
+                .check::<TestConfig>()
+                .into_iter()
+                .map(|issue| issue.key)
+                .collect::<Vec<_>>(),
+            vec!["test.nested.count"]
+        );
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        let mut f = NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"
+            [test]
+            host = "default"
+            port = 1
+            verbose = false
+
+            [test.nested]
+            name = "default"
+            count = 1
+
+            [test.staging]
+            host = "staging"
+
+            [test.staging.nested]
+            count = 2
+            "#
+        )
+        .unwrap();
+
+        let config: TestConfig = ConfigLoader::new("test")
+            .file_with_profile(f.path(), Some("staging"))
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_eq!(
+            config,
+            TestConfig {
+                host: "staging".into(),
+                port: 1,
+                verbose: false,
+                nested: Nested {
+                    name: "default".into(),
+                    count: 2,
+                },
+            }
+        );
+
+        let config: TestConfig = ConfigLoader::new("test")
+            .file(f.path())
+            .unwrap()
+            .load()
+            .unwrap();
+        assert_eq!(config.host, "default");
+        assert_eq!(config.nested.count, 1);
+
+        assert_matches!(
+            ConfigLoader::new("test").file_with_profile(f.path(), Some("lab1")),
+            Err(ConfigError::MissingProfile { section: "test", profile, .. }) => assert_eq!(profile, "lab1")
+        );
+
+        // Options that are tables are not profiles.
+        assert_matches!(
+            ConfigLoader::new("test").file_with_profile(f.path(), Some("host")),
+            Err(ConfigError::MissingProfile { .. })
+        );
+    }
+
+    #[test]
+    fn test_config_include() {
+        let dir = TempDir::new().unwrap();
+
+        fs::write(
+            dir.path().join("base.toml"),
+            r#"
+            [test]
+            host = "base"
+            port = 1
+            verbose = false
+
+            [test.nested]
+            name = "base"
+            count = 1
+            "#,
+        )
+        .unwrap();
+
+        fs::create_dir(dir.path().join("lab")).unwrap();
+        fs::write(
+            dir.path().join("lab").join("lab.toml"),
+            r#"
+            include = ["../base.toml"]
+
+            [test]
+            port = 2
+
+            [test.nested]
+            count = 2
+            "#,
+        )
+        .unwrap();
+
+        fs::write(
+            dir.path().join("machine.toml"),
+            r#"
+            include = ["base.toml", "lab/lab.toml"]
+
+            [test]
+            host = "machine"
+            "#,
+        )
+        .unwrap();
+
+        let config: TestConfig = ConfigLoader::new("test")
+            .file(dir.path().join("machine.toml"))
+            .unwrap()
+            .load()
+            .unwrap();
+
+        assert_eq!(
+            config,
+            TestConfig {
+                host: "machine".into(),
+                port: 2,
+                verbose: false,
+                nested: Nested {
+                    name: "base".into(),
+                    count: 2,
+                },
+            }
+        );
+
+        fs::write(dir.path().join("a.toml"), r#"include = ["b.toml"]"#).unwrap();
+        fs::write(dir.path().join("b.toml"), r#"include = ["a.toml"]"#).unwrap();
+        assert_matches!(
+            ConfigLoader::new("test").file(dir.path().join("a.toml")),
+            Err(ConfigError::IncludeCycle { .. })
+        );
+
+        fs::write(dir.path().join("c.toml"), r#"include = "base.toml""#).unwrap();
+        assert_matches!(
+            ConfigLoader::new("test").file(dir.path().join("c.toml")),
+            Err(ConfigError::InvalidInclude { .. })
+        );
+
+        fs::write(dir.path().join("d.toml"), r#"include = ["missing.toml"]"#).unwrap();
+        assert_matches!(
+            ConfigLoader::new("test").file(dir.path().join("d.toml")),
+            Err(ConfigError::OpenConfig { path, .. }) => assert!(path.ends_with("missing.toml"))
+        );
+    }
+
+    #[test]
+    fn test_parse_env_value() {
+        assert_eq!(parse_env_value("true"), Value::Boolean(true));
+        assert_eq!(parse_env_value("8"), Value::Integer(8));
+        assert_eq!(
+            parse_env_value("0.0.0.0:8888"),
+            Value::String("0.0.0.0:8888".into())
+        );
+        assert_eq!(
+            parse_env_value(r"C:\fxrunner"),
+            Value::String(r"C:\fxrunner".into())
+        );
+    }
 }
@@ -0,0 +1,161 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Discovering runners on the local network.
+//!
+//! A recorder broadcasts a probe over UDP and every runner that receives it
+//! answers with an [`Advertisement`](struct.Advertisement.html) describing
+//! itself. This removes the need to keep track of the addresses of the runners
+//! in a small lab.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use slog::{debug, warn, Logger};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+/// The default port that runners answer discovery probes on.
+pub const DISCOVERY_PORT: u16 = 8890;
+
+/// The contents of a discovery probe.
+const PROBE: &[u8] = b"fxrecord-discover/1";
+
+/// The largest advertisement that will be received.
+const MAX_ADVERTISEMENT_SIZE: usize = 4096;
+
+/// A runner's description of itself, sent in response to a probe.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Advertisement {
+    /// The name of the runner.
+    pub name: String,
+
+    /// The version of the runner.
+    pub version: String,
+
+    /// The port the runner accepts connections from recorders on.
+    pub port: u16,
+
+    /// The optional features that the runner is configured for.
+    pub capabilities: Vec<String>,
+
+    /// Whether or not the runner is serving a request.
+    pub busy: bool,
+}
+
+/// A runner that answered a discovery probe.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DiscoveredRunner {
+    /// The address to connect to the runner on.
+    pub host: SocketAddr,
+
+    #[serde(flatten)]
+    pub advertisement: Advertisement,
+}
+
+/// Answer discovery probes received on the given socket.
+///
+/// Each probe is answered with the advertisement returned by `advertise` at
+/// the time the probe is received.
+pub async fn serve_discovery<F>(log: Logger, mut socket: UdpSocket, advertise: F) -> io::Result<()>
+where
+    F: Fn() -> Advertisement,
+{
+    let mut buf = [0u8; 64];
+
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+
+        if &buf[..len] != PROBE {
+            debug!(log, "Ignoring unexpected discovery datagram"; "peer" => %peer);
+            continue;
+        }
+
+        let advertisement =
+            serde_json::to_vec(&advertise()).expect("could not serialize advertisement");
+
+        // A recorder that goes away before the answer arrives must not stop
+        // the runner from answering others.
+        if let Err(e) = socket.send_to(&advertisement, &peer).await {
+            warn!(log, "Could not answer discovery probe"; "peer" => %peer, "error" => %e);
+        }
+    }
+}
+
+/// Return the address that probes should be broadcast to.
+pub fn broadcast_addr(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port)
+}
+
+/// Send a discovery probe to `target` and collect the answers received
+/// within `wait`.
+///
+/// Runners are returned in the order they answered. Malformed answers are
+/// ignored.
+pub async fn discover(target: SocketAddr, wait: Duration) -> io::Result<Vec<DiscoveredRunner>> {
+    let mut socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(PROBE, &target).await?;
+
+    let deadline = Instant::now() + wait;
+    let mut buf = vec![0u8; MAX_ADVERTISEMENT_SIZE];
+
+    let mut runners: Vec<DiscoveredRunner> = Vec::new();
+
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, peer) = received?;
+
+        if let Ok(advertisement) = serde_json::from_slice::<Advertisement>(&buf[..len]) {
+            let host = SocketAddr::new(peer.ip(), advertisement.port);
+
+            // A runner reachable on more than one interface may answer more
+            // than once.
+            if runners.iter().all(|runner| runner.host != host) {
+                runners.push(DiscoveredRunner {
+                    host,
+                    advertisement,
+                });
+            }
+        }
+    }
+
+    Ok(runners)
+}
+
+#[cfg(test)]
+mod test {
+    use slog::{o, Discard};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_discover() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let advertisement = Advertisement {
+            name: "runner".into(),
+            version: "0.1.0".into(),
+            port: 8888,
+            capabilities: vec!["proxy".into()],
+            busy: false,
+        };
+
+        tokio::spawn(serve_discovery(Logger::root(Discard, o!()), socket, {
+            let advertisement = advertisement.clone();
+            move || advertisement.clone()
+        }));
+
+        let runners = discover(addr, Duration::from_millis(500)).await.unwrap();
+        assert_eq!(
+            runners,
+            vec![DiscoveredRunner {
+                host: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8888),
+                advertisement,
+            }]
+        );
+    }
+}
@@ -2,8 +2,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::any::type_name;
 use std::error::Error;
-use std::fmt::{Debug, Display};
+use std::fmt::{self, Debug, Display};
 
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
@@ -18,13 +19,322 @@ pub struct ErrorMessage<D: Debug + Display + Send + Sync + 'static>(pub D);
 
 impl<D: Debug + Display + Send + Sync + 'static> Error for ErrorMessage<D> {}
 
-/// An extension trait for `Error` that can convert errors into error messages
+/// An error sent across the network, along with the chain of errors that
+/// caused it.
+///
+/// The error displays only its own message, like any other error, and its
+/// causes are available through [`source()`][source]. Use
+/// [`chain()`](#method.chain) to display the whole chain.
+///
+/// [source]: https://doc.rust-lang.org/std/error/trait.Error.html#method.source
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(from = "ForeignErrorRepr")]
+pub struct ForeignError {
+    /// What failed.
+    ///
+    /// Only the outermost error of a chain is classified.
+    pub kind: ForeignErrorKind,
+
+    /// The message of the error.
+    pub message: String,
+
+    /// The name of the type of the error, if known.
+    ///
+    /// The types of the errors that caused an error are erased, so for those
+    /// this is the name that leads their `Debug` representation. For derived
+    /// implementations, that is the name of the type or enum variant.
+    pub type_name: Option<String>,
+
+    /// The error that caused this one, if any.
+    pub source: Option<Box<ForeignError>>,
+}
+
+/// What failed on the remote side, so that errors can be matched without
+/// comparing their messages.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Display, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForeignErrorKind {
+    /// The runner was serving another session.
+    Busy,
+
+    /// Creating, resuming, or finishing a session.
+    Session,
+
+    /// Resolving or downloading a build from Taskcluster.
+    Taskcluster,
+
+    /// Acquiring a build from anywhere other than Taskcluster.
+    Download,
+
+    /// Extracting the build.
+    Extraction,
+
+    /// Receiving, creating, or returning the profile, or writing its prefs.
+    Profile,
+
+    /// Restarting the runner.
+    Shutdown,
+
+    /// Waiting for the runner to become idle.
+    Idle,
+
+    /// The runner's desktop was locked, covered, or about to be interrupted
+    /// by the screensaver.
+    Desktop,
+
+    /// Configuring, starting, navigating, or stopping Firefox.
+    Firefox,
+
+    /// Starting the recording proxy.
+    Proxy,
+
+    /// Setting the timezone or synchronizing the clock.
+    Clock,
+
+    /// Overriding host names.
+    Hosts,
+
+    /// Conditioning the network.
+    Network,
+
+    /// Capturing or returning telemetry pings.
+    Telemetry,
+
+    /// Reading a file that was expected to exist.
+    Io,
+
+    /// A phase of the session did not finish in time.
+    Timeout,
+
+    /// Communicating with the other side of the protocol.
+    Protocol,
+
+    /// Anything else, including errors from older versions that did not
+    /// classify their errors.
+    #[default]
+    Other,
+}
+
+/// The serialized forms of a [`ForeignError`](struct.ForeignError.html).
+///
+/// Older versions sent errors as a bare message.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ForeignErrorRepr {
+    Message(String),
+    Chain {
+        #[serde(default)]
+        kind: ForeignErrorKind,
+        message: String,
+        #[serde(default)]
+        type_name: Option<String>,
+        #[serde(default)]
+        source: Option<Box<ForeignError>>,
+    },
+}
+
+impl From<ForeignErrorRepr> for ForeignError {
+    fn from(repr: ForeignErrorRepr) -> Self {
+        match repr {
+            ForeignErrorRepr::Message(message) => ForeignError::new(message),
+            ForeignErrorRepr::Chain {
+                kind,
+                message,
+                type_name,
+                source,
+            } => ForeignError {
+                kind,
+                message,
+                type_name,
+                source,
+            },
+        }
+    }
+}
+
+impl ForeignError {
+    /// Create an error that consists only of a message.
+    pub fn new<M: Into<String>>(message: M) -> Self {
+        ForeignError {
+            kind: ForeignErrorKind::Other,
+            message: message.into(),
+            type_name: None,
+            source: None,
+        }
+    }
+
+    /// Convert an error whose type has been erased, and the errors that caused
+    /// it.
+    fn from_dyn(error: &dyn Error) -> Self {
+        let debug = format!("{:?}", error);
+        let type_name = debug
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .next()
+            .filter(|name| !name.is_empty())
+            .map(Into::into);
+
+        ForeignError {
+            kind: ForeignErrorKind::Other,
+            message: error.to_string(),
+            type_name,
+            source: error
+                .source()
+                .map(|source| Box::new(Self::from_dyn(source))),
+        }
+    }
+
+    /// Classify the error.
+    pub fn with_kind(mut self, kind: ForeignErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    /// Return the error and the errors that caused it, outermost first.
+    pub fn errors(&self) -> impl Iterator<Item = &ForeignError> {
+        let mut next = Some(self);
+
+        std::iter::from_fn(move || {
+            let current = next?;
+            next = current.source.as_deref();
+            Some(current)
+        })
+    }
+
+    /// Return the root cause of the error.
+    pub fn root_cause(&self) -> &ForeignError {
+        self.errors().last().unwrap()
+    }
+
+    /// Display the error and every error that caused it.
+    pub fn chain(&self) -> ErrorChain<'_> {
+        ErrorChain(self)
+    }
+}
+
+impl Display for ForeignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ForeignError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|source| source as _)
+    }
+}
+
+/// Displays a [`ForeignError`](struct.ForeignError.html) and the errors that
+/// caused it, separated by colons.
+///
+/// Errors often include the message of their cause in their own, so causes
+/// whose message is already shown are skipped.
+pub struct ErrorChain<'a>(&'a ForeignError);
+
+impl Display for ErrorChain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut shown: Option<&str> = None;
+
+        for error in self.0.errors() {
+            if let Some(shown) = shown {
+                if shown.ends_with(error.message.as_str()) {
+                    continue;
+                }
+                write!(f, ": ")?;
+            }
+
+            write!(f, "{}", error.message)?;
+            if let Some(ref type_name) = error.type_name {
+                write!(f, " ({})", type_name)?;
+            }
+
+            shown = Some(&error.message);
+        }
+
+        Ok(())
+    }
+}
+
+/// An extension trait for `Error` that can convert errors into foreign errors
 /// for transport across the network.
 pub trait ErrorExt: Error {
-    /// Convert the `Error` into an [`ErrorMessage`](struct.ErrorMessage.html).
-    fn into_error_message(&self) -> ErrorMessage<String> {
-        ErrorMessage(self.to_string())
+    /// Convert the `Error` and the errors that caused it into a
+    /// [`ForeignError`](struct.ForeignError.html).
+    ///
+    /// The error is not classified. Use
+    /// [`with_kind()`](struct.ForeignError.html#method.with_kind) to do so.
+    fn to_foreign_error(&self) -> ForeignError
+    where
+        Self: Sized,
+    {
+        ForeignError {
+            kind: ForeignErrorKind::Other,
+            message: self.to_string(),
+            type_name: Some(type_name::<Self>().into()),
+            source: self
+                .source()
+                .map(|source| Box::new(ForeignError::from_dyn(source))),
+        }
     }
 }
 
 impl<E: Error> ErrorExt for E {}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use thiserror::Error;
+
+    use super::*;
+
+    #[derive(Debug, Error)]
+    enum DownloadError {
+        #[error("could not write build: {}", .0)]
+        Write(#[source] io::Error),
+    }
+
+    #[derive(Debug, Error)]
+    #[error("could not download build")]
+    struct FetchError(#[source] DownloadError);
+
+    #[test]
+    fn test_foreign_error() {
+        let error = FetchError(DownloadError::Write(io::Error::other("disk full")));
+
+        let foreign = error
+            .to_foreign_error()
+            .with_kind(ForeignErrorKind::Download);
+        assert_eq!(foreign.kind, ForeignErrorKind::Download);
+        assert_eq!(foreign.to_string(), "could not download build");
+        assert_eq!(foreign.errors().count(), 3);
+        assert_eq!(foreign.root_cause().message, "disk full");
+        assert_eq!(
+            foreign.source.as_ref().unwrap().type_name.as_deref(),
+            Some("Write")
+        );
+
+        // The message of the innermost error is already included in the
+        // message of the error it caused.
+        assert_eq!(
+            foreign.chain().to_string(),
+            format!(
+                "could not download build ({}): could not write build: disk full (Write)",
+                type_name::<FetchError>()
+            )
+        );
+
+        let json = serde_json::to_string(&foreign).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ForeignError>(&json).unwrap(),
+            foreign
+        );
+    }
+
+    #[test]
+    fn test_foreign_error_message() {
+        let foreign: ForeignError = serde_json::from_str("\"the runner is busy\"").unwrap();
+        assert_eq!(foreign, ForeignError::new("the runner is busy"));
+        assert_eq!(foreign.kind, ForeignErrorKind::Other);
+        assert_eq!(foreign.chain().to_string(), "the runner is busy");
+    }
+}
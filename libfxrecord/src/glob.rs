@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Matching artifact names and profile paths against glob patterns.
+
+/// Return whether or not the name matches the glob pattern.
+///
+/// A `*` matches any sequence of characters and a `?` matches any single
+/// character, except for the path separator `/`.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+
+    // The position of the last `*` in the pattern and the position in the name
+    // it is currently matched up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+
+            Some('?') if name[n] != '/' => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+
+            Some(&c) if c != '?' && c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+
+            _ => {}
+        }
+
+        // Extend the last `*` by one character, unless that would cross a path
+        // separator.
+        match backtrack {
+            Some((star_p, star_n)) if name[star_n] != '/' => {
+                backtrack = Some((star_p, star_n + 1));
+                p = star_p + 1;
+                n = star_n + 1;
+            }
+            _ => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches(
+            "public/build/target.zip",
+            "public/build/target.zip"
+        ));
+        assert!(glob_matches(
+            "public/build/*.zip",
+            "public/build/target.zip"
+        ));
+        assert!(glob_matches(
+            "public/build/target.*",
+            "public/build/target.tar.bz2"
+        ));
+        assert!(glob_matches(
+            "public/*/target.zip",
+            "public/build/target.zip"
+        ));
+        assert!(glob_matches(
+            "public/build/targe?.zip",
+            "public/build/target.zip"
+        ));
+        assert!(glob_matches("*", "target.zip"));
+
+        assert!(!glob_matches(
+            "public/build/*.zip",
+            "public/build/target.tar.bz2"
+        ));
+        assert!(!glob_matches("*.zip", "public/build/target.zip"));
+        assert!(!glob_matches(
+            "public?build/target.zip",
+            "public/build/target.zip"
+        ));
+        assert!(!glob_matches(
+            "public/build/target.zip",
+            "public/build/target.zip2"
+        ));
+    }
+}
@@ -3,10 +3,31 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod config;
+pub mod discovery;
 pub mod error;
+pub mod glob;
 pub mod logging;
 pub mod net;
 pub mod prefs;
+pub mod retry;
+pub mod secret;
+pub mod storage;
+pub mod timeout;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use sha2::{Digest, Sha256};
 
 /// The shade of orange visualmetrics.p; expects for pre-recording frames.
 pub const ORANGE: [u8; 3] = [222, 100, 13];
+
+/// Return the hex encoding of the bytes.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Return the hex-encoded SHA-256 digest of the bytes.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
@@ -3,46 +3,212 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::fmt;
-use std::fs::OpenOptions;
-use std::io;
-use std::path::Path;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use chrono::Utc;
-use slog::{Drain, Duplicate, Key, Logger, OwnedKVList, Record, Serializer, KV};
+use serde::Deserialize;
+use slog::{Drain, Duplicate, Key, Logger, Never, OwnedKVList, Record, Serializer, KV};
 use slog_term::{Decorator, PlainDecorator, RecordDecorator, TermDecorator};
 
+mod rotate;
+
+pub use rotate::{RotatingFile, RotationConfig};
+
 // RFC3339 timestamp with millisecond precision.
 const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3fZ";
 
+/// The configuration of a single log drain.
+///
+/// Any number of drains may be configured, e.g., to log JSON to a file for
+/// ingestion while logging human-readable output to the terminal.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct DrainConfig {
+    /// The format of the log records.
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// The file to append log records to.
+    ///
+    /// If not provided, records are written to stderr. The file's directory is
+    /// created if it does not exist.
+    pub path: Option<PathBuf>,
+
+    /// When to rotate the file.
+    ///
+    /// If not provided, the file is never rotated.
+    pub rotate: Option<RotationConfig>,
+}
+
+impl DrainConfig {
+    /// Human-readable output to stderr.
+    pub fn terminal() -> Self {
+        DrainConfig {
+            format: LogFormat::Pretty,
+            path: None,
+            rotate: None,
+        }
+    }
+
+    /// Human-readable output to the given file, which is rotated with the
+    /// default rotation settings.
+    pub fn file<P: Into<PathBuf>>(path: P) -> Self {
+        DrainConfig {
+            format: LogFormat::Pretty,
+            path: Some(path.into()),
+            rotate: Some(RotationConfig::default()),
+        }
+    }
+}
+
+/// The format of log records.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable records, with each key-value pair on its own line.
+    #[default]
+    Pretty,
+
+    /// One JSON object per record.
+    Json,
+}
+
+/// A type-erased drain.
+type BoxedDrain = Box<dyn Drain<Ok = (), Err = Never> + Send>;
+
+/// Create a logger that logs to each of the given drains.
+pub fn build_logger(drains: &[DrainConfig]) -> Result<Logger, io::Error> {
+    let drains = drains
+        .iter()
+        .map(build_drain)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let drain = slog_async::Async::new(MultiDrain(drains)).build().fuse();
+    Ok(Logger::root(drain, slog::o! {}))
+}
+
 /// Create a logger that logs to stderr.
 pub fn build_terminal_logger() -> Logger {
-    let decorator = TermDecorator::new().stderr().force_plain().build();
-    let drain = MultiLineDrain { decorator }.fuse();
+    build_logger(&[DrainConfig::terminal()]).expect("could not build terminal logger")
+}
+
+/// Create a logger that logs to stderr and to a file.
+pub fn build_file_logger(path: &Path) -> Result<Logger, io::Error> {
+    build_logger(&[DrainConfig::terminal(), DrainConfig::file(path)])
+}
 
-    let drain = slog_async::Async::new(drain).build().fuse();
+/// Create a logger that logs to the given logger and also appends
+/// human-readable records to a file.
+///
+/// The file is only open while a record is being written, so that it may be
+/// removed while the logger is still alive. Records that cannot be written to
+/// the file are dropped.
+pub fn build_tee_logger(log: &Logger, path: &Path) -> Logger {
+    let file_drain = MultiLineDrain {
+        decorator: PlainDecorator::new(AppendOnFlush {
+            path: path.into(),
+            buf: Vec::new(),
+        }),
+    };
 
+    let drain = Duplicate::new(log.clone(), Mutex::new(file_drain).ignore_res()).ignore_res();
     Logger::root(drain, slog::o! {})
 }
 
-/// Create a logger that logs to stderr and to a file.
-pub fn build_file_logger(path: &Path) -> Result<Logger, std::io::Error> {
-    let term_decorator = TermDecorator::new().stderr().force_plain().build();
-    let term_drain = MultiLineDrain {
-        decorator: term_decorator,
+fn build_drain(config: &DrainConfig) -> Result<BoxedDrain, io::Error> {
+    let drain: BoxedDrain = match (config.format, &config.path) {
+        (LogFormat::Pretty, None) => Box::new(
+            MultiLineDrain {
+                decorator: TermDecorator::new().stderr().force_plain().build(),
+            }
+            .fuse(),
+        ),
+        (LogFormat::Pretty, Some(path)) => Box::new(
+            MultiLineDrain {
+                decorator: PlainDecorator::new(open_log(path, config.rotate.as_ref())?),
+            }
+            .fuse(),
+        ),
+        (LogFormat::Json, None) => Box::new(
+            slog_json::Json::new(io::stderr())
+                .add_default_keys()
+                .build()
+                .fuse(),
+        ),
+        (LogFormat::Json, Some(path)) => Box::new(
+            slog_json::Json::new(open_log(path, config.rotate.as_ref())?)
+                .add_default_keys()
+                .set_flush(true)
+                .build()
+                .fuse(),
+        ),
+    };
+
+    Ok(drain)
+}
+
+fn open_log(
+    path: &Path,
+    rotate: Option<&RotationConfig>,
+) -> Result<Box<dyn Write + Send>, io::Error> {
+    match rotate {
+        Some(rotate) => Ok(Box::new(RotatingFile::open(path, rotate)?)),
+        None => {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+
+            Ok(Box::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))
+        }
     }
-    .fuse();
+}
 
-    let f = OpenOptions::new().create(true).append(true).open(path)?;
+/// A writer that buffers writes and appends them to a file when flushed.
+struct AppendOnFlush {
+    path: PathBuf,
+    buf: Vec<u8>,
+}
 
-    let file_decorator = PlainDecorator::new(f);
-    let file_drain = MultiLineDrain {
-        decorator: file_decorator,
+impl Write for AppendOnFlush {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
     }
-    .fuse();
 
-    let drain = Duplicate::new(file_drain, term_drain).fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-    Ok(Logger::root(drain, slog::o! {}))
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&self.buf));
+
+        self.buf.clear();
+        result
+    }
+}
+
+/// A drain that logs every record to each of its drains.
+struct MultiDrain(Vec<BoxedDrain>);
+
+impl Drain for MultiDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        for drain in &self.0 {
+            drain.log(record, values)?;
+        }
+
+        Ok(())
+    }
 }
 
 /// A drain that serializes each key-value pair on their own line, indented from
@@ -0,0 +1,267 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Log files that are rotated once they grow too large or too old.
+
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+/// When to rotate a log file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct RotationConfig {
+    /// Rotate the file once it reaches this size, in megabytes.
+    pub max_size_mb: Option<u64>,
+
+    /// Rotate the file once it is this old, in hours.
+    pub max_age_hours: Option<u64>,
+
+    /// The number of rotated files to keep.
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+}
+
+fn default_keep() -> u32 {
+    5
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            max_size_mb: Some(10),
+            max_age_hours: None,
+            keep: default_keep(),
+        }
+    }
+}
+
+/// A log file that is rotated according to a [`RotationConfig`].
+///
+/// Rotated files are named after the log file with a numeric suffix, e.g.,
+/// `fxrunner.log.1` is the most recently rotated file. Only `keep` rotated
+/// files are kept.
+///
+/// Rotation is only considered when the file is flushed, which the log drains
+/// do after every record, so that a record is never split across files.
+///
+/// [`RotationConfig`]: struct.RotationConfig.html
+pub struct RotatingFile {
+    path: PathBuf,
+
+    /// The current file.
+    ///
+    /// This is only `None` while rotating, as Windows does not allow open
+    /// files to be renamed.
+    file: Option<File>,
+
+    /// The size of the current file.
+    size: u64,
+
+    /// When the current file was created.
+    created: SystemTime,
+
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: u32,
+}
+
+impl RotatingFile {
+    /// Open the log file at the given path for appending, creating it and its
+    /// parent directories if necessary.
+    pub fn open(path: &Path, config: &RotationConfig) -> Result<Self, io::Error> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let (file, size, created) = open_log(path)?;
+
+        Ok(RotatingFile {
+            path: path.into(),
+            file: Some(file),
+            size,
+            created,
+            max_size: config.max_size_mb.map(|mb| mb * 1024 * 1024),
+            max_age: config
+                .max_age_hours
+                .map(|hours| Duration::from_secs(hours * 60 * 60)),
+            keep: config.keep,
+        })
+    }
+
+    /// Return the current file.
+    ///
+    /// If a previous rotation failed part way, the file is re-opened.
+    fn file(&mut self) -> Result<&mut File, io::Error> {
+        if self.file.is_none() {
+            let (file, size, created) = open_log(&self.path)?;
+            self.file = Some(file);
+            self.size = size;
+            self.created = created;
+        }
+
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    /// Whether or not the current file is due to be rotated.
+    fn should_rotate(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        let too_large = self.max_size.is_some_and(|max_size| self.size >= max_size);
+        let too_old = self.max_age.is_some_and(|max_age| {
+            self.created
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+
+        too_large || too_old
+    }
+
+    /// Rotate the current file and open a new one in its place.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        drop(self.file.take());
+
+        if self.keep == 0 {
+            remove_file(&self.path)?;
+        } else {
+            let oldest = rotated_path(&self.path, self.keep);
+            if oldest.exists() {
+                remove_file(&oldest)?;
+            }
+
+            for i in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, i);
+                if from.exists() {
+                    rename(&from, rotated_path(&self.path, i + 1))?;
+                }
+            }
+
+            rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        let (file, size, _) = open_log(&self.path)?;
+        self.file = Some(file);
+        self.size = size;
+        self.created = SystemTime::now();
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file()?.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file()?.flush()?;
+
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Open a log file for appending, returning its size and creation time.
+fn open_log(path: &Path) -> Result<(File, u64, SystemTime), io::Error> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+
+    // Not every platform records when a file was created.
+    let created = metadata
+        .created()
+        .or_else(|_| metadata.modified())
+        .unwrap_or_else(|_| SystemTime::now());
+
+    Ok((file, metadata.len(), created))
+}
+
+/// Return the path of the `n`th rotated file.
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    rotated.into()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::read_to_string;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("logs").join("test.log");
+
+        let mut f = RotatingFile::open(
+            &path,
+            &RotationConfig {
+                max_size_mb: Some(1),
+                max_age_hours: None,
+                keep: 2,
+            },
+        )
+        .unwrap();
+        f.max_size = Some(8);
+
+        for record in &["record 1\n", "record 2\n", "record 3\n", "record 4\n"] {
+            f.write_all(record.as_bytes()).unwrap();
+            f.flush().unwrap();
+        }
+
+        f.write_all(b"record 5\n").unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "record 5\n");
+        assert_eq!(
+            read_to_string(rotated_path(&path, 1)).unwrap(),
+            "record 4\n"
+        );
+        assert_eq!(
+            read_to_string(rotated_path(&path, 2)).unwrap(),
+            "record 3\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("test.log");
+
+        let mut f = RotatingFile::open(
+            &path,
+            &RotationConfig {
+                max_size_mb: None,
+                max_age_hours: Some(1),
+                keep: 1,
+            },
+        )
+        .unwrap();
+
+        f.write_all(b"record 1\n").unwrap();
+        f.flush().unwrap();
+        assert!(!rotated_path(&path, 1).exists());
+
+        f.created -= Duration::from_secs(2 * 60 * 60);
+        f.write_all(b"record 2\n").unwrap();
+        f.flush().unwrap();
+
+        assert_eq!(read_to_string(&path).unwrap(), "");
+        assert_eq!(
+            read_to_string(rotated_path(&path, 1)).unwrap(),
+            "record 1\nrecord 2\n"
+        );
+    }
+}
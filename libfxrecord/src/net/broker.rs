@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Messages exchanged with `fxbroker`.
+//!
+//! Runners register with the broker when they start. Recorders ask the broker
+//! for a lease on a runner with the tags they need, waiting in a queue until
+//! one is free, and then connect to the leased runner directly. The recorder
+//! renews the lease while it uses the runner. Once the session is finished,
+//! the recorder releases the lease so that the runner can be leased again.
+
+use std::collections::BTreeMap;
+
+use derive_more::Display;
+use libfxrecord_macros::message_type;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::net::message::{ForeignResult, KindMismatch, Message, MessageContent};
+use crate::net::proto::{Proto, ProtoError};
+
+/// A runner registering itself with the broker.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RunnerRegistration {
+    /// The name of the runner, which is unique within the fleet.
+    pub name: String,
+
+    /// The port the runner accepts connections from recorders on.
+    ///
+    /// The runner is reached at this port on the address it registered from.
+    pub port: u16,
+
+    /// The tags describing the runner, e.g., `os = "win10"`.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A request from a recorder for a runner with the given tags.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeaseRequest {
+    /// The tags the runner must have.
+    pub tags: BTreeMap<String, String>,
+
+    /// A description of the recorder, which is recorded in the fleet's
+    /// history.
+    pub requester: String,
+}
+
+/// A runner leased to a recorder.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Lease {
+    /// The ID of the lease, which must be given to release it.
+    pub lease_id: String,
+
+    /// The name of the leased runner.
+    pub runner: String,
+
+    /// The address to connect to the leased runner on.
+    pub host: String,
+
+    /// How long the lease lasts, in seconds.
+    ///
+    /// If the lease is neither renewed nor released in time, the runner is
+    /// returned to the pool anyway.
+    pub duration_secs: u64,
+}
+
+/// A recorder extending its lease on a runner that it is still using.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeaseRenewal {
+    /// The ID of the lease.
+    pub lease_id: String,
+}
+
+/// A recorder returning a leased runner to the pool.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeaseRelease {
+    /// The ID of the lease.
+    pub lease_id: String,
+
+    /// What became of the session the runner was leased for.
+    pub outcome: LeaseOutcome,
+}
+
+/// What became of the session a runner was leased for.
+#[derive(Clone, Debug, Deserialize, Display, Eq, PartialEq, Serialize)]
+pub enum LeaseOutcome {
+    /// The session finished successfully.
+    #[display(fmt = "succeeded")]
+    Succeeded,
+
+    /// The session failed with the given error.
+    #[display(fmt = "failed: {}", _0)]
+    Failed(String),
+}
+
+impl From<RunnerRegistration> for BrokerRequest {
+    fn from(registration: RunnerRegistration) -> BrokerRequest {
+        BrokerRequest::Register(registration)
+    }
+}
+
+impl From<LeaseRequest> for BrokerRequest {
+    fn from(request: LeaseRequest) -> BrokerRequest {
+        BrokerRequest::Lease(request)
+    }
+}
+
+impl From<LeaseRenewal> for BrokerRequest {
+    fn from(renewal: LeaseRenewal) -> BrokerRequest {
+        BrokerRequest::Renew(renewal)
+    }
+}
+
+impl From<LeaseRelease> for BrokerRequest {
+    fn from(release: LeaseRelease) -> BrokerRequest {
+        BrokerRequest::Release(release)
+    }
+}
+
+message_type! {
+    /// A message from a runner or recorder to FxBroker.
+    BrokerClientMessage,
+
+    /// The kind of a [`BrokerClientMessage`](struct.BrokerClientMessage.html).
+    BrokerClientMessageKind;
+
+    /// A request to the broker.
+    ///
+    /// Each connection to the broker carries a single request.
+    pub enum BrokerRequest {
+        /// Add a runner to the pool, replacing any runner of the same name.
+        ///
+        /// The broker responds with [`Registered`](struct.Registered.html).
+        Register(RunnerRegistration),
+
+        /// Ask for a lease on a runner.
+        ///
+        /// The broker responds with [`Queued`](struct.Queued.html) once the
+        /// request is queued, and then with [`Leased`](struct.Leased.html)
+        /// once a runner is free.
+        Lease(LeaseRequest),
+
+        /// Restart the duration of a lease.
+        ///
+        /// The broker responds with [`Renewed`](struct.Renewed.html).
+        Renew(LeaseRenewal),
+
+        /// Return a leased runner to the pool.
+        ///
+        /// The broker responds with [`Released`](struct.Released.html).
+        Release(LeaseRelease),
+    }
+}
+
+message_type! {
+    /// A message from FxBroker to a runner or recorder.
+    BrokerMessage,
+
+    /// The kind of a [`BrokerMessage`](struct.BrokerMessage.html).
+    BrokerMessageKind;
+
+    /// The result of registering a runner.
+    pub struct Registered {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The lease request is waiting in the queue.
+    pub struct Queued {
+        /// The number of requests ahead of this one, including requests for
+        /// runners with other tags.
+        pub position: usize,
+    }
+
+    /// The result of a lease request.
+    pub struct Leased {
+        pub result: ForeignResult<Lease>,
+    }
+
+    /// The result of renewing a lease.
+    pub struct Renewed {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The result of releasing a lease.
+    pub struct Released {
+        pub result: ForeignResult<()>,
+    }
+}
+
+/// A connection to the broker from a runner or recorder.
+pub type BrokerClientProto<St = TcpStream> =
+    Proto<BrokerMessage, BrokerClientMessage, BrokerMessageKind, BrokerClientMessageKind, St>;
+
+/// A connection from a runner or recorder to the broker.
+pub type BrokerServerProto<St = TcpStream> =
+    Proto<BrokerClientMessage, BrokerMessage, BrokerClientMessageKind, BrokerMessageKind, St>;
+
+/// Register a runner with the broker.
+pub async fn register<St>(
+    proto: &mut BrokerClientProto<St>,
+    registration: RunnerRegistration,
+) -> Result<(), ProtoError<BrokerMessageKind>>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    proto.send(BrokerRequest::from(registration)).await?;
+    proto.recv::<Registered>().await?.result?;
+
+    Ok(())
+}
+
+/// Ask the broker for a lease, waiting until a runner is free.
+///
+/// `on_queued` is called with the position of the request in the queue once
+/// it has been queued.
+pub async fn lease<St, F>(
+    proto: &mut BrokerClientProto<St>,
+    request: LeaseRequest,
+    on_queued: F,
+) -> Result<Lease, ProtoError<BrokerMessageKind>>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(usize),
+{
+    proto.send(BrokerRequest::from(request)).await?;
+
+    let Queued { position } = proto.recv().await?;
+    on_queued(position);
+
+    Ok(proto.recv::<Leased>().await?.result?)
+}
+
+/// Renew a lease, so that the runner is not returned to the pool while it is
+/// still in use.
+pub async fn renew<St>(
+    proto: &mut BrokerClientProto<St>,
+    renewal: LeaseRenewal,
+) -> Result<(), ProtoError<BrokerMessageKind>>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    proto.send(BrokerRequest::from(renewal)).await?;
+    proto.recv::<Renewed>().await?.result?;
+
+    Ok(())
+}
+
+/// Return a leased runner to the pool.
+pub async fn release<St>(
+    proto: &mut BrokerClientProto<St>,
+    release: LeaseRelease,
+) -> Result<(), ProtoError<BrokerMessageKind>>
+where
+    St: AsyncRead + AsyncWrite + Unpin,
+{
+    proto.send(BrokerRequest::from(release)).await?;
+    proto.recv::<Released>().await?.result?;
+
+    Ok(())
+}
@@ -0,0 +1,418 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compression of the payloads sent between messages.
+//!
+//! Builds and profiles are sent as raw bytes after the message announcing
+//! their size. The recorder offers the codecs it supports when it requests a
+//! session and the runner picks one of them. Both sides then compress every
+//! payload of at least [`COMPRESSION_THRESHOLD`](constant.COMPRESSION_THRESHOLD.html)
+//! bytes with that codec. Messages themselves are never compressed.
+//!
+//! A compressed payload is sent as a series of frames, each prefixed with its
+//! length as a big-endian `u32`, followed by an empty frame. This way the
+//! receiver knows where the payload ends without knowing its compressed size
+//! in advance.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The smallest payload worth compressing, in bytes.
+pub const COMPRESSION_THRESHOLD: u64 = 64 * 1024;
+
+/// The codecs supported by this version of the protocol, most preferred
+/// first.
+///
+/// [`Codec::Identity`](enum.Codec.html#variant.Identity) is always supported
+/// and need not be offered.
+pub const SUPPORTED_CODECS: &[Codec] = &[Codec::Deflate];
+
+/// The size of the chunks a payload is read in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The largest frame that is accepted.
+///
+/// Each frame holds one compressed chunk, which is only larger than the chunk
+/// itself when the chunk does not compress.
+const MAX_FRAME_SIZE: usize = 2 * CHUNK_SIZE;
+
+/// How a payload is encoded.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// The payload is sent as-is.
+    #[default]
+    Identity,
+
+    /// The payload is compressed with DEFLATE.
+    Deflate,
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Identity => write!(f, "identity"),
+            Codec::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+impl Codec {
+    /// Return the codec that a payload of the given size is sent with.
+    ///
+    /// Payloads smaller than the compression threshold are sent as-is.
+    pub fn for_payload(self, size: u64) -> Codec {
+        if size < COMPRESSION_THRESHOLD {
+            Codec::Identity
+        } else {
+            self
+        }
+    }
+}
+
+/// Pick the first of the offered codecs that is supported.
+pub fn negotiate(offered: &[Codec]) -> Codec {
+    offered
+        .iter()
+        .copied()
+        .find(|codec| SUPPORTED_CODECS.contains(codec))
+        .unwrap_or_default()
+}
+
+/// Statistics about a payload that was sent or received.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct TransferStats {
+    /// The codec the payload was sent with.
+    pub codec: Codec,
+
+    /// The size of the payload, in bytes.
+    pub size: u64,
+
+    /// The number of bytes sent over the connection.
+    pub wire_size: u64,
+}
+
+/// Send the payload of the given size read from `reader` to `writer`.
+///
+/// The payload is compressed with `codec` if it is large enough.
+pub async fn send_payload<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    codec: Codec,
+) -> io::Result<TransferStats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let codec = codec.for_payload(size);
+    let mut reader = reader.take(size);
+
+    let (sent, wire_size) = match codec {
+        Codec::Identity => {
+            let sent = tokio::io::copy(&mut reader, writer).await?;
+            (sent, sent)
+        }
+
+        Codec::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            let mut buf = vec![0; CHUNK_SIZE];
+            let mut sent = 0;
+            let mut wire_size = 0;
+
+            loop {
+                let n = reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+
+                // Flushing after every chunk lets the receiver decompress the
+                // payload as it arrives.
+                encoder.write_all(&buf[..n])?;
+                encoder.flush()?;
+
+                sent += n as u64;
+                wire_size += write_frame(writer, encoder.get_mut()).await?;
+            }
+
+            let mut rest = encoder.finish()?;
+            wire_size += write_frame(writer, &mut rest).await?;
+
+            writer.write_u32(0).await?;
+            wire_size += 4;
+
+            (sent, wire_size)
+        }
+    };
+
+    if sent != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("payload ended after {} of {} bytes", sent, size),
+        ));
+    }
+
+    writer.flush().await?;
+
+    Ok(TransferStats {
+        codec,
+        size,
+        wire_size,
+    })
+}
+
+/// Receive a payload of the given size sent by
+/// [`send_payload()`](fn.send_payload.html) from `reader` and write it to
+/// `writer`.
+///
+/// `on_progress` is called with the number of bytes of the payload received
+/// so far. Nothing past the end of the payload is read from `reader`.
+pub async fn recv_payload<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    codec: Codec,
+    mut on_progress: F,
+) -> io::Result<TransferStats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(u64),
+{
+    let codec = codec.for_payload(size);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+
+    let wire_size = match codec {
+        Codec::Identity => {
+            while received < size {
+                let len = (size - received).min(buf.len() as u64) as usize;
+
+                let n = reader.read(&mut buf[..len]).await?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before the payload was received",
+                    ));
+                }
+
+                writer.write_all(&buf[..n]).await?;
+
+                received += n as u64;
+                on_progress(received);
+            }
+
+            size
+        }
+
+        Codec::Deflate => {
+            let mut decoder = DeflateDecoder::new(Vec::new());
+            let mut wire_size = 0;
+
+            loop {
+                let len = reader.read_u32().await? as usize;
+                wire_size += 4 + len as u64;
+
+                if len == 0 {
+                    break;
+                }
+
+                if len > MAX_FRAME_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("frame of {} bytes is too large", len),
+                    ));
+                }
+
+                buf.resize(len, 0);
+                reader.read_exact(&mut buf[..len]).await?;
+
+                decoder.write_all(&buf[..len])?;
+                decoder.flush()?;
+
+                received += write_decompressed(writer, decoder.get_mut(), size - received).await?;
+                on_progress(received);
+            }
+
+            let mut rest = decoder.finish()?;
+            received += write_decompressed(writer, &mut rest, size - received).await?;
+
+            wire_size
+        }
+    };
+
+    if received != size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("received {} bytes of a {}-byte payload", received, size),
+        ));
+    }
+
+    writer.flush().await?;
+
+    Ok(TransferStats {
+        codec,
+        size,
+        wire_size,
+    })
+}
+
+/// Write the non-empty frame and clear it, returning the number of bytes
+/// written.
+async fn write_frame<W>(writer: &mut W, frame: &mut Vec<u8>) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    // An empty frame marks the end of the payload.
+    if frame.is_empty() {
+        return Ok(0);
+    }
+
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await?;
+
+    let written = 4 + frame.len() as u64;
+    frame.clear();
+
+    Ok(written)
+}
+
+/// Write the decompressed bytes and clear them, returning how many there were.
+///
+/// Nothing is written if there are more than the `remaining` bytes of the
+/// payload, so a payload cannot grow past its declared size.
+async fn write_decompressed<W>(
+    writer: &mut W,
+    decompressed: &mut Vec<u8>,
+    remaining: u64,
+) -> io::Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    if decompressed.len() as u64 > remaining {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "payload is larger than its declared size",
+        ));
+    }
+
+    writer.write_all(decompressed).await?;
+
+    let written = decompressed.len() as u64;
+    decompressed.clear();
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn round_trip(payload: &[u8], codec: Codec) -> (Vec<u8>, TransferStats, Vec<u8>) {
+        let mut wire = Vec::new();
+        let sent = send_payload(&mut &payload[..], &mut wire, payload.len() as u64, codec)
+            .await
+            .unwrap();
+
+        assert_eq!(sent.wire_size, wire.len() as u64);
+        wire.extend_from_slice(b"trailing");
+
+        let mut reader = &wire[..];
+        let mut received = Vec::new();
+        let mut progress = 0;
+        let stats = recv_payload(
+            &mut reader,
+            &mut received,
+            payload.len() as u64,
+            codec,
+            |n| progress = n,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stats, sent);
+        assert_eq!(progress, payload.len() as u64);
+
+        (received, stats, reader.to_vec())
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(&[]), Codec::Identity);
+        assert_eq!(negotiate(&[Codec::Identity]), Codec::Identity);
+        assert_eq!(
+            negotiate(&[Codec::Deflate, Codec::Identity]),
+            Codec::Deflate
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payload() {
+        let payload = b"firefox".repeat(COMPRESSION_THRESHOLD as usize);
+
+        let (received, stats, trailing) = round_trip(&payload, Codec::Deflate).await;
+        assert_eq!(received, payload);
+        assert_eq!(stats.codec, Codec::Deflate);
+        assert!(stats.wire_size < stats.size);
+        // Only the payload is consumed from the stream.
+        assert_eq!(trailing, b"trailing");
+
+        let (received, stats, trailing) = round_trip(&payload, Codec::Identity).await;
+        assert_eq!(received, payload);
+        assert_eq!(stats.codec, Codec::Identity);
+        assert_eq!(stats.wire_size, stats.size);
+        assert_eq!(trailing, b"trailing");
+
+        // Small payloads are never compressed.
+        let (received, stats, _) = round_trip(b"prefs", Codec::Deflate).await;
+        assert_eq!(received, b"prefs");
+        assert_eq!(stats.codec, Codec::Identity);
+    }
+
+    #[tokio::test]
+    async fn test_recv_payload_invalid() {
+        let size = COMPRESSION_THRESHOLD;
+
+        // A frame length that was never sent is rejected before anything is
+        // read into memory.
+        let mut wire = Vec::new();
+        wire.extend_from_slice(&u32::MAX.to_be_bytes());
+
+        let mut received = Vec::new();
+        let err = recv_payload(&mut &wire[..], &mut received, size, Codec::Deflate, |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!("frame of {} bytes is too large", u32::MAX)
+        );
+
+        // A payload that decompresses to more than its declared size is
+        // rejected before the excess is written.
+        let payload = vec![0u8; 4 * size as usize];
+        let mut wire = Vec::new();
+        send_payload(
+            &mut &payload[..],
+            &mut wire,
+            payload.len() as u64,
+            Codec::Deflate,
+        )
+        .await
+        .unwrap();
+
+        let mut received = Vec::new();
+        let err = recv_payload(&mut &wire[..], &mut received, size, Codec::Deflate, |_| {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "payload is larger than its declared size");
+        assert!(received.len() as u64 <= size);
+    }
+}
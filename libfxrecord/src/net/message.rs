@@ -12,15 +12,22 @@
 //! [Proto]: ./struct.Proto.html
 //! [message_type]: ../../../libfxrecord_macros/macro.message_type.html
 
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::fmt::{Debug, Display};
+use std::fmt::{self, Debug, Display};
+use std::net::IpAddr;
+use std::ops::Add;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use derive_more::Display;
 use libfxrecord_macros::message_type;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::error::ErrorMessage;
+use crate::error::ForeignError;
+use crate::net::compression::Codec;
 use crate::prefs::PrefValue;
 
 /// A message is a serializable and deserializable type.
@@ -77,19 +84,471 @@ pub enum Idle {
     Skip,
 }
 
+/// The Taskcluster task that produced a build.
+#[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub enum BuildTask {
+    /// A task ID.
+    TaskId(String),
+
+    /// A route in the Taskcluster index, which the runner resolves to a task
+    /// ID.
+    IndexRoute(String),
+
+    /// A revision of a project, which the runner resolves to a task ID via the
+    /// index.
+    Revision {
+        /// The project (e.g., `mozilla-central`).
+        project: String,
+
+        /// The revision.
+        revision: String,
+
+        /// The build platform (e.g., `win64-shippable-opt`).
+        platform: String,
+    },
+}
+
+impl BuildTask {
+    /// Return the index route for the build task, if it must be resolved.
+    pub fn index_route(&self) -> Option<String> {
+        match self {
+            BuildTask::TaskId(..) => None,
+            BuildTask::IndexRoute(route) => Some(route.clone()),
+            BuildTask::Revision {
+                project,
+                revision,
+                platform,
+            } => Some(format!(
+                "gecko.v2.{}.revision.{}.firefox.{}",
+                project, revision, platform
+            )),
+        }
+    }
+}
+
+impl From<&str> for BuildTask {
+    fn from(task_id: &str) -> Self {
+        BuildTask::TaskId(task_id.into())
+    }
+}
+
+/// Where the runner acquires the build of Firefox for a session.
+#[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub enum BuildSource {
+    /// A build artifact from a Taskcluster task.
+    Taskcluster {
+        /// The build task.
+        task: BuildTask,
+
+        /// The name of the build artifact to download, if not the runner's
+        /// default.
+        ///
+        /// This may be a glob pattern, which must match exactly one artifact.
+        artifact: Option<String>,
+    },
+
+    /// An official build from archive.mozilla.org.
+    MozillaArchive(OfficialBuild),
+
+    /// A build archive that the runner downloads from the given URL.
+    Url(String),
+
+    /// A build archive that already exists on the runner at the given path.
+    Path(PathBuf),
+
+    /// A build archive that the recorder sends to the runner.
+    Upload {
+        /// The size of the archive.
+        size: u64,
+
+        /// The hex-encoded SHA-256 digest of the archive.
+        ///
+        /// A retried request only reuses a build uploaded with the same
+        /// digest.
+        sha256: String,
+    },
+}
+
+/// An official build published to archive.mozilla.org.
+#[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub enum OfficialBuild {
+    /// A released version on the given channel (e.g., `80.0` on release or
+    /// `81.0b3` on beta).
+    Release { channel: Channel, version: String },
+
+    /// The mozilla-central nightly build from the given date, in the form
+    /// `YYYY-MM-DD`.
+    ///
+    /// If there were multiple nightly builds that day, the last is used.
+    Nightly { date: String },
+}
+
+/// A release channel of Firefox.
+#[derive(Clone, Copy, Debug, Display, Eq, Deserialize, PartialEq, Serialize)]
+pub enum Channel {
+    #[display(fmt = "release")]
+    Release,
+
+    #[display(fmt = "beta")]
+    Beta,
+
+    #[display(fmt = "esr")]
+    Esr,
+
+    #[display(fmt = "devedition")]
+    DevEdition,
+}
+
+impl Channel {
+    /// The name of the product that builds on this channel are published
+    /// under.
+    pub fn product(self) -> &'static str {
+        match self {
+            Channel::Release | Channel::Beta | Channel::Esr => "firefox",
+            Channel::DevEdition => "devedition",
+        }
+    }
+}
+
+impl From<BuildTask> for BuildSource {
+    fn from(task: BuildTask) -> Self {
+        BuildSource::Taskcluster {
+            task,
+            artifact: None,
+        }
+    }
+}
+
 /// A request for a new session.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct NewSessionRequest {
-    /// The task ID of the Taskcluster build task.
+    /// Where the runner acquires the build.
+    pub build: BuildSource,
+
+    /// The size of the zip archive of a distribution that will be sent, if
+    /// any.
     ///
-    /// The build artifact from this task will be downloaded by the runner.
-    pub build_task_id: String,
+    /// The runner installs the distribution, which must contain a
+    /// `distribution.ini`, into the build to simulate a partner repack.
+    #[serde(default)]
+    pub distribution_size: Option<u64>,
 
     /// The size of the profile that will be sent, if any.
     pub profile_size: Option<u64>,
 
+    /// Whether or not only the changes to the profile cached on the runner
+    /// will be sent.
+    ///
+    /// If set, the runner sends a [`ProfileManifest`](struct.ProfileManifest.html)
+    /// and the recorder replies with a [`ProfileDelta`](struct.ProfileDelta.html)
+    /// instead of sending the entire profile.
+    #[serde(default)]
+    pub profile_delta: bool,
+
+    /// The name of a profile template on the runner to copy instead of
+    /// creating a new profile.
+    ///
+    /// This is ignored if a profile will be sent.
+    #[serde(default)]
+    pub profile_template: Option<String>,
+
     /// Prefs to override in the profile.
     pub prefs: Vec<(String, PrefValue)>,
+
+    /// The locale to run Firefox in, e.g., `de` or `ja`, if not the build's
+    /// own.
+    ///
+    /// Builds from archive.mozilla.org are downloaded in this locale. For any
+    /// other build, the runner installs the matching language pack into the
+    /// profile.
+    #[serde(default)]
+    pub locale: Option<String>,
+
+    /// The size of the zip archive of extensions that will be sent, if any.
+    ///
+    /// Each file in the archive is an XPI that the runner installs into the
+    /// profile.
+    #[serde(default)]
+    pub extensions_size: Option<u64>,
+
+    /// An ID chosen by the recorder that is the same for every attempt at
+    /// this request.
+    ///
+    /// If the recorder retries the request after a network error, the runner
+    /// reuses the build it already acquired for the request instead of
+    /// acquiring it again.
+    #[serde(default)]
+    pub request_id: Option<String>,
+
+    /// The codecs the recorder supports for the build and profile, most
+    /// preferred first.
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
+}
+
+/// The type of measurement a session performs.
+#[derive(Clone, Debug, Default, Eq, Deserialize, PartialEq, Serialize)]
+pub enum SessionType {
+    /// Measure a cold start of Firefox.
+    ///
+    /// Recording begins before Firefox is launched.
+    #[default]
+    ColdStart,
+
+    /// Measure a page load in an already running Firefox.
+    ///
+    /// Firefox is started on an orange page and allowed to settle before
+    /// recording begins. Recording begins at navigation start and ends once the
+    /// runner has become idle after navigating to the given URL.
+    PageLoad { url: String },
+}
+
+/// How the runner should proxy Firefox's network traffic.
+#[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub enum ProxyMode {
+    /// Record all traffic into the named archive on the runner.
+    Record { archive: String },
+
+    /// Replay traffic from the named archive on the runner.
+    Replay { archive: String },
+}
+
+impl ProxyMode {
+    /// Return the name of the archive.
+    pub fn archive(&self) -> &str {
+        match self {
+            ProxyMode::Record { archive } | ProxyMode::Replay { archive } => archive,
+        }
+    }
+}
+
+/// Metadata identifying a build of Firefox.
+#[derive(Clone, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub struct BuildMetadata {
+    /// The version of Firefox.
+    pub version: String,
+
+    /// The build ID.
+    pub build_id: String,
+
+    /// The repository the build was built from, if known.
+    pub source_repository: Option<String>,
+
+    /// The revision the build was built from, if known.
+    pub source_stamp: Option<String>,
+}
+
+/// Network conditions to emulate while running Firefox.
+#[derive(Clone, Copy, Debug, Eq, Deserialize, PartialEq, Serialize)]
+pub struct NetworkConditions {
+    /// The download bandwidth in kilobits per second, or zero for unlimited.
+    pub download_kbps: u32,
+
+    /// The upload bandwidth in kilobits per second, or zero for unlimited.
+    pub upload_kbps: u32,
+
+    /// The round trip latency in milliseconds.
+    pub latency_ms: u32,
+}
+
+/// Options controlling how the runner runs Firefox.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RunOptions {
+    /// The type of measurement to perform.
+    pub session_type: SessionType,
+
+    /// How to proxy Firefox's network traffic, if at all.
+    pub proxy: Option<ProxyMode>,
+
+    /// Host names that should resolve to the given addresses for the duration
+    /// of the session.
+    pub host_overrides: Vec<(String, IpAddr)>,
+
+    /// The network conditions to emulate, if any.
+    pub network: Option<NetworkConditions>,
+
+    /// Whether or not the runner should send the profile back to the recorder
+    /// once Firefox has stopped.
+    pub return_profile: bool,
+
+    /// Environment variables to set for Firefox.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+
+    /// Whether or not the runner should upload large artifacts, such as the
+    /// returned profile, to its artifact store instead of sending them to
+    /// the recorder.
+    ///
+    /// Artifacts are sent to the recorder as usual if the runner has no
+    /// artifact store or cannot upload to it.
+    #[serde(default)]
+    pub upload_artifacts: bool,
+
+    /// Whether or not the runner should capture the telemetry pings Firefox
+    /// submits during the session and send them to the recorder.
+    #[serde(default)]
+    pub capture_pings: bool,
+
+    /// Whether or not the runner should collect a memory report from Firefox
+    /// once startup has settled and send it to the recorder.
+    ///
+    /// The report is collected over Marionette after the recording has
+    /// finished, so Firefox runs with Marionette enabled.
+    #[serde(default)]
+    pub memory_report: bool,
+
+    /// The timezone to set on the runner for the duration of the session, if
+    /// any.
+    ///
+    /// This is a Windows time zone ID, e.g., `UTC` or `Pacific Standard Time`.
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    /// Whether or not the runner should synchronize its clock with its time
+    /// server before the session.
+    #[serde(default)]
+    pub sync_clock: bool,
+
+    /// Whether or not Firefox should be started in safe mode, which disables
+    /// extensions, themes, and hardware acceleration.
+    #[serde(default)]
+    pub safe_mode: bool,
+
+    /// Whether or not the session is measured without video capture.
+    ///
+    /// The runner waits for Firefox to finish starting up before reporting
+    /// that it has started, so that the recorder can stop Firefox without
+    /// waiting out a recording. Startup is measured by the markers in
+    /// Firefox's startup telemetry instead.
+    #[serde(default)]
+    pub headless_measurement: bool,
+
+    /// How many more times the runner should launch Firefox after its first
+    /// launch.
+    ///
+    /// Every launch uses the same extracted build and is measured in turn,
+    /// without the runner restarting in between.
+    #[serde(default)]
+    pub relaunches: u32,
+
+    /// Whether or not the runner should reset the profile to its state before
+    /// the first launch before each relaunch, instead of reusing it.
+    #[serde(default)]
+    pub reset_profile: bool,
+
+    /// How long the runner should wait between launches of Firefox, so that
+    /// one launch does not interfere with the next.
+    #[serde(default)]
+    pub cool_down: Duration,
+
+    /// Whether or not the runner should also wait for the disk to become idle
+    /// between launches of Firefox, e.g., for Firefox's delayed writes and
+    /// the search indexer to finish.
+    #[serde(default)]
+    pub cool_down_disk_idle: bool,
+
+    /// Whether or not the runner should restart after the session finishes,
+    /// keeping the session so that it can be resumed again after a cold start.
+    #[serde(default)]
+    pub restart_after: bool,
+}
+
+impl RunOptions {
+    /// Whether or not the runner cools down between launches of Firefox.
+    pub fn cools_down(&self) -> bool {
+        self.cool_down > Duration::from_secs(0) || self.cool_down_disk_idle
+    }
+}
+
+/// An artifact of a session that the runner uploaded to its artifact store.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UploadedArtifact {
+    /// The name of the artifact, e.g., `profile.zip`.
+    pub name: String,
+
+    /// The key of the object in the bucket.
+    pub key: String,
+
+    /// The location of the object, e.g., `s3://bucket/key`.
+    pub url: String,
+
+    /// The size of the artifact, in bytes.
+    pub size: u64,
+}
+
+/// The telemetry pings captured during a session.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CapturedPings {
+    /// The number of pings captured.
+    pub count: u64,
+
+    /// The size of the zip archive of the pings, in bytes.
+    pub size: u64,
+}
+
+/// Statistics about Firefox's startup cache during a session.
+///
+/// The startup cache stores compiled scripts and other resources used during
+/// startup, so whether or not it was populated explains much of the
+/// difference between cold and warm starts.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StartupCacheStats {
+    /// The size of the startup cache in the profile before Firefox started, in
+    /// bytes.
+    ///
+    /// This is zero if the profile had no startup cache.
+    pub size_before: u64,
+
+    /// The size of the startup cache in the profile after Firefox stopped, in
+    /// bytes.
+    pub size_after: u64,
+
+    /// The requests made to the startup cache, if Firefox's telemetry recorded
+    /// them.
+    pub requests: Option<StartupCacheRequests>,
+}
+
+/// The requests made to Firefox's startup cache, as recorded by the
+/// `STARTUP_CACHE_REQUESTS` histogram.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StartupCacheRequests {
+    /// The number of requests served from memory.
+    pub hit_memory: u64,
+
+    /// The number of requests served from disk.
+    pub hit_disk: u64,
+
+    /// The number of requests that missed the cache.
+    pub miss: u64,
+}
+
+/// The graphics hardware Firefox ran on and how it used it, as recorded in
+/// the telemetry environment.
+///
+/// Graphics initialization dominates some startup profiles, so this explains
+/// differences between runners and between builds.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GraphicsInfo {
+    /// The description of the active graphics adapter.
+    pub adapter: Option<String>,
+
+    /// The vendor ID of the active graphics adapter, e.g., `0x10de`.
+    pub vendor_id: Option<String>,
+
+    /// The device ID of the active graphics adapter.
+    pub device_id: Option<String>,
+
+    /// The version of the active graphics adapter's driver.
+    pub driver_version: Option<String>,
+
+    /// The compositor Firefox used, e.g., `webrender`, `d3d11`, or `basic`.
+    pub compositor: Option<String>,
+
+    /// Whether or not compositing was hardware accelerated.
+    pub hardware_acceleration: Option<bool>,
+
+    /// Whether or not WebRender was used.
+    pub webrender: Option<bool>,
 }
 
 /// A request to resume an existing session.
@@ -100,27 +559,226 @@ pub struct ResumeSessionRequest {
 
     /// Whether or not the runner should wait for idle before running Firefox.
     pub idle: Idle,
+
+    /// How the runner should run Firefox.
+    pub run_options: RunOptions,
+
+    /// The codecs the recorder supports for the returned profile, most
+    /// preferred first.
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
 }
 
-#[derive(Debug, Display, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Display, Eq, PartialEq, Serialize, Deserialize)]
 pub enum DownloadStatus {
     Downloading,
+
+    /// Progress of an ongoing download.
+    ///
+    /// This is only sent while downloading a build.
+    Progress(DownloadProgress),
+
     Downloaded,
+
+    /// Progress of an ongoing extraction.
+    ///
+    /// This is only sent while extracting a build.
+    Extracting(ExtractProgress),
+
     Extracted,
 }
 
+/// The progress of a download.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct DownloadProgress {
+    /// The number of bytes downloaded so far.
+    pub downloaded: u64,
+
+    /// The total size of the download, if known.
+    pub total: Option<u64>,
+}
+
+impl Display for DownloadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.total {
+            Some(total) if total > 0 => write!(
+                f,
+                "{} of {} bytes ({}%)",
+                self.downloaded,
+                total,
+                self.downloaded * 100 / total
+            ),
+            _ => write!(f, "{} bytes", self.downloaded),
+        }
+    }
+}
+
+/// The progress of an extraction.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExtractProgress {
+    /// The number of files extracted so far.
+    pub extracted: u64,
+
+    /// The total number of files in the archive, if known.
+    pub total: Option<u64>,
+}
+
+impl Display for ExtractProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.total {
+            Some(total) if total > 0 => write!(
+                f,
+                "{} of {} files ({}%)",
+                self.extracted,
+                total,
+                self.extracted * 100 / total
+            ),
+            _ => write!(f, "{} files", self.extracted),
+        }
+    }
+}
+
+/// The details of a restart scheduled by the runner.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RestartInfo {
+    /// The delay before the runner restarts.
+    pub delay: Duration,
+
+    /// How the runner ensured that the restart is a cold start.
+    pub fast_startup: FastStartup,
+}
+
+/// The state of Windows Fast Startup for a restart.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FastStartup {
+    /// Fast Startup was not enabled.
+    #[display(fmt = "not enabled")]
+    NotEnabled,
+
+    /// Fast Startup was disabled for the restart.
+    ///
+    /// It is re-enabled when the session is resumed or cancelled.
+    #[display(fmt = "disabled for restart")]
+    Disabled,
+}
+
+/// How long the runner took to restart before a session was resumed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BootTimings {
+    /// From the runner initiating its restart to the runner starting again,
+    /// if the runner recorded the restart.
+    pub restart: Option<Duration>,
+
+    /// From the machine booting to its desktop becoming interactive.
+    pub boot_to_desktop: Duration,
+}
+
+/// The bytes a runner transferred for a session.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthUsage {
+    /// The number of bytes of builds downloaded, e.g., from Taskcluster.
+    ///
+    /// Builds already on the runner or uploaded by the recorder are not
+    /// counted.
+    pub downloaded: u64,
+
+    /// The number of bytes sent to the recorder.
+    pub sent: u64,
+
+    /// The number of bytes received from the recorder.
+    pub received: u64,
+}
+
+impl Add for BandwidthUsage {
+    type Output = BandwidthUsage;
+
+    fn add(self, other: BandwidthUsage) -> BandwidthUsage {
+        BandwidthUsage {
+            downloaded: self.downloaded + other.downloaded,
+            sent: self.sent + other.sent,
+            received: self.received + other.received,
+        }
+    }
+}
+
+/// A restart initiated by the runner.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RestartRecord {
+    /// When the restart was initiated.
+    pub timestamp: DateTime<Utc>,
+
+    /// The reason given for the restart.
+    pub reason: String,
+
+    /// The ID of the session that the restart was for.
+    pub session_id: String,
+
+    /// What became of the restart.
+    pub result: RestartResult,
+}
+
+impl RestartRecord {
+    /// Create a record of a restart initiated now.
+    pub fn new(reason: &str, session_id: &str, result: RestartResult) -> Self {
+        RestartRecord {
+            timestamp: Utc::now(),
+            reason: reason.into(),
+            session_id: session_id.into(),
+            result,
+        }
+    }
+}
+
+/// The state of the runner, sent in response to a
+/// [`Ping`](enum.Session.html#variant.Ping).
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunnerStatus {
+    /// The version of the runner.
+    pub version: String,
+
+    /// Whether or not the runner is serving another request.
+    pub busy: bool,
+
+    /// The most recent restarts initiated by the runner, oldest first.
+    pub restarts: Vec<RestartRecord>,
+}
+
+/// What became of a restart initiated by the runner.
+#[derive(Clone, Debug, Display, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RestartResult {
+    /// The restart was scheduled.
+    #[display(fmt = "scheduled")]
+    Scheduled,
+
+    /// The restart was scheduled and then cancelled by the recorder.
+    #[display(fmt = "cancelled")]
+    Cancelled,
+
+    /// The restart could not be scheduled.
+    #[display(fmt = "failed: {}", _0)]
+    Failed(String),
+}
+
 impl DownloadStatus {
     /// Return the next expected state, if any.
     pub fn next(&self) -> Option<DownloadStatus> {
         match self {
-            DownloadStatus::Downloading => Some(DownloadStatus::Downloaded),
-            DownloadStatus::Downloaded => Some(DownloadStatus::Extracted),
+            DownloadStatus::Downloading | DownloadStatus::Progress(..) => {
+                Some(DownloadStatus::Downloaded)
+            }
+            DownloadStatus::Downloaded | DownloadStatus::Extracting(..) => {
+                Some(DownloadStatus::Extracted)
+            }
             DownloadStatus::Extracted => None,
         }
     }
 }
 
-pub type ForeignResult<T> = Result<T, ErrorMessage<String>>;
+/// The hex-encoded SHA-256 hashes of the files in a profile, keyed by their
+/// `/`-separated paths relative to the profile directory.
+pub type ProfileHashes = BTreeMap<String, String>;
+
+pub type ForeignResult<T> = Result<T, ForeignError>;
 
 message_type! {
     /// A message from FxRecorder to FxRunner.
@@ -141,6 +799,12 @@ message_type! {
         /// A request to resume a [previous
         /// request](enum.RecorderSession.html#variant.NewSession).
         ResumeSession(ResumeSessionRequest),
+
+        /// A request for the runner's [status](struct.Status.html).
+        ///
+        /// No session is started. The runner answers even while it is serving
+        /// another recorder.
+        Ping,
     }
 
     /// Request the runner start Firefox.
@@ -148,10 +812,42 @@ message_type! {
     /// Sent once the recorder has started ffmpeg.
     pub struct StartFirefox;
 
+    /// Request the runner navigate Firefox to the page being measured.
+    ///
+    /// Only sent for [page load](enum.SessionType.html#variant.PageLoad)
+    /// sessions, once the recorder has started recording.
+    pub struct Navigate;
+
     /// Request the runner to stop Firefox.
     ///
     /// Send once the recorder has finished recording.
     pub struct StopFirefox;
+
+    /// Request the runner cancel a new session instead of restarting.
+    ///
+    /// Only sent after the runner has scheduled its
+    /// [restart](struct.Restarting.html) and before the delay has elapsed.
+    pub struct CancelSession;
+
+    /// The changes to the profile cached on the runner.
+    ///
+    /// Sent in response to a [`ProfileManifest`](struct.ProfileManifest.html).
+    /// The changed files are sent as a zip archive of the given size after the
+    /// runner reports it is [receiving the profile](struct.RecvProfile.html).
+    pub struct ProfileDelta {
+        /// The size of the zip archive of changed files.
+        pub size: u64,
+
+        /// The paths of cached files that are no longer in the profile.
+        pub removed: Vec<String>,
+    }
+
+    /// The recorder is ready to receive a file from the runner.
+    ///
+    /// Sent in response to a [`TelemetryPings`](struct.TelemetryPings.html),
+    /// [`MemoryReport`](struct.MemoryReport.html), or
+    /// [`ReturnProfile`](struct.ReturnProfile.html) that was not uploaded. The runner sends the file once it receives this.
+    pub struct RecvReturned;
 }
 
 message_type! {
@@ -161,11 +857,31 @@ message_type! {
     /// The kind of a [`RunnerMessage`](struct.RunnerMessage.html).
     RunnerMessageKind;
 
+    /// The result of resolving the build task to a task ID.
+    ///
+    /// This is only sent for builds from Taskcluster.
+    pub struct ResolveTask {
+        pub result: ForeignResult<String>,
+    }
+
     /// The status of the DownloadBuild phase.
     pub struct DownloadBuild {
         pub result: ForeignResult<DownloadStatus>,
     }
 
+    /// The runner is ready to receive the distribution.
+    ///
+    /// Only sent when the recorder is sending a
+    /// [distribution](struct.NewSessionRequest.html#structfield.distribution_size).
+    /// The recorder sends the zip archive of the distribution once it receives
+    /// this.
+    pub struct RecvDistribution;
+
+    /// The status of the InstallDistribution phase.
+    pub struct InstalledDistribution {
+        pub result: ForeignResult<()>,
+    }
+
     /// The status of the disable updates phase.
     pub struct DisableUpdates {
         pub result: ForeignResult<()>,
@@ -176,6 +892,15 @@ message_type! {
         pub result: ForeignResult<DownloadStatus>,
     }
 
+    /// The files in the profile cached on the runner.
+    ///
+    /// Only sent when the recorder requested a
+    /// [profile delta](struct.NewSessionRequest.html#structfield.profile_delta).
+    /// The manifest is empty if the runner has no cached profile.
+    pub struct ProfileManifest {
+        pub manifest: ProfileHashes,
+    }
+
     /// The result of the CreateProfile phase.
     pub struct CreateProfile {
         pub result: ForeignResult<()>,
@@ -186,21 +911,129 @@ message_type! {
         pub result: ForeignResult<()>,
     }
 
+    /// The status of the InstallLocale phase.
+    ///
+    /// Only sent when a [locale](struct.NewSessionRequest.html#structfield.locale)
+    /// was requested.
+    pub struct InstalledLocale {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The runner is ready to receive the extensions.
+    ///
+    /// Only sent when the recorder is sending
+    /// [extensions](struct.NewSessionRequest.html#structfield.extensions_size).
+    /// The recorder sends the zip archive of extensions once it receives this.
+    pub struct RecvExtensions;
+
+    /// The status of the InstallExtensions phase.
+    ///
+    /// On success, the result contains the IDs of the installed extensions.
+    pub struct InstalledExtensions {
+        pub result: ForeignResult<Vec<String>>,
+    }
+
     /// The status of the Restarting phase.
     pub struct Restarting {
+        /// The details of the restart.
+        ///
+        /// Until the delay has elapsed, the recorder may send a
+        /// [`CancelSession`](struct.CancelSession.html) to abort the restart.
+        pub result: ForeignResult<RestartInfo>,
+    }
+
+    /// The status of the RestartCancelled phase.
+    ///
+    /// Sent in response to a [`CancelSession`](struct.CancelSession.html).
+    pub struct RestartCancelled {
         pub result: ForeignResult<()>,
     }
 
+    /// The response to a [`Ping`](enum.Session.html#variant.Ping).
+    pub struct Status {
+        pub status: RunnerStatus,
+    }
+
     /// The status of the NewSession phase.
     pub struct NewSessionResponse {
         /// The session ID to be given in a
         /// [`ResumeSession`](enum.Session.html#variant.ResumeSession) message.
         pub session_id: ForeignResult<String>,
+
+        /// The codec chosen from those offered in the request.
+        #[serde(default)]
+        pub codec: Codec,
+
+        /// The size of the largest zipped profile or profile delta, in bytes,
+        /// that the runner accepts, if it has a limit.
+        ///
+        /// The recorder should not send a larger profile, which the runner
+        /// would refuse after preparing the build.
+        #[serde(default)]
+        pub max_profile_size: Option<u64>,
     }
 
     /// The status of the ResumeResponse phase.
     pub struct ResumeResponse {
         pub result: ForeignResult<()>,
+
+        /// The codec chosen from those offered in the request.
+        #[serde(default)]
+        pub codec: Codec,
+    }
+
+    /// The metadata of the build being used for the session.
+    ///
+    /// Sent once the session has been resumed.
+    pub struct BuildInfo {
+        pub result: ForeignResult<BuildMetadata>,
+    }
+
+    /// How long the runner took to restart for the session.
+    ///
+    /// Sent once the build has been identified.
+    pub struct BootInfo {
+        pub result: ForeignResult<BootTimings>,
+    }
+
+    /// The status of the NormalizeClock phase.
+    ///
+    /// Only sent when a [timezone](struct.RunOptions.html#structfield.timezone)
+    /// or [clock synchronization](struct.RunOptions.html#structfield.sync_clock)
+    /// was requested.
+    pub struct NormalizedClock {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The status of the OverrideHosts phase.
+    ///
+    /// Only sent when [host overrides](struct.RunOptions.html#structfield.host_overrides)
+    /// were requested.
+    pub struct OverrodeHosts {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The status of the StartProxy phase.
+    ///
+    /// Only sent when a [proxy mode](enum.ProxyMode.html) was requested.
+    pub struct StartedProxy {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The status of the ConditionNetwork phase.
+    ///
+    /// Only sent when [network conditions](struct.NetworkConditions.html)
+    /// were requested.
+    pub struct ConditionedNetwork {
+        pub result: ForeignResult<()>,
+    }
+
+    /// The status of the StartPingCapture phase.
+    ///
+    /// Only sent when the recorder requested
+    /// [pings be captured](struct.RunOptions.html#structfield.capture_pings).
+    pub struct StartedPingCapture {
+        pub result: ForeignResult<()>,
     }
 
     /// The status of the WaitForIdle phase.
@@ -213,13 +1046,111 @@ message_type! {
         pub result: ForeignResult<()>,
     }
 
+    /// The status of the Navigate phase.
+    ///
+    /// Sent once the page has loaded and the runner has become idle.
+    pub struct Navigated {
+        pub result: ForeignResult<()>,
+    }
+
     /// The status of the StopFirefox phase.
     pub struct StoppedFirefox {
-        pub result: Result<(), Vec<ErrorMessage<String>>>,
+        pub result: Result<(), Vec<ForeignError>>,
+    }
+
+    /// Startup metrics extracted from the telemetry Firefox saved in the
+    /// profile.
+    ///
+    /// Sent once Firefox has stopped.
+    pub struct StartupTelemetry {
+        pub result: ForeignResult<BTreeMap<String, u64>>,
+
+        /// Statistics about the startup cache, if the runner could collect
+        /// them.
+        #[serde(default)]
+        pub startup_cache: Option<StartupCacheStats>,
+
+        /// The graphics hardware and configuration Firefox used, if the
+        /// runner could find them.
+        #[serde(default)]
+        pub graphics: Option<GraphicsInfo>,
+    }
+
+    /// The runner finished cooling down after a launch of Firefox.
+    ///
+    /// Only sent between launches when the recorder requested a
+    /// [cool-down](struct.RunOptions.html#structfield.cool_down).
+    pub struct CooledDown;
+
+    /// The telemetry pings Firefox submitted during the session.
+    ///
+    /// Only sent when the recorder requested
+    /// [pings be captured](struct.RunOptions.html#structfield.capture_pings).
+    /// On success, the pings are sent as a zip archive of the given size once
+    /// the recorder is [ready to receive](struct.RecvReturned.html) it, unless
+    /// it was uploaded.
+    pub struct TelemetryPings {
+        pub result: ForeignResult<CapturedPings>,
+
+        /// Where the pings were uploaded to, if the recorder requested
+        /// [artifacts be uploaded](struct.RunOptions.html#structfield.upload_artifacts).
+        ///
+        /// Nothing follows this message when the pings were uploaded.
+        #[serde(default)]
+        pub uploaded: Option<UploadedArtifact>,
+    }
+
+    /// The memory report Firefox wrote once startup settled.
+    ///
+    /// Only sent when the recorder requested a
+    /// [memory report](struct.RunOptions.html#structfield.memory_report).
+    /// On success, the result contains the size of the gzipped report, which
+    /// is sent once the recorder is [ready to receive](struct.RecvReturned.html)
+    /// it, unless it was uploaded.
+    pub struct MemoryReport {
+        pub result: ForeignResult<u64>,
+
+        /// Where the report was uploaded to, if the recorder requested
+        /// [artifacts be uploaded](struct.RunOptions.html#structfield.upload_artifacts).
+        ///
+        /// Nothing follows this message when the report was uploaded.
+        #[serde(default)]
+        pub uploaded: Option<UploadedArtifact>,
+    }
+
+    /// The status of the ReturnProfile phase.
+    ///
+    /// Only sent when the recorder requested the
+    /// [profile be returned](struct.RunOptions.html#structfield.return_profile).
+    /// On success, the result contains the size of the zipped profile, which
+    /// is sent once the recorder is [ready to receive](struct.RecvReturned.html)
+    /// it, unless it was uploaded.
+    pub struct ReturnProfile {
+        pub result: ForeignResult<u64>,
+
+        /// Where the profile was uploaded to, if the recorder requested
+        /// [artifacts be uploaded](struct.RunOptions.html#structfield.upload_artifacts).
+        ///
+        /// Nothing follows this message when the profile was uploaded.
+        #[serde(default)]
+        pub uploaded: Option<UploadedArtifact>,
+    }
+
+    /// The runner's log of the session.
+    ///
+    /// Sent once Firefox has stopped, so that the recorder can store the log
+    /// with the session's results.
+    pub struct SessionLog {
+        pub result: ForeignResult<String>,
     }
 
     /// The status of any cleanup or teardown before the session finishes.
     pub struct SessionFinished {
         pub result: ForeignResult<()>,
+
+        /// The bytes the runner transferred for the session so far, across
+        /// every restart, if it could determine them.
+        #[serde(default)]
+        pub bandwidth: Option<BandwidthUsage>,
     }
 }
@@ -7,48 +7,48 @@ use std::io;
 
 use futures::prelude::*;
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_serde::formats::Json;
 use tokio_util::codec::LengthDelimitedCodec;
 
-use crate::error::ErrorMessage;
+use crate::error::ForeignError;
 use crate::net::message::{KindMismatch, Message, MessageContent};
 
 /// A protocol for receiving messages of type `R` and sending messages of type
-/// `S` over a `TcpStream`.
+/// `S` over a stream of type `T`, which is a `TcpStream` unless otherwise
+/// specified.
 ///
 /// Messages are JSON-encoded and prefixed with their length before transmission.
 ///
 /// Here `RK` and `SK` are the kinds of the message types `R` and `S`
 /// respectively, as per the [`Message`](trait.Message.html#associatedtype.Kind) trait.
-pub struct Proto<R, S, RK, SK>
+pub struct Proto<R, S, RK, SK, T = TcpStream>
 where
     for<'de> R: Message<'de, Kind = RK>,
     for<'de> S: Message<'de, Kind = SK>,
     RK: Debug + Display + Eq + PartialEq,
     SK: Debug + Display + Eq + PartialEq,
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    stream: tokio_serde::Framed<
-        tokio_util::codec::Framed<TcpStream, LengthDelimitedCodec>,
-        R,
-        S,
-        Json<R, S>,
-    >,
+    stream:
+        tokio_serde::Framed<tokio_util::codec::Framed<T, LengthDelimitedCodec>, R, S, Json<R, S>>,
 
     // We need to include `RK` and `SK ` in the type signature for this struct
     // to get around limitations with HKT.
     _marker: std::marker::PhantomData<(RK, SK)>,
 }
 
-impl<R, S, RK, SK> Proto<R, S, RK, SK>
+impl<R, S, RK, SK, T> Proto<R, S, RK, SK, T>
 where
     for<'de> R: Message<'de, Kind = RK>,
     for<'de> S: Message<'de, Kind = SK>,
     RK: Debug + Display + Eq + PartialEq,
     SK: Debug + Display + Eq + PartialEq,
+    T: AsyncRead + AsyncWrite + Unpin,
 {
     /// Wrap the stream for communicating via messages.
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: T) -> Self {
         Self {
             stream: tokio_serde::Framed::new(
                 tokio_util::codec::Framed::new(stream, LengthDelimitedCodec::new()),
@@ -92,8 +92,13 @@ where
         Ok(M::try_from(msg).expect("M::kind() and msg.kind() are equal"))
     }
 
+    /// Return a reference to the underlying stream.
+    pub fn get_ref(&self) -> &T {
+        self.stream.get_ref().get_ref()
+    }
+
     /// Consume the `Proto`, returning the underlying stream.
-    pub fn into_inner(self) -> TcpStream {
+    pub fn into_inner(self) -> T {
         self.stream.into_inner().into_inner()
     }
 }
@@ -107,10 +112,10 @@ pub enum ProtoError<K: Debug + Display> {
 
     /// An error occurred on the remote side of the protocol.
     ///
-    /// Due to the error being serialized across the protocol, the underlying
-    /// error cannot have a cause.
-    #[error("a remote error occurred: {}", .0)]
-    Foreign(#[from] ErrorMessage<String>),
+    /// The error is displayed along with the chain of errors that caused it
+    /// on the remote side.
+    #[error("a remote error occurred: {}", .0.chain())]
+    Foreign(#[from] ForeignError),
 
     /// The stream was closed unexpectedly.
     #[error("unexpected end of stream")]
@@ -55,6 +55,30 @@ impl From<PrefValue> for Value {
     }
 }
 
+impl From<bool> for PrefValue {
+    fn from(b: bool) -> PrefValue {
+        PrefValue(Value::Bool(b))
+    }
+}
+
+impl From<i64> for PrefValue {
+    fn from(n: i64) -> PrefValue {
+        PrefValue(Value::Number(n.into()))
+    }
+}
+
+impl From<&str> for PrefValue {
+    fn from(s: &str) -> PrefValue {
+        PrefValue(Value::String(s.into()))
+    }
+}
+
+impl From<String> for PrefValue {
+    fn from(s: String) -> PrefValue {
+        PrefValue(Value::String(s))
+    }
+}
+
 /// Write all the prefs from the iterator into the `w`.
 pub async fn write_prefs<W, P>(w: &mut W, prefs: P) -> Result<(), io::Error>
 where
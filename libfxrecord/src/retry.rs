@@ -0,0 +1,576 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use slog::{warn, Logger};
+use thiserror::Error;
+use tokio::time::delay_for;
+
+/// Randomization applied to the delays between attempts.
+///
+/// Without jitter, clients that fail at the same time will also retry at the
+/// same time. See
+/// https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+/// for a comparison of the strategies.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Jitter {
+    /// Each delay is exactly twice the previous delay.
+    #[default]
+    None,
+
+    /// Each delay is chosen uniformly between zero and the delay that would
+    /// have been used without jitter.
+    Full,
+
+    /// Each delay is chosen uniformly between the initial delay and three
+    /// times the previous delay.
+    Decorrelated,
+}
+
+/// The delays between attempts for exponential backoff.
+#[derive(Debug)]
+struct Backoff {
+    /// The initial delay.
+    base: Duration,
+
+    /// The next delay without jitter.
+    exponential: Duration,
+
+    /// The previous delay, after jitter.
+    previous: Duration,
+
+    jitter: Jitter,
+}
+
+impl Backoff {
+    fn new(base: Duration, jitter: Jitter) -> Self {
+        Backoff {
+            base,
+            exponential: base,
+            previous: base,
+            jitter,
+        }
+    }
+
+    /// Return the next delay.
+    fn next_delay<R: Rng>(&mut self, rng: &mut R) -> Duration {
+        let delay = match self.jitter {
+            Jitter::None => self.exponential,
+            Jitter::Full => random_between(rng, Duration::from_secs(0), self.exponential),
+            Jitter::Decorrelated => random_between(rng, self.base, self.previous * 3),
+        };
+
+        self.exponential *= 2;
+        self.previous = delay;
+
+        delay
+    }
+}
+
+/// Return a duration chosen uniformly from the inclusive range `[low, high]`.
+fn random_between<R: Rng>(rng: &mut R, low: Duration, high: Duration) -> Duration {
+    if high <= low {
+        return low;
+    }
+
+    let nanos = rng.gen_range(0, (high - low).as_nanos() as u64 + 1);
+    low + Duration::from_nanos(nanos)
+}
+
+#[derive(Debug, Error)]
+#[error("failed after {} retries", retries)]
+/// An error that occurred when retrying a fallable operation.
+pub struct RetryError<E: Error + 'static> {
+    /// The last error that occurred.
+    source: E,
+
+    /// The number of retries.
+    retries: u32,
+}
+
+impl<E: Error + 'static> RetryError<E> {
+    /// Return the last error that occurred.
+    pub fn into_source(self) -> E {
+        self.source
+    }
+}
+
+/// A policy that decides whether and when a failed operation is retried.
+pub trait RetryPolicy<E> {
+    /// Return the delay before the first attempt, if any.
+    fn initial_delay(&mut self) -> Option<Duration> {
+        None
+    }
+
+    /// Return the delay before the next attempt, or `None` to give up.
+    ///
+    /// `attempts` is the number of attempts made so far and `elapsed` is the
+    /// time since the first attempt started.
+    fn next_delay(&mut self, error: &E, attempts: u32, elapsed: Duration) -> Option<Duration>;
+
+    /// Call `hook` after every failed attempt.
+    fn on_retry<H>(self, hook: H) -> OnRetry<Self, H>
+    where
+        Self: Sized,
+        H: FnMut(&RetryAttempt<'_, E>),
+    {
+        OnRetry { policy: self, hook }
+    }
+}
+
+/// A failed attempt, as reported to a retry hook.
+#[derive(Debug)]
+pub struct RetryAttempt<'a, E> {
+    /// The number of attempts made so far.
+    pub attempt: u32,
+
+    /// The delay before the next attempt, or `None` if the operation will not
+    /// be retried.
+    pub delay: Option<Duration>,
+
+    /// The error from the failed attempt.
+    pub error: &'a E,
+}
+
+/// A retry policy that calls a hook after every failed attempt.
+///
+/// See [`RetryPolicy::on_retry`](trait.RetryPolicy.html#method.on_retry).
+#[derive(Debug)]
+pub struct OnRetry<R, H> {
+    policy: R,
+    hook: H,
+}
+
+impl<E, R, H> RetryPolicy<E> for OnRetry<R, H>
+where
+    R: RetryPolicy<E>,
+    H: FnMut(&RetryAttempt<'_, E>),
+{
+    fn initial_delay(&mut self) -> Option<Duration> {
+        self.policy.initial_delay()
+    }
+
+    fn next_delay(&mut self, error: &E, attempts: u32, elapsed: Duration) -> Option<Duration> {
+        let delay = self.policy.next_delay(error, attempts, elapsed);
+
+        (self.hook)(&RetryAttempt {
+            attempt: attempts,
+            delay,
+            error,
+        });
+
+        delay
+    }
+}
+
+/// Return a retry hook that logs every failed attempt.
+pub fn log_retries<E: Display>(log: Logger) -> impl FnMut(&RetryAttempt<'_, E>) {
+    move |attempt| match attempt.delay {
+        Some(delay) => warn!(
+            log,
+            "Attempt failed; retrying";
+            "attempt" => attempt.attempt,
+            "delay_ms" => delay.as_millis() as u64,
+            "error" => %attempt.error,
+        ),
+        None => warn!(
+            log,
+            "Attempt failed; giving up";
+            "attempt" => attempt.attempt,
+            "error" => %attempt.error,
+        ),
+    }
+}
+
+/// A predicate that decides whether an error is worth retrying.
+pub trait RetryPredicate<E> {
+    fn should_retry(&self, error: &E) -> bool;
+}
+
+impl<E, F> RetryPredicate<E> for F
+where
+    F: Fn(&E) -> bool,
+{
+    fn should_retry(&self, error: &E) -> bool {
+        self(error)
+    }
+}
+
+/// A [`RetryPredicate`](trait.RetryPredicate.html) that retries every error.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AlwaysRetry;
+
+impl<E> RetryPredicate<E> for AlwaysRetry {
+    fn should_retry(&self, _: &E) -> bool {
+        true
+    }
+}
+
+/// A retry policy using exponential backoff.
+///
+/// By default, every error is retried, there is no limit on the elapsed time,
+/// and there is no delay before the first attempt.
+#[derive(Debug)]
+pub struct ExponentialBackoff<P = AlwaysRetry> {
+    backoff: Backoff,
+
+    /// The maximum number of attempts.
+    max_attempts: u32,
+
+    /// The maximum time to spend retrying, if any.
+    max_elapsed: Option<Duration>,
+
+    /// The maximum delay between attempts, if any.
+    max_delay: Option<Duration>,
+
+    /// Whether or not to delay before the first attempt.
+    delay_first: bool,
+
+    should_retry: P,
+}
+
+impl ExponentialBackoff {
+    /// Create a policy that makes at most `max_attempts` attempts, waiting
+    /// `wait` after the first failed attempt and twice as long after each
+    /// subsequent failed attempt.
+    pub fn new(wait: Duration, max_attempts: u32) -> Self {
+        assert!(max_attempts > 0);
+
+        ExponentialBackoff {
+            backoff: Backoff::new(wait, Jitter::None),
+            max_attempts,
+            max_elapsed: None,
+            max_delay: None,
+            delay_first: false,
+            should_retry: AlwaysRetry,
+        }
+    }
+}
+
+impl<P> ExponentialBackoff<P> {
+    /// Randomize the delays between attempts.
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.backoff.jitter = jitter;
+        self
+    }
+
+    /// Give up once the next attempt would start after `max_elapsed` has
+    /// passed since the first attempt.
+    pub fn max_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.max_elapsed = Some(max_elapsed);
+        self
+    }
+
+    /// Never wait longer than `max_delay` between attempts.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Delay before the first attempt as well as between attempts.
+    pub fn delay_first(mut self) -> Self {
+        self.delay_first = true;
+        self
+    }
+
+    /// Only retry errors for which the predicate returns true.
+    pub fn retry_if<Q>(self, should_retry: Q) -> ExponentialBackoff<Q> {
+        ExponentialBackoff {
+            backoff: self.backoff,
+            max_attempts: self.max_attempts,
+            max_elapsed: self.max_elapsed,
+            max_delay: self.max_delay,
+            delay_first: self.delay_first,
+            should_retry,
+        }
+    }
+
+    /// Return the next delay, no longer than the maximum delay.
+    fn next_backoff(&mut self) -> Duration {
+        let delay = self.backoff.next_delay(&mut thread_rng());
+
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
+        }
+    }
+}
+
+impl<E, P> RetryPolicy<E> for ExponentialBackoff<P>
+where
+    P: RetryPredicate<E>,
+{
+    fn initial_delay(&mut self) -> Option<Duration> {
+        if self.delay_first {
+            Some(self.next_backoff())
+        } else {
+            None
+        }
+    }
+
+    fn next_delay(&mut self, error: &E, attempts: u32, elapsed: Duration) -> Option<Duration> {
+        if attempts >= self.max_attempts || !self.should_retry.should_retry(error) {
+            return None;
+        }
+
+        let delay = self.next_backoff();
+
+        match self.max_elapsed {
+            Some(max_elapsed) if elapsed + delay > max_elapsed => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Attempt to resolve the future returned by the given function `retries` times
+/// using exponential backoff before the first attempt and between subsequent
+/// attempts.
+pub async fn delayed_exponential_retry<F, Fut, T, E>(
+    f: F,
+    wait: Duration,
+    retries: u32,
+) -> Result<T, RetryError<E>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Error + 'static,
+{
+    retry(ExponentialBackoff::new(wait, retries).delay_first(), f).await
+}
+
+/// Attempt to resolve the future returned by the given function up to
+/// `attempts` times using exponential backoff between attempts.
+///
+/// Errors for which `should_retry` returns false are not retried.
+pub async fn exponential_retry<F, Fut, T, E, P>(
+    f: F,
+    should_retry: P,
+    wait: Duration,
+    attempts: u32,
+) -> Result<T, RetryError<E>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Error + 'static,
+    P: Fn(&E) -> bool,
+{
+    retry(
+        ExponentialBackoff::new(wait, attempts).retry_if(should_retry),
+        f,
+    )
+    .await
+}
+
+/// Attempt to resolve the future returned by the given function, retrying
+/// failed attempts according to the given policy.
+pub async fn retry<R, F, Fut, T, E>(mut policy: R, f: F) -> Result<T, RetryError<E>>
+where
+    R: RetryPolicy<E>,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Error + 'static,
+{
+    if let Some(delay) = policy.initial_delay() {
+        delay_for(delay).await;
+    }
+
+    let start = Instant::now();
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match f().await {
+            Ok(r) => return Ok(r),
+            Err(e) => match policy.next_delay(&e, attempt, start.elapsed()) {
+                Some(delay) => delay_for(delay).await,
+                None => {
+                    return Err(RetryError {
+                        source: e,
+                        retries: attempt,
+                    })
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let base = Duration::from_secs(1);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut backoff = Backoff::new(base, Jitter::None);
+        let delays = (0..4)
+            .map(|_| backoff.next_delay(&mut rng))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8]
+                .iter()
+                .map(|&s| Duration::from_secs(s))
+                .collect::<Vec<_>>()
+        );
+
+        for _ in 0..100 {
+            let mut backoff = Backoff::new(base, Jitter::Full);
+            for i in 0..8 {
+                let delay = backoff.next_delay(&mut rng);
+                assert!(delay <= base * 2u32.pow(i));
+            }
+        }
+
+        for _ in 0..100 {
+            let mut backoff = Backoff::new(base, Jitter::Decorrelated);
+            let mut previous = base;
+            for _ in 0..8 {
+                let delay = backoff.next_delay(&mut rng);
+                assert!(delay >= base);
+                assert!(delay <= previous * 3);
+                previous = delay;
+            }
+        }
+
+        // The jittered delays are actually spread out.
+        let mut backoff = Backoff::new(base, Jitter::Full);
+        let first = backoff.next_delay(&mut rng);
+        assert!((0..10).any(|_| Backoff::new(base, Jitter::Full).next_delay(&mut rng) != first));
+    }
+
+    #[test]
+    fn test_random_between() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let low = Duration::from_millis(10);
+        let high = Duration::from_millis(20);
+
+        for _ in 0..1000 {
+            let d = random_between(&mut rng, low, high);
+            assert!(d >= low && d <= high);
+        }
+
+        assert_eq!(random_between(&mut rng, high, low), high);
+        assert_eq!(random_between(&mut rng, low, low), low);
+    }
+
+    #[tokio::test]
+    async fn test_exponential_retry() {
+        let calls = Cell::new(0);
+        let f = || {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move {
+                if n < 3 {
+                    Err(io::Error::other("transient"))
+                } else {
+                    Ok(n)
+                }
+            }
+        };
+
+        assert_eq!(
+            exponential_retry(f, |_| true, Duration::from_millis(1), 5)
+                .await
+                .unwrap(),
+            3
+        );
+
+        calls.set(0);
+        let e = exponential_retry(f, |_| true, Duration::from_millis(1), 2)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "failed after 2 retries");
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        let e = exponential_retry(f, |_| false, Duration::from_millis(1), 5)
+            .await
+            .unwrap_err();
+        assert_eq!(e.into_source().to_string(), "transient");
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_on_retry() {
+        let calls = Cell::new(0);
+        let f = || {
+            calls.set(calls.get() + 1);
+            async { Err::<(), _>(io::Error::other("transient")) }
+        };
+
+        let mut attempts = Vec::new();
+        let policy = ExponentialBackoff::new(Duration::from_millis(1), 3).on_retry(
+            |attempt: &RetryAttempt<'_, io::Error>| {
+                assert_eq!(attempt.error.to_string(), "transient");
+                attempts.push((attempt.attempt, attempt.delay));
+            },
+        );
+
+        retry(policy, f).await.unwrap_err();
+        assert_eq!(calls.get(), 3);
+        assert_eq!(
+            attempts,
+            vec![
+                (1, Some(Duration::from_millis(1))),
+                (2, Some(Duration::from_millis(2))),
+                (3, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exponential_backoff_policy() {
+        let err = io::Error::other("transient");
+        let wait = Duration::from_secs(1);
+        let zero = Duration::from_secs(0);
+
+        let mut policy = ExponentialBackoff::new(wait, 3);
+        assert_eq!(RetryPolicy::<io::Error>::initial_delay(&mut policy), None);
+        assert_eq!(policy.next_delay(&err, 1, zero), Some(wait));
+        assert_eq!(policy.next_delay(&err, 2, zero), Some(wait * 2));
+        assert_eq!(policy.next_delay(&err, 3, zero), None);
+
+        let mut policy = ExponentialBackoff::new(wait, 3).delay_first();
+        assert_eq!(
+            RetryPolicy::<io::Error>::initial_delay(&mut policy),
+            Some(wait)
+        );
+        assert_eq!(policy.next_delay(&err, 1, zero), Some(wait * 2));
+
+        let mut policy = ExponentialBackoff::new(wait, 10).max_elapsed(Duration::from_secs(5));
+        assert_eq!(policy.next_delay(&err, 1, zero), Some(wait));
+        assert_eq!(policy.next_delay(&err, 2, wait), Some(wait * 2));
+        assert_eq!(policy.next_delay(&err, 3, wait * 3), None);
+
+        let mut policy = ExponentialBackoff::new(wait, 10).max_delay(wait * 3);
+        assert_eq!(policy.next_delay(&err, 1, zero), Some(wait));
+        assert_eq!(policy.next_delay(&err, 2, zero), Some(wait * 2));
+        assert_eq!(policy.next_delay(&err, 3, zero), Some(wait * 3));
+        assert_eq!(policy.next_delay(&err, 4, zero), Some(wait * 3));
+
+        let mut policy = ExponentialBackoff::new(wait, 10)
+            .retry_if(|e: &io::Error| e.kind() == io::ErrorKind::TimedOut);
+        assert_eq!(policy.next_delay(&err, 1, zero), None);
+        assert_eq!(
+            policy.next_delay(&io::Error::from(io::ErrorKind::TimedOut), 1, zero),
+            Some(wait)
+        );
+    }
+}
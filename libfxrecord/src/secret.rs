@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Secret configuration values that need not live in the configuration file.
+
+use std::env;
+use std::fmt;
+use std::io;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// A secret configuration value, such as an access token.
+///
+/// In the configuration file, a secret is one of:
+///
+/// * a string, which is the secret itself;
+/// * `{ env = "NAME" }`, which reads the secret from the environment
+///   variable `NAME`; or
+/// * `{ credential = "TARGET" }`, which reads the secret from the generic
+///   credential named `TARGET` in the Windows Credential Manager.
+///
+/// Secrets are resolved when they are used, so that the value is never part of
+/// the loaded configuration. The `Debug` implementation never reveals the
+/// secret itself.
+#[derive(Clone, Deserialize, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum Secret {
+    /// The secret, given directly.
+    Literal(String),
+
+    /// The name of an environment variable containing the secret.
+    Env { env: String },
+
+    /// The target name of a generic credential containing the secret.
+    Credential { credential: String },
+}
+
+impl Secret {
+    /// Return the value of the secret.
+    pub fn resolve(&self) -> Result<String, SecretError> {
+        match self {
+            Secret::Literal(secret) => Ok(secret.clone()),
+            Secret::Env { env: name } => env::var(name).map_err(|source| SecretError::Env {
+                name: name.clone(),
+                source,
+            }),
+            Secret::Credential { credential: target } => {
+                read_credential(target).map_err(|source| SecretError::Credential {
+                    target: target.clone(),
+                    source,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Secret::Literal(..) => f.write_str("Secret(<redacted>)"),
+            Secret::Env { env: name } => f.debug_struct("Secret").field("env", name).finish(),
+            Secret::Credential { credential: target } => f
+                .debug_struct("Secret")
+                .field("credential", target)
+                .finish(),
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret::Literal(secret)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret::Literal(secret.into())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("Could not read secret from environment variable `{}': {}", .name, .source)]
+    Env { name: String, source: env::VarError },
+
+    #[error("Could not read secret from credential `{}': {}", .target, .source)]
+    Credential { target: String, source: io::Error },
+}
+
+/// Read the secret of the generic credential with the given target name.
+#[cfg(windows)]
+fn read_credential(target: &str) -> Result<String, io::Error> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+    use std::slice;
+
+    use winapi::um::wincred::{CredFree, CredReadW, CRED_TYPE_GENERIC, PCREDENTIALW};
+
+    let target = OsStr::new(target)
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<_>>();
+
+    let mut credential: PCREDENTIALW = null_mut();
+
+    // The blob is copied out of the credential before it is freed.
+    unsafe {
+        if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let blob = slice::from_raw_parts(
+            (*credential).CredentialBlob,
+            (*credential).CredentialBlobSize as usize,
+        )
+        .to_vec();
+
+        CredFree(credential as _);
+
+        Ok(decode_credential_blob(&blob))
+    }
+}
+
+/// Read the secret of the generic credential with the given target name.
+#[cfg(not(windows))]
+fn read_credential(_target: &str) -> Result<String, io::Error> {
+    Err(io::Error::other(
+        "the credential store is only supported on Windows",
+    ))
+}
+
+/// Decode a credential blob.
+///
+/// Credentials stored with `cmdkey` or the Credential Manager are UTF-16, but
+/// other tools may store UTF-8. Secrets are expected to be ASCII, so a blob is
+/// treated as UTF-16 when every other byte is zero.
+#[cfg(any(windows, test))]
+fn decode_credential_blob(blob: &[u8]) -> String {
+    if blob.len().is_multiple_of(2) && blob.iter().skip(1).step_by(2).all(|&b| b == 0) {
+        let wide = blob
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+
+        String::from_utf16_lossy(&wide)
+    } else {
+        String::from_utf8_lossy(blob).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        secret: Secret,
+    }
+
+    fn parse(s: &str) -> Secret {
+        toml::from_str::<Config>(s).unwrap().secret
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(parse(r#"secret = "hunter2""#), Secret::from("hunter2"));
+        assert_eq!(
+            parse(r#"secret = { env = "FXRECORD_SECRET" }"#),
+            Secret::Env {
+                env: "FXRECORD_SECRET".into()
+            }
+        );
+        assert_eq!(
+            parse(r#"secret = { credential = "fxrunner/taskcluster" }"#),
+            Secret::Credential {
+                credential: "fxrunner/taskcluster".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(Secret::from("hunter2").resolve().unwrap(), "hunter2");
+
+        env::set_var("FXRECORD_TEST_SECRET", "hunter2");
+        assert_eq!(
+            Secret::Env {
+                env: "FXRECORD_TEST_SECRET".into()
+            }
+            .resolve()
+            .unwrap(),
+            "hunter2"
+        );
+
+        assert_matches!(
+            Secret::Env {
+                env: "FXRECORD_TEST_MISSING_SECRET".into()
+            }
+            .resolve(),
+            Err(SecretError::Env { .. })
+        );
+    }
+
+    #[test]
+    fn test_debug() {
+        assert_eq!(
+            format!("{:?}", Secret::from("hunter2")),
+            "Secret(<redacted>)"
+        );
+    }
+
+    #[test]
+    fn test_decode_credential_blob() {
+        let utf16 = "hunter2"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        assert_eq!(decode_credential_blob(&utf16), "hunter2");
+        assert_eq!(decode_credential_blob(b"hunter2"), "hunter2");
+        assert_eq!(decode_credential_blob(b"hunter22"), "hunter22");
+    }
+}
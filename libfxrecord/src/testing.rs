@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for testing code that speaks the fxrecord protocol without a
+//! network.
+//!
+//! This module is only available with the `testing` feature.
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub mod strategies;
+
+/// Assert that a message is unchanged by being encoded and decoded as it
+/// would be on the wire.
+///
+/// Messages do not implement `PartialEq`, so they are compared by their
+/// encodings: the message is encoded, decoded, and encoded again.
+pub fn assert_round_trip<M>(msg: &M)
+where
+    M: Debug + DeserializeOwned + Serialize,
+{
+    let encoded = serde_json::to_vec(msg).expect("could not encode message");
+    let decoded: M = serde_json::from_slice(&encoded)
+        .unwrap_or_else(|e| panic!("could not decode message {:?}: {}", msg, e));
+    let reencoded = serde_json::to_vec(&decoded).expect("could not re-encode message");
+
+    assert_eq!(
+        String::from_utf8_lossy(&encoded),
+        String::from_utf8_lossy(&reencoded),
+        "message changed after a round trip: {:?}",
+        msg
+    );
+}
+
+/// One direction of a [`MemoryStream`](struct.MemoryStream.html).
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+
+    /// Whether or not the writing end has been shut down or dropped.
+    write_closed: bool,
+
+    /// Whether or not the reading end has been dropped.
+    read_closed: bool,
+
+    /// The task waiting for data to be written, if any.
+    read_waker: Option<Waker>,
+}
+
+/// One end of an in-memory duplex stream created by [`duplex`](fn.duplex.html).
+///
+/// Bytes written to one end may be read from the other. Reads return EOF once
+/// the other end has been shut down or dropped and writes fail once the other
+/// end has been dropped.
+#[derive(Debug)]
+pub struct MemoryStream {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// Create a pair of connected in-memory streams.
+///
+/// This can be used in place of a `TcpStream` to run both sides of the
+/// protocol in a single test.
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+
+    (
+        MemoryStream {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        MemoryStream { read: b, write: a },
+    )
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+
+        if pipe.buf.is_empty() && !buf.is_empty() {
+            if pipe.write_closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(pipe.buf.len());
+        for (dest, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dest = src;
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+
+        if pipe.read_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        pipe.buf.extend(buf);
+        if let Some(waker) = pipe.read_waker.take() {
+            waker.wake();
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        close_write(&self.write);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MemoryStream {
+    fn drop(&mut self) {
+        close_write(&self.write);
+        self.read.lock().unwrap().read_closed = true;
+    }
+}
+
+/// Close the writing end of the pipe, waking its reader.
+fn close_write(pipe: &Mutex<Pipe>) {
+    let mut pipe = pipe.lock().unwrap();
+    pipe.write_closed = true;
+    if let Some(waker) = pipe.read_waker.take() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_duplex() {
+        let (mut a, mut b) = duplex();
+
+        let reader = tokio::spawn(async move {
+            let mut buf = String::new();
+            b.read_to_string(&mut buf).await.unwrap();
+            b.write_all(b"pong").await.unwrap();
+            buf
+        });
+
+        a.write_all(b"ping").await.unwrap();
+        a.shutdown().await.unwrap();
+
+        let mut buf = String::new();
+        a.read_to_string(&mut buf).await.unwrap();
+
+        assert_eq!(reader.await.unwrap(), "ping");
+        assert_eq!(buf, "pong");
+
+        let (mut a, b) = duplex();
+        drop(b);
+        assert_eq!(
+            a.write_all(b"ping").await.unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
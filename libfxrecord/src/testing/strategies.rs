@@ -0,0 +1,697 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! [proptest] strategies for every protocol message.
+//!
+//! `RecorderMessage`, `RunnerMessage`, and the broker's messages implement
+//! `Arbitrary`, so they can be generated with `any::<RecorderMessage>()`. The strategies for the types
+//! that make up the messages are exposed so that tests may build on them.
+//!
+//! [proptest]: https://docs.rs/proptest
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use proptest::collection::{btree_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use proptest::strategy::LazyJust;
+use serde_json::Value;
+
+use crate::error::{ForeignError, ForeignErrorKind};
+use crate::net::broker::*;
+use crate::net::compression::Codec;
+use crate::net::*;
+use crate::prefs::PrefValue;
+
+/// The maximum number of elements in generated collections.
+const MAX_LEN: usize = 4;
+
+/// A strategy for arbitrary strings.
+fn string() -> impl Strategy<Value = String> {
+    any::<String>()
+}
+
+/// A strategy for a successful result from `ok`, or an arbitrary error.
+pub fn foreign_result<S>(ok: S) -> impl Strategy<Value = ForeignResult<S::Value>>
+where
+    S: Strategy,
+    S::Value: Debug,
+{
+    prop_oneof![ok.prop_map(Ok), foreign_error().prop_map(Err)]
+}
+
+pub fn foreign_error_kind() -> impl Strategy<Value = ForeignErrorKind> {
+    use ForeignErrorKind::*;
+
+    prop::sample::select(vec![
+        Busy,
+        Session,
+        Taskcluster,
+        Download,
+        Extraction,
+        Profile,
+        Shutdown,
+        Idle,
+        Desktop,
+        Firefox,
+        Proxy,
+        Clock,
+        Hosts,
+        Network,
+        Telemetry,
+        Io,
+        Timeout,
+        Protocol,
+        Other,
+    ])
+}
+
+/// A strategy for arbitrary errors with a chain of up to three causes.
+pub fn foreign_error() -> impl Strategy<Value = ForeignError> {
+    (
+        foreign_error_kind(),
+        vec((string(), option::of(string())), 1..5),
+    )
+        .prop_map(|(kind, errors)| {
+            let error = errors
+                .into_iter()
+                .rev()
+                .fold(None, |source, (message, type_name)| {
+                    Some(ForeignError {
+                        kind: ForeignErrorKind::Other,
+                        message,
+                        type_name,
+                        source: source.map(Box::new),
+                    })
+                })
+                .unwrap();
+
+            error.with_kind(kind)
+        })
+}
+
+/// A strategy for pref values.
+///
+/// Only integers are generated for numbers, as Firefox does not support other
+/// numeric prefs.
+pub fn pref_value() -> impl Strategy<Value = PrefValue> {
+    prop_oneof![
+        any::<bool>().prop_map(Value::from),
+        any::<i32>().prop_map(Value::from),
+        string().prop_map(Value::from),
+    ]
+    .prop_map(|v| PrefValue::try_from(v).unwrap())
+}
+
+pub fn build_task() -> impl Strategy<Value = BuildTask> {
+    prop_oneof![
+        string().prop_map(BuildTask::TaskId),
+        string().prop_map(BuildTask::IndexRoute),
+        (string(), string(), string()).prop_map(|(project, revision, platform)| {
+            BuildTask::Revision {
+                project,
+                revision,
+                platform,
+            }
+        }),
+    ]
+}
+
+pub fn channel() -> impl Strategy<Value = Channel> {
+    prop_oneof![
+        Just(Channel::Release),
+        Just(Channel::Beta),
+        Just(Channel::Esr),
+        Just(Channel::DevEdition),
+    ]
+}
+
+pub fn official_build() -> impl Strategy<Value = OfficialBuild> {
+    prop_oneof![
+        (channel(), string())
+            .prop_map(|(channel, version)| OfficialBuild::Release { channel, version }),
+        string().prop_map(|date| OfficialBuild::Nightly { date }),
+    ]
+}
+
+pub fn build_source() -> impl Strategy<Value = BuildSource> {
+    prop_oneof![
+        (build_task(), option::of(string()))
+            .prop_map(|(task, artifact)| BuildSource::Taskcluster { task, artifact }),
+        official_build().prop_map(BuildSource::MozillaArchive),
+        string().prop_map(BuildSource::Url),
+        string().prop_map(|path| BuildSource::Path(PathBuf::from(path))),
+        (any::<u64>(), "[0-9a-f]{64}")
+            .prop_map(|(size, sha256)| BuildSource::Upload { size, sha256 }),
+    ]
+}
+
+pub fn codec() -> impl Strategy<Value = Codec> {
+    prop_oneof![Just(Codec::Identity), Just(Codec::Deflate)]
+}
+
+pub fn new_session_request() -> impl Strategy<Value = NewSessionRequest> {
+    (
+        build_source(),
+        option::of(any::<u64>()),
+        option::of(any::<u64>()),
+        any::<bool>(),
+        option::of(string()),
+        vec((string(), pref_value()), 0..MAX_LEN),
+        option::of(string()),
+        option::of(any::<u64>()),
+        option::of(string()),
+        vec(codec(), 0..MAX_LEN),
+    )
+        .prop_map(
+            |(
+                build,
+                distribution_size,
+                profile_size,
+                profile_delta,
+                profile_template,
+                prefs,
+                locale,
+                extensions_size,
+                request_id,
+                codecs,
+            )| {
+                NewSessionRequest {
+                    build,
+                    distribution_size,
+                    profile_size,
+                    profile_delta,
+                    profile_template,
+                    prefs,
+                    locale,
+                    extensions_size,
+                    request_id,
+                    codecs,
+                }
+            },
+        )
+}
+
+pub fn idle() -> impl Strategy<Value = Idle> {
+    prop_oneof![Just(Idle::Wait), Just(Idle::Skip)]
+}
+
+pub fn session_type() -> impl Strategy<Value = SessionType> {
+    prop_oneof![
+        Just(SessionType::ColdStart),
+        string().prop_map(|url| SessionType::PageLoad { url }),
+    ]
+}
+
+pub fn proxy_mode() -> impl Strategy<Value = ProxyMode> {
+    prop_oneof![
+        string().prop_map(|archive| ProxyMode::Record { archive }),
+        string().prop_map(|archive| ProxyMode::Replay { archive }),
+    ]
+}
+
+pub fn network_conditions() -> impl Strategy<Value = NetworkConditions> {
+    any::<(u32, u32, u32)>().prop_map(|(download_kbps, upload_kbps, latency_ms)| {
+        NetworkConditions {
+            download_kbps,
+            upload_kbps,
+            latency_ms,
+        }
+    })
+}
+
+pub fn run_options() -> impl Strategy<Value = RunOptions> {
+    (
+        session_type(),
+        option::of(proxy_mode()),
+        vec((string(), any::<IpAddr>()), 0..MAX_LEN),
+        option::of(network_conditions()),
+        any::<bool>(),
+        vec((string(), string()), 0..MAX_LEN),
+        (any::<bool>(), any::<bool>(), any::<bool>()),
+        (
+            option::of(string()),
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+        ),
+        (
+            any::<u32>(),
+            any::<bool>(),
+            duration(),
+            any::<bool>(),
+            any::<bool>(),
+        ),
+    )
+        .prop_map(
+            |(
+                session_type,
+                proxy,
+                host_overrides,
+                network,
+                return_profile,
+                env,
+                (upload_artifacts, capture_pings, memory_report),
+                (timezone, sync_clock, safe_mode, headless_measurement),
+                (relaunches, reset_profile, cool_down, cool_down_disk_idle, restart_after),
+            )| {
+                RunOptions {
+                    session_type,
+                    proxy,
+                    host_overrides,
+                    network,
+                    return_profile,
+                    env,
+                    upload_artifacts,
+                    capture_pings,
+                    memory_report,
+                    timezone,
+                    sync_clock,
+                    safe_mode,
+                    headless_measurement,
+                    relaunches,
+                    reset_profile,
+                    cool_down,
+                    cool_down_disk_idle,
+                    restart_after,
+                }
+            },
+        )
+}
+
+pub fn uploaded_artifact() -> impl Strategy<Value = UploadedArtifact> {
+    (string(), string(), string(), any::<u64>()).prop_map(|(name, key, url, size)| {
+        UploadedArtifact {
+            name,
+            key,
+            url,
+            size,
+        }
+    })
+}
+
+pub fn captured_pings() -> impl Strategy<Value = CapturedPings> {
+    (any::<u64>(), any::<u64>()).prop_map(|(count, size)| CapturedPings { count, size })
+}
+
+pub fn startup_cache_stats() -> impl Strategy<Value = StartupCacheStats> {
+    (
+        any::<u64>(),
+        any::<u64>(),
+        option::of(startup_cache_requests()),
+    )
+        .prop_map(|(size_before, size_after, requests)| StartupCacheStats {
+            size_before,
+            size_after,
+            requests,
+        })
+}
+
+pub fn startup_cache_requests() -> impl Strategy<Value = StartupCacheRequests> {
+    (any::<u64>(), any::<u64>(), any::<u64>()).prop_map(|(hit_memory, hit_disk, miss)| {
+        StartupCacheRequests {
+            hit_memory,
+            hit_disk,
+            miss,
+        }
+    })
+}
+
+pub fn graphics_info() -> impl Strategy<Value = GraphicsInfo> {
+    (
+        option::of(string()),
+        option::of(string()),
+        option::of(string()),
+        option::of(string()),
+        option::of(string()),
+        option::of(any::<bool>()),
+        option::of(any::<bool>()),
+    )
+        .prop_map(
+            |(
+                adapter,
+                vendor_id,
+                device_id,
+                driver_version,
+                compositor,
+                hardware_acceleration,
+                webrender,
+            )| GraphicsInfo {
+                adapter,
+                vendor_id,
+                device_id,
+                driver_version,
+                compositor,
+                hardware_acceleration,
+                webrender,
+            },
+        )
+}
+
+pub fn resume_session_request() -> impl Strategy<Value = ResumeSessionRequest> {
+    (string(), idle(), run_options(), vec(codec(), 0..MAX_LEN)).prop_map(
+        |(session_id, idle, run_options, codecs)| ResumeSessionRequest {
+            session_id,
+            idle,
+            run_options,
+            codecs,
+        },
+    )
+}
+
+pub fn session() -> impl Strategy<Value = Session> {
+    prop_oneof![
+        new_session_request().prop_map(Session::NewSession),
+        resume_session_request().prop_map(Session::ResumeSession),
+        LazyJust::new(|| Session::Ping),
+    ]
+}
+
+pub fn download_progress() -> impl Strategy<Value = DownloadProgress> {
+    (any::<u64>(), option::of(any::<u64>()))
+        .prop_map(|(downloaded, total)| DownloadProgress { downloaded, total })
+}
+
+pub fn extract_progress() -> impl Strategy<Value = ExtractProgress> {
+    (any::<u64>(), option::of(any::<u64>()))
+        .prop_map(|(extracted, total)| ExtractProgress { extracted, total })
+}
+
+pub fn download_status() -> impl Strategy<Value = DownloadStatus> {
+    prop_oneof![
+        Just(DownloadStatus::Downloading),
+        download_progress().prop_map(DownloadStatus::Progress),
+        Just(DownloadStatus::Downloaded),
+        extract_progress().prop_map(DownloadStatus::Extracting),
+        Just(DownloadStatus::Extracted),
+    ]
+}
+
+pub fn duration() -> impl Strategy<Value = Duration> {
+    (any::<u64>(), 0..1_000_000_000u32).prop_map(|(secs, nanos)| Duration::new(secs, nanos))
+}
+
+pub fn fast_startup() -> impl Strategy<Value = FastStartup> {
+    prop_oneof![Just(FastStartup::NotEnabled), Just(FastStartup::Disabled)]
+}
+
+pub fn restart_info() -> impl Strategy<Value = RestartInfo> {
+    (duration(), fast_startup()).prop_map(|(delay, fast_startup)| RestartInfo {
+        delay,
+        fast_startup,
+    })
+}
+
+pub fn boot_timings() -> impl Strategy<Value = BootTimings> {
+    (option::of(duration()), duration()).prop_map(|(restart, boot_to_desktop)| BootTimings {
+        restart,
+        boot_to_desktop,
+    })
+}
+
+pub fn bandwidth_usage() -> impl Strategy<Value = BandwidthUsage> {
+    (any::<u64>(), any::<u64>(), any::<u64>()).prop_map(|(downloaded, sent, received)| {
+        BandwidthUsage {
+            downloaded,
+            sent,
+            received,
+        }
+    })
+}
+
+pub fn restart_result() -> impl Strategy<Value = RestartResult> {
+    prop_oneof![
+        Just(RestartResult::Scheduled),
+        Just(RestartResult::Cancelled),
+        string().prop_map(RestartResult::Failed),
+    ]
+}
+
+pub fn restart_record() -> impl Strategy<Value = RestartRecord> {
+    // Timestamps between 1970 and 2100.
+    (
+        0..4_102_444_800i64,
+        0..1_000_000_000u32,
+        string(),
+        string(),
+        restart_result(),
+    )
+        .prop_map(|(secs, nanos, reason, session_id, result)| RestartRecord {
+            timestamp: Utc.timestamp(secs, nanos),
+            reason,
+            session_id,
+            result,
+        })
+}
+
+pub fn runner_status() -> impl Strategy<Value = RunnerStatus> {
+    (string(), any::<bool>(), vec(restart_record(), 0..MAX_LEN)).prop_map(
+        |(version, busy, restarts)| RunnerStatus {
+            version,
+            busy,
+            restarts,
+        },
+    )
+}
+
+pub fn build_metadata() -> impl Strategy<Value = BuildMetadata> {
+    (
+        string(),
+        string(),
+        option::of(string()),
+        option::of(string()),
+    )
+        .prop_map(
+            |(version, build_id, source_repository, source_stamp)| BuildMetadata {
+                version,
+                build_id,
+                source_repository,
+                source_stamp,
+            },
+        )
+}
+
+pub fn recorder_message() -> impl Strategy<Value = RecorderMessage> {
+    prop_oneof![
+        session().prop_map(RecorderMessage::from),
+        LazyJust::new(|| RecorderMessage::from(StartFirefox)),
+        LazyJust::new(|| RecorderMessage::from(Navigate)),
+        LazyJust::new(|| RecorderMessage::from(StopFirefox)),
+        LazyJust::new(|| RecorderMessage::from(CancelSession)),
+        (any::<u64>(), vec(string(), 0..MAX_LEN))
+            .prop_map(|(size, removed)| RecorderMessage::from(ProfileDelta { size, removed })),
+        LazyJust::new(|| RecorderMessage::from(RecvReturned)),
+    ]
+}
+
+pub fn runner_message() -> impl Strategy<Value = RunnerMessage> {
+    let unit = || foreign_result(Just(()));
+
+    prop_oneof![
+        foreign_result(string()).prop_map(|result| RunnerMessage::from(ResolveTask { result })),
+        foreign_result(download_status())
+            .prop_map(|result| RunnerMessage::from(DownloadBuild { result })),
+        LazyJust::new(|| RunnerMessage::from(RecvDistribution)),
+        unit().prop_map(|result| RunnerMessage::from(InstalledDistribution { result })),
+        unit().prop_map(|result| RunnerMessage::from(DisableUpdates { result })),
+        foreign_result(download_status())
+            .prop_map(|result| RunnerMessage::from(RecvProfile { result })),
+        btree_map(string(), string(), 0..MAX_LEN)
+            .prop_map(|manifest| RunnerMessage::from(ProfileManifest { manifest })),
+        unit().prop_map(|result| RunnerMessage::from(CreateProfile { result })),
+        unit().prop_map(|result| RunnerMessage::from(WritePrefs { result })),
+        unit().prop_map(|result| RunnerMessage::from(InstalledLocale { result })),
+        LazyJust::new(|| RunnerMessage::from(RecvExtensions)),
+        foreign_result(vec(string(), 0..MAX_LEN))
+            .prop_map(|result| RunnerMessage::from(InstalledExtensions { result })),
+        foreign_result(restart_info())
+            .prop_map(|result| RunnerMessage::from(Restarting { result })),
+        unit().prop_map(|result| RunnerMessage::from(RestartCancelled { result })),
+        runner_status().prop_map(|status| RunnerMessage::from(Status { status })),
+        (foreign_result(string()), codec(), option::of(any::<u64>())).prop_map(
+            |(session_id, codec, max_profile_size)| {
+                RunnerMessage::from(NewSessionResponse {
+                    session_id,
+                    codec,
+                    max_profile_size,
+                })
+            }
+        ),
+        (unit(), codec())
+            .prop_map(|(result, codec)| RunnerMessage::from(ResumeResponse { result, codec })),
+        foreign_result(build_metadata())
+            .prop_map(|result| RunnerMessage::from(BuildInfo { result })),
+        foreign_result(boot_timings()).prop_map(|result| RunnerMessage::from(BootInfo { result })),
+        unit().prop_map(|result| RunnerMessage::from(NormalizedClock { result })),
+        unit().prop_map(|result| RunnerMessage::from(OverrodeHosts { result })),
+        unit().prop_map(|result| RunnerMessage::from(StartedProxy { result })),
+        unit().prop_map(|result| RunnerMessage::from(ConditionedNetwork { result })),
+        unit().prop_map(|result| RunnerMessage::from(StartedPingCapture { result })),
+        unit().prop_map(|result| RunnerMessage::from(WaitForIdle { result })),
+        unit().prop_map(|result| RunnerMessage::from(StartedFirefox { result })),
+        unit().prop_map(|result| RunnerMessage::from(Navigated { result })),
+        prop_oneof![Just(Ok(())), vec(foreign_error(), 0..MAX_LEN).prop_map(Err),]
+            .prop_map(|result| RunnerMessage::from(StoppedFirefox { result })),
+        (
+            foreign_result(btree_map(string(), any::<u64>(), 0..MAX_LEN)),
+            option::of(startup_cache_stats()),
+            option::of(graphics_info())
+        )
+            .prop_map(|(result, startup_cache, graphics)| RunnerMessage::from(
+                StartupTelemetry {
+                    result,
+                    startup_cache,
+                    graphics
+                }
+            )),
+        LazyJust::new(|| RunnerMessage::from(CooledDown)),
+        (
+            foreign_result(captured_pings()),
+            option::of(uploaded_artifact())
+        )
+            .prop_map(|(result, uploaded)| RunnerMessage::from(TelemetryPings {
+                result,
+                uploaded
+            })),
+        (
+            foreign_result(any::<u64>()),
+            option::of(uploaded_artifact())
+        )
+            .prop_map(|(result, uploaded)| RunnerMessage::from(MemoryReport { result, uploaded })),
+        (
+            foreign_result(any::<u64>()),
+            option::of(uploaded_artifact())
+        )
+            .prop_map(|(result, uploaded)| RunnerMessage::from(ReturnProfile { result, uploaded })),
+        foreign_result(string()).prop_map(|result| RunnerMessage::from(SessionLog { result })),
+        (unit(), option::of(bandwidth_usage())).prop_map(|(result, bandwidth)| {
+            RunnerMessage::from(SessionFinished { result, bandwidth })
+        }),
+    ]
+}
+
+pub fn tags() -> impl Strategy<Value = BTreeMap<String, String>> {
+    btree_map(string(), string(), 0..MAX_LEN)
+}
+
+pub fn lease_outcome() -> impl Strategy<Value = LeaseOutcome> {
+    prop_oneof![
+        Just(LeaseOutcome::Succeeded),
+        string().prop_map(LeaseOutcome::Failed),
+    ]
+}
+
+pub fn broker_request() -> impl Strategy<Value = BrokerRequest> {
+    prop_oneof![
+        (string(), any::<u16>(), tags()).prop_map(|(name, port, tags)| {
+            BrokerRequest::Register(RunnerRegistration { name, port, tags })
+        }),
+        (tags(), string())
+            .prop_map(|(tags, requester)| BrokerRequest::Lease(LeaseRequest { tags, requester })),
+        string().prop_map(|lease_id| BrokerRequest::Renew(LeaseRenewal { lease_id })),
+        (string(), lease_outcome()).prop_map(|(lease_id, outcome)| {
+            BrokerRequest::Release(LeaseRelease { lease_id, outcome })
+        }),
+    ]
+}
+
+pub fn lease() -> impl Strategy<Value = Lease> {
+    (string(), string(), string(), any::<u64>()).prop_map(
+        |(lease_id, runner, host, duration_secs)| Lease {
+            lease_id,
+            runner,
+            host,
+            duration_secs,
+        },
+    )
+}
+
+pub fn broker_client_message() -> impl Strategy<Value = BrokerClientMessage> {
+    broker_request().prop_map(BrokerClientMessage::from)
+}
+
+pub fn broker_message() -> impl Strategy<Value = BrokerMessage> {
+    let unit = || foreign_result(Just(()));
+
+    prop_oneof![
+        unit().prop_map(|result| BrokerMessage::from(Registered { result })),
+        any::<usize>().prop_map(|position| BrokerMessage::from(Queued { position })),
+        foreign_result(lease()).prop_map(|result| BrokerMessage::from(Leased { result })),
+        unit().prop_map(|result| BrokerMessage::from(Renewed { result })),
+        unit().prop_map(|result| BrokerMessage::from(Released { result })),
+    ]
+}
+
+impl Arbitrary for RecorderMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        recorder_message().boxed()
+    }
+}
+
+impl Arbitrary for RunnerMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        runner_message().boxed()
+    }
+}
+
+impl Arbitrary for BrokerClientMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        broker_client_message().boxed()
+    }
+}
+
+impl Arbitrary for BrokerMessage {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        broker_message().boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::net::broker::{BrokerClientMessage, BrokerMessage};
+    use crate::net::{RecorderMessage, RunnerMessage};
+    use crate::testing::assert_round_trip;
+
+    proptest! {
+        #[test]
+        fn test_recorder_message_round_trip(msg in any::<RecorderMessage>()) {
+            assert_round_trip(&msg);
+        }
+
+        #[test]
+        fn test_runner_message_round_trip(msg in any::<RunnerMessage>()) {
+            assert_round_trip(&msg);
+        }
+
+        #[test]
+        fn test_broker_client_message_round_trip(msg in any::<BrokerClientMessage>()) {
+            assert_round_trip(&msg);
+        }
+
+        #[test]
+        fn test_broker_message_round_trip(msg in any::<BrokerMessage>()) {
+            assert_round_trip(&msg);
+        }
+    }
+}
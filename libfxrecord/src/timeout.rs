@@ -0,0 +1,184 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limits on how long each phase of a session may take.
+//!
+//! Both the runner and the recorder are configured with a `timeouts` table.
+//! Each side enforces the limits of the phases that it waits on, so that a
+//! stuck session fails with an error naming the phase that did not finish.
+
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::config::{ConfigIssues, Validate};
+
+/// A phase of a session that can be limited by a timeout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeoutPhase {
+    /// Exchanging the new or resumed session request and response.
+    Handshake,
+
+    /// Waiting for the other side to reconnect after the runner restarts.
+    RestartReconnect,
+
+    /// Downloading and extracting the build.
+    Download,
+
+    /// Sending, copying, or creating the profile.
+    ProfileTransfer,
+
+    /// Starting Firefox, including waiting for it to settle before a page
+    /// load.
+    Launch,
+
+    /// Capturing video while Firefox runs.
+    Capture,
+}
+
+impl TimeoutPhase {
+    /// The name of the phase's option in the `timeouts` table.
+    pub fn config_key(self) -> &'static str {
+        match self {
+            TimeoutPhase::Handshake => "handshake_secs",
+            TimeoutPhase::RestartReconnect => "restart_reconnect_secs",
+            TimeoutPhase::Download => "download_secs",
+            TimeoutPhase::ProfileTransfer => "profile_transfer_secs",
+            TimeoutPhase::Launch => "launch_secs",
+            TimeoutPhase::Capture => "capture_secs",
+        }
+    }
+}
+
+impl Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutPhase::Handshake => "the handshake",
+            TimeoutPhase::RestartReconnect => "reconnection after restart",
+            TimeoutPhase::Download => "the build download",
+            TimeoutPhase::ProfileTransfer => "the profile transfer",
+            TimeoutPhase::Launch => "Firefox to launch",
+            TimeoutPhase::Capture => "the capture",
+        })
+    }
+}
+
+/// The time each phase of a session may take, in seconds.
+///
+/// Phases without a timeout may take as long as they need.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub handshake_secs: Option<u64>,
+    pub restart_reconnect_secs: Option<u64>,
+    pub download_secs: Option<u64>,
+    pub profile_transfer_secs: Option<u64>,
+    pub launch_secs: Option<u64>,
+    pub capture_secs: Option<u64>,
+}
+
+impl TimeoutConfig {
+    /// Return the timeout of the given phase, if any.
+    pub fn get(&self, phase: TimeoutPhase) -> Option<Duration> {
+        let secs = match phase {
+            TimeoutPhase::Handshake => self.handshake_secs,
+            TimeoutPhase::RestartReconnect => self.restart_reconnect_secs,
+            TimeoutPhase::Download => self.download_secs,
+            TimeoutPhase::ProfileTransfer => self.profile_transfer_secs,
+            TimeoutPhase::Launch => self.launch_secs,
+            TimeoutPhase::Capture => self.capture_secs,
+        };
+
+        secs.map(Duration::from_secs)
+    }
+}
+
+impl Validate for TimeoutConfig {
+    fn validate(&self, issues: &mut ConfigIssues) {
+        for phase in &[
+            TimeoutPhase::Handshake,
+            TimeoutPhase::RestartReconnect,
+            TimeoutPhase::Download,
+            TimeoutPhase::ProfileTransfer,
+            TimeoutPhase::Launch,
+            TimeoutPhase::Capture,
+        ] {
+            if let Some(limit) = self.get(*phase) {
+                issues.check_range(phase.config_key(), limit.as_secs(), 1, u64::MAX);
+            }
+        }
+    }
+}
+
+/// Run the future to completion, unless it takes longer than `limit`.
+///
+/// If there is no limit, the future is run to completion.
+pub async fn with_timeout<F: Future>(
+    phase: TimeoutPhase,
+    limit: Option<Duration>,
+    f: F,
+) -> Result<F::Output, TimeoutError> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, f)
+            .await
+            .map_err(|_| TimeoutError { phase, limit }),
+        None => Ok(f.await),
+    }
+}
+
+/// A phase of a session did not finish within its timeout.
+#[derive(Debug, Error)]
+#[error(
+    "Timed out after {:?} waiting for {} (see `timeouts.{}')",
+    .limit,
+    .phase,
+    .phase.config_key()
+)]
+pub struct TimeoutError {
+    pub phase: TimeoutPhase,
+    pub limit: Duration,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let config = TimeoutConfig {
+            download_secs: Some(1),
+            ..TimeoutConfig::default()
+        };
+
+        assert_eq!(
+            config.get(TimeoutPhase::Download),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(config.get(TimeoutPhase::Capture), None);
+
+        assert_eq!(
+            with_timeout(TimeoutPhase::Capture, None, async { 1 })
+                .await
+                .unwrap(),
+            1
+        );
+
+        let err = with_timeout(
+            TimeoutPhase::Download,
+            Some(Duration::from_millis(1)),
+            tokio::time::delay_for(Duration::from_secs(60)),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(err.phase, TimeoutPhase::Download);
+        assert_eq!(
+            err.to_string(),
+            "Timed out after 1ms waiting for the build download (see `timeouts.download_secs')"
+        );
+    }
+}